@@ -167,6 +167,7 @@ impl Configuration {
             diskencryption_clevis: None,
             additional_serviceinfo: None,
             after_onboarding_reboot: Some(false),
+            disabled_modules: None,
         })
     }
 }
@@ -202,15 +203,21 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
             session_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join("rendezvous_sessions"),
             },
+            session_store_encryption_key_path: None,
 
             trusted_manufacturer_keys_path: Some(
                 AbsolutePathBuf::new(aio_dir.join("keys").join("manufacturer_cert.pem"))
                     .expect("Failed to build absolute path"),
             ),
+            ondie: None,
+            revocation: None,
+            crypto_policy: None,
 
             max_wait_seconds: None,
+            max_concurrent_requests: None,
 
             bind: get_bind(config_args.listen_port_rendezvous_server)?,
+            drain_timeout_seconds: 30,
         };
     write_config(aio_dir, "rendezvous_server.yml", &rendezvous_config)
         .context("Error writing rendezvous server configuration file")?;
@@ -222,10 +229,13 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
                 .generate_serviceinfo_settings()
                 .context("Error generating serviceinfo settings")?,
 
-            bind: get_bind(config_args.listen_port_serviceinfo_api_server)?,
+            bind: Some(get_bind(config_args.listen_port_serviceinfo_api_server)?),
+            unix_socket: None,
 
             service_info_auth_token: Some(config_args.serviceinfo_api_auth_token.clone()),
             admin_auth_token: Some(config_args.serviceinfo_api_admin_token.clone()),
+            admin_access_control: None,
+            tls: None,
 
             device_specific_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join("serviceinfo_api_devices"),
@@ -252,8 +262,10 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
             session_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join("manufacturing_sessions"),
             },
+            session_store_encryption_key_path: None,
 
             bind: get_bind(config_args.listen_port_manufacturing_server)?,
+            drain_timeout_seconds: 30,
 
             ownership_voucher_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join(if config_args.separate_manufacturing_and_owner_voucher_store {
@@ -281,15 +293,18 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
                     cert_path: AbsolutePathBuf::new(
                         aio_dir.join("keys").join("diun_cert.pem"),
                     ).unwrap(),
+                    tpm_endorsement_ca_certs_path: None,
                 }
                 )
             },
             rendezvous_info: config_args
                 .generate_rendezvous_info()
                 .context("Error generating rendezvous info")?,
+            crypto_policy: None,
             manufacturing: fdo_util::servers::configuration::manufacturing_server::ManufacturingSettings {
                 manufacturer_cert_path: AbsolutePathBuf::new(aio_dir.join("keys").join("manufacturer_cert.pem")).unwrap(),
                 manufacturer_private_key: Some(AbsolutePathBuf::new(aio_dir.join("keys").join("manufacturer_key.der")).unwrap()),
+                manufacturer_private_key_uri: None,
                 device_cert_ca_private_key: AbsolutePathBuf::new(aio_dir.join("keys").join("device_ca_key.der")).unwrap(),
                 device_cert_ca_chain: AbsolutePathBuf::new(aio_dir.join("keys").join("device_ca_cert.pem")).unwrap(),
                 owner_cert_path: Some(AbsolutePathBuf::new(aio_dir.join("keys").join("owner_cert.pem")).unwrap()),
@@ -308,20 +323,28 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
             session_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join("owner_onboarding_sessions"),
             },
+            session_store_encryption_key_path: None,
 
             bind: get_bind(config_args.listen_port_owner_onboarding_server)?,
+            drain_timeout_seconds: 30,
 
             ownership_voucher_store_driver: StoreConfig::Directory {
                 path: aio_dir.join("stores").join("owner_vouchers"),
             },
+            ownership_voucher_cache: None,
             trusted_device_keys_path: AbsolutePathBuf::new(
                 aio_dir.join("keys").join("device_ca_cert.pem"),
             )
             .unwrap(),
-            owner_private_key_path: AbsolutePathBuf::new(
-                aio_dir.join("keys").join("owner_key.der"),
-            )
-            .unwrap(),
+            ondie: None,
+            revocation: None,
+            crypto_policy: None,
+            verify_device_certificate_chains: false,
+            voucher_policy: Default::default(),
+            owner_private_key_path: Some(
+                AbsolutePathBuf::new(aio_dir.join("keys").join("owner_key.der")).unwrap(),
+            ),
+            owner_private_key_uri: None,
 
             owner_public_key_path: AbsolutePathBuf::new(
                 aio_dir.join("keys").join("owner_cert.pem"),
@@ -335,10 +358,21 @@ fn generate_configs(aio_dir: &Path, config_args: &Configuration) -> Result<(), E
                 fdo_http_wrapper::client::JsonAuthentication::BearerToken {
                     token: config_args.serviceinfo_api_auth_token.clone(),
                 },
+            service_info_api_ca_path: None,
+            service_info_api_unix_socket: None,
+            event_publishers: Vec::new(),
+            max_device_service_info_size: None,
+            service_info_spool_store_driver: None,
             owner_addresses: config_args
                 .generate_owner_addresses()
                 .context("Error generating owner addresses")?,
             report_to_rendezvous_endpoint_enabled: true,
+            to0_registration: Default::default(),
+            credential_reuse_enabled: false,
+            resale_enabled: false,
+            management_api_auth_token: None,
+            management_api_access_control: None,
+            max_concurrent_requests: None,
         };
     write_config(
         aio_dir,
@@ -14,6 +14,7 @@ use openssl::x509::{X509Name, X509};
 use std::env;
 
 mod aio;
+mod management;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Subject {
@@ -133,6 +134,45 @@ impl LogLevel {
 enum Commands {
     GenerateKeyAndCert(GenerateKeyAndCertArguments),
     Aio(Box<crate::aio::AioArgs>),
+    /// Manages the ownership vouchers of a running Owner Onboarding Server
+    Management(crate::management::ManagementArgs),
+    /// Upgrades the schema of a SQLite or PostgreSQL store to the version of this release
+    MigrateStore(MigrateStoreArguments),
+}
+
+#[derive(Args)]
+struct MigrateStoreArguments {
+    /// Path to the SQLite database, or postgresql:// URL of the PostgreSQL database, of the store
+    store: String,
+}
+
+fn migrate_store(args: &MigrateStoreArguments) -> Result<(), Error> {
+    // All the stores of a PostgreSQL database share its schema, so the name isn't needed
+    let store = if args.store.starts_with("postgres://") || args.store.starts_with("postgresql://")
+    {
+        fdo_store::StoreConfig::Postgres {
+            url: args.store.clone(),
+            name: String::new(),
+            auto_migrate: false,
+        }
+    } else {
+        fdo_store::StoreConfig::Sqlite {
+            path: PathBuf::from(&args.store),
+            auto_migrate: false,
+        }
+    };
+    match store.migrate()? {
+        Some(migration) if migration.from == migration.to => {
+            log::info!("Store is at the latest schema version {}", migration.to)
+        }
+        Some(migration) => log::info!(
+            "Store migrated from schema version {} to {}",
+            migration.from,
+            migration.to
+        ),
+        None => log::info!("Store doesn't have a schema"),
+    }
+    Ok(())
 }
 
 #[derive(Args)]
@@ -165,5 +205,7 @@ async fn main() -> Result<()> {
     match cli.command {
         Commands::GenerateKeyAndCert(args) => generate_key_and_cert(&args),
         Commands::Aio(args) => aio::run_aio_subcommand(*args).await,
+        Commands::Management(args) => management::run_management_subcommand(args).await,
+        Commands::MigrateStore(args) => migrate_store(&args),
     }
 }
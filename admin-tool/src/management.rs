@@ -0,0 +1,187 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand};
+use serde_json::Value;
+
+#[derive(Debug, Args)]
+pub(crate) struct ManagementArgs {
+    /// URL of the Owner Onboarding Server, e.g. http://localhost:8081
+    #[clap(long)]
+    url: String,
+    /// Management API token of the server (its management_api_auth_token)
+    #[clap(long, conflicts_with = "auth_token_path")]
+    auth_token: Option<String>,
+    /// Path to a file holding the management API token
+    #[clap(long)]
+    auth_token_path: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: ManagementCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum ManagementCommand {
+    /// List the ownership vouchers of the server
+    List {
+        /// Only list the vouchers of devices that didn't onboard yet
+        #[clap(long)]
+        pending: bool,
+        /// Only list the vouchers with this device info
+        #[clap(long)]
+        device_info: Option<String>,
+    },
+    /// Show the onboarding status of a device
+    Status {
+        /// GUID of the device
+        guid: String,
+    },
+    /// Register the ownership voucher with its rendezvous servers now
+    Register {
+        /// GUID of the device
+        guid: String,
+    },
+    /// Delete the ownership voucher, so that the device can no longer onboard
+    Revoke {
+        /// GUID of the device
+        guid: String,
+    },
+    /// Show the configuration of the server, without secrets
+    DumpConfig,
+}
+
+struct ManagementClient {
+    base_url: String,
+    auth_token: String,
+    client: reqwest::Client,
+}
+
+impl ManagementClient {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}/management/v1/{}", self.base_url, path))
+            .bearer_auth(&self.auth_token)
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder, what: &str) -> Result<String> {
+        let resp = request
+            .send()
+            .await
+            .with_context(|| format!("Error requesting {what}"))?;
+        let status = resp.status();
+        let body = resp
+            .text()
+            .await
+            .with_context(|| format!("Error reading {what}"))?;
+        if status == reqwest::StatusCode::NOT_FOUND {
+            bail!("{} not found", what);
+        }
+        if !status.is_success() {
+            bail!("Error requesting {}: {}: {}", what, status, body);
+        }
+        Ok(body)
+    }
+
+    async fn get_json(&self, path: &str, what: &str) -> Result<Value> {
+        let body = self
+            .send(self.request(reqwest::Method::GET, path), what)
+            .await?;
+        serde_json::from_str(&body).with_context(|| format!("Error parsing {what}"))
+    }
+}
+
+pub(crate) async fn run_management_subcommand(args: ManagementArgs) -> Result<()> {
+    let auth_token = match (args.auth_token, args.auth_token_path) {
+        (Some(token), _) => token,
+        (None, Some(path)) => std::fs::read_to_string(&path)
+            .with_context(|| format!("Error reading auth token from {path:?}"))?
+            .trim()
+            .to_string(),
+        (None, None) => bail!("Either --auth-token or --auth-token-path is required"),
+    };
+    let client = ManagementClient {
+        base_url: args.url.trim_end_matches('/').to_string(),
+        auth_token,
+        client: reqwest::Client::new(),
+    };
+
+    match args.command {
+        ManagementCommand::List {
+            pending,
+            device_info,
+        } => {
+            let mut request = client.request(reqwest::Method::GET, "ownership_voucher");
+            if pending {
+                request = request.query(&[("pending", "true")]);
+            }
+            if let Some(device_info) = &device_info {
+                request = request.query(&[("device_info", device_info)]);
+            }
+            let body = client.send(request, "ownership vouchers").await?;
+            let ovs: Vec<Value> =
+                serde_json::from_str(&body).context("Error parsing ownership vouchers")?;
+            for ov in &ovs {
+                println!(
+                    "{}\t{}",
+                    ov["guid"].as_str().unwrap_or_default(),
+                    ov["device_info"].as_str().unwrap_or_default()
+                );
+            }
+            log::info!("{} ownership vouchers", ovs.len());
+        }
+        ManagementCommand::Status { guid } => {
+            let status = client
+                .get_json(
+                    &format!("ownership_voucher/{guid}/status"),
+                    &format!("Ownership voucher {guid}"),
+                )
+                .await?;
+            println!("GUID: {}", status["guid"].as_str().unwrap_or_default());
+            println!(
+                "Device info: {}",
+                status["device_info"].as_str().unwrap_or_default()
+            );
+            println!(
+                "Onboarded: {}",
+                status["onboarded"].as_bool().unwrap_or_default()
+            );
+            println!(
+                "Rendezvous registration due: {}",
+                status["registration_due"].as_bool().unwrap_or_default()
+            );
+        }
+        ManagementCommand::Register { guid } => {
+            client
+                .send(
+                    client.request(
+                        reqwest::Method::POST,
+                        &format!("ownership_voucher/{guid}/registration"),
+                    ),
+                    &format!("Ownership voucher {guid}"),
+                )
+                .await?;
+            log::info!("Ownership voucher {} registered", guid);
+        }
+        ManagementCommand::Revoke { guid } => {
+            client
+                .send(
+                    client.request(
+                        reqwest::Method::DELETE,
+                        &format!("ownership_voucher/{guid}"),
+                    ),
+                    &format!("Ownership voucher {guid}"),
+                )
+                .await?;
+            log::info!("Ownership voucher {} revoked", guid);
+        }
+        ManagementCommand::DumpConfig => {
+            let configuration = client.get_json("configuration", "Configuration").await?;
+            print!(
+                "{}",
+                serde_yaml::to_string(&configuration).context("Error serializing configuration")?
+            );
+        }
+    }
+
+    Ok(())
+}
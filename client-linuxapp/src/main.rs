@@ -1,4 +1,4 @@
-use std::{borrow::Borrow, env, fs, path::PathBuf, process::Command, thread, time};
+use std::{borrow::Borrow, convert::TryFrom, env, fs, process::Command, thread, time};
 
 use anyhow::{anyhow, bail, Context, Result};
 use rand::Rng;
@@ -7,40 +7,44 @@ use thiserror::Error;
 use fdo_data_formats::{
     cborparser::ParsedArray,
     constants::{
-        DeviceSigType, ErrorCode, HeaderKeys, MessageType, RendezvousProtocolValue,
+        DeviceSigType, ErrorCode, HashType, HeaderKeys, MessageType, RendezvousProtocolValue,
         TransportProtocol,
     },
     enhanced_types::{RendezvousInterpretedDirective, RendezvousInterpreterSide},
     messages,
     ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::PublicKey,
     types::{
-        new_eat, COSEHeaderMap, COSESign, CipherSuite, EATokenPayload, HMac, KexSuite,
-        KeyDeriveSide, KeyExchange, Nonce, PayloadCreating, SigInfo, TO1DataPayload,
-        TO2AddressEntry, TO2ProveDevicePayload, TO2ProveOVHdrPayload, UnverifiedValue,
+        new_eat, COSEHeaderMap, COSESign, CipherSuite, EATokenPayload, Guid, HMac, Hash, KexSuite,
+        KeyDeriveSide, KeyExchange, Nonce, PayloadCreating, RendezvousInfo, SigInfo,
+        TO1DataPayload, TO2AddressEntry, TO2ProveDevicePayload, TO2ProveOVHdrPayload,
+        TO2SetupDevicePayload, UnverifiedValue,
     },
     DeviceCredential, ProtocolVersion, Serializable,
 };
 use fdo_http_wrapper::client::{RequestResult, ServiceClient};
+use fdo_util::crypto_policy::CryptoPolicy;
 use fdo_util::device_credential_locations;
 use fdo_util::device_credential_locations::UsableDeviceCredentialLocation;
+use fdo_util::onboarding::{marker_file_location, OnboardingStatus};
 
 mod reencrypt;
 mod serviceinfo;
+mod state;
+mod systemd;
 
-const DEVICE_ONBOARDING_EXECUTED_MARKER_FILE: &str = "/etc/device_onboarding_performed";
-
-fn marker_file_location() -> PathBuf {
-    if let Ok(path) = env::var("DEVICE_ONBOARDING_EXECUTED_MARKER_FILE_PATH") {
-        PathBuf::from(path)
-    } else {
-        PathBuf::from(DEVICE_ONBOARDING_EXECUTED_MARKER_FILE)
-    }
-}
+use state::{FailureStage, OnboardingState};
 
 // Rendezvous delays related variables
 const RV_DEFAULT_DELAY_SEC: f32 = 120.0;
 const RV_DEFAULT_DELAY_OFFSET: f32 = 30.0;
 const RV_USER_DEFINED_DELAY_OFFSET: f32 = 0.25;
+// Attempts per rendezvous directive, with exponential backoff between them
+const RV_DIRECTIVE_ATTEMPTS: u32 = 3;
+const RV_RETRY_BASE_DELAY_SEC: f32 = 2.0;
+const RV_RETRY_MAX_DELAY_SEC: f32 = 60.0;
+// Timeout for resolving rendezvous DNS names, can be overridden with RV_DNS_TIMEOUT_SEC
+const RV_DEFAULT_DNS_TIMEOUT_SEC: u64 = 5;
 
 // Encapsulates errors caused during TO1/TO2
 #[derive(Debug)]
@@ -100,8 +104,16 @@ fn get_to2_urls(entries: &[TO2AddressEntry]) -> Vec<String> {
         let prot_text = match addr_entry.protocol() {
             TransportProtocol::Http => "http",
             TransportProtocol::Https => "https",
-            _ => continue,
+            _ => {
+                log::info!(
+                    "Skipping TO2 address with unsupported protocol: {:?}",
+                    addr_entry
+                );
+                continue;
+            }
         };
+        // An entry can have both a DNS name and an IP address: the DNS name is
+        // tried first, with the IP address as fallback
         if let Some(dns_name) = addr_entry.dns() {
             urls.push(format!(
                 "{}://{}:{}",
@@ -114,7 +126,7 @@ fn get_to2_urls(entries: &[TO2AddressEntry]) -> Vec<String> {
             urls.push(format!(
                 "{}://{}:{}",
                 prot_text,
-                ip_address,
+                ip_address.as_url_host(),
                 addr_entry.port()
             ));
         }
@@ -123,11 +135,68 @@ fn get_to2_urls(entries: &[TO2AddressEntry]) -> Vec<String> {
     urls
 }
 
+// The key exchange suite the Device asks for in TO2.HelloDevice, ASYMKEX
+// suites require an RSA Owner key of the matching size
+fn kex_suite() -> Result<KexSuite> {
+    match env::var("DEVICE_KEX_SUITE") {
+        Ok(suite) => suite
+            .parse()
+            .with_context(|| format!("Invalid DEVICE_KEX_SUITE {suite:?}")),
+        Err(_) => Ok(KexSuite::Ecdh384),
+    }
+}
+
+// Restricts the algorithms the Device accepts, with the settings of a server
+// crypto_policy section
+fn crypto_policy() -> Result<Option<CryptoPolicy>> {
+    env::var_os("DEVICE_CRYPTO_POLICY_PATH")
+        .map(|path| CryptoPolicy::load(path.as_ref()))
+        .transpose()
+}
+
+fn dns_timeout() -> time::Duration {
+    let timeout = env::var("RV_DNS_TIMEOUT_SEC")
+        .ok()
+        .and_then(|timeout| timeout.parse().ok())
+        .unwrap_or(RV_DEFAULT_DNS_TIMEOUT_SEC);
+    time::Duration::from_secs(timeout)
+}
+
+async fn dns_name_resolves(dns_name: &str, port: u32) -> bool {
+    let port = match u16::try_from(port) {
+        Ok(port) => port,
+        Err(_) => return false,
+    };
+    match tokio::time::timeout(dns_timeout(), tokio::net::lookup_host((dns_name, port))).await {
+        Ok(Ok(mut addrs)) => addrs.next().is_some(),
+        Ok(Err(e)) => {
+            log::debug!("Error resolving {}: {:?}", dns_name, e);
+            false
+        }
+        Err(_) => {
+            log::debug!("Timeout resolving {}", dns_name);
+            false
+        }
+    }
+}
+
 async fn get_client_list(rv_entry: &RendezvousInterpretedDirective) -> Result<Vec<ServiceClient>> {
     log::trace!("Getting client list from rv_entry {:?}", rv_entry);
     let mut service_client_list = Vec::new();
 
-    let urls = rv_entry.get_urls();
+    // Try the DNS name first, and the literal IP addresses if it can't be resolved
+    let mut urls = Vec::new();
+    if let Some(dns_name) = &rv_entry.dns_name {
+        if dns_name_resolves(dns_name, rv_entry.port).await {
+            urls.extend(rv_entry.get_dns_urls());
+        } else {
+            log::warn!(
+                "Unable to resolve rendezvous DNS name {}, trying IP addresses",
+                dns_name
+            );
+        }
+    }
+    urls.extend(rv_entry.get_ip_urls());
     if urls.is_empty() {
         log::trace!("No URLs found");
     }
@@ -140,16 +209,23 @@ async fn get_client_list(rv_entry: &RendezvousInterpretedDirective) -> Result<Ve
     if rv_entry.user_input {
         bail!("Rendezvous User Input is not yet implemented");
     }
-    if rv_entry.protocol != RendezvousProtocolValue::Http
-        && rv_entry.protocol != RendezvousProtocolValue::Https
-    {
-        bail!("Non-HTTP(S) protocol is not implemented");
+    if !matches!(
+        rv_entry.protocol,
+        RendezvousProtocolValue::Http
+            | RendezvousProtocolValue::Https
+            | RendezvousProtocolValue::Rest
+    ) {
+        bail!(
+            "Rendezvous protocol {:?} is not implemented",
+            rv_entry.protocol
+        );
     }
     for url in &urls {
-        service_client_list.push(fdo_http_wrapper::client::ServiceClient::new(
-            ProtocolVersion::Version1_1,
-            url,
-        ));
+        // Rendezvous servers may only speak protocol version 1.0
+        service_client_list.push(
+            fdo_http_wrapper::client::ServiceClient::new(ProtocolVersion::Version1_1, url)
+                .with_version_negotiation(),
+        );
     }
     log::trace!("Client list: {:?}", service_client_list);
     Ok(service_client_list)
@@ -315,9 +391,9 @@ fn get_rv_info(devcred: &dyn DeviceCredential) -> Result<Vec<RendezvousInterpret
 
 async fn get_to1d(
     devcred: &dyn DeviceCredential,
-    mut client_list: Vec<ServiceClient>,
+    client_list: &mut [ServiceClient],
 ) -> Result<COSESign> {
-    for client in client_list.as_mut_slice() {
+    for client in client_list.iter_mut() {
         match perform_to1(devcred, client)
             .await
             .context("Error performing TO1")
@@ -553,7 +629,8 @@ async fn get_and_verify_ov_header_signature(
     prove_ov_hdr_payload: &UnverifiedValue<TO2ProveOVHdrPayload>,
     header_hmac: HMac,
     to1d: &COSESign,
-) -> Result<TO2ProveOVHdrPayload, ClientError> {
+    crypto_policy: Option<&CryptoPolicy>,
+) -> Result<(TO2ProveOVHdrPayload, OwnershipVoucherHeader, PublicKey), ClientError> {
     // Get the other OV entries
     let ov_entries = get_ov_entries(
         client,
@@ -589,6 +666,20 @@ async fn get_and_verify_ov_header_signature(
         ownership_voucher
     );
 
+    if let Some(crypto_policy) = crypto_policy {
+        crypto_policy
+            .check_voucher(&ownership_voucher)
+            .context("Ownership voucher does not comply with the crypto policy")
+            .map_err(|e| {
+                ClientError::Response(ErrorResult::new(
+                    ErrorCode::InvalidMessageError,
+                    "Ownership voucher does not comply with the crypto policy",
+                    MessageType::TO2OVNextEntry,
+                    e,
+                ))
+            })?;
+    }
+
     // Get the last entry of the ownership voucher, this automatically validates everything (yay abstraction!)
     let ov_owner_entry = ownership_voucher
         .iter_entries()
@@ -624,7 +715,7 @@ async fn get_and_verify_ov_header_signature(
 
     // Now, we can finally verify the OV Header signature we got at the top!
     let prove_ov_hdr_payload: TO2ProveOVHdrPayload = prove_ov_hdr
-        .get_payload(ov_owner_entry.public_key().pkey())
+        .get_payload_with_public_key(ov_owner_entry.public_key())
         .context("Error validating ProveOVHdr signature")
         .map_err(|e| {
             ClientError::Response(ErrorResult::new(
@@ -640,7 +731,7 @@ async fn get_and_verify_ov_header_signature(
     );
 
     // Verify that to1d was signed by the current owner
-    to1d.verify(ov_owner_entry.public_key().pkey())
+    to1d.verify_with_public_key(ov_owner_entry.public_key())
         .context("Error validating to1d after receiving full ownership voucher")
         .map_err(|e| {
             ClientError::Response(ErrorResult::new(
@@ -650,12 +741,17 @@ async fn get_and_verify_ov_header_signature(
                 e,
             ))
         })?;
-    Ok(prove_ov_hdr_payload)
+    Ok((
+        prove_ov_hdr_payload,
+        ownership_voucher.header().clone(),
+        ov_owner_entry.public_key().clone(),
+    ))
 }
 
 async fn perform_key_derivation(
     client: &mut ServiceClient,
     prove_ov_hdr_payload: TO2ProveOVHdrPayload,
+    owner_pubkey: &PublicKey,
     kexsuite: KexSuite,
     ciphersuite: CipherSuite,
 ) -> Result<(KeyExchange, fdo_http_wrapper::EncryptionKeys), ClientError> {
@@ -671,7 +767,13 @@ async fn perform_key_derivation(
 
     // Perform the key derivation
     let a_key_exchange = prove_ov_hdr_payload.a_key_exchange();
-    let b_key_exchange = KeyExchange::new(kexsuite)
+    // For ASYMKEX, the Device random is encrypted to the Owner key
+    let b_key_exchange = if kexsuite.is_asymkex() {
+        KeyExchange::new_asymkex_device(kexsuite, owner_pubkey.pkey())
+    } else {
+        KeyExchange::new(kexsuite)
+    };
+    let b_key_exchange = b_key_exchange
         .context("Error creating device side of key exchange")
         .map_err(|e| {
             ClientError::Response(ErrorResult::new(
@@ -703,15 +805,106 @@ async fn perform_key_derivation(
     Ok((b_key_exchange, new_keys))
 }
 
+/// What happens to the device credential, as requested by the SetupDevice message
+enum CredentialUpdate {
+    /// Credential Reuse: the credential is kept, and stays active
+    Reuse,
+    /// The owner does not replace the credential
+    Keep,
+    /// The credential gets replaced after Done2, so the owner can resell the device
+    Replace {
+        guid: Guid,
+        rvinfo: RendezvousInfo,
+        pubkey_hash: Hash,
+        hmac: HMac,
+    },
+}
+
+fn process_setup_device(
+    devcred: &dyn DeviceCredential,
+    ov_header: &OwnershipVoucherHeader,
+    owner_pubkey: &PublicKey,
+    nonce7: &Nonce,
+    setup_device: &COSESign,
+) -> Result<CredentialUpdate> {
+    let owner2_key = setup_device
+        .get_payload_unverified::<TO2SetupDevicePayload>()
+        .context("Error parsing SetupDevice payload")?
+        .get_unverified_value()
+        .owner2_key()
+        .clone();
+    let payload: TO2SetupDevicePayload = setup_device
+        .get_payload_with_public_key(&owner2_key)
+        .context("Error validating SetupDevice signature")?;
+    if payload.nonce7() != nonce7 {
+        bail!("Nonce7 in SetupDevice did not match");
+    }
+
+    // Credential Reuse keeps the GUID, RendezvousInfo and owner key
+    if payload.guid() == devcred.device_guid()
+        && payload.owner2_key().pkey().public_eq(owner_pubkey.pkey())
+        && payload.rendezvous_info().serialize_data()?
+            == devcred.rendezvous_info().serialize_data()?
+    {
+        return Ok(CredentialUpdate::Reuse);
+    }
+    // A credential without RendezvousInfo could never be used to onboard again, so
+    // owners that are not interested in a replacement credential send an empty one
+    if payload.rendezvous_info().values().is_empty() {
+        return Ok(CredentialUpdate::Keep);
+    }
+
+    // Build the header of the voucher the owner creates for the new credential
+    let new_header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        payload.guid().clone(),
+        payload.rendezvous_info().clone(),
+        ov_header.device_info().to_string(),
+        payload.owner2_key().clone(),
+        ov_header.device_certificate_chain_hash().cloned(),
+    )
+    .context("Error building replacement ownership voucher header")?;
+    // The device HMAC keeps the strength of the device key, which is reflected by the
+    // device certificate chain hash, and the new owner key determines its hash type
+    let hmac_type = ov_header
+        .device_certificate_chain_hash()
+        .map(|hash| hash.get_type().hmac())
+        .unwrap_or(HashType::HmacSha384);
+    let pubkey_hash_type = payload
+        .owner2_key()
+        .hash_type()
+        .context("Unsupported owner key type")?;
+    let hmac = devcred
+        .perform_hmac(
+            &new_header
+                .serialize_data()
+                .context("Error serializing replacement ownership voucher header")?,
+            hmac_type,
+        )
+        .context("Error computing replacement HMAC")?;
+    let pubkey_hash = new_header
+        .manufacturer_public_key_hash(pubkey_hash_type)
+        .context("Error computing new owner public key hash")?;
+
+    Ok(CredentialUpdate::Replace {
+        guid: payload.guid().clone(),
+        rvinfo: payload.rendezvous_info().clone(),
+        pubkey_hash,
+        hmac,
+    })
+}
+
 /// TO2: Sends ProveDevice, Receives SetupDevice
 async fn perform_provedevice(
     devcred: &dyn DeviceCredential,
     client: &mut ServiceClient,
+    ov_header: &OwnershipVoucherHeader,
+    owner_pubkey: &PublicKey,
     b_key_exchange: KeyExchange,
     nonce6: &Nonce,
     nonce7: &Nonce,
     new_keys: fdo_http_wrapper::EncryptionKeys,
-) -> Result<(), ClientError> {
+) -> Result<CredentialUpdate, ClientError> {
     let prove_device_payload = TO2ProveDevicePayload::new(
         b_key_exchange
             .get_public()
@@ -791,14 +984,53 @@ async fn perform_provedevice(
     })?;
     log::trace!("Got setup_device response: {:?}", setup_device);
 
-    Ok(())
+    let credential_update = process_setup_device(
+        devcred,
+        ov_header,
+        owner_pubkey,
+        nonce7,
+        &setup_device.into_token(),
+    )
+    .map_err(|e| {
+        ClientError::Response(ErrorResult::new(
+            ErrorCode::InvalidMessageError,
+            "Error processing SetupDevice",
+            MessageType::TO2SetupDevice,
+            e,
+        ))
+    })?;
+    match credential_update {
+        CredentialUpdate::Reuse => {
+            log::info!("Owner requested Credential Reuse, keeping the device credential")
+        }
+        CredentialUpdate::Replace { ref guid, .. } => {
+            log::info!(
+                "Owner requested a credential replacement, new GUID {:?}",
+                guid
+            )
+        }
+        CredentialUpdate::Keep => {}
+    }
+
+    Ok(credential_update)
 }
 
-/// TO2: Sends DeviceServiceInfoReady, Receives OwnerService
-async fn perform_deviceserviceinfoready(client: &mut ServiceClient) -> Result<(), ClientError> {
+/// TO2: Sends DeviceServiceInfoReady, Receives OwnerServiceInfoReady, returning the
+/// largest DeviceServiceInfo the Owner accepts
+async fn perform_deviceserviceinfoready(
+    client: &mut ServiceClient,
+    replacement_hmac: Option<HMac>,
+) -> Result<Option<u64>, ClientError> {
+    // Devices on constrained links can ask for smaller OwnerServiceInfo messages
+    let max_owner_service_info_size = env::var("MAX_OWNER_SERVICE_INFO_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok());
     let owner_service_info_ready: RequestResult<messages::v11::to2::OwnerServiceInfoReady> = client
         .send_request(
-            messages::v11::to2::DeviceServiceInfoReady::new(None, None),
+            messages::v11::to2::DeviceServiceInfoReady::new(
+                replacement_hmac,
+                max_owner_service_info_size,
+            ),
             None,
         )
         .await;
@@ -816,7 +1048,7 @@ async fn perform_deviceserviceinfoready(client: &mut ServiceClient) -> Result<()
         "Received OwnerServiceInfoReady: {:?}",
         owner_service_info_ready
     );
-    Ok(())
+    Ok(owner_service_info_ready.max_device_service_info_size())
 }
 
 /// TO2: Sends Done, Receives Done2
@@ -852,6 +1084,7 @@ async fn perform_to2(
     devcred: &dyn DeviceCredential,
     url: &str,
     to1d: &COSESign,
+    crypto_policy: Option<&CryptoPolicy>,
 ) -> Result<bool> {
     log::info!("Performing TO2 protocol, URL: {:?}", url);
 
@@ -872,8 +1105,15 @@ async fn perform_to2(
     };
 
     let sigtype = DeviceSigType::StSECP384R1;
-    let kexsuite = KexSuite::Ecdh384;
+    let kexsuite = kex_suite()?;
+    log::trace!("Using key exchange suite {}", kexsuite.to_string());
     let ciphersuite = CipherSuite::A256Gcm;
+    if let Some(crypto_policy) = crypto_policy {
+        crypto_policy
+            .check_kex_suite(kexsuite)
+            .and_then(|_| crypto_policy.check_cipher_suite(ciphersuite))
+            .context("TO2 key exchange or cipher suite does not comply with the crypto policy")?;
+    }
 
     // Send: HelloDevice, Receive: ProveOVHdr
     let (prove_ov_hdr, prove_ov_hdr_payload, header_hmac) =
@@ -907,16 +1147,17 @@ async fn perform_to2(
         },
     };
     // Get OV and verify its signature
-    let prove_ov_hdr_payload = match get_and_verify_ov_header_signature(
+    let (prove_ov_hdr_payload, ov_header, owner_pubkey) = match get_and_verify_ov_header_signature(
         &mut client,
         &prove_ov_hdr,
         &prove_ov_hdr_payload,
         header_hmac,
         to1d,
+        crypto_policy,
     )
     .await
     {
-        Ok(payload) => payload,
+        Ok(values) => values,
         Err(e) => match e {
             ClientError::Request(e) => {
                 send_client_error(&mut client, &e).await;
@@ -933,6 +1174,7 @@ async fn perform_to2(
     let (b_key_exchange, new_keys) = match perform_key_derivation(
         &mut client,
         prove_ov_hdr_payload,
+        &owner_pubkey,
         kexsuite,
         ciphersuite,
     )
@@ -967,9 +1209,11 @@ async fn perform_to2(
     };
 
     // Send: ProveDevice, Receive: SetupDevice
-    match perform_provedevice(
+    let credential_update = match perform_provedevice(
         devcred,
         &mut client,
+        &ov_header,
+        &owner_pubkey,
         b_key_exchange,
         &nonce6,
         &nonce7,
@@ -977,7 +1221,7 @@ async fn perform_to2(
     )
     .await
     {
-        Ok(_) => (),
+        Ok(credential_update) => credential_update,
         Err(e) => match e {
             ClientError::Request(e) => {
                 send_client_error(&mut client, &e).await;
@@ -991,22 +1235,32 @@ async fn perform_to2(
     };
 
     // Send: DeviceServiceInfoReady, Receive: OwnerServiceInfoReady
-    match perform_deviceserviceinfoready(&mut client).await {
-        Ok(_) => (),
-        Err(e) => match e {
-            ClientError::Request(e) => {
-                send_client_error(&mut client, &e).await;
-                bail!(e.error);
-            }
-            ClientError::Response(e) => {
-                send_client_error(&mut client, &e).await;
-                bail!(e.error);
-            }
-        },
+    let replacement_hmac = match &credential_update {
+        CredentialUpdate::Replace { hmac, .. } => Some(hmac.clone()),
+        _ => None,
     };
+    let max_device_service_info_size =
+        match perform_deviceserviceinfoready(&mut client, replacement_hmac).await {
+            Ok(max_size) => max_size,
+            Err(e) => match e {
+                ClientError::Request(e) => {
+                    send_client_error(&mut client, &e).await;
+                    bail!(e.error);
+                }
+                ClientError::Response(e) => {
+                    send_client_error(&mut client, &e).await;
+                    bail!(e.error);
+                }
+            },
+        };
 
     // Now, the magic: performing the roundtrip! We delegated that.
-    let reboot_required = match serviceinfo::perform_to2_serviceinfos(&mut client).await {
+    let reboot_required = match serviceinfo::perform_to2_serviceinfos(
+        &mut client,
+        max_device_service_info_size,
+    )
+    .await
+    {
         Err(serviceinfo_err) => {
             log::error!("ServiceInfo failed, error: {:?}", serviceinfo_err);
             let e_result = ErrorResult::new(
@@ -1016,37 +1270,53 @@ async fn perform_to2(
                 anyhow!("Error performing the ServiceInfo roundtrips"),
             );
             send_client_error(&mut client, &e_result).await;
-            bail!(e_result.error);
+            return Err(e_result.error.context(FailureStage::ServiceInfo));
         }
         Ok(reboot) => reboot,
     };
     log::trace!("Got reboot_required: {reboot_required}");
 
-    if mark_device_onboarding_executed().is_err() {
-        let e_result = ErrorResult::new(
-            ErrorCode::InternalServerError,
-            "Error creating the device onboarding executed marker file",
-            MessageType::TO2OwnerServiceInfo,
-            anyhow!("Error creating the device onboarding executed marker file"),
-        );
-        send_client_error(&mut client, &e_result).await;
-        bail!(e_result.error);
-    }
+    // With Credential Reuse the device keeps its credential to onboard again later
+    if !matches!(credential_update, CredentialUpdate::Reuse) {
+        if mark_device_onboarding_executed().is_err() {
+            let e_result = ErrorResult::new(
+                ErrorCode::InternalServerError,
+                "Error creating the device onboarding executed marker file",
+                MessageType::TO2OwnerServiceInfo,
+                anyhow!("Error creating the device onboarding executed marker file"),
+            );
+            send_client_error(&mut client, &e_result).await;
+            bail!(e_result.error);
+        }
 
-    if devcredloc.deactivate().is_err() {
-        let e_result = ErrorResult::new(
-            ErrorCode::InternalServerError,
-            "Error deactivating device credential",
-            MessageType::TO2OwnerServiceInfo,
-            anyhow!("Error deactivating device credential"),
-        );
-        send_client_error(&mut client, &e_result).await;
-        bail!(e_result.error);
+        if devcredloc.deactivate().is_err() {
+            let e_result = ErrorResult::new(
+                ErrorCode::InternalServerError,
+                "Error deactivating device credential",
+                MessageType::TO2OwnerServiceInfo,
+                anyhow!("Error deactivating device credential"),
+            );
+            send_client_error(&mut client, &e_result).await;
+            bail!(e_result.error);
+        }
     }
 
     // Send: Done, Receive: Done2
     match perform_done(nonce7, nonce6, &mut client).await {
-        Ok(_) => Ok(reboot_required),
+        Ok(_) => {
+            if let CredentialUpdate::Replace {
+                guid,
+                rvinfo,
+                pubkey_hash,
+                ..
+            } = credential_update
+            {
+                devcredloc
+                    .replace(guid, rvinfo, pubkey_hash)
+                    .context("Error storing replaced device credential")?;
+            }
+            Ok(reboot_required)
+        }
         Err(e) => match e {
             ClientError::Request(e) => {
                 send_client_error(&mut client, &e).await;
@@ -1082,8 +1352,117 @@ fn sleep_between_retries(rv_entry_delay: u32) {
     thread::sleep(sleep_time);
 }
 
+fn get_backoff_delay(attempt: u32) -> time::Duration {
+    let mut rng = rand::thread_rng();
+    let delay = (RV_RETRY_BASE_DELAY_SEC * 2f32.powi(attempt as i32)).min(RV_RETRY_MAX_DELAY_SEC);
+    let delay = rng.gen_range(
+        delay * (1.0 - RV_USER_DEFINED_DELAY_OFFSET)..=delay * (1.0 + RV_USER_DEFINED_DELAY_OFFSET),
+    );
+    time::Duration::from_secs_f32(delay)
+}
+
+/// Tries to get the TO1D from the rendezvous servers of a directive, retrying
+/// with exponential backoff
+async fn get_to1d_with_retries(
+    devcred: &dyn DeviceCredential,
+    rv_entry: &RendezvousInterpretedDirective,
+) -> Result<COSESign> {
+    let mut client_list = get_client_list(rv_entry)
+        .await
+        .context("Error getting usable rendezvous client list")?;
+    if client_list.is_empty() {
+        bail!("No usable rendezvous server addresses");
+    }
+
+    let mut attempt = 0;
+    loop {
+        match get_to1d(devcred, &mut client_list).await {
+            Ok(to1d) => return Ok(to1d),
+            Err(e) => {
+                attempt += 1;
+                if attempt >= RV_DIRECTIVE_ATTEMPTS {
+                    return Err(e);
+                }
+                let backoff = get_backoff_delay(attempt - 1);
+                log::warn!(
+                    "{:?}, retrying in {} seconds (attempt {} of {})",
+                    e,
+                    backoff.as_secs(),
+                    attempt + 1,
+                    RV_DIRECTIVE_ATTEMPTS
+                );
+                thread::sleep(backoff);
+            }
+        }
+    }
+}
+
+/// How a run of the client ended, when it didn't fail
+enum Outcome {
+    Onboarded,
+    AlreadyOnboarded,
+    NoDeviceCredential,
+}
+
+impl Outcome {
+    fn exit_status(&self) -> i32 {
+        match self {
+            Outcome::Onboarded => 0,
+            Outcome::AlreadyOnboarded => systemd::EXIT_ALREADY_ONBOARDED,
+            Outcome::NoDeviceCredential => systemd::EXIT_NO_DEVICE_CREDENTIAL,
+        }
+    }
+}
+
+fn check_status() -> i32 {
+    match fdo_util::onboarding::check() {
+        Ok(status) => {
+            println!("{status}");
+            match status {
+                OnboardingStatus::Required => 0,
+                OnboardingStatus::AlreadyOnboarded | OnboardingStatus::CredentialInactive => {
+                    systemd::EXIT_ALREADY_ONBOARDED
+                }
+                OnboardingStatus::NoDeviceCredential => systemd::EXIT_NO_DEVICE_CREDENTIAL,
+                OnboardingStatus::TpmUnavailable => systemd::EXIT_TPM_UNAVAILABLE,
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            1
+        }
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    // Only reports whether onboarding is needed, in the exit status
+    if env::args().any(|arg| arg == "--check") {
+        fdo_http_wrapper::init_logging();
+        std::process::exit(check_status());
+    }
+
+    // Makes a single onboarding attempt, and tells apart the reasons for not
+    // onboarding in the exit status
+    let oneshot = env::args().any(|arg| arg == "--oneshot");
+
+    let result = run(oneshot).await;
+    fdo_http_wrapper::telemetry::shutdown().await;
+    match result {
+        Ok(outcome) => {
+            if oneshot {
+                std::process::exit(outcome.exit_status());
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            systemd::notify_status(&format!("Onboarding failed: {e}"));
+            std::process::exit(fdo_util::exit_status(&e));
+        }
+    }
+}
+
+async fn run(oneshot: bool) -> Result<Outcome> {
     fdo_util::add_version!();
     fdo_http_wrapper::init_logging();
 
@@ -1093,7 +1472,19 @@ async fn main() -> Result<()> {
         bail!("Provide environment ALLOW_NONINTEROPERABLE_KDF=1 to enable interoperable KDF");
     }
 
+    // Reactivates the device credential and ignores a previous onboarding, to
+    // onboard a device again, for example after it was moved to a new owner
+    let force_reonboard = env::args().any(|arg| arg == "--force-reonboard")
+        || env::var_os("FORCE_REONBOARD").is_some();
+
     let marker_file = marker_file_location();
+    if force_reonboard && marker_file.exists() {
+        log::info!(
+            "Forced re-onboarding, removing Device Onboarding marker file {:?}",
+            marker_file
+        );
+        fs::remove_file(&marker_file).context("Error removing marker file")?;
+    }
     if marker_file.exists() {
         log::info!(
             "Device Onboarding marker file {:?} exists, not rerunning FDO onboarding",
@@ -1101,13 +1492,15 @@ async fn main() -> Result<()> {
         );
         reencrypt::perform_required_reencrypts()
             .context("Error performing required re-encrypts")?;
-        return Ok(());
+        systemd::notify_ready("Device already onboarded");
+        return Ok(Outcome::AlreadyOnboarded);
     }
 
     let devcred_location = match device_credential_locations::find() {
         None => {
             log::info!("No usable device credential located, skipping Device Onboarding");
-            return Ok(());
+            systemd::notify_ready("No device credential found");
+            return Ok(Outcome::NoDeviceCredential);
         }
         Some(Err(e)) => {
             log::error!("Error opening device credential: {:?}", e);
@@ -1118,6 +1511,13 @@ async fn main() -> Result<()> {
 
     log::info!("Found device credential at {:?}", devcred_location);
 
+    if force_reonboard {
+        log::info!("Forced re-onboarding, reactivating device credential");
+        devcred_location
+            .reactivate()
+            .context("Error reactivating device credential")?;
+    }
+
     let dc = devcred_location
         .read()
         .context("Error reading device credential")?;
@@ -1125,7 +1525,8 @@ async fn main() -> Result<()> {
 
     if !dc.is_active() {
         log::info!("Device credential deactivated, skipping Device Onboarding");
-        return Ok(());
+        systemd::notify_ready("Device already onboarded");
+        return Ok(Outcome::AlreadyOnboarded);
     }
     if dc.protocol_version() != ProtocolVersion::Version1_1 {
         bail!(
@@ -1136,29 +1537,46 @@ async fn main() -> Result<()> {
 
     // Get rv entries
     let rv_info = get_rv_info(dc.as_ref())?;
+    let crypto_policy = crypto_policy().context("Error loading crypto policy")?;
+
+    let max_attempts = match state::max_attempts()? {
+        None if oneshot => Some(1),
+        max_attempts => max_attempts,
+    };
+    let mut onboarding_state = OnboardingState::load().unwrap_or_else(|e| {
+        log::warn!("{:?}, starting from a fresh onboarding state", e);
+        OnboardingState::default()
+    });
+    if let Some(last_failure) = onboarding_state.last_failure {
+        log::info!(
+            "Resuming onboarding after {} failed attempts, last failure: {}",
+            onboarding_state.attempts,
+            last_failure
+        );
+    }
 
     let mut onboarding_performed = false;
     let mut reboot_si_required = false;
-    let mut rv_entry_delay = 0;
+    let mut attempts = 0;
 
     loop {
-        for rv_entry in rv_info.iter() {
-            rv_entry_delay = rv_entry.delay;
+        // The furthest stage reached by any directive in this attempt
+        let mut failure_stage = FailureStage::Rendezvous;
+
+        // Directives are tried in order, waiting for the delay of a directive
+        // before moving on to the next one
+        for (pos, rv_entry) in rv_info.iter().enumerate() {
+            if pos != 0 && rv_info[pos - 1].delay != 0 {
+                sleep_between_retries(rv_info[pos - 1].delay);
+            }
 
-            let client_list = match get_client_list(rv_entry).await {
-                Ok(client_list) => client_list,
-                Err(e) => {
-                    log::error!(
-                        "Error {:?} getting usable rendezvous client list from rv_entry {:?}",
-                        e,
-                        rv_entry
-                    );
-                    continue;
-                }
-            };
+            systemd::notify_status(&format!(
+                "Contacting rendezvous servers (attempt {})",
+                attempts + 1
+            ));
 
             // Get owner info
-            let to1d = get_to1d(dc.as_ref(), client_list).await;
+            let to1d = get_to1d_with_retries(dc.as_ref(), rv_entry).await;
             let to1d = match to1d {
                 Ok(to1d) => to1d,
                 Err(e) => {
@@ -1184,6 +1602,14 @@ async fn main() -> Result<()> {
                 }
             };
 
+            if let Some(crypto_policy) = &crypto_policy {
+                let to0d_hash = to1d_payload.get_unverified_value().to1d_to_to0d_hash();
+                if let Err(e) = crypto_policy.check_hash_type(to0d_hash.get_type()) {
+                    log::error!("To1d from rv_entry {:?}: {}", rv_entry, e);
+                    continue;
+                }
+            }
+
             // Contact owner and perform ownership transfer
             let to2_addresses = to1d_payload.get_unverified_value().to2_addresses();
             let to2_addresses = get_to2_urls(to2_addresses);
@@ -1198,9 +1624,16 @@ async fn main() -> Result<()> {
             }
 
             for to2_address in to2_addresses {
-                match perform_to2(devcred_location.borrow(), dc.as_ref(), &to2_address, &to1d)
-                    .await
-                    .context("Error performing TO2 ownership protocol")
+                systemd::notify_status(&format!("Onboarding with owner {to2_address}"));
+                match perform_to2(
+                    devcred_location.borrow(),
+                    dc.as_ref(),
+                    &to2_address,
+                    &to1d,
+                    crypto_policy.as_ref(),
+                )
+                .await
+                .context("Error performing TO2 ownership protocol")
                 {
                     Ok(maybe_reboot) => {
                         onboarding_performed = true;
@@ -1211,6 +1644,11 @@ async fn main() -> Result<()> {
                     }
                     Err(e) => {
                         log::error!("{:?} with TO2 address {}", e, to2_address);
+                        let stage = e
+                            .downcast_ref::<FailureStage>()
+                            .copied()
+                            .unwrap_or(FailureStage::To2);
+                        failure_stage = failure_stage.max(stage);
                         continue;
                     }
                 }
@@ -1221,17 +1659,47 @@ async fn main() -> Result<()> {
         }
         if onboarding_performed {
             break;
-        } else {
-            sleep_between_retries(rv_entry_delay);
         }
+
+        attempts += 1;
+        onboarding_state.record_failure(failure_stage);
+        if let Err(e) = onboarding_state.save() {
+            log::warn!("Error saving onboarding state: {:?}", e);
+        }
+        if max_attempts.map_or(false, |max| attempts >= max) {
+            bail!(
+                "Onboarding failed after {} attempts, last failure: {}",
+                attempts,
+                failure_stage
+            );
+        }
+
+        // All directives failed: wait for the delay of the last one, or the
+        // default delay, doubled for every failed attempt, before starting over
+        let base_delay =
+            get_delay_between_retries(rv_info.last().map(|rv_entry| rv_entry.delay).unwrap_or(0));
+        let backoff = onboarding_state.backoff_delay(base_delay);
+        let status = format!(
+            "Onboarding attempt {} failed ({}), retrying in {} seconds",
+            onboarding_state.attempts,
+            failure_stage,
+            backoff.as_secs()
+        );
+        log::info!("{}", status);
+        systemd::notify_status(&status);
+        thread::sleep(backoff);
+    }
+    if let Err(e) = OnboardingState::clear() {
+        log::warn!("Error removing onboarding state: {:?}", e);
     }
     log::info!("Secure Device Onboarding DONE");
     log::info!("Reboot required? {}", reboot_si_required);
+    systemd::notify_ready("Device onboarding done");
     if reboot_si_required {
         Command::new("systemctl")
             .arg("reboot")
             .spawn()
             .expect("Reboot failed");
     }
-    Ok(())
+    Ok(Outcome::Onboarded)
 }
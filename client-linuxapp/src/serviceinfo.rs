@@ -1,10 +1,10 @@
 use std::process::{Command, Stdio};
 use std::{
     collections::HashSet,
-    fs::{File, Permissions},
-    io::Write,
+    fs::{File, OpenOptions, Permissions},
+    io::{Seek, SeekFrom, Write},
     path::Path,
-    str,
+    str::{self, FromStr},
 };
 use std::{env, fs};
 use std::{os::unix::fs::PermissionsExt, path::PathBuf};
@@ -17,17 +17,21 @@ use fdo_data_formats::{
         StandardServiceInfoModule,
     },
     messages::v11::to2::{DeviceServiceInfo, OwnerServiceInfo},
-    types::{CborSimpleTypeExt, Hash, ServiceInfo},
+    types::{CborSimpleType, CborSimpleTypeExt, Hash, ServiceInfo},
 };
 use fdo_http_wrapper::client::{RequestResult, ServiceClient};
 use fdo_util::passwd_shadow;
 
 const MAX_SERVICE_INFO_LOOPS: u32 = 1000;
+// The spec default, used when the Owner does not tell us its maximum
+const DEFAULT_MAX_DEVICE_SERVICE_INFO_SIZE: u64 = 1300;
 
 fn find_available_modules() -> Result<Vec<ServiceInfoModule>> {
     let mut module_list = vec![
         // These modules are always here
         StandardServiceInfoModule::DevMod.into(),
+        StandardServiceInfoModule::Download.into(),
+        StandardServiceInfoModule::Command.into(),
         FedoraIotServiceInfoModule::SSHKey.into(),
         FedoraIotServiceInfoModule::BinaryFile.into(),
         FedoraIotServiceInfoModule::Command.into(),
@@ -43,6 +47,21 @@ fn find_available_modules() -> Result<Vec<ServiceInfoModule>> {
         module_list.push(FedoraIotServiceInfoModule::DiskEncryptionClevis.into());
     }
 
+    // Allow the administrator to disable modules, devmod is mandatory
+    if let Ok(disabled) = env::var("DISABLED_SERVICEINFO_MODULES") {
+        let disabled: HashSet<ServiceInfoModule> = disabled
+            .split(',')
+            .map(|module| module.trim())
+            .filter(|module| !module.is_empty())
+            .map(ServiceInfoModule::from_str)
+            .collect::<Result<_, _>>()
+            .context("Error parsing DISABLED_SERVICEINFO_MODULES")?;
+        module_list.retain(|module| {
+            *module == ServiceInfoModule::from(StandardServiceInfoModule::DevMod)
+                || !disabled.contains(module)
+        });
+    }
+
     Ok(module_list)
 }
 
@@ -289,6 +308,176 @@ impl<'a> BinaryFileInProgress<'a> {
     }
 }
 
+#[derive(Debug)]
+struct DownloadInProgress {
+    path: Option<String>,
+    length: Option<u64>,
+    mode: Option<u32>,
+    owner: Option<String>,
+    group: Option<String>,
+    destination: Option<PathBuf>,
+    partial_path: Option<PathBuf>,
+    partial_file: Option<File>,
+    digest: Option<Hash>,
+    written: u64,
+}
+
+impl DownloadInProgress {
+    fn new() -> Self {
+        DownloadInProgress {
+            path: None,
+            length: None,
+            mode: None,
+            owner: None,
+            group: None,
+            destination: None,
+            partial_path: None,
+            partial_file: None,
+            digest: None,
+            written: 0,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.partial_file.is_some() && Some(self.written) == self.length
+    }
+
+    /// The partial file is named after the digest, so we only ever resume the same contents
+    fn partial_path(destination: &Path, digest: &Hash) -> Result<PathBuf> {
+        let file_name = destination
+            .file_name()
+            .context("Download path has no file name")?
+            .to_string_lossy();
+        let digest_prefix: String = digest.value_bytes()[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Ok(destination.with_file_name(format!(".{file_name}.{digest_prefix}.fdo-download")))
+    }
+
+    fn start(&mut self, prefix: Option<&str>, si_out: &mut ServiceInfo) -> Result<()> {
+        let path = self
+            .path
+            .as_ref()
+            .context("Got download sha-384 before name")?;
+        let length = self.length.context("Got download sha-384 before length")?;
+        let destination = BinaryFileInProgress::destination_path(path, prefix)?;
+        let partial_path =
+            DownloadInProgress::partial_path(&destination, self.digest.as_ref().unwrap())?;
+
+        fs::create_dir_all(destination.parent().unwrap())
+            .context("Error creating file's directory")?;
+
+        // Anything left over from an earlier, interrupted, onboarding can be resumed
+        let resume_at = match fs::metadata(&partial_path) {
+            Ok(metadata) if metadata.len() <= length => metadata.len(),
+            _ => 0,
+        };
+        let mut partial_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&partial_path)
+            .context("Error creating partial download file")?;
+        partial_file
+            .set_len(resume_at)
+            .context("Error truncating partial download file")?;
+        partial_file
+            .seek(SeekFrom::End(0))
+            .context("Error seeking in partial download file")?;
+
+        if resume_at != 0 {
+            log::info!(
+                "Resuming download of {:?} at byte {}",
+                destination,
+                resume_at
+            );
+            si_out.add(StandardServiceInfoModule::Download, "resume", &resume_at)?;
+        }
+
+        self.destination = Some(destination);
+        self.partial_path = Some(partial_path);
+        self.partial_file = Some(partial_file);
+        self.written = resume_at;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        let partial_file = match self.partial_file.as_mut() {
+            None => bail!("Got download data before sha-384"),
+            Some(file) => file,
+        };
+        if self.written + data.len() as u64 > self.length.unwrap() {
+            bail!(
+                "Got more download data than the announced {} bytes",
+                self.length.unwrap()
+            );
+        }
+        partial_file
+            .write_all(data)
+            .context("Error writing partial download file")?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn deploy(self, si_out: &mut ServiceInfo) -> Result<()> {
+        let destination = self.destination.unwrap();
+        let partial_path = self.partial_path.unwrap();
+        let mode = self.mode.unwrap_or(0o600);
+
+        let partial_file = self.partial_file.unwrap();
+        partial_file
+            .sync_all()
+            .context("Error syncing partial download file")?;
+        drop(partial_file);
+
+        let contents = fs::read(&partial_path).context("Error reading partial download file")?;
+        if let Err(e) = self.digest.as_ref().unwrap().compare_data(&contents) {
+            // Don't try to resume corrupted contents next time
+            let _ = fs::remove_file(&partial_path);
+            bail!(
+                "Got download (path {:?}) with invalid digest: {:?}",
+                destination,
+                e
+            );
+        }
+
+        log::info!(
+            "Creating file {:?} with {} bytes (mode {:?})",
+            destination,
+            self.written,
+            mode
+        );
+
+        fs::set_permissions(&partial_path, Permissions::from_mode(mode))
+            .context("Error setting file permissions")?;
+        let uid = match &self.owner {
+            None => None,
+            Some(owner) => Some(nix::unistd::Uid::from(
+                passwd_shadow::get_user_uid_gid_home(owner)
+                    .with_context(|| format!("Error looking up file owner {owner}"))?
+                    .0,
+            )),
+        };
+        let gid = match &self.group {
+            None => None,
+            Some(group) => Some(
+                nix::unistd::Group::from_name(group)
+                    .with_context(|| format!("Error looking up file group {group}"))?
+                    .with_context(|| format!("File group {group} does not exist"))?
+                    .gid,
+            ),
+        };
+        if uid.is_some() || gid.is_some() {
+            nix::unistd::chown(&partial_path, uid, gid).context("Error setting file ownership")?;
+        }
+        fs::rename(&partial_path, &destination).context("Error moving file into place")?;
+
+        si_out.add(StandardServiceInfoModule::Download, "done", &self.written)?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct DiskEncryptionInProgress {
     disk_label: Option<String>,
@@ -382,58 +571,87 @@ impl DiskEncryptionInProgress {
     }
 }
 
+// Command output is returned in pieces, so it fits the ServiceInfo messages
+const COMMAND_OUTPUT_CHUNK_SIZE: usize = 1024;
+
 #[derive(Debug)]
 struct CommandInProgress {
+    module: ServiceInfoModule,
     command: Option<String>,
     args: Vec<String>,
+    env: Vec<(String, String)>,
+    stdin: Option<Vec<u8>>,
     may_fail: bool,
     return_stdout: bool,
     return_stderr: bool,
 }
 
 impl CommandInProgress {
-    fn new() -> Self {
+    fn new(module: ServiceInfoModule) -> Self {
         CommandInProgress {
+            module,
             command: None,
             args: Vec::new(),
+            env: Vec::new(),
+            stdin: None,
             may_fail: false,
             return_stdout: false,
             return_stderr: false,
         }
     }
 
+    fn add_output(&self, si_out: &mut ServiceInfo, key: &str, output: &[u8]) -> Result<()> {
+        for chunk in output.chunks(COMMAND_OUTPUT_CHUNK_SIZE) {
+            si_out.add(self.module.clone(), key, &serde_bytes::Bytes::new(chunk))?;
+        }
+        Ok(())
+    }
+
     fn execute(self, si_out: &mut ServiceInfo) -> Result<()> {
         si_out.add(
-            FedoraIotServiceInfoModule::Command,
+            self.module.clone(),
             "command",
             self.command.as_ref().unwrap(),
         )?;
-        si_out.add(FedoraIotServiceInfoModule::Command, "args", &self.args)?;
+        si_out.add(self.module.clone(), "args", &self.args)?;
 
         let mut cmd = Command::new(self.command.as_ref().unwrap());
-        cmd.args(&self.args);
+        cmd.args(&self.args)
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .stdin(if self.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
 
-        let output = cmd.output().context("Error running command")?;
+        let mut child = cmd.spawn().context("Error running command")?;
+        let stdin_writer = match (child.stdin.take(), self.stdin.clone()) {
+            // Write from a thread, so a command filling its stdout can't deadlock us
+            (Some(mut child_stdin), Some(stdin)) => {
+                Some(std::thread::spawn(move || child_stdin.write_all(&stdin)))
+            }
+            _ => None,
+        };
+        let output = child.wait_with_output().context("Error running command")?;
+        if let Some(stdin_writer) = stdin_writer {
+            if let Err(e) = stdin_writer.join().unwrap() {
+                log::warn!("Error writing command stdin: {:?}", e);
+            }
+        }
 
         if self.return_stdout {
-            si_out.add(
-                FedoraIotServiceInfoModule::Command,
-                "stdout",
-                &serde_bytes::Bytes::new(&output.stdout),
-            )?;
+            self.add_output(si_out, "stdout", &output.stdout)?;
         }
         if self.return_stderr {
-            si_out.add(
-                FedoraIotServiceInfoModule::Command,
-                "stderr",
-                &serde_bytes::Bytes::new(&output.stderr),
-            )?;
+            self.add_output(si_out, "stderr", &output.stderr)?;
         }
-        si_out.add(
-            FedoraIotServiceInfoModule::Command,
-            "exit_code",
-            &output.status.code(),
-        )?;
+        let exit_code_key = match self.module {
+            ServiceInfoModule::Standard(_) => "exitcode",
+            _ => "exit_code",
+        };
+        si_out.add(self.module.clone(), exit_code_key, &output.status.code())?;
 
         if self.may_fail || output.status.success() {
             Ok(())
@@ -448,357 +666,695 @@ impl CommandInProgress {
     }
 }
 
-async fn process_serviceinfo_in(si_in: &ServiceInfo, si_out: &mut ServiceInfo) -> Result<bool> {
-    let mut active_modules: HashSet<ServiceInfoModule> = HashSet::new();
+/// The device side of a ServiceInfo module
+trait ServiceInfoModuleHandler {
+    /// Processes a single key sent by the Owner for this module
+    fn handle(&mut self, key: &str, value: &CborSimpleType, si_out: &mut ServiceInfo)
+        -> Result<()>;
+
+    /// Called after all ServiceInfo in a message was processed, if the module is still active
+    fn finish(&mut self, _si_out: &mut ServiceInfo) -> Result<()> {
+        Ok(())
+    }
 
-    let mut sshkey_user: Option<String> = None;
-    let mut sshkey_password: Option<String> = None;
-    let mut sshkey_keys: Option<String> = None;
+    fn reboot_requested(&self) -> bool {
+        false
+    }
+}
 
-    let mut rhsm_organization_id: Option<String> = None;
-    let mut rhsm_activation_key: Option<String> = None;
-    let mut rhsm_perform_insights: Option<bool> = None;
+fn new_module_handler<'a>(
+    module: &ServiceInfoModule,
+    binary_file_prefix: Option<&'a str>,
+) -> Option<Box<dyn ServiceInfoModuleHandler + 'a>> {
+    match module {
+        ServiceInfoModule::FedoraIot(FedoraIotServiceInfoModule::SSHKey) => {
+            Some(Box::new(SSHKeyModule::default()))
+        }
+        ServiceInfoModule::FedoraIot(FedoraIotServiceInfoModule::Reboot) => {
+            Some(Box::new(RebootModule::default()))
+        }
+        ServiceInfoModule::FedoraIot(FedoraIotServiceInfoModule::BinaryFile) => {
+            Some(Box::new(BinaryFileModule {
+                prefix: binary_file_prefix,
+                in_progress: BinaryFileInProgress::new(binary_file_prefix),
+            }))
+        }
+        ServiceInfoModule::Standard(StandardServiceInfoModule::Download) => {
+            Some(Box::new(DownloadModule {
+                prefix: binary_file_prefix,
+                in_progress: DownloadInProgress::new(),
+            }))
+        }
+        ServiceInfoModule::Standard(StandardServiceInfoModule::Command)
+        | ServiceInfoModule::FedoraIot(FedoraIotServiceInfoModule::Command) => {
+            Some(Box::new(CommandModule {
+                module: module.clone(),
+                in_progress: CommandInProgress::new(module.clone()),
+            }))
+        }
+        ServiceInfoModule::FedoraIot(FedoraIotServiceInfoModule::DiskEncryptionClevis) => {
+            Some(Box::new(DiskEncryptionModule {
+                in_progress: DiskEncryptionInProgress::new(),
+            }))
+        }
+        ServiceInfoModule::RedHatCom(RedHatComServiceInfoModule::SubscriptionManager) => {
+            Some(Box::new(SubscriptionManagerModule::default()))
+        }
+        _ => None,
+    }
+}
 
-    let binary_file_prefix_owned = env::var("BINARYFILE_PATH_PREFIX").ok();
-    let mut binary_file_in_progress =
-        BinaryFileInProgress::new(binary_file_prefix_owned.as_deref());
-    let mut command_in_progress = CommandInProgress::new();
-    let mut disk_encryption_in_progress = DiskEncryptionInProgress::new();
+#[derive(Debug, Default)]
+struct SSHKeyModule {
+    user: Option<String>,
+    password: Option<String>,
+    keys: Option<String>,
+}
 
-    let mut reboot_requested = false;
+impl ServiceInfoModuleHandler for SSHKeyModule {
+    fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        _si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        if key == "username" {
+            let value = value.as_str().context("Error parsing username value")?;
+            self.user = Some(value.to_string());
+            log::info!("Username is: {value}");
+        } else if key == "password" {
+            let value = value.as_str().context("Error parsing password value")?;
+            self.password = Some(value.to_string());
+            log::info!("Password is present");
+        } else if key == "sshkeys" {
+            let value = value.as_str().context("Error parsing sshkey value")?;
+            self.keys = Some(value.to_string());
+            log::info!("Keys are present");
+        }
+        Ok(())
+    }
 
-    for (module, key, value) in si_in.iter() {
-        log::trace!("Got module {}, command {}, value {:?}", module, key, value);
-        if key == "active" {
-            let value = value.as_bool().context("Error parsing active value")?;
-            if value {
-                log::trace!("Activating module {}", module);
-                active_modules.insert(module);
-            } else {
-                log::trace!("Deactivating module {}", module);
-                active_modules.remove(&module);
+    fn finish(&mut self, _si_out: &mut ServiceInfo) -> Result<()> {
+        let user = match &self.user {
+            None => bail!("SSHkey module missing username"),
+            Some(user) => user,
+        };
+        if self.keys.is_none() && self.password.is_none() {
+            bail!("SSHkey module missing password and key");
+        }
+        if let Some(password) = &self.password {
+            log::info!("SSHkey module was active, creating user with password");
+            create_user_with_password(user, password)
+                .context(format!("Error creating new user with password: {user}"))?;
+        }
+        if let Some(keys) = &self.keys {
+            log::info!("SSHkey module was active, installing SSH keys");
+            create_user(user).context(format!("Error creating new user: {user}"))?;
+            for key in keys.split(';') {
+                install_ssh_key(user, key).context("Error installing SSH key")?;
+                log::info!("Installed sshkey: {key}");
             }
-            continue;
         }
-        if !active_modules.contains(&module) {
-            log::trace!("Skipping non-activated module {}", module);
-            bail!("Non-activated module {} got request", module);
-        }
-        if module == FedoraIotServiceInfoModule::SSHKey.into() {
-            if key == "username" {
-                let value = value.as_str().context("Error parsing username value")?;
-                sshkey_user = Some(value.to_string());
-                log::info!("Username is: {value}");
-            } else if key == "password" {
-                let value = value.as_str().context("Error parsing password value")?;
-                sshkey_password = Some(value.to_string());
-                log::info!("Password is present");
-            } else if key == "sshkeys" {
-                let value = value.as_str().context("Error parsing sshkey value")?;
-                sshkey_keys = Some(value.to_string());
-                log::info!("Keys are present");
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct RebootModule {
+    reboot_requested: bool,
+}
+
+impl ServiceInfoModuleHandler for RebootModule {
+    fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        _si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        if key == "reboot" {
+            let value = value.as_bool().context("Error parsing reboot value")?;
+            self.reboot_requested = value;
+            log::trace!("Got reboot value: {value}");
+        }
+        Ok(())
+    }
+
+    fn reboot_requested(&self) -> bool {
+        self.reboot_requested
+    }
+}
+
+#[derive(Debug, Default)]
+struct SubscriptionManagerModule {
+    organization_id: Option<String>,
+    activation_key: Option<String>,
+    perform_insights: Option<bool>,
+}
+
+impl ServiceInfoModuleHandler for SubscriptionManagerModule {
+    fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        _si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        if key == "organization_id" {
+            let value = value
+                .as_str()
+                .with_context(|| format!("Error parsing rhsm {key} value"))?;
+            self.organization_id = Some(value.to_string());
+        } else if key == "activation_key" {
+            let value = value
+                .as_str()
+                .with_context(|| format!("Error parsing rhsm {key} value"))?;
+            self.activation_key = Some(value.to_string());
+        } else if key == "perform_insights" {
+            let value = value
+                .as_bool()
+                .with_context(|| format!("Error parsing rhsm {key} value"))?;
+            self.perform_insights = Some(value);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _si_out: &mut ServiceInfo) -> Result<()> {
+        log::debug!("RHSM module was active, running RHSM");
+        match (
+            &self.organization_id,
+            &self.activation_key,
+            self.perform_insights,
+        ) {
+            (Some(organization_id), Some(activation_key), Some(perform_insights)) => {
+                perform_rhsm(organization_id, activation_key, perform_insights)
+                    .context("Error performing RHSM enrollment")
             }
-        } else if module == FedoraIotServiceInfoModule::Reboot.into() {
-            if key == "reboot" {
-                let value = value.as_bool().context("Error parsing reboot value")?;
-                reboot_requested = value;
-                log::trace!("Got reboot value: {value}");
+            _ => bail!("Missing one of the RHSM module configurations"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BinaryFileModule<'a> {
+    prefix: Option<&'a str>,
+    in_progress: BinaryFileInProgress<'a>,
+}
+
+impl ServiceInfoModuleHandler for BinaryFileModule<'_> {
+    fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        _si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        let binary_file_in_progress = &mut self.in_progress;
+        if key == "name" {
+            if binary_file_in_progress.path.is_some() {
+                bail!(
+                    "Got binary file path {:?} after path {:?}",
+                    value,
+                    binary_file_in_progress.path
+                );
             }
-        } else if module == RedHatComServiceInfoModule::SubscriptionManager.into() {
-            if key == "organization_id" {
-                let value = value
+            binary_file_in_progress.path = Some(
+                value
                     .as_str()
-                    .with_context(|| format!("Error parsing rhsm {key} value"))?;
-                rhsm_organization_id = Some(value.to_string());
-            } else if key == "activation_key" {
-                let value = value
-                    .as_str()
-                    .with_context(|| format!("Error parsing rhsm {key} value"))?;
-                rhsm_activation_key = Some(value.to_string());
-            } else if key == "perform_insights" {
-                let value = value
-                    .as_bool()
-                    .with_context(|| format!("Error parsing rhsm {key} value"))?;
-                rhsm_perform_insights = Some(value);
+                    .context("Error parsing binary file name")?
+                    .to_string(),
+            );
+        } else if key == "length" {
+            if binary_file_in_progress.length.is_some() {
+                bail!(
+                    "Got binary file length {:?} after length {:?}",
+                    value,
+                    binary_file_in_progress.length
+                );
             }
-        } else if module == FedoraIotServiceInfoModule::BinaryFile.into() {
-            if key == "name" {
-                if binary_file_in_progress.path.is_some() {
-                    bail!(
-                        "Got binary file path {:?} after path {:?}",
-                        value,
-                        binary_file_in_progress.path
-                    );
-                }
-                binary_file_in_progress.path = Some(
-                    value
-                        .as_str()
-                        .context("Error parsing binary file name")?
-                        .to_string(),
+            binary_file_in_progress.length =
+                Some(value.as_u64().context("Error parsing binary file length")?);
+            binary_file_in_progress.contents = Some(Vec::with_capacity(
+                binary_file_in_progress.length.unwrap() as usize,
+            ));
+        } else if key.starts_with("data") {
+            if binary_file_in_progress.contents.is_none() {
+                bail!("Got binary file data before length {:?}", value);
+            }
+            binary_file_in_progress
+                .contents
+                .as_mut()
+                .unwrap()
+                .extend_from_slice(value.as_bytes().context("Error parsing binary file data")?);
+        } else if key == "mode" {
+            if binary_file_in_progress.mode.is_some() {
+                bail!(
+                    "Got binary file mode {:?} after mode {:?}",
+                    value,
+                    binary_file_in_progress.mode
                 );
-            } else if key == "length" {
-                if binary_file_in_progress.length.is_some() {
-                    bail!(
-                        "Got binary file length {:?} after length {:?}",
-                        value,
-                        binary_file_in_progress.length
-                    );
-                }
-                binary_file_in_progress.length =
-                    Some(value.as_u64().context("Error parsing binary file length")?);
-                binary_file_in_progress.contents = Some(Vec::with_capacity(
-                    binary_file_in_progress.length.unwrap() as usize,
-                ));
-            } else if key.starts_with("data") {
-                if binary_file_in_progress.contents.is_none() {
-                    bail!("Got binary file data before length {:?}", value);
-                }
-                binary_file_in_progress
-                    .contents
-                    .as_mut()
-                    .unwrap()
-                    .extend_from_slice(value.as_bytes().context("Error parsing binary file data")?);
-            } else if key == "mode" {
-                if binary_file_in_progress.mode.is_some() {
-                    bail!(
-                        "Got binary file mode {:?} after mode {:?}",
-                        value,
-                        binary_file_in_progress.mode
-                    );
-                }
-                binary_file_in_progress.mode =
-                    Some(value.as_u32().context("Error parsing binary file mode")?);
-            } else if key.starts_with("sha-") {
-                let sha_type = key.split('-').nth(1).unwrap();
-                let sha_value = value
-                    .as_bytes()
-                    .with_context(|| format!("Error parsing binary file sha-{sha_type} value"))?;
-                let hasher = match sha_type {
-                    "256" => HashType::Sha256,
-                    "384" => HashType::Sha384,
-                    _ => {
-                        bail!("Unknown sha-{}", sha_type);
-                    }
-                };
-                binary_file_in_progress.digest =
-                    Some(Hash::from_digest(hasher, sha_value.to_vec())?);
-
-                // We got the full file, check it and add it to the files to get deployed
-                if binary_file_in_progress.path.is_none() {
-                    bail!("Got binary file sha-{} before name", sha_type);
-                }
-                if binary_file_in_progress.length.is_none() {
-                    bail!("Got binary file sha-{} before length", sha_type);
-                }
-                let read_bytes = binary_file_in_progress.contents.as_ref().unwrap().len();
-                if read_bytes != binary_file_in_progress.length.unwrap() as usize {
-                    bail!(
-                        "Got binary file (path {}) with length {} but only {} bytes of data",
-                        binary_file_in_progress.path.as_ref().unwrap(),
-                        binary_file_in_progress.length.unwrap(),
-                        read_bytes
-                    );
-                }
-                if let Err(e) = binary_file_in_progress
-                    .digest
-                    .as_ref()
-                    .unwrap()
-                    .compare_data(binary_file_in_progress.contents.as_ref().unwrap())
-                {
-                    bail!(
-                        "Got binary file (path {}) with invalid digest: {:?}",
-                        binary_file_in_progress.path.as_ref().unwrap(),
-                        e
-                    );
+            }
+            binary_file_in_progress.mode =
+                Some(value.as_u32().context("Error parsing binary file mode")?);
+        } else if key.starts_with("sha-") {
+            let sha_type = key.split('-').nth(1).unwrap();
+            let sha_value = value
+                .as_bytes()
+                .with_context(|| format!("Error parsing binary file sha-{sha_type} value"))?;
+            let hasher = match sha_type {
+                "256" => HashType::Sha256,
+                "384" => HashType::Sha384,
+                _ => {
+                    bail!("Unknown sha-{}", sha_type);
                 }
+            };
+            binary_file_in_progress.digest = Some(Hash::from_digest(hasher, sha_value.to_vec())?);
 
-                binary_file_in_progress
-                    .deploy()
-                    .context("Error deploying binary file")?;
-                binary_file_in_progress =
-                    BinaryFileInProgress::new(binary_file_prefix_owned.as_deref());
+            // We got the full file, check it and add it to the files to get deployed
+            if binary_file_in_progress.path.is_none() {
+                bail!("Got binary file sha-{} before name", sha_type);
             }
-        } else if module == FedoraIotServiceInfoModule::Command.into() {
-            if key == "command" {
-                if command_in_progress.command.is_some() {
-                    bail!(
-                        "Got command {:?} after command {:?}",
-                        value,
-                        command_in_progress.command
-                    );
-                }
-                command_in_progress.command =
-                    Some(value.as_str().context("Error parsing command")?.to_string());
-            } else if key == "args" {
-                command_in_progress.args =
-                    value.as_str_array().context("Error parsing command args")?;
-            } else if key == "may_fail" {
-                command_in_progress.may_fail =
-                    value.as_bool().context("Error parsing command may_fail")?;
-            } else if key == "return_stdout" {
-                command_in_progress.return_stdout = value
-                    .as_bool()
-                    .context("Error parsing command return_stdout")?;
-            } else if key == "return_stderr" {
-                command_in_progress.return_stderr = value
-                    .as_bool()
-                    .context("Error parsing command return_stderr")?;
-            } else if key == "execute" {
-                command_in_progress
-                    .execute(si_out)
-                    .context("Error executing command")?;
-                command_in_progress = CommandInProgress::new();
+            if binary_file_in_progress.length.is_none() {
+                bail!("Got binary file sha-{} before length", sha_type);
             }
-        } else if module == FedoraIotServiceInfoModule::DiskEncryptionClevis.into() {
-            if key == "disk-label" {
-                if disk_encryption_in_progress.disk_label.is_some() {
-                    bail!(
-                        "Got clevis disk-label {:?} after disk-label {:?}",
-                        value,
-                        disk_encryption_in_progress.disk_label
-                    );
-                }
-                disk_encryption_in_progress.disk_label = Some(
-                    value
-                        .as_str()
-                        .context("Error parsing clevis disk-label")?
-                        .to_string(),
+            let read_bytes = binary_file_in_progress.contents.as_ref().unwrap().len();
+            if read_bytes != binary_file_in_progress.length.unwrap() as usize {
+                bail!(
+                    "Got binary file (path {}) with length {} but only {} bytes of data",
+                    binary_file_in_progress.path.as_ref().unwrap(),
+                    binary_file_in_progress.length.unwrap(),
+                    read_bytes
                 );
-            } else if key == "pin" {
-                if disk_encryption_in_progress.pin.is_some() {
-                    bail!(
-                        "Got clevis pin {:?} after pin {:?}",
-                        value,
-                        disk_encryption_in_progress.pin
-                    );
-                }
-                disk_encryption_in_progress.pin = Some(
-                    value
-                        .as_str()
-                        .context("Error parsing clevis pin")?
-                        .to_string(),
+            }
+            if let Err(e) = binary_file_in_progress
+                .digest
+                .as_ref()
+                .unwrap()
+                .compare_data(binary_file_in_progress.contents.as_ref().unwrap())
+            {
+                bail!(
+                    "Got binary file (path {}) with invalid digest: {:?}",
+                    binary_file_in_progress.path.as_ref().unwrap(),
+                    e
                 );
-            } else if key == "config" {
-                if disk_encryption_in_progress.config.is_some() {
-                    bail!(
-                        "Got clevis config {:?} after config {:?}",
-                        value,
-                        disk_encryption_in_progress.config
-                    );
-                }
-                disk_encryption_in_progress.config = Some(
-                    value
-                        .as_str()
-                        .context("Error parsing clevis pin config")?
-                        .to_string(),
+            }
+
+            std::mem::replace(
+                &mut self.in_progress,
+                BinaryFileInProgress::new(self.prefix),
+            )
+            .deploy()
+            .context("Error deploying binary file")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct DownloadModule<'a> {
+    prefix: Option<&'a str>,
+    in_progress: DownloadInProgress,
+}
+
+impl DownloadModule<'_> {
+    fn deploy_if_complete(&mut self, si_out: &mut ServiceInfo) -> Result<()> {
+        if self.in_progress.is_complete() {
+            std::mem::replace(&mut self.in_progress, DownloadInProgress::new())
+                .deploy(si_out)
+                .context("Error deploying downloaded file")?;
+        }
+        Ok(())
+    }
+}
+
+impl ServiceInfoModuleHandler for DownloadModule<'_> {
+    fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        let download_in_progress = &mut self.in_progress;
+        if key == "name" {
+            if download_in_progress.path.is_some() {
+                bail!(
+                    "Got download name {:?} after name {:?}",
+                    value,
+                    download_in_progress.path
                 );
-            } else if key == "reencrypt" {
-                disk_encryption_in_progress.reencrypt =
-                    value.as_bool().context("Error parsing clevis reencrypt")?;
-            } else if key == "execute" {
-                disk_encryption_in_progress
-                    .execute(si_out)
-                    .context("Error executing clevis")?;
-                disk_encryption_in_progress = DiskEncryptionInProgress::new();
             }
+            download_in_progress.path = Some(
+                value
+                    .as_str()
+                    .context("Error parsing download name")?
+                    .to_string(),
+            );
+        } else if key == "length" {
+            download_in_progress.length =
+                Some(value.as_u64().context("Error parsing download length")?);
+        } else if key == "mode" {
+            download_in_progress.mode =
+                Some(value.as_u32().context("Error parsing download mode")?);
+        } else if key == "owner" {
+            download_in_progress.owner = Some(
+                value
+                    .as_str()
+                    .context("Error parsing download owner")?
+                    .to_string(),
+            );
+        } else if key == "group" {
+            download_in_progress.group = Some(
+                value
+                    .as_str()
+                    .context("Error parsing download group")?
+                    .to_string(),
+            );
+        } else if key == "sha-384" {
+            let sha_value = value
+                .as_bytes()
+                .context("Error parsing download sha-384 value")?;
+            download_in_progress.digest =
+                Some(Hash::from_digest(HashType::Sha384, sha_value.to_vec())?);
+            download_in_progress.start(self.prefix, si_out)?;
+            // Empty or fully resumed files won't get any data
+            self.deploy_if_complete(si_out)?;
+        } else if key == "data" {
+            download_in_progress.write(value.as_bytes().context("Error parsing download data")?)?;
+            self.deploy_if_complete(si_out)?;
         }
+        Ok(())
     }
 
-    // Perform SSH or password setup
-    if active_modules.contains(&FedoraIotServiceInfoModule::SSHKey.into()) {
-        if sshkey_user.is_none() {
-            bail!("SSHkey module missing username");
-        } else if sshkey_keys.is_none() && sshkey_password.is_none() {
-            bail!("SSHkey module missing password and key");
+    fn finish(&mut self, _si_out: &mut ServiceInfo) -> Result<()> {
+        if let Some(path) = &self.in_progress.path {
+            bail!("Download of {} was not completed", path);
         }
-        if sshkey_password.is_some() {
-            log::info!("SSHkey module was active, creating user with password");
-            create_user_with_password(
-                sshkey_user.as_ref().unwrap(),
-                sshkey_password.as_ref().unwrap(),
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct CommandModule {
+    module: ServiceInfoModule,
+    in_progress: CommandInProgress,
+}
+
+impl ServiceInfoModuleHandler for CommandModule {
+    fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        let command_in_progress = &mut self.in_progress;
+        if key == "command" {
+            if command_in_progress.command.is_some() {
+                bail!(
+                    "Got command {:?} after command {:?}",
+                    value,
+                    command_in_progress.command
+                );
+            }
+            command_in_progress.command =
+                Some(value.as_str().context("Error parsing command")?.to_string());
+        } else if key == "args" {
+            command_in_progress.args =
+                value.as_str_array().context("Error parsing command args")?;
+        } else if key == "env" {
+            command_in_progress.env = value
+                .as_str_array()
+                .context("Error parsing command env")?
+                .iter()
+                .map(|var| match var.split_once('=') {
+                    Some((key, value)) => Ok((key.to_string(), value.to_string())),
+                    None => Err(anyhow!(
+                        "Invalid command env entry {var}, expected KEY=VALUE"
+                    )),
+                })
+                .collect::<Result<_>>()?;
+        } else if key == "stdin" {
+            command_in_progress.stdin = Some(
+                value
+                    .as_bytes()
+                    .context("Error parsing command stdin")?
+                    .to_vec(),
+            );
+        } else if key == "may_fail" {
+            command_in_progress.may_fail =
+                value.as_bool().context("Error parsing command may_fail")?;
+        } else if key == "return_stdout" {
+            command_in_progress.return_stdout = value
+                .as_bool()
+                .context("Error parsing command return_stdout")?;
+        } else if key == "return_stderr" {
+            command_in_progress.return_stderr = value
+                .as_bool()
+                .context("Error parsing command return_stderr")?;
+        } else if key == "execute" {
+            std::mem::replace(
+                &mut self.in_progress,
+                CommandInProgress::new(self.module.clone()),
             )
-            .context(format!(
-                "Error creating new user with password: {}",
-                sshkey_user.as_ref().unwrap()
-            ))?;
+            .execute(si_out)
+            .context("Error executing command")?;
         }
-        if let Some(sshkey_keys) = sshkey_keys {
-            log::info!("SSHkey module was active, installing SSH keys");
-            create_user(sshkey_user.as_ref().unwrap()).context(format!(
-                "Error creating new user: {}",
-                sshkey_user.as_ref().unwrap()
-            ))?;
-            let sshkey_keys_v: Vec<String> =
-                sshkey_keys.split(';').map(|s| s.to_string()).collect();
-            for key in sshkey_keys_v {
-                let key_s: String = key;
-                install_ssh_key(sshkey_user.as_ref().unwrap(), key_s.as_str())
-                    .context("Error installing SSH key")?;
-                log::info!("Installed sshkey: {key_s}");
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct DiskEncryptionModule {
+    in_progress: DiskEncryptionInProgress,
+}
+
+impl ServiceInfoModuleHandler for DiskEncryptionModule {
+    fn handle(
+        &mut self,
+        key: &str,
+        value: &CborSimpleType,
+        si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        let disk_encryption_in_progress = &mut self.in_progress;
+        if key == "disk-label" {
+            if disk_encryption_in_progress.disk_label.is_some() {
+                bail!(
+                    "Got clevis disk-label {:?} after disk-label {:?}",
+                    value,
+                    disk_encryption_in_progress.disk_label
+                );
+            }
+            disk_encryption_in_progress.disk_label = Some(
+                value
+                    .as_str()
+                    .context("Error parsing clevis disk-label")?
+                    .to_string(),
+            );
+        } else if key == "pin" {
+            if disk_encryption_in_progress.pin.is_some() {
+                bail!(
+                    "Got clevis pin {:?} after pin {:?}",
+                    value,
+                    disk_encryption_in_progress.pin
+                );
             }
+            disk_encryption_in_progress.pin = Some(
+                value
+                    .as_str()
+                    .context("Error parsing clevis pin")?
+                    .to_string(),
+            );
+        } else if key == "config" {
+            if disk_encryption_in_progress.config.is_some() {
+                bail!(
+                    "Got clevis config {:?} after config {:?}",
+                    value,
+                    disk_encryption_in_progress.config
+                );
+            }
+            disk_encryption_in_progress.config = Some(
+                value
+                    .as_str()
+                    .context("Error parsing clevis pin config")?
+                    .to_string(),
+            );
+        } else if key == "reencrypt" {
+            disk_encryption_in_progress.reencrypt =
+                value.as_bool().context("Error parsing clevis reencrypt")?;
+        } else if key == "execute" {
+            std::mem::replace(&mut self.in_progress, DiskEncryptionInProgress::new())
+                .execute(si_out)
+                .context("Error executing clevis")?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps track of the modules activated by the Owner over all ServiceInfo loops
+struct ServiceInfoModules<'a> {
+    enabled_modules: &'a [ServiceInfoModule],
+    binary_file_prefix: Option<&'a str>,
+    // Handlers are kept in activation order, so they are also finished in that order
+    handlers: Vec<(ServiceInfoModule, Box<dyn ServiceInfoModuleHandler + 'a>)>,
+    active_modules: HashSet<ServiceInfoModule>,
+}
+
+impl<'a> ServiceInfoModules<'a> {
+    fn new(enabled_modules: &'a [ServiceInfoModule], binary_file_prefix: Option<&'a str>) -> Self {
+        ServiceInfoModules {
+            enabled_modules,
+            binary_file_prefix,
+            handlers: Vec::new(),
+            active_modules: HashSet::new(),
         }
     }
 
-    // Perform RHSM
-    if active_modules.contains(&RedHatComServiceInfoModule::SubscriptionManager.into()) {
-        log::debug!("RHSM module was active, running RHSM");
-        if rhsm_organization_id.is_none()
-            || rhsm_activation_key.is_none()
-            || rhsm_perform_insights.is_none()
-        {
-            bail!("Missing one of the RHSM module configurations");
-        }
-        perform_rhsm(
-            rhsm_organization_id.as_ref().unwrap(),
-            rhsm_activation_key.as_ref().unwrap(),
-            rhsm_perform_insights.unwrap(),
-        )
-        .context("Error performing RHSM enrollment")?;
+    fn process_serviceinfo_in(
+        &mut self,
+        si_in: &ServiceInfo,
+        si_out: &mut ServiceInfo,
+    ) -> Result<()> {
+        for (module, key, value) in si_in.iter() {
+            log::trace!("Got module {}, command {}, value {:?}", module, key, value);
+            if key == "active" {
+                let value = value.as_bool().context("Error parsing active value")?;
+                if !value {
+                    log::trace!("Deactivating module {}", module);
+                    self.active_modules.remove(&module);
+                } else if !self.enabled_modules.contains(&module) {
+                    log::info!("Owner tried to activate disabled module {}", module);
+                    si_out.add(module, "active", &false)?;
+                } else {
+                    log::trace!("Activating module {}", module);
+                    if !self.handlers.iter().any(|(m, _)| m == &module) {
+                        if let Some(handler) = new_module_handler(&module, self.binary_file_prefix)
+                        {
+                            self.handlers.push((module.clone(), handler));
+                        }
+                    }
+                    self.active_modules.insert(module);
+                }
+                continue;
+            }
+            if !self.active_modules.contains(&module) {
+                log::trace!("Skipping non-activated module {}", module);
+                bail!("Non-activated module {} got request", module);
+            }
+            match self.handlers.iter_mut().find(|(m, _)| m == &module) {
+                Some((_, handler)) => handler.handle(&key, &value, si_out)?,
+                None => log::trace!("No handler for module {}, ignoring {}", module, key),
+            }
+        }
+        Ok(())
     }
 
-    Ok(reboot_requested)
+    /// Finishes all modules still active, returning whether any of them requested a reboot
+    fn finish(&mut self, si_out: &mut ServiceInfo) -> Result<bool> {
+        let mut reboot_requested = false;
+        for (module, handler) in self.handlers.iter_mut() {
+            if !self.active_modules.contains(&*module) {
+                continue;
+            }
+            handler
+                .finish(si_out)
+                .with_context(|| format!("Error finishing module {module}"))?;
+            reboot_requested |= handler.reboot_requested();
+        }
+        Ok(reboot_requested)
+    }
+}
+
+const DMI_PRODUCT_NAME: &str = "/sys/class/dmi/id/product_name";
+const DMI_PRODUCT_SERIAL: &str = "/sys/class/dmi/id/product_serial";
+const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+const DEVICE_TREE_SERIAL: &str = "/proc/device-tree/serial-number";
+
+/// Reads the first of `paths` that exists and isn't empty, e.g. a DMI or device tree property
+fn read_system_property(paths: &[&str]) -> Option<String> {
+    paths.iter().find_map(|path| {
+        let value = fs::read_to_string(path).ok()?;
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    })
+}
+
+/// Adds the mandatory devmod module, describing this device
+fn add_devmod(out_si: &mut ServiceInfo, modules: &[ServiceInfoModule]) -> Result<()> {
+    let version = match sys_info::linux_os_release() {
+        Ok(release) => release.pretty_name,
+        Err(e) => {
+            log::warn!("Error getting operating system information: {:?}", e);
+            None
+        }
+    };
+    let version = match version {
+        Some(version) => version,
+        None => sys_info::os_release().context("Error getting kernel release")?,
+    };
+    let device = read_system_property(&[DMI_PRODUCT_NAME, DEVICE_TREE_MODEL])
+        .unwrap_or_else(|| "unknown".to_string());
+
+    out_si.add(StandardServiceInfoModule::DevMod, "active", &true)?;
+    out_si.add(
+        StandardServiceInfoModule::DevMod,
+        "os",
+        &std::env::consts::OS,
+    )?;
+    out_si.add(
+        StandardServiceInfoModule::DevMod,
+        "arch",
+        &std::env::consts::ARCH,
+    )?;
+    out_si.add(StandardServiceInfoModule::DevMod, "version", &version)?;
+    out_si.add(StandardServiceInfoModule::DevMod, "device", &device)?;
+    // The serial number is usually only readable by root
+    if let Some(serial) = read_system_property(&[DMI_PRODUCT_SERIAL, DEVICE_TREE_SERIAL]) {
+        out_si.add(StandardServiceInfoModule::DevMod, "sn", &serial)?;
+    }
+    out_si.add(StandardServiceInfoModule::DevMod, "pathsep", &"/")?;
+    out_si.add(StandardServiceInfoModule::DevMod, "sep", &":")?;
+    out_si.add(StandardServiceInfoModule::DevMod, "nl", &"\n")?;
+    out_si.add(
+        StandardServiceInfoModule::DevMod,
+        "tmp",
+        &env::temp_dir().to_string_lossy(),
+    )?;
+    out_si.add(
+        StandardServiceInfoModule::DevMod,
+        "bin",
+        &std::env::consts::ARCH,
+    )?;
+    out_si.add_modules(modules)?;
+    Ok(())
 }
 
-pub(crate) async fn perform_to2_serviceinfos(client: &mut ServiceClient) -> Result<bool> {
+pub(crate) async fn perform_to2_serviceinfos(
+    client: &mut ServiceClient,
+    max_device_service_info_size: Option<u64>,
+) -> Result<bool> {
+    let max_size =
+        max_device_service_info_size.unwrap_or(DEFAULT_MAX_DEVICE_SERVICE_INFO_SIZE) as usize;
     let mut loop_num = 0;
     let mut out_si = ServiceInfo::new();
-    let mut reboot_required = false;
+    // OwnerServiceInfo sent with IsMoreServiceInfo, only processed once complete
+    let mut in_si = ServiceInfo::new();
+    let modules = find_available_modules().context("Error getting list of modules")?;
+    let binary_file_prefix = env::var("BINARYFILE_PATH_PREFIX").ok();
+    let mut module_handlers = ServiceInfoModules::new(&modules, binary_file_prefix.as_deref());
 
     while loop_num < MAX_SERVICE_INFO_LOOPS {
         if loop_num == 0 {
-            let modules = find_available_modules().context("Error getting list of modules")?;
-            let sysinfo = sys_info::linux_os_release()
-                .context("Error getting operating system information")?;
-
-            // We just blindly send the devmod module
-            out_si.add(StandardServiceInfoModule::DevMod, "active", &true)?;
-            out_si.add(
-                StandardServiceInfoModule::DevMod,
-                "os",
-                &std::env::consts::OS,
-            )?;
-            out_si.add(
-                StandardServiceInfoModule::DevMod,
-                "arch",
-                &std::env::consts::ARCH,
-            )?;
-            out_si.add(
-                StandardServiceInfoModule::DevMod,
-                "version",
-                &sysinfo.pretty_name.unwrap(),
-            )?;
-            out_si.add(StandardServiceInfoModule::DevMod, "device", &"unused")?;
-            out_si.add(StandardServiceInfoModule::DevMod, "sep", &":")?;
-            out_si.add(
-                StandardServiceInfoModule::DevMod,
-                "bin",
-                &std::env::consts::ARCH,
-            )?;
-            out_si.add_modules(&modules)?;
-        }
-
-        let send_si = DeviceServiceInfo::new(false, out_si);
-        out_si = ServiceInfo::new();
+            add_devmod(&mut out_si, &modules).context("Error adding devmod information")?;
+        }
+
+        // Whatever doesn't fit in this message is sent in the next loops
+        let (page, rest, _) = out_si
+            .split_page(max_size, false, |_, _| false)
+            .context("Error splitting ServiceInfo")?;
+        let is_more_service_info = !rest.is_empty();
+        out_si = rest;
+
+        let send_si = DeviceServiceInfo::new(is_more_service_info, page);
         log::trace!("Sending ServiceInfo loop {}: {:?}", loop_num, send_si);
 
         let return_si: RequestResult<OwnerServiceInfo> = client.send_request(send_si, None).await;
@@ -806,22 +1362,34 @@ pub(crate) async fn perform_to2_serviceinfos(client: &mut ServiceClient) -> Resu
             return_si.with_context(|| format!("Error during ServiceInfo loop {loop_num}"))?;
         log::trace!("Got ServiceInfo loop {}: {:?}", loop_num, return_si);
 
+        if is_more_service_info {
+            if return_si.is_done() || !return_si.service_info().is_empty() {
+                bail!("Owner sent ServiceInfo before receiving all of ours");
+            }
+            loop_num += 1;
+            continue;
+        }
         if return_si.is_done() {
             log::trace!("ServiceInfo loops done, number taken: {}", loop_num);
-            return Ok(reboot_required);
+            // Modules may span multiple loops, so only finish them once the Owner is done
+            return module_handlers
+                .finish(&mut out_si)
+                .context("Error finishing ServiceInfo modules");
         }
+        in_si
+            .append_split(return_si.service_info())
+            .context("Error joining OwnerServiceInfo")?;
         if return_si.is_more_service_info() {
-            // TODO
-            bail!("OwnerServiceInfo indicated it has more for us.. we don't support that yet");
+            // Ask for the rest with an empty DeviceServiceInfo
+            loop_num += 1;
+            continue;
         }
 
         // Process
-        let reboot_si = process_serviceinfo_in(return_si.service_info(), &mut out_si)
-            .await
+        let complete_si = std::mem::take(&mut in_si);
+        module_handlers
+            .process_serviceinfo_in(&complete_si, &mut out_si)
             .context("Error processing returned serviceinfo")?;
-        if !reboot_required {
-            reboot_required = reboot_si;
-        }
 
         loop_num += 1;
     }
@@ -855,6 +1423,93 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_download_resume() {
+        let prefix = tempfile::tempdir().unwrap();
+        let prefix_str = prefix.path().to_str().unwrap();
+        let contents: Vec<u8> = (0..=255).cycle().take(3000).collect();
+        let digest = Hash::from_data(HashType::Sha384, &contents).unwrap();
+
+        let start_download = |module: &mut DownloadModule, si_out: &mut ServiceInfo| {
+            module
+                .handle("name", &CborSimpleType::Text("/etc/file".into()), si_out)
+                .unwrap();
+            module
+                .handle("length", &CborSimpleType::Integer(3000), si_out)
+                .unwrap();
+            module
+                .handle(
+                    "sha-384",
+                    &CborSimpleType::Bytes(digest.value_bytes().to_vec()),
+                    si_out,
+                )
+                .unwrap();
+        };
+
+        // The connection drops after the first chunk
+        let mut si_out = ServiceInfo::new();
+        let mut module = DownloadModule {
+            prefix: Some(prefix_str),
+            in_progress: DownloadInProgress::new(),
+        };
+        start_download(&mut module, &mut si_out);
+        assert!(si_out.is_empty());
+        module
+            .handle(
+                "data",
+                &CborSimpleType::Bytes(contents[..1000].to_vec()),
+                &mut si_out,
+            )
+            .unwrap();
+        assert!(module.finish(&mut si_out).is_err());
+
+        // The next attempt resumes after that chunk
+        let mut si_out = ServiceInfo::new();
+        let mut module = DownloadModule {
+            prefix: Some(prefix_str),
+            in_progress: DownloadInProgress::new(),
+        };
+        start_download(&mut module, &mut si_out);
+        let (_, key, value) = si_out.iter().next().unwrap();
+        assert_eq!(key, "resume");
+        assert_eq!(value.as_u64(), Some(1000));
+        module
+            .handle(
+                "data",
+                &CborSimpleType::Bytes(contents[1000..].to_vec()),
+                &mut si_out,
+            )
+            .unwrap();
+        assert!(module.finish(&mut si_out).is_ok());
+        let (_, key, value) = si_out.iter().nth(1).unwrap();
+        assert_eq!(key, "done");
+        assert_eq!(value.as_u64(), Some(3000));
+
+        assert_eq!(fs::read(prefix.path().join("etc/file")).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_command_env_stdin() {
+        let mut command = CommandInProgress::new(StandardServiceInfoModule::Command.into());
+        command.command = Some("sh".to_string());
+        command.args = vec![
+            "-c".to_string(),
+            "read line; echo $GREETING $line".to_string(),
+        ];
+        command.env = vec![("GREETING".to_string(), "hello".to_string())];
+        command.stdin = Some(b"world\n".to_vec());
+        command.return_stdout = true;
+
+        let mut si_out = ServiceInfo::new();
+        command.execute(&mut si_out).unwrap();
+
+        let values: Vec<_> = si_out.iter().map(|(_, key, value)| (key, value)).collect();
+        assert_eq!(values[2].0, "stdout");
+        assert_eq!(values[2].1.as_bytes(), Some(&b"hello world\n"[..]));
+        assert_eq!(values[3].0, "exitcode");
+        assert_eq!(values[3].1.as_i64(), Some(0));
+    }
+
     #[test]
     fn test_pw_encryption() {
         let type_5_encryption = "$5$ML4hMHtER3/SY9D2$2eWHscoFbfVebDC32qA2dPo3pD6FFM6CRTrvAOMpwQ";
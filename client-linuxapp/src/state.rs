@@ -0,0 +1,161 @@
+use std::{
+    env, fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+
+const ONBOARDING_STATE_FILE: &str = "/etc/device_onboarding_state";
+// Upper limit of the backoff between onboarding attempts
+const MAX_BACKOFF_SEC: u64 = 3600;
+
+fn state_file_location() -> PathBuf {
+    if let Ok(path) = env::var("DEVICE_ONBOARDING_STATE_FILE_PATH") {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from(ONBOARDING_STATE_FILE)
+    }
+}
+
+/// Maximum number of onboarding attempts in a single run of the client, set
+/// with `ONBOARDING_MAX_ATTEMPTS`. None (or 0) means retrying forever.
+pub(crate) fn max_attempts() -> Result<Option<u32>> {
+    match env::var("ONBOARDING_MAX_ATTEMPTS") {
+        Err(_) => Ok(None),
+        Ok(value) => match value.parse::<u32>() {
+            Ok(0) => Ok(None),
+            Ok(max) => Ok(Some(max)),
+            Err(_) => bail!("Invalid ONBOARDING_MAX_ATTEMPTS value: {}", value),
+        },
+    }
+}
+
+/// The stage at which an onboarding attempt failed, ordered by how far the
+/// attempt got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum FailureStage {
+    Rendezvous,
+    To2,
+    ServiceInfo,
+}
+
+impl FailureStage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FailureStage::Rendezvous => "rendezvous",
+            FailureStage::To2 => "to2",
+            FailureStage::ServiceInfo => "serviceinfo",
+        }
+    }
+}
+
+impl FromStr for FailureStage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rendezvous" => Ok(FailureStage::Rendezvous),
+            "to2" => Ok(FailureStage::To2),
+            "serviceinfo" => Ok(FailureStage::ServiceInfo),
+            _ => bail!("Unknown failure stage {}", s),
+        }
+    }
+}
+
+impl fmt::Display for FailureStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureStage::Rendezvous => write!(f, "Rendezvous server unreachable"),
+            FailureStage::To2 => write!(f, "TO2 rejected"),
+            FailureStage::ServiceInfo => write!(f, "ServiceInfo failed"),
+        }
+    }
+}
+
+/// Onboarding progress kept across runs of the client, so that a client
+/// started again (for example by a systemd timer) keeps backing off.
+#[derive(Debug, Default)]
+pub(crate) struct OnboardingState {
+    pub(crate) attempts: u32,
+    pub(crate) last_failure: Option<FailureStage>,
+    // Seconds since the UNIX epoch
+    pub(crate) last_attempt: Option<u64>,
+}
+
+impl OnboardingState {
+    /// Loads the state, or an empty state if none was saved.
+    pub(crate) fn load() -> Result<Self> {
+        let path = state_file_location();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Error reading onboarding state {path:?}"))
+            }
+        };
+
+        let mut state = Self::default();
+        for line in contents.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "attempts" => state.attempts = value.parse().context("Invalid attempts")?,
+                "last_failure" => state.last_failure = Some(value.parse()?),
+                "last_attempt" => {
+                    state.last_attempt = Some(value.parse().context("Invalid last_attempt")?)
+                }
+                _ => log::debug!("Ignoring unknown onboarding state key {}", key),
+            }
+        }
+        Ok(state)
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let mut contents = format!("attempts={}\n", self.attempts);
+        if let Some(last_failure) = self.last_failure {
+            contents.push_str(&format!("last_failure={}\n", last_failure.as_str()));
+        }
+        if let Some(last_attempt) = self.last_attempt {
+            contents.push_str(&format!("last_attempt={last_attempt}\n"));
+        }
+
+        let path = state_file_location();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .with_context(|| format!("Error writing onboarding state {tmp_path:?}"))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Error writing onboarding state {path:?}"))
+    }
+
+    /// Removes the saved state, after a successful onboarding.
+    pub(crate) fn clear() -> Result<()> {
+        let path = state_file_location();
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Error removing onboarding state {path:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn record_failure(&mut self, stage: FailureStage) {
+        self.attempts = self.attempts.saturating_add(1);
+        self.last_failure = Some(stage);
+        self.last_attempt = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since_epoch| since_epoch.as_secs());
+    }
+
+    /// Delay before the next attempt: `base_delay_sec` doubled for every
+    /// failed attempt after the first one, capped at one hour.
+    pub(crate) fn backoff_delay(&self, base_delay_sec: u64) -> Duration {
+        let exponent = self.attempts.saturating_sub(1).min(16);
+        let delay = base_delay_sec.saturating_mul(1 << exponent);
+        Duration::from_secs(delay.min(MAX_BACKOFF_SEC.max(base_delay_sec)))
+    }
+}
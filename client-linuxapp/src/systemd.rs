@@ -0,0 +1,54 @@
+use std::env;
+
+use anyhow::{Context, Result};
+use nix::sys::socket::{self, AddressFamily, MsgFlags, SockFlag, SockType, UnixAddr};
+
+// Exit statuses in oneshot and check modes, on top of the ones from fdo_util::exit_status
+pub(crate) const EXIT_ALREADY_ONBOARDED: i32 = 3;
+pub(crate) const EXIT_NO_DEVICE_CREDENTIAL: i32 = 4;
+pub(crate) const EXIT_TPM_UNAVAILABLE: i32 = 5;
+
+fn send_notification(state: &str) -> Result<()> {
+    let socket_path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket_path = socket_path
+        .to_str()
+        .context("NOTIFY_SOCKET is not valid UTF-8")?;
+    let addr = match socket_path.strip_prefix('@') {
+        Some(name) => UnixAddr::new_abstract(name.as_bytes()),
+        None => UnixAddr::new(socket_path),
+    }
+    .context("Invalid NOTIFY_SOCKET address")?;
+
+    let fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .context("Error creating notification socket")?;
+    let res = socket::sendto(fd, state.as_bytes(), &addr, MsgFlags::empty())
+        .context("Error sending notification");
+    let _ = nix::unistd::close(fd);
+    res.map(|_| ())
+}
+
+/// Sends `state` to the service manager with the sd_notify protocol, if the
+/// client runs as a `Type=notify` service.
+pub(crate) fn notify(state: &str) {
+    if let Err(e) = send_notification(state) {
+        log::debug!("Error notifying service manager: {:?}", e);
+    }
+}
+
+/// Updates the status shown by `systemctl status`.
+pub(crate) fn notify_status(status: &str) {
+    notify(&format!("STATUS={status}"));
+}
+
+/// Tells the service manager that the client is done, with a final status.
+pub(crate) fn notify_ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={status}"));
+}
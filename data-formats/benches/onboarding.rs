@@ -0,0 +1,226 @@
+//! Benchmarks of the operations on the onboarding hot path.
+//!
+//! Run with `cargo bench -p fdo-data-formats`. The voucher and COSE benchmarks
+//! are reported per key type, as e.g. `voucher/verify_entries/secp384r1`.
+
+use std::convert::TryFrom;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Private},
+    rsa::Rsa,
+    x509::{X509Builder, X509NameBuilder, X509},
+};
+
+use fdo_data_formats::{
+    constants::HashType,
+    cose::COSESign,
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::PublicKey,
+    types::{CipherSuite, Guid, HMac, KexSuite, KeyDeriveSide, KeyExchange, RendezvousInfo},
+    ProtocolVersion, Serializable,
+};
+
+// Number of owners the benchmark vouchers are extended to
+const VOUCHER_ENTRIES: usize = 3;
+
+#[derive(Clone, Copy)]
+enum KeyType {
+    Secp256r1,
+    Secp384r1,
+    Rsa2048,
+    Rsa3072,
+    Ed25519,
+}
+
+const KEY_TYPES: [KeyType; 5] = [
+    KeyType::Secp256r1,
+    KeyType::Secp384r1,
+    KeyType::Rsa2048,
+    KeyType::Rsa3072,
+    KeyType::Ed25519,
+];
+
+impl KeyType {
+    fn is_enabled(&self) -> bool {
+        !matches!(self, KeyType::Ed25519) || cfg!(feature = "ed25519")
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            KeyType::Secp256r1 => "secp256r1",
+            KeyType::Secp384r1 => "secp384r1",
+            KeyType::Rsa2048 => "rsa2048",
+            KeyType::Rsa3072 => "rsa3072",
+            KeyType::Ed25519 => "ed25519",
+        }
+    }
+
+    fn generate(&self) -> PKey<Private> {
+        let ec_key = |nid| {
+            let group = EcGroup::from_curve_name(nid).unwrap();
+            PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+        };
+        match self {
+            KeyType::Secp256r1 => ec_key(Nid::X9_62_PRIME256V1),
+            KeyType::Secp384r1 => ec_key(Nid::SECP384R1),
+            KeyType::Rsa2048 => PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            KeyType::Rsa3072 => PKey::from_rsa(Rsa::generate(3072).unwrap()).unwrap(),
+            KeyType::Ed25519 => PKey::generate_ed25519().unwrap(),
+        }
+    }
+}
+
+/// A generated key with a self-signed certificate
+struct Party {
+    key: PKey<Private>,
+    public_key: PublicKey,
+}
+
+impl Party {
+    fn generate(key_type: KeyType) -> Self {
+        let key = key_type.generate();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "FDO benchmark").unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let digest = if key.id() == Id::ED25519 {
+            MessageDigest::null()
+        } else {
+            MessageDigest::sha384()
+        };
+        builder.sign(&key, digest).unwrap();
+        let cert: X509 = builder.build();
+
+        Party {
+            public_key: PublicKey::try_from(cert).unwrap(),
+            key,
+        }
+    }
+}
+
+/// Builds a voucher from a generated manufacturer, extended to `VOUCHER_ENTRIES` owners
+fn build_voucher(key_type: KeyType) -> (OwnershipVoucher, Party) {
+    let manufacturer = Party::generate(key_type);
+    let header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        Guid::new().unwrap(),
+        RendezvousInfo::new(vec![]).unwrap(),
+        "benchmark-device".to_string(),
+        manufacturer.public_key.clone(),
+        None,
+    )
+    .unwrap();
+    let hmac = HMac::from_digest(HashType::HmacSha384, vec![0; 48]).unwrap();
+    let mut ov = OwnershipVoucher::new(header, hmac, None).unwrap();
+
+    let mut current = manufacturer;
+    for _ in 0..VOUCHER_ENTRIES {
+        let next = Party::generate(key_type);
+        ov.extend(&current.key, None, &next.public_key).unwrap();
+        current = next;
+    }
+    (ov, current)
+}
+
+fn bench_voucher(c: &mut Criterion) {
+    let mut group = c.benchmark_group("voucher");
+    for key_type in KEY_TYPES.into_iter().filter(KeyType::is_enabled) {
+        let (ov, _) = build_voucher(key_type);
+        let serialized = ov.serialize_data().unwrap();
+
+        group.bench_with_input(
+            BenchmarkId::new("parse", key_type.name()),
+            &serialized,
+            |b, serialized| b.iter(|| OwnershipVoucher::from_pem_or_raw(serialized).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("serialize", key_type.name()),
+            &ov,
+            |b, ov| b.iter(|| ov.serialize_data().unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("verify_entries", key_type.name()),
+            &ov,
+            |b, ov| {
+                b.iter(|| {
+                    for entry in ov.iter_entries().unwrap() {
+                        entry.unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_cose(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cose");
+    for key_type in KEY_TYPES.into_iter().filter(KeyType::is_enabled) {
+        let (ov, owner) = build_voucher(key_type);
+        let payload = ov.header().clone();
+        let signed = COSESign::new_with_pkey(&payload, None, &owner.key).unwrap();
+
+        group.bench_with_input(
+            BenchmarkId::new("sign", key_type.name()),
+            &payload,
+            |b, payload| b.iter(|| COSESign::new_with_pkey(payload, None, &owner.key).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("verify", key_type.name()),
+            &signed,
+            |b, signed| b.iter(|| signed.verify_with_public_key(&owner.public_key).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn bench_key_exchange(c: &mut Criterion) {
+    let mut group = c.benchmark_group("key_exchange");
+    let suites = [
+        ("ecdh256", KexSuite::Ecdh256, CipherSuite::A128Gcm),
+        ("ecdh384", KexSuite::Ecdh384, CipherSuite::A256Gcm),
+        ("dhkexid14", KexSuite::DhkexId14, CipherSuite::A128Gcm),
+        ("dhkexid15", KexSuite::DhkexId15, CipherSuite::A256Gcm),
+    ];
+    for (name, kexsuite, ciphersuite) in suites {
+        let owner_public = KeyExchange::new(kexsuite).unwrap().get_public().unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("device", name),
+            &owner_public,
+            |b, owner_public| {
+                b.iter(|| {
+                    let device = KeyExchange::new(kexsuite).unwrap();
+                    device.get_public().unwrap();
+                    device
+                        .derive_key(KeyDeriveSide::Device, ciphersuite, owner_public, false)
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_voucher, bench_cose, bench_key_exchange);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+#![no_main]
+
+use fdo_data_formats::{devicecredential::FileDeviceCredential, inspect, DeviceCredential};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = inspect::inspect_device_credential(data);
+
+    let devcred = match FileDeviceCredential::from_file_data(data) {
+        Ok(devcred) => devcred,
+        Err(_) => return,
+    };
+
+    // TPM credentials need a TPM to do anything more
+    if !devcred.requires_tpm() {
+        let _ = devcred.key_storage.public_key();
+        let _ = devcred.get_signer();
+    }
+    let _ = devcred.rendezvous_info();
+
+    devcred
+        .to_file_data()
+        .expect("Error serializing parsed device credential");
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+//! The first byte selects the key exchange suite, the rest is the public value of the
+//! other side, as received in HelloDevice/ProveDevice or DIUN Connect.
+
+use fdo_data_formats::types::{CipherSuite, KexSuite, KeyDeriveSide, KeyExchange};
+use libfuzzer_sys::fuzz_target;
+
+// DH and ASYMKEX are left out: DH key generation is too slow to run for every input,
+// and the ASYMKEX value is only passed to OpenSSL
+const SUITES: &[KexSuite] = &[KexSuite::Ecdh256, KexSuite::Ecdh384];
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let suite = SUITES[data[0] as usize % SUITES.len()];
+    let kex = KeyExchange::new(suite).expect("Error generating key exchange");
+    let _ = kex.derive_key(
+        KeyDeriveSide::OwnerService,
+        CipherSuite::A256Gcm,
+        &data[1..],
+        false,
+    );
+});
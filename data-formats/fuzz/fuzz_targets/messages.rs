@@ -0,0 +1,70 @@
+#![no_main]
+
+//! The first byte selects the wire format version (odd for 1.1, even for 1.0), the second
+//! one the message type, the rest is the message.
+
+use fdo_data_formats::{
+    messages::{
+        v11::{di, diun, to0, to1, to2, ErrorMessage},
+        Message,
+    },
+    ProtocolVersion,
+};
+use libfuzzer_sys::fuzz_target;
+
+fn parse<M: Message>(version: ProtocolVersion, data: &[u8]) {
+    if let Ok(message) = M::deserialize_for_version(version, data) {
+        let _ = message.serialize_for_version(version);
+    }
+}
+
+// New messages go at the end, so that the corpus keeps selecting the same messages
+const PARSERS: &[fn(ProtocolVersion, &[u8])] = &[
+    parse::<di::AppStart>,
+    parse::<di::SetCredentials>,
+    parse::<di::SetHMAC>,
+    parse::<di::Done>,
+    parse::<diun::Connect>,
+    parse::<diun::Accept>,
+    parse::<diun::RequestKeyParameters>,
+    parse::<diun::ProvideKeyParameters>,
+    parse::<diun::ProvideKey>,
+    parse::<diun::RequestAttestationChallenge>,
+    parse::<diun::AttestationChallenge>,
+    parse::<diun::ProvideAttestedKey>,
+    parse::<diun::Done>,
+    parse::<to0::Hello>,
+    parse::<to0::HelloAck>,
+    parse::<to0::OwnerSign>,
+    parse::<to0::AcceptOwner>,
+    parse::<to1::HelloRV>,
+    parse::<to1::HelloRVAck>,
+    parse::<to1::ProveToRV>,
+    parse::<to1::RVRedirect>,
+    parse::<to2::HelloDevice>,
+    parse::<to2::ProveOVHdr>,
+    parse::<to2::GetOVNextEntry>,
+    parse::<to2::OVNextEntry>,
+    parse::<to2::ProveDevice>,
+    parse::<to2::SetupDevice>,
+    parse::<to2::DeviceServiceInfoReady>,
+    parse::<to2::OwnerServiceInfoReady>,
+    parse::<to2::DeviceServiceInfo>,
+    parse::<to2::OwnerServiceInfo>,
+    parse::<to2::Done>,
+    parse::<to2::Done2>,
+    parse::<ErrorMessage>,
+];
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let version = if data[0] & 1 == 1 {
+        ProtocolVersion::Version1_1
+    } else {
+        ProtocolVersion::Version1_0
+    };
+    let parser = PARSERS[data[1] as usize % PARSERS.len()];
+    parser(version, &data[2..]);
+});
@@ -0,0 +1,32 @@
+#![no_main]
+
+use fdo_data_formats::{inspect, ownershipvoucher::OwnershipVoucher, Serializable};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = inspect::inspect_ownership_voucher(data);
+
+    let ov = match OwnershipVoucher::from_pem_or_raw(data) {
+        Ok(ov) => ov,
+        Err(_) => return,
+    };
+
+    // The accessors rely on the validation done while parsing
+    let _ = ov.header();
+    let _ = ov.device_certificate_chain();
+    for entry_num in 0..ov.num_entries() {
+        let _ = ov.entry(entry_num as usize);
+    }
+    if let Ok(entries) = ov.iter_entries() {
+        for entry in entries {
+            if entry.is_err() {
+                break;
+            }
+        }
+    }
+
+    let serialized = ov
+        .serialize_data()
+        .expect("Error serializing parsed voucher");
+    OwnershipVoucher::deserialize_data(&serialized).expect("Error parsing serialized voucher");
+});
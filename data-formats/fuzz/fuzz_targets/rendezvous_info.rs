@@ -0,0 +1,20 @@
+#![no_main]
+
+use fdo_data_formats::{
+    enhanced_types::RendezvousInterpreterSide, types::RendezvousInfo, Serializable,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let rvinfo = match RendezvousInfo::deserialize_data(data) {
+        Ok(rvinfo) => rvinfo,
+        Err(_) => return,
+    };
+
+    let _ = rvinfo.to_interpreted(RendezvousInterpreterSide::Device);
+    let _ = rvinfo.to_interpreted(RendezvousInterpreterSide::Owner);
+
+    rvinfo
+        .serialize_data()
+        .expect("Error serializing parsed rendezvous info");
+});
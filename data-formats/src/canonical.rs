@@ -0,0 +1,464 @@
+//! Canonical CBOR encoding (RFC 7049, section 3.9)
+//!
+//! Signatures and HMACs are computed over encoded CBOR, so two implementations
+//! only agree on them if they encode signed structures the same way. The
+//! canonical encoding uses definite lengths and the shortest form of every
+//! integer, length and tag, and sorts map keys by the length of their encoding
+//! first and then bytewise. This is the same order serde_cbor uses for the
+//! maps in its `Value`, like the COSE header maps.
+//!
+//! Floats are kept as they are encoded, no FDO structure contains any.
+//!
+//! Received signed structures are checked in the encoding they were received in,
+//! and rejected if it is not canonical. Vouchers created by implementations that
+//! don't sort map keys can be accepted, with a warning, after calling
+//! [`set_accept_non_canonical`].
+
+use std::convert::TryFrom;
+#[cfg(feature = "crypto")]
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{errors::Result, Error};
+
+// Deeper nesting than this is not used by any FDO structure
+const MAX_DEPTH: usize = 64;
+
+static ACCEPT_NON_CANONICAL: AtomicBool = AtomicBool::new(false);
+
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+const INDEFINITE: u8 = 31;
+const BREAK: u8 = 0xff;
+
+/// Re-encodes a single CBOR item in the canonical encoding
+pub fn canonicalize(data: &[u8]) -> Result<Vec<u8>> {
+    let mut input = data;
+    let out = Encoder::new(false).encode(&mut input, 0)?;
+    if !input.is_empty() {
+        return Err(Error::InconsistentValue("Trailing data after CBOR item"));
+    }
+    Ok(out)
+}
+
+/// Checks that a single CBOR item uses the canonical encoding
+///
+/// Returns [`Error::NonCanonicalEncoding`] with the first deviation found.
+pub fn check_canonical(data: &[u8]) -> Result<()> {
+    let mut input = data;
+    Encoder::new(true).encode(&mut input, 0)?;
+    if !input.is_empty() {
+        return Err(Error::InconsistentValue("Trailing data after CBOR item"));
+    }
+    Ok(())
+}
+
+/// Whether a single CBOR item uses the canonical encoding
+pub fn is_canonical(data: &[u8]) -> bool {
+    check_canonical(data).is_ok()
+}
+
+/// Sets whether received signed structures that are not canonically encoded are
+/// accepted, for the whole process
+///
+/// They are rejected by default. When accepted, a warning is logged for each.
+pub fn set_accept_non_canonical(accept: bool) {
+    ACCEPT_NON_CANONICAL.store(accept, Ordering::Relaxed);
+}
+
+/// Whether received signed structures that are not canonically encoded are accepted
+pub fn accepts_non_canonical() -> bool {
+    ACCEPT_NON_CANONICAL.load(Ordering::Relaxed)
+}
+
+/// Checks the encoding of a received structure, as received
+///
+/// Signatures and HMACs are checked over the encoding as received if it is accepted.
+/// Invalid CBOR is left for the parser to report.
+#[cfg(feature = "crypto")]
+pub(crate) fn check_received(what: &str, data: &[u8]) -> Result<()> {
+    check_received_as(what, data, accepts_non_canonical())
+}
+
+#[cfg(feature = "crypto")]
+fn check_received_as(what: &str, data: &[u8], accept_non_canonical: bool) -> Result<()> {
+    match check_canonical(data) {
+        Err(Error::NonCanonicalEncoding(reason)) if accept_non_canonical => {
+            log::warn!("{} is not canonically encoded: {}", what, reason);
+            Ok(())
+        }
+        Err(Error::NonCanonicalEncoding(reason)) => {
+            log::error!("{} is not canonically encoded: {}", what, reason);
+            Err(Error::NonCanonicalEncoding(reason))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Keeps a copy of the bytes read, so they can be checked by [`check_received`]
+/// without re-encoding the parsed structure
+#[cfg(feature = "crypto")]
+pub(crate) struct RecordingReader<R> {
+    inner: R,
+    recorded: Vec<u8>,
+}
+
+#[cfg(feature = "crypto")]
+impl<R: Read> RecordingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        RecordingReader {
+            inner,
+            recorded: Vec::new(),
+        }
+    }
+
+    pub(crate) fn recorded(&self) -> &[u8] {
+        &self.recorded
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    let data: &'a [u8] = input;
+    if data.len() < len {
+        return Err(Error::InconsistentValue("Truncated CBOR item"));
+    }
+    let (taken, rest) = data.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+/// Consumes the break ending an indefinite length item, if that's next
+fn at_break(input: &mut &[u8]) -> Result<bool> {
+    match take(&mut &input[..], 1)?[0] {
+        BREAK => {
+            take(input, 1)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Reads the argument of an item, returning it and whether it was in its shortest form
+fn read_argument(input: &mut &[u8], minor: u8) -> Result<(u64, bool)> {
+    let len = match minor {
+        0..=23 => return Ok((minor as u64, true)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => {
+            return Err(Error::InconsistentValue(
+                "Invalid CBOR additional information",
+            ))
+        }
+    };
+    let value = take(input, len)?
+        .iter()
+        .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    let shortest = match minor {
+        24 => value >= 24,
+        25 => value > u8::MAX as u64,
+        26 => value > u16::MAX as u64,
+        _ => value > u32::MAX as u64,
+    };
+    Ok((value, shortest))
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        out.push(major | value as u8);
+    } else if value <= u8::MAX as u64 {
+        out.push(major | 24);
+        out.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Re-encodes items canonically, or in strict mode fails on the first non-canonical encoding
+struct Encoder {
+    strict: bool,
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(strict: bool) -> Self {
+        Encoder {
+            strict,
+            out: Vec::new(),
+        }
+    }
+
+    fn non_canonical(&self, reason: &'static str) -> Result<()> {
+        if self.strict {
+            Err(Error::NonCanonicalEncoding(reason))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn encode(mut self, input: &mut &[u8], depth: usize) -> Result<Vec<u8>> {
+        self.item(input, depth)?;
+        Ok(self.out)
+    }
+
+    fn encode_nested(&self, input: &mut &[u8], depth: usize) -> Result<Vec<u8>> {
+        Encoder::new(self.strict).encode(input, depth)
+    }
+
+    fn item(&mut self, input: &mut &[u8], depth: usize) -> Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(Error::InconsistentValue("CBOR item nested too deeply"));
+        }
+
+        let initial = take(input, 1)?[0];
+        let major = initial >> 5;
+        let minor = initial & 0x1f;
+
+        if minor == INDEFINITE {
+            return self.indefinite_item(input, major, depth);
+        }
+        if major == MAJOR_SIMPLE && (25..=27).contains(&minor) {
+            // Floats are copied as they are
+            self.out.push(initial);
+            self.out.extend_from_slice(take(input, 1 << (minor - 24))?);
+            return Ok(());
+        }
+
+        let (value, shortest) = read_argument(input, minor)?;
+        if !shortest {
+            self.non_canonical("Integer or length not in its shortest form")?;
+        }
+
+        match major {
+            MAJOR_BYTES | MAJOR_TEXT => {
+                let len = usize::try_from(value)
+                    .map_err(|_| Error::InconsistentValue("CBOR string too long"))?;
+                let contents = take(input, len)?;
+                write_head(&mut self.out, major, value);
+                self.out.extend_from_slice(contents);
+            }
+            MAJOR_ARRAY => {
+                write_head(&mut self.out, major, value);
+                for _ in 0..value {
+                    self.item(input, depth + 1)?;
+                }
+            }
+            MAJOR_MAP => {
+                let mut entries = Vec::new();
+                for _ in 0..value {
+                    let key = self.encode_nested(input, depth + 1)?;
+                    let value = self.encode_nested(input, depth + 1)?;
+                    entries.push((key, value));
+                }
+                self.map(entries)?;
+            }
+            MAJOR_TAG => {
+                write_head(&mut self.out, major, value);
+                self.item(input, depth + 1)?;
+            }
+            MAJOR_SIMPLE if minor == 24 && value < 32 => {
+                return Err(Error::InconsistentValue("Invalid CBOR simple value"));
+            }
+            // Unsigned and negative integers, and simple values
+            _ => write_head(&mut self.out, major, value),
+        }
+        Ok(())
+    }
+
+    fn indefinite_item(&mut self, input: &mut &[u8], major: u8, depth: usize) -> Result<()> {
+        self.non_canonical("Indefinite length item")?;
+
+        match major {
+            MAJOR_BYTES | MAJOR_TEXT => {
+                let mut contents = Vec::new();
+                while !at_break(input)? {
+                    let initial = take(input, 1)?[0];
+                    if initial >> 5 != major || initial & 0x1f == INDEFINITE {
+                        return Err(Error::InconsistentValue("Invalid CBOR string chunk"));
+                    }
+                    let (len, _) = read_argument(input, initial & 0x1f)?;
+                    let len = usize::try_from(len)
+                        .map_err(|_| Error::InconsistentValue("CBOR string too long"))?;
+                    contents.extend_from_slice(take(input, len)?);
+                }
+                write_head(&mut self.out, major, contents.len() as u64);
+                self.out.extend_from_slice(&contents);
+            }
+            MAJOR_ARRAY => {
+                let mut items = Vec::new();
+                while !at_break(input)? {
+                    items.push(self.encode_nested(input, depth + 1)?);
+                }
+                write_head(&mut self.out, major, items.len() as u64);
+                for item in items {
+                    self.out.extend_from_slice(&item);
+                }
+            }
+            MAJOR_MAP => {
+                let mut entries = Vec::new();
+                while !at_break(input)? {
+                    let key = self.encode_nested(input, depth + 1)?;
+                    let value = self.encode_nested(input, depth + 1)?;
+                    entries.push((key, value));
+                }
+                self.map(entries)?;
+            }
+            _ => {
+                return Err(Error::InconsistentValue(
+                    "Invalid indefinite length CBOR item",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn map(&mut self, mut entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let key_order = |a: &[u8], b: &[u8]| a.len().cmp(&b.len()).then_with(|| a.cmp(b));
+
+        if entries
+            .windows(2)
+            .any(|pair| key_order(&pair[0].0, &pair[1].0).is_gt())
+        {
+            self.non_canonical("Map keys not in canonical order")?;
+            entries.sort_by(|a, b| key_order(&a.0, &b.0));
+        }
+        if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(Error::InconsistentValue("Duplicate CBOR map key"));
+        }
+
+        write_head(&mut self.out, MAJOR_MAP, entries.len() as u64);
+        for (key, value) in entries {
+            self.out.extend_from_slice(&key);
+            self.out.extend_from_slice(&value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{canonicalize, check_canonical, is_canonical};
+    use crate::Error;
+
+    fn assert_non_canonical(data: &str, canonical: &str) {
+        let data = hex::decode(data).unwrap();
+        assert!(matches!(
+            check_canonical(&data),
+            Err(Error::NonCanonicalEncoding(_))
+        ));
+        assert_eq!(hex::encode(canonicalize(&data).unwrap()), canonical);
+        assert!(is_canonical(&hex::decode(canonical).unwrap()));
+    }
+
+    #[test]
+    fn test_canonical_passthrough() {
+        for data in [
+            "00",
+            "1818",
+            "3903e7",
+            "4401020304",
+            "6449455446",
+            "83010203",
+            "a3010a0b0b190100f6",
+            "d2820102",
+            "f93c00",
+        ] {
+            let data = hex::decode(data).unwrap();
+            assert!(is_canonical(&data), "{}", hex::encode(&data));
+            assert_eq!(canonicalize(&data).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_non_shortest() {
+        assert_non_canonical("1817", "17");
+        assert_non_canonical("190001", "01");
+        assert_non_canonical("5800", "40");
+        assert_non_canonical("9a0000000101", "8101");
+        assert_non_canonical("d90012820102", "d2820102");
+    }
+
+    #[test]
+    fn test_indefinite() {
+        assert_non_canonical("9f0102ff", "820102");
+        assert_non_canonical("5f42010243030405ff", "450102030405");
+        assert_non_canonical("bf0102ff", "a10102");
+    }
+
+    #[test]
+    fn test_map_order() {
+        // Shorter keys first, then bytewise
+        assert_non_canonical("a2190100010a02", "a20a0219010001");
+        assert_non_canonical("a22001020b", "a2020b2001");
+        assert_non_canonical("a2616201616102", "a2616102616201");
+        assert_non_canonical("a262616101616202", "a261620262616101");
+    }
+
+    #[test]
+    fn test_invalid() {
+        for data in [
+            "", "18", "4201", "8201", "a201", "a20101", "ff", "1c", "5f4101",
+        ] {
+            let data = hex::decode(data).unwrap();
+            assert!(canonicalize(&data).is_err(), "{}", hex::encode(&data));
+        }
+        // Duplicate keys can't be fixed
+        assert!(canonicalize(&hex::decode("a201010102").unwrap()).is_err());
+        // Trailing data
+        assert!(check_canonical(&hex::decode("0101").unwrap()).is_err());
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn test_recording_reader() {
+        use std::io::Read;
+
+        let data = hex::decode("d9001282010203").unwrap();
+        let mut reader = super::RecordingReader::new(&data[..]);
+        let mut item = [0; 6];
+        reader.read_exact(&mut item).unwrap();
+        // Only what was read is recorded
+        assert_eq!(reader.recorded(), &data[..6]);
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn test_check_received() {
+        use super::{accepts_non_canonical, check_received, check_received_as};
+
+        // Rejecting is the default
+        assert!(!accepts_non_canonical());
+        check_received("test", &hex::decode("d2820102").unwrap()).unwrap();
+        // Invalid CBOR is left to the parser
+        check_received("test", &hex::decode("8201").unwrap()).unwrap();
+
+        let non_canonical = hex::decode("d90012820102").unwrap();
+        assert!(matches!(
+            check_received("test", &non_canonical),
+            Err(Error::NonCanonicalEncoding(_))
+        ));
+        check_received_as("test", &non_canonical, true).unwrap();
+    }
+}
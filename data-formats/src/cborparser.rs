@@ -182,7 +182,7 @@ where
         Ok((minor as u64, vec![]))
     } else {
         let mut buf = vec![0; length_size as usize];
-        reader.read_exact(&mut buf).unwrap();
+        reader.read_exact(&mut buf)?;
 
         match length_size {
             1 => Ok((buf[0] as u64, buf)),
@@ -201,6 +201,180 @@ where
     }
 }
 
+/// Reads the (optionally tagged) start of an array, returning the tag, the number of
+/// items and the raw header bytes.
+fn read_array_start<R>(mut reader: R) -> Result<(Option<u64>, u64, Vec<u8>), Error>
+where
+    R: std::io::Read,
+{
+    let mut header_buf = Vec::new();
+
+    let mut singlebyte_buf = [0u8; 1];
+    match reader.read_exact(&mut singlebyte_buf) {
+        Ok(_) => {}
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => return Err(ArrayParseError::NoData.into()),
+            _ => return Err(e.into()),
+        },
+    }
+    header_buf.extend_from_slice(&singlebyte_buf);
+    let first_major_type = MajorType::maybe_from_u8(singlebyte_buf[0] & MASK_TYPE)?;
+    let tag = match first_major_type {
+        MajorType::Tag => {
+            let (tag_val, mut tag_bytes) = read_len(&mut reader, singlebyte_buf[0])?;
+            header_buf.append(&mut tag_bytes);
+
+            // Read the next byte, which should be the major type of the array
+            reader.read_exact(&mut singlebyte_buf)?;
+            header_buf.extend_from_slice(&singlebyte_buf);
+
+            Some(tag_val)
+        }
+        MajorType::Array => None,
+        tp => return Err(Error::from(ArrayParseError::InvalidTopLevelType(tp))),
+    };
+
+    let first_major_type = MajorType::maybe_from_u8(singlebyte_buf[0] & MASK_TYPE)?;
+    if first_major_type != MajorType::Array {
+        return Err(Error::from(ArrayParseError::InvalidTopLevelType(
+            first_major_type,
+        )));
+    }
+
+    let (map_len, mut map_len_bytes) = read_len(&mut reader, singlebyte_buf[0])?;
+    header_buf.append(&mut map_len_bytes);
+
+    Ok((tag, map_len, header_buf))
+}
+
+/// Reads a single, possibly nested, item and writes its raw encoding to `out`.
+fn read_raw_item<R, W>(mut reader: R, mut out: W) -> Result<(), Error>
+where
+    R: std::io::Read,
+    W: std::io::Write,
+{
+    let mut singlebyte_buf = [0u8; 1];
+    let mut left_at_depth = vec![1];
+    loop {
+        if left_at_depth.is_empty() {
+            // Got to the end of the item
+            break;
+        }
+
+        reader.read_exact(&mut singlebyte_buf)?;
+        out.write_all(&singlebyte_buf)?;
+
+        let major_type = MajorType::maybe_from_u8(singlebyte_buf[0] & MASK_TYPE)?;
+        let minor = singlebyte_buf[0] & MASK_VAL;
+
+        if major_type == MajorType::Tag {
+            let (_, tag_bytes) = read_len(&mut reader, minor)?;
+            out.write_all(&tag_bytes)?;
+
+            continue;
+        }
+
+        match major_type {
+            MajorType::Unsigned | MajorType::Negative => {
+                let (_, val_bytes) = read_len(&mut reader, minor)?;
+                out.write_all(&val_bytes)?;
+            }
+            MajorType::ByteString | MajorType::TextString => {
+                let (length, len_bytes) = read_len(&mut reader, minor)?;
+                out.write_all(&len_bytes)?;
+
+                let copied = std::io::copy(&mut reader.by_ref().take(length), &mut out)?;
+                if copied != length {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+            MajorType::Array => {
+                let (length, len_bytes) = read_len(&mut reader, minor)?;
+                out.write_all(&len_bytes)?;
+
+                if length != 0 {
+                    left_at_depth.insert(0, length);
+                }
+            }
+            MajorType::Map => {
+                let (length, len_bytes) = read_len(&mut reader, minor)?;
+                out.write_all(&len_bytes)?;
+
+                if length != 0 {
+                    // With a map, "length" is the number of key-value pairs, so there are 2 * length items.
+                    // We do not actually need to parse the values in the Map, as long as we determine the correct
+                    // stop position.
+                    let items = length
+                        .checked_mul(2)
+                        .ok_or(ArrayParseError::ParseFailure("Map length out of range"))?;
+                    left_at_depth.insert(0, items);
+                }
+            }
+            n => return Err(ArrayParseError::UnsupportedMajorType(n as u8).into()),
+        }
+
+        if left_at_depth[0] == 1 {
+            // This was the last item, we're now back in the higher level array
+            left_at_depth.remove(0);
+        } else if left_at_depth[0] == 0 {
+            // This should not happen, but let's check
+            return Err(
+                ArrayParseError::ParseFailure("Array parse encountered 0 items left").into(),
+            );
+        } else {
+            left_at_depth[0] -= 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Iterator over the raw items of a serialized array, without decoding or copying them.
+#[derive(Debug, Clone)]
+pub struct RawArrayIter<'a> {
+    remaining: u64,
+    data: &'a [u8],
+}
+
+impl<'a> RawArrayIter<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = data;
+        let (_, remaining, _) = read_array_start(&mut reader)?;
+        Ok(RawArrayIter {
+            remaining,
+            data: reader,
+        })
+    }
+}
+
+impl<'a> Iterator for RawArrayIter<'a> {
+    type Item = Result<&'a [u8], Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let mut reader = self.data;
+        if let Err(e) = read_raw_item(&mut reader, std::io::sink()) {
+            self.remaining = 0;
+            return Some(Err(e));
+        }
+        let (item, rest) = self.data.split_at(self.data.len() - reader.len());
+        self.data = rest;
+        self.remaining -= 1;
+
+        Some(Ok(item))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.remaining as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for RawArrayIter<'a> {}
+
 impl<N: ParsedArraySize> MaybeSerializable for ParsedArray<N> {
     fn is_nodata_error(err: &Error) -> bool {
         matches!(err, Error::ArrayParseError(ArrayParseError::NoData))
@@ -232,148 +406,55 @@ impl<N: ParsedArraySize> Serializable for ParsedArray<N> {
         Ok(())
     }
 
+    // With the whole input available, the items are only scanned to find where they end,
+    // and each is copied once into an allocation of the right size
+    fn deserialize_data(data: &[u8]) -> Result<Self, Error> {
+        let mut reader = data;
+        let (tag, map_len, header_buf) = read_array_start(&mut reader)?;
+        Self::check_len(map_len)?;
+
+        // Every item takes at least one byte, which bounds the length from untrusted input
+        let mut parsed_items = Vec::with_capacity((map_len as usize).min(reader.len()));
+        for _ in 0..map_len {
+            let item_start = reader;
+            read_raw_item(&mut reader, std::io::sink())?;
+            parsed_items.push(item_start[..item_start.len() - reader.len()].to_vec());
+        }
+
+        Ok(Self::from_items(tag, header_buf, parsed_items))
+    }
+
     fn deserialize_from_reader<R>(mut reader: R) -> Result<Self, Error>
     where
         R: std::io::Read,
     {
-        let mut header_buf = Vec::new();
+        let (tag, map_len, header_buf) = read_array_start(&mut reader)?;
+        Self::check_len(map_len)?;
 
         // Parse JUST the top-level array, leave everything else as raw binary things
         let mut parsed_items = Vec::new();
-
-        let mut singlebyte_buf = [0u8; 1];
-        match reader.read_exact(&mut singlebyte_buf) {
-            Ok(_) => {}
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => return Err(ArrayParseError::NoData.into()),
-                _ => return Err(e.into()),
-            },
-        }
-        header_buf.extend_from_slice(&singlebyte_buf);
-        let first_major_type = MajorType::maybe_from_u8(singlebyte_buf[0] & MASK_TYPE)?;
-        let tag = match first_major_type {
-            MajorType::Tag => {
-                let (tag_val, mut tag_bytes) = read_len(&mut reader, singlebyte_buf[0])?;
-                header_buf.append(&mut tag_bytes);
-
-                // Read the next byte, which should be the major type of the array
-                reader.read_exact(&mut singlebyte_buf)?;
-                header_buf.extend_from_slice(&singlebyte_buf);
-
-                Some(tag_val)
-            }
-            MajorType::Array => None,
-            tp => return Err(Error::from(ArrayParseError::InvalidTopLevelType(tp))),
-        };
-
-        let first_major_type = MajorType::maybe_from_u8(singlebyte_buf[0] & MASK_TYPE)?;
-        if first_major_type != MajorType::Array {
-            return Err(Error::from(ArrayParseError::InvalidTopLevelType(
-                first_major_type,
-            )));
-        }
-
-        let (map_len, mut map_len_bytes) = read_len(&mut reader, singlebyte_buf[0])?;
-        header_buf.append(&mut map_len_bytes);
-
-        if let Some(expected_len) = N::SIZE {
-            if map_len != expected_len {
-                return Err(ArrayParseError::InvalidNumberOfElements(map_len, expected_len).into());
-            }
-        } else if map_len == 0 {
-            return Ok(ParsedArray {
-                tag,
-                header: None,
-                contents: Vec::new(),
-
-                _marker: std::marker::PhantomData,
-            });
+        for _ in 0..map_len {
+            let mut item = Vec::new();
+            read_raw_item(&mut reader, &mut item)?;
+            parsed_items.push(item);
         }
 
-        let mut left_at_depth = vec![map_len];
-        let mut toplevel_buf = Vec::new();
-        loop {
-            if left_at_depth.is_empty() {
-                // Got to the end of the array
-                break;
-            }
-
-            reader.read_exact(&mut singlebyte_buf)?;
-            toplevel_buf.extend_from_slice(&singlebyte_buf);
-
-            let major_type = MajorType::maybe_from_u8(singlebyte_buf[0] & MASK_TYPE)?;
-            let minor = singlebyte_buf[0] & MASK_VAL;
-
-            if major_type == MajorType::Tag {
-                let (_, mut tag_bytes) = read_len(&mut reader, minor)?;
-                toplevel_buf.append(&mut tag_bytes);
-
-                continue;
-            }
-
-            match major_type {
-                MajorType::Unsigned | MajorType::Negative => {
-                    let (_, mut val_bytes) = read_len(&mut reader, minor)?;
-                    toplevel_buf.append(&mut val_bytes);
-                }
-                MajorType::ByteString | MajorType::TextString => {
-                    let (length, mut len_bytes) = read_len(&mut reader, minor)?;
-                    toplevel_buf.append(&mut len_bytes);
-
-                    let mut buf = vec![0; length as usize];
-                    reader.read_exact(&mut buf)?;
-                    toplevel_buf.append(&mut buf);
-                }
-                MajorType::Array => {
-                    let (length, mut len_bytes) = read_len(&mut reader, minor)?;
-                    toplevel_buf.append(&mut len_bytes);
-
-                    if length != 0 {
-                        left_at_depth.insert(0, length);
-                    }
-                }
-                MajorType::Map => {
-                    let (length, mut len_bytes) = read_len(&mut reader, minor)?;
-                    toplevel_buf.append(&mut len_bytes);
-
-                    if length != 0 {
-                        // With a map, "length" is the number of key-value pairs, so there are 2 * length items.
-                        // We do not actually need to parse the values in the Map, as long as we determine the correct
-                        // stop position.
-                        left_at_depth.insert(0, length * 2);
-                    }
-                }
-                n => return Err(ArrayParseError::UnsupportedMajorType(n as u8).into()),
-            }
-
-            if left_at_depth.len() == 1 {
-                // This was the end of an item at the top level
-                parsed_items.push(toplevel_buf);
-                toplevel_buf = Vec::new();
-            }
-            if left_at_depth[0] == 1 {
-                // This was the last item, we're now back in the higher level array
-                left_at_depth.remove(0);
-            } else if left_at_depth[0] == 0 {
-                // This should not happen, but let's check
-                return Err(
-                    ArrayParseError::ParseFailure("Array parse encountered 0 items left").into(),
-                );
-            } else {
-                left_at_depth[0] -= 1;
-            }
-        }
+        Ok(Self::from_items(tag, header_buf, parsed_items))
+    }
+}
 
-        if !left_at_depth.is_empty() {
-            return Err(ArrayParseError::ParseFailure("Too few items in array").into());
-        }
-        if let Some(expected_size) = N::SIZE {
-            if (parsed_items.len() as u64) != expected_size {
-                return Err(ArrayParseError::ParseFailure("Too many items in array").into());
+impl<N: ParsedArraySize> ParsedArray<N> {
+    fn check_len(map_len: u64) -> Result<(), Error> {
+        match N::SIZE {
+            Some(expected_len) if map_len != expected_len => {
+                Err(ArrayParseError::InvalidNumberOfElements(map_len, expected_len).into())
             }
+            _ => Ok(()),
         }
+    }
 
-        Ok(ParsedArray {
+    fn from_items(tag: Option<u64>, header_buf: Vec<u8>, contents: Vec<Vec<u8>>) -> Self {
+        ParsedArray {
             tag,
 
             header: if N::SIZE.is_some() {
@@ -381,10 +462,10 @@ impl<N: ParsedArraySize> Serializable for ParsedArray<N> {
             } else {
                 None
             },
-            contents: parsed_items,
+            contents,
 
             _marker: std::marker::PhantomData,
-        })
+        }
     }
 }
 
@@ -634,6 +715,30 @@ mod test {
         assert_eq!(serialized, vec![0x82, 0x01, 0x03]);
     }
 
+    #[test]
+    fn test_slice_and_reader_parse_equal() {
+        let data = vec![
+            0xC6, 0x83, 0x42, 0x01, 0x02, 0x81, 0x63, 0x61, 0x62, 0x63, 0xA0,
+        ];
+        let from_slice: ParsedArray<super::ParsedArraySize3> =
+            ParsedArray::deserialize_data(&data).expect("Failed to parse slice");
+        let from_reader: ParsedArray<super::ParsedArraySize3> =
+            ParsedArray::deserialize_from_reader(&data[..]).expect("Failed to parse reader");
+        assert_eq!(from_slice.raw_values(), from_reader.raw_values());
+        assert_eq!(from_slice.tag(), Some(6));
+        assert_eq!(from_slice.serialize_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_truncated_array() {
+        // Byte string with a two-byte length, of which only one byte is present
+        let data = vec![0x81, 0x59, 0x01];
+        assert!(ParsedArray::<super::ParsedArraySize1>::deserialize_data(&data).is_err());
+        // Byte string that is shorter than its length
+        let data = vec![0x81, 0x43, 0x01];
+        assert!(ParsedArray::<super::ParsedArraySize1>::deserialize_data(&data).is_err());
+    }
+
     #[test]
     #[should_panic]
     fn test_panic_with_nth_larger() {
@@ -910,4 +1015,47 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn raw_array_iter() {
+        // [1, [2, 3], {4: "a"}, h'0506', 18([])]
+        let data = hex::decode("8501820203A1046161420506D280").unwrap();
+        let items: Vec<&[u8]> = super::RawArrayIter::new(&data)
+            .expect("Failed to parse")
+            .collect::<Result<_, _>>()
+            .expect("Failed to iterate");
+        assert_eq!(
+            items,
+            vec![
+                &[0x01][..],
+                &[0x82, 0x02, 0x03][..],
+                &[0xa1, 0x04, 0x61, 0x61][..],
+                &[0x42, 0x05, 0x06][..],
+                &[0xd2, 0x80][..],
+            ]
+        );
+
+        let parsed: ParsedArray<super::ParsedArraySize5> =
+            ParsedArray::deserialize_data(&data).expect("Failed to parse");
+        assert_eq!(parsed.raw_values(), items);
+    }
+
+    #[test]
+    fn raw_array_iter_truncated() {
+        let data = hex::decode("83018202").unwrap();
+        let mut iter = super::RawArrayIter::new(&data).expect("Failed to parse");
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.next().unwrap().unwrap(), &[0x01][..]);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_map_length_overflow() {
+        // A map claiming 2^63 entries inside a single-element array
+        let data = hex::decode("81bb8000000000000000").unwrap();
+        let parsed: Result<ParsedArray<super::ParsedArraySizeDynamic>, _> =
+            ParsedArray::deserialize_data(&data);
+        assert!(parsed.is_err());
+    }
 }
@@ -5,7 +5,12 @@ use crate::{errors::Result, Error};
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use openssl::hash::MessageDigest;
+#[cfg(feature = "crypto")]
+use openssl::{
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{HasPublic, Id, PKeyRef},
+};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
 mod serviceinfo_names;
@@ -23,12 +28,38 @@ pub enum ProtocolVersion {
     Version1_1 = 101,
 }
 
+impl ProtocolVersion {
+    /// All protocol versions that can be spoken, newest first
+    pub const SUPPORTED: &'static [ProtocolVersion] =
+        &[ProtocolVersion::Version1_1, ProtocolVersion::Version1_0];
+
+    /// Picks the newest protocol version supported by both sides.
+    pub fn negotiate(peer_versions: &[ProtocolVersion]) -> Option<ProtocolVersion> {
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|version| peer_versions.contains(version))
+    }
+}
+
 impl std::fmt::Display for ProtocolVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", *self as u16)
     }
 }
 
+impl TryFrom<u16> for ProtocolVersion {
+    type Error = Error;
+
+    fn try_from(value: u16) -> Result<Self> {
+        match value {
+            100 => Ok(ProtocolVersion::Version1_0),
+            101 => Ok(ProtocolVersion::Version1_1),
+            _ => Err(Error::UnsupportedVersion(None)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr, PartialEq, Eq)]
 #[repr(i8)]
 #[non_exhaustive]
@@ -51,6 +82,7 @@ impl FromStr for HashType {
     }
 }
 
+#[cfg(feature = "crypto")]
 impl TryFrom<HashType> for MessageDigest {
     type Error = Error;
 
@@ -64,6 +96,7 @@ impl TryFrom<HashType> for MessageDigest {
 }
 
 impl HashType {
+    #[cfg(feature = "crypto")]
     pub fn get_md(&self) -> MessageDigest {
         match self {
             HashType::Sha256 => MessageDigest::sha256(),
@@ -90,8 +123,36 @@ impl HashType {
             HashType::HmacSha384 => HashType::Sha384,
         }
     }
+
+    /// Returns the HMAC type with the same strength as this hash type.
+    pub fn hmac(&self) -> HashType {
+        match self {
+            HashType::Sha256 | HashType::HmacSha256 => HashType::HmacSha256,
+            HashType::Sha384 | HashType::HmacSha384 => HashType::HmacSha384,
+        }
+    }
+
+    /// Returns the hash type matching the strength of `pkey`.
+    ///
+    /// Keys of 256-bit strength (SECP256R1, RSA2048, Ed25519) use SHA-256, while
+    /// SECP384R1 and RSA3072 or larger keys use SHA-384.
+    #[cfg(feature = "crypto")]
+    pub fn for_pkey<T: HasPublic>(pkey: &PKeyRef<T>) -> Result<HashType> {
+        match pkey.id() {
+            Id::EC => match pkey.ec_key()?.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => Ok(HashType::Sha256),
+                Some(Nid::SECP384R1) => Ok(HashType::Sha384),
+                _ => Err(Error::UnsupportedAlgorithm),
+            },
+            Id::RSA if pkey.bits() >= 3072 => Ok(HashType::Sha384),
+            Id::RSA => Ok(HashType::Sha256),
+            Id::ED25519 => Ok(HashType::Sha256),
+            _ => Err(Error::UnsupportedAlgorithm),
+        }
+    }
 }
 
+#[cfg(feature = "crypto")]
 impl TryFrom<MessageDigest> for HashType {
     type Error = Error;
 
@@ -104,6 +165,9 @@ impl TryFrom<MessageDigest> for HashType {
     }
 }
 
+// COSE algorithm identifiers (RFC 8152, section 8.1 and RFC 8812)
+const ES256: i16 = -7;
+const ES384: i16 = -35;
 const RS256: i16 = -257;
 const RS384: i16 = -258;
 
@@ -111,8 +175,8 @@ const RS384: i16 = -258;
 #[repr(i16)]
 #[non_exhaustive]
 pub enum DeviceSigType {
-    StSECP256R1 = (aws_nitro_enclaves_cose::sign::SignatureAlgorithm::ES256 as i16),
-    StSECP384R1 = (aws_nitro_enclaves_cose::sign::SignatureAlgorithm::ES384 as i16),
+    StSECP256R1 = ES256,
+    StSECP384R1 = ES384,
     StRSA2048 = RS256,
     StRSA3072 = RS384,
     StEPID10 = 90,
@@ -120,7 +184,7 @@ pub enum DeviceSigType {
     StEPID20 = 92,
 }
 
-#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq)]
 #[repr(i16)]
 #[non_exhaustive]
 pub enum PublicKeyType {
@@ -129,6 +193,47 @@ pub enum PublicKeyType {
     RsaPss = 6,
     SECP256R1 = 10,
     SECP384R1 = 11,
+    // Not assigned by the FDO specification, used for COSE EdDSA signatures. Other
+    // implementations don't know it, so it's only (de)serialized with the "ed25519" feature.
+    Ed25519 = 12,
+}
+
+impl PublicKeyType {
+    /// Whether keys of this type can be used in FDO structures
+    pub fn is_enabled(&self) -> bool {
+        cfg!(feature = "ed25519") || *self != PublicKeyType::Ed25519
+    }
+}
+
+impl serde::Serialize for PublicKeyType {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if !self.is_enabled() {
+            return Err(serde::ser::Error::custom(format!(
+                "Public key type {:?} is not enabled",
+                self
+            )));
+        }
+        serializer.serialize_i16(*self as i16)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PublicKeyType {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = <i16 as serde::Deserialize>::deserialize(deserializer)?;
+        match PublicKeyType::from_i16(value) {
+            Some(key_type) if key_type.is_enabled() => Ok(key_type),
+            _ => Err(serde::de::Error::custom(format!(
+                "Unsupported public key type {}",
+                value
+            ))),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr)]
@@ -184,7 +289,7 @@ pub enum TransportProtocol {
     CoAPS = 6,
 }
 
-#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr, PartialEq, Eq)]
 #[repr(u8)]
 #[non_exhaustive]
 pub enum RendezvousVariable {
@@ -207,7 +312,7 @@ pub enum RendezvousVariable {
 }
 
 impl RendezvousVariable {
-    fn name(&self) -> &'static str {
+    pub fn name(&self) -> &'static str {
         match self {
             RendezvousVariable::DeviceOnly => "device-only",
             RendezvousVariable::OwnerOnly => "owner-only",
@@ -227,6 +332,7 @@ impl RendezvousVariable {
         }
     }
 
+    #[cfg(feature = "crypto")]
     pub fn value_from_human_to_machine(&self, val: serde_cbor::Value) -> Result<serde_cbor::Value> {
         Ok(match self {
             // These are just markers: their existence means they're true
@@ -238,7 +344,8 @@ impl RendezvousVariable {
             // These are integers
             RendezvousVariable::DevicePort
             | RendezvousVariable::OwnerPort
-            | RendezvousVariable::Delaysec => match val {
+            | RendezvousVariable::Delaysec
+            | RendezvousVariable::Medium => match val {
                 serde_cbor::Value::Integer(i) => serde_cbor::Value::Integer(i),
                 _ => return Err(Error::InconsistentValue(self.name())),
             },
@@ -271,12 +378,14 @@ impl RendezvousVariable {
                 _ => return Err(Error::InconsistentValue(self.name())),
             },
 
-            // TODO
-            RendezvousVariable::ServerCertHash => {
-                return Err(Error::NotImplemented("ServerCertHash"))
-            }
-            RendezvousVariable::CaCertHash => return Err(Error::NotImplemented("CaCertHash")),
-            RendezvousVariable::Medium => return Err(Error::NotImplemented("Medium")),
+            // Hashes are written as "<hash type>:<hex digest>", e.g. "sha256:abcd..."
+            RendezvousVariable::ServerCertHash | RendezvousVariable::CaCertHash => match val {
+                serde_cbor::Value::Text(v) => serde_cbor::value::to_value(
+                    crate::types::Hash::from_str(&v)
+                        .map_err(|_| Error::InconsistentValue(self.name()))?,
+                )?,
+                _ => return Err(Error::InconsistentValue(self.name())),
+            },
         })
     }
 }
@@ -389,11 +498,31 @@ pub enum MessageType {
     DIUNProvideKeyParameters = 213,
     DIUNProvideKey = 214,
     DIUNDone = 215,
+    DIUNRequestAttestationChallenge = 216,
+    DIUNAttestationChallenge = 217,
+    DIUNProvideAttestedKey = 218,
 
     // Error
     Error = 255,
 }
 
+impl MessageType {
+    /// Whether the message is part of the TO0 or TO1 protocol, with the rendezvous server
+    pub fn is_rendezvous_protocol(&self) -> bool {
+        matches!(
+            self,
+            MessageType::TO0Hello
+                | MessageType::TO0HelloAck
+                | MessageType::TO0OwnerSign
+                | MessageType::TO0AcceptOwner
+                | MessageType::TO1HelloRV
+                | MessageType::TO1HelloRVAck
+                | MessageType::TO1ProveToRV
+                | MessageType::TO1RVRedirect
+        )
+    }
+}
+
 impl TryFrom<u8> for MessageType {
     type Error = ();
 
@@ -460,3 +589,101 @@ impl FromStr for KeyStorageType {
         })
     }
 }
+
+#[cfg(all(test, feature = "crypto"))]
+mod test_hashtype {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::PKey,
+        rsa::Rsa,
+    };
+
+    use super::HashType;
+
+    #[test]
+    fn test_hashtype_for_pkey() {
+        let p256 = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let p256 = PKey::from_ec_key(EcKey::generate(&p256).unwrap()).unwrap();
+        assert_eq!(HashType::for_pkey(&p256).unwrap(), HashType::Sha256);
+
+        let p384 = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let p384 = PKey::from_ec_key(EcKey::generate(&p384).unwrap()).unwrap();
+        assert_eq!(HashType::for_pkey(&p384).unwrap(), HashType::Sha384);
+
+        let rsa2048 = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        assert_eq!(HashType::for_pkey(&rsa2048).unwrap(), HashType::Sha256);
+
+        let p521 = EcGroup::from_curve_name(Nid::SECP521R1).unwrap();
+        let p521 = PKey::from_ec_key(EcKey::generate(&p521).unwrap()).unwrap();
+        assert!(HashType::for_pkey(&p521).is_err());
+    }
+
+    #[test]
+    fn test_hashtype_hmac() {
+        assert_eq!(HashType::Sha256.hmac(), HashType::HmacSha256);
+        assert_eq!(HashType::Sha384.hmac(), HashType::HmacSha384);
+        assert_eq!(HashType::HmacSha384.hmac(), HashType::HmacSha384);
+    }
+}
+
+#[cfg(test)]
+mod test_protocolversion {
+    use std::convert::TryFrom;
+
+    use super::ProtocolVersion;
+
+    #[test]
+    fn test_protocolversion_negotiate() {
+        assert_eq!(
+            ProtocolVersion::negotiate(&[ProtocolVersion::Version1_0, ProtocolVersion::Version1_1]),
+            Some(ProtocolVersion::Version1_1)
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(&[ProtocolVersion::Version1_0]),
+            Some(ProtocolVersion::Version1_0)
+        );
+        assert_eq!(ProtocolVersion::negotiate(&[]), None);
+    }
+
+    #[test]
+    fn test_protocolversion_try_from() {
+        assert_eq!(
+            ProtocolVersion::try_from(100).unwrap(),
+            ProtocolVersion::Version1_0
+        );
+        assert_eq!(
+            ProtocolVersion::try_from(101).unwrap(),
+            ProtocolVersion::Version1_1
+        );
+        assert!(ProtocolVersion::try_from(102).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_publickeytype {
+    use super::PublicKeyType;
+
+    #[test]
+    fn test_publickeytype_serialization() {
+        let encoded = serde_cbor::to_vec(&PublicKeyType::SECP384R1).unwrap();
+        assert_eq!(encoded, [0x0b]);
+        let decoded: PublicKeyType = serde_cbor::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, PublicKeyType::SECP384R1);
+
+        assert!(serde_cbor::from_slice::<PublicKeyType>(&[0x02]).is_err());
+    }
+
+    #[test]
+    fn test_publickeytype_ed25519() {
+        let encoded = serde_cbor::to_vec(&PublicKeyType::Ed25519);
+        let decoded = serde_cbor::from_slice::<PublicKeyType>(&[0x0c]);
+        if cfg!(feature = "ed25519") {
+            assert_eq!(encoded.unwrap(), [0x0c]);
+            assert_eq!(decoded.unwrap(), PublicKeyType::Ed25519);
+        } else {
+            assert!(encoded.is_err());
+            assert!(decoded.is_err());
+        }
+    }
+}
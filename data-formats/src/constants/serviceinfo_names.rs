@@ -60,6 +60,8 @@ impl FromStr for ServiceInfoModule {
             }
 
             "devmod" => StandardServiceInfoModule::DevMod.into(),
+            "fdo.download" => StandardServiceInfoModule::Download.into(),
+            "fdo.command" => StandardServiceInfoModule::Command.into(),
 
             other => ServiceInfoModule::Unsupported(other.to_string()),
         })
@@ -105,6 +107,8 @@ impl Display for ServiceInfoModule {
 #[non_exhaustive]
 pub enum StandardServiceInfoModule {
     DevMod,
+    Download,
+    Command,
 }
 
 impl Display for StandardServiceInfoModule {
@@ -114,6 +118,8 @@ impl Display for StandardServiceInfoModule {
             "{}",
             match self {
                 StandardServiceInfoModule::DevMod => "devmod",
+                StandardServiceInfoModule::Download => "fdo.download",
+                StandardServiceInfoModule::Command => "fdo.command",
             }
         )
     }
@@ -0,0 +1,712 @@
+//! COSE_Sign1 (RFC 8152, section 4.2) creation and verification
+//!
+//! All signed FDO structures go through `COSESign`, which supports ECDSA keys
+//! through aws-nitro-enclaves-cose, and RSA and EdDSA signatures through OpenSSL.
+
+use aws_nitro_enclaves_cose::crypto::{SigningPrivateKey, SigningPublicKey};
+use aws_nitro_enclaves_cose::CoseSign1 as COSESignInner;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{self, HasPublic, PKeyRef, Private, Public},
+    rsa::Padding,
+    sign::{RsaPssSaltlen, Signer, Verifier},
+};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+
+use crate::{
+    canonical,
+    cborparser::ParsedArray,
+    constants::{HeaderKeys, PublicKeyType},
+    errors::Error,
+    publickey::PublicKey,
+    types::{
+        eat_from_map, EATokenPayload, PayloadState, PayloadUnverified, PayloadVerified,
+        UnverifiedValue,
+    },
+    Serializable,
+};
+
+pub(crate) type COSEHeaderMapType = std::collections::HashMap<i64, serde_cbor::Value>;
+
+#[derive(Debug, Clone)]
+pub struct COSEHeaderMap(pub(crate) COSEHeaderMapType);
+
+impl From<COSEHeaderMap> for aws_nitro_enclaves_cose::header_map::HeaderMap {
+    fn from(mut chm: COSEHeaderMap) -> aws_nitro_enclaves_cose::header_map::HeaderMap {
+        let mut new = aws_nitro_enclaves_cose::header_map::HeaderMap::new();
+        for (key, value) in chm.0.drain() {
+            new.insert(serde_cbor::Value::Integer(key as i128), value);
+        }
+        new
+    }
+}
+
+impl COSEHeaderMap {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        COSEHeaderMap(std::collections::HashMap::new())
+    }
+
+    pub fn insert<T>(&mut self, key: HeaderKeys, value: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        self.0
+            .insert(key as i64, serde_cbor::value::to_value(value)?);
+        Ok(())
+    }
+
+    pub(crate) fn get<T>(&self, key: &HeaderKeys) -> Result<Option<T>, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        match self.0.get(&(*key as i64)) {
+            None => Ok(None),
+            Some(val) => Ok(Some(serde_cbor::value::from_value(val.clone())?)),
+        }
+    }
+}
+
+const COSESIGN_TAG: u64 = 18;
+// COSE algorithm identifiers (RFC 8152, section 8.2 and RFC 8230, section 2)
+const COSE_ALGORITHM_EDDSA: i64 = -8;
+const COSE_ALGORITHM_PS256: i64 = -37;
+const COSE_ALGORITHM_PS384: i64 = -38;
+const COSE_ALGORITHM_RS256: i64 = -257;
+const COSE_ALGORITHM_RS384: i64 = -258;
+pub(crate) const COSE_HEADER_ALGORITHM: i64 = 1;
+
+/// A signature algorithm computed with OpenSSL, as aws-nitro-enclaves-cose only supports ECDSA
+#[derive(Clone, Copy)]
+struct OpensslAlgorithm {
+    id: i64,
+    digest: Option<MessageDigest>,
+    pss: bool,
+}
+
+impl OpensslAlgorithm {
+    /// Selects the algorithm for a key of the given type, or None for ECDSA keys
+    fn for_key<T: HasPublic>(
+        key: &PKeyRef<T>,
+        key_type: PublicKeyType,
+    ) -> Result<Option<Self>, Error> {
+        let (id, digest, pss) = match key_type {
+            PublicKeyType::SECP256R1 | PublicKeyType::SECP384R1 => return Ok(None),
+            PublicKeyType::Ed25519 if key.id() == pkey::Id::ED25519 => {
+                (COSE_ALGORITHM_EDDSA, None, false)
+            }
+            PublicKeyType::Rsa2048RESTR | PublicKeyType::RsaPkcs | PublicKeyType::RsaPss
+                if key.id() == pkey::Id::RSA =>
+            {
+                let pss = key_type == PublicKeyType::RsaPss;
+                // RSA 2048 is signed with SHA-256, RSA 3072 with SHA-384
+                match (key.bits(), pss) {
+                    (2048, false) => (COSE_ALGORITHM_RS256, Some(MessageDigest::sha256()), false),
+                    (3072, false) => (COSE_ALGORITHM_RS384, Some(MessageDigest::sha384()), false),
+                    (2048, true) => (COSE_ALGORITHM_PS256, Some(MessageDigest::sha256()), true),
+                    (3072, true) => (COSE_ALGORITHM_PS384, Some(MessageDigest::sha384()), true),
+                    _ => return Err(Error::UnsupportedAlgorithm),
+                }
+            }
+            _ => return Err(Error::InconsistentValue("public key type")),
+        };
+        Ok(Some(OpensslAlgorithm { id, digest, pss }))
+    }
+
+    fn sign(&self, key: &PKeyRef<Private>, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signer = match self.digest {
+            Some(digest) => Signer::new(digest, key)?,
+            None => Signer::new_without_digest(key)?,
+        };
+        if let (true, Some(digest)) = (self.pss, self.digest) {
+            signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+            signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+            signer.set_rsa_mgf1_md(digest)?;
+        }
+        Ok(signer.sign_oneshot_to_vec(data)?)
+    }
+
+    fn verify(&self, key: &PKeyRef<Public>, data: &[u8], signature: &[u8]) -> Result<bool, Error> {
+        let mut verifier = match self.digest {
+            Some(digest) => Verifier::new(digest, key)?,
+            None => Verifier::new_without_digest(key)?,
+        };
+        if let (true, Some(digest)) = (self.pss, self.digest) {
+            verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+            verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+            verifier.set_rsa_mgf1_md(digest)?;
+        }
+        Ok(verifier.verify_oneshot(signature, data)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct COSESign {
+    contents: ParsedArray<crate::cborparser::ParsedArraySize4>,
+
+    cached_inner: COSESignInner,
+}
+
+impl Serializable for COSESign {
+    fn deserialize_from_reader<R>(reader: R) -> Result<Self, Error>
+    where
+        R: std::io::Read,
+    {
+        let mut reader = canonical::RecordingReader::new(reader);
+        let array = ParsedArray::deserialize_from_reader(&mut reader)?;
+        canonical::check_received("COSESign", reader.recorded())?;
+
+        if array.tag() != Some(COSESIGN_TAG) {
+            if array.tag().is_none() {
+                return Err(Error::InconsistentValue("Missing tag on COSESign"));
+            } else {
+                return Err(Error::InconsistentValue("Invalid tag on COSESign"));
+            }
+        }
+
+        // TODO
+        let data = array.serialize_data()?;
+        for (index, what) in [(0, "COSESign protected header"), (2, "COSESign payload")] {
+            if let Ok(value) = array.get::<ByteBuf>(index) {
+                canonical::check_received(what, &value)?;
+            }
+        }
+        let inner = COSESignInner::from_bytes(&data)?;
+
+        Ok(COSESign {
+            contents: array,
+
+            cached_inner: inner,
+        })
+    }
+
+    fn serialize_to_writer<W>(&self, writer: W) -> Result<(), Error>
+    where
+        W: std::io::Write,
+    {
+        // There is no way this data structure should be able to be constructed without either
+        // deserializing (which checks the tag) or us constructing it, where we set the tag.
+        // Just make sure it's there before serializing.
+        if !matches!(self.contents.tag(), Some(COSESIGN_TAG)) {
+            return Err(Error::InconsistentValue(
+                "Invalid tag on COSESign on serialize_data",
+            ));
+        }
+
+        self.contents.serialize_to_writer(writer)
+    }
+}
+
+impl COSESign {
+    fn new_from_inner(inner: COSESignInner) -> Result<Self, Error> {
+        let mut contents = ParsedArray::deserialize_data(&inner.serialize_data()?)?;
+        contents.set_tag(Some(COSESIGN_TAG));
+
+        Ok(COSESign {
+            contents,
+
+            cached_inner: inner,
+        })
+    }
+
+    pub fn new<T>(
+        payload: &T,
+        unprotected: Option<COSEHeaderMap>,
+        sign_key: &dyn SigningPrivateKey,
+    ) -> Result<Self, Error>
+    where
+        T: Serializable,
+    {
+        let unprotected = match unprotected {
+            Some(v) => v,
+            None => COSEHeaderMap::new(),
+        };
+        // Signatures are over the encoded payload, which must be deterministic
+        let payload = canonical::canonicalize(&payload.serialize_data()?)?;
+
+        let inner = COSESignInner::new(&payload, &unprotected.into(), sign_key)?;
+
+        Self::new_from_inner(inner)
+    }
+
+    pub fn new_with_protected<T>(
+        payload: &T,
+        protected: COSEHeaderMap,
+        unprotected: Option<COSEHeaderMap>,
+        sign_key: &dyn SigningPrivateKey,
+    ) -> Result<Self, Error>
+    where
+        T: Serializable,
+    {
+        let unprotected = match unprotected {
+            Some(v) => v,
+            None => COSEHeaderMap::new(),
+        };
+        let payload = canonical::canonicalize(&payload.serialize_data()?)?;
+
+        let (sig_alg, _) = sign_key.get_parameters()?;
+        let mut protected: aws_nitro_enclaves_cose::header_map::HeaderMap = protected.into();
+        protected.insert(1.into(), (sig_alg as i8).into());
+
+        let inner =
+            COSESignInner::new_with_protected(&payload, &protected, &unprotected.into(), sign_key)?;
+
+        Self::new_from_inner(inner)
+    }
+
+    /// Creates a new COSESign, signing with an OpenSSL private key.
+    ///
+    /// Unlike `new`, this also supports Ed25519 keys, which are signed with EdDSA, and
+    /// RSA keys, which are signed with PKCS#1 v1.5 signatures.
+    pub fn new_with_pkey<T>(
+        payload: &T,
+        unprotected: Option<COSEHeaderMap>,
+        sign_key: &PKeyRef<Private>,
+    ) -> Result<Self, Error>
+    where
+        T: Serializable,
+    {
+        let key_type = match sign_key.id() {
+            pkey::Id::ED25519 => PublicKeyType::Ed25519,
+            pkey::Id::RSA => PublicKeyType::RsaPkcs,
+            _ => return Self::new(payload, unprotected, sign_key),
+        };
+        Self::new_with_key_type(payload, unprotected, sign_key, key_type)
+    }
+
+    /// Creates a new COSESign, signing with an OpenSSL private key of the given FDO key type.
+    ///
+    /// The key type selects between PKCS#1 v1.5 and PSS signatures for RSA keys.
+    pub fn new_with_key_type<T>(
+        payload: &T,
+        unprotected: Option<COSEHeaderMap>,
+        sign_key: &PKeyRef<Private>,
+        key_type: PublicKeyType,
+    ) -> Result<Self, Error>
+    where
+        T: Serializable,
+    {
+        let alg = match OpensslAlgorithm::for_key(sign_key, key_type)? {
+            Some(alg) => alg,
+            None => return Self::new(payload, unprotected, sign_key),
+        };
+
+        let unprotected: aws_nitro_enclaves_cose::header_map::HeaderMap = match unprotected {
+            Some(v) => v,
+            None => COSEHeaderMap::new(),
+        }
+        .into();
+        let payload = canonical::canonicalize(&payload.serialize_data()?)?;
+
+        let mut protected = aws_nitro_enclaves_cose::header_map::HeaderMap::new();
+        protected.insert(COSE_HEADER_ALGORITHM.into(), alg.id.into());
+        let protected = serde_cbor::to_vec(&protected)?;
+
+        let signature = alg.sign(sign_key, &Self::sig_structure(&protected, &payload)?)?;
+
+        let mut contents = ParsedArray::deserialize_data(&serde_cbor::to_vec(&(
+            ByteBuf::from(protected),
+            unprotected,
+            ByteBuf::from(payload),
+            ByteBuf::from(signature),
+        ))?)?;
+        contents.set_tag(Some(COSESIGN_TAG));
+        let inner = COSESignInner::from_bytes(&contents.serialize_data()?)?;
+
+        Ok(COSESign {
+            contents,
+
+            cached_inner: inner,
+        })
+    }
+
+    fn sig_structure(protected: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(serde_cbor::to_vec(&(
+            "Signature1",
+            ByteBuf::from(protected),
+            ByteBuf::new(),
+            ByteBuf::from(payload),
+        ))?)
+    }
+
+    fn verify_openssl(&self, key: &PKeyRef<Public>, alg: OpensslAlgorithm) -> Result<(), Error> {
+        let protected: ByteBuf = self.contents.get(0)?;
+        let payload: ByteBuf = self.contents.get(2)?;
+        let signature: ByteBuf = self.contents.get(3)?;
+
+        let protected_map: COSEHeaderMapType = serde_cbor::from_slice(&protected)?;
+        match protected_map.get(&COSE_HEADER_ALGORITHM) {
+            Some(serde_cbor::Value::Integer(id)) if *id == alg.id as i128 => {}
+            _ => return Err(Error::InconsistentValue("COSESign algorithm")),
+        }
+
+        let sig_structure = Self::sig_structure(&protected, &payload)?;
+        if alg.verify(key, &sig_structure, &signature)? {
+            Ok(())
+        } else {
+            Err(Error::InconsistentValue("Signature verification failed"))
+        }
+    }
+
+    /// Verifies the signature with a FDO public key, supporting all of its key types.
+    pub fn verify_with_public_key(&self, key: &PublicKey) -> Result<(), Error> {
+        match OpensslAlgorithm::for_key(key.pkey(), key.keytype())? {
+            Some(alg) => self.verify_openssl(key.pkey(), alg),
+            None => self.verify(key.pkey()),
+        }
+    }
+
+    pub fn verify(&self, sign_key: &dyn SigningPublicKey) -> Result<(), Error> {
+        if self.cached_inner.verify_signature(sign_key)? {
+            Ok(())
+        } else {
+            Err(Error::InconsistentValue("Signature verification failed"))
+        }
+    }
+
+    pub fn from_eat<ES>(
+        eat: EATokenPayload<ES>,
+        unprotected: Option<COSEHeaderMap>,
+        sign_key: &dyn SigningPrivateKey,
+    ) -> Result<Self, Error>
+    where
+        ES: PayloadState,
+    {
+        let claims = eat.to_map();
+        Self::new(&claims.0, unprotected, sign_key)
+    }
+
+    pub fn get_payload_unverified<T>(&self) -> Result<UnverifiedValue<T>, Error>
+    where
+        T: Serializable,
+    {
+        let payload = self.cached_inner.get_payload(None)?;
+        Ok(UnverifiedValue(T::deserialize_data(&payload)?))
+    }
+
+    pub fn get_payload<T>(&self, key: &dyn SigningPublicKey) -> Result<T, Error>
+    where
+        T: Serializable,
+    {
+        let payload = self.cached_inner.get_payload(Some(key))?;
+        T::deserialize_data(&payload)
+    }
+
+    pub fn get_payload_with_public_key<T>(&self, key: &PublicKey) -> Result<T, Error>
+    where
+        T: Serializable,
+    {
+        match OpensslAlgorithm::for_key(key.pkey(), key.keytype())? {
+            Some(alg) => {
+                self.verify_openssl(key.pkey(), alg)?;
+                let payload = self.cached_inner.get_payload(None)?;
+                T::deserialize_data(&payload)
+            }
+            None => self.get_payload(key.pkey()),
+        }
+    }
+
+    pub fn get_eat_unverified(&self) -> Result<EATokenPayload<PayloadUnverified>, Error> {
+        let claims: COSEHeaderMapType = unsafe { self.get_payload_unverified()?.into_unverified() };
+        let claims = COSEHeaderMap(claims);
+
+        eat_from_map(claims)
+    }
+
+    pub fn get_eat(
+        &self,
+        key: &dyn SigningPublicKey,
+    ) -> Result<EATokenPayload<PayloadVerified>, Error> {
+        let claims: COSEHeaderMapType = self.get_payload(key)?;
+        let claims = COSEHeaderMap(claims);
+
+        eat_from_map(claims)
+    }
+
+    pub fn get_protected_value_unverified<T>(
+        &self,
+        header_key: HeaderKeys,
+    ) -> Result<Option<UnverifiedValue<T>>, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let (protected, _) = self.cached_inner.get_protected_and_payload(None)?;
+        match protected.get(&header_key.cbor_value()) {
+            None => Ok(None),
+            Some(val) => Ok(Some(UnverifiedValue(serde_cbor::value::from_value(
+                val.clone(),
+            )?))),
+        }
+    }
+
+    pub fn get_protected_value<T>(
+        &self,
+        header_key: HeaderKeys,
+        key: &dyn SigningPublicKey,
+    ) -> Result<Option<T>, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let (protected, _) = self.cached_inner.get_protected_and_payload(Some(key))?;
+        match protected.get(&header_key.cbor_value()) {
+            None => Ok(None),
+            Some(val) => Ok(Some(serde_cbor::value::from_value(val.clone())?)),
+        }
+    }
+
+    pub fn get_unprotected_value<T>(&self, key: HeaderKeys) -> Result<Option<T>, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        match self.cached_inner.get_unprotected().get(&key.cbor_value()) {
+            None => Ok(None),
+            Some(val) => Ok(Some(serde_cbor::value::from_value(val.clone())?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_cosesign {
+    use openssl::{
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::{PKey, Private, Public},
+    };
+
+    use serde_bytes::ByteBuf;
+
+    use super::{
+        COSEHeaderMap, COSEHeaderMapType, COSESign, OpensslAlgorithm, COSE_ALGORITHM_PS256,
+        COSE_ALGORITHM_PS384, COSE_ALGORITHM_RS256, COSE_ALGORITHM_RS384, COSE_HEADER_ALGORITHM,
+    };
+    use crate::{
+        constants::{HeaderKeys, PublicKeyType},
+        errors::Error,
+        types::{new_eat, Guid, Nonce},
+        Serializable,
+    };
+
+    // RFC 8152, appendix C.2.1: single ECDSA signature, signed with key "11" of appendix C.7
+    const RFC8152_C21_SIGN1: &str = "d28443a10126a10442313154546869732069732074686520636f6e74656e742e58408eb33e4ca31d1c465ab05aac34cc6b23d58fef5c083106c4d25a91aef0b0117e2af9a291aa32e14ab834dc56ed2a223444547e01f11d3b0916e5a4c345cacb36";
+    const RFC8152_KEY11_X: &str =
+        "bac5b11cad8f99f9c72b05cf4b9e26d244dc189f745228255a219a86d6a09eff";
+    const RFC8152_KEY11_Y: &str =
+        "20138bf82dc1b6d562be0fa54ab7804a3a64b6d72ccfed6b6fb6ed28bbfc117e";
+    const RFC8152_KEY11_D: &str =
+        "57c92077664146e876760c9520d054aa93c3afb04e306705db6090308507b4d3";
+
+    fn rfc8152_key11() -> (PKey<Private>, PKey<Public>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let x = BigNum::from_hex_str(RFC8152_KEY11_X).unwrap();
+        let y = BigNum::from_hex_str(RFC8152_KEY11_Y).unwrap();
+        let d = BigNum::from_hex_str(RFC8152_KEY11_D).unwrap();
+
+        let public = EcKey::from_public_key_affine_coordinates(&group, &x, &y).unwrap();
+        let private = EcKey::from_private_components(&group, &d, public.public_key()).unwrap();
+        private.check_key().unwrap();
+
+        (
+            PKey::from_ec_key(private).unwrap(),
+            PKey::from_ec_key(public).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_cosesign_rfc8152_vector() {
+        let (_, pubkey) = rfc8152_key11();
+        let data = hex::decode(RFC8152_C21_SIGN1).unwrap();
+
+        let signed = COSESign::deserialize_data(&data).unwrap();
+        signed.verify(&*pubkey).unwrap();
+        assert_eq!(
+            signed.cached_inner.get_payload(Some(&*pubkey)).unwrap(),
+            b"This is the content."
+        );
+        // Re-serializing must not alter the signed structure
+        assert_eq!(signed.serialize_data().unwrap(), data);
+
+        // Flip a bit in the signature
+        let mut tampered = data;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let tampered = COSESign::deserialize_data(&tampered).unwrap();
+        assert!(tampered.verify(&*pubkey).is_err());
+    }
+
+    #[test]
+    fn test_cosesign_non_canonical() {
+        let (_, pubkey) = rfc8152_key11();
+        // The array length, encoded in two bytes instead of one
+        let mut data = hex::decode("d29804").unwrap();
+        data.extend_from_slice(&hex::decode(RFC8152_C21_SIGN1).unwrap()[2..]);
+
+        // Even though the signature doesn't cover the outer encoding
+        assert!(matches!(
+            COSESign::deserialize_data(&data),
+            Err(Error::NonCanonicalEncoding(_))
+        ));
+        // Canonically encoded, it parses and verifies
+        let mut data = hex::decode("d284").unwrap();
+        data.extend_from_slice(&hex::decode(RFC8152_C21_SIGN1).unwrap()[2..]);
+        COSESign::deserialize_data(&data)
+            .unwrap()
+            .verify(&*pubkey)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cosesign_rejects_untagged() {
+        let data = hex::decode(RFC8152_C21_SIGN1).unwrap();
+        // Strip the COSE_Sign1 tag
+        assert!(COSESign::deserialize_data(&data[1..]).is_err());
+    }
+
+    #[test]
+    fn test_cosesign_ecdsa_roundtrip() {
+        let (key, pubkey) = rfc8152_key11();
+
+        let mut unprotected = COSEHeaderMap::new();
+        unprotected
+            .insert(
+                HeaderKeys::CUPHNonce,
+                &serde_bytes::ByteBuf::from(vec![1, 2, 3]),
+            )
+            .unwrap();
+        let payload = "hello world".to_string();
+        let signed = COSESign::new(&payload, Some(unprotected), &*key).unwrap();
+
+        // ECDSA signatures are randomized, but must always verify
+        let signed = COSESign::deserialize_data(&signed.serialize_data().unwrap()).unwrap();
+        let verified: String = signed.get_payload(&*pubkey).unwrap();
+        assert_eq!(verified, payload);
+        let nonce: Option<serde_bytes::ByteBuf> =
+            signed.get_unprotected_value(HeaderKeys::CUPHNonce).unwrap();
+        assert_eq!(nonce.unwrap().as_ref(), &[1, 2, 3]);
+
+        let other_key =
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let other_pubkey = PKey::from_ec_key(
+            EcKey::from_public_key(other_key.group(), other_key.public_key()).unwrap(),
+        )
+        .unwrap();
+        assert!(signed.verify(&*other_pubkey).is_err());
+    }
+
+    fn verify_with_key_type(
+        signed: &COSESign,
+        key: &PKey<Public>,
+        key_type: PublicKeyType,
+    ) -> Result<(), Error> {
+        let alg = OpensslAlgorithm::for_key(key, key_type)?.unwrap();
+        signed.verify_openssl(key, alg)
+    }
+
+    #[test]
+    fn test_cosesign_rsa_roundtrip() {
+        for (bits, key_type, alg) in [
+            (2048, PublicKeyType::Rsa2048RESTR, COSE_ALGORITHM_RS256),
+            (2048, PublicKeyType::RsaPkcs, COSE_ALGORITHM_RS256),
+            (3072, PublicKeyType::RsaPkcs, COSE_ALGORITHM_RS384),
+            (2048, PublicKeyType::RsaPss, COSE_ALGORITHM_PS256),
+            (3072, PublicKeyType::RsaPss, COSE_ALGORITHM_PS384),
+        ] {
+            let key = PKey::from_rsa(openssl::rsa::Rsa::generate(bits).unwrap()).unwrap();
+            let pubkey = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+
+            let payload = "hello world".to_string();
+            let signed = COSESign::new_with_key_type(&payload, None, &key, key_type).unwrap();
+
+            let protected: ByteBuf = signed.contents.get(0).unwrap();
+            let protected: COSEHeaderMapType = serde_cbor::from_slice(&protected).unwrap();
+            assert_eq!(
+                protected.get(&COSE_HEADER_ALGORITHM),
+                Some(&serde_cbor::Value::Integer(alg as i128))
+            );
+            verify_with_key_type(&signed, &pubkey, key_type).unwrap();
+
+            // PKCS#1 v1.5 and PSS signatures can't be swapped
+            let other_type = match key_type {
+                PublicKeyType::RsaPss => PublicKeyType::RsaPkcs,
+                _ => PublicKeyType::RsaPss,
+            };
+            assert!(verify_with_key_type(&signed, &pubkey, other_type).is_err());
+
+            let other_key = PKey::from_rsa(openssl::rsa::Rsa::generate(bits).unwrap()).unwrap();
+            let other_pubkey =
+                PKey::public_key_from_der(&other_key.public_key_to_der().unwrap()).unwrap();
+            assert!(verify_with_key_type(&signed, &other_pubkey, key_type).is_err());
+        }
+    }
+
+    #[test]
+    fn test_cosesign_eddsa_roundtrip() {
+        let key = PKey::generate_ed25519().unwrap();
+        let pubkey = PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap();
+        let other_key = PKey::generate_ed25519().unwrap();
+        let other_pubkey =
+            PKey::public_key_from_der(&other_key.public_key_to_der().unwrap()).unwrap();
+
+        let payload = "hello world".to_string();
+        let signed = COSESign::new_with_pkey(&payload, None, &key).unwrap();
+
+        verify_with_key_type(&signed, &pubkey, PublicKeyType::Ed25519).unwrap();
+        assert!(verify_with_key_type(&signed, &other_pubkey, PublicKeyType::Ed25519).is_err());
+    }
+
+    #[test]
+    fn test_cosesign_eat_roundtrip() {
+        let (key, pubkey) = rfc8152_key11();
+        let nonce = Nonce::new().unwrap();
+        let guid = Guid::new().unwrap();
+
+        let eat = new_eat(Some(&"payload".to_string()), nonce.clone(), guid.clone()).unwrap();
+        let signed = COSESign::from_eat(eat, None, &*key).unwrap();
+        let signed = COSESign::deserialize_data(&signed.serialize_data().unwrap()).unwrap();
+
+        let eat = signed.get_eat(&*pubkey).unwrap();
+        assert_eq!(eat.nonce(), &nonce);
+        assert_eq!(eat.device_guid(), guid);
+        assert_eq!(
+            eat.payload::<String>().unwrap(),
+            Some("payload".to_string())
+        );
+
+        let other_key =
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let other_pubkey = PKey::from_ec_key(
+            EcKey::from_public_key(other_key.group(), other_key.public_key()).unwrap(),
+        )
+        .unwrap();
+        assert!(signed.get_eat(&*other_pubkey).is_err());
+    }
+
+    #[test]
+    fn test_cosesign_eat_invalid_claims() {
+        let (key, pubkey) = rfc8152_key11();
+
+        let mut claims = COSEHeaderMap::new();
+        claims
+            .insert(HeaderKeys::EatNonce, &Nonce::new().unwrap())
+            .unwrap();
+        let missing_ueid = COSESign::new(&claims.0, None, &*key).unwrap();
+        assert!(missing_ueid.get_eat(&*pubkey).is_err());
+
+        // An UEID must be the RAND type byte followed by the 16-byte GUID
+        claims
+            .insert(
+                HeaderKeys::EatUeid,
+                &serde_bytes::ByteBuf::from(vec![0x01, 1, 2, 3]),
+            )
+            .unwrap();
+        let short_ueid = COSESign::new(&claims.0, None, &*key).unwrap();
+        assert!(short_ueid.get_eat(&*pubkey).is_err());
+
+        claims
+            .insert(HeaderKeys::EatUeid, &serde_bytes::ByteBuf::new())
+            .unwrap();
+        let empty_ueid = COSESign::new(&claims.0, None, &*key).unwrap();
+        assert!(empty_ueid.get_eat(&*pubkey).is_err());
+    }
+}
@@ -4,26 +4,37 @@ use std::{
 };
 
 use crate::{
-    constants::HashType,
+    constants::{HashType, PublicKeyType},
     errors::Error,
+    secret::Secret,
     types::HMac,
     types::{Guid, Hash, RendezvousInfo},
-    DeviceCredential, ProtocolVersion,
+    DeviceCredential, ProtocolVersion, Serializable,
 };
 
 use aws_nitro_enclaves_cose::{error::CoseError, sign::SignatureAlgorithm};
-use openssl::{pkey::PKey, sign::Signer};
+use openssl::{
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+    pkey::{PKey, Public},
+    rsa::Rsa,
+    sign::Signer,
+};
 use serde::{Deserialize, Serialize};
 use serde_tuple::Serialize_tuple;
 use tss_esapi::{
-    attributes::ObjectAttributesBuilder, structures::PublicBuilder, traits::UnMarshall,
+    attributes::ObjectAttributesBuilder,
+    interface_types::algorithm::HashingAlgorithm,
+    structures::PublicBuilder,
+    traits::{Marshall, UnMarshall},
 };
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum KeyStorage {
     Plain {
-        hmac_secret: Vec<u8>,
-        private_key: Vec<u8>,
+        hmac_secret: Secret,
+        private_key: Secret,
     },
     Tpm {
         signing_public: Vec<u8>,
@@ -56,6 +67,46 @@ fn get_semi_tpm_ctx_and_primary(
 }
 
 impl KeyStorage {
+    /// Generates a new signing key and HMAC key inside the TPM.
+    ///
+    /// The private parts are wrapped by the TPM, so only the TPM that created them can use them.
+    pub fn new_tpm(key_type: PublicKeyType) -> Result<Self, Error> {
+        let (mut tss_context, primary_handle) = get_semi_tpm_ctx_and_primary()?;
+
+        let signing_template = semi_tpm_signing_key_template(key_type)?;
+        let hmac_template = semi_tpm_hmac_key_template(key_type)?;
+
+        let signing_key_result = tss_context.execute_with_nullauth_session(|ctx| {
+            ctx.create(primary_handle, signing_template, None, None, None, None)
+        })?;
+        let hmac_key_result = tss_context.execute_with_nullauth_session(|ctx| {
+            ctx.create(primary_handle, hmac_template, None, None, None, None)
+        })?;
+
+        Ok(KeyStorage::Tpm {
+            signing_public: signing_key_result.out_public.marshall()?,
+            signing_private: signing_key_result.out_private.to_vec(),
+            hmac_public: hmac_key_result.out_public.marshall()?,
+            hmac_private: hmac_key_result.out_private.to_vec(),
+        })
+    }
+
+    pub fn public_key(&self) -> Result<PKey<Public>, Error> {
+        match self {
+            KeyStorage::Plain {
+                ref private_key, ..
+            } => {
+                let private_key = PKey::private_key_from_der(private_key)?;
+                Ok(PKey::public_key_from_der(
+                    &private_key.public_key_to_der()?,
+                )?)
+            }
+            KeyStorage::Tpm { signing_public, .. } => {
+                tpm_public_key(&tss_esapi::structures::Public::unmarshall(signing_public)?)
+            }
+        }
+    }
+
     pub fn perform_hmac(&self, data: &[u8], hmac_type: HashType) -> Result<HMac, Error> {
         match self {
             KeyStorage::Plain {
@@ -128,6 +179,43 @@ impl KeyStorage {
     }
 }
 
+/// Converts the public area of a TPM key to an OpenSSL public key
+pub fn tpm_public_key(public: &tss_esapi::structures::Public) -> Result<PKey<Public>, Error> {
+    match public {
+        tss_esapi::structures::Public::Rsa {
+            parameters, unique, ..
+        } => {
+            // An exponent of zero stands for the default exponent
+            let exponent = match parameters.exponent().value() {
+                0 => 65537,
+                exponent => exponent,
+            };
+            let exponent = BigNum::from_u32(exponent)?;
+            let modulus = BigNum::from_slice(unique.value())?;
+            Ok(PKey::from_rsa(Rsa::from_public_components(
+                modulus, exponent,
+            )?)?)
+        }
+        tss_esapi::structures::Public::Ecc {
+            parameters, unique, ..
+        } => {
+            let curve = match parameters.ecc_curve() {
+                tss_esapi::interface_types::ecc::EccCurve::NistP256 => Nid::X9_62_PRIME256V1,
+                tss_esapi::interface_types::ecc::EccCurve::NistP384 => Nid::SECP384R1,
+                _ => return Err(Error::UnsupportedAlgorithm),
+            };
+            let curve = EcGroup::from_curve_name(curve)?;
+            let x = BigNum::from_slice(unique.x())?;
+            let y = BigNum::from_slice(unique.y())?;
+
+            Ok(PKey::from_ec_key(
+                EcKey::from_public_key_affine_coordinates(&curve, &x, &y)?,
+            )?)
+        }
+        _ => Err(Error::UnsupportedAlgorithm),
+    }
+}
+
 pub fn semi_tpm_primary_key_template() -> Result<tss_esapi::structures::Public, Error> {
     let primary_attributes = ObjectAttributesBuilder::new()
         .with_fixed_tpm(true)
@@ -157,6 +245,75 @@ pub fn semi_tpm_primary_key_template() -> Result<tss_esapi::structures::Public,
         .map_err(Error::from)
 }
 
+pub fn semi_tpm_hmac_key_template(
+    key_type: PublicKeyType,
+) -> Result<tss_esapi::structures::Public, Error> {
+    let hash_algo = match key_type {
+        PublicKeyType::SECP256R1 => HashingAlgorithm::Sha256,
+        PublicKeyType::SECP384R1 => HashingAlgorithm::Sha384,
+        _ => return Err(Error::UnsupportedAlgorithm),
+    };
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_sensitive_data_origin(true)
+        .with_restricted(false)
+        .with_sign_encrypt(true)
+        .with_user_with_auth(true)
+        .build()?;
+    PublicBuilder::new()
+        .with_object_attributes(attributes)
+        .with_public_algorithm(tss_esapi::interface_types::algorithm::PublicAlgorithm::KeyedHash)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_keyed_hash_parameters(tss_esapi::structures::PublicKeyedHashParameters::new(
+            tss_esapi::structures::KeyedHashScheme::Hmac {
+                hmac_scheme: tss_esapi::structures::HmacScheme::new(hash_algo),
+            },
+        ))
+        .with_keyed_hash_unique_identifier(Default::default())
+        .build()
+        .map_err(Error::from)
+}
+
+pub fn semi_tpm_signing_key_template(
+    key_type: PublicKeyType,
+) -> Result<tss_esapi::structures::Public, Error> {
+    let (curve, hash_algo) = match key_type {
+        PublicKeyType::SECP256R1 => (
+            tss_esapi::interface_types::ecc::EccCurve::NistP256,
+            HashingAlgorithm::Sha256,
+        ),
+        PublicKeyType::SECP384R1 => (
+            tss_esapi::interface_types::ecc::EccCurve::NistP384,
+            HashingAlgorithm::Sha384,
+        ),
+        _ => return Err(Error::UnsupportedAlgorithm),
+    };
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_user_with_auth(true)
+        .with_sensitive_data_origin(true)
+        .with_restricted(false)
+        .with_sign_encrypt(true)
+        .build()?;
+    PublicBuilder::new()
+        .with_object_attributes(attributes)
+        .with_public_algorithm(tss_esapi::interface_types::algorithm::PublicAlgorithm::Ecc)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_ecc_parameters(tss_esapi::structures::PublicEccParameters::new(
+            tss_esapi::structures::SymmetricDefinitionObject::Null,
+            tss_esapi::structures::EccScheme::EcDsa(tss_esapi::structures::HashScheme::new(
+                hash_algo,
+            )),
+            curve,
+            tss_esapi::structures::KeyDerivationFunctionScheme::Null,
+        ))
+        .with_ecc_unique_identifier(Default::default())
+        .build()
+        .map_err(Error::from)
+}
+
 #[derive(Debug, Serialize_tuple, Deserialize)]
 pub struct FileDeviceCredential {
     pub active: bool,             // Active
@@ -169,6 +326,46 @@ pub struct FileDeviceCredential {
     pub key_storage: KeyStorage,
 }
 
+// Device credential files start with this magic, followed by a big-endian u16
+// format version and the payload. Files without the magic are in the legacy
+// format, which is the payload without any envelope.
+const FILE_MAGIC: &[u8] = b"FDODC";
+const FILE_HEADER_LEN: usize = FILE_MAGIC.len() + 2;
+pub const FILE_FORMAT_VERSION: u16 = 1;
+
+impl FileDeviceCredential {
+    /// Returns the format version of a device credential file, or None for the legacy format
+    pub fn file_format_version(data: &[u8]) -> Result<Option<u16>, Error> {
+        if !data.starts_with(FILE_MAGIC) {
+            return Ok(None);
+        }
+        if data.len() < FILE_HEADER_LEN {
+            return Err(Error::InconsistentValue("device credential file header"));
+        }
+        Ok(Some(u16::from_be_bytes([
+            data[FILE_MAGIC.len()],
+            data[FILE_MAGIC.len() + 1],
+        ])))
+    }
+
+    /// Parses a device credential file, in either the versioned or the legacy format
+    pub fn from_file_data(data: &[u8]) -> Result<Self, Error> {
+        match Self::file_format_version(data)? {
+            None => Self::deserialize_data(data),
+            Some(FILE_FORMAT_VERSION) => Self::deserialize_data(&data[FILE_HEADER_LEN..]),
+            Some(version) => Err(Error::UnsupportedDeviceCredentialFileVersion(version)),
+        }
+    }
+
+    /// Serializes the device credential in the current versioned file format
+    pub fn to_file_data(&self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::from(FILE_MAGIC);
+        data.extend_from_slice(&FILE_FORMAT_VERSION.to_be_bytes());
+        self.serialize_to_writer(&mut data)?;
+        Ok(data)
+    }
+}
+
 impl DeviceCredential for FileDeviceCredential {
     fn is_active(&self) -> bool {
         self.active
@@ -186,6 +383,10 @@ impl DeviceCredential for FileDeviceCredential {
         }
     }
 
+    fn perform_hmac(&self, data: &[u8], hmac_type: HashType) -> Result<HMac, Error> {
+        self.key_storage.perform_hmac(data, hmac_type)
+    }
+
     fn device_info(&self) -> &str {
         &self.device_info
     }
@@ -202,6 +403,10 @@ impl DeviceCredential for FileDeviceCredential {
         &self.pubkey_hash
     }
 
+    fn requires_tpm(&self) -> bool {
+        matches!(self.key_storage, KeyStorage::Tpm { .. })
+    }
+
     fn get_signer(
         &self,
     ) -> Result<Box<dyn aws_nitro_enclaves_cose::crypto::SigningPrivateKey>, Error> {
@@ -1,4 +1,5 @@
 use crate::{
+    constants::HashType,
     errors::Error,
     types::{Guid, HMac, Hash, RendezvousInfo},
     ProtocolVersion,
@@ -8,11 +9,17 @@ pub trait DeviceCredential: std::fmt::Debug {
     fn is_active(&self) -> bool;
     fn protocol_version(&self) -> ProtocolVersion;
     fn verify_hmac(&self, data: &[u8], hmac: &HMac) -> Result<(), Error>;
+    fn perform_hmac(&self, data: &[u8], hmac_type: HashType) -> Result<HMac, Error>;
     fn device_info(&self) -> &str;
     fn device_guid(&self) -> &Guid;
     fn rendezvous_info(&self) -> &RendezvousInfo;
     fn manufacturer_pubkey_hash(&self) -> &Hash;
 
+    /// Whether the keys of the credential are stored in a TPM
+    fn requires_tpm(&self) -> bool {
+        false
+    }
+
     fn get_signer(
         &self,
     ) -> Result<Box<dyn aws_nitro_enclaves_cose::crypto::SigningPrivateKey>, Error>;
@@ -0,0 +1,323 @@
+//! CBOR diagnostic notation (RFC 8949, section 8)
+//!
+//! This is used as a human readable interchange format for structures like
+//! Ownership Vouchers. The supported subset of the notation is: integers,
+//! floats, text and byte strings (`h'..'` and `'..'`), arrays, maps, tags,
+//! booleans and `null`.
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use ciborium::value::{Integer, Value};
+
+use crate::{errors::Result, Error};
+
+const INDENT: &str = "  ";
+
+/// Formats a CBOR value in diagnostic notation
+pub fn to_diagnostic(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, 0);
+    out
+}
+
+fn write_indent(out: &mut String, level: usize) {
+    for _ in 0..level {
+        out.push_str(INDENT);
+    }
+}
+
+fn write_text(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_value(out: &mut String, value: &Value, level: usize) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Integer(i) => {
+            let _ = write!(out, "{}", i128::from(*i));
+        }
+        Value::Float(f) => {
+            let _ = write!(out, "{f:?}");
+        }
+        Value::Bytes(b) => {
+            let _ = write!(out, "h'{}'", hex::encode(b));
+        }
+        Value::Text(t) => write_text(out, t),
+        Value::Tag(tag, inner) => {
+            let _ = write!(out, "{tag}(");
+            write_value(out, inner, level);
+            out.push(')');
+        }
+        Value::Array(items) => {
+            if items.is_empty() {
+                out.push_str("[]");
+                return;
+            }
+            out.push_str("[\n");
+            for (pos, item) in items.iter().enumerate() {
+                write_indent(out, level + 1);
+                write_value(out, item, level + 1);
+                if pos + 1 != items.len() {
+                    out.push(',');
+                }
+                out.push('\n');
+            }
+            write_indent(out, level);
+            out.push(']');
+        }
+        Value::Map(entries) => {
+            if entries.is_empty() {
+                out.push_str("{}");
+                return;
+            }
+            out.push_str("{\n");
+            for (pos, (key, value)) in entries.iter().enumerate() {
+                write_indent(out, level + 1);
+                write_value(out, key, level + 1);
+                out.push_str(": ");
+                write_value(out, value, level + 1);
+                if pos + 1 != entries.len() {
+                    out.push(',');
+                }
+                out.push('\n');
+            }
+            write_indent(out, level);
+            out.push('}');
+        }
+        _ => out.push_str("undefined"),
+    }
+}
+
+/// Parses a CBOR value from diagnostic notation
+pub fn from_diagnostic(text: &str) -> Result<Value> {
+    let mut parser = Parser {
+        input: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace()?;
+    if parser.pos != parser.input.len() {
+        return Err(parser.error("trailing data"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> Error {
+        Error::DiagnosticParseError(format!("{} at offset {}", msg, self.pos))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else if c == b'/' {
+                // Comments are enclosed in slashes
+                self.pos += 1;
+                while self
+                    .peek()
+                    .ok_or_else(|| self.error("unterminated comment"))?
+                    != b'/'
+                {
+                    self.pos += 1;
+                }
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        self.skip_whitespace()?;
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{}'", expected as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        if self.input[self.pos..].starts_with(keyword.as_bytes()) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        self.skip_whitespace()?;
+        match self.peek() {
+            None => Err(self.error("unexpected end of input")),
+            Some(b'[') => self.parse_array(),
+            Some(b'{') => self.parse_map(),
+            Some(b'"') => Ok(Value::Text(self.parse_text()?)),
+            Some(b'\'') => Ok(Value::Bytes(self.parse_text()?.into_bytes())),
+            Some(b'h') if self.input.get(self.pos + 1) == Some(&b'\'') => self.parse_hex(),
+            Some(c) if c == b'-' || c.is_ascii_digit() => self.parse_number(),
+            Some(_) => {
+                if self.consume_keyword("true") {
+                    Ok(Value::Bool(true))
+                } else if self.consume_keyword("false") {
+                    Ok(Value::Bool(false))
+                } else if self.consume_keyword("null") || self.consume_keyword("undefined") {
+                    Ok(Value::Null)
+                } else {
+                    Err(self.error("unexpected character"))
+                }
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace()?;
+            if self.peek() == Some(b']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+            if !items.is_empty() {
+                self.expect(b',')?;
+            }
+            items.push(self.parse_value()?);
+        }
+    }
+
+    fn parse_map(&mut self) -> Result<Value> {
+        self.expect(b'{')?;
+        let mut entries: Vec<(Value, Value)> = Vec::new();
+        loop {
+            self.skip_whitespace()?;
+            if self.peek() == Some(b'}') {
+                self.pos += 1;
+                return Ok(Value::Map(entries));
+            }
+            if !entries.is_empty() {
+                self.expect(b',')?;
+            }
+            let key = self.parse_value()?;
+            self.expect(b':')?;
+            let value = self.parse_value()?;
+            if entries.iter().any(|(k, _)| k == &key) {
+                return Err(self.error("duplicate map key"));
+            }
+            entries.push((key, value));
+        }
+    }
+
+    fn parse_text(&mut self) -> Result<String> {
+        let quote = self.peek().unwrap();
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                c if c == quote => break,
+                b'\\' => {
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match escaped {
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let code = self
+                                .input
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|code| std::str::from_utf8(code).ok())
+                                .and_then(|code| u32::from_str_radix(code, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?;
+                            self.pos += 4;
+                            let mut buf = [0; 4];
+                            out.extend_from_slice(code.encode_utf8(&mut buf).as_bytes());
+                        }
+                        c => out.push(c),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8 in string"))
+    }
+
+    fn parse_hex(&mut self) -> Result<Value> {
+        // Skip the h'
+        self.pos += 2;
+        let mut digits = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated byte string")),
+                Some(b'\'') => break,
+                Some(c) if c.is_ascii_whitespace() => {}
+                Some(c) => digits.push(c as char),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(Value::Bytes(hex::decode(digits)?))
+    }
+
+    fn parse_number(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == b'-' || c == b'+' || c == b'.' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let number = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+
+        if let Ok(value) = number.parse::<i128>() {
+            self.skip_whitespace()?;
+            if self.peek() == Some(b'(') {
+                let tag = u64::try_from(value).map_err(|_| self.error("invalid tag number"))?;
+                self.pos += 1;
+                let inner = self.parse_value()?;
+                self.expect(b')')?;
+                return Ok(Value::Tag(tag, Box::new(inner)));
+            }
+            let value = Integer::try_from(value).map_err(|_| self.error("integer out of range"))?;
+            return Ok(Value::Integer(value));
+        }
+        number
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| self.error("invalid number"))
+    }
+}
@@ -39,25 +39,43 @@ pub struct RendezvousInterpretedDirective {
 }
 
 impl RendezvousInterpretedDirective {
-    pub fn get_urls(&self) -> Vec<String> {
-        let protocol_text = match self.protocol {
-            RendezvousProtocolValue::Http => "http",
-            RendezvousProtocolValue::Https => "https",
+    fn host_urls(&self, host: &str) -> Vec<String> {
+        let protocol_texts: &[&str] = match self.protocol {
+            RendezvousProtocolValue::Http => &["http"],
+            RendezvousProtocolValue::Https => &["https"],
+            // REST leaves the choice to the client: prefer HTTPS, fall back to HTTP
+            RendezvousProtocolValue::Rest => &["https", "http"],
             _ => return Vec::new(),
         };
 
-        let mut urls = Vec::new();
+        protocol_texts
+            .iter()
+            .map(|protocol_text| format!("{}://{}:{}", protocol_text, host, self.port))
+            .collect()
+    }
 
-        if let Some(dns_name) = &self.dns_name {
-            urls.push(format!("{}://{}:{}", protocol_text, dns_name, self.port));
+    /// Returns the URLs using the DNS name of this directive
+    pub fn get_dns_urls(&self) -> Vec<String> {
+        match &self.dns_name {
+            Some(dns_name) => self.host_urls(dns_name),
+            None => Vec::new(),
         }
+    }
 
-        if let Some(ip_addresses) = self.ip_addresses.as_ref() {
-            for ip_address in ip_addresses {
-                urls.push(format!("{}://{}:{}", protocol_text, ip_address, self.port));
-            }
-        }
+    /// Returns the URLs using the IP addresses of this directive
+    pub fn get_ip_urls(&self) -> Vec<String> {
+        self.ip_addresses
+            .iter()
+            .flatten()
+            .flat_map(|ip_address| self.host_urls(&ip_address.as_url_host()))
+            .collect()
+    }
 
+    /// Returns the URLs to try for this directive, in order of preference:
+    /// the DNS name first, then the literal IP addresses
+    pub fn get_urls(&self) -> Vec<String> {
+        let mut urls = self.get_dns_urls();
+        urls.extend(self.get_ip_urls());
         urls
     }
 
@@ -264,13 +282,22 @@ impl X5Bag {
         P: openssl::pkey::HasPublic,
     {
         for (_, cert) in self.certs.iter() {
-            if cert.public_key().unwrap().public_eq(to_find) {
+            // Certificates with key types OpenSSL doesn't support can't match
+            if cert
+                .public_key()
+                .map(|key| key.public_eq(to_find))
+                .unwrap_or(false)
+            {
                 return true;
             }
         }
         false
     }
 
+    pub fn certs(&self) -> impl Iterator<Item = &X509> {
+        self.certs.values()
+    }
+
     pub fn into_vec(mut self) -> Vec<X509> {
         self.certs.drain().map(|(_, v)| v).collect()
     }
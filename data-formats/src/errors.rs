@@ -14,13 +14,17 @@ pub enum ChainError {
     NoTrustedRoot,
     #[error("Non-issuer certificate at position {0}")]
     NonIssuer(usize),
+    #[error("Certificate at position {0} failed validation: {1}")]
+    Validation(usize, &'static str),
 }
 
 #[derive(Error, Debug)]
 #[non_exhaustive]
 pub enum Error {
+    #[cfg(feature = "crypto")]
     #[error("Cryptographic error stack: {0}")]
     CryptoStack(#[from] openssl::error::ErrorStack),
+    #[cfg(feature = "crypto")]
     #[error("Key derivation error: {0:?}")]
     Kdf(#[from] openssl_kdf::KdfError),
     #[error("Serialization error: {0}")]
@@ -29,6 +33,7 @@ pub enum Error {
     CiboriumDeError(#[from] ciborium::de::Error<std::io::Error>),
     #[error("Serialization error (ciborium): {0}")]
     CiboriumSerError(#[from] ciborium::ser::Error<std::io::Error>),
+    #[cfg(feature = "crypto")]
     #[error("COSE error: {0}")]
     Cose(#[from] aws_nitro_enclaves_cose::error::CoseError),
     #[error("Invalid hash value")]
@@ -55,20 +60,28 @@ pub enum Error {
     KeyExchangeError(&'static str),
     #[error("Invalid certificate chain encountered: {0}")]
     InvalidChain(ChainError),
+    #[cfg(feature = "crypto")]
     #[error("Array parse error: {0}")]
     ArrayParseError(#[from] crate::cborparser::ArrayParseError),
     #[error("PEM parse error")]
     PemError(#[from] pem::PemError),
     #[error("Invalid PEM tag: {0}")]
     InvalidPemTag(String),
+    #[error("Error parsing CBOR diagnostic notation: {0}")]
+    DiagnosticParseError(String),
+    #[error("Non-canonical CBOR encoding: {0}")]
+    NonCanonicalEncoding(&'static str),
     #[error("I/O error")]
     IoError(#[from] std::io::Error),
     #[error("Error parsing hex value: {0}")]
     HexError(#[from] hex::FromHexError),
     #[error("Error parsing ip address: {0}")]
     AddrError(#[from] std::net::AddrParseError),
+    #[error("Unsupported device credential file format version {0}")]
+    UnsupportedDeviceCredentialFileVersion(u16),
     #[error("Unsupported version structure encountered. Version: {0:?}")]
     UnsupportedVersion(Option<crate::constants::ProtocolVersion>),
+    #[cfg(feature = "crypto")]
     #[error("TPM/TSS error: {0:?}")]
     TssError(#[from] tss_esapi::Error),
 }
@@ -0,0 +1,278 @@
+//! Read-only inspection of Ownership Vouchers and device credentials
+//!
+//! Unlike the full data structures, this does not need any cryptographic
+//! library: nothing is verified and no public keys are loaded, the structures
+//! are only parsed to show what they contain. This makes it usable on targets
+//! without OpenSSL, like wasm32-unknown-unknown, when building without the
+//! `crypto` feature.
+
+use ciborium::value::Value;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_tuple::Deserialize_tuple;
+
+use crate::{
+    constants::{HashType, PublicKeyEncoding, PublicKeyType},
+    diagnostic::to_diagnostic,
+    errors::Result,
+    Error, ProtocolVersion,
+};
+
+// Keep in sync with ownershipvoucher and devicecredential::file
+const VOUCHER_PEM_TAG: &str = "OWNERSHIP VOUCHER";
+const DEVICE_CREDENTIAL_FILE_MAGIC: &[u8] = b"FDODC";
+const DEVICE_CREDENTIAL_FILE_HEADER_LEN: usize = DEVICE_CREDENTIAL_FILE_MAGIC.len() + 2;
+const COSESIGN_TAG: u64 = 18;
+
+#[derive(Debug, Deserialize_tuple)]
+struct RawOwnershipVoucher {
+    protocol_version: ProtocolVersion,
+    header: ByteBuf,
+    _header_hmac: IgnoredAny,
+    device_certificate_chain: Option<Vec<ByteBuf>>,
+    entries: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize_tuple)]
+struct RawOwnershipVoucherHeader {
+    protocol_version: ProtocolVersion,
+    guid: ByteBuf,
+    rendezvous_info: Value,
+    device_info: String,
+    manufacturer_public_key: RawPublicKey,
+    device_certificate_chain_hash: Option<RawHash>,
+}
+
+#[derive(Debug, Deserialize_tuple)]
+struct RawOwnershipVoucherEntryPayload {
+    _hash_previous_entry: IgnoredAny,
+    _hash_header_info: IgnoredAny,
+    _extra: IgnoredAny,
+    public_key: RawPublicKey,
+}
+
+#[derive(Debug, Deserialize_tuple)]
+struct RawPublicKey {
+    key_type: PublicKeyType,
+    encoding: PublicKeyEncoding,
+    _data: IgnoredAny,
+}
+
+#[derive(Debug, Deserialize_tuple)]
+struct RawHash {
+    hash_type: HashType,
+    value: ByteBuf,
+}
+
+#[derive(Debug, Deserialize_tuple)]
+struct RawFileDeviceCredential {
+    active: bool,
+    protver: ProtocolVersion,
+    device_info: String,
+    guid: ByteBuf,
+    rvinfo: Value,
+    pubkey_hash: RawHash,
+    key_storage: RawKeyStorage,
+}
+
+// The key material itself is never read, so it does not end up in a summary
+#[derive(Debug, Deserialize)]
+enum RawKeyStorage {
+    Plain(IgnoredAny),
+    Tpm(IgnoredAny),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicKeySummary {
+    pub key_type: String,
+    pub encoding: String,
+}
+
+impl From<RawPublicKey> for PublicKeySummary {
+    fn from(raw: RawPublicKey) -> Self {
+        PublicKeySummary {
+            key_type: format!("{:?}", raw.key_type),
+            encoding: format!("{:?}", raw.encoding),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HashSummary {
+    pub hash_type: String,
+    pub value: String,
+}
+
+impl From<RawHash> for HashSummary {
+    fn from(raw: RawHash) -> Self {
+        HashSummary {
+            hash_type: format!("{:?}", raw.hash_type),
+            value: hex::encode(raw.value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OwnershipVoucherSummary {
+    pub protocol_version: u16,
+    pub guid: String,
+    pub device_info: String,
+    /// Rendezvous info, in CBOR diagnostic notation
+    pub rendezvous_info: String,
+    pub manufacturer_public_key: PublicKeySummary,
+    pub device_certificate_chain_hash: Option<HashSummary>,
+    pub num_device_certificates: usize,
+    /// The public key of every owner the voucher was extended to, in order
+    pub owner_public_keys: Vec<PublicKeySummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceCredentialSummary {
+    /// None for the legacy file format without a header
+    pub file_format_version: Option<u16>,
+    pub active: bool,
+    pub protocol_version: u16,
+    pub guid: String,
+    pub device_info: String,
+    /// Rendezvous info, in CBOR diagnostic notation
+    pub rendezvous_info: String,
+    pub public_key_hash: HashSummary,
+    pub key_storage: String,
+}
+
+fn guid_to_string(guid: &[u8]) -> Result<String> {
+    uuid::Uuid::from_slice(guid)
+        .map(|guid| guid.to_string())
+        .map_err(|_| Error::InconsistentValue("guid"))
+}
+
+fn entry_public_key(entry: Value) -> Result<PublicKeySummary> {
+    let entry = match entry {
+        Value::Tag(COSESIGN_TAG, entry) => *entry,
+        Value::Tag(_, _) => return Err(Error::InconsistentValue("Invalid tag on COSESign")),
+        _ => return Err(Error::InconsistentValue("Missing tag on COSESign")),
+    };
+    let payload = match entry {
+        Value::Array(mut parts) if parts.len() == 4 => parts.swap_remove(2),
+        _ => return Err(Error::InconsistentValue("COSESign structure")),
+    };
+    let payload = match payload {
+        Value::Bytes(payload) => payload,
+        _ => return Err(Error::InconsistentValue("COSESign payload")),
+    };
+    let payload: RawOwnershipVoucherEntryPayload = ciborium::de::from_reader(&payload[..])?;
+    Ok(payload.public_key.into())
+}
+
+/// Summarizes an ownership voucher, either PEM encoded or raw
+pub fn inspect_ownership_voucher(data: &[u8]) -> Result<OwnershipVoucherSummary> {
+    let raw: RawOwnershipVoucher = if data.starts_with(b"--") {
+        let parsed = pem::parse(data)?;
+        if parsed.tag() != VOUCHER_PEM_TAG {
+            return Err(Error::InvalidPemTag(parsed.tag().to_string()));
+        }
+        ciborium::de::from_reader(parsed.contents())?
+    } else {
+        ciborium::de::from_reader(data)?
+    };
+    let header: RawOwnershipVoucherHeader = ciborium::de::from_reader(&raw.header[..])?;
+    if header.protocol_version != raw.protocol_version {
+        return Err(Error::InconsistentValue("protocol version"));
+    }
+
+    Ok(OwnershipVoucherSummary {
+        protocol_version: raw.protocol_version as u16,
+        guid: guid_to_string(&header.guid)?,
+        device_info: header.device_info,
+        rendezvous_info: to_diagnostic(&header.rendezvous_info),
+        manufacturer_public_key: header.manufacturer_public_key.into(),
+        device_certificate_chain_hash: header.device_certificate_chain_hash.map(HashSummary::from),
+        num_device_certificates: raw.device_certificate_chain.map_or(0, |chain| chain.len()),
+        owner_public_keys: raw
+            .entries
+            .into_iter()
+            .map(entry_public_key)
+            .collect::<Result<_>>()?,
+    })
+}
+
+/// Summarizes a device credential file, in either the versioned or the legacy format
+///
+/// The key material is not part of the summary.
+pub fn inspect_device_credential(data: &[u8]) -> Result<DeviceCredentialSummary> {
+    let (file_format_version, payload) = if data.starts_with(DEVICE_CREDENTIAL_FILE_MAGIC) {
+        if data.len() < DEVICE_CREDENTIAL_FILE_HEADER_LEN {
+            return Err(Error::InconsistentValue("device credential file header"));
+        }
+        let version = u16::from_be_bytes([
+            data[DEVICE_CREDENTIAL_FILE_MAGIC.len()],
+            data[DEVICE_CREDENTIAL_FILE_MAGIC.len() + 1],
+        ]);
+        if version != 1 {
+            return Err(Error::UnsupportedDeviceCredentialFileVersion(version));
+        }
+        (Some(version), &data[DEVICE_CREDENTIAL_FILE_HEADER_LEN..])
+    } else {
+        (None, data)
+    };
+    let raw: RawFileDeviceCredential = ciborium::de::from_reader(payload)?;
+
+    Ok(DeviceCredentialSummary {
+        file_format_version,
+        active: raw.active,
+        protocol_version: raw.protver as u16,
+        guid: guid_to_string(&raw.guid)?,
+        device_info: raw.device_info,
+        rendezvous_info: to_diagnostic(&raw.rvinfo),
+        public_key_hash: raw.pubkey_hash.into(),
+        key_storage: match raw.key_storage {
+            RawKeyStorage::Plain(_) => "plain",
+            RawKeyStorage::Tpm(_) => "tpm",
+        }
+        .to_string(),
+    })
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod test {
+    use crate::{
+        constants::HashType,
+        devicecredential::{file::KeyStorage, FileDeviceCredential},
+        types::{Guid, Hash, RendezvousInfo},
+        ProtocolVersion,
+    };
+
+    use super::inspect_device_credential;
+
+    #[test]
+    fn test_inspect_device_credential() {
+        let guid = Guid::new().unwrap();
+        let devcred = FileDeviceCredential {
+            active: true,
+            protver: ProtocolVersion::Version1_1,
+            device_info: "testdevice".to_string(),
+            guid: guid.clone(),
+            rvinfo: RendezvousInfo::new(vec![]).unwrap(),
+            pubkey_hash: Hash::from_data(HashType::Sha256, b"manufacturer key").unwrap(),
+            key_storage: KeyStorage::Plain {
+                hmac_secret: vec![1; 32].into(),
+                private_key: vec![2; 32].into(),
+            },
+        };
+
+        let summary = inspect_device_credential(&devcred.to_file_data().unwrap()).unwrap();
+        assert_eq!(summary.file_format_version, Some(1));
+        assert!(summary.active);
+        assert_eq!(summary.protocol_version, 101);
+        assert_eq!(summary.guid, guid.to_string());
+        assert_eq!(summary.device_info, "testdevice");
+        assert_eq!(summary.public_key_hash.hash_type, "Sha256");
+        assert_eq!(summary.key_storage, "plain");
+
+        let mut legacy = Vec::new();
+        crate::Serializable::serialize_to_writer(&devcred, &mut legacy).unwrap();
+        let summary = inspect_device_credential(&legacy).unwrap();
+        assert_eq!(summary.file_format_version, None);
+        assert_eq!(summary.guid, guid.to_string());
+    }
+}
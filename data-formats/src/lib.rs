@@ -4,21 +4,41 @@ pub use errors::Error;
 pub mod constants;
 pub use constants::ProtocolVersion;
 
+#[cfg(feature = "crypto")]
 pub mod devicecredential;
+#[cfg(feature = "crypto")]
 pub use crate::devicecredential::DeviceCredential;
 
+#[cfg(feature = "crypto")]
 pub mod types;
 
+#[cfg(feature = "crypto")]
 pub mod enhanced_types;
 
+#[cfg(feature = "crypto")]
 pub mod ownershipvoucher;
 
+#[cfg(feature = "crypto")]
 pub mod publickey;
 
+#[cfg(feature = "crypto")]
 pub mod messages;
 
+#[cfg(feature = "crypto")]
 pub mod cborparser;
 
+#[cfg(feature = "crypto")]
+pub mod cose;
+
+#[cfg(feature = "crypto")]
+pub mod secret;
+
+pub mod canonical;
+
+pub mod diagnostic;
+
+pub mod inspect;
+
 mod serializable;
 pub use serializable::DeserializableMany;
 pub use serializable::Serializable;
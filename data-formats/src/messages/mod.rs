@@ -33,6 +33,30 @@ pub trait Message: Send + Serializable + Sized {
 
     fn encryption_requirement() -> Option<EncryptionRequirement>;
 
+    /// Whether the message can be exchanged using the wire format of `version`.
+    ///
+    /// Besides their own version, the TO0 and TO1 messages can use the 1.0 wire format.
+    /// DI and TO2 can't, as they create or use the ownership voucher, which is only
+    /// supported in the 1.1 format.
+    fn supports_protocol_version(version: ProtocolVersion) -> bool {
+        version == Self::protocol_version()
+            || (version == ProtocolVersion::Version1_0
+                && Self::message_type().is_rendezvous_protocol())
+    }
+
+    /// Serializes the message in the wire format of `version`.
+    fn serialize_for_version(&self, _version: ProtocolVersion) -> Result<Vec<u8>, crate::Error> {
+        self.serialize_data()
+    }
+
+    /// Deserializes a message received in the wire format of `version`.
+    fn deserialize_for_version(
+        _version: ProtocolVersion,
+        data: &[u8],
+    ) -> Result<Self, crate::Error> {
+        Self::deserialize_data(data)
+    }
+
     fn status_code() -> http::StatusCode {
         http::StatusCode::OK
     }
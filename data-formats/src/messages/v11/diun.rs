@@ -243,6 +243,152 @@ impl Message for ProvideKey {
 
 impl ClientMessage for ProvideKey {}
 
+/// Starts the TPM attestation of the device key, instead of ProvideKey.
+///
+/// The manufacturing server answers with a credential that only the TPM holding both the
+/// endorsement key and the attestation key can activate.
+#[derive(Debug, Serialize_tuple, Deserialize)]
+pub struct RequestAttestationChallenge {
+    endorsement_key_certificate: Vec<u8>, // DER-encoded EK certificate
+    attestation_key_public: Vec<u8>,      // Marshalled TPMT_PUBLIC of the AK
+}
+
+impl RequestAttestationChallenge {
+    pub fn new(endorsement_key_certificate: Vec<u8>, attestation_key_public: Vec<u8>) -> Self {
+        RequestAttestationChallenge {
+            endorsement_key_certificate,
+            attestation_key_public,
+        }
+    }
+
+    pub fn endorsement_key_certificate(&self) -> &[u8] {
+        &self.endorsement_key_certificate
+    }
+
+    pub fn attestation_key_public(&self) -> &[u8] {
+        &self.attestation_key_public
+    }
+}
+
+impl Message for RequestAttestationChallenge {
+    fn message_type() -> MessageType {
+        MessageType::DIUNRequestAttestationChallenge
+    }
+
+    fn is_valid_previous_message(message_type: Option<MessageType>) -> bool {
+        matches!(message_type, Some(MessageType::DIUNProvideKeyParameters))
+    }
+
+    fn encryption_requirement() -> Option<EncryptionRequirement> {
+        Some(EncryptionRequirement::MustBeEncrypted)
+    }
+
+    fn protocol_version() -> crate::ProtocolVersion {
+        crate::ProtocolVersion::Version1_1
+    }
+}
+
+impl ClientMessage for RequestAttestationChallenge {}
+
+#[derive(Debug, Serialize_tuple, Deserialize)]
+pub struct AttestationChallenge {
+    credential_blob: Vec<u8>, // TPM2B_ID_OBJECT buffer, as returned by TPM2_MakeCredential
+    encrypted_secret: Vec<u8>, // TPM2B_ENCRYPTED_SECRET buffer
+}
+
+impl AttestationChallenge {
+    pub fn new(credential_blob: Vec<u8>, encrypted_secret: Vec<u8>) -> Self {
+        AttestationChallenge {
+            credential_blob,
+            encrypted_secret,
+        }
+    }
+
+    pub fn credential_blob(&self) -> &[u8] {
+        &self.credential_blob
+    }
+
+    pub fn encrypted_secret(&self) -> &[u8] {
+        &self.encrypted_secret
+    }
+}
+
+impl Message for AttestationChallenge {
+    fn message_type() -> MessageType {
+        MessageType::DIUNAttestationChallenge
+    }
+
+    fn is_valid_previous_message(message_type: Option<MessageType>) -> bool {
+        matches!(
+            message_type,
+            Some(MessageType::DIUNRequestAttestationChallenge)
+        )
+    }
+
+    fn encryption_requirement() -> Option<EncryptionRequirement> {
+        Some(EncryptionRequirement::MustBeEncrypted)
+    }
+
+    fn protocol_version() -> crate::ProtocolVersion {
+        crate::ProtocolVersion::Version1_1
+    }
+}
+
+impl ServerMessage for AttestationChallenge {}
+
+/// Provides a TPM-resident device key, certified by the attestation key.
+///
+/// The certification uses the activated credential of the AttestationChallenge as
+/// qualifying data.
+#[derive(Debug, Serialize_tuple, Deserialize)]
+pub struct ProvideAttestedKey {
+    public_key: Vec<u8>,        // Marshalled TPMT_PUBLIC of the device key
+    certify_info: Vec<u8>,      // Marshalled TPMS_ATTEST, as returned by TPM2_Certify
+    certify_signature: Vec<u8>, // AK signature over certify_info, PKCS#1 v1.5 or DER-encoded ECDSA
+}
+
+impl ProvideAttestedKey {
+    pub fn new(public_key: Vec<u8>, certify_info: Vec<u8>, certify_signature: Vec<u8>) -> Self {
+        ProvideAttestedKey {
+            public_key,
+            certify_info,
+            certify_signature,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn certify_info(&self) -> &[u8] {
+        &self.certify_info
+    }
+
+    pub fn certify_signature(&self) -> &[u8] {
+        &self.certify_signature
+    }
+}
+
+impl Message for ProvideAttestedKey {
+    fn message_type() -> MessageType {
+        MessageType::DIUNProvideAttestedKey
+    }
+
+    fn is_valid_previous_message(message_type: Option<MessageType>) -> bool {
+        matches!(message_type, Some(MessageType::DIUNAttestationChallenge))
+    }
+
+    fn encryption_requirement() -> Option<EncryptionRequirement> {
+        Some(EncryptionRequirement::MustBeEncrypted)
+    }
+
+    fn protocol_version() -> crate::ProtocolVersion {
+        crate::ProtocolVersion::Version1_1
+    }
+}
+
+impl ClientMessage for ProvideAttestedKey {}
+
 #[derive(Debug, Serialize_tuple, Deserialize)]
 pub struct Done {
     mfg_string_type: MfgStringType,
@@ -264,7 +410,10 @@ impl Message for Done {
     }
 
     fn is_valid_previous_message(message_type: Option<MessageType>) -> bool {
-        matches!(message_type, Some(MessageType::DIUNProvideKey))
+        matches!(
+            message_type,
+            Some(MessageType::DIUNProvideKey) | Some(MessageType::DIUNProvideAttestedKey)
+        )
     }
 
     fn encryption_requirement() -> Option<EncryptionRequirement> {
@@ -51,6 +51,20 @@ impl ErrorMessage {
     }
 }
 
+impl std::fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (error code {:?} ({}), previous message {:?}, uuid {:x})",
+            self.error_string,
+            self.error_code,
+            self.error_code as u16,
+            self.previous_message_type,
+            self.error_uuid
+        )
+    }
+}
+
 impl Message for ErrorMessage {
     fn message_type() -> MessageType {
         MessageType::Error
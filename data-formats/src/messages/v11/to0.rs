@@ -164,6 +164,35 @@ impl Message for OwnerSign {
     fn protocol_version() -> crate::ProtocolVersion {
         crate::ProtocolVersion::Version1_1
     }
+
+    // In 1.0, TO0d is not wrapped in a byte string. The raw bytes are kept as they are,
+    // so that the TO0d hash in TO1d still matches.
+    fn serialize_for_version(&self, version: crate::ProtocolVersion) -> Result<Vec<u8>, Error> {
+        match version {
+            crate::ProtocolVersion::Version1_0 => {
+                // Array of two elements
+                let mut output = vec![0x82];
+                output.extend_from_slice(&self.cached_to0d);
+                self.cached_to1d.serialize_to_writer(&mut output)?;
+                Ok(output)
+            }
+            _ => self.serialize_data(),
+        }
+    }
+
+    fn deserialize_for_version(
+        version: crate::ProtocolVersion,
+        data: &[u8],
+    ) -> Result<Self, Error> {
+        match version {
+            crate::ProtocolVersion::Version1_0 => {
+                let contents: ParsedArray<crate::cborparser::ParsedArraySize2> =
+                    ParsedArray::deserialize_data(data)?;
+                OwnerSign::new(ByteBuf::from(contents.get_raw(0)), contents.get(1)?)
+            }
+            _ => OwnerSign::deserialize_data(data),
+        }
+    }
 }
 
 impl ClientMessage for OwnerSign {}
@@ -1,20 +1,24 @@
 use std::collections::HashMap;
 use std::ops::Range;
+use std::sync::OnceLock;
 
-use openssl::pkey::{PKeyRef, Private};
+use aws_nitro_enclaves_cose::crypto::SigningPrivateKey;
+use openssl::pkey::{HasPublic, PKeyRef, Private, Public};
 use serde::Deserialize;
 use serde_bytes::ByteBuf;
 use serde_tuple::Serialize_tuple;
 
 use crate::{
+    canonical,
     cborparser::{
-        ParsedArray, ParsedArrayBuilder, ParsedArraySize5, ParsedArraySize6, ParsedArraySizeDynamic,
+        ParsedArray, ParsedArrayBuilder, ParsedArraySize5, ParsedArraySize6,
+        ParsedArraySizeDynamic, RawArrayIter,
     },
-    constants::HashType,
+    constants::{HashType, PublicKeyType},
     errors::Result,
     publickey::{PublicKey, X5Chain},
     serializable::MaybeSerializable,
-    types::{COSESign, Guid, HMac, Hash, RendezvousInfo, UnverifiedValue},
+    types::{COSESign, CborSimpleType, Guid, HMac, Hash, RendezvousInfo, UnverifiedValue},
     DeserializableMany, Error, ProtocolVersion, Serializable,
 };
 
@@ -33,39 +37,63 @@ enum OwnershipVoucherIndex {
     Entries = 4,
 }
 
+/// An Ownership Voucher.
+///
+/// Only the small fields are decoded when a voucher is loaded: the header and device
+/// certificate chain are validated, but only kept decoded once they are accessed, and
+/// entries are decoded one at a time from the raw voucher when iterating over them.
+/// This keeps the memory use low for services holding many (large) vouchers.
 #[derive(Debug, Clone)]
 pub struct OwnershipVoucher {
     contents: ParsedArray<ParsedArraySize5>,
 
     // Cached data
     cached_protocol_version: ProtocolVersion,
-    cached_header: OwnershipVoucherHeader,
+    cached_header: OnceLock<OwnershipVoucherHeader>,
     cached_header_hmac: HMac,
-    cached_device_certificate_chain: Option<X5Chain>,
-    cached_entries: ParsedArray<ParsedArraySizeDynamic>,
+    cached_device_certificate_chain: OnceLock<Option<X5Chain>>,
+    cached_num_entries: u16,
 }
 
 impl OwnershipVoucher {
     fn from_parsed_array(contents: ParsedArray<ParsedArraySize5>) -> Result<Self> {
         let cached_protocol_version =
             contents.get(OwnershipVoucherIndex::ProtocolVersion as usize)?;
-        let cached_header: ByteBuf = contents.get(OwnershipVoucherIndex::Header as usize)?;
-        let cached_header = OwnershipVoucherHeader::deserialize_data(&cached_header)?;
         let cached_header_hmac = contents.get(OwnershipVoucherIndex::HeaderHmac as usize)?;
-        let cached_device_certificate_chain =
-            contents.get(OwnershipVoucherIndex::DeviceCertificateChain as usize)?;
-        let cached_entries = contents.get(OwnershipVoucherIndex::Entries as usize)?;
+
+        // Validate the lazily decoded fields, so that accessing them later can't fail
+        let header: ByteBuf = contents.get(OwnershipVoucherIndex::Header as usize)?;
+        OwnershipVoucherHeader::deserialize_data(&header)?;
+        contents.get::<Option<X5Chain>>(OwnershipVoucherIndex::DeviceCertificateChain as usize)?;
+        let mut cached_num_entries: u16 = 0;
+        for entry in RawArrayIter::new(contents.get_raw(OwnershipVoucherIndex::Entries as usize))? {
+            entry?;
+            cached_num_entries =
+                cached_num_entries
+                    .checked_add(1)
+                    .ok_or(Error::InconsistentValue(
+                        "Too many ownership voucher entries",
+                    ))?;
+        }
 
         Ok(OwnershipVoucher {
             contents,
 
             cached_protocol_version,
-            cached_header,
+            cached_header: OnceLock::new(),
             cached_header_hmac,
-            cached_device_certificate_chain,
-            cached_entries,
+            cached_device_certificate_chain: OnceLock::new(),
+            cached_num_entries,
         })
     }
+
+    fn raw_entries(&self) -> RawArrayIter<'_> {
+        RawArrayIter::new(
+            self.contents
+                .get_raw(OwnershipVoucherIndex::Entries as usize),
+        )
+        .expect("Entries validated on creation")
+    }
 }
 
 impl Serializable for OwnershipVoucher {
@@ -73,7 +101,8 @@ impl Serializable for OwnershipVoucher {
     where
         R: std::io::Read,
     {
-        let contents = ParsedArray::deserialize_from_reader(reader);
+        let mut reader = canonical::RecordingReader::new(reader);
+        let contents = ParsedArray::deserialize_from_reader(&mut reader);
         if let Err(Error::ArrayParseError(
             crate::cborparser::ArrayParseError::InvalidNumberOfElements(4, 5),
         )) = contents
@@ -81,6 +110,8 @@ impl Serializable for OwnershipVoucher {
             return Err(Error::UnsupportedVersion(Some(ProtocolVersion::Version1_0)));
         };
         let contents = contents?;
+        // The header is checked when it's parsed, the entries when they are verified
+        canonical::check_received("Ownership voucher", reader.recorded())?;
         Self::from_parsed_array(contents)
     }
 
@@ -133,10 +164,13 @@ impl OwnershipVoucher {
             contents,
 
             cached_protocol_version: protocol_version,
-            cached_header,
+            cached_header: OnceLock::from(cached_header),
             cached_header_hmac: header_hmac,
-            cached_device_certificate_chain: None,
-            cached_entries: entries,
+            cached_device_certificate_chain: OnceLock::from(None),
+            cached_num_entries: entries
+                .len()
+                .try_into()
+                .map_err(|_| Error::InconsistentValue("Too many ownership voucher entries"))?,
         })
     }
 
@@ -167,10 +201,10 @@ impl OwnershipVoucher {
             contents,
 
             cached_protocol_version: ProtocolVersion::Version1_1,
-            cached_header: header,
+            cached_header: OnceLock::from(header),
             cached_header_hmac: header_hmac,
-            cached_device_certificate_chain: device_certificate_chain,
-            cached_entries: entries,
+            cached_device_certificate_chain: OnceLock::from(device_certificate_chain),
+            cached_num_entries: 0,
         })
     }
 
@@ -196,7 +230,7 @@ impl OwnershipVoucher {
     }
 
     pub fn from_pem_or_raw(data: &[u8]) -> Result<Self> {
-        if data[0] == data[1] && data[0] == b'-' {
+        if data.starts_with(b"--") {
             Self::from_pem(data)
         } else {
             Self::deserialize_data(data)
@@ -208,6 +242,19 @@ impl OwnershipVoucher {
         Ok(pem::encode(&block))
     }
 
+    pub fn from_diagnostic(data: &str) -> Result<Self> {
+        let value = crate::diagnostic::from_diagnostic(data)?;
+        let mut raw = Vec::new();
+        ciborium::ser::into_writer(&value, &mut raw)?;
+        Self::deserialize_data(&raw)
+    }
+
+    pub fn to_diagnostic(&self) -> Result<String> {
+        let raw = self.serialize_data()?;
+        let value: ciborium::value::Value = ciborium::de::from_reader(raw.as_slice())?;
+        Ok(crate::diagnostic::to_diagnostic(&value))
+    }
+
     fn hash_type(&self) -> HashType {
         self.cached_header_hmac.get_type().inner_hash()
     }
@@ -217,11 +264,17 @@ impl OwnershipVoucher {
     }
 
     pub fn device_certificate_chain(&self) -> Option<&X5Chain> {
-        self.cached_device_certificate_chain.as_ref()
+        self.cached_device_certificate_chain
+            .get_or_init(|| {
+                self.contents
+                    .get(OwnershipVoucherIndex::DeviceCertificateChain as usize)
+                    .expect("Device certificate chain validated on creation")
+            })
+            .as_ref()
     }
 
     pub fn device_certificate_chain_hash(&self, hash_type: HashType) -> Option<Result<Hash>> {
-        if self.cached_device_certificate_chain.is_none() {
+        if self.device_certificate_chain().is_none() {
             None
         } else {
             Some(self.contents.get_hash(
@@ -232,11 +285,16 @@ impl OwnershipVoucher {
     }
 
     pub fn num_entries(&self) -> u16 {
-        self.cached_entries.len() as u16
+        self.cached_num_entries
     }
 
     pub fn entry(&self, entry_num: usize) -> Result<OwnershipVoucherEntry> {
-        self.cached_entries.get(entry_num)
+        match self.raw_entries().nth(entry_num) {
+            Some(raw) => OwnershipVoucherEntry::deserialize_data(raw?),
+            None => Err(Error::InconsistentValue(
+                "Ownership voucher entry out of range",
+            )),
+        }
     }
 
     fn hdr_hash(&self, hash_type: HashType) -> Result<Hash> {
@@ -259,21 +317,69 @@ impl OwnershipVoucher {
         extra: ExtraType,
         next_party: &PublicKey,
     ) -> Result<()> {
+        self.extend_inner(
+            owner_private_key,
+            extra,
+            next_party,
+            |new_entry, key_type| {
+                COSESign::new_with_key_type(new_entry, None, owner_private_key, key_type)
+            },
+        )
+    }
+
+    /// Extends the voucher with a signer that doesn't expose its private key, like an HSM.
+    ///
+    /// Only owner keys with ECDSA signatures are supported.
+    pub fn extend_with_signer(
+        &mut self,
+        owner_signer: &dyn SigningPrivateKey,
+        owner_public_key: &PKeyRef<Public>,
+        extra: ExtraType,
+        next_party: &PublicKey,
+    ) -> Result<()> {
+        self.extend_inner(
+            owner_public_key,
+            extra,
+            next_party,
+            |new_entry, key_type| match key_type {
+                PublicKeyType::SECP256R1 | PublicKeyType::SECP384R1 => {
+                    COSESign::new(new_entry, None, owner_signer)
+                }
+                _ => Err(Error::NotImplemented(
+                    "Signing ownership voucher entries with non-ECDSA signers",
+                )),
+            },
+        )
+    }
+
+    fn extend_inner<T, F>(
+        &mut self,
+        owner_key: &PKeyRef<T>,
+        extra: ExtraType,
+        next_party: &PublicKey,
+        sign: F,
+    ) -> Result<()>
+    where
+        T: HasPublic,
+        F: FnOnce(&OwnershipVoucherEntryPayload, PublicKeyType) -> Result<COSESign>,
+    {
         if extra.is_some() && self.cached_protocol_version < ProtocolVersion::Version1_1 {
             return Err(Error::InvalidProtocolVersion(self.cached_protocol_version));
         }
 
         let hdrinfo_hash = self.header().get_hdr_info_hash(self.hash_type())?;
-        let (last_hash, current_owner_pubkey) = if self.cached_entries.is_empty() {
+        let mut entries: ParsedArray<ParsedArraySizeDynamic> =
+            self.contents.get(OwnershipVoucherIndex::Entries as usize)?;
+        let (last_hash, current_owner_pubkey) = if entries.is_empty() {
             (
                 self.hdr_hash(self.hash_type())?,
                 self.header().manufacturer_public_key().clone(),
             )
         } else {
-            let last_idx = self.cached_entries.len() - 1;
+            let last_idx = entries.len() - 1;
 
-            let last_hash = self.cached_entries.get_hash(last_idx, self.hash_type())?;
-            let lastentry: OwnershipVoucherEntry = self.cached_entries.get(last_idx)?;
+            let last_hash = entries.get_hash(last_idx, self.hash_type())?;
+            let lastentry: OwnershipVoucherEntry = entries.get(last_idx)?;
             let lastentry: UnverifiedValue<OwnershipVoucherEntryPayload> =
                 lastentry.get_payload_unverified()?;
 
@@ -283,7 +389,7 @@ impl OwnershipVoucher {
             )
         };
 
-        if !current_owner_pubkey.matches_pkey(owner_private_key)? {
+        if !current_owner_pubkey.matches_pkey(owner_key)? {
             return Err(Error::NonOwnerKey);
         }
 
@@ -291,23 +397,33 @@ impl OwnershipVoucher {
         let new_entry =
             OwnershipVoucherEntryPayload::new(last_hash, hdrinfo_hash, extra, next_party.clone())?;
 
-        // Sign with private key
-        let signed_new_entry = COSESign::new(&new_entry, None, owner_private_key)?;
+        // Sign with private key, the signature algorithm is selected from the type of the
+        // owner key in the voucher: RSAPSS keys are signed with PSS, other RSA keys with
+        // PKCS#1 v1.5
+        let signed_new_entry = sign(&new_entry, current_owner_pubkey.keytype())?;
         let signed_new_entry = OwnershipVoucherEntry::new(signed_new_entry);
 
         // Append
-        self.cached_entries.push(&signed_new_entry)?;
+        let num_entries =
+            self.cached_num_entries
+                .checked_add(1)
+                .ok_or(Error::InconsistentValue(
+                    "Too many ownership voucher entries",
+                ))?;
+        entries.push(&signed_new_entry)?;
 
-        self.contents.set(
-            OwnershipVoucherIndex::Entries as usize,
-            &self.cached_entries,
-        )?;
+        self.contents
+            .set(OwnershipVoucherIndex::Entries as usize, &entries)?;
+        self.cached_num_entries = num_entries;
 
         Ok(())
     }
 
     pub fn header(&self) -> &OwnershipVoucherHeader {
-        &self.cached_header
+        self.cached_header.get_or_init(|| {
+            OwnershipVoucherHeader::deserialize_data(&self.header_raw())
+                .expect("Header validated on creation")
+        })
     }
 
     pub fn header_raw(&self) -> ByteBuf {
@@ -321,7 +437,8 @@ impl<'a> OwnershipVoucher {
     pub fn iter_entries(&'a self) -> Result<EntryIter> {
         Ok(EntryIter {
             voucher: self,
-            index: 0,
+            entries: self.raw_entries(),
+            previous_entry: None,
             errored: false,
 
             last_pubkey: self.header().manufacturer_public_key().clone(),
@@ -329,10 +446,12 @@ impl<'a> OwnershipVoucher {
     }
 }
 
+/// Iterator that decodes and validates the voucher entries one at a time.
 #[derive(Debug)]
 pub struct EntryIter<'a> {
     voucher: &'a OwnershipVoucher,
-    index: usize,
+    entries: RawArrayIter<'a>,
+    previous_entry: Option<&'a [u8]>,
     errored: bool,
 
     last_pubkey: PublicKey,
@@ -346,24 +465,23 @@ impl<'a> Iterator for EntryIter<'a> {
             log::warn!("Previous entry validation failed");
             return None;
         }
-        if self.index >= self.voucher.cached_entries.len() {
-            return None;
-        }
-
-        let entry = self.voucher.cached_entries.get(self.index);
-        if let Err(e) = entry {
-            log::warn!("Error getting next entry: {:?}", e);
-            self.errored = true;
-            return Some(Err(e));
-        }
-        let entry = self.process_element(entry.unwrap());
+        let raw_entry = match self.entries.next()? {
+            Ok(raw_entry) => raw_entry,
+            Err(e) => {
+                log::warn!("Error getting next entry: {:?}", e);
+                self.errored = true;
+                return Some(Err(e));
+            }
+        };
+        let entry = OwnershipVoucherEntry::deserialize_data(raw_entry)
+            .and_then(|entry| self.process_element(entry));
 
         if entry.is_err() {
             log::warn!("Error validating ownership voucher: {:?}", entry);
             self.errored = true;
         }
 
-        self.index += 1;
+        self.previous_entry = Some(raw_entry);
 
         Some(entry)
     }
@@ -375,16 +493,17 @@ impl<'a> EntryIter<'a> {
         entry: OwnershipVoucherEntry,
     ) -> Result<OwnershipVoucherEntryPayload> {
         let entry = entry.0;
-        let entry: OwnershipVoucherEntryPayload = entry.get_payload(self.last_pubkey.pkey())?;
+        let entry: OwnershipVoucherEntryPayload =
+            entry.get_payload_with_public_key(&self.last_pubkey)?;
 
         // Compare the HashPreviousEntry to either (HeaderTag || HeaderHmac) or the previous entry
-        let hash_previous_entry = if self.index == 0 {
-            self.voucher
-                .hdr_hash(entry.hash_previous_entry.get_type())?
-        } else {
-            self.voucher
-                .cached_entries
-                .get_hash(self.index - 1, entry.hash_previous_entry.get_type())?
+        let hash_previous_entry = match self.previous_entry {
+            None => self
+                .voucher
+                .hdr_hash(entry.hash_previous_entry.get_type())?,
+            Some(previous_entry) => {
+                Hash::from_data(entry.hash_previous_entry.get_type(), previous_entry)?
+            }
         };
         match entry.hash_previous_entry.compare(&hash_previous_entry) {
             Ok(_) => {}
@@ -409,6 +528,14 @@ impl<'a> EntryIter<'a> {
             }
         }
 
+        // An owner key conveyed as a certificate chain must be delegated by its last certificate
+        if let Some(chain) = entry.public_key.chain() {
+            if let Err(e) = chain.verify_delegation() {
+                log::error!("Error verifying owner certificate chain");
+                return Err(e);
+            }
+        }
+
         // Set the next public key to the key in this entry
         self.last_pubkey = entry.public_key.clone();
 
@@ -450,7 +577,7 @@ impl OwnershipVoucherHeader {
         device_certificate_chain_hash: Option<Hash>,
     ) -> Result<Self> {
         let device_info = device_info.trim().to_string();
-        let mut contents = ParsedArrayBuilder::new();
+        let mut contents = ParsedArrayBuilder::<ParsedArraySize6>::new();
         contents.set(
             OwnershipVoucherHeaderIndex::ProtocolVersion as usize,
             &protocol_version,
@@ -472,7 +599,10 @@ impl OwnershipVoucherHeader {
             OwnershipVoucherHeaderIndex::DeviceCertificateChainHash as usize,
             &device_certificate_chain_hash,
         )?;
-        let contents = contents.build();
+        // The header is covered by the HMAC and the first entry's signature
+        let contents = ParsedArray::deserialize_data(&canonical::canonicalize(
+            &contents.build().serialize_data()?,
+        )?)?;
 
         Ok(OwnershipVoucherHeader {
             contents,
@@ -554,6 +684,36 @@ fn test_check_device_info_unsupported_characters() {
     assert!(is_device_info_valid.is_err());
 }
 
+#[test]
+fn test_from_pem_or_raw_short_input() {
+    assert!(OwnershipVoucher::from_pem_or_raw(b"").is_err());
+    assert!(OwnershipVoucher::from_pem_or_raw(b"-").is_err());
+}
+
+#[test]
+fn test_non_canonical_rejected() {
+    let data = include_bytes!("../fuzz/corpus/ownership_voucher/v101_voucher1.cbor");
+    OwnershipVoucher::deserialize_data(data).unwrap();
+
+    // The voucher array length encoded in two bytes
+    let mut non_canonical = vec![0x98, 0x05];
+    non_canonical.extend_from_slice(&data[1..]);
+    assert!(matches!(
+        OwnershipVoucher::deserialize_data(&non_canonical),
+        Err(Error::NonCanonicalEncoding(_))
+    ));
+
+    // The header array length encoded in two bytes, which makes the header a byte longer
+    assert_eq!(&data[3..6], &[0x58, 0xe9, 0x86]);
+    let mut non_canonical = data[..3].to_vec();
+    non_canonical.extend_from_slice(&[0x58, 0xea, 0x98, 0x06]);
+    non_canonical.extend_from_slice(&data[6..]);
+    assert!(matches!(
+        OwnershipVoucher::from_pem_or_raw(&non_canonical),
+        Err(Error::NonCanonicalEncoding(_))
+    ));
+}
+
 #[test]
 fn test_check_device_info_supported_characters() {
     let device_info: String = "FDO".to_string();
@@ -566,7 +726,9 @@ impl Serializable for OwnershipVoucherHeader {
     where
         R: std::io::Read,
     {
-        let contents = ParsedArray::deserialize_from_reader(reader)?;
+        let mut reader = canonical::RecordingReader::new(reader);
+        let contents = ParsedArray::deserialize_from_reader(&mut reader)?;
+        canonical::check_received("Ownership voucher header", reader.recorded())?;
 
         let cached_protocol_version =
             contents.get(OwnershipVoucherHeaderIndex::ProtocolVersion as usize)?;
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fmt;
@@ -6,7 +7,10 @@ use std::fmt::Display;
 use openssl::{
     nid::Nid,
     pkey::{self, PKey, PKeyRef, Public},
-    x509::{X509VerifyResult, X509},
+    stack::Stack,
+    x509::{
+        store::X509StoreBuilder, verify::X509VerifyFlags, X509StoreContext, X509VerifyResult, X509,
+    },
 };
 use serde::{
     de::Error as _,
@@ -16,7 +20,7 @@ use serde::{
 use serde_tuple::Serialize_tuple;
 
 use crate::{
-    constants::{PublicKeyEncoding, PublicKeyType},
+    constants::{HashType, PublicKeyEncoding, PublicKeyType},
     enhanced_types::X5Bag,
     errors::{ChainError, Error, Result},
     types::Hash,
@@ -144,28 +148,58 @@ impl PublicKey {
                 3072 => Ok(PublicKeyType::RsaPkcs),
                 _ => Err(Error::UnsupportedAlgorithm),
             },
+            pkey::Id::ED25519 if PublicKeyType::Ed25519.is_enabled() => Ok(PublicKeyType::Ed25519),
             _ => Err(Error::UnsupportedAlgorithm),
         }
     }
 
-    pub fn pkey(&self) -> &PKeyRef<Public> {
-        &self.pkey
+    fn key_type_matches_pkey(key_type: PublicKeyType, pkey: &PKeyRef<Public>) -> bool {
+        if !key_type.is_enabled() {
+            return false;
+        }
+        match key_type {
+            PublicKeyType::SECP256R1 | PublicKeyType::SECP384R1 | PublicKeyType::Ed25519 => {
+                matches!(PublicKey::key_type_from_pkey(pkey), Ok(detected) if detected == key_type)
+            }
+            PublicKeyType::Rsa2048RESTR => pkey.id() == pkey::Id::RSA && pkey.bits() == 2048,
+            // The spec defines RSAPKCS and RSAPSS for both RSA 2048 (signed with SHA-256) and
+            // RSA 3072 (signed with SHA-384). RSA2048RESTR is only a restricted 2048 bit type,
+            // which is what 2048 bit keys are detected as when no type is requested.
+            PublicKeyType::RsaPkcs | PublicKeyType::RsaPss => {
+                pkey.id() == pkey::Id::RSA && (pkey.bits() == 2048 || pkey.bits() == 3072)
+            }
+        }
     }
 
-    pub fn matches_pkey<T: openssl::pkey::HasPublic>(&self, other: &PKeyRef<T>) -> Result<bool> {
-        Ok(self.pkey.public_eq(other))
-    }
-}
+    /// Builds an X509-encoded public key from a certificate, using an explicitly
+    /// requested key type instead of the one detected from the key itself.
+    pub fn from_x509_with_key_type(x509: X509, key_type: PublicKeyType) -> Result<Self> {
+        let pkey = x509.public_key()?;
+        if !PublicKey::key_type_matches_pkey(key_type, &pkey) {
+            return Err(Error::InconsistentValue("public key type"));
+        }
+        let encoded = pkey.public_key_to_der()?;
 
-impl TryFrom<X5Chain> for PublicKey {
-    type Error = Error;
+        Ok(PublicKey {
+            key_type,
+            encoding: PublicKeyEncoding::X509,
+            data: encoded,
 
-    fn try_from(chain: X5Chain) -> Result<Self> {
+            pkey,
+            certs: None,
+        })
+    }
+
+    /// Builds an X5Chain-encoded public key, using an explicitly requested key type
+    /// instead of the one detected from the leaf certificate.
+    pub fn from_x5chain_with_key_type(chain: X5Chain, key_type: PublicKeyType) -> Result<Self> {
         let leaf_cert = chain
             .leaf_certificate()
             .ok_or(Error::InconsistentValue("x5chain without leaf certificate"))?;
         let pkey = leaf_cert.public_key()?;
-        let key_type = PublicKey::key_type_from_pkey(&pkey)?;
+        if !PublicKey::key_type_matches_pkey(key_type, &pkey) {
+            return Err(Error::InconsistentValue("public key type"));
+        }
         let encoded = chain.to_vec()?;
 
         Ok(PublicKey {
@@ -177,24 +211,39 @@ impl TryFrom<X5Chain> for PublicKey {
             certs: Some(chain),
         })
     }
+
+    pub fn pkey(&self) -> &PKeyRef<Public> {
+        &self.pkey
+    }
+
+    pub fn matches_pkey<T: openssl::pkey::HasPublic>(&self, other: &PKeyRef<T>) -> Result<bool> {
+        Ok(self.pkey.public_eq(other))
+    }
+
+    /// The hash type matching the strength of this key.
+    pub fn hash_type(&self) -> Result<HashType> {
+        HashType::for_pkey(&self.pkey)
+    }
 }
 
-impl TryFrom<X509> for PublicKey {
+impl TryFrom<X5Chain> for PublicKey {
     type Error = Error;
 
-    fn try_from(x509: X509) -> Result<Self> {
-        let pkey = x509.public_key()?;
-        let key_type = PublicKey::key_type_from_pkey(&pkey)?;
-        let encoded = pkey.public_key_to_der()?;
+    fn try_from(chain: X5Chain) -> Result<Self> {
+        let leaf_cert = chain
+            .leaf_certificate()
+            .ok_or(Error::InconsistentValue("x5chain without leaf certificate"))?;
+        let key_type = PublicKey::key_type_from_pkey(&leaf_cert.public_key()?)?;
+        PublicKey::from_x5chain_with_key_type(chain, key_type)
+    }
+}
 
-        Ok(PublicKey {
-            key_type,
-            encoding: PublicKeyEncoding::X509,
-            data: encoded,
+impl TryFrom<X509> for PublicKey {
+    type Error = Error;
 
-            pkey,
-            certs: None,
-        })
+    fn try_from(x509: X509) -> Result<Self> {
+        let key_type = PublicKey::key_type_from_pkey(&x509.public_key()?)?;
+        PublicKey::from_x509_with_key_type(x509, key_type)
     }
 }
 
@@ -208,6 +257,18 @@ impl Display for PublicKey {
     }
 }
 
+/// A DER-encoded certificate, borrowed from the input when it is parsed from a slice
+struct CertificateDer<'a>(Cow<'a, [u8]>);
+
+impl<'de: 'a, 'a> Deserialize<'de> for CertificateDer<'a> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        serde_bytes::deserialize(deserializer).map(CertificateDer)
+    }
+}
+
 // X5Chain order: [leaf, intermediate1, ..., intermediateN, root]
 #[derive(Debug, Clone)]
 pub struct X5Chain {
@@ -234,9 +295,9 @@ impl<'de> Deserialize<'de> for X5Chain {
             {
                 let mut chain = Vec::new();
 
-                while let Some(x509) = seq.next_element::<serde_bytes::ByteBuf>()? {
-                    log::trace!("Deserializing certificate: {:?}", x509);
-                    let x509 = X509::from_der(&x509).map_err(A::Error::custom)?;
+                while let Some(x509) = seq.next_element::<CertificateDer>()? {
+                    log::trace!("Deserializing certificate: {:?}", x509.0);
+                    let x509 = X509::from_der(&x509.0).map_err(A::Error::custom)?;
                     chain.push(x509);
                 }
                 Ok(X5Chain { chain })
@@ -294,6 +355,18 @@ impl X5Chain {
         )
     }
 
+    /// Checks that every certificate is issued by the next one, returning the leaf certificate.
+    ///
+    /// This establishes that the leaf key was delegated by the last certificate of the chain,
+    /// trusting that one is up to the caller.
+    pub fn verify_delegation(&self) -> Result<&X509> {
+        let last = match self.chain.last() {
+            Some(last) => last,
+            None => return Err(Error::InvalidChain(ChainError::Empty)),
+        };
+        self.verify(|last, cert| std::ptr::eq(*last, cert), &last)
+    }
+
     pub fn insecure_verify_without_root_verification(&self) -> Result<&X509> {
         self.verify(
             |_, cert| {
@@ -304,6 +377,49 @@ impl X5Chain {
         )
     }
 
+    /// Fully validates the chain up to one of `trust_anchors`, returning the leaf certificate.
+    ///
+    /// Unlike [`X5Chain::verify`], this also checks the validity period and the basic
+    /// constraints of every certificate. Trust anchors may be intermediate CAs, and do not
+    /// need to be included in the chain.
+    pub fn verify_full(&self, trust_anchors: &X5Bag) -> Result<&X509> {
+        let leaf = match self.chain.first() {
+            Some(leaf) => leaf,
+            None => return Err(Error::InvalidChain(ChainError::Empty)),
+        };
+
+        let mut store = X509StoreBuilder::new()?;
+        for anchor in trust_anchors.certs() {
+            store.add_cert(anchor.clone())?;
+        }
+        store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+        let store = store.build();
+
+        let mut untrusted = Stack::new()?;
+        for cert in &self.chain[1..] {
+            untrusted.push(cert.clone())?;
+        }
+
+        let mut context = X509StoreContext::new()?;
+        let failure = context.init(&store, leaf, &untrusted, |ctx| {
+            if ctx.verify_cert()? {
+                Ok(None)
+            } else {
+                Ok(Some((ctx.error_depth() as usize, ctx.error())))
+            }
+        })?;
+        match failure {
+            None => Ok(leaf),
+            Some((depth, error)) => {
+                log::debug!("Chain validation failed at depth {}: {}", depth, error);
+                Err(Error::InvalidChain(ChainError::Validation(
+                    depth,
+                    error.error_string(),
+                )))
+            }
+        }
+    }
+
     pub fn verify<UD, F>(&self, is_trusted_root: F, user_data: &UD) -> Result<&X509>
     where
         F: Fn(&UD, &X509) -> bool,
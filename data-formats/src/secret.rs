@@ -0,0 +1,152 @@
+//! Handling of secret key material.
+//!
+//! Secrets are wiped from memory when they are dropped, and kept out of swap where the
+//! platform allows it. Comparisons of secrets, and of values derived from them like HMACs
+//! and nonces, should go through [`constant_time_eq`].
+
+use std::ops::Deref;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+/// Compares two byte strings in constant time.
+///
+/// Only the length is compared in variable time, which is not a secret for any of the values
+/// we compare.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // memcmp::eq panics on slices of different lengths
+    a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
+/// Secret bytes, zeroized on drop.
+///
+/// The buffer is locked into memory on platforms supporting mlock. This is best effort:
+/// locking fails when the RLIMIT_MEMLOCK limit is reached, and locks don't nest, so dropping
+/// a secret unlocks the pages it shares with other secrets.
+///
+/// This serializes the same as a `Vec<u8>`.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(value: Vec<u8>) -> Self {
+        lock(&value);
+        Secret(value)
+    }
+}
+
+impl From<Vec<u8>> for Secret {
+    fn from(value: Vec<u8>) -> Self {
+        Secret::new(value)
+    }
+}
+
+impl From<&[u8]> for Secret {
+    fn from(value: &[u8]) -> Self {
+        Secret::new(value.to_vec())
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Secret {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Self {
+        Secret::new(self.0.clone())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        let (ptr, len) = (self.0.as_ptr(), self.0.len());
+        self.0.zeroize();
+        unlock(ptr, len);
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[[ SECRET: REDACTED ]]")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<u8>::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+#[cfg(unix)]
+fn lock(value: &[u8]) {
+    if value.is_empty() {
+        return;
+    }
+    if unsafe { libc::mlock(value.as_ptr() as *const libc::c_void, value.len()) } != 0 {
+        log::trace!(
+            "Unable to lock secret into memory: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(unix)]
+fn unlock(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    unsafe {
+        libc::munlock(ptr as *const libc::c_void, len);
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_value: &[u8]) {}
+
+#[cfg(not(unix))]
+fn unlock(_ptr: *const u8, _len: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, Secret};
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_secret_serialization() {
+        let secret = Secret::new(vec![1, 2, 3]);
+        assert_eq!(format!("{secret:?}"), "[[ SECRET: REDACTED ]]");
+
+        let serialized = serde_cbor::to_vec(&secret).unwrap();
+        assert_eq!(serialized, serde_cbor::to_vec(&vec![1u8, 2, 3]).unwrap());
+        let deserialized: Secret = serde_cbor::from_slice(&serialized).unwrap();
+        assert_eq!(&deserialized[..], &[1, 2, 3]);
+    }
+}
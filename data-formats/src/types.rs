@@ -6,8 +6,6 @@ use std::{
     string::ToString,
 };
 
-use aws_nitro_enclaves_cose::crypto::{SigningPrivateKey, SigningPublicKey};
-use aws_nitro_enclaves_cose::CoseSign1 as COSESignInner;
 use serde_bytes::ByteBuf;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use serde_tuple::Serialize_tuple;
@@ -15,8 +13,8 @@ use serde_tuple::Serialize_tuple;
 use crate::{
     cborparser::{ParsedArray, ParsedArrayBuilder},
     constants::{
-        DeviceSigType, HashType, HeaderKeys, RendezvousVariable, ServiceInfoModule,
-        StandardServiceInfoModule, TransportProtocol,
+        DeviceSigType, HashType, HeaderKeys, PublicKeyType, RendezvousProtocolValue,
+        RendezvousVariable, ServiceInfoModule, StandardServiceInfoModule, TransportProtocol,
     },
     errors::Error,
     ownershipvoucher::OwnershipVoucher,
@@ -28,15 +26,21 @@ use openssl::{
     bn::{BigNum, BigNumContext},
     dh::Dh,
     ec::{EcGroup, EcKey, EcPoint},
+    encrypt::{Decrypter, Encrypter},
     hash::{hash, MessageDigest},
     nid::Nid,
-    pkey::Params,
+    pkey::{HasPublic, PKeyRef, Params, Private, Public},
     rand::rand_bytes,
-    symm::Cipher,
+    rsa::Padding,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
 };
 use openssl_kdf::{perform_kdf, KdfArgument, KdfKbMode, KdfMacType, KdfType};
 use serde::{Deserialize, Serialize};
 
+pub use crate::cose::{COSEHeaderMap, COSESign};
+use crate::cose::{COSEHeaderMapType, COSE_HEADER_ALGORITHM};
+use crate::secret::{constant_time_eq, Secret};
+
 #[derive(Serialize_tuple, Deserialize, Clone)]
 pub struct Hash {
     hash_type: HashType,
@@ -105,7 +109,7 @@ impl Hash {
         let other_digest = hash(self.hash_type.try_into()?, other)?;
 
         // Compare
-        if openssl::memcmp::eq(&self.value, &other_digest) {
+        if constant_time_eq(&self.value, &other_digest) {
             Ok(())
         } else {
             Err(Error::IncorrectHash)
@@ -123,13 +127,13 @@ impl Hash {
 
 impl PartialEq for Hash {
     fn eq(&self, other: &Self) -> bool {
-        openssl::memcmp::eq(&self.value, &other.value)
+        self.hash_type == other.hash_type && constant_time_eq(&self.value, &other.value)
     }
 }
 
 impl PartialEq<openssl::hash::DigestBytes> for Hash {
     fn eq(&self, other: &openssl::hash::DigestBytes) -> bool {
-        openssl::memcmp::eq(&self.value, other.as_ref())
+        constant_time_eq(&self.value, other.as_ref())
     }
 }
 
@@ -184,6 +188,37 @@ mod test_hash {
     }
 }
 
+#[cfg(test)]
+mod test_nonce {
+    use std::str::FromStr;
+
+    use crate::Error;
+
+    use super::Nonce;
+
+    #[test]
+    fn test_nonce_eq() {
+        let nonce = Nonce::new().unwrap();
+        let parsed = Nonce::from_str(&nonce.to_string()).unwrap();
+        assert_eq!(nonce, parsed);
+        assert_ne!(nonce, Nonce::new().unwrap());
+        // Different lengths must compare unequal rather than panic
+        assert_ne!(nonce, Nonce::from_value(&nonce[..8]).unwrap());
+    }
+
+    #[test]
+    fn test_nonce_fromstr_invalid() {
+        assert!(matches!(
+            Nonce::from_str("zz").unwrap_err(),
+            Error::HexError(_)
+        ));
+        assert!(matches!(
+            Nonce::from_str("abcd").unwrap_err(),
+            Error::InconsistentValue("Nonce length")
+        ));
+    }
+}
+
 pub type HMac = Hash;
 
 #[derive(Clone, Debug, Serialize_tuple, Deserialize)]
@@ -269,7 +304,7 @@ impl Nonce {
 
 impl PartialEq for Nonce {
     fn eq(&self, other: &Self) -> bool {
-        openssl::memcmp::eq(&self.0, &other.0)
+        constant_time_eq(&self.0, &other.0)
     }
 }
 
@@ -283,10 +318,11 @@ impl FromStr for Nonce {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Error> {
-        let decoded = hex::decode(s).unwrap();
-        let boxed_slice = decoded.into_boxed_slice();
-        let boxed_array: Box<[u8; 16]> = boxed_slice.try_into().unwrap();
-        Ok(Nonce(boxed_array.to_vec()))
+        let decoded = hex::decode(s)?;
+        if decoded.len() != 16 {
+            return Err(Error::InconsistentValue("Nonce length"));
+        }
+        Ok(Nonce(decoded))
     }
 }
 
@@ -323,7 +359,8 @@ impl Guid {
     }
 
     fn from_ueid(data: &[u8]) -> Result<Self, Error> {
-        if data[0] != EAT_RAND {
+        // UEID type byte, followed by the 16 byte GUID
+        if data.len() != 17 || data[0] != EAT_RAND {
             Err(Error::InconsistentValue("Invalid UEID"))
         } else {
             Ok(Guid(data[1..].into()))
@@ -353,7 +390,7 @@ impl Deref for Guid {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IPAddress(std::net::IpAddr);
 
 impl From<std::net::IpAddr> for IPAddress {
@@ -362,6 +399,16 @@ impl From<std::net::IpAddr> for IPAddress {
     }
 }
 
+impl IPAddress {
+    /// Formats the address as the host part of a URL, with brackets around IPv6 addresses
+    pub fn as_url_host(&self) -> String {
+        match &self.0 {
+            std::net::IpAddr::V4(addr) => addr.to_string(),
+            std::net::IpAddr::V6(addr) => format!("[{addr}]"),
+        }
+    }
+}
+
 impl std::fmt::Display for IPAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
@@ -438,6 +485,8 @@ impl RendezvousInfo {
 
             for (variable, value) in directive {
                 let value = value.serialize_data()?;
+                // Make sure the value has the right type for the variable
+                RendezvousValue::parse(variable, &value)?;
                 let value = ByteBuf::from(value);
 
                 out_directive.push((variable, value));
@@ -449,14 +498,248 @@ impl RendezvousInfo {
         Ok(RendezvousInfo(out))
     }
 
+    pub fn from_directives(directives: Vec<RendezvousDirective>) -> Result<RendezvousInfo, Error> {
+        let info = RendezvousInfo(directives);
+        info.typed_values()?;
+        Ok(info)
+    }
+
     pub fn values(&self) -> &[RendezvousDirective] {
         &self.0
     }
+
+    pub fn typed_values(&self) -> Result<Vec<Vec<(RendezvousVariable, RendezvousValue)>>, Error> {
+        self.0
+            .iter()
+            .map(|directive| {
+                directive
+                    .iter()
+                    .map(|(variable, value)| {
+                        Ok((*variable, RendezvousValue::parse(*variable, value)?))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
 }
 
 pub type RendezvousDirective = Vec<RendezvousInstruction>;
 pub type RendezvousInstruction = (RendezvousVariable, ByteBuf);
 
+/// The typed value of a RendezvousInstruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum RendezvousValue {
+    /// DeviceOnly, OwnerOnly, UserInput and Bypass: their presence means they are true
+    Marker,
+    IPAddress(IPAddress),
+    /// DevicePort and OwnerPort
+    Port(Port),
+    Dns(DNSAddress),
+    /// ServerCertHash and CaCertHash
+    CertHash(Hash),
+    /// WifiSsid and WifiPw
+    Text(String),
+    Medium(u8),
+    Protocol(RendezvousProtocolValue),
+    DelaySec(u32),
+}
+
+impl RendezvousValue {
+    fn matches(&self, variable: RendezvousVariable) -> bool {
+        matches!(
+            (variable, self),
+            (RendezvousVariable::DeviceOnly, RendezvousValue::Marker)
+                | (RendezvousVariable::OwnerOnly, RendezvousValue::Marker)
+                | (RendezvousVariable::UserInput, RendezvousValue::Marker)
+                | (RendezvousVariable::Bypass, RendezvousValue::Marker)
+                | (RendezvousVariable::IPAddress, RendezvousValue::IPAddress(_))
+                | (RendezvousVariable::DevicePort, RendezvousValue::Port(_))
+                | (RendezvousVariable::OwnerPort, RendezvousValue::Port(_))
+                | (RendezvousVariable::Dns, RendezvousValue::Dns(_))
+                | (
+                    RendezvousVariable::ServerCertHash,
+                    RendezvousValue::CertHash(_)
+                )
+                | (RendezvousVariable::CaCertHash, RendezvousValue::CertHash(_))
+                | (RendezvousVariable::WifiSsid, RendezvousValue::Text(_))
+                | (RendezvousVariable::WifiPw, RendezvousValue::Text(_))
+                | (RendezvousVariable::Medium, RendezvousValue::Medium(_))
+                | (RendezvousVariable::Protocol, RendezvousValue::Protocol(_))
+                | (RendezvousVariable::Delaysec, RendezvousValue::DelaySec(_))
+        )
+    }
+
+    /// Parses the encoded value of an instruction, checking it has the right type for the variable
+    pub fn parse(variable: RendezvousVariable, value: &[u8]) -> Result<Self, Error> {
+        let value = CborSimpleType::deserialize_data(value)?;
+        let inconsistent = |_| Error::InconsistentValue(variable.name());
+
+        Ok(match variable {
+            RendezvousVariable::DeviceOnly
+            | RendezvousVariable::OwnerOnly
+            | RendezvousVariable::UserInput
+            | RendezvousVariable::Bypass => match value {
+                CborSimpleType::Null | CborSimpleType::Bool(true) => RendezvousValue::Marker,
+                _ => return Err(Error::InconsistentValue(variable.name())),
+            },
+            RendezvousVariable::IPAddress => RendezvousValue::IPAddress(
+                serde_cbor::value::from_value(value).map_err(inconsistent)?,
+            ),
+            RendezvousVariable::DevicePort | RendezvousVariable::OwnerPort => {
+                RendezvousValue::Port(serde_cbor::value::from_value(value).map_err(inconsistent)?)
+            }
+            RendezvousVariable::Dns => {
+                RendezvousValue::Dns(serde_cbor::value::from_value(value).map_err(inconsistent)?)
+            }
+            RendezvousVariable::ServerCertHash | RendezvousVariable::CaCertHash => {
+                RendezvousValue::CertHash(
+                    serde_cbor::value::from_value(value).map_err(inconsistent)?,
+                )
+            }
+            RendezvousVariable::WifiSsid | RendezvousVariable::WifiPw => {
+                RendezvousValue::Text(serde_cbor::value::from_value(value).map_err(inconsistent)?)
+            }
+            RendezvousVariable::Medium => {
+                RendezvousValue::Medium(serde_cbor::value::from_value(value).map_err(inconsistent)?)
+            }
+            RendezvousVariable::Protocol => RendezvousValue::Protocol(
+                serde_cbor::value::from_value(value).map_err(inconsistent)?,
+            ),
+            RendezvousVariable::Delaysec => RendezvousValue::DelaySec(
+                serde_cbor::value::from_value(value).map_err(inconsistent)?,
+            ),
+        })
+    }
+
+    fn serialize_data(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            RendezvousValue::Marker => CborSimpleType::Null.serialize_data(),
+            RendezvousValue::IPAddress(v) => v.serialize_data(),
+            RendezvousValue::Port(v) => v.serialize_data(),
+            RendezvousValue::Dns(v) => v.serialize_data(),
+            RendezvousValue::CertHash(v) => v.serialize_data(),
+            RendezvousValue::Text(v) => v.serialize_data(),
+            RendezvousValue::Medium(v) => v.serialize_data(),
+            RendezvousValue::Protocol(v) => v.serialize_data(),
+            RendezvousValue::DelaySec(v) => v.serialize_data(),
+        }
+    }
+}
+
+/// Builds a RendezvousDirective out of typed values
+#[derive(Debug, Default)]
+pub struct RendezvousDirectiveBuilder {
+    instructions: Vec<(RendezvousVariable, RendezvousValue)>,
+}
+
+impl RendezvousDirectiveBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an instruction, the value type is checked by `build`
+    pub fn instruction(mut self, variable: RendezvousVariable, value: RendezvousValue) -> Self {
+        self.instructions.push((variable, value));
+        self
+    }
+
+    pub fn device_only(self) -> Self {
+        self.instruction(RendezvousVariable::DeviceOnly, RendezvousValue::Marker)
+    }
+
+    pub fn owner_only(self) -> Self {
+        self.instruction(RendezvousVariable::OwnerOnly, RendezvousValue::Marker)
+    }
+
+    pub fn ip_address(self, addr: IpAddr) -> Self {
+        self.instruction(
+            RendezvousVariable::IPAddress,
+            RendezvousValue::IPAddress(addr.into()),
+        )
+    }
+
+    pub fn device_port(self, port: Port) -> Self {
+        self.instruction(RendezvousVariable::DevicePort, RendezvousValue::Port(port))
+    }
+
+    pub fn owner_port(self, port: Port) -> Self {
+        self.instruction(RendezvousVariable::OwnerPort, RendezvousValue::Port(port))
+    }
+
+    pub fn dns(self, name: &str) -> Self {
+        self.instruction(
+            RendezvousVariable::Dns,
+            RendezvousValue::Dns(name.to_string()),
+        )
+    }
+
+    pub fn server_cert_hash(self, hash: Hash) -> Self {
+        self.instruction(
+            RendezvousVariable::ServerCertHash,
+            RendezvousValue::CertHash(hash),
+        )
+    }
+
+    pub fn ca_cert_hash(self, hash: Hash) -> Self {
+        self.instruction(
+            RendezvousVariable::CaCertHash,
+            RendezvousValue::CertHash(hash),
+        )
+    }
+
+    pub fn user_input(self) -> Self {
+        self.instruction(RendezvousVariable::UserInput, RendezvousValue::Marker)
+    }
+
+    pub fn wifi_ssid(self, ssid: &str) -> Self {
+        self.instruction(
+            RendezvousVariable::WifiSsid,
+            RendezvousValue::Text(ssid.to_string()),
+        )
+    }
+
+    pub fn wifi_pw(self, password: &str) -> Self {
+        self.instruction(
+            RendezvousVariable::WifiPw,
+            RendezvousValue::Text(password.to_string()),
+        )
+    }
+
+    pub fn medium(self, medium: u8) -> Self {
+        self.instruction(RendezvousVariable::Medium, RendezvousValue::Medium(medium))
+    }
+
+    pub fn protocol(self, protocol: RendezvousProtocolValue) -> Self {
+        self.instruction(
+            RendezvousVariable::Protocol,
+            RendezvousValue::Protocol(protocol),
+        )
+    }
+
+    pub fn delay_sec(self, delay: u32) -> Self {
+        self.instruction(
+            RendezvousVariable::Delaysec,
+            RendezvousValue::DelaySec(delay),
+        )
+    }
+
+    pub fn bypass(self) -> Self {
+        self.instruction(RendezvousVariable::Bypass, RendezvousValue::Marker)
+    }
+
+    pub fn build(self) -> Result<RendezvousDirective, Error> {
+        self.instructions
+            .into_iter()
+            .map(|(variable, value)| {
+                if !value.matches(variable) {
+                    return Err(Error::InconsistentValue(variable.name()));
+                }
+                Ok((variable, ByteBuf::from(value.serialize_data()?)))
+            })
+            .collect()
+    }
+}
+
 // TODO: This sends serde_cbor outwards. Possibly re-do this
 pub type CborSimpleType = serde_cbor::Value;
 
@@ -731,7 +1014,7 @@ impl TO2ProveDevicePayload {
     }
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct ServiceInfo(Vec<(String, ByteBuf)>);
 
@@ -740,6 +1023,14 @@ impl ServiceInfo {
         ServiceInfo(vec![])
     }
 
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn add<M, T>(&mut self, module: M, key: &str, value: &T) -> Result<(), Error>
     where
         M: Into<ServiceInfoModule>,
@@ -774,6 +1065,86 @@ impl ServiceInfo {
         self.add(StandardServiceInfoModule::DevMod, "modules", &list)
     }
 
+    /// Appends all entries of `other`.
+    pub fn extend(&mut self, other: &ServiceInfo) {
+        self.0.extend(other.0.iter().cloned());
+    }
+
+    /// Splits the entries that fit in `max_size` serialized bytes off the start, returning
+    /// them and the remaining entries. The page always holds at least one entry, and ends
+    /// after any entry for which `ends_page` returns true.
+    ///
+    /// With `split_values`, a byte string value that doesn't fit in a page of its own is
+    /// split, with the rest of the value as the first entry of the remainder. The returned
+    /// bool is true if a value was split, in which case the page is sent with
+    /// IsMoreServiceInfo set, so that the receiver joins the value again with
+    /// [`ServiceInfo::append_split`].
+    pub fn split_page<F>(
+        &self,
+        max_size: usize,
+        split_values: bool,
+        ends_page: F,
+    ) -> Result<(ServiceInfo, ServiceInfo, bool), Error>
+    where
+        F: Fn(&ServiceInfoModule, &str) -> bool,
+    {
+        let mut page = ServiceInfo::new();
+        let mut rest = ServiceInfo::new();
+        let mut split = false;
+
+        let mut entries = self.0.iter();
+        for (module_key, value) in entries.by_ref() {
+            page.0.push((module_key.clone(), value.clone()));
+            if page.serialize_data()?.len() > max_size {
+                page.0.pop();
+                if !page.is_empty() {
+                    rest.0.push((module_key.clone(), value.clone()));
+                } else {
+                    match split_value(module_key, value, max_size)? {
+                        Some((first, remainder)) if split_values => {
+                            page.0.push((module_key.clone(), first));
+                            rest.0.push((module_key.clone(), remainder));
+                            split = true;
+                        }
+                        _ => page.0.push((module_key.clone(), value.clone())),
+                    }
+                }
+                break;
+            }
+            if let Some((module, key)) = module_key.split_once(':') {
+                if ends_page(&ServiceInfoModule::from_str(module)?, key) {
+                    break;
+                }
+            }
+        }
+        rest.0.extend(entries.cloned());
+
+        Ok((page, rest, split))
+    }
+
+    /// Appends `next`, the ServiceInfo received after this one in a message with
+    /// IsMoreServiceInfo set, joining a byte string value that was split between the
+    /// last entry of this ServiceInfo and the first entry of `next`.
+    pub fn append_split(&mut self, next: &ServiceInfo) -> Result<(), Error> {
+        let mut next_entries = next.0.iter();
+        if let (Some((last_key, last_value)), Some((first_key, first_value))) =
+            (self.0.last_mut(), next.0.first())
+        {
+            if last_key == first_key {
+                if let (CborSimpleType::Bytes(mut data), CborSimpleType::Bytes(remainder)) = (
+                    serde_cbor::from_slice::<CborSimpleType>(last_value)?,
+                    serde_cbor::from_slice::<CborSimpleType>(first_value)?,
+                ) {
+                    data.extend_from_slice(&remainder);
+                    *last_value = encode_bytes_value(&data)?;
+                    next_entries.next();
+                }
+            }
+        }
+        self.0.extend(next_entries.cloned());
+        Ok(())
+    }
+
     pub fn iter(&self) -> ServiceInfoIter {
         ServiceInfoIter { info: self, pos: 0 }
     }
@@ -795,6 +1166,40 @@ impl ServiceInfo {
     }
 }
 
+fn encode_bytes_value(data: &[u8]) -> Result<ByteBuf, Error> {
+    let mut buffer = Vec::new();
+    ciborium::ser::into_writer(&serde_bytes::Bytes::new(data), &mut buffer)?;
+    Ok(ByteBuf::from(buffer))
+}
+
+// Room for the byte string headers of a split value and of the ServiceInfo value wrapping it
+const SPLIT_VALUE_OVERHEAD: usize = 18;
+
+/// Splits a byte string ServiceInfo value, so that an entry with the first part fits
+/// in `max_size` serialized bytes. Returns None for other values, or if the value fits.
+fn split_value(
+    module_key: &str,
+    value: &ByteBuf,
+    max_size: usize,
+) -> Result<Option<(ByteBuf, ByteBuf)>, Error> {
+    let data = match serde_cbor::from_slice::<CborSimpleType>(value)? {
+        CborSimpleType::Bytes(data) => data,
+        _ => return Ok(None),
+    };
+    let overhead = ServiceInfo(vec![(module_key.to_string(), ByteBuf::new())])
+        .serialize_data()?
+        .len()
+        + SPLIT_VALUE_OVERHEAD;
+    let part_size = max_size.saturating_sub(overhead);
+    if part_size == 0 || part_size >= data.len() {
+        return Ok(None);
+    }
+    Ok(Some((
+        encode_bytes_value(&data[..part_size])?,
+        encode_bytes_value(&data[part_size..])?,
+    )))
+}
+
 #[derive(Debug)]
 pub struct ServiceInfoIter<'a> {
     info: &'a ServiceInfo,
@@ -975,8 +1380,8 @@ impl MAROEPrefix {
 
 #[derive(Serialize, Deserialize)]
 pub enum DerivedKeys {
-    Combined { sevk: Vec<u8> },
-    Split { sek: Vec<u8>, svk: Vec<u8> },
+    Combined { sevk: Secret },
+    Split { sek: Secret, svk: Secret },
 }
 
 impl std::fmt::Debug for DerivedKeys {
@@ -993,8 +1398,11 @@ pub enum KeyDeriveSide {
 
 #[derive(Serialize, Deserialize)]
 pub enum KeyExchange {
-    Ecdh(KexSuite, Vec<u8>, Vec<u8>),
-    Dhkex(KexSuite, Vec<u8>),
+    // Our private key, and our random
+    Ecdh(KexSuite, Secret, Vec<u8>),
+    Dhkex(KexSuite, Secret),
+    // Our random, and on the Device side, the random encrypted to the Owner key
+    Asymkex(KexSuite, Secret, Option<Vec<u8>>),
 }
 
 const KEY_DERIVE_LABEL: &[u8] = b"FIDO-KDF";
@@ -1006,21 +1414,52 @@ impl KeyExchange {
             KexSuite::DhkexId14 | KexSuite::DhkexId15 => {
                 let dh_params = suite.get_dh_params()?;
                 let key = dh_params.generate_key()?;
-                Ok(KeyExchange::Dhkex(suite, key.private_key().to_vec()))
+                Ok(KeyExchange::Dhkex(suite, key.private_key().to_vec().into()))
             }
             KexSuite::Ecdh256 | KexSuite::Ecdh384 => {
                 let ec_group = suite.get_ecdh_group()?;
                 let key = EcKey::generate(&ec_group)?;
-                let key = key.private_key_to_der()?;
+                let key = Secret::new(key.private_key_to_der()?);
 
                 let mut our_random = vec![0; suite.get_ecdh_random_size()];
                 rand_bytes(&mut our_random)?;
 
                 Ok(KeyExchange::Ecdh(suite, key, our_random))
             }
+            KexSuite::Asymkex2048 | KexSuite::Asymkex3072 => {
+                // This is the Owner side, the Device side needs the Owner key
+                let mut our_random = vec![0; suite.get_asymkex_random_size()];
+                rand_bytes(&mut our_random)?;
+
+                Ok(KeyExchange::Asymkex(suite, our_random.into(), None))
+            }
         }
     }
 
+    pub fn new_asymkex_device(
+        suite: KexSuite,
+        owner_public_key: &PKeyRef<Public>,
+    ) -> Result<Self, Error> {
+        suite.check_asymkex_owner_key(owner_public_key)?;
+
+        let mut our_random = vec![0; suite.get_asymkex_random_size()];
+        rand_bytes(&mut our_random)?;
+
+        let mut encrypter = Encrypter::new(owner_public_key)?;
+        encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+        encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+        encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+        let mut encrypted = vec![0; encrypter.encrypt_len(&our_random)?];
+        let encrypted_len = encrypter.encrypt(&our_random, &mut encrypted)?;
+        encrypted.truncate(encrypted_len);
+
+        Ok(KeyExchange::Asymkex(
+            suite,
+            our_random.into(),
+            Some(encrypted),
+        ))
+    }
+
     pub fn get_public(&self) -> Result<Vec<u8>, Error> {
         match self {
             KeyExchange::Dhkex(suite, key) => {
@@ -1049,6 +1488,8 @@ impl KeyExchange {
 
                 Ok(self.encode_ecdh_bstr(&public_x, &public_y, our_random))
             }
+            KeyExchange::Asymkex(_, our_random, None) => Ok(our_random.to_vec()),
+            KeyExchange::Asymkex(_, _, Some(encrypted)) => Ok(encrypted.clone()),
         }
     }
 
@@ -1067,22 +1508,24 @@ impl KeyExchange {
         out
     }
 
+    /// Splits a length-prefixed field off the start of an ECDH bstr
+    fn decode_ecdh_bstr_field(bstr: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+        if bstr.len() < std::mem::size_of::<u16>() {
+            return Err(Error::KeyExchangeError("Invalid ecdh bstr received"));
+        }
+        let (len, bstr) = bstr.split_at(std::mem::size_of::<u16>());
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        if bstr.len() < len {
+            return Err(Error::KeyExchangeError("Invalid ecdh bstr received"));
+        }
+        Ok(bstr.split_at(len))
+    }
+
     #[allow(clippy::type_complexity)]
     fn decode_ecdh_bstr(&self, bstr: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Error> {
-        let (ax_len, bstr) = bstr.split_at(std::mem::size_of::<u16>());
-        let ax_len = u16::from_be_bytes(ax_len.try_into().unwrap());
-
-        let (ax, bstr) = bstr.split_at(ax_len as usize);
-
-        let (ay_len, bstr) = bstr.split_at(std::mem::size_of::<u16>());
-        let ay_len = u16::from_be_bytes(ay_len.try_into().unwrap());
-
-        let (ay, bstr) = bstr.split_at(ay_len as usize);
-
-        let (random_len, bstr) = bstr.split_at(std::mem::size_of::<u16>());
-        let random_len = u16::from_be_bytes(random_len.try_into().unwrap());
-
-        let (random, bstr) = bstr.split_at(random_len as usize);
+        let (ax, bstr) = Self::decode_ecdh_bstr_field(bstr)?;
+        let (ay, bstr) = Self::decode_ecdh_bstr_field(bstr)?;
+        let (random, bstr) = Self::decode_ecdh_bstr_field(bstr)?;
 
         if !bstr.is_empty() {
             Err(Error::KeyExchangeError("Invalid ecdh bstr received"))
@@ -1170,17 +1613,73 @@ impl KeyExchange {
         }
     }
 
+    fn derive_key_asymkex(
+        &self,
+        our_side: KeyDeriveSide,
+        other: &[u8],
+        owner_key: Option<&PKeyRef<Private>>,
+    ) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        if let KeyExchange::Asymkex(suite, our_random, encrypted) = self {
+            match (our_side, encrypted) {
+                (KeyDeriveSide::Device, Some(_)) => {
+                    if other.len() != suite.get_asymkex_random_size() {
+                        return Err(Error::KeyExchangeError("Other random is invalid size"));
+                    }
+                    // ShSe is the Device random, ContextRand the Owner random
+                    Ok((our_random.to_vec(), other.to_vec()))
+                }
+                (KeyDeriveSide::OwnerService, None) => {
+                    let owner_key = owner_key.ok_or(Error::KeyExchangeError(
+                        "ASYMKEX requires the Owner key to derive keys",
+                    ))?;
+                    let mut decrypter = Decrypter::new(owner_key)?;
+                    decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+                    decrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+                    decrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+                    let mut other_random = vec![0; decrypter.decrypt_len(other)?];
+                    let other_random_len = decrypter.decrypt(other, &mut other_random)?;
+                    other_random.truncate(other_random_len);
+                    if other_random.len() != suite.get_asymkex_random_size() {
+                        return Err(Error::KeyExchangeError("Other random is invalid size"));
+                    }
+
+                    Ok((other_random, our_random.to_vec()))
+                }
+                _ => Err(Error::KeyExchangeError(
+                    "ASYMKEX key exchange used for the wrong side",
+                )),
+            }
+        } else {
+            // Only ASYMKEX suites call into here
+            unreachable!()
+        }
+    }
+
     pub fn derive_key(
         &self,
         our_side: KeyDeriveSide,
         cipher: CipherSuite,
         other: &[u8],
+        use_noninteroperable_kdf: bool,
+    ) -> Result<DerivedKeys, Error> {
+        self.derive_key_with_owner_key(our_side, cipher, other, None, use_noninteroperable_kdf)
+    }
+
+    /// Derives the session keys, using the Owner key where the suite needs it (ASYMKEX)
+    pub fn derive_key_with_owner_key(
+        &self,
+        our_side: KeyDeriveSide,
+        cipher: CipherSuite,
+        other: &[u8],
+        owner_key: Option<&PKeyRef<Private>>,
         mut use_noninteroperable_kdf: bool,
     ) -> Result<DerivedKeys, Error> {
         let (shared_secret, context_rand) = match self {
             KeyExchange::Dhkex(..) => self.derive_key_dh(other)?,
             KeyExchange::Ecdh(..) => self.derive_key_ecdh(our_side, other)?,
+            KeyExchange::Asymkex(..) => self.derive_key_asymkex(our_side, other, owner_key)?,
         };
+        let shared_secret = Secret::new(shared_secret);
 
         let mut salt = Vec::with_capacity(KEY_DERIVE_CONTEXT_PREFIX.len() + context_rand.len() + 2);
         salt.extend_from_slice(KEY_DERIVE_CONTEXT_PREFIX);
@@ -1208,7 +1707,7 @@ impl KeyExchange {
             &KdfArgument::KbInfo(&salt),
             &KdfArgument::Key(&shared_secret),
         ];
-        let key_out = perform_kdf(
+        let key_out = Secret::new(perform_kdf(
             KdfType::KeyBased,
             if use_noninteroperable_kdf {
                 log::info!("Using non-interoperable KDF");
@@ -1218,15 +1717,15 @@ impl KeyExchange {
                 &interoperable_kdf_args
             },
             cipher.required_keylen(),
-        )?;
+        )?);
 
         if cipher.uses_combined_key() {
             Ok(DerivedKeys::Combined { sevk: key_out })
         } else {
             let (svk, sek) = key_out.split_at(cipher.split_key_split_pos());
             Ok(DerivedKeys::Split {
-                svk: svk.to_vec(),
-                sek: sek.to_vec(),
+                svk: svk.into(),
+                sek: sek.into(),
             })
         }
     }
@@ -1260,6 +1759,9 @@ pub enum KexSuite {
     // Diffie-Hellmann Key Exchange Protocol
     DhkexId14,
     DhkexId15,
+    // Asymmetric Key Exchange Protocol, using the RSA Owner key
+    Asymkex2048,
+    Asymkex3072,
 }
 
 impl FromStr for KexSuite {
@@ -1271,6 +1773,8 @@ impl FromStr for KexSuite {
             "ECDH384" => Ok(KexSuite::Ecdh384),
             "DHKEXid14" => Ok(KexSuite::DhkexId14),
             "DHKEXid15" => Ok(KexSuite::DhkexId15),
+            "ASYMKEX2048" => Ok(KexSuite::Asymkex2048),
+            "ASYMKEX3072" => Ok(KexSuite::Asymkex3072),
             other => Err(Error::InvalidSuiteName(other.to_string())),
         }
     }
@@ -1283,6 +1787,8 @@ impl ToString for KexSuite {
             KexSuite::Ecdh384 => "ECDH384".to_string(),
             KexSuite::DhkexId14 => "DHKEXid14".to_string(),
             KexSuite::DhkexId15 => "DHKEXid15".to_string(),
+            KexSuite::Asymkex2048 => "ASYMKEX2048".to_string(),
+            KexSuite::Asymkex3072 => "ASYMKEX3072".to_string(),
         }
     }
 }
@@ -1328,6 +1834,30 @@ impl<'de> Deserialize<'de> for KexSuite {
 }
 
 impl KexSuite {
+    pub fn is_asymkex(&self) -> bool {
+        matches!(self, KexSuite::Asymkex2048 | KexSuite::Asymkex3072)
+    }
+
+    /// Checks that the Owner key can be used with this ASYMKEX suite: it needs
+    /// to be an RSA key with the modulus size of the suite.
+    pub fn check_asymkex_owner_key<T: HasPublic>(
+        &self,
+        owner_key: &PKeyRef<T>,
+    ) -> Result<(), Error> {
+        if !self.is_asymkex() {
+            return Err(Error::KeyExchangeError("Not an ASYMKEX suite"));
+        }
+        let owner_key_rsa = owner_key
+            .rsa()
+            .map_err(|_| Error::KeyExchangeError("ASYMKEX requires an RSA Owner key"))?;
+        if owner_key_rsa.size() * 8 != self.get_asymkex_rsa_bits() {
+            return Err(Error::KeyExchangeError(
+                "Owner key size does not match ASYMKEX suite",
+            ));
+        }
+        Ok(())
+    }
+
     fn get_ecdh_random_size(&self) -> usize {
         match self {
             KexSuite::Ecdh256 => 16,
@@ -1347,6 +1877,24 @@ impl KexSuite {
         Ok(EcGroup::from_curve_name(curve_name)?)
     }
 
+    fn get_asymkex_random_size(&self) -> usize {
+        match self {
+            KexSuite::Asymkex2048 => 32,
+            KexSuite::Asymkex3072 => 96,
+            // Only ASYMKEX suites call into here
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_asymkex_rsa_bits(&self) -> u32 {
+        match self {
+            KexSuite::Asymkex2048 => 2048,
+            KexSuite::Asymkex3072 => 3072,
+            // Only ASYMKEX suites call into here
+            _ => unreachable!(),
+        }
+    }
+
     fn get_dh_params(&self) -> Result<Dh<Params>, Error> {
         match self {
             KexSuite::DhkexId14 => {
@@ -1404,19 +1952,33 @@ pub enum CipherSuite {
     // Combined ciphers
     A128Gcm = 1,
     A256Gcm = 3,
+    AesCcm64_128_128 = 32,
+    AesCcm64_128_256 = 33,
 }
 
+const COSEENCRYPT0_TAG: u64 = 16;
+const COSE_HEADER_IV: i64 = 5;
+// AES-CCM-64-*: 64 bit length field, so a 7 byte nonce, and a 128 bit tag
+const CCM_NONCE_LEN: usize = 7;
+const CCM_TAG_LEN: usize = 16;
+
 impl CipherSuite {
     fn uses_combined_key(&self) -> bool {
         match self {
-            CipherSuite::A128Gcm | CipherSuite::A256Gcm => true,
+            CipherSuite::A128Gcm
+            | CipherSuite::A256Gcm
+            | CipherSuite::AesCcm64_128_128
+            | CipherSuite::AesCcm64_128_256 => true,
         }
     }
 
     fn split_key_split_pos(&self) -> usize {
         match self {
-            CipherSuite::A128Gcm | CipherSuite::A256Gcm => {
-                // Gcm ciphers should never call into the split_key
+            CipherSuite::A128Gcm
+            | CipherSuite::A256Gcm
+            | CipherSuite::AesCcm64_128_128
+            | CipherSuite::AesCcm64_128_256 => {
+                // Combined ciphers should never call into the split_key
                 unreachable!()
             }
         }
@@ -1424,14 +1986,17 @@ impl CipherSuite {
 
     fn required_keylen(&self) -> usize {
         match self {
-            CipherSuite::A128Gcm => 16,
-            CipherSuite::A256Gcm => 32,
+            CipherSuite::A128Gcm | CipherSuite::AesCcm64_128_128 => 16,
+            CipherSuite::A256Gcm | CipherSuite::AesCcm64_128_256 => 32,
         }
     }
 
     fn kdf_digest(&self) -> MessageDigest {
         match self {
-            CipherSuite::A128Gcm | CipherSuite::A256Gcm => MessageDigest::sha256(),
+            CipherSuite::A128Gcm
+            | CipherSuite::A256Gcm
+            | CipherSuite::AesCcm64_128_128
+            | CipherSuite::AesCcm64_128_256 => MessageDigest::sha256(),
         }
     }
 
@@ -1439,7 +2004,104 @@ impl CipherSuite {
         match self {
             CipherSuite::A128Gcm => Cipher::aes_128_gcm(),
             CipherSuite::A256Gcm => Cipher::aes_256_gcm(),
+            CipherSuite::AesCcm64_128_128 => Cipher::aes_128_ccm(),
+            CipherSuite::AesCcm64_128_256 => Cipher::aes_256_ccm(),
+        }
+    }
+
+    pub fn is_ccm(&self) -> bool {
+        matches!(
+            self,
+            CipherSuite::AesCcm64_128_128 | CipherSuite::AesCcm64_128_256
+        )
+    }
+
+    fn encrypt0_enc_structure(protected: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(serde_cbor::to_vec(&(
+            "Encrypt0",
+            ByteBuf::from(protected),
+            ByteBuf::new(),
+        ))?)
+    }
+
+    /// Wraps plaintext in a COSE_Encrypt0 with one of the AES-CCM suites
+    ///
+    /// The GCM suites are handled by the COSE library.
+    pub fn ccm_encrypt0(&self, key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.is_ccm() {
+            return Err(Error::InconsistentValue("Encrypt0 cipher suite"));
+        }
+
+        let mut protected = aws_nitro_enclaves_cose::header_map::HeaderMap::new();
+        protected.insert(COSE_HEADER_ALGORITHM.into(), (*self as i64).into());
+        let protected = serde_cbor::to_vec(&protected)?;
+
+        let mut nonce = [0; CCM_NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut unprotected = aws_nitro_enclaves_cose::header_map::HeaderMap::new();
+        unprotected.insert(
+            COSE_HEADER_IV.into(),
+            serde_cbor::Value::Bytes(nonce.to_vec()),
+        );
+
+        let mut tag = [0; CCM_TAG_LEN];
+        let mut ciphertext = encrypt_aead(
+            self.openssl_cipher(),
+            key,
+            Some(&nonce),
+            &Self::encrypt0_enc_structure(&protected)?,
+            plaintext,
+            &mut tag,
+        )?;
+        ciphertext.extend_from_slice(&tag);
+
+        let mut contents = ParsedArray::<crate::cborparser::ParsedArraySize3>::deserialize_data(
+            &serde_cbor::to_vec(&(
+                ByteBuf::from(protected),
+                unprotected,
+                ByteBuf::from(ciphertext),
+            ))?,
+        )?;
+        contents.set_tag(Some(COSEENCRYPT0_TAG));
+        contents.serialize_data()
+    }
+
+    /// Decrypts a COSE_Encrypt0 produced with one of the AES-CCM suites
+    pub fn ccm_decrypt0(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.is_ccm() {
+            return Err(Error::InconsistentValue("Encrypt0 cipher suite"));
+        }
+
+        let contents = ParsedArray::<crate::cborparser::ParsedArraySize3>::deserialize_data(data)?;
+        if !matches!(contents.tag(), None | Some(COSEENCRYPT0_TAG)) {
+            return Err(Error::InconsistentValue("Encrypt0 tag"));
+        }
+        let protected: ByteBuf = contents.get(0)?;
+        let unprotected: COSEHeaderMapType = contents.get(1)?;
+        let ciphertext: ByteBuf = contents.get(2)?;
+
+        let protected_map: COSEHeaderMapType = serde_cbor::from_slice(&protected)?;
+        match protected_map.get(&COSE_HEADER_ALGORITHM) {
+            Some(serde_cbor::Value::Integer(alg)) if *alg == *self as i128 => {}
+            _ => return Err(Error::InconsistentValue("Encrypt0 algorithm")),
+        }
+        let nonce = match unprotected.get(&COSE_HEADER_IV) {
+            Some(serde_cbor::Value::Bytes(nonce)) if nonce.len() == CCM_NONCE_LEN => nonce,
+            _ => return Err(Error::InconsistentValue("Encrypt0 IV")),
+        };
+        if ciphertext.len() < CCM_TAG_LEN {
+            return Err(Error::InconsistentValue("Encrypt0 ciphertext"));
         }
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - CCM_TAG_LEN);
+
+        Ok(decrypt_aead(
+            self.openssl_cipher(),
+            key,
+            Some(nonce),
+            &Self::encrypt0_enc_structure(&protected)?,
+            ciphertext,
+            tag,
+        )?)
     }
 }
 
@@ -1450,6 +2112,8 @@ impl FromStr for CipherSuite {
         match s {
             "A128GCM" => Ok(CipherSuite::A128Gcm),
             "A256GCM" => Ok(CipherSuite::A256Gcm),
+            "AES-CCM-64-128-128" => Ok(CipherSuite::AesCcm64_128_128),
+            "AES-CCM-64-128-256" => Ok(CipherSuite::AesCcm64_128_256),
             other => Err(Error::InvalidSuiteName(other.to_string())),
         }
     }
@@ -1460,6 +2124,8 @@ impl ToString for CipherSuite {
         match self {
             CipherSuite::A128Gcm => "A128GCM".to_string(),
             CipherSuite::A256Gcm => "A256GCM".to_string(),
+            CipherSuite::AesCcm64_128_128 => "AES-CCM-64-128-128".to_string(),
+            CipherSuite::AesCcm64_128_256 => "AES-CCM-64-128-256".to_string(),
         }
     }
 }
@@ -1484,7 +2150,7 @@ pub struct PayloadCreating;
 impl PayloadState for PayloadCreating {}
 impl PayloadStateCreating for PayloadCreating {}
 
-pub struct UnverifiedValue<T>(T);
+pub struct UnverifiedValue<T>(pub(crate) T);
 
 impl<T> std::fmt::Debug for UnverifiedValue<T>
 where
@@ -1502,7 +2168,7 @@ impl<T> UnverifiedValue<T> {
         &self.0
     }
 
-    unsafe fn into_unverified(self) -> T {
+    pub(crate) unsafe fn into_unverified(self) -> T {
         self.0
     }
 }
@@ -1550,7 +2216,7 @@ where
         self.other_claims.get(&key)
     }
 
-    fn to_map(&self) -> COSEHeaderMap {
+    pub(crate) fn to_map(&self) -> COSEHeaderMap {
         let mut res = self.other_claims.clone();
 
         if let Some(payload) = &self.payload {
@@ -1630,7 +2296,7 @@ where
     }
 }
 
-fn eat_from_map<S>(mut claims: COSEHeaderMap) -> Result<EATokenPayload<S>, Error>
+pub(crate) fn eat_from_map<S>(mut claims: COSEHeaderMap) -> Result<EATokenPayload<S>, Error>
 where
     S: PayloadState,
 {
@@ -1686,247 +2352,315 @@ where
     })
 }
 
-type COSEHeaderMapType = std::collections::HashMap<i64, serde_cbor::Value>;
-
-#[derive(Debug, Clone)]
-pub struct COSEHeaderMap(COSEHeaderMapType);
+#[cfg(test)]
+mod test_serviceinfo {
+    use super::{CborSimpleTypeExt, ServiceInfo};
+    use crate::{constants::StandardServiceInfoModule, Serializable};
 
-impl From<COSEHeaderMap> for aws_nitro_enclaves_cose::header_map::HeaderMap {
-    fn from(mut chm: COSEHeaderMap) -> aws_nitro_enclaves_cose::header_map::HeaderMap {
-        let mut new = aws_nitro_enclaves_cose::header_map::HeaderMap::new();
-        for (key, value) in chm.0.drain() {
-            new.insert(serde_cbor::Value::Integer(key as i128), value);
+    #[test]
+    fn test_split_page_and_join() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut si = ServiceInfo::new();
+        si.add(StandardServiceInfoModule::DevMod, "active", &true)
+            .unwrap();
+        si.add(
+            StandardServiceInfoModule::Download,
+            "data",
+            &serde_bytes::Bytes::new(&data),
+        )
+        .unwrap();
+
+        // The small entry doesn't share a page with the large value
+        let (page, rest, split) = si.split_page(1300, true, |_, _| false).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(rest.len(), 1);
+        assert!(!split);
+
+        let mut received = ServiceInfo::new();
+        let mut pending = rest;
+        let mut pages = 0;
+        loop {
+            let (page, rest, split) = pending.split_page(1300, true, |_, _| false).unwrap();
+            assert!(page.serialize_data().unwrap().len() <= 1300);
+            received.append_split(&page).unwrap();
+            pages += 1;
+            if !split {
+                assert!(rest.is_empty());
+                break;
+            }
+            pending = rest;
         }
-        new
-    }
-}
+        assert_eq!(pages, 4);
 
-impl COSEHeaderMap {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        COSEHeaderMap(std::collections::HashMap::new())
+        let values: Vec<_> = received.iter().collect();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].1, "data");
+        assert_eq!(values[0].2.as_bytes().unwrap(), &data[..]);
     }
 
-    pub fn insert<T>(&mut self, key: HeaderKeys, value: &T) -> Result<(), Error>
-    where
-        T: Serialize,
-    {
-        self.0
-            .insert(key as i64, serde_cbor::value::to_value(value)?);
-        Ok(())
-    }
+    #[test]
+    fn test_split_page_without_splitting_values() {
+        let mut si = ServiceInfo::new();
+        si.add(
+            StandardServiceInfoModule::Download,
+            "data",
+            &serde_bytes::Bytes::new(&[0u8; 2000]),
+        )
+        .unwrap();
+        si.add(StandardServiceInfoModule::DevMod, "active", &true)
+            .unwrap();
 
-    fn get<T>(&self, key: &HeaderKeys) -> Result<Option<T>, Error>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        match self.0.get(&(*key as i64)) {
-            None => Ok(None),
-            Some(val) => Ok(Some(serde_cbor::value::from_value(val.clone())?)),
-        }
+        let (page, rest, split) = si.split_page(1300, false, |_, _| false).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(rest.len(), 1);
+        assert!(!split);
+
+        // Pages end early when asked to
+        let (page, rest, _) = si.split_page(10000, false, |_, key| key == "data").unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(rest.len(), 1);
     }
 }
 
-const COSESIGN_TAG: u64 = 18;
-
-#[derive(Debug, Clone)]
-pub struct COSESign {
-    contents: ParsedArray<crate::cborparser::ParsedArraySize4>,
+#[cfg(test)]
+mod test_key_exchange {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::PKey,
+        rsa::Rsa,
+    };
 
-    cached_inner: COSESignInner,
-}
+    use super::{CipherSuite, DerivedKeys, KexSuite, KeyDeriveSide, KeyExchange};
 
-impl Serializable for COSESign {
-    fn deserialize_from_reader<R>(reader: R) -> Result<Self, Error>
-    where
-        R: std::io::Read,
-    {
-        let array = ParsedArray::deserialize_from_reader(reader)?;
-
-        if array.tag() != Some(COSESIGN_TAG) {
-            if array.tag().is_none() {
-                return Err(Error::InconsistentValue("Missing tag on COSESign"));
-            } else {
-                return Err(Error::InconsistentValue("Invalid tag on COSESign"));
-            }
+    fn sevk(keys: DerivedKeys) -> String {
+        match keys {
+            DerivedKeys::Combined { sevk } => hex::encode(sevk),
+            DerivedKeys::Split { .. } => panic!("Unexpected split keys"),
         }
-
-        // TODO
-        let data = array.serialize_data()?;
-        let inner = COSESignInner::from_bytes(&data)?;
-
-        Ok(COSESign {
-            contents: array,
-
-            cached_inner: inner,
-        })
     }
 
-    fn serialize_to_writer<W>(&self, writer: W) -> Result<(), Error>
-    where
-        W: std::io::Write,
-    {
-        // There is no way this data structure should be able to be constructed without either
-        // deserializing (which checks the tag) or us constructing it, where we set the tag.
-        // Just make sure it's there before serializing.
-        if !matches!(self.contents.tag(), Some(COSESIGN_TAG)) {
-            return Err(Error::InconsistentValue(
-                "Invalid tag on COSESign on serialize_data",
-            ));
+    // Expected values were computed with the OpenSSL KBKDF, as (interoperable, non-interoperable)
+    fn expected(values: (&'static str, &'static str)) -> &'static str {
+        if crate::interoperable_kdf_available() {
+            values.0
+        } else {
+            values.1
         }
-
-        self.contents.serialize_to_writer(writer)
-    }
-}
-
-impl COSESign {
-    fn new_from_inner(inner: COSESignInner) -> Result<Self, Error> {
-        let mut contents = ParsedArray::deserialize_data(&inner.serialize_data()?)?;
-        contents.set_tag(Some(COSESIGN_TAG));
-
-        Ok(COSESign {
-            contents,
-
-            cached_inner: inner,
-        })
-    }
-
-    pub fn new<T>(
-        payload: &T,
-        unprotected: Option<COSEHeaderMap>,
-        sign_key: &dyn SigningPrivateKey,
-    ) -> Result<Self, Error>
-    where
-        T: Serializable,
-    {
-        let unprotected = match unprotected {
-            Some(v) => v,
-            None => COSEHeaderMap::new(),
-        };
-        let payload = payload.serialize_data()?;
-
-        let inner = COSESignInner::new(&payload, &unprotected.into(), sign_key)?;
-
-        Self::new_from_inner(inner)
     }
 
-    pub fn new_with_protected<T>(
-        payload: &T,
-        protected: COSEHeaderMap,
-        unprotected: Option<COSEHeaderMap>,
-        sign_key: &dyn SigningPrivateKey,
-    ) -> Result<Self, Error>
-    where
-        T: Serializable,
-    {
-        let unprotected = match unprotected {
-            Some(v) => v,
-            None => COSEHeaderMap::new(),
-        };
-        let payload = payload.serialize_data()?;
-
-        let (sig_alg, _) = sign_key.get_parameters()?;
-        let mut protected: aws_nitro_enclaves_cose::header_map::HeaderMap = protected.into();
-        protected.insert(1.into(), (sig_alg as i8).into());
-
-        let inner =
-            COSESignInner::new_with_protected(&payload, &protected, &unprotected.into(), sign_key)?;
+    #[test]
+    fn test_ecdh256_known_answer() {
+        let owner = KeyExchange::Ecdh(
+            KexSuite::Ecdh256,
+            hex::decode("307702010104205a365d4773c3a40818c6132fb1f9f1e5088dd5d5a7b5e7520a7432d43ad965c5a00a06082a8648ce3d030107a14403420004b49e8b36689fbbca06fa680498ca2856f9f6e99930ae1564a52ec56c70c3cac4d780f4a1fd500c8b059dbc22c71fe803f3bc7e1559946293ef75268d5efa6235").unwrap().into(),
+            (0x10..0x20).collect(),
+        );
+        let device = KeyExchange::Ecdh(
+            KexSuite::Ecdh256,
+            hex::decode("30770201010420679b0d50c93ab45ccb5f1cd042db3c1f1215ac25ea024b98d1a2e0a0cb48da1ca00a06082a8648ce3d030107a14403420004067612984321936c9ac0ccaa74d4b50df87d09d478f22214449971597649f3c5b08d622db4e3a3b87df0e8df5a9c410c44204274bd873f448fc496ccca06ca9b").unwrap().into(),
+            (0x20..0x30).collect(),
+        );
+        let expected = expected((
+            "785740f8bf5fa27d1873e05bf5f91a7a",
+            "34eb0ef576e705ffcbb6e56052424255",
+        ));
 
-        Self::new_from_inner(inner)
+        let owner_keys = owner
+            .derive_key(
+                KeyDeriveSide::OwnerService,
+                CipherSuite::A128Gcm,
+                &device.get_public().unwrap(),
+                false,
+            )
+            .unwrap();
+        let device_keys = device
+            .derive_key(
+                KeyDeriveSide::Device,
+                CipherSuite::A128Gcm,
+                &owner.get_public().unwrap(),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(sevk(owner_keys), expected);
+        assert_eq!(sevk(device_keys), expected);
     }
 
-    pub fn verify(&self, sign_key: &dyn SigningPublicKey) -> Result<(), Error> {
-        if self.cached_inner.verify_signature(sign_key)? {
-            Ok(())
-        } else {
-            Err(Error::InconsistentValue("Signature verification failed"))
-        }
-    }
-
-    pub fn from_eat<ES>(
-        eat: EATokenPayload<ES>,
-        unprotected: Option<COSEHeaderMap>,
-        sign_key: &dyn SigningPrivateKey,
-    ) -> Result<Self, Error>
-    where
-        ES: PayloadState,
-    {
-        let claims = eat.to_map();
-        Self::new(&claims.0, unprotected, sign_key)
-    }
+    #[test]
+    fn test_dhkexid14_known_answer() {
+        let owner = KeyExchange::Dhkex(
+            KexSuite::DhkexId14,
+            hex::decode("ef1364ad1c02f25228e710ab0a98466f761555bf125a9812b9f5b35a22a6cf39")
+                .unwrap()
+                .into(),
+        );
+        let device = KeyExchange::Dhkex(
+            KexSuite::DhkexId14,
+            hex::decode("4e8fd30b058d0a1baf6d3634349c39acdb028a03f51780a8c7f810fac0db561e")
+                .unwrap()
+                .into(),
+        );
+        let expected = expected((
+            "16342c9d5c02f3c0448f31193099457bbf45ad63c896a9105b9cd129967fad0a",
+            "5c271862178df7e209a05c197ea74a9fd15d71e1f4c9474a4d9b228be47b5170",
+        ));
 
-    pub fn get_payload_unverified<T>(&self) -> Result<UnverifiedValue<T>, Error>
-    where
-        T: Serializable,
-    {
-        let payload = self.cached_inner.get_payload(None)?;
-        Ok(UnverifiedValue(T::deserialize_data(&payload)?))
+        let owner_keys = owner
+            .derive_key(
+                KeyDeriveSide::OwnerService,
+                CipherSuite::A256Gcm,
+                &device.get_public().unwrap(),
+                false,
+            )
+            .unwrap();
+        let device_keys = device
+            .derive_key(
+                KeyDeriveSide::Device,
+                CipherSuite::A256Gcm,
+                &owner.get_public().unwrap(),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(sevk(owner_keys), expected);
+        assert_eq!(sevk(device_keys), expected);
     }
 
-    pub fn get_payload<T>(&self, key: &dyn SigningPublicKey) -> Result<T, Error>
-    where
-        T: Serializable,
-    {
-        let payload = self.cached_inner.get_payload(Some(key))?;
-        T::deserialize_data(&payload)
+    #[test]
+    fn test_asymkex_known_answer() {
+        // ShSe is the Device random, ContextRand the Owner random
+        let device = KeyExchange::Asymkex(
+            KexSuite::Asymkex2048,
+            (0x60..0x80).collect::<Vec<u8>>().into(),
+            Some(vec![]),
+        );
+        let owner_random: Vec<u8> = (0x40..0x60).collect();
+
+        let device_keys = device
+            .derive_key(
+                KeyDeriveSide::Device,
+                CipherSuite::A128Gcm,
+                &owner_random,
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(
+            sevk(device_keys),
+            expected((
+                "fd4ac1839f7a64afb8ee274fb29485e2",
+                "07117c8e43930ab30951e28eba8bdd26",
+            ))
+        );
     }
 
-    pub fn get_eat_unverified(&self) -> Result<EATokenPayload<PayloadUnverified>, Error> {
-        let claims: COSEHeaderMapType = unsafe { self.get_payload_unverified()?.into_unverified() };
-        let claims = COSEHeaderMap(claims);
-
-        eat_from_map(claims)
+    #[test]
+    fn test_asymkex_roundtrip() {
+        let owner_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let owner_public_key =
+            PKey::public_key_from_der(&owner_key.public_key_to_der().unwrap()).unwrap();
+
+        let owner = KeyExchange::new(KexSuite::Asymkex2048).unwrap();
+        let device =
+            KeyExchange::new_asymkex_device(KexSuite::Asymkex2048, &owner_public_key).unwrap();
+        assert!(KeyExchange::new_asymkex_device(KexSuite::Asymkex3072, &owner_public_key).is_err());
+
+        let device_public = device.get_public().unwrap();
+        assert!(owner
+            .derive_key(
+                KeyDeriveSide::OwnerService,
+                CipherSuite::A256Gcm,
+                &device_public,
+                false,
+            )
+            .is_err());
+
+        let owner_keys = owner
+            .derive_key_with_owner_key(
+                KeyDeriveSide::OwnerService,
+                CipherSuite::A256Gcm,
+                &device_public,
+                Some(&owner_key),
+                false,
+            )
+            .unwrap();
+        let device_keys = device
+            .derive_key(
+                KeyDeriveSide::Device,
+                CipherSuite::A256Gcm,
+                &owner.get_public().unwrap(),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(sevk(owner_keys), sevk(device_keys));
     }
 
-    pub fn get_eat(
-        &self,
-        key: &dyn SigningPublicKey,
-    ) -> Result<EATokenPayload<PayloadVerified>, Error> {
-        let claims: COSEHeaderMapType = self.get_payload(key)?;
-        let claims = COSEHeaderMap(claims);
-
-        eat_from_map(claims)
+    #[test]
+    fn test_asymkex_owner_key_check() {
+        let rsa_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ec_group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec_key = PKey::from_ec_key(EcKey::generate(&ec_group).unwrap()).unwrap();
+
+        assert!(KexSuite::Asymkex2048.is_asymkex());
+        assert!(!KexSuite::Ecdh384.is_asymkex());
+        assert!(KexSuite::Asymkex2048
+            .check_asymkex_owner_key(&rsa_key)
+            .is_ok());
+        assert!(KexSuite::Asymkex3072
+            .check_asymkex_owner_key(&rsa_key)
+            .is_err());
+        assert!(KexSuite::Asymkex2048
+            .check_asymkex_owner_key(&ec_key)
+            .is_err());
+        assert!(KexSuite::Ecdh256.check_asymkex_owner_key(&rsa_key).is_err());
     }
 
-    pub fn get_protected_value_unverified<T>(
-        &self,
-        header_key: HeaderKeys,
-    ) -> Result<Option<UnverifiedValue<T>>, Error>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        let (protected, _) = self.cached_inner.get_protected_and_payload(None)?;
-        match protected.get(&header_key.cbor_value()) {
-            None => Ok(None),
-            Some(val) => Ok(Some(UnverifiedValue(serde_cbor::value::from_value(
-                val.clone(),
-            )?))),
+    #[test]
+    fn test_ecdh_truncated_public() {
+        let owner = KeyExchange::new(KexSuite::Ecdh256).unwrap();
+        let device = KeyExchange::new(KexSuite::Ecdh256).unwrap();
+        let device_public = device.get_public().unwrap();
+
+        for len in [0, 1, 2, 20, device_public.len() - 1] {
+            assert!(owner
+                .derive_key(
+                    KeyDeriveSide::OwnerService,
+                    CipherSuite::A256Gcm,
+                    &device_public[..len],
+                    false,
+                )
+                .is_err());
         }
     }
+}
 
-    pub fn get_protected_value<T>(
-        &self,
-        header_key: HeaderKeys,
-        key: &dyn SigningPublicKey,
-    ) -> Result<Option<T>, Error>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        let (protected, _) = self.cached_inner.get_protected_and_payload(Some(key))?;
-        match protected.get(&header_key.cbor_value()) {
-            None => Ok(None),
-            Some(val) => Ok(Some(serde_cbor::value::from_value(val.clone())?)),
-        }
-    }
+#[cfg(test)]
+mod test_ciphersuite {
+    use super::CipherSuite;
 
-    pub fn get_unprotected_value<T>(&self, key: HeaderKeys) -> Result<Option<T>, Error>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        match self.cached_inner.get_unprotected().get(&key.cbor_value()) {
-            None => Ok(None),
-            Some(val) => Ok(Some(serde_cbor::value::from_value(val.clone())?)),
+    #[test]
+    fn test_ccm_encrypt0_roundtrip() {
+        for (suite, keylen) in [
+            (CipherSuite::AesCcm64_128_128, 16),
+            (CipherSuite::AesCcm64_128_256, 32),
+        ] {
+            let key = vec![0x42; keylen];
+            let encrypted = suite.ccm_encrypt0(&key, b"hello world").unwrap();
+            assert_eq!(
+                suite.ccm_decrypt0(&key, &encrypted).unwrap(),
+                b"hello world"
+            );
+
+            let mut tampered = encrypted.clone();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 0x01;
+            assert!(suite.ccm_decrypt0(&key, &tampered).is_err());
+
+            assert!(suite.ccm_decrypt0(&vec![0x24; keylen], &encrypted).is_err());
         }
+        assert!(CipherSuite::A128Gcm.ccm_encrypt0(&[0; 16], b"").is_err());
     }
 }
 
@@ -1998,8 +2732,17 @@ impl<'de> Deserialize<'de> for RemoteTransport {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum RemoteAddress {
-    IP { ip_address: String },
-    Dns { dns_name: String },
+    // Needs to be first, as untagged enums pick the first variant that matches
+    DnsIP {
+        dns_name: String,
+        ip_address: String,
+    },
+    IP {
+        ip_address: String,
+    },
+    Dns {
+        dns_name: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -2037,6 +2780,18 @@ impl TryFrom<RemoteConnection> for Vec<TO2AddressEntry> {
 
         for addr in &rc.addresses {
             match addr {
+                RemoteAddress::DnsIP {
+                    dns_name,
+                    ip_address,
+                } => {
+                    let addr = IpAddr::from_str(ip_address)?;
+                    results.push(TO2AddressEntry::new(
+                        Some(addr.into()),
+                        Some(dns_name.clone()),
+                        rc.port,
+                        transport,
+                    ));
+                }
                 RemoteAddress::IP { ip_address } => {
                     let addr = IpAddr::from_str(ip_address)?;
                     results.push(TO2AddressEntry::new(
@@ -0,0 +1,792 @@
+//! Property-based round-trip tests of the data formats.
+//!
+//! Every value is serialized, parsed back and serialized again: both encodings must be
+//! identical, so a type that reads its fields in a different order than it writes them
+//! is caught. The encoding must also use the CBOR preferred serialization: definite
+//! lengths and the shortest argument encoding, as other FDO implementations expect.
+//!
+//! Run with `cargo test -p fdo-data-formats --test roundtrip`. Set `PROPTEST_CASES` to run
+//! more cases than the default 256.
+
+use std::{convert::TryFrom, net::IpAddr, sync::OnceLock};
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{X509Builder, X509NameBuilder},
+};
+use proptest::{collection::vec, option, prelude::*, test_runner::TestCaseError};
+
+use fdo_data_formats::{
+    constants::{
+        DeviceSigType, ErrorCode, HashType, KeyStorageType, MessageType, MfgStringType,
+        PublicKeyType, RendezvousProtocolValue, ServiceInfoModule, TransportProtocol,
+    },
+    cose::COSESign,
+    devicecredential::{file::KeyStorage, FileDeviceCredential},
+    messages::{
+        v11::{di, diun, to0, to1, to2, ErrorMessage},
+        Message,
+    },
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::PublicKey,
+    types::{
+        CborSimpleType, CipherSuite, Guid, Hash, IPAddress, KexSuite, Nonce, RemoteAddress,
+        RemoteConnection, RemoteTransport, RendezvousDirectiveBuilder, RendezvousInfo, ServiceInfo,
+        SigInfo, TO0Data, TO1DataPayload, TO2AddressEntry, TO2ProveDevicePayload,
+        TO2SetupDevicePayload,
+    },
+    ProtocolVersion, Serializable,
+};
+
+/// Serializes, parses and re-serializes `value`, checking that the encodings match
+fn roundtrip<T: Serializable>(value: &T) -> Result<T, TestCaseError> {
+    let encoded = value
+        .serialize_data()
+        .map_err(|e| TestCaseError::fail(format!("Error serializing: {e:?}")))?;
+    check_preferred_serialization(&encoded)?;
+
+    let decoded = T::deserialize_data(&encoded)
+        .map_err(|e| TestCaseError::fail(format!("Error parsing {}: {e:?}", hex(&encoded))))?;
+    let reencoded = decoded
+        .serialize_data()
+        .map_err(|e| TestCaseError::fail(format!("Error re-serializing: {e:?}")))?;
+    prop_assert_eq!(hex(&encoded), hex(&reencoded));
+
+    Ok(decoded)
+}
+
+/// Like `roundtrip`, for the wire format of a specific protocol version
+fn roundtrip_version<M: Message>(version: ProtocolVersion, msg: &M) -> Result<M, TestCaseError> {
+    let encoded = msg
+        .serialize_for_version(version)
+        .map_err(|e| TestCaseError::fail(format!("Error serializing: {e:?}")))?;
+    check_preferred_serialization(&encoded)?;
+
+    let decoded = M::deserialize_for_version(version, &encoded)
+        .map_err(|e| TestCaseError::fail(format!("Error parsing {}: {e:?}", hex(&encoded))))?;
+    let reencoded = decoded
+        .serialize_for_version(version)
+        .map_err(|e| TestCaseError::fail(format!("Error re-serializing: {e:?}")))?;
+    prop_assert_eq!(hex(&encoded), hex(&reencoded));
+
+    Ok(decoded)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Checks that `data` is a single CBOR item with definite lengths and shortest arguments
+fn check_preferred_serialization(data: &[u8]) -> Result<(), TestCaseError> {
+    let rest =
+        check_item(data).map_err(|e| TestCaseError::fail(format!("{e} in {}", hex(data))))?;
+    prop_assert!(rest.is_empty(), "Trailing data in {}", hex(data));
+    Ok(())
+}
+
+fn check_item(data: &[u8]) -> Result<&[u8], String> {
+    let (&initial, data) = data.split_first().ok_or("Truncated item")?;
+    let major = initial >> 5;
+    let minor = initial & 0x1f;
+
+    let (arg, data) = match minor {
+        0..=23 => (minor as u64, data),
+        24..=27 => {
+            let len = 1 << (minor - 24);
+            if data.len() < len {
+                return Err("Truncated argument".to_string());
+            }
+            let (arg, data) = data.split_at(len);
+            let arg = arg.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            // Floats are major type 7 with a 2, 4 or 8 byte argument
+            let is_float = major == 7 && minor >= 25;
+            let min = match minor {
+                24 => 24,
+                25 => 1 << 8,
+                26 => 1 << 16,
+                _ => 1 << 32,
+            };
+            if !is_float && arg < min {
+                return Err(format!("Non-shortest argument {arg} with length {len}"));
+            }
+            (arg, data)
+        }
+        31 => return Err("Indefinite length item".to_string()),
+        _ => return Err(format!("Reserved additional information {minor}")),
+    };
+
+    match major {
+        // Integers and simple values
+        0 | 1 | 7 => Ok(data),
+        // Byte and text strings
+        2 | 3 => {
+            let len = usize::try_from(arg).map_err(|_| "String too long")?;
+            if data.len() < len {
+                return Err("Truncated string".to_string());
+            }
+            Ok(&data[len..])
+        }
+        // Arrays and maps
+        4 | 5 => {
+            let items = if major == 5 {
+                arg.saturating_mul(2)
+            } else {
+                arg
+            };
+            let mut data = data;
+            for _ in 0..items {
+                data = check_item(data)?;
+            }
+            Ok(data)
+        }
+        // Tags
+        6 => check_item(data),
+        _ => unreachable!(),
+    }
+}
+
+/// The key and self-signed certificate used for all signatures
+fn signer() -> &'static (PKey<Private>, PublicKey) {
+    static SIGNER: OnceLock<(PKey<Private>, PublicKey)> = OnceLock::new();
+
+    SIGNER.get_or_init(|| {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "FDO round-trip test")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let public_key = PublicKey::try_from(builder.build()).unwrap();
+        (key, public_key)
+    })
+}
+
+fn bytes() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..300)
+}
+
+fn text() -> impl Strategy<Value = String> {
+    ".{0,40}"
+}
+
+fn protocol_version() -> impl Strategy<Value = ProtocolVersion> {
+    prop_oneof![
+        Just(ProtocolVersion::Version1_0),
+        Just(ProtocolVersion::Version1_1),
+    ]
+}
+
+fn hash_type() -> impl Strategy<Value = HashType> {
+    prop_oneof![
+        Just(HashType::Sha256),
+        Just(HashType::Sha384),
+        Just(HashType::HmacSha256),
+        Just(HashType::HmacSha384),
+    ]
+}
+
+fn hash() -> impl Strategy<Value = Hash> {
+    hash_type().prop_flat_map(|hash_type| {
+        vec(any::<u8>(), hash_type.digest_size())
+            .prop_map(move |value| Hash::from_digest(hash_type, value).unwrap())
+    })
+}
+
+fn nonce() -> impl Strategy<Value = Nonce> {
+    any::<[u8; 16]>().prop_map(|value| Nonce::from_value(&value).unwrap())
+}
+
+fn guid() -> impl Strategy<Value = Guid> {
+    any::<[u8; 16]>().prop_map(|value| uuid::Uuid::from_bytes(value).to_string().parse().unwrap())
+}
+
+fn ip_address() -> impl Strategy<Value = IpAddr> {
+    any::<IpAddr>()
+}
+
+fn sig_info() -> impl Strategy<Value = SigInfo> {
+    let sig_type = prop_oneof![
+        Just(DeviceSigType::StSECP256R1),
+        Just(DeviceSigType::StSECP384R1),
+        Just(DeviceSigType::StRSA2048),
+        Just(DeviceSigType::StRSA3072),
+        Just(DeviceSigType::StEPID10),
+        Just(DeviceSigType::StEPID11),
+        Just(DeviceSigType::StEPID20),
+    ];
+    (sig_type, bytes()).prop_map(|(sig_type, info)| SigInfo::new(sig_type, info))
+}
+
+fn kex_suite() -> impl Strategy<Value = KexSuite> {
+    prop_oneof![
+        Just(KexSuite::Ecdh256),
+        Just(KexSuite::Ecdh384),
+        Just(KexSuite::DhkexId14),
+        Just(KexSuite::DhkexId15),
+        Just(KexSuite::Asymkex2048),
+        Just(KexSuite::Asymkex3072),
+    ]
+}
+
+fn cipher_suite() -> impl Strategy<Value = CipherSuite> {
+    prop_oneof![
+        Just(CipherSuite::A128Gcm),
+        Just(CipherSuite::A256Gcm),
+        Just(CipherSuite::AesCcm64_128_128),
+        Just(CipherSuite::AesCcm64_128_256),
+    ]
+}
+
+fn public_key_type() -> impl Strategy<Value = PublicKeyType> {
+    prop_oneof![
+        Just(PublicKeyType::Rsa2048RESTR),
+        Just(PublicKeyType::RsaPkcs),
+        Just(PublicKeyType::RsaPss),
+        Just(PublicKeyType::SECP256R1),
+        Just(PublicKeyType::SECP384R1),
+        Just(PublicKeyType::Ed25519),
+    ]
+    .prop_filter("Public key type not enabled", PublicKeyType::is_enabled)
+}
+
+fn key_storage_type() -> impl Strategy<Value = KeyStorageType> {
+    prop_oneof![Just(KeyStorageType::FileSystem), Just(KeyStorageType::Tpm)]
+}
+
+fn transport_protocol() -> impl Strategy<Value = TransportProtocol> {
+    prop_oneof![
+        Just(TransportProtocol::Tcp),
+        Just(TransportProtocol::Tls),
+        Just(TransportProtocol::Http),
+        Just(TransportProtocol::CoAP),
+        Just(TransportProtocol::Https),
+        Just(TransportProtocol::CoAPS),
+    ]
+}
+
+fn message_type() -> impl Strategy<Value = MessageType> {
+    prop_oneof![
+        Just(MessageType::DIAppStart),
+        Just(MessageType::DISetHMAC),
+        Just(MessageType::TO0OwnerSign),
+        Just(MessageType::TO1ProveToRV),
+        Just(MessageType::TO2HelloDevice),
+        Just(MessageType::TO2DeviceServiceInfo),
+        Just(MessageType::DIUNConnect),
+        Just(MessageType::Error),
+    ]
+}
+
+fn error_code() -> impl Strategy<Value = ErrorCode> {
+    prop_oneof![
+        Just(ErrorCode::InvalidJWT),
+        Just(ErrorCode::InvalidOwnershipVoucher),
+        Just(ErrorCode::InvalidOwnerSignBody),
+        Just(ErrorCode::InvalidIPAddress),
+        Just(ErrorCode::InvalidGUID),
+        Just(ErrorCode::ResourceNotFound),
+        Just(ErrorCode::MessageBodyError),
+        Just(ErrorCode::InvalidMessageError),
+        Just(ErrorCode::CredReuseError),
+        Just(ErrorCode::InternalServerError),
+    ]
+}
+
+/// CBOR values without floats, whose encoding isn't unique
+fn cbor_value() -> impl Strategy<Value = CborSimpleType> {
+    let leaf = prop_oneof![
+        Just(CborSimpleType::Null),
+        any::<bool>().prop_map(CborSimpleType::Bool),
+        any::<i64>().prop_map(|i| CborSimpleType::Integer(i.into())),
+        bytes().prop_map(CborSimpleType::Bytes),
+        text().prop_map(CborSimpleType::Text),
+    ];
+    leaf.prop_recursive(3, 20, 5, |inner| {
+        vec(inner, 0..5).prop_map(CborSimpleType::Array)
+    })
+}
+
+fn rendezvous_info() -> impl Strategy<Value = RendezvousInfo> {
+    let instruction = prop_oneof![
+        Just(RendezvousDirectiveBuilder::device_only as fn(_) -> _),
+        Just(RendezvousDirectiveBuilder::owner_only as fn(_) -> _),
+        Just(RendezvousDirectiveBuilder::user_input as fn(_) -> _),
+        Just(RendezvousDirectiveBuilder::bypass as fn(_) -> _),
+    ];
+    let directive = (
+        vec(instruction, 0..3),
+        option::of(ip_address()),
+        option::of(any::<u16>()),
+        option::of(any::<u16>()),
+        option::of(text()),
+        option::of(hash()),
+        option::of(text()),
+        option::of(any::<u8>()),
+        option::of(prop_oneof![
+            Just(RendezvousProtocolValue::Rest),
+            Just(RendezvousProtocolValue::Http),
+            Just(RendezvousProtocolValue::Https),
+            Just(RendezvousProtocolValue::Tcp),
+            Just(RendezvousProtocolValue::Tls),
+            Just(RendezvousProtocolValue::CoAPTCP),
+            Just(RendezvousProtocolValue::CoAPUDP),
+        ]),
+        option::of(any::<u32>()),
+    )
+        .prop_map(
+            |(
+                markers,
+                ip,
+                device_port,
+                owner_port,
+                dns,
+                cert_hash,
+                ssid,
+                medium,
+                protocol,
+                delay,
+            )| {
+                let mut builder = RendezvousDirectiveBuilder::new();
+                for marker in markers {
+                    builder = marker(builder);
+                }
+                if let Some(ip) = ip {
+                    builder = builder.ip_address(ip);
+                }
+                if let Some(port) = device_port {
+                    builder = builder.device_port(port);
+                }
+                if let Some(port) = owner_port {
+                    builder = builder.owner_port(port);
+                }
+                if let Some(dns) = dns {
+                    builder = builder.dns(&dns);
+                }
+                if let Some(hash) = cert_hash {
+                    builder = builder.server_cert_hash(hash);
+                }
+                if let Some(ssid) = ssid {
+                    builder = builder.wifi_ssid(&ssid);
+                }
+                if let Some(medium) = medium {
+                    builder = builder.medium(medium);
+                }
+                if let Some(protocol) = protocol {
+                    builder = builder.protocol(protocol);
+                }
+                if let Some(delay) = delay {
+                    builder = builder.delay_sec(delay);
+                }
+                builder.build().unwrap()
+            },
+        );
+    vec(directive, 0..4).prop_map(|directives| RendezvousInfo::from_directives(directives).unwrap())
+}
+
+fn to2_address_entry() -> impl Strategy<Value = TO2AddressEntry> {
+    (
+        option::of(ip_address()),
+        option::of(text()),
+        any::<u16>(),
+        transport_protocol(),
+    )
+        .prop_map(|(ip, dns, port, protocol)| {
+            TO2AddressEntry::new(ip.map(IPAddress::from), dns, port, protocol)
+        })
+}
+
+fn to1d_payload() -> impl Strategy<Value = TO1DataPayload> {
+    (vec(to2_address_entry(), 0..4), hash())
+        .prop_map(|(addresses, hash)| TO1DataPayload::new(addresses, hash))
+}
+
+fn service_info() -> impl Strategy<Value = ServiceInfo> {
+    let module = prop_oneof![
+        Just("devmod".to_string()),
+        Just("fdo.download".to_string()),
+        Just("org.fedoraiot.sshkey".to_string()),
+        "[a-z.]{1,20}",
+    ];
+    vec((module, "[a-z]{1,10}", cbor_value()), 0..8).prop_map(|entries| {
+        let mut service_info = ServiceInfo::new();
+        for (module, key, value) in entries {
+            service_info
+                .add(module.parse::<ServiceInfoModule>().unwrap(), &key, &value)
+                .unwrap();
+        }
+        service_info
+    })
+}
+
+fn ov_header() -> impl Strategy<Value = OwnershipVoucherHeader> {
+    (
+        guid(),
+        rendezvous_info(),
+        "[a-zA-Z0-9-]{1,20}",
+        option::of(hash()),
+    )
+        .prop_map(|(guid, rvinfo, device_info, cert_chain_hash)| {
+            OwnershipVoucherHeader::new(
+                ProtocolVersion::Version1_1,
+                guid,
+                rvinfo,
+                device_info,
+                signer().1.clone(),
+                cert_chain_hash,
+            )
+            .unwrap()
+        })
+}
+
+fn ownership_voucher() -> impl Strategy<Value = OwnershipVoucher> {
+    (ov_header(), hash(), 0..3usize).prop_map(|(header, hmac, num_entries)| {
+        let (key, public_key) = signer();
+        let mut ov = OwnershipVoucher::new(header, hmac, None).unwrap();
+        for _ in 0..num_entries {
+            ov.extend(key, None, public_key).unwrap();
+        }
+        ov
+    })
+}
+
+fn signed<T: Serializable>(payload: &T) -> COSESign {
+    COSESign::new_with_pkey(payload, None, &signer().0).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn test_hash(hash in hash()) {
+        let decoded = roundtrip(&hash)?;
+        prop_assert_eq!(decoded, hash);
+    }
+
+    #[test]
+    fn test_nonce_and_guid(nonce in nonce(), guid in guid()) {
+        prop_assert_eq!(roundtrip(&nonce)?, nonce);
+        prop_assert_eq!(roundtrip(&guid)?, guid);
+    }
+
+    #[test]
+    fn test_ip_address(addr in ip_address()) {
+        let addr = IPAddress::from(addr);
+        prop_assert_eq!(roundtrip(&addr)?, addr);
+    }
+
+    #[test]
+    fn test_sig_info(sig_info in sig_info()) {
+        let decoded = roundtrip(&sig_info)?;
+        prop_assert_eq!(decoded.sig_type(), sig_info.sig_type());
+        prop_assert_eq!(decoded.info(), sig_info.info());
+    }
+
+    #[test]
+    fn test_suites(kex_suite in kex_suite(), cipher_suite in cipher_suite()) {
+        roundtrip(&kex_suite)?;
+        roundtrip(&cipher_suite)?;
+    }
+
+    #[test]
+    fn test_rendezvous_info(rvinfo in rendezvous_info()) {
+        let decoded = roundtrip(&rvinfo)?;
+        prop_assert_eq!(
+            decoded.typed_values().unwrap(),
+            rvinfo.typed_values().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_to1d_payload(payload in to1d_payload()) {
+        let decoded = roundtrip(&payload)?;
+        prop_assert_eq!(decoded.to2_addresses().len(), payload.to2_addresses().len());
+        for (decoded, entry) in decoded.to2_addresses().iter().zip(payload.to2_addresses()) {
+            prop_assert_eq!(decoded.ip(), entry.ip());
+            prop_assert_eq!(decoded.dns(), entry.dns());
+            prop_assert_eq!(decoded.port(), entry.port());
+        }
+        prop_assert_eq!(decoded.to1d_to_to0d_hash(), payload.to1d_to_to0d_hash());
+    }
+
+    #[test]
+    fn test_service_info(service_info in service_info()) {
+        let decoded = roundtrip(&service_info)?;
+        prop_assert_eq!(decoded.values().unwrap(), service_info.values().unwrap());
+    }
+
+    #[test]
+    fn test_remote_connection(
+        port in any::<u16>(),
+        dns_name in "[a-z.]{1,20}",
+        ip_address in ip_address(),
+    ) {
+        let connection = RemoteConnection::new(
+            RemoteTransport::Https,
+            vec![
+                RemoteAddress::DnsIP {
+                    dns_name: dns_name.clone(),
+                    ip_address: ip_address.to_string(),
+                },
+                RemoteAddress::IP {
+                    ip_address: ip_address.to_string(),
+                },
+                RemoteAddress::Dns { dns_name },
+            ],
+            port,
+        );
+        roundtrip(&connection)?;
+    }
+
+    #[test]
+    fn test_ownership_voucher(
+        ov in ownership_voucher(),
+        wait_seconds in any::<u32>(),
+        nonce in nonce(),
+    ) {
+        let decoded = roundtrip(&ov)?;
+        prop_assert_eq!(decoded.num_entries(), ov.num_entries());
+        prop_assert_eq!(decoded.header().guid(), ov.header().guid());
+
+        let to0d = TO0Data::new(ov, wait_seconds, nonce.clone()).unwrap();
+        let decoded = roundtrip(&to0d)?;
+        prop_assert_eq!(decoded.wait_seconds(), wait_seconds);
+        prop_assert_eq!(decoded.nonce(), &nonce);
+    }
+
+    #[test]
+    fn test_device_credential(
+        active in any::<bool>(),
+        protver in protocol_version(),
+        device_info in text(),
+        guid in guid(),
+        rvinfo in rendezvous_info(),
+        pubkey_hash in hash(),
+        hmac_secret in bytes(),
+        private_key in bytes(),
+    ) {
+        let credential = FileDeviceCredential {
+            active,
+            protver,
+            device_info,
+            guid,
+            rvinfo,
+            pubkey_hash,
+            key_storage: KeyStorage::Plain {
+                hmac_secret: hmac_secret.into(),
+                private_key: private_key.into(),
+            },
+        };
+        let decoded = roundtrip(&credential)?;
+        prop_assert_eq!(decoded.active, credential.active);
+        prop_assert_eq!(decoded.device_info, credential.device_info);
+        prop_assert_eq!(decoded.guid, credential.guid);
+
+        let file_data = credential.to_file_data().unwrap();
+        let decoded = FileDeviceCredential::from_file_data(&file_data).unwrap();
+        prop_assert_eq!(hex(&decoded.to_file_data().unwrap()), hex(&file_data));
+    }
+
+    #[test]
+    fn test_di_messages(mfg_info in cbor_value(), header in ov_header(), hmac in hash()) {
+        let decoded = roundtrip(&di::AppStart::new(mfg_info.clone()).unwrap())?;
+        prop_assert_eq!(decoded.mfg_info().unwrap(), mfg_info);
+
+        let decoded = roundtrip(&di::SetCredentials::new(header))?;
+        roundtrip(decoded.ov_header())?;
+
+        let decoded = roundtrip(&di::SetHMAC::new(hmac.clone()))?;
+        prop_assert_eq!(decoded.hmac(), &hmac);
+
+        roundtrip(&di::Done::new())?;
+    }
+
+    #[test]
+    fn test_diun_messages(
+        nonce in nonce(),
+        kex_suite in kex_suite(),
+        cipher_suite in cipher_suite(),
+        data in (bytes(), bytes(), bytes()),
+        tenant_id in option::of(text()),
+        key_type in public_key_type(),
+        storage_types in option::of(vec(key_storage_type(), 0..3)),
+        storage_type in key_storage_type(),
+    ) {
+        let (first, second, third) = data;
+
+        let connect = diun::Connect::new(nonce.clone(), kex_suite, cipher_suite, first.clone());
+        let decoded = roundtrip(&connect)?;
+        prop_assert_eq!(decoded.nonce_diun_1(), &nonce);
+        prop_assert_eq!(decoded.key_exchange(), &first[..]);
+
+        let accept = diun::Accept::new(signed(&diun::AcceptPayload::new(first.clone())));
+        let decoded = roundtrip(&accept)?;
+        let payload: diun::AcceptPayload = decoded
+            .into_token()
+            .get_payload_with_public_key(&signer().1)
+            .unwrap();
+        prop_assert_eq!(payload.key_exchange(), &first[..]);
+
+        let decoded = roundtrip(&diun::RequestKeyParameters::new(tenant_id.clone()))?;
+        prop_assert_eq!(decoded.tenant_id(), tenant_id.as_deref());
+
+        let decoded = roundtrip(&diun::ProvideKeyParameters::new(key_type, storage_types.clone()))?;
+        prop_assert_eq!(decoded.key_type(), &key_type);
+        prop_assert_eq!(decoded.key_storage_types_allowed(), storage_types.as_deref());
+
+        let decoded = roundtrip(&diun::ProvideKey::new(first.clone(), storage_type))?;
+        prop_assert_eq!(decoded.public_key(), &first[..]);
+        prop_assert_eq!(decoded.public_key_storage(), storage_type);
+
+        let request = diun::RequestAttestationChallenge::new(first.clone(), second.clone());
+        let decoded = roundtrip(&request)?;
+        prop_assert_eq!(decoded.endorsement_key_certificate(), &first[..]);
+        prop_assert_eq!(decoded.attestation_key_public(), &second[..]);
+
+        let decoded = roundtrip(&diun::AttestationChallenge::new(first.clone(), second.clone()))?;
+        prop_assert_eq!(decoded.credential_blob(), &first[..]);
+        prop_assert_eq!(decoded.encrypted_secret(), &second[..]);
+
+        let attested = diun::ProvideAttestedKey::new(first.clone(), second.clone(), third.clone());
+        let decoded = roundtrip(&attested)?;
+        prop_assert_eq!(decoded.public_key(), &first[..]);
+        prop_assert_eq!(decoded.certify_info(), &second[..]);
+        prop_assert_eq!(decoded.certify_signature(), &third[..]);
+
+        for mfg_string_type in [MfgStringType::SerialNumber, MfgStringType::MACAddress] {
+            let decoded = roundtrip(&diun::Done::new(mfg_string_type))?;
+            prop_assert_eq!(decoded.mfg_string_type(), mfg_string_type);
+        }
+    }
+
+    #[test]
+    fn test_rendezvous_messages(
+        version in protocol_version(),
+        nonce in nonce(),
+        wait_seconds in any::<u32>(),
+        guid in guid(),
+        sig_info in sig_info(),
+        to1d in to1d_payload(),
+    ) {
+        roundtrip_version(version, &to0::Hello::new())?;
+
+        let decoded = roundtrip_version(version, &to0::HelloAck::new(nonce.clone()))?;
+        prop_assert_eq!(decoded.nonce3(), &nonce);
+
+        let decoded = roundtrip_version(version, &to0::AcceptOwner::new(wait_seconds))?;
+        prop_assert_eq!(decoded.wait_seconds(), wait_seconds);
+
+        let hello = to1::HelloRV::new(guid.clone(), sig_info.clone());
+        let decoded = roundtrip_version(version, &hello)?;
+        prop_assert_eq!(decoded.guid(), &guid);
+        prop_assert_eq!(decoded.a_signature_info().info(), sig_info.info());
+
+        let decoded = roundtrip_version(version, &to1::HelloRVAck::new(nonce.clone(), sig_info))?;
+        prop_assert_eq!(decoded.nonce4(), &nonce);
+
+        roundtrip_version(version, &to1::RVRedirect::new(signed(&to1d)))?;
+    }
+
+    #[test]
+    fn test_to2_messages(
+        guid in guid(),
+        nonce in nonce(),
+        kex_suite in kex_suite(),
+        cipher_suite in cipher_suite(),
+        sig_info in sig_info(),
+        entry_num in any::<u8>(),
+        replacement_hmac in option::of(hash()),
+        sizes in (option::of(any::<u64>()), option::of(any::<u64>())),
+        flags in (any::<bool>(), any::<bool>()),
+        service_info in service_info(),
+        rvinfo in rendezvous_info(),
+        key_exchange in bytes(),
+    ) {
+        let hello = to2::HelloDevice::new(
+            guid.clone(),
+            nonce.clone(),
+            kex_suite,
+            cipher_suite,
+            sig_info,
+        );
+        let decoded = roundtrip(&hello)?;
+        prop_assert_eq!(decoded.guid(), &guid);
+        prop_assert_eq!(decoded.nonce5(), &nonce);
+
+        let decoded = roundtrip(&to2::GetOVNextEntry::new(entry_num))?;
+        prop_assert_eq!(decoded.entry_num(), entry_num);
+
+        let payload = TO2ProveDevicePayload::new(key_exchange);
+        roundtrip(&payload)?;
+
+        let payload = TO2SetupDevicePayload::new(rvinfo, guid, nonce.clone(), signer().1.clone());
+        roundtrip(&payload)?;
+        roundtrip(&to2::SetupDevice::new(signed(&payload)))?;
+
+        let (max_owner_size, max_device_size) = sizes;
+        let ready = to2::DeviceServiceInfoReady::new(replacement_hmac.clone(), max_owner_size);
+        let decoded = roundtrip(&ready)?;
+        prop_assert_eq!(decoded.replacement_hmac(), replacement_hmac.as_ref());
+        prop_assert_eq!(decoded.max_owner_service_info_size(), max_owner_size);
+
+        let decoded = roundtrip(&to2::OwnerServiceInfoReady::new(max_device_size))?;
+        prop_assert_eq!(decoded.max_device_service_info_size(), max_device_size);
+
+        let (is_more, is_done) = flags;
+        let decoded = roundtrip(&to2::DeviceServiceInfo::new(is_more, service_info.clone()))?;
+        prop_assert_eq!(decoded.is_more_service_info(), is_more);
+        prop_assert_eq!(decoded.service_info().len(), service_info.len());
+
+        let owner_service_info = to2::OwnerServiceInfo::new(is_more, is_done, service_info.clone());
+        let decoded = roundtrip(&owner_service_info)?;
+        prop_assert_eq!(decoded.is_more_service_info(), is_more);
+        prop_assert_eq!(decoded.is_done(), is_done);
+        prop_assert_eq!(decoded.service_info().len(), service_info.len());
+
+        let decoded = roundtrip(&to2::Done::new(nonce.clone()))?;
+        prop_assert_eq!(decoded.nonce6(), &nonce);
+
+        let decoded = roundtrip(&to2::Done2::new(nonce.clone()))?;
+        prop_assert_eq!(decoded.nonce7(), &nonce);
+    }
+
+    #[test]
+    fn test_error_message(
+        error_code in error_code(),
+        previous_message_type in message_type(),
+        error_string in text(),
+        // CBOR integers are at most 64 bits
+        error_uuid in any::<u64>(),
+    ) {
+        let msg = ErrorMessage::new(
+            error_code,
+            previous_message_type,
+            error_string.clone(),
+            error_uuid.into(),
+        );
+        let decoded = roundtrip(&msg)?;
+        prop_assert_eq!(decoded.previous_message_type(), previous_message_type);
+        prop_assert_eq!(decoded.error_string(), error_string);
+        prop_assert_eq!(decoded.error_uuid(), error_uuid as u128);
+    }
+}
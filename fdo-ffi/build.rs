@@ -0,0 +1,30 @@
+use std::env;
+
+const VERSION_MAJOR: &str = env!("CARGO_PKG_VERSION_MAJOR");
+const VERSION_MINOR: &str = env!("CARGO_PKG_VERSION_MINOR");
+const VERSION_PATCH: &str = env!("CARGO_PKG_VERSION_PATCH");
+const MANIFEST_DIR: &str = env!("CARGO_MANIFEST_DIR");
+const AUTOGEN_WARNING: &str = "/* This file is automatically generated, do not modify */";
+
+fn main() {
+    println!("cargo:rustc-cdylib-link-arg=-Wl,-soname,libfdo_ffi.so.{VERSION_MAJOR}");
+
+    cbindgen::Builder::new()
+        .with_crate(MANIFEST_DIR)
+        .with_parse_deps(true)
+        .with_parse_include(&["fdo-data-formats"])
+        .with_include_version(false)
+        .with_include_guard("FDO_FFI_H")
+        .with_after_include(format!(
+            "\n#define FDO_FFI_MAJOR {VERSION_MAJOR}\n#define FDO_FFI_MINOR {VERSION_MINOR}\n#define FDO_FFI_PATCH {VERSION_PATCH}"
+        ))
+        .with_pragma_once(true)
+        .with_autogen_warning(AUTOGEN_WARNING)
+        .with_style(cbindgen::Style::Both)
+        .with_item_prefix("Fdo")
+        .with_documentation(true)
+        .with_language(cbindgen::Language::C)
+        .generate()
+        .expect("Unable to generate bindings")
+        .write_to_file("fdo_ffi.h");
+}
@@ -0,0 +1,114 @@
+#ifndef FDO_FFI_H
+#define FDO_FFI_H
+
+#pragma once
+
+/* This file is automatically generated, do not modify */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define FDO_FFI_MAJOR 0
+#define FDO_FFI_MINOR 4
+#define FDO_FFI_PATCH 13
+
+typedef struct FdoOwnershipVoucher FdoOwnershipVoucher;
+
+/**
+ * Free a string returned by fdo-ffi functions
+ */
+void fdo_ffi_free_string(char *s);
+
+/**
+ * Free a data buffer returned by fdo-ffi functions
+ */
+void fdo_ffi_free_data(uint8_t *data, size_t len);
+
+/**
+ * Returns a string describing the last error that occurred in this thread
+ *
+ * Note: The returned string ownership is transferred to the caller, and should
+ * be freed with `fdo_ffi_free_string`
+ */
+char *fdo_ffi_get_last_error(void);
+
+/**
+ * Creates a new OwnershipVoucher from raw or PEM data
+ *
+ * Return value:
+ * NULL on error (last error is set)
+ * Pointer to an FdoOwnershipVoucher on success
+ */
+struct FdoOwnershipVoucher *fdo_ffi_ownershipvoucher_from_data(const void *data, size_t len);
+
+/**
+ * Creates a new OwnershipVoucher from PEM data
+ *
+ * Return value:
+ * NULL on error (last error is set)
+ * Pointer to an FdoOwnershipVoucher on success
+ */
+struct FdoOwnershipVoucher *fdo_ffi_ownershipvoucher_from_pem(const void *data, size_t len);
+
+/**
+ * Frees an OwnershipVoucher
+ */
+void fdo_ffi_ownershipvoucher_free(struct FdoOwnershipVoucher *v);
+
+/**
+ * Verifies the signatures and hash chain of the entries of the ownership voucher
+ *
+ * If trusted_manufacturer_certs is not NULL, it is PEM data with one or more
+ * certificates, and the manufacturer public key must be one of them.
+ *
+ * Return value:
+ * -1 on error or if the voucher is invalid (last error is set)
+ * 0 if the voucher is valid
+ */
+int32_t fdo_ffi_ownershipvoucher_validate(const struct FdoOwnershipVoucher *v,
+                                          const void *trusted_manufacturer_certs,
+                                          size_t trusted_manufacturer_certs_len);
+
+/**
+ * Extends the ownership voucher to a new owner
+ *
+ * owner_private_key is the PEM private key of the current owner, and
+ * new_owner_cert the PEM certificate of the new owner.
+ *
+ * Return value:
+ * -1 on error (last error is set)
+ * 0 on success
+ */
+int32_t fdo_ffi_ownershipvoucher_extend(struct FdoOwnershipVoucher *v,
+                                        const void *owner_private_key,
+                                        size_t owner_private_key_len,
+                                        const void *new_owner_cert,
+                                        size_t new_owner_cert_len);
+
+/**
+ * Serializes the ownership voucher to raw data
+ *
+ * Return value:
+ * NULL on error (last error is set)
+ * Pointer to the data on success, with its length stored in len
+ *
+ * Note: The returned data ownership is transferred to the caller, and should
+ * be freed with `fdo_ffi_free_data`
+ */
+uint8_t *fdo_ffi_ownershipvoucher_to_data(const struct FdoOwnershipVoucher *v, size_t *len);
+
+/**
+ * Serializes the ownership voucher to PEM
+ *
+ * Return value:
+ * NULL on error (last error is set)
+ * Pointer to a string containing the PEM voucher on success
+ *
+ * Note: The returned string ownership is transferred to the caller, and should
+ * be freed with `fdo_ffi_free_string`
+ */
+char *fdo_ffi_ownershipvoucher_to_pem(const struct FdoOwnershipVoucher *v);
+
+#endif /* FDO_FFI_H */
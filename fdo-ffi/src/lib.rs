@@ -0,0 +1,117 @@
+//! C API to parse, validate, extend and serialize ownership vouchers, for manufacturing line
+//! software that can't use the Rust crates directly.
+//!
+//! The C header `fdo_ffi.h` is generated by cbindgen when building this crate.
+
+use libc::c_char;
+use std::{cell::RefCell, ffi::CString, ptr::null_mut};
+
+mod ownershipvoucher;
+
+thread_local! {
+    // Per thread, so that concurrent callers don't see each other's errors
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = None);
+}
+
+fn set_last_error<T>(err: T)
+where
+    T: ToString,
+{
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(err.to_string()));
+}
+
+/// Free a string returned by fdo-ffi functions
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn fdo_ffi_free_string(s: *mut c_char) {
+    clear_last_error();
+
+    if s.is_null() {
+        return;
+    }
+    drop(CString::from_raw(s));
+}
+
+/// Free a data buffer returned by fdo-ffi functions
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn fdo_ffi_free_data(data: *mut u8, len: libc::size_t) {
+    clear_last_error();
+
+    if data.is_null() {
+        return;
+    }
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)));
+}
+
+/// Returns a string describing the last error that occurred in this thread
+///
+/// Note: The returned string ownership is transferred to the caller, and should
+/// be freed with `fdo_ffi_free_string`
+#[no_mangle]
+pub extern "C" fn fdo_ffi_get_last_error() -> *mut c_char {
+    LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+        None => null_mut(),
+        // Error messages can contain anything, but must not panic across the FFI boundary
+        Some(e) => CString::new(e.replace('\0', "\\0"))
+            .map(CString::into_raw)
+            .unwrap_or(null_mut()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    pub(crate) fn last_error() -> Option<String> {
+        let e = fdo_ffi_get_last_error();
+        if e.is_null() {
+            return None;
+        }
+        let msg = unsafe { CStr::from_ptr(e) }.to_str().unwrap().to_string();
+        unsafe { fdo_ffi_free_string(e) };
+        Some(msg)
+    }
+
+    #[test]
+    fn test_last_error() {
+        clear_last_error();
+        assert_eq!(last_error(), None);
+
+        set_last_error("test error");
+        assert_eq!(last_error().as_deref(), Some("test error"));
+        // Like every other call, freeing the error string clears the last error
+        assert_eq!(last_error(), None);
+    }
+
+    #[test]
+    fn test_last_error_nul() {
+        set_last_error("invalid\0error");
+        assert_eq!(last_error().as_deref(), Some("invalid\\0error"));
+    }
+
+    #[test]
+    fn test_free_null() {
+        unsafe {
+            fdo_ffi_free_string(null_mut());
+            fdo_ffi_free_data(null_mut(), 0);
+            fdo_ffi_free_data(null_mut(), 10);
+        }
+        assert_eq!(last_error(), None);
+    }
+
+    #[test]
+    fn test_free() {
+        let s = CString::new("test").unwrap().into_raw();
+        unsafe { fdo_ffi_free_string(s) };
+
+        let data = Box::into_raw(vec![1u8, 2, 3].into_boxed_slice()) as *mut u8;
+        unsafe { fdo_ffi_free_data(data, 3) };
+    }
+}
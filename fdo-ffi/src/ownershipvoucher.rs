@@ -0,0 +1,532 @@
+use libc::c_char;
+use openssl::{pkey::PKey, x509::X509};
+use std::{convert::TryFrom, ffi::CString, ptr::null_mut, slice};
+
+pub use fdo_data_formats::ownershipvoucher::OwnershipVoucher;
+use fdo_data_formats::{enhanced_types::X5Bag, publickey::PublicKey, Serializable};
+
+use super::{clear_last_error, set_last_error};
+
+#[no_mangle]
+/// Creates a new OwnershipVoucher from raw or PEM data
+///
+/// Return value:
+/// NULL on error (last error is set)
+/// Pointer to an FdoOwnershipVoucher on success
+pub extern "C" fn fdo_ffi_ownershipvoucher_from_data(
+    data: *const std::ffi::c_void,
+    len: libc::size_t,
+) -> *mut OwnershipVoucher {
+    clear_last_error();
+
+    if data.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_from_data: data is null");
+        return null_mut();
+    }
+    let data = unsafe { slice::from_raw_parts(data as *const u8, len) };
+    match OwnershipVoucher::from_pem_or_raw(data) {
+        Ok(voucher) => Box::into_raw(Box::new(voucher)),
+        Err(e) => {
+            set_last_error(e);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+/// Creates a new OwnershipVoucher from PEM data
+///
+/// Return value:
+/// NULL on error (last error is set)
+/// Pointer to an FdoOwnershipVoucher on success
+pub extern "C" fn fdo_ffi_ownershipvoucher_from_pem(
+    data: *const std::ffi::c_void,
+    len: libc::size_t,
+) -> *mut OwnershipVoucher {
+    clear_last_error();
+
+    if data.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_from_pem: data is null");
+        return null_mut();
+    }
+    let data = unsafe { slice::from_raw_parts(data as *const u8, len) };
+    match OwnershipVoucher::from_pem(data) {
+        Ok(voucher) => Box::into_raw(Box::new(voucher)),
+        Err(e) => {
+            set_last_error(e);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+/// Frees an OwnershipVoucher
+pub extern "C" fn fdo_ffi_ownershipvoucher_free(v: *mut OwnershipVoucher) {
+    clear_last_error();
+
+    if v.is_null() {
+        return;
+    }
+    unsafe {
+        drop(Box::from_raw(v));
+    }
+}
+
+#[no_mangle]
+/// Verifies the signatures and hash chain of the entries of the ownership voucher
+///
+/// If trusted_manufacturer_certs is not NULL, it is PEM data with one or more
+/// certificates, and the manufacturer public key must be one of them.
+///
+/// Return value:
+/// -1 on error or if the voucher is invalid (last error is set)
+/// 0 if the voucher is valid
+pub extern "C" fn fdo_ffi_ownershipvoucher_validate(
+    v: *const OwnershipVoucher,
+    trusted_manufacturer_certs: *const std::ffi::c_void,
+    trusted_manufacturer_certs_len: libc::size_t,
+) -> i32 {
+    clear_last_error();
+
+    if v.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_validate: v is null");
+        return -1;
+    }
+    let voucher = unsafe { &*v };
+
+    if !trusted_manufacturer_certs.is_null() {
+        let certs = unsafe {
+            slice::from_raw_parts(
+                trusted_manufacturer_certs as *const u8,
+                trusted_manufacturer_certs_len,
+            )
+        };
+        let trusted = match X509::stack_from_pem(certs)
+            .map_err(|e| e.to_string())
+            .and_then(|certs| X5Bag::with_certs(certs).map_err(|e| e.to_string()))
+        {
+            Ok(trusted) => trusted,
+            Err(e) => {
+                set_last_error(format!("Error parsing trusted manufacturer certs: {e}"));
+                return -1;
+            }
+        };
+        if !trusted.contains_publickey(voucher.header().manufacturer_public_key()) {
+            set_last_error("Manufacturer public key is not trusted");
+            return -1;
+        }
+    }
+
+    let entries = match voucher.iter_entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            set_last_error(e);
+            return -1;
+        }
+    };
+    for (pos, entry) in entries.enumerate() {
+        if let Err(e) = entry {
+            set_last_error(format!("Entry {pos} is invalid: {e}"));
+            return -1;
+        }
+    }
+
+    0
+}
+
+#[no_mangle]
+/// Extends the ownership voucher to a new owner
+///
+/// owner_private_key is the PEM private key of the current owner, and
+/// new_owner_cert the PEM certificate of the new owner.
+///
+/// Return value:
+/// -1 on error (last error is set)
+/// 0 on success
+pub extern "C" fn fdo_ffi_ownershipvoucher_extend(
+    v: *mut OwnershipVoucher,
+    owner_private_key: *const std::ffi::c_void,
+    owner_private_key_len: libc::size_t,
+    new_owner_cert: *const std::ffi::c_void,
+    new_owner_cert_len: libc::size_t,
+) -> i32 {
+    clear_last_error();
+
+    if v.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_extend: v is null");
+        return -1;
+    }
+    if owner_private_key.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_extend: owner_private_key is null");
+        return -1;
+    }
+    if new_owner_cert.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_extend: new_owner_cert is null");
+        return -1;
+    }
+    let voucher = unsafe { &mut *v };
+    let owner_private_key =
+        unsafe { slice::from_raw_parts(owner_private_key as *const u8, owner_private_key_len) };
+    let new_owner_cert =
+        unsafe { slice::from_raw_parts(new_owner_cert as *const u8, new_owner_cert_len) };
+
+    let owner_private_key = match PKey::private_key_from_pem(owner_private_key) {
+        Ok(key) => key,
+        Err(e) => {
+            set_last_error(format!("Error parsing owner private key: {e}"));
+            return -1;
+        }
+    };
+    let new_owner_pubkey = match X509::from_pem(new_owner_cert)
+        .map_err(|e| e.to_string())
+        .and_then(|cert| PublicKey::try_from(cert).map_err(|e| e.to_string()))
+    {
+        Ok(pubkey) => pubkey,
+        Err(e) => {
+            set_last_error(format!("Error parsing new owner certificate: {e}"));
+            return -1;
+        }
+    };
+
+    match voucher.extend(&owner_private_key, None, &new_owner_pubkey) {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+/// Serializes the ownership voucher to raw data
+///
+/// Return value:
+/// NULL on error (last error is set)
+/// Pointer to the data on success, with its length stored in len
+///
+/// Note: The returned data ownership is transferred to the caller, and should
+/// be freed with `fdo_ffi_free_data`
+pub extern "C" fn fdo_ffi_ownershipvoucher_to_data(
+    v: *const OwnershipVoucher,
+    len: *mut libc::size_t,
+) -> *mut u8 {
+    clear_last_error();
+
+    if v.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_to_data: v is null");
+        return null_mut();
+    }
+    if len.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_to_data: len is null");
+        return null_mut();
+    }
+    let voucher = unsafe { &*v };
+    match voucher.serialize_data() {
+        Ok(data) => {
+            let data = data.into_boxed_slice();
+            unsafe {
+                *len = data.len();
+            }
+            Box::into_raw(data) as *mut u8
+        }
+        Err(e) => {
+            set_last_error(e);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+/// Serializes the ownership voucher to PEM
+///
+/// Return value:
+/// NULL on error (last error is set)
+/// Pointer to a string containing the PEM voucher on success
+///
+/// Note: The returned string ownership is transferred to the caller, and should
+/// be freed with `fdo_ffi_free_string`
+pub extern "C" fn fdo_ffi_ownershipvoucher_to_pem(v: *const OwnershipVoucher) -> *mut c_char {
+    clear_last_error();
+
+    if v.is_null() {
+        set_last_error("fdo_ffi_ownershipvoucher_to_pem: v is null");
+        return null_mut();
+    }
+    let voucher = unsafe { &*v };
+    match voucher
+        .to_pem()
+        .map_err(|e| e.to_string())
+        .and_then(|pem| CString::new(pem).map_err(|e| e.to_string()))
+    {
+        Ok(cstr) => cstr.into_raw(),
+        Err(e) => {
+            set_last_error(e);
+            null_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_void, CStr},
+        ptr::null,
+    };
+
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::Private,
+        x509::{X509Builder, X509NameBuilder},
+    };
+
+    use fdo_data_formats::{
+        constants::HashType,
+        ownershipvoucher::OwnershipVoucherHeader,
+        types::{Guid, HMac, RendezvousInfo},
+        ProtocolVersion,
+    };
+
+    use super::*;
+    use crate::{fdo_ffi_free_data, fdo_ffi_free_string, tests::last_error};
+
+    struct Party {
+        key: PKey<Private>,
+        cert: X509,
+    }
+
+    impl Party {
+        fn new(name: &str) -> Self {
+            let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+            let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+            let mut subject = X509NameBuilder::new().unwrap();
+            subject.append_entry_by_text("CN", name).unwrap();
+            let subject = subject.build();
+            let mut cert = X509Builder::new().unwrap();
+            cert.set_version(2).unwrap();
+            cert.set_subject_name(&subject).unwrap();
+            cert.set_issuer_name(&subject).unwrap();
+            cert.set_pubkey(&key).unwrap();
+            cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+                .unwrap();
+            cert.sign(&key, MessageDigest::sha384()).unwrap();
+
+            Party {
+                key,
+                cert: cert.build(),
+            }
+        }
+
+        fn key_pem(&self) -> Vec<u8> {
+            self.key.private_key_to_pem_pkcs8().unwrap()
+        }
+
+        fn cert_pem(&self) -> Vec<u8> {
+            self.cert.to_pem().unwrap()
+        }
+    }
+
+    fn new_voucher(manufacturer: &Party) -> *mut OwnershipVoucher {
+        let header = OwnershipVoucherHeader::new(
+            ProtocolVersion::Version1_1,
+            Guid::new().unwrap(),
+            RendezvousInfo::new(vec![]).unwrap(),
+            "testdevice".to_string(),
+            PublicKey::try_from(manufacturer.cert.clone()).unwrap(),
+            None,
+        )
+        .unwrap();
+        let hmac = HMac::from_digest(HashType::HmacSha384, vec![0; 48]).unwrap();
+        let voucher = OwnershipVoucher::new(header, hmac, None).unwrap();
+        Box::into_raw(Box::new(voucher))
+    }
+
+    fn as_void(data: &[u8]) -> *const c_void {
+        data.as_ptr() as *const c_void
+    }
+
+    fn extend(v: *mut OwnershipVoucher, owner_private_key: &[u8], new_owner_cert: &[u8]) -> i32 {
+        fdo_ffi_ownershipvoucher_extend(
+            v,
+            as_void(owner_private_key),
+            owner_private_key.len(),
+            as_void(new_owner_cert),
+            new_owner_cert.len(),
+        )
+    }
+
+    fn validate(v: *const OwnershipVoucher, trusted_manufacturer_certs: Option<&[u8]>) -> i32 {
+        match trusted_manufacturer_certs {
+            None => fdo_ffi_ownershipvoucher_validate(v, null(), 0),
+            Some(certs) => fdo_ffi_ownershipvoucher_validate(v, as_void(certs), certs.len()),
+        }
+    }
+
+    #[test]
+    fn test_null_pointers() {
+        let manufacturer = Party::new("manufacturer");
+        let owner = Party::new("owner");
+        let (key, cert) = (manufacturer.key_pem(), owner.cert_pem());
+        let v = new_voucher(&manufacturer);
+        let mut len = 0;
+
+        assert!(fdo_ffi_ownershipvoucher_from_data(null(), 0).is_null());
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_from_data: data is null")
+        );
+        assert!(fdo_ffi_ownershipvoucher_from_pem(null(), 0).is_null());
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_from_pem: data is null")
+        );
+
+        assert_eq!(validate(null(), None), -1);
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_validate: v is null")
+        );
+
+        assert_eq!(extend(null_mut(), &key, &cert), -1);
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_extend: v is null")
+        );
+        let result = fdo_ffi_ownershipvoucher_extend(v, null(), 0, as_void(&cert), cert.len());
+        assert_eq!(result, -1);
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_extend: owner_private_key is null")
+        );
+        let result = fdo_ffi_ownershipvoucher_extend(v, as_void(&key), key.len(), null(), 0);
+        assert_eq!(result, -1);
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_extend: new_owner_cert is null")
+        );
+
+        assert!(fdo_ffi_ownershipvoucher_to_data(null(), &mut len).is_null());
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_to_data: v is null")
+        );
+        assert!(fdo_ffi_ownershipvoucher_to_data(v, null_mut()).is_null());
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_to_data: len is null")
+        );
+        assert!(fdo_ffi_ownershipvoucher_to_pem(null()).is_null());
+        assert_eq!(
+            last_error().as_deref(),
+            Some("fdo_ffi_ownershipvoucher_to_pem: v is null")
+        );
+
+        // The failed extensions left the voucher alone
+        assert_eq!(unsafe { &*v }.num_entries(), 0);
+
+        fdo_ffi_ownershipvoucher_free(null_mut());
+        assert_eq!(last_error(), None);
+        fdo_ffi_ownershipvoucher_free(v);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let v = new_voucher(&Party::new("manufacturer"));
+        let expected = unsafe { &*v }.serialize_data().unwrap();
+
+        // Raw data, which is freed with fdo_ffi_free_data
+        let mut len = 0;
+        let data = fdo_ffi_ownershipvoucher_to_data(v, &mut len);
+        assert!(!data.is_null());
+        assert_eq!(unsafe { slice::from_raw_parts(data, len) }, &expected[..]);
+        let parsed = fdo_ffi_ownershipvoucher_from_data(data as *const c_void, len);
+        unsafe { fdo_ffi_free_data(data, len) };
+        assert!(!parsed.is_null());
+        assert_eq!(unsafe { &*parsed }.serialize_data().unwrap(), expected);
+        fdo_ffi_ownershipvoucher_free(parsed);
+
+        // PEM, which is freed with fdo_ffi_free_string
+        let pem = fdo_ffi_ownershipvoucher_to_pem(v);
+        assert!(!pem.is_null());
+        let pem_bytes = unsafe { CStr::from_ptr(pem) }.to_bytes().to_vec();
+        unsafe { fdo_ffi_free_string(pem) };
+        for parse in [
+            fdo_ffi_ownershipvoucher_from_pem,
+            fdo_ffi_ownershipvoucher_from_data,
+        ] {
+            let parsed = parse(as_void(&pem_bytes), pem_bytes.len());
+            assert!(!parsed.is_null());
+            assert_eq!(unsafe { &*parsed }.serialize_data().unwrap(), expected);
+            fdo_ffi_ownershipvoucher_free(parsed);
+        }
+
+        fdo_ffi_ownershipvoucher_free(v);
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let garbage = b"not a voucher";
+        assert!(fdo_ffi_ownershipvoucher_from_data(as_void(garbage), garbage.len()).is_null());
+        assert!(last_error().is_some());
+        assert!(fdo_ffi_ownershipvoucher_from_pem(as_void(garbage), garbage.len()).is_null());
+        assert!(last_error().is_some());
+    }
+
+    #[test]
+    fn test_validate() {
+        let manufacturer = Party::new("manufacturer");
+        let other = Party::new("other");
+        let v = new_voucher(&manufacturer);
+
+        assert_eq!(validate(v, None), 0);
+        assert_eq!(validate(v, Some(&manufacturer.cert_pem())), 0);
+        let mut trusted = other.cert_pem();
+        trusted.extend(manufacturer.cert_pem());
+        assert_eq!(validate(v, Some(&trusted)), 0);
+
+        assert_eq!(validate(v, Some(&other.cert_pem())), -1);
+        assert_eq!(
+            last_error().as_deref(),
+            Some("Manufacturer public key is not trusted")
+        );
+        assert_eq!(validate(v, Some(b"not a certificate")), -1);
+        assert!(last_error()
+            .unwrap()
+            .starts_with("Error parsing trusted manufacturer certs"));
+
+        fdo_ffi_ownershipvoucher_free(v);
+    }
+
+    #[test]
+    fn test_extend() {
+        let manufacturer = Party::new("manufacturer");
+        let owner = Party::new("owner");
+        let next_owner = Party::new("next owner");
+        let v = new_voucher(&manufacturer);
+
+        assert_eq!(extend(v, &manufacturer.key_pem(), &owner.cert_pem()), 0);
+        assert_eq!(extend(v, &owner.key_pem(), &next_owner.cert_pem()), 0);
+        assert_eq!(unsafe { &*v }.num_entries(), 2);
+        assert_eq!(validate(v, Some(&manufacturer.cert_pem())), 0);
+
+        // Only the current owner can extend the voucher
+        assert_eq!(extend(v, &owner.key_pem(), &owner.cert_pem()), -1);
+        assert!(last_error().is_some());
+        assert_eq!(extend(v, b"not a key", &owner.cert_pem()), -1);
+        assert!(last_error()
+            .unwrap()
+            .starts_with("Error parsing owner private key"));
+        assert_eq!(extend(v, &next_owner.key_pem(), b"not a certificate"), -1);
+        assert!(last_error()
+            .unwrap()
+            .starts_with("Error parsing new owner certificate"));
+        assert_eq!(unsafe { &*v }.num_entries(), 2);
+
+        fdo_ffi_ownershipvoucher_free(v);
+    }
+}
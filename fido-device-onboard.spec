@@ -214,6 +214,8 @@ Requires: cryptsetup
 %license LICENSE LICENSE.dependencies
 %{_libexecdir}/fdo/fdo-client-linuxapp
 %{_unitdir}/fdo-client-linuxapp.service
+%{_unitdir}/fdo-client-linuxapp.timer
+%{_unitdir}/fdo-client-linuxapp-firstboot.service
 
 %post -n fdo-client
 %systemd_post fdo-client-linuxapp.service
@@ -1,4 +1,10 @@
-use std::{convert::TryFrom, str::FromStr};
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -10,6 +16,7 @@ use fdo_data_formats::{
     ProtocolVersion, Serializable,
 };
 
+use crate::telemetry::MessageSpan;
 use crate::EncryptionKeys;
 
 #[derive(Debug, Error)]
@@ -29,7 +36,7 @@ pub enum Error {
     InvalidMessageType(String),
     #[error("Invalid message type {0:?} encountered, expected {1:?}")]
     InvalidMessage(MessageType, MessageType),
-    #[error("Error returned by server: {0:?}")]
+    #[error("Error returned by server: {0}")]
     Error(ErrorMessage),
     #[error("Request message encryption requirement not met: {0:?}")]
     RequestEncryptionNotSatisfied(EncryptionRequirement),
@@ -43,6 +50,26 @@ pub enum Error {
     UrlParseError(#[from] url::ParseError),
     #[error("The URL {0:?} is not valid: {1:?}")]
     InvalidUrl(String, &'static str),
+    #[error("Error reading certificate: {0}")]
+    CertificateRead(#[from] std::io::Error),
+    #[error("Error connecting to Unix socket: {0}")]
+    UnixSocketConnect(std::io::Error),
+    #[error("Error performing request over Unix socket: {0}")]
+    UnixSocketRequest(#[from] hyper::Error),
+    #[error("Request failed with status {0}")]
+    HttpStatus(u16),
+    #[error("Error parsing JSON response: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// The error message returned by the server, if the server rejected the request
+    pub fn error_message(&self) -> Option<&ErrorMessage> {
+        match self {
+            Error::Error(message) => Some(message),
+            _ => None,
+        }
+    }
 }
 
 pub type RequestResult<MT> = Result<MT, Error>;
@@ -57,6 +84,10 @@ pub enum JsonAuthentication {
         client_certificate: Vec<u8>,
         password: String,
     },
+    ClientCertificatePath {
+        client_certificate_path: std::path::PathBuf,
+        password: String,
+    },
 }
 
 #[derive(Debug)]
@@ -64,23 +95,49 @@ pub struct JsonClient {
     base_url: reqwest::Url,
     client: reqwest::Client,
     authentication: JsonAuthentication,
+    unix_socket: Option<PathBuf>,
 }
 
 impl JsonClient {
     pub fn new(base_url: String, authentication: JsonAuthentication) -> RequestResult<Self> {
+        Self::new_with_root_certificate(base_url, authentication, None)
+    }
+
+    /// Creates a client that also trusts the given PEM CA certificate for the server
+    pub fn new_with_root_certificate(
+        base_url: String,
+        authentication: JsonAuthentication,
+        root_certificate: Option<&[u8]>,
+    ) -> RequestResult<Self> {
         let mut client_builder = reqwest::Client::builder();
 
-        let authentication = if let JsonAuthentication::ClientCertificate {
-            client_certificate,
-            password,
-        } = authentication
-        {
-            let identity = reqwest::tls::Identity::from_pkcs12_der(&client_certificate, &password)?;
-            client_builder = client_builder.identity(identity);
-            // We no longer need to keep track of this
-            JsonAuthentication::None
-        } else {
-            authentication
+        if let Some(root_certificate) = root_certificate {
+            client_builder = client_builder
+                .add_root_certificate(reqwest::Certificate::from_pem(root_certificate)?);
+        }
+
+        let authentication = match authentication {
+            JsonAuthentication::ClientCertificate {
+                client_certificate,
+                password,
+            } => {
+                let identity =
+                    reqwest::tls::Identity::from_pkcs12_der(&client_certificate, &password)?;
+                client_builder = client_builder.identity(identity);
+                // We no longer need to keep track of this
+                JsonAuthentication::None
+            }
+            JsonAuthentication::ClientCertificatePath {
+                client_certificate_path,
+                password,
+            } => {
+                let client_certificate = std::fs::read(client_certificate_path)?;
+                let identity =
+                    reqwest::tls::Identity::from_pkcs12_der(&client_certificate, &password)?;
+                client_builder = client_builder.identity(identity);
+                JsonAuthentication::None
+            }
+            authentication => authentication,
         };
 
         let base_url = reqwest::Url::parse(&base_url)?;
@@ -95,9 +152,17 @@ impl JsonClient {
             base_url,
             client: client_builder.build()?,
             authentication,
+            unix_socket: None,
         })
     }
 
+    /// Sends the requests over the Unix socket at `path` instead of connecting to the
+    /// host of the base URL. TLS and client certificates are not used on the socket.
+    pub fn with_unix_socket(mut self, path: PathBuf) -> Self {
+        self.unix_socket = Some(path);
+        self
+    }
+
     pub async fn send_get<'a, QT, OT>(&self, query: QT) -> RequestResult<OT>
     where
         QT: IntoIterator<Item = (&'a str, &'a str)>,
@@ -105,26 +170,53 @@ impl JsonClient {
     {
         let mut url = self.base_url.clone();
 
-        let query_str = query
-            .into_iter()
-            .map(|(k, v)| format!("{k}={v}"))
-            .collect::<Vec<_>>()
-            .join("&");
-
-        url.set_query(Some(&query_str));
+        // Values may contain arbitrary strings, so let the URL encode them
+        url.query_pairs_mut().extend_pairs(query);
 
-        let request_builder = self.client.request(reqwest::Method::GET, url);
-
-        let request_builder = match &self.authentication {
-            JsonAuthentication::None => request_builder,
-            JsonAuthentication::BearerToken { token } => {
-                request_builder.header("Authorization", format!("Bearer {token}"))
-            }
-            JsonAuthentication::ClientCertificate { .. } => {
+        let authorization = match &self.authentication {
+            JsonAuthentication::None => None,
+            JsonAuthentication::BearerToken { token } => Some(format!("Bearer {token}")),
+            JsonAuthentication::ClientCertificate { .. }
+            | JsonAuthentication::ClientCertificatePath { .. } => {
                 unreachable!("Should not be possible to get here")
             }
         };
 
+        let span = MessageSpan::client(format!("GET {}", url.path()), None);
+        let mut trace_headers = http::HeaderMap::new();
+        span.inject(&mut trace_headers);
+
+        let resp = self.get(url, authorization, trace_headers).await;
+        if let Err(e) = &resp {
+            span.set_error(e.to_string());
+        }
+        resp
+    }
+
+    async fn get<OT>(
+        &self,
+        url: reqwest::Url,
+        authorization: Option<String>,
+        trace_headers: http::HeaderMap,
+    ) -> RequestResult<OT>
+    where
+        OT: serde::de::DeserializeOwned,
+    {
+        if let Some(socket_path) = &self.unix_socket {
+            let resp =
+                unix_socket_get(socket_path, &url, authorization.as_deref(), trace_headers).await?;
+            return Ok(serde_json::from_slice(&resp)?);
+        }
+
+        let request_builder = self
+            .client
+            .request(reqwest::Method::GET, url)
+            .headers(trace_headers);
+        let request_builder = match authorization {
+            None => request_builder,
+            Some(authorization) => request_builder.header("Authorization", authorization),
+        };
+
         let request = request_builder.build()?;
 
         log::trace!("Sending JSON API request: {:?}", request);
@@ -137,30 +229,122 @@ impl JsonClient {
     }
 }
 
+/// Sends a GET request for `url` over the Unix socket at `socket_path`, returning the body.
+async fn unix_socket_get(
+    socket_path: &Path,
+    url: &reqwest::Url,
+    authorization: Option<&str>,
+    trace_headers: http::HeaderMap,
+) -> RequestResult<hyper::body::Bytes> {
+    let stream = tokio::net::UnixStream::connect(socket_path)
+        .await
+        .map_err(Error::UnixSocketConnect)?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::debug!("Error on Unix socket connection: {:?}", e);
+        }
+    });
+
+    let path_and_query = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let mut request =
+        hyper::Request::get(path_and_query).header("Host", url.host_str().unwrap_or("localhost"));
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    if let Some(headers) = request.headers_mut() {
+        headers.extend(trace_headers);
+    }
+    let request = request
+        .body(hyper::Body::empty())
+        .map_err(|_| Error::InvalidUrl(url.to_string(), "URL is not a valid request target"))?;
+
+    log::trace!(
+        "Sending JSON API request over {:?}: {:?}",
+        socket_path,
+        request
+    );
+
+    let resp = sender.send_request(request).await?;
+
+    log::trace!("Received JSON API response: {:?}", resp);
+
+    if !resp.status().is_success() {
+        return Err(Error::HttpStatus(resp.status().as_u16()));
+    }
+    Ok(hyper::body::to_bytes(resp.into_body()).await?)
+}
+
+// Idle connections are kept this long, to be reused by the next message or session
+const POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+
+/// HTTP client shared by all service clients, so that connections (and their TLS
+/// handshakes) are reused across the messages of a session and across sessions.
+fn shared_client() -> reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            reqwest::Client::builder()
+                .pool_idle_timeout(Duration::from_secs(POOL_IDLE_TIMEOUT_SECS))
+                .tcp_keepalive(Duration::from_secs(POOL_IDLE_TIMEOUT_SECS))
+                .build()
+                .unwrap_or_else(|e| {
+                    log::warn!("Error building shared HTTP client, using defaults: {:?}", e);
+                    reqwest::Client::new()
+                })
+        })
+        .clone()
+}
+
 #[derive(Debug)]
 pub struct ServiceClient {
     protocol_version: ProtocolVersion,
+    // Whether older protocol versions are tried if the server doesn't support the current one
+    negotiate_version: bool,
     base_url: String,
     client: reqwest::Client,
     authorization_token: Option<String>,
+    session_affinity: Option<String>,
     encryption_keys: EncryptionKeys,
     last_message_type: Option<MessageType>,
     non_interoperable_kdf_required: Option<bool>,
+    // Parent of the spans of the messages, so that a session is a single trace
+    span: MessageSpan,
 }
 
 impl ServiceClient {
     pub fn new(protocol_version: ProtocolVersion, base_url: &str) -> Self {
+        let span = MessageSpan::client("FDO session".to_string(), None);
+        span.set_attribute("server.address", base_url.to_string());
         ServiceClient {
             protocol_version,
+            negotiate_version: false,
             base_url: base_url.trim_end_matches('/').to_string(),
-            client: reqwest::Client::new(),
+            client: shared_client(),
             authorization_token: None,
+            session_affinity: None,
             encryption_keys: EncryptionKeys::unencrypted(),
             last_message_type: None,
             non_interoperable_kdf_required: None,
+            span,
         }
     }
 
+    /// Falls back to older protocol versions if the server doesn't support the current one.
+    ///
+    /// The version is picked on the first message, and used for the rest of the session.
+    pub fn with_version_negotiation(mut self) -> Self {
+        self.negotiate_version = true;
+        self
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
     pub fn non_interoperable_kdf_required(&self) -> Option<bool> {
         self.non_interoperable_kdf_required
     }
@@ -170,6 +354,26 @@ impl ServiceClient {
         to_send: OM,
         new_keys: Option<EncryptionKeys>,
     ) -> RequestResult<SM>
+    where
+        OM: Message + ClientMessage,
+        SM: Message + ServerMessage,
+    {
+        let span = MessageSpan::client(format!("{:?}", OM::message_type()), Some(&self.span));
+        span.set_attribute("fdo.message_type", (OM::message_type() as u8).to_string());
+
+        let resp = self.send_message(&span, to_send, new_keys).await;
+        if let Err(e) = &resp {
+            span.set_error(e.to_string());
+        }
+        resp
+    }
+
+    async fn send_message<OM, SM>(
+        &mut self,
+        span: &MessageSpan,
+        to_send: OM,
+        new_keys: Option<EncryptionKeys>,
+    ) -> RequestResult<SM>
     where
         OM: Message + ClientMessage,
         SM: Message + ServerMessage,
@@ -208,37 +412,76 @@ impl ServiceClient {
             }
         }
 
-        let to_send = to_send.serialize_data()?;
-        let to_send = self.encryption_keys.encrypt(&to_send)?;
-        log::trace!("Sending message: {:?}", hex::encode(&to_send));
+        // Only the first message of a session may pick the protocol version
+        let mut versions: Vec<ProtocolVersion> =
+            if self.negotiate_version && self.authorization_token.is_none() {
+                ProtocolVersion::SUPPORTED
+                    .iter()
+                    .copied()
+                    .filter(|version| {
+                        *version <= self.protocol_version
+                            && OM::supports_protocol_version(*version)
+                            && SM::supports_protocol_version(*version)
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+        if versions.is_empty() {
+            versions.push(self.protocol_version);
+        }
 
-        let url = format!(
-            "{}/fdo/{}/msg/{}",
-            &self.base_url,
-            self.protocol_version,
-            OM::message_type() as u8
-        );
+        let resp = loop {
+            let protocol_version = versions.remove(0);
+            let data = to_send.serialize_for_version(protocol_version)?;
+            let data = self.encryption_keys.encrypt(&data)?;
+            log::trace!("Sending message: {:?}", hex::encode(&data));
+
+            let url = format!(
+                "{}/fdo/{}/msg/{}",
+                &self.base_url,
+                protocol_version,
+                OM::message_type() as u8
+            );
 
-        let mut req = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/cbor")
-            .body(to_send);
+            let mut req = self
+                .client
+                .post(&url)
+                .header("Content-Type", "application/cbor")
+                .body(data);
 
-        if let Some(authorization_token) = &self.authorization_token {
-            req = req.header("Authorization", authorization_token);
-        }
+            if let Some(authorization_token) = &self.authorization_token {
+                req = req.header("Authorization", authorization_token);
+            }
+            if let Some(session_affinity) = &self.session_affinity {
+                req = req.header(crate::SESSION_AFFINITY_HEADER, session_affinity);
+            }
 
-        if !fdo_data_formats::interoperable_kdf_available() {
-            req = req.header("X-Non-Interoperable-KDF", "true");
-        }
+            if !fdo_data_formats::interoperable_kdf_available() {
+                req = req.header("X-Non-Interoperable-KDF", "true");
+            }
+
+            let mut trace_headers = http::HeaderMap::new();
+            span.inject(&mut trace_headers);
+            req = req.headers(trace_headers);
+
+            let resp = req.send().await?;
+            if resp.status() == reqwest::StatusCode::NOT_FOUND && !versions.is_empty() {
+                log::debug!(
+                    "Server does not support protocol version {}, trying {}",
+                    protocol_version,
+                    versions[0]
+                );
+                continue;
+            }
+            self.protocol_version = protocol_version;
+            break resp;
+        };
 
         if let Some(new_keys) = new_keys {
             self.encryption_keys = new_keys;
         }
 
-        let resp = req.send().await?;
-
         if self.non_interoperable_kdf_required.is_none() {
             self.non_interoperable_kdf_required = Some(
                 resp.headers()
@@ -279,6 +522,11 @@ impl ServiceClient {
         if let Some(val) = resp.headers().get("authorization") {
             self.authorization_token = Some(val.to_str().unwrap().to_string());
         }
+        if let Some(val) = resp.headers().get(crate::SESSION_AFFINITY_HEADER) {
+            if let Ok(val) = val.to_str() {
+                self.session_affinity = Some(val.to_string());
+            }
+        }
 
         let is_success = if resp.status().is_success() {
             if msgtype != SM::message_type() {
@@ -297,7 +545,7 @@ impl ServiceClient {
 
         if is_success {
             let resp = self.encryption_keys.decrypt(&resp)?;
-            Ok(SM::deserialize_data(&resp)?)
+            Ok(SM::deserialize_for_version(self.protocol_version, &resp)?)
         } else {
             Err(Error::Error(ErrorMessage::deserialize_data(&resp)?))
         }
@@ -1,21 +1,76 @@
+use std::borrow::Cow;
+use std::io::Write;
+
 use serde::{Deserialize, Serialize};
 
 use aws_nitro_enclaves_cose::error::CoseError;
 use aws_nitro_enclaves_cose::{CipherConfiguration, CoseEncrypt0};
 use fdo_data_formats::types::{CipherSuite, DerivedKeys};
 
+pub mod log_context;
+pub mod telemetry;
+
 #[cfg(feature = "server")]
 pub mod server;
 
 #[cfg(feature = "client")]
 pub mod client;
 
+/// Header carrying the token of the server instance handling a session, sent back by
+/// clients so that load balancers can keep the session on that instance
+pub const SESSION_AFFINITY_HEADER: &str = "X-FDO-Session-Affinity";
+
 pub fn init_logging() {
     let filter = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
-    pretty_env_logger::formatted_timed_builder()
+    let json = matches!(std::env::var("LOG_FORMAT").as_deref(), Ok("json"));
+
+    let mut builder = pretty_env_logger::formatted_timed_builder();
+    builder
         .filter_level(log::LevelFilter::Info)
-        .parse_filters(&filter)
-        .init();
+        .parse_filters(&filter);
+
+    if json {
+        builder.format(|buf, record| {
+            let mut entry = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            log_context::with_current(|context| {
+                if let Some(context) = context {
+                    entry["session_id"] = context.session_id.clone().into();
+                    if let Some(device_guid) = &context.device_guid {
+                        entry["device_guid"] = device_guid.clone().into();
+                    }
+                }
+            });
+            writeln!(buf, "{entry}")
+        });
+    } else {
+        builder.format(|buf, record| {
+            let context = log_context::with_current(|context| match context {
+                None => String::new(),
+                Some(context) => match &context.device_guid {
+                    None => format!("[session {}] ", context.session_id),
+                    Some(guid) => format!("[session {} device {}] ", context.session_id, guid),
+                },
+            });
+            writeln!(
+                buf,
+                " {} {} {} > {}{}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target(),
+                context,
+                record.args()
+            )
+        });
+    }
+
+    builder.init();
+
+    telemetry::init();
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,33 +104,47 @@ impl EncryptionKeys {
 
     #[allow(clippy::panic)]
     fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CoseError> {
-        if self.cipher_suite.is_none() {
-            Ok(plaintext.to_vec())
-        } else {
-            let k = match &self.keys {
-                Some(DerivedKeys::Combined { sevk: k }) => k,
-                _ => panic!(),
-            };
-            CoseEncrypt0::new(plaintext, CipherConfiguration::Gcm, &k[..])
-                .map(|c| c.as_bytes(true))?
+        match &self.cipher_suite {
+            None => Ok(plaintext.to_vec()),
+            Some(cipher_suite) => {
+                let k = match &self.keys {
+                    Some(DerivedKeys::Combined { sevk: k }) => k,
+                    _ => panic!(),
+                };
+                if cipher_suite.is_ccm() {
+                    return cipher_suite
+                        .ccm_encrypt0(k, plaintext)
+                        .map_err(|e| CoseError::SpecificationError(e.to_string()));
+                }
+                CoseEncrypt0::new(plaintext, CipherConfiguration::Gcm, &k[..])
+                    .map(|c| c.as_bytes(true))?
+            }
         }
     }
 
+    // Unencrypted messages are parsed straight from the received buffer
     #[allow(clippy::panic)]
-    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CoseError> {
-        if self.cipher_suite.is_none() {
-            Ok(ciphertext.to_vec())
-        } else {
-            let k = match &self.keys {
-                Some(DerivedKeys::Combined { sevk: k }) => k,
-                _ => panic!(),
-            };
-            match CoseEncrypt0::from_bytes(ciphertext) {
-                Ok(v) => match v.decrypt(k) {
-                    Ok((_, _, payload)) => Ok(payload),
+    fn decrypt<'a>(&self, ciphertext: &'a [u8]) -> Result<Cow<'a, [u8]>, CoseError> {
+        match &self.cipher_suite {
+            None => Ok(Cow::Borrowed(ciphertext)),
+            Some(cipher_suite) => {
+                let k = match &self.keys {
+                    Some(DerivedKeys::Combined { sevk: k }) => k,
+                    _ => panic!(),
+                };
+                if cipher_suite.is_ccm() {
+                    return cipher_suite
+                        .ccm_decrypt0(k, ciphertext)
+                        .map(Cow::Owned)
+                        .map_err(|e| CoseError::SpecificationError(e.to_string()));
+                }
+                match CoseEncrypt0::from_bytes(ciphertext) {
+                    Ok(v) => match v.decrypt(&k[..]) {
+                        Ok((_, _, payload)) => Ok(Cow::Owned(payload)),
+                        Err(e) => Err(e),
+                    },
                     Err(e) => Err(e),
-                },
-                Err(e) => Err(e),
+                }
             }
         }
     }
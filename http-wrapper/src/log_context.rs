@@ -0,0 +1,46 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Information added to every log line emitted while handling a request
+#[derive(Debug, Clone)]
+pub struct LogContext {
+    pub session_id: String,
+    pub device_guid: Option<String>,
+}
+
+thread_local! {
+    static CURRENT_CONTEXT: RefCell<Option<Arc<LogContext>>> = RefCell::new(None);
+}
+
+/// Calls `f` with the context of the future that is currently being polled on this thread
+pub fn with_current<R>(f: impl FnOnce(Option<&LogContext>) -> R) -> R {
+    CURRENT_CONTEXT.with(|current| f(current.borrow().as_deref()))
+}
+
+/// A future that has a LogContext set for the duration of each poll
+pub struct WithLogContext<F> {
+    context: Arc<LogContext>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithLogContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let previous = CURRENT_CONTEXT.with(|current| current.replace(Some(this.context.clone())));
+        let result = this.inner.as_mut().poll(cx);
+        CURRENT_CONTEXT.with(|current| *current.borrow_mut() = previous);
+        result
+    }
+}
+
+pub fn with_log_context<F: Future>(context: LogContext, inner: F) -> WithLogContext<F> {
+    WithLogContext {
+        context: Arc::new(context),
+        inner: Box::pin(inner),
+    }
+}
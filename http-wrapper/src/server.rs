@@ -1,18 +1,30 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
+use super::log_context::{with_log_context, LogContext};
+use super::telemetry::MessageSpan;
 use super::EncryptionKeys;
 use fdo_data_formats::{
     constants::{ErrorCode, HashType, MessageType},
     messages::{
         self, v11::ErrorMessage, ClientMessage, EncryptionRequirement, Message, ServerMessage,
     },
-    types::Hash,
-    ProtocolVersion,
+    secret::Secret,
+    types::{Hash, Nonce},
+    ProtocolVersion, Serializable,
 };
 use fdo_store::{MetadataLocalKey, Store};
 
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use warp::{Filter, Rejection};
 pub use warp_sessions::Session;
 
@@ -39,33 +51,202 @@ impl MetadataLocalKey for SessionStoreMetadataKey {
 }
 
 pub struct SessionStore {
-    store: Box<dyn Store<fdo_store::ReadWriteOpen, String, Session, SessionStoreMetadataKey>>,
+    store: Box<dyn Store<fdo_store::ReadWriteOpen, String, StoredSession, SessionStoreMetadataKey>>,
+    encryption_key: Option<SessionEncryptionKey>,
+
+    // Once set, only messages of sessions that already started are served
+    draining: AtomicBool,
+    // Sessions served by this process that didn't finish yet, with the time of their last message
+    active_sessions: Mutex<HashMap<String, Instant>>,
 }
 
 impl SessionStore {
     pub fn new(
-        store: Box<dyn Store<fdo_store::ReadWriteOpen, String, Session, SessionStoreMetadataKey>>,
+        store: Box<
+            dyn Store<fdo_store::ReadWriteOpen, String, StoredSession, SessionStoreMetadataKey>,
+        >,
+        encryption_key: Option<SessionEncryptionKey>,
     ) -> Arc<Self> {
-        Arc::new(SessionStore { store })
+        Arc::new(SessionStore {
+            store,
+            encryption_key,
+            draining: AtomicBool::new(false),
+            active_sessions: Mutex::new(HashMap::new()),
+        })
     }
 }
 
 const SESSION_TTL_SECS: u64 = 600;
+// Sessions without a message for this long are not waited for when draining
+const DRAIN_IDLE_SESSION_SECS: u64 = 60;
+const DRAIN_POLL_INTERVAL_MSECS: u64 = 500;
+// Interval of the TCP keep-alive probes on idle client connections
+const TCP_KEEPALIVE_SECS: u64 = 60;
+
+const SESSION_ENCRYPTION_KEY_LEN: usize = 32;
+const SESSION_ENCRYPTION_IV_LEN: usize = 12;
+const SESSION_ENCRYPTION_TAG_LEN: usize = 16;
+
+/// AES-256-GCM key used to encrypt sessions before they are persisted
+#[derive(Clone)]
+pub struct SessionEncryptionKey(Secret);
+
+impl std::fmt::Debug for SessionEncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionEncryptionKey")
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionEncryptionKey {
+    pub fn from_bytes(key: &[u8]) -> Result<Self, SessionError> {
+        if key.len() != SESSION_ENCRYPTION_KEY_LEN {
+            return Err(SessionError::Unspecified(format!(
+                "Session encryption key must be {SESSION_ENCRYPTION_KEY_LEN} bytes, got {}",
+                key.len()
+            )));
+        }
+        Ok(SessionEncryptionKey(key.into()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SessionError> {
+        let contents = std::fs::read(path).map_err(|e| {
+            SessionError::Unspecified(format!(
+                "Error reading session encryption key from {}: {e}",
+                path.display()
+            ))
+        })?;
+        Self::from_bytes(&contents)
+    }
+
+    fn encrypt(&self, id: &str, session: &Session) -> Result<StoredSession, SessionError> {
+        let plaintext = session
+            .serialize_data()
+            .map_err(|e| SessionError::Unspecified(format!("Error serializing session: {e}")))?;
+        let mut iv = [0u8; SESSION_ENCRYPTION_IV_LEN];
+        openssl::rand::rand_bytes(&mut iv)?;
+        let mut tag = [0u8; SESSION_ENCRYPTION_TAG_LEN];
+        // The session ID is used as AAD, so that blobs can't be swapped between sessions
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(&iv),
+            id.as_bytes(),
+            &plaintext,
+            &mut tag,
+        )?;
+        Ok(StoredSession::Encrypted {
+            iv: ByteBuf::from(iv.to_vec()),
+            ciphertext: ByteBuf::from(ciphertext),
+            tag: ByteBuf::from(tag.to_vec()),
+        })
+    }
+
+    fn decrypt(
+        &self,
+        id: &str,
+        iv: &[u8],
+        ciphertext: &[u8],
+        tag: &[u8],
+    ) -> Result<Session, SessionError> {
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(iv),
+            id.as_bytes(),
+            ciphertext,
+            tag,
+        )?;
+        Session::deserialize_data(&plaintext)
+            .map_err(|e| SessionError::Unspecified(format!("Error deserializing session: {e}")))
+    }
+}
+
+/// A session as persisted in the session store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredSessionFormat")]
+pub enum StoredSession {
+    Plain(Session),
+    Encrypted {
+        iv: ByteBuf,
+        ciphertext: ByteBuf,
+        tag: ByteBuf,
+    },
+}
+
+// Sessions persisted before sessions could be encrypted are bare sessions
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredSessionFormat {
+    Current(CurrentStoredSession),
+    Legacy(Session),
+}
+
+#[derive(Deserialize)]
+enum CurrentStoredSession {
+    Plain(Session),
+    Encrypted {
+        iv: ByteBuf,
+        ciphertext: ByteBuf,
+        tag: ByteBuf,
+    },
+}
+
+impl From<StoredSessionFormat> for StoredSession {
+    fn from(format: StoredSessionFormat) -> Self {
+        match format {
+            StoredSessionFormat::Current(CurrentStoredSession::Plain(session))
+            | StoredSessionFormat::Legacy(session) => StoredSession::Plain(session),
+            StoredSessionFormat::Current(CurrentStoredSession::Encrypted {
+                iv,
+                ciphertext,
+                tag,
+            }) => StoredSession::Encrypted {
+                iv,
+                ciphertext,
+                tag,
+            },
+        }
+    }
+}
 
 impl SessionStore {
     async fn load_session(&self, token: String) -> Result<Option<Session>, SessionError> {
         let id = Session::id_from_cookie_value(&token)
             .map_err(|_| SessionError::Unspecified("Invalid cookie token".to_string()))?;
-        Ok(self.store.load_data(&id).await?.and_then(Session::validate))
+        let session = match (self.store.load_data(&id).await?, &self.encryption_key) {
+            (None, _) => return Ok(None),
+            (Some(StoredSession::Plain(session)), None) => session,
+            (
+                Some(StoredSession::Encrypted {
+                    iv,
+                    ciphertext,
+                    tag,
+                }),
+                Some(key),
+            ) => key.decrypt(&id, &iv, &ciphertext, &tag)?,
+            (Some(StoredSession::Plain(_)), Some(_)) => {
+                log::warn!("Ignoring unencrypted session while session encryption is enabled");
+                return Ok(None);
+            }
+            (Some(StoredSession::Encrypted { .. }), None) => {
+                log::warn!("Ignoring encrypted session while session encryption is disabled");
+                return Ok(None);
+            }
+        };
+        Ok(Session::validate(session))
     }
 
     async fn store_session(&self, session: Session) -> Result<Option<String>, SessionError> {
-        self.store
-            .store_data(session.id().to_string(), session.clone())
-            .await?;
+        let id = session.id().to_string();
+        let stored = match &self.encryption_key {
+            Some(key) => key.encrypt(&id, &session)?,
+            None => StoredSession::Plain(session.clone()),
+        };
+        self.store.store_data(id.clone(), stored).await?;
         self.store
             .store_metadata(
-                &session.id().to_string(),
+                &id,
                 &fdo_store::MetadataKey::Ttl,
                 &time::Duration::new(SESSION_TTL_SECS as i64, 0),
             )
@@ -81,16 +262,97 @@ impl SessionStore {
     }
 
     pub async fn perform_maintenance(&self) -> Result<(), SessionError> {
-        Ok(self.store.perform_maintenance().await?)
+        // Sessions that ended with an error are never marked as finished
+        if let Ok(mut active_sessions) = self.active_sessions.lock() {
+            active_sessions.retain(|_, last_message| {
+                last_message.elapsed() < Duration::from_secs(SESSION_TTL_SECS)
+            });
+        }
+        self.store.perform_maintenance().await?;
+        Ok(())
+    }
+
+    /// Stops starting new sessions, and waits until the sessions in progress are done, for at
+    /// most `timeout`.
+    ///
+    /// Sessions that are idle for a minute are not waited for. As every session is stored
+    /// after each message, those can still be resumed by another server sharing the session
+    /// store.
+    pub async fn drain(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        loop {
+            let active = self.num_active_sessions();
+            if active == 0 {
+                log::info!("No sessions in progress");
+                return;
+            }
+            if started.elapsed() >= timeout {
+                log::warn!(
+                    "Timed out waiting for sessions, {} sessions still in progress",
+                    active
+                );
+                return;
+            }
+            log::info!("Waiting for {} sessions in progress to finish", active);
+            tokio::time::sleep(Duration::from_millis(DRAIN_POLL_INTERVAL_MSECS)).await;
+        }
+    }
+
+    fn num_active_sessions(&self) -> usize {
+        match self.active_sessions.lock() {
+            Ok(active_sessions) => active_sessions
+                .values()
+                .filter(|last_message| {
+                    last_message.elapsed() < Duration::from_secs(DRAIN_IDLE_SESSION_SECS)
+                })
+                .count(),
+            Err(_) => 0,
+        }
+    }
+
+    fn new_session<IM: Message>(&self) -> Result<Session, Rejection> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(Error::new(
+                ErrorCode::InternalServerError,
+                IM::message_type(),
+                "Server is shutting down",
+            )
+            .into());
+        }
+        Ok(Session::new())
+    }
+
+    fn track_session(&self, session_id: String, finished: bool) {
+        if let Ok(mut active_sessions) = self.active_sessions.lock() {
+            if finished {
+                active_sessions.remove(&session_id);
+            } else {
+                active_sessions.insert(session_id, Instant::now());
+            }
+        }
     }
 }
 
+// Last message of a session of each of the protocols
+fn is_final_message(message_type: MessageType) -> bool {
+    matches!(
+        message_type,
+        MessageType::DIDone
+            | MessageType::TO0AcceptOwner
+            | MessageType::TO1RVRedirect
+            | MessageType::TO2Done2
+    )
+}
+
 #[derive(Debug, Error)]
 pub enum SessionError {
     #[error("Unspecified error: {0}")]
     Unspecified(String),
     #[error("Store error")]
     Store(#[from] fdo_store::StoreError),
+    #[error("Cryptographic error")]
+    Crypto(#[from] openssl::error::ErrorStack),
 }
 
 #[derive(Debug)]
@@ -132,6 +394,39 @@ impl Error {
 
 impl warp::reject::Reject for Error {}
 
+/// Checks a nonce received from the peer against the one stored in the session
+/// under `key`, removing it from the session so that a replayed message is refused.
+pub fn consume_session_nonce<M>(
+    session: &mut Session,
+    key: &str,
+    received: &Nonce,
+) -> Result<(), Error>
+where
+    M: Message,
+{
+    let expected: Nonce = match session.get(key) {
+        Some(v) => v,
+        None => {
+            log::warn!("No {} in session, message out of sequence or replayed", key);
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                M::message_type(),
+                "Request sequence failure",
+            ));
+        }
+    };
+    session.remove(key);
+
+    if &expected != received {
+        return Err(Error::new(
+            ErrorCode::InvalidMessageError,
+            M::message_type(),
+            "Nonce invalid",
+        ));
+    }
+    Ok(())
+}
+
 pub async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Infallible> {
     let local_err: Error;
 
@@ -153,6 +448,25 @@ pub async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, I
             "Invalid request type",
         );
         &local_err
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some()
+        || err.find::<warp::reject::LengthRequired>().is_some()
+    {
+        local_err = Error::new(
+            ErrorCode::MessageBodyError,
+            MessageType::Invalid,
+            "Invalid request body size",
+        );
+        &local_err
+    } else if err.find::<warp::reject::MissingHeader>().is_some()
+        || err.find::<warp::reject::InvalidHeader>().is_some()
+        || err.find::<warp::reject::MethodNotAllowed>().is_some()
+    {
+        local_err = Error::new(
+            ErrorCode::MessageBodyError,
+            MessageType::Invalid,
+            "Invalid request",
+        );
+        &local_err
     } else {
         local_err = Error::new(
             ErrorCode::InternalServerError,
@@ -162,6 +476,7 @@ pub async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, I
         &local_err
     };
 
+    log::info!("Responding with error message: {}", err.0);
     Ok(to_response::<ErrorMessage>(err.0.to_response(), None))
 }
 
@@ -171,14 +486,43 @@ impl warp::reject::Reject for ParseError {}
 
 const ENCRYPTION_KEYS_SES_KEY: &str = "_encryption_keys_";
 const LAST_MSG_SES_KEY: &str = "_last_message_type_";
+const PROTOCOL_VERSION_SES_KEY: &str = "_protocol_version_";
 
 async fn parse_request<IM>(
     inbound: warp::hyper::body::Bytes,
-    ses_with_store: RequestInformation,
+    mut ses_with_store: RequestInformation,
+    protocol_version: ProtocolVersion,
 ) -> Result<(IM, RequestInformation), warp::Rejection>
 where
     IM: messages::Message,
 {
+    // The protocol version is picked by the first message of a session
+    match ses_with_store
+        .session
+        .get::<ProtocolVersion>(PROTOCOL_VERSION_SES_KEY)
+    {
+        Some(session_version) if session_version != protocol_version => {
+            log::warn!(
+                "Client sent message with protocol version {}, in a session using {}",
+                protocol_version,
+                session_version
+            );
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                IM::message_type(),
+                "Protocol version changed during session",
+            )
+            .into());
+        }
+        Some(_) => {}
+        None => {
+            ses_with_store
+                .session
+                .insert(PROTOCOL_VERSION_SES_KEY, protocol_version)
+                .map_err(Error::from_error::<IM, _>)?;
+        }
+    }
+
     let last_msg_type: Option<MessageType> = ses_with_store.session.get(LAST_MSG_SES_KEY);
     if !IM::is_valid_previous_message(last_msg_type) {
         log::warn!(
@@ -230,7 +574,7 @@ where
             .into())
         }
     };
-    let req = IM::deserialize_data(&inbound).map_err(|e| {
+    let req = IM::deserialize_for_version(protocol_version, &inbound).map_err(|e| {
         log::info!("Error parsing request: {:?}", e);
         warp::reject::custom(ParseError)
     })?;
@@ -279,22 +623,25 @@ where
         .session
         .get(ENCRYPTION_KEYS_SES_KEY)
         .unwrap_or_else(EncryptionKeys::unencrypted);
+    let session_id = ses_with_store.session.id().to_string();
+    let finished = ses_with_store.session.is_destroyed() || is_final_message(OM::message_type());
 
-    Ok((
-        response,
-        ses_with_store
-            .session_store
-            .store_session(ses_with_store.session)
-            .await
-            .map_err(|_| {
-                Error::new(
-                    ErrorCode::InternalServerError,
-                    IM::message_type(),
-                    "Error storing session",
-                )
-            })?,
-        keys,
-    ))
+    let token = ses_with_store
+        .session_store
+        .store_session(ses_with_store.session)
+        .await
+        .map_err(|_| {
+            Error::new(
+                ErrorCode::InternalServerError,
+                IM::message_type(),
+                "Error storing session",
+            )
+        })?;
+    ses_with_store
+        .session_store
+        .track_session(session_id, finished);
+
+    Ok((response, token, keys))
 }
 
 fn to_response<MT>(val: Vec<u8>, token: Option<String>) -> warp::reply::Response
@@ -315,10 +662,20 @@ where
     if !fdo_data_formats::interoperable_kdf_available() {
         builder = builder.header("X-Non-Interoperable-KDF", "true");
     }
+    builder = builder.header(crate::SESSION_AFFINITY_HEADER, instance_affinity_token());
 
     builder.body(val.into()).unwrap()
 }
 
+/// Token identifying this server process, sent with every response.
+///
+/// Clients send it back for the rest of the session, so that a load balancer can route all
+/// messages of a session to the same instance.
+fn instance_affinity_token() -> &'static str {
+    static TOKEN: OnceLock<String> = OnceLock::new();
+    TOKEN.get_or_init(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
 async fn encrypt_and_generate_response<IM, OM>(
     val: Vec<u8>,
     token: Option<String>,
@@ -362,6 +719,105 @@ where
     Ok(to_response::<OM>(val, token))
 }
 
+/// Slot of a request within the concurrency limit, released once the request is done
+pub struct RequestSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Limits the number of requests handled at the same time, None (or 0) meaning no limit.
+///
+/// Requests over the limit wait for a slot, so that a burst of sessions queues up
+/// instead of all of them competing for the stores and the CPU at once.
+pub fn request_concurrency_limit(
+    max_concurrent: Option<usize>,
+) -> warp::filters::BoxedFilter<(RequestSlot,)> {
+    let semaphore = max_concurrent
+        .filter(|max| *max > 0)
+        .map(|max| Arc::new(Semaphore::new(max)));
+    warp::any()
+        .and_then(move || {
+            let semaphore = semaphore.clone();
+            async move {
+                let permit = match semaphore {
+                    Some(semaphore) => semaphore.acquire_owned().await.ok(),
+                    None => None,
+                };
+                Ok::<_, Rejection>(RequestSlot { _permit: permit })
+            }
+        })
+        .boxed()
+}
+
+fn blocking_slots() -> &'static Semaphore {
+    static SLOTS: OnceLock<Semaphore> = OnceLock::new();
+    SLOTS.get_or_init(|| {
+        let cpus = std::thread::available_parallelism()
+            .map(|cpus| cpus.get())
+            .unwrap_or(1);
+        Semaphore::new(cpus)
+    })
+}
+
+/// Runs CPU-heavy work, like signing, signature verification or key derivation, on the
+/// blocking thread pool, so that it doesn't stall the async workers serving other
+/// sessions. At most one such task per CPU runs at a time, the others wait their turn.
+pub async fn run_blocking<IM, T, F>(work: F) -> Result<T, Rejection>
+where
+    IM: Message,
+    F: FnOnce() -> Result<T, Rejection> + Send + 'static,
+    T: Send + 'static,
+{
+    let _slot = blocking_slots().acquire().await.ok();
+    match tokio::task::spawn_blocking(work).await {
+        Ok(res) => res,
+        Err(e) => {
+            log::error!(
+                "Error running blocking task for {:?}: {:?}",
+                IM::message_type(),
+                e
+            );
+            Err(Error::new(
+                ErrorCode::InternalServerError,
+                IM::message_type(),
+                "Internal error",
+            )
+            .into())
+        }
+    }
+}
+
+/// Binds to `addr` and returns the future serving `filter` until `shutdown` completes.
+///
+/// Connections are kept alive between requests, and pipelined HTTP/1.1 requests are
+/// answered together, as every FDO session is a series of messages to the same server.
+pub fn serve_with_graceful_shutdown<F>(
+    filter: F,
+    addr: SocketAddr,
+    shutdown: impl futures::Future<Output = ()> + Send + 'static,
+) -> Result<impl futures::Future<Output = ()>, warp::hyper::Error>
+where
+    F: Filter<Error = Infallible> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let service = warp::service(filter);
+    let make_service = warp::hyper::service::make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let server = warp::hyper::Server::try_bind(&addr)?
+        .tcp_keepalive(Some(Duration::from_secs(TCP_KEEPALIVE_SECS)))
+        .http1_keepalive(true)
+        .http1_pipeline_flush(true)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown);
+    Ok(async move {
+        if let Err(e) = server.await {
+            log::error!("Error serving requests: {:?}", e);
+        }
+        crate::telemetry::shutdown().await;
+    })
+}
+
 pub fn ping_handler() -> warp::filters::BoxedFilter<(warp::reply::Response,)> {
     warp::post()
         .and(warp::path("ping"))
@@ -393,13 +849,16 @@ where
             );
         }
     }
-    if protocol_version != IM::protocol_version() {
+    if !IM::supports_protocol_version(protocol_version)
+        || !OM::supports_protocol_version(protocol_version)
+    {
         // This is a programming error, let's just check this on start
         #[allow(clippy::panic)]
         {
             panic!(
-                "Programming error: IM {:?} is not of the selected protocol version {:?}",
-                IM::protocol_version(),
+                "Programming error: IM {:?} or OM {:?} does not support protocol version {:?}",
+                IM::message_type(),
+                OM::message_type(),
                 protocol_version
             );
         }
@@ -419,7 +878,7 @@ where
     warp::post()
         // Construct expected HTTP path
         .and(warp::path("fdo"))
-        .and(warp::path(IM::protocol_version().to_string()))
+        .and(warp::path(protocol_version.to_string()))
         .and(warp::path("msg"))
         .and(warp::path((IM::message_type() as u8).to_string()))
         // Parse the request
@@ -446,7 +905,7 @@ where
                         };
                         match ses_store.load_session(val.to_string()).await {
                             Ok(Some(ses)) => ses,
-                            Ok(None) => Session::new(),
+                            Ok(None) => ses_store.new_session::<IM>()?,
                             Err(_) => {
                                 return Err(Rejection::from(Error::new(
                                     ErrorCode::InternalServerError,
@@ -456,7 +915,7 @@ where
                             }
                         }
                     }
-                    None => Session::new(),
+                    None => ses_store.new_session::<IM>()?,
                 };
                 let req_hash = Hash::from_data(HashType::Sha256, &req).unwrap();
                 Ok((
@@ -472,14 +931,37 @@ where
             },
         )
         .untuple_one()
-        .and_then(parse_request)
+        .and_then(move |req, ses| parse_request::<IM>(req, ses, protocol_version))
         // Insert the user data
         .map(move |(req, ses)| (user_data.clone(), req, ses))
         // Move the request message to the end
         .map(move |(user_data, req, ses)| (user_data, ses, req))
-        // Call the handler
+        // Call the handler, tagging its log lines and its span with the session
         .untuple_one()
-        .and_then(handler)
+        .and_then(move |user_data, ses: RequestInformation, req| {
+            let context = LogContext {
+                session_id: ses.session.id().to_string(),
+                device_guid: ses.session.get("device_guid"),
+            };
+            let span = MessageSpan::server(format!("{:?}", IM::message_type()), &ses.headers);
+            span.set_attribute("fdo.message_type", (IM::message_type() as u8).to_string());
+            span.set_attribute("fdo.protocol_version", protocol_version.to_string());
+            span.set_attribute("fdo.session_id", context.session_id.clone());
+            let response = span.in_span(with_log_context(context, handler(user_data, ses, req)));
+            async move {
+                let response = response.await;
+                match &response {
+                    // The handlers of the first messages of a device add its GUID
+                    Ok((_, ses)) => {
+                        if let Some(device_guid) = ses.session.get::<String>("device_guid") {
+                            span.set_attribute("fdo.device_guid", device_guid);
+                        }
+                    }
+                    Err(rejection) => span.set_error(format!("{rejection:?}")),
+                }
+                response
+            }
+        })
         .untuple_one()
         // Process "session" storage
         .and_then(store_session::<IM, _>)
@@ -492,3 +974,267 @@ where
         .and_then(encrypt_and_generate_response::<IM, OM>)
         .boxed()
 }
+
+/// Serves the messages with every protocol version that both of them support.
+pub fn fdo_versioned_request_filter<UDT, IM, OM, F, FR>(
+    user_data: UDT,
+    session_store: SessionStoreT,
+    handler: F,
+) -> warp::filters::BoxedFilter<(warp::reply::Response,)>
+where
+    UDT: Clone + Send + Sync + 'static,
+    F: Fn(UDT, RequestInformation, IM) -> FR + Clone + Send + Sync + 'static,
+    FR: futures::Future<Output = Result<(OM, RequestInformation), warp::Rejection>> + Send,
+    IM: messages::Message + ClientMessage + 'static,
+    OM: messages::Message + ServerMessage + 'static,
+{
+    let filter = ProtocolVersion::SUPPORTED
+        .iter()
+        .filter(|version| {
+            IM::supports_protocol_version(**version) && OM::supports_protocol_version(**version)
+        })
+        .map(|version| {
+            fdo_request_filter(
+                *version,
+                user_data.clone(),
+                session_store.clone(),
+                handler.clone(),
+            )
+        })
+        .reduce(|filters, filter| filters.or(filter).unify().boxed());
+    match filter {
+        Some(filter) => filter,
+        None => {
+            // This is a programming error, let's just check this on start
+            #[allow(clippy::panic)]
+            {
+                panic!(
+                    "Programming error: IM {:?} and OM {:?} have no protocol version in common",
+                    IM::message_type(),
+                    OM::message_type()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_nonce {
+    use fdo_data_formats::{messages::v11::to0::OwnerSign, types::Nonce};
+
+    use super::{consume_session_nonce, Session};
+
+    #[test]
+    fn test_consume_session_nonce() {
+        let nonce = Nonce::new().unwrap();
+        let mut session = Session::new();
+        session.insert("nonce3", nonce.clone()).unwrap();
+
+        consume_session_nonce::<OwnerSign>(&mut session, "nonce3", &nonce).unwrap();
+        // A replayed message is refused
+        assert!(consume_session_nonce::<OwnerSign>(&mut session, "nonce3", &nonce).is_err());
+    }
+
+    #[test]
+    fn test_consume_session_nonce_mismatch() {
+        let nonce = Nonce::new().unwrap();
+        let other = Nonce::new().unwrap();
+        let mut session = Session::new();
+        session.insert("nonce3", nonce.clone()).unwrap();
+
+        assert!(consume_session_nonce::<OwnerSign>(&mut session, "nonce3", &other).is_err());
+        // The nonce is gone after a failed attempt as well
+        assert!(consume_session_nonce::<OwnerSign>(&mut session, "nonce3", &nonce).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_session_encryption {
+    use std::future::Future;
+    use std::sync::Arc;
+
+    use fdo_data_formats::Serializable;
+    use fdo_store::StoreConfig;
+    use serde_bytes::ByteBuf;
+
+    use super::{Session, SessionEncryptionKey, SessionStore, StoredSession};
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn session_store(encryption_key: Option<&SessionEncryptionKey>) -> Arc<SessionStore> {
+        SessionStore::new(
+            StoreConfig::Memory.initialize().unwrap(),
+            encryption_key.cloned(),
+        )
+    }
+
+    fn key(byte: u8) -> SessionEncryptionKey {
+        SessionEncryptionKey::from_bytes(&[byte; 32]).unwrap()
+    }
+
+    fn id_of(cookie: &str) -> String {
+        Session::id_from_cookie_value(cookie).unwrap()
+    }
+
+    fn test_session() -> Session {
+        let mut session = Session::new();
+        session.insert("nonce", "value").unwrap();
+        session
+    }
+
+    fn encrypted_parts(stored: StoredSession) -> (ByteBuf, ByteBuf, ByteBuf) {
+        match stored {
+            StoredSession::Encrypted {
+                iv,
+                ciphertext,
+                tag,
+            } => (iv, ciphertext, tag),
+            StoredSession::Plain(_) => panic!("Session was not encrypted"),
+        }
+    }
+
+    #[test]
+    fn test_session_key_length() {
+        assert!(SessionEncryptionKey::from_bytes(&[0; 16]).is_err());
+        assert!(SessionEncryptionKey::from_bytes(&[0; 33]).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_session_roundtrip() {
+        let key = key(1);
+        let store = session_store(Some(&key));
+
+        let session = test_session();
+        let id = session.id().to_string();
+        let cookie = block_on(store.store_session(session)).unwrap().unwrap();
+
+        // The session is not persisted in plain text
+        match block_on(store.store.load_data(&id)).unwrap().unwrap() {
+            StoredSession::Encrypted { ciphertext, .. } => {
+                assert!(!ciphertext.windows(5).any(|window| window == b"value"))
+            }
+            StoredSession::Plain(_) => panic!("Session was not encrypted"),
+        }
+
+        let loaded = block_on(store.load_session(cookie)).unwrap().unwrap();
+        assert_eq!(loaded.id(), id);
+        assert_eq!(loaded.get::<String>("nonce").as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn test_encrypted_session_tampered() {
+        let key = key(1);
+        let session = test_session();
+        let id = session.id().to_string();
+        let (iv, ciphertext, tag) = encrypted_parts(key.encrypt(&id, &session).unwrap());
+        key.decrypt(&id, &iv, &ciphertext, &tag).unwrap();
+
+        let mut tampered = ciphertext.to_vec();
+        tampered[0] ^= 0x01;
+        assert!(key.decrypt(&id, &iv, &tampered, &tag).is_err());
+
+        let mut tampered = tag.to_vec();
+        tampered[0] ^= 0x01;
+        assert!(key.decrypt(&id, &iv, &ciphertext, &tampered).is_err());
+
+        let mut tampered = iv.to_vec();
+        tampered[0] ^= 0x01;
+        assert!(key.decrypt(&id, &tampered, &ciphertext, &tag).is_err());
+
+        // The blob of one session can't be used for another one
+        assert!(key
+            .decrypt(Session::new().id(), &iv, &ciphertext, &tag)
+            .is_err());
+    }
+
+    #[test]
+    fn test_encrypted_session_wrong_key() {
+        let session = test_session();
+        let id = session.id().to_string();
+        let (iv, ciphertext, tag) = encrypted_parts(key(1).encrypt(&id, &session).unwrap());
+        assert!(key(2).decrypt(&id, &iv, &ciphertext, &tag).is_err());
+
+        // Through the session store, the session can't be loaded
+        let writer = session_store(Some(&key(1)));
+        let cookie = block_on(writer.store_session(test_session()))
+            .unwrap()
+            .unwrap();
+        let stored = block_on(writer.store.load_data(&id_of(&cookie)))
+            .unwrap()
+            .unwrap();
+        let reader = session_store(Some(&key(2)));
+        block_on(reader.store.store_data(id_of(&cookie), stored)).unwrap();
+        assert!(block_on(reader.load_session(cookie)).is_err());
+    }
+
+    #[test]
+    fn test_plain_session() {
+        let store = session_store(None);
+        let cookie = block_on(store.store_session(test_session()))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            block_on(store.store.load_data(&id_of(&cookie))).unwrap(),
+            Some(StoredSession::Plain(_))
+        ));
+        let loaded = block_on(store.load_session(cookie)).unwrap().unwrap();
+        assert_eq!(loaded.get::<String>("nonce").as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn test_legacy_session() {
+        // Sessions persisted before encryption was added are bare sessions
+        let session = test_session();
+        let legacy = session.serialize_data().unwrap();
+        match StoredSession::deserialize_data(&legacy).unwrap() {
+            StoredSession::Plain(loaded) => {
+                assert_eq!(loaded.id(), session.id());
+                assert_eq!(loaded.get::<String>("nonce").as_deref(), Some("value"));
+            }
+            StoredSession::Encrypted { .. } => panic!("Legacy session parsed as encrypted"),
+        }
+
+        let stored = StoredSession::Plain(session.clone())
+            .serialize_data()
+            .unwrap();
+        assert!(matches!(
+            StoredSession::deserialize_data(&stored).unwrap(),
+            StoredSession::Plain(_)
+        ));
+        let stored = key(1).encrypt(session.id(), &session).unwrap();
+        assert!(matches!(
+            StoredSession::deserialize_data(&stored.serialize_data().unwrap()).unwrap(),
+            StoredSession::Encrypted { .. }
+        ));
+    }
+
+    #[test]
+    fn test_session_encryption_mismatch() {
+        // Plain sessions are ignored once encryption is enabled, and the other way around
+        let plain = session_store(None);
+        let encrypted = session_store(Some(&key(1)));
+
+        let cookie = block_on(plain.store_session(test_session()))
+            .unwrap()
+            .unwrap();
+        let stored = block_on(plain.store.load_data(&id_of(&cookie)))
+            .unwrap()
+            .unwrap();
+        block_on(encrypted.store.store_data(id_of(&cookie), stored)).unwrap();
+        assert!(block_on(encrypted.load_session(cookie)).unwrap().is_none());
+
+        let cookie = block_on(encrypted.store_session(test_session()))
+            .unwrap()
+            .unwrap();
+        let stored = block_on(encrypted.store.load_data(&id_of(&cookie)))
+            .unwrap()
+            .unwrap();
+        block_on(plain.store.store_data(id_of(&cookie), stored)).unwrap();
+        assert!(block_on(plain.load_session(cookie)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,186 @@
+//! OpenTelemetry traces of the requests, exported with OTLP.
+//!
+//! Servers start a span for every protocol message they handle, and clients for every
+//! message they send, passing their trace on in the W3C `traceparent` header. All spans
+//! of a device carry its GUID in the `fdo.device_guid` attribute, as the protocols of a
+//! device are run by different clients, sometimes days apart.
+//!
+//! Without the "otlp" feature, spans do nothing.
+
+use std::future::Future;
+
+#[cfg(feature = "otlp")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+#[cfg(feature = "otlp")]
+const TRACER_NAME: &str = "fdo";
+
+/// Sets up the export of the spans, if an OTLP endpoint is set in the environment.
+///
+/// The exporter is configured with the standard environment variables, like
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` and `OTEL_SERVICE_NAME`. The service name defaults to
+/// the name of the executable.
+pub fn init() {
+    #[cfg(feature = "otlp")]
+    {
+        // Traces of clients are continued even when this process doesn't export its spans
+        global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        if let Err(e) = install_exporter() {
+            log::error!("Error setting up OTLP trace export: {:?}", e);
+        }
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn install_exporter() -> Result<(), opentelemetry::trace::TraceError> {
+    use opentelemetry_sdk::Resource;
+
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none()
+        && std::env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_none()
+    {
+        return Ok(());
+    }
+    // Spans are exported from a background task
+    if tokio::runtime::Handle::try_current().is_err() {
+        return Err("OTLP trace export needs a Tokio runtime".into());
+    }
+
+    let mut resource = Resource::default();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        let executable = std::env::current_exe().ok();
+        if let Some(name) = executable.as_deref().and_then(std::path::Path::file_name) {
+            resource = resource.merge(&Resource::new(vec![KeyValue::new(
+                "service.name",
+                name.to_string_lossy().into_owned(),
+            )]));
+        }
+    }
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http())
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    log::info!("Exporting traces with OTLP");
+    Ok(())
+}
+
+/// Exports the spans that are still queued, call before exiting.
+pub async fn shutdown() {
+    #[cfg(feature = "otlp")]
+    {
+        // Waits for the export, which runs on the runtime
+        if let Err(e) = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await {
+            log::warn!("Error exporting the last spans: {:?}", e);
+        }
+    }
+}
+
+/// A span, ended when dropped
+pub struct MessageSpan {
+    #[cfg(feature = "otlp")]
+    context: Context,
+}
+
+impl std::fmt::Debug for MessageSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("MessageSpan");
+        #[cfg(feature = "otlp")]
+        debug.field("span_context", self.context.span().span_context());
+        debug.finish()
+    }
+}
+
+impl MessageSpan {
+    /// Starts the span of a request received by a server, continuing the trace of the
+    /// client if it sent one
+    pub fn server(name: String, headers: &http::HeaderMap) -> Self {
+        #[cfg(feature = "otlp")]
+        {
+            let parent = global::get_text_map_propagator(|propagator| {
+                propagator.extract(&opentelemetry_http::HeaderExtractor(headers))
+            });
+            MessageSpan::start(name, SpanKind::Server, &parent)
+        }
+        #[cfg(not(feature = "otlp"))]
+        {
+            let _ = (name, headers);
+            MessageSpan {}
+        }
+    }
+
+    /// Starts the span of a request sent by a client, as a child of `parent`, or else
+    /// of the span of the request being handled
+    pub fn client(name: String, parent: Option<&MessageSpan>) -> Self {
+        #[cfg(feature = "otlp")]
+        {
+            match parent {
+                Some(parent) => MessageSpan::start(name, SpanKind::Client, &parent.context),
+                None => MessageSpan::start(name, SpanKind::Client, &Context::current()),
+            }
+        }
+        #[cfg(not(feature = "otlp"))]
+        {
+            let _ = (name, parent);
+            MessageSpan {}
+        }
+    }
+
+    #[cfg(feature = "otlp")]
+    fn start(name: String, kind: SpanKind, parent: &Context) -> Self {
+        let tracer = global::tracer(TRACER_NAME);
+        let span = tracer
+            .span_builder(name)
+            .with_kind(kind)
+            .start_with_context(&tracer, parent);
+        MessageSpan {
+            context: parent.with_span(span),
+        }
+    }
+
+    pub fn set_attribute(&self, key: &'static str, value: String) {
+        #[cfg(feature = "otlp")]
+        self.context.span().set_attribute(KeyValue::new(key, value));
+        #[cfg(not(feature = "otlp"))]
+        let _ = (key, value);
+    }
+
+    /// Marks the request as failed
+    pub fn set_error(&self, description: String) {
+        #[cfg(feature = "otlp")]
+        self.context.span().set_status(Status::error(description));
+        #[cfg(not(feature = "otlp"))]
+        let _ = description;
+    }
+
+    /// Adds the header passing the trace on to the server, with this span as parent
+    pub fn inject(&self, headers: &mut http::HeaderMap) {
+        #[cfg(feature = "otlp")]
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(
+                &self.context,
+                &mut opentelemetry_http::HeaderInjector(headers),
+            )
+        });
+        #[cfg(not(feature = "otlp"))]
+        let _ = headers;
+    }
+
+    /// Runs `future` in this span, so that the requests it sends are its children
+    pub fn in_span<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        #[cfg(feature = "otlp")]
+        {
+            future.with_context(self.context.clone())
+        }
+        #[cfg(not(feature = "otlp"))]
+        {
+            future
+        }
+    }
+}
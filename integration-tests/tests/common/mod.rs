@@ -16,7 +16,8 @@ use openssl::{
     ec::{EcGroup, EcKey},
     hash::MessageDigest,
     nid::Nid,
-    pkey::PKey,
+    pkey::{PKey, PKeyRef, Private},
+    rsa::Rsa,
     x509::{X509Builder, X509NameBuilder},
 };
 
@@ -274,72 +275,86 @@ impl TestContext {
             let private_key =
                 PKey::from_ec_key(private_key).context("Error converting private key to PKey")?;
 
-            // Now create a certificate over (and with) the key
-            let mut subject_name =
-                X509NameBuilder::new().context("Error creating X509NameBuilder")?;
-            subject_name
-                .append_entry_by_text("CN", key_name)
-                .context("Error adding CN")?;
-            let subject_name = subject_name.build();
-
-            let serial_number = BigNum::from_u32(42).context("Error creating serial number")?;
-            let serial_number = Asn1Integer::from_bn(&serial_number)
-                .context("Error converting serial number to ASN1Integer")?;
-
-            let mut cert_builder = X509Builder::new().context("Error creating X509Builder")?;
-            cert_builder
-                .set_version(2)
-                .context("Error setting version")?;
-            cert_builder
-                .set_not_after(
-                    Asn1Time::days_from_now(1)
-                        .context("Error creating Asn1Time")?
-                        .as_ref(),
-                )
-                .context("Error setting not after")?;
-            cert_builder
-                .set_not_before(
-                    Asn1Time::days_from_now(0)
-                        .context("Error creating Asn1Time")?
-                        .as_ref(),
-                )
-                .context("Error setting not before")?;
-            cert_builder
-                .set_issuer_name(&subject_name)
-                .context("Error setting issuer name")?;
-            cert_builder
-                .set_subject_name(&subject_name)
-                .context("Error setting issuer name")?;
-            cert_builder
-                .set_pubkey(&private_key)
-                .context("Error setting public key")?;
-            cert_builder
-                .set_serial_number(&serial_number)
-                .context("Error setting serial number")?;
-
-            cert_builder
-                .sign(&private_key, MessageDigest::sha384())
-                .context("Error signing certificate")?;
-            let cert = cert_builder.build();
-
-            // Now serialize the key and certificate
-            let private_key = private_key
-                .private_key_to_der()
-                .context("Error converting private key to DER")?;
-            let cert = cert
-                .to_pem()
-                .context("Error converting certificate to PEM")?;
-
-            // Now write them to disk
-            fs::write(keys_path.join(format!("{}_key.der", key_name)), private_key)
-                .context("Error writing private key")?;
-            fs::write(keys_path.join(format!("{}_cert.pem", key_name)), cert)
-                .context("Error writing certificate")?;
+            self.write_key(key_name, &private_key)?;
         }
 
         Ok(())
     }
 
+    /// Replaces a generated key and its certificate with a new RSA key of the given size
+    pub fn replace_key_with_rsa(&self, key_name: &str, bits: u32) -> Result<()> {
+        let private_key = Rsa::generate(bits).context("Error generating RSA key")?;
+        let private_key =
+            PKey::from_rsa(private_key).context("Error converting private key to PKey")?;
+        self.write_key(key_name, &private_key)
+    }
+
+    fn write_key(&self, key_name: &str, private_key: &PKeyRef<Private>) -> Result<()> {
+        // Now create a certificate over (and with) the key
+        let mut subject_name = X509NameBuilder::new().context("Error creating X509NameBuilder")?;
+        subject_name
+            .append_entry_by_text("CN", key_name)
+            .context("Error adding CN")?;
+        let subject_name = subject_name.build();
+
+        let serial_number = BigNum::from_u32(42).context("Error creating serial number")?;
+        let serial_number = Asn1Integer::from_bn(&serial_number)
+            .context("Error converting serial number to ASN1Integer")?;
+
+        let mut cert_builder = X509Builder::new().context("Error creating X509Builder")?;
+        cert_builder
+            .set_version(2)
+            .context("Error setting version")?;
+        cert_builder
+            .set_not_after(
+                Asn1Time::days_from_now(1)
+                    .context("Error creating Asn1Time")?
+                    .as_ref(),
+            )
+            .context("Error setting not after")?;
+        cert_builder
+            .set_not_before(
+                Asn1Time::days_from_now(0)
+                    .context("Error creating Asn1Time")?
+                    .as_ref(),
+            )
+            .context("Error setting not before")?;
+        cert_builder
+            .set_issuer_name(&subject_name)
+            .context("Error setting issuer name")?;
+        cert_builder
+            .set_subject_name(&subject_name)
+            .context("Error setting issuer name")?;
+        cert_builder
+            .set_pubkey(private_key)
+            .context("Error setting public key")?;
+        cert_builder
+            .set_serial_number(&serial_number)
+            .context("Error setting serial number")?;
+
+        cert_builder
+            .sign(private_key, MessageDigest::sha384())
+            .context("Error signing certificate")?;
+        let cert = cert_builder.build();
+
+        // Now serialize the key and certificate
+        let private_key = private_key
+            .private_key_to_der()
+            .context("Error converting private key to DER")?;
+        let cert = cert
+            .to_pem()
+            .context("Error converting certificate to PEM")?;
+
+        // Now write them to disk
+        let keys_path = self.keys_path();
+        fs::write(keys_path.join(format!("{}_key.der", key_name)), private_key)
+            .context("Error writing private key")?;
+        fs::write(keys_path.join(format!("{}_cert.pem", key_name)), cert)
+            .context("Error writing certificate")?;
+
+        Ok(())
+    }
+
     pub fn testpath(&self) -> &Path {
         &self.testpath
     }
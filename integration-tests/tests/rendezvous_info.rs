@@ -0,0 +1,137 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use fdo_data_formats::{
+    constants::{RendezvousProtocolValue, RendezvousVariable},
+    types::{CborSimpleType, Hash, RendezvousDirectiveBuilder, RendezvousInfo, RendezvousValue},
+    Serializable,
+};
+
+#[test]
+fn test_rendezvous_info_roundtrip() -> Result<()> {
+    let cert_hash =
+        Hash::from_str("sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08")
+            .context("Error parsing hash")?;
+
+    let directive = RendezvousDirectiveBuilder::new()
+        .device_only()
+        .ip_address("192.0.2.1".parse()?)
+        .ip_address("2001:db8::1".parse()?)
+        .device_port(8082)
+        .dns("rendezvous.example.com")
+        .server_cert_hash(cert_hash.clone())
+        .ca_cert_hash(cert_hash.clone())
+        .user_input()
+        .wifi_ssid("fdo")
+        .wifi_pw("secret")
+        .medium(20)
+        .protocol(RendezvousProtocolValue::Https)
+        .delay_sec(30)
+        .bypass()
+        .build()
+        .context("Error building directive")?;
+    let owner_directive = RendezvousDirectiveBuilder::new()
+        .owner_only()
+        .owner_port(443)
+        .build()
+        .context("Error building owner directive")?;
+
+    let info = RendezvousInfo::from_directives(vec![directive, owner_directive])
+        .context("Error building rendezvous info")?;
+    let serialized = info.serialize_data().context("Error serializing")?;
+    let deserialized =
+        RendezvousInfo::deserialize_data(&serialized).context("Error deserializing")?;
+
+    let values = deserialized.typed_values().context("Error parsing")?;
+    assert_eq!(values.len(), 2);
+    assert_eq!(values[0].len(), 14);
+    assert_eq!(values[0][3].1, RendezvousValue::Port(8082));
+    assert_eq!(
+        values[0][4].1,
+        RendezvousValue::Dns("rendezvous.example.com".to_string())
+    );
+    assert_eq!(values[0][5].1, RendezvousValue::CertHash(cert_hash));
+    assert_eq!(values[0][10].1, RendezvousValue::Medium(20));
+    assert_eq!(
+        values[0][11].1,
+        RendezvousValue::Protocol(RendezvousProtocolValue::Https)
+    );
+    assert_eq!(values[1][1].1, RendezvousValue::Port(443));
+    assert_eq!(values, info.typed_values()?);
+
+    Ok(())
+}
+
+#[test]
+fn test_rendezvous_info_from_human() -> Result<()> {
+    let directive = vec![
+        (
+            RendezvousVariable::Dns,
+            RendezvousVariable::Dns
+                .value_from_human_to_machine(CborSimpleType::Text("localhost".to_string()))?,
+        ),
+        (
+            RendezvousVariable::IPAddress,
+            RendezvousVariable::IPAddress
+                .value_from_human_to_machine(CborSimpleType::Text("127.0.0.1".to_string()))?,
+        ),
+        (
+            RendezvousVariable::Protocol,
+            RendezvousVariable::Protocol
+                .value_from_human_to_machine(CborSimpleType::Text("http".to_string()))?,
+        ),
+        (
+            RendezvousVariable::DevicePort,
+            RendezvousVariable::DevicePort
+                .value_from_human_to_machine(CborSimpleType::Integer(8082))?,
+        ),
+    ];
+    let info = RendezvousInfo::new(vec![directive]).context("Error building rendezvous info")?;
+
+    let values = info.typed_values()?;
+    assert_eq!(
+        values[0][1].1,
+        RendezvousValue::IPAddress("127.0.0.1".parse::<std::net::IpAddr>()?.into())
+    );
+    assert_eq!(values[0][3].1, RendezvousValue::Port(8082));
+
+    Ok(())
+}
+
+#[test]
+fn test_rendezvous_info_rejects_malformed() {
+    // Port out of range
+    assert!(RendezvousInfo::new(vec![vec![(
+        RendezvousVariable::DevicePort,
+        CborSimpleType::Integer(70000)
+    )]])
+    .is_err());
+    // Port as text
+    assert!(RendezvousInfo::new(vec![vec![(
+        RendezvousVariable::OwnerPort,
+        CborSimpleType::Text("8080".to_string())
+    )]])
+    .is_err());
+    // IP address with an invalid length
+    assert!(RendezvousInfo::new(vec![vec![(
+        RendezvousVariable::IPAddress,
+        CborSimpleType::Bytes(vec![127, 0, 0])
+    )]])
+    .is_err());
+    // Unknown protocol
+    assert!(RendezvousInfo::new(vec![vec![(
+        RendezvousVariable::Protocol,
+        CborSimpleType::Integer(42)
+    )]])
+    .is_err());
+    // Hash without a hash type
+    assert!(RendezvousVariable::ServerCertHash
+        .value_from_human_to_machine(CborSimpleType::Text("abcd".to_string()))
+        .is_err());
+    // Value type not matching the variable
+    assert!(RendezvousDirectiveBuilder::new()
+        .instruction(RendezvousVariable::Dns, RendezvousValue::Port(80))
+        .build()
+        .is_err());
+}
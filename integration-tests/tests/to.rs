@@ -16,8 +16,12 @@ async fn test_to() -> Result<()> {
         for server_noninteroperable_kdf in [true, false] {
             L.l(format!("Starting test case, client_noninteroperable_kdf: {:?}, server_noninteroperable_kdf: {:?}", client_noninteroperable_kdf, server_noninteroperable_kdf));
             L.l("********************************************************============================================================");
-            if let Err(e) =
-                test_to_impl(client_noninteroperable_kdf, server_noninteroperable_kdf).await
+            if let Err(e) = test_to_impl(
+                client_noninteroperable_kdf,
+                server_noninteroperable_kdf,
+                None,
+            )
+            .await
             {
                 L.l(format!("Test FAILED: {:?}", e));
                 failed.push(TestCase {
@@ -41,6 +45,11 @@ async fn test_to() -> Result<()> {
     }
 }
 
+#[tokio::test]
+async fn test_to_asymkex() -> Result<()> {
+    test_to_impl(false, false, Some(3072)).await
+}
+
 #[derive(Debug)]
 struct TestCase {
     #[allow(dead_code)]
@@ -54,9 +63,17 @@ struct TestCase {
 async fn test_to_impl(
     client_noninteroperable_kdf: bool,
     server_noninteroperable_kdf: bool,
+    asymkex_rsa_bits: Option<u32>,
 ) -> Result<()> {
     let mut ctx = TestContext::new().context("Error building test context")?;
 
+    // ASYMKEX encrypts to the Owner key, so that needs to be an RSA key of the suite size
+    let kex_suite = asymkex_rsa_bits.map(|bits| format!("ASYMKEX{}", bits));
+    if let Some(bits) = asymkex_rsa_bits {
+        ctx.replace_key_with_rsa("owner", bits)
+            .context("Error creating RSA owner key")?;
+    }
+
     let rendezvous_server = ctx
         .start_test_server(
             Binary::RendezvousServer,
@@ -173,6 +190,29 @@ async fn test_to_impl(
         })?;
     }
 
+    let provenance_output = ctx
+        .run_owner_tool(
+            &key_path,
+            &[
+                "provenance",
+                ov_path.to_str().unwrap(),
+                &format!(
+                    "--trusted-manufacturer-certs={}",
+                    key_path.join("manufacturer_cert.pem").to_str().unwrap()
+                ),
+                "--output=json",
+            ],
+        )
+        .context("Error running provenance")?;
+    provenance_output
+        .expect_success()
+        .context("provenance failed")?;
+    let provenance: serde_json::Value = serde_json::from_slice(provenance_output.raw_stdout())
+        .context("Error parsing provenance report")?;
+    assert_eq!(provenance["valid"], true);
+    assert_eq!(provenance["manufacturer"]["status"], "valid");
+    assert_eq!(provenance["owners"].as_array().unwrap().len(), 2);
+
     let device_guid = determine_device_credential_guid(&dc_path)
         .context("Error determining device GUID")?
         .to_string();
@@ -236,6 +276,9 @@ async fn test_to_impl(
                 if client_noninteroperable_kdf {
                     cfg.env("FORCE_NONINTEROPERABLE_KDF", &"true");
                 }
+                if let Some(kex_suite) = &kex_suite {
+                    cfg.env("DEVICE_KEX_SUITE", kex_suite);
+                }
                 Ok(())
             },
             Duration::from_secs(5),
@@ -253,6 +296,9 @@ async fn test_to_impl(
     if !client_noninteroperable_kdf && !server_noninteroperable_kdf {
         output.expect_stderr_line("Using fully interoperable KDF")?;
     }
+    if let Some(kex_suite) = &kex_suite {
+        output.expect_stderr_line(&format!("Using key exchange suite {}", kex_suite))?;
+    }
 
     pretty_assertions::assert_eq!(
         fs::read_to_string(&marker_file_path).context("Error reading marker file")?,
@@ -292,7 +338,7 @@ ssh-ed25519 sshkey_default user@example2.com
 
 fn determine_device_credential_guid(path: &Path) -> Result<Guid> {
     let dc_contents = fs::read(path).context("Error reading device credential")?;
-    let dc: FileDeviceCredential =
-        serde_cbor::from_slice(&dc_contents).context("Error deserializing device credential")?;
+    let dc = FileDeviceCredential::from_file_data(&dc_contents)
+        .context("Error deserializing device credential")?;
     Ok(dc.guid)
 }
@@ -1,11 +1,26 @@
 mod common;
 
+use std::convert::TryFrom;
 use std::path::Path;
+use std::time::Instant;
 
 use anyhow::{Context, Result};
+use openssl::{
+    asn1::Asn1Time,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    x509::{X509Builder, X509NameBuilder, X509},
+};
 
 use fdo_data_formats::{
-    ownershipvoucher::OwnershipVoucher, DeserializableMany, Error, ProtocolVersion,
+    constants::{HashType, PublicKeyType},
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::{PublicKey, X5Chain},
+    types::{Guid, HMac, Hash, RendezvousInfo},
+    DeserializableMany, Error, ProtocolVersion, Serializable,
 };
 
 fn test_single_voucher(_path: &Path, voucher: &[u8]) -> Result<()> {
@@ -100,6 +115,27 @@ fn test_multiple_vouchers_pem() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_vouchers_diagnostic() -> Result<()> {
+    execute_for_each_voucher("vouchers/v101/", (), |_, path, voucher| {
+        let voucher = OwnershipVoucher::from_pem(&voucher).context("Error parsing OV")?;
+
+        let diagnostic = voucher
+            .to_diagnostic()
+            .context("Error formatting OV as diagnostic")?;
+        let parsed = OwnershipVoucher::from_diagnostic(&diagnostic)
+            .with_context(|| format!("Error parsing diagnostic OV {:?}", path))?;
+
+        assert_eq!(
+            voucher.serialize_data()?,
+            parsed.serialize_data()?,
+            "Diagnostic round-trip changed {:?}",
+            path
+        );
+        Ok(())
+    })
+}
+
 // We explicitly decided to drop support for the old format.
 #[test]
 fn test_voucher_v100() -> Result<()> {
@@ -119,3 +155,242 @@ fn test_voucher_v100() -> Result<()> {
         Err(anyhow::anyhow!("One or more vouchers failed"))
     }
 }
+
+fn key_and_public_key() -> Result<(PKey<Private>, X509, PublicKey)> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "owner")?;
+    let name = name.build();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&Asn1Time::days_from_now(1)?)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+    let cert = builder.build();
+
+    let public_key = PublicKey::try_from(cert.clone())?;
+    Ok((key, cert, public_key))
+}
+
+/// Builds a serialized voucher with `num_entries` entries, all signed by the same key.
+fn build_voucher(num_entries: u16) -> Result<Vec<u8>> {
+    let (key, device_cert, public_key) = key_and_public_key()?;
+    let device_chain_hash = Hash::from_data(HashType::Sha256, &device_cert.to_der()?)?;
+    let device_chain = X5Chain::new(vec![device_cert])?;
+    let header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        Guid::new()?,
+        RendezvousInfo::new(vec![])?,
+        "test-device".to_string(),
+        public_key.clone(),
+        Some(device_chain_hash),
+    )?;
+    let hmac = HMac::from_digest(HashType::HmacSha256, vec![0; 32])?;
+    let mut voucher = OwnershipVoucher::new(header, hmac, Some(device_chain))?;
+    for _ in 0..num_entries {
+        voucher.extend(&key, None, &public_key)?;
+    }
+    Ok(voucher.serialize_data()?)
+}
+
+#[test]
+fn test_voucher_entries() -> Result<()> {
+    let raw = build_voucher(5).context("Error building voucher")?;
+    let voucher = OwnershipVoucher::deserialize_data(&raw).context("Error parsing OV")?;
+
+    assert_eq!(voucher.num_entries(), 5);
+    assert_eq!(voucher.iter_entries()?.count(), 5);
+    for entry in voucher.iter_entries()? {
+        entry.context("Error validating OV entry")?;
+    }
+    voucher.entry(4)?;
+    assert!(voucher.entry(5).is_err());
+    assert_eq!(voucher.serialize_data()?, raw);
+
+    // Tampering with an entry breaks the chain of entries
+    let mut tampered = raw.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 0xff;
+    let tampered = OwnershipVoucher::deserialize_data(&tampered)?;
+    assert!(tampered.iter_entries()?.any(|entry| entry.is_err()));
+
+    Ok(())
+}
+
+fn delegate_key_and_cert(
+    issuer_key: &PKey<Private>,
+    issuer: &X509,
+) -> Result<(PKey<Private>, X509)> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "delegate")?;
+    let name = name.build();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(issuer.subject_name())?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&Asn1Time::days_from_now(1)?)?;
+    builder.sign(issuer_key, MessageDigest::sha256())?;
+    Ok((key, builder.build()))
+}
+
+/// Builds a voucher without entries, returning it with the manufacturer key.
+fn new_voucher() -> Result<(PKey<Private>, OwnershipVoucher)> {
+    let (key, _, public_key) = key_and_public_key()?;
+    let header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        Guid::new()?,
+        RendezvousInfo::new(vec![])?,
+        "test-device".to_string(),
+        public_key,
+        None,
+    )?;
+    let hmac = HMac::from_digest(HashType::HmacSha256, vec![0; 32])?;
+    Ok((key, OwnershipVoucher::new(header, hmac, None)?))
+}
+
+#[test]
+fn test_voucher_delegated_owner_entry() -> Result<()> {
+    let (owner_key, owner_cert, owner_public_key) = key_and_public_key()?;
+    let (delegate_key, delegate_cert) = delegate_key_and_cert(&owner_key, &owner_cert)?;
+    let delegated_public_key =
+        PublicKey::try_from(X5Chain::new(vec![delegate_cert, owner_cert.clone()])?)?;
+
+    let (manufacturer_key, mut voucher) = new_voucher()?;
+    voucher.extend(&manufacturer_key, None, &delegated_public_key)?;
+    // Only the delegated key can extend the voucher further
+    assert!(voucher
+        .clone()
+        .extend(&owner_key, None, &owner_public_key)
+        .is_err());
+    voucher.extend(&delegate_key, None, &owner_public_key)?;
+
+    let voucher = OwnershipVoucher::deserialize_data(&voucher.serialize_data()?)?;
+    let entries = voucher
+        .iter_entries()?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Error validating OV entries")?;
+    assert!(entries[0].public_key().chain().is_some());
+
+    // A chain whose leaf was not issued by the next certificate is rejected
+    let (_, other_cert, _) = key_and_public_key()?;
+    let (_, delegate_cert) = delegate_key_and_cert(&owner_key, &owner_cert)?;
+    let forged_public_key = PublicKey::try_from(X5Chain::new(vec![delegate_cert, other_cert])?)?;
+    let (manufacturer_key, mut voucher) = new_voucher()?;
+    voucher.extend(&manufacturer_key, None, &forged_public_key)?;
+    assert!(matches!(
+        voucher.iter_entries()?.next(),
+        Some(Err(Error::InvalidChain(_)))
+    ));
+
+    Ok(())
+}
+
+fn rsa_key_and_public_key(
+    bits: u32,
+    key_type: PublicKeyType,
+) -> Result<(PKey<Private>, PublicKey)> {
+    let key = PKey::from_rsa(Rsa::generate(bits)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", "owner")?;
+    let name = name.build();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&Asn1Time::days_from_now(1)?)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    let public_key = PublicKey::from_x509_with_key_type(builder.build(), key_type)?;
+    Ok((key, public_key))
+}
+
+#[test]
+fn test_voucher_rsa_owner_entries() -> Result<()> {
+    let owners = [
+        rsa_key_and_public_key(2048, PublicKeyType::Rsa2048RESTR)?,
+        rsa_key_and_public_key(3072, PublicKeyType::RsaPkcs)?,
+        rsa_key_and_public_key(2048, PublicKeyType::RsaPss)?,
+        rsa_key_and_public_key(3072, PublicKeyType::RsaPss)?,
+    ];
+    let (_, _, last_public_key) = key_and_public_key()?;
+
+    let (manufacturer_key, mut voucher) = new_voucher()?;
+    voucher.extend(&manufacturer_key, None, &owners[0].1)?;
+    for (idx, (owner_key, _)) in owners.iter().enumerate() {
+        let next_public_key = owners
+            .get(idx + 1)
+            .map(|(_, public_key)| public_key)
+            .unwrap_or(&last_public_key);
+        voucher.extend(owner_key, None, next_public_key)?;
+    }
+
+    let voucher = OwnershipVoucher::deserialize_data(&voucher.serialize_data()?)?;
+    let entries = voucher
+        .iter_entries()?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Error validating OV entries")?;
+    assert_eq!(entries.len(), owners.len() + 1);
+    for (entry, (_, public_key)) in entries.iter().zip(owners.iter()) {
+        assert_eq!(entry.public_key().keytype(), public_key.keytype());
+    }
+
+    Ok(())
+}
+
+// Run with: cargo test --test voucher_tests -- --ignored --nocapture bench_large_vouchers
+#[test]
+#[ignore]
+fn bench_large_vouchers() -> Result<()> {
+    const NUM_VOUCHERS: usize = 10_000;
+    const NUM_ENTRIES: u16 = 200;
+
+    let raw = build_voucher(NUM_ENTRIES).context("Error building voucher")?;
+
+    let start = Instant::now();
+    let vouchers = (0..NUM_VOUCHERS)
+        .map(|_| OwnershipVoucher::deserialize_data(&raw))
+        .collect::<Result<Vec<_>, _>>()?;
+    println!(
+        "Loaded {} vouchers of {} bytes with {} entries in {:?}",
+        vouchers.len(),
+        raw.len(),
+        NUM_ENTRIES,
+        start.elapsed()
+    );
+
+    let start = Instant::now();
+    for voucher in vouchers.iter().take(100) {
+        for entry in voucher.iter_entries()? {
+            entry?;
+        }
+    }
+    println!(
+        "Validated the entries of 100 vouchers in {:?}",
+        start.elapsed()
+    );
+
+    let start = Instant::now();
+    for voucher in &vouchers {
+        voucher.header().guid();
+    }
+    println!(
+        "Decoded {} headers in {:?}",
+        vouchers.len(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use openssl::{
+    asn1::{Asn1Integer, Asn1Time},
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{extension::BasicConstraints, X509Builder, X509NameBuilder, X509},
+};
+
+use fdo_data_formats::{enhanced_types::X5Bag, publickey::X5Chain, Error};
+
+struct Issued {
+    key: PKey<Private>,
+    cert: X509,
+}
+
+fn issue(
+    name: &str,
+    issuer: Option<&Issued>,
+    is_ca: bool,
+    (not_before, not_after): (Asn1Time, Asn1Time),
+) -> Result<Issued> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_text("CN", name)?;
+    let subject = subject.build();
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&Asn1Integer::from_bn(&BigNum::from_u32(42)?)?)?;
+    builder.set_subject_name(&subject)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    if is_ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    }
+    match issuer {
+        Some(issuer) => {
+            builder.set_issuer_name(issuer.cert.subject_name())?;
+            builder.sign(&issuer.key, MessageDigest::sha256())?;
+        }
+        None => {
+            builder.set_issuer_name(&subject)?;
+            builder.sign(&key, MessageDigest::sha256())?;
+        }
+    }
+
+    Ok(Issued {
+        key,
+        cert: builder.build(),
+    })
+}
+
+fn valid() -> Result<(Asn1Time, Asn1Time)> {
+    Ok((Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(30)?))
+}
+
+fn chain(certs: &[&Issued]) -> Result<X5Chain> {
+    X5Chain::new(certs.iter().map(|i| i.cert.clone()).collect()).context("Error building chain")
+}
+
+#[test]
+fn test_x5chain_verify_full() -> Result<()> {
+    let root = issue("root", None, true, valid()?)?;
+    let intermediate = issue("intermediate", Some(&root), true, valid()?)?;
+    let device = issue("device", Some(&intermediate), false, valid()?)?;
+
+    let anchors = X5Bag::with_certs(vec![root.cert.clone()])?;
+    let device_chain = chain(&[&device, &intermediate])?;
+    let leaf = device_chain.verify_full(&anchors)?;
+    assert_eq!(leaf.to_der()?, device.cert.to_der()?);
+
+    // Including the root in the chain works just as well
+    chain(&[&device, &intermediate, &root])?.verify_full(&anchors)?;
+
+    // Intermediate CAs can be used as trust anchor
+    let anchors = X5Bag::with_certs(vec![intermediate.cert.clone()])?;
+    device_chain.verify_full(&anchors)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_x5chain_verify_full_rejects() -> Result<()> {
+    let root = issue("root", None, true, valid()?)?;
+    let anchors = X5Bag::with_certs(vec![root.cert.clone()])?;
+
+    // Not signed by a trust anchor
+    let other_root = issue("other root", None, true, valid()?)?;
+    let device = issue("device", Some(&other_root), false, valid()?)?;
+    assert!(chain(&[&device, &other_root])?
+        .verify_full(&anchors)
+        .is_err());
+
+    // Issuer is not a CA
+    let not_ca = issue("not a ca", Some(&root), false, valid()?)?;
+    let device = issue("device", Some(&not_ca), false, valid()?)?;
+    assert!(matches!(
+        chain(&[&device, &not_ca])?.verify_full(&anchors),
+        Err(Error::InvalidChain(_))
+    ));
+
+    // Expired
+    let expired = (Asn1Time::from_unix(0)?, Asn1Time::from_unix(86400)?);
+    let device = issue("device", Some(&root), false, expired)?;
+    assert!(chain(&[&device])?.verify_full(&anchors).is_err());
+
+    // Not yet valid
+    let future = (Asn1Time::days_from_now(10)?, Asn1Time::days_from_now(30)?);
+    let device = issue("device", Some(&root), false, future)?;
+    assert!(chain(&[&device])?.verify_full(&anchors).is_err());
+
+    Ok(())
+}
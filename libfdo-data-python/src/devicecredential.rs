@@ -0,0 +1,71 @@
+use pyo3::{prelude::*, types::PyBytes};
+
+use fdo_data_formats::devicecredential::{file::KeyStorage, FileDeviceCredential};
+
+use crate::to_py_err;
+
+/// A device credential file, as written by fdo-owner-tool and the manufacturing client
+#[pyclass(name = "DeviceCredential", module = "fdo")]
+pub(crate) struct PyDeviceCredential(FileDeviceCredential);
+
+#[pymethods]
+impl PyDeviceCredential {
+    /// Parses a device credential file, in the current or the legacy format
+    #[staticmethod]
+    fn from_data(data: &[u8]) -> PyResult<Self> {
+        FileDeviceCredential::from_file_data(data)
+            .map(PyDeviceCredential)
+            .map_err(to_py_err)
+    }
+
+    /// Serializes the device credential in the current file format
+    fn to_data<'py>(&self, py: Python<'py>) -> PyResult<&'py PyBytes> {
+        let data = self.0.to_file_data().map_err(to_py_err)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Whether the device attempts onboarding
+    #[getter]
+    fn active(&self) -> bool {
+        self.0.active
+    }
+
+    #[setter]
+    fn set_active(&mut self, active: bool) {
+        self.0.active = active;
+    }
+
+    #[getter]
+    fn protocol_version(&self) -> i32 {
+        self.0.protver as i32
+    }
+
+    #[getter]
+    fn guid(&self) -> String {
+        self.0.guid.to_string()
+    }
+
+    #[getter]
+    fn device_info(&self) -> String {
+        self.0.device_info.clone()
+    }
+
+    /// Hash of the manufacturer public key
+    #[getter]
+    fn public_key_hash(&self) -> String {
+        self.0.pubkey_hash.to_string()
+    }
+
+    /// Where the device keys are stored: "plain" or "tpm"
+    #[getter]
+    fn key_storage(&self) -> &'static str {
+        match self.0.key_storage {
+            KeyStorage::Plain { .. } => "plain",
+            KeyStorage::Tpm { .. } => "tpm",
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<DeviceCredential {}>", self.guid())
+    }
+}
@@ -0,0 +1,21 @@
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+
+mod devicecredential;
+mod management;
+mod ownershipvoucher;
+
+create_exception!(fdo, FdoError, PyException);
+
+fn to_py_err<E: std::fmt::Display>(err: E) -> PyErr {
+    FdoError::new_err(err.to_string())
+}
+
+/// Python bindings for ownership vouchers, device credentials and the owner management API
+#[pymodule]
+fn fdo(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add("FdoError", py.get_type::<FdoError>())?;
+    m.add_class::<ownershipvoucher::PyOwnershipVoucher>()?;
+    m.add_class::<devicecredential::PyDeviceCredential>()?;
+    m.add_class::<management::PyOwnerManagementClient>()?;
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use pyo3::{
+    prelude::*,
+    types::{PyDict, PyList},
+};
+use serde_json::Value;
+
+use crate::{to_py_err, FdoError};
+
+/// Client of the Ownership Voucher management API of the Owner Onboarding Server
+#[pyclass(name = "OwnerManagementClient", module = "fdo")]
+pub(crate) struct PyOwnerManagementClient {
+    base_url: String,
+    auth_token: String,
+    client: reqwest::blocking::Client,
+}
+
+impl PyOwnerManagementClient {
+    fn send(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> PyResult<String> {
+        let resp = self
+            .client
+            .request(method, format!("{}/management/v1/{}", self.base_url, path))
+            .bearer_auth(&self.auth_token)
+            .query(query)
+            .send()
+            .map_err(to_py_err)?;
+        let status = resp.status();
+        let body = resp.text().map_err(to_py_err)?;
+        if !status.is_success() {
+            return Err(FdoError::new_err(format!(
+                "Error requesting {path}: {status}: {body}"
+            )));
+        }
+        Ok(body)
+    }
+
+    fn get_json(&self, py: Python<'_>, path: &str, query: &[(&str, &str)]) -> PyResult<PyObject> {
+        let body = py.allow_threads(|| self.send(reqwest::Method::GET, path, query))?;
+        let value: Value = serde_json::from_str(&body).map_err(to_py_err)?;
+        json_to_py(py, &value)
+    }
+}
+
+#[pymethods]
+impl PyOwnerManagementClient {
+    /// url is the base URL of the server, e.g. http://localhost:8081, and auth_token its
+    /// management_api_auth_token
+    #[new]
+    fn new(url: &str, auth_token: &str) -> PyResult<Self> {
+        Ok(PyOwnerManagementClient {
+            base_url: url.trim_end_matches('/').to_string(),
+            auth_token: auth_token.to_string(),
+            client: reqwest::blocking::Client::builder()
+                .build()
+                .map_err(to_py_err)?,
+        })
+    }
+
+    /// Lists the ownership vouchers, as dicts with their guid and device_info
+    #[pyo3(signature = (pending=false, device_info=None))]
+    fn list(&self, py: Python<'_>, pending: bool, device_info: Option<&str>) -> PyResult<PyObject> {
+        let mut query = Vec::new();
+        if pending {
+            query.push(("pending", "true"));
+        }
+        if let Some(device_info) = device_info {
+            query.push(("device_info", device_info));
+        }
+        self.get_json(py, "ownership_voucher", &query)
+    }
+
+    /// Returns the onboarding status of a device as a dict
+    fn status(&self, py: Python<'_>, guid: &str) -> PyResult<PyObject> {
+        self.get_json(py, &format!("ownership_voucher/{guid}/status"), &[])
+    }
+
+    /// Registers the ownership voucher with its rendezvous servers now
+    fn register(&self, py: Python<'_>, guid: &str) -> PyResult<()> {
+        py.allow_threads(|| {
+            self.send(
+                reqwest::Method::POST,
+                &format!("ownership_voucher/{guid}/registration"),
+                &[],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Deletes the ownership voucher, so that the device can no longer onboard
+    fn revoke(&self, py: Python<'_>, guid: &str) -> PyResult<()> {
+        py.allow_threads(|| {
+            self.send(
+                reqwest::Method::DELETE,
+                &format!("ownership_voucher/{guid}"),
+                &[],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Returns the configuration of the server, without secrets
+    fn configuration(&self, py: Python<'_>) -> PyResult<PyObject> {
+        self.get_json(py, "configuration", &[])
+    }
+}
+
+fn json_to_py(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.into_py(py),
+        Value::Number(n) => {
+            if let Some(n) = n.as_i64() {
+                n.into_py(py)
+            } else if let Some(n) = n.as_u64() {
+                n.into_py(py)
+            } else {
+                n.as_f64().unwrap_or_default().into_py(py)
+            }
+        }
+        Value::String(s) => s.into_py(py),
+        Value::Array(values) => {
+            let list = PyList::empty(py);
+            for value in values {
+                list.append(json_to_py(py, value)?)?;
+            }
+            list.into_py(py)
+        }
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, value) in map {
+                dict.set_item(key, json_to_py(py, value)?)?;
+            }
+            dict.into_py(py)
+        }
+    })
+}
@@ -0,0 +1,96 @@
+use std::convert::TryFrom;
+
+use openssl::{pkey::PKey, x509::X509};
+use pyo3::{prelude::*, types::PyBytes};
+
+use fdo_data_formats::{
+    enhanced_types::X5Bag, ownershipvoucher::OwnershipVoucher, publickey::PublicKey, Serializable,
+};
+
+use crate::{to_py_err, FdoError};
+
+/// An ownership voucher
+#[pyclass(name = "OwnershipVoucher", module = "fdo")]
+pub(crate) struct PyOwnershipVoucher(OwnershipVoucher);
+
+#[pymethods]
+impl PyOwnershipVoucher {
+    /// Parses a PEM encoded voucher
+    #[staticmethod]
+    fn from_pem(data: &[u8]) -> PyResult<Self> {
+        OwnershipVoucher::from_pem(data)
+            .map(PyOwnershipVoucher)
+            .map_err(to_py_err)
+    }
+
+    /// Parses a raw (COSE) voucher
+    #[staticmethod]
+    fn from_data(data: &[u8]) -> PyResult<Self> {
+        OwnershipVoucher::deserialize_data(data)
+            .map(PyOwnershipVoucher)
+            .map_err(to_py_err)
+    }
+
+    fn to_pem(&self) -> PyResult<String> {
+        self.0.to_pem().map_err(to_py_err)
+    }
+
+    fn to_data<'py>(&self, py: Python<'py>) -> PyResult<&'py PyBytes> {
+        let data = self.0.serialize_data().map_err(to_py_err)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    #[getter]
+    fn protocol_version(&self) -> i32 {
+        self.0.header().protocol_version() as i32
+    }
+
+    #[getter]
+    fn guid(&self) -> String {
+        self.0.header().guid().to_string()
+    }
+
+    #[getter]
+    fn device_info(&self) -> String {
+        self.0.header().device_info().to_string()
+    }
+
+    #[getter]
+    fn num_entries(&self) -> u16 {
+        self.0.num_entries()
+    }
+
+    /// Verifies the signatures and hash chain of the entries, raising FdoError if invalid.
+    ///
+    /// With trusted_manufacturer_certs (PEM), the manufacturer public key must be one of them.
+    #[pyo3(signature = (trusted_manufacturer_certs=None))]
+    fn validate(&self, trusted_manufacturer_certs: Option<&[u8]>) -> PyResult<()> {
+        if let Some(certs) = trusted_manufacturer_certs {
+            let certs = X509::stack_from_pem(certs).map_err(to_py_err)?;
+            let trusted = X5Bag::with_certs(certs).map_err(to_py_err)?;
+            if !trusted.contains_publickey(self.0.header().manufacturer_public_key()) {
+                return Err(FdoError::new_err("Manufacturer public key is not trusted"));
+            }
+        }
+
+        for (pos, entry) in self.0.iter_entries().map_err(to_py_err)?.enumerate() {
+            entry.map_err(|e| FdoError::new_err(format!("Entry {pos} is invalid: {e}")))?;
+        }
+        Ok(())
+    }
+
+    /// Extends the voucher to the owner of new_owner_cert, signed with the PEM private key of
+    /// the current owner
+    fn extend(&mut self, owner_private_key: &[u8], new_owner_cert: &[u8]) -> PyResult<()> {
+        let owner_private_key = PKey::private_key_from_pem(owner_private_key).map_err(to_py_err)?;
+        let new_owner_cert = X509::from_pem(new_owner_cert).map_err(to_py_err)?;
+        let new_owner_pubkey = PublicKey::try_from(new_owner_cert).map_err(to_py_err)?;
+        self.0
+            .extend(&owner_private_key, None, &new_owner_pubkey)
+            .map_err(to_py_err)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<OwnershipVoucher {}>", self.guid())
+    }
+}
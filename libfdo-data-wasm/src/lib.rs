@@ -0,0 +1,21 @@
+use wasm_bindgen::prelude::*;
+
+use fdo_data_formats::inspect;
+
+fn to_js_error<E: std::fmt::Display>(err: E) -> JsError {
+    JsError::new(&err.to_string())
+}
+
+/// Summarizes a PEM encoded or raw ownership voucher, without verifying it
+#[wasm_bindgen(js_name = inspectOwnershipVoucher)]
+pub fn inspect_ownership_voucher(data: &[u8]) -> Result<JsValue, JsError> {
+    let summary = inspect::inspect_ownership_voucher(data).map_err(to_js_error)?;
+    serde_wasm_bindgen::to_value(&summary).map_err(to_js_error)
+}
+
+/// Summarizes a device credential file, without its key material
+#[wasm_bindgen(js_name = inspectDeviceCredential)]
+pub fn inspect_device_credential(data: &[u8]) -> Result<JsValue, JsError> {
+    let summary = inspect::inspect_device_credential(data).map_err(to_js_error)?;
+    serde_wasm_bindgen::to_value(&summary).map_err(to_js_error)
+}
@@ -8,10 +8,14 @@ use std::{convert::TryInto, env, str::FromStr};
 
 use fdo_data_formats::{
     constants::{HashType, HeaderKeys, KeyStorageType, MfgStringType, PublicKeyType},
-    devicecredential::{file::KeyStorage, FileDeviceCredential},
+    devicecredential::{
+        file::{tpm_public_key, KeyStorage},
+        FileDeviceCredential,
+    },
     enhanced_types::X5Bag,
     messages,
     publickey::PublicKey,
+    secret::Secret,
     types::{
         CborSimpleType, CipherSuite, Guid, HMac, Hash, KexSuite, KeyDeriveSide, KeyExchange, Nonce,
         RendezvousInfo,
@@ -23,23 +27,20 @@ use fdo_http_wrapper::{
     EncryptionKeys,
 };
 use openssl::{
-    bn::BigNum,
     ec::{EcGroup, EcKey},
-    hash::MessageDigest,
     nid::Nid,
     pkey::{PKey, Private},
-    rsa::Rsa,
     sign::Signer,
 };
 
 use fdo_util::{device_credential_locations, device_identification};
 use tss_esapi::{
-    attributes::ObjectAttributesBuilder,
     interface_types::algorithm::HashingAlgorithm,
-    structures::PublicBuilder,
     traits::{Marshall, UnMarshall},
 };
 
+mod tpm_attestation;
+
 const DEVICE_CREDENTIAL_FILESYSTEM_PATH: &str = "/etc/device-credentials";
 
 #[derive(Parser, Debug)]
@@ -104,11 +105,17 @@ struct NoPlainDIArgs {
     /// iface name for the MACAddress Device Identification string type.
     #[clap(long)]
     iface: Option<String>,
+
+    /// Create the device key in the TPM, and prove this to the manufacturing server
+    /// with the TPM endorsement key.
+    #[clap(long)]
+    tpm_attestation: bool,
 }
 
 async fn perform_diun(
     client: &mut ServiceClient,
     pub_key_verification: DiunPublicKeyVerificationMode,
+    tpm_attestation: bool,
 ) -> Result<(KeyReference, MfgStringType)> {
     log::info!("Performing DIUN");
 
@@ -190,12 +197,27 @@ async fn perform_diun(
     let key_parameters = key_parameters.context("Error requesting key parameters")?;
     log::debug!("Key parameters: {:?}", key_parameters);
 
-    let key_ref = KeyReference::get_new_key(
-        *key_parameters.key_type(),
-        key_parameters.key_storage_types_allowed(),
-    )
-    .await
-    .context("Error getting new key")?;
+    let key_storage_types_allowed = if tpm_attestation {
+        if let Some(allowed) = key_parameters.key_storage_types_allowed() {
+            if !allowed.contains(&KeyStorageType::Tpm) {
+                bail!("TPM attestation requested, but server does not allow TPM keys");
+            }
+        }
+        Some(&[KeyStorageType::Tpm][..])
+    } else {
+        key_parameters.key_storage_types_allowed()
+    };
+    let mut key_ref =
+        KeyReference::get_new_key(*key_parameters.key_type(), key_storage_types_allowed)
+            .await
+            .context("Error getting new key")?;
+
+    if tpm_attestation {
+        let done = perform_tpm_attestation(client, &mut key_ref)
+            .await
+            .context("Error performing TPM attestation")?;
+        return Ok((key_ref, done.mfg_string_type()));
+    }
 
     let done: RequestResult<messages::v11::diun::Done> = client
         .send_request(
@@ -212,6 +234,67 @@ async fn perform_diun(
     Ok((key_ref, done.mfg_string_type()))
 }
 
+async fn perform_tpm_attestation(
+    client: &mut ServiceClient,
+    key_ref: &mut KeyReference,
+) -> Result<messages::v11::diun::Done> {
+    let (tss_context, primary_handle, signing_public, signing_private) = match key_ref {
+        KeyReference::SemiTpm {
+            tss_context,
+            primary_handle,
+            signing_public,
+            signing_private,
+            ..
+        } => (
+            tss_context,
+            *primary_handle,
+            signing_public,
+            signing_private,
+        ),
+        _ => bail!("TPM attestation requires a TPM key"),
+    };
+
+    let attestation_key = tpm_attestation::AttestationKey::create(tss_context)?;
+
+    let challenge: RequestResult<messages::v11::diun::AttestationChallenge> = client
+        .send_request(
+            messages::v11::diun::RequestAttestationChallenge::new(
+                attestation_key.endorsement_key_certificate().to_vec(),
+                attestation_key.public().to_vec(),
+            ),
+            None,
+        )
+        .await;
+    let challenge = challenge.context("Error requesting attestation challenge")?;
+
+    let secret = attestation_key.activate_credential(
+        tss_context,
+        challenge.credential_blob(),
+        challenge.encrypted_secret(),
+    )?;
+    let signing_key =
+        tpm_attestation::load_key(tss_context, primary_handle, signing_public, signing_private)
+            .context("Error loading signing key")?;
+    let certification = attestation_key.certify(tss_context, signing_key, &secret);
+    tss_context
+        .flush_context(signing_key.into())
+        .context("Error flushing signing key")?;
+    let (certify_info, certify_signature) = certification?;
+    attestation_key.flush(tss_context)?;
+
+    let done: RequestResult<messages::v11::diun::Done> = client
+        .send_request(
+            messages::v11::diun::ProvideAttestedKey::new(
+                signing_public.clone(),
+                certify_info,
+                certify_signature,
+            ),
+            None,
+        )
+        .await;
+    done.context("Error sending ProvideAttestedKey")
+}
+
 async fn perform_di(
     client: &mut ServiceClient,
     mut key_reference: KeyReference,
@@ -232,8 +315,12 @@ async fn perform_di(
     let ov_header_hmac = key_reference
         .perform_hmac(&ov_header_buf)
         .context("Error computing HMac over Ownership Voucher Header")?;
+    let manufacturer_hash_type = ov_header
+        .manufacturer_public_key()
+        .hash_type()
+        .context("Unsupported manufacturer key type")?;
     let manufacturer_public_key_hash = ov_header
-        .manufacturer_public_key_hash(HashType::Sha384)
+        .manufacturer_public_key_hash(manufacturer_hash_type)
         .context("Error getting manufacturer public key hash")?;
 
     key_reference
@@ -286,7 +373,16 @@ fn get_X5Bag_from_rootcerts_path(rootcerts_path: String) -> Result<X5Bag> {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    let result = run().await;
+    fdo_http_wrapper::telemetry::shutdown().await;
+    if let Err(e) = result {
+        eprintln!("Error: {e:?}");
+        std::process::exit(fdo_util::exit_status(&e));
+    }
+}
+
+async fn run() -> Result<()> {
     fdo_util::add_version!();
     fdo_http_wrapper::init_logging();
 
@@ -370,9 +466,10 @@ async fn main() -> Result<()> {
 
                 log::debug!("Performing DIUN");
                 client = ServiceClient::new(ProtocolVersion::Version1_1, &url);
-                (keyref, mfg_string_type) = perform_diun(&mut client, diun_pub_key_verification)
-                    .await
-                    .context("Error performing DIUN")?;
+                (keyref, mfg_string_type) =
+                    perform_diun(&mut client, diun_pub_key_verification, args.tpm_attestation)
+                        .await
+                        .context("Error performing DIUN")?;
                 if mfg_string_type == MfgStringType::MACAddress {
                     // user provided iface
                     if args.iface.is_some() {
@@ -449,9 +546,14 @@ async fn main() -> Result<()> {
             if let Ok(iface_var) = env::var("DI_MFG_STRING_TYPE_MAC_IFACE") {
                 iface = Some(iface_var);
             }
-            (keyref, mfg_string_type) = perform_diun(&mut client, diun_pub_key_verification)
-                .await
-                .context("Error performing DIUN")?;
+            let tpm_attestation = match env::var("DIUN_TPM_ATTESTATION") {
+                Ok(val) => val == "true",
+                Err(_) => false,
+            };
+            (keyref, mfg_string_type) =
+                perform_diun(&mut client, diun_pub_key_verification, tpm_attestation)
+                    .await
+                    .context("Error performing DIUN")?;
             if mfg_string_type == MfgStringType::MACAddress && iface.is_none() {
                 match get_default_network_iface() {
                     Ok(Some(result)) => {
@@ -521,7 +623,7 @@ async fn get_mfg_info(
 enum KeyReference {
     FileSystem {
         sign_key: PKey<Private>,
-        hmac_key: Vec<u8>,
+        hmac_key: Secret,
     },
     SemiTpm {
         tss_context: Box<tss_esapi::Context>,
@@ -535,83 +637,6 @@ enum KeyReference {
     },
 }
 
-fn semi_tpm_hmac_key_template(keytype: PublicKeyType) -> Result<tss_esapi::structures::Public> {
-    let hash_algo = match keytype {
-        PublicKeyType::SECP256R1 => HashingAlgorithm::Sha256,
-        PublicKeyType::SECP384R1 => HashingAlgorithm::Sha384,
-        _ => bail!("Unsupported key type {:?}", keytype),
-    };
-    let primary_attributes = ObjectAttributesBuilder::new()
-        .with_fixed_tpm(true)
-        .with_fixed_parent(true)
-        .with_sensitive_data_origin(true)
-        .with_restricted(false)
-        .with_sign_encrypt(true)
-        .with_user_with_auth(true)
-        .build()
-        .context("Error creating object attributes")?;
-    PublicBuilder::new()
-        .with_object_attributes(primary_attributes)
-        .with_public_algorithm(tss_esapi::interface_types::algorithm::PublicAlgorithm::KeyedHash)
-        .with_name_hashing_algorithm(
-            tss_esapi::interface_types::algorithm::HashingAlgorithm::Sha256,
-        )
-        .with_keyed_hash_parameters(tss_esapi::structures::PublicKeyedHashParameters::new(
-            tss_esapi::structures::KeyedHashScheme::Hmac {
-                hmac_scheme: tss_esapi::structures::HmacScheme::new(hash_algo),
-            },
-        ))
-        .with_keyed_hash_unique_identifier(Default::default())
-        .build()
-        .context("Error creating public template")
-}
-
-fn semi_tpm_signing_key_template(key_type: PublicKeyType) -> Result<tss_esapi::structures::Public> {
-    let primary_attributes = ObjectAttributesBuilder::new()
-        .with_fixed_tpm(true)
-        .with_fixed_parent(true)
-        .with_user_with_auth(true)
-        .with_sensitive_data_origin(true)
-        .with_restricted(false)
-        .with_sign_encrypt(true)
-        .build()
-        .context("Error creating object attributes")?;
-    let builder = PublicBuilder::new().with_object_attributes(primary_attributes);
-
-    match key_type {
-        PublicKeyType::SECP256R1 | PublicKeyType::SECP384R1 => {
-            let (curve, hash_algo) = match key_type {
-                PublicKeyType::SECP256R1 => (
-                    tss_esapi::interface_types::ecc::EccCurve::NistP256,
-                    HashingAlgorithm::Sha256,
-                ),
-                PublicKeyType::SECP384R1 => (
-                    tss_esapi::interface_types::ecc::EccCurve::NistP384,
-                    HashingAlgorithm::Sha384,
-                ),
-                _ => unreachable!(),
-            };
-            builder
-                .with_public_algorithm(tss_esapi::interface_types::algorithm::PublicAlgorithm::Ecc)
-                .with_name_hashing_algorithm(
-                    tss_esapi::interface_types::algorithm::HashingAlgorithm::Sha256,
-                )
-                .with_ecc_parameters(tss_esapi::structures::PublicEccParameters::new(
-                    tss_esapi::structures::SymmetricDefinitionObject::Null,
-                    tss_esapi::structures::EccScheme::EcDsa(
-                        tss_esapi::structures::HashScheme::new(hash_algo),
-                    ),
-                    curve,
-                    tss_esapi::structures::KeyDerivationFunctionScheme::Null,
-                ))
-                .with_ecc_unique_identifier(Default::default())
-        }
-        _ => bail!("Unsupported key type {:?}", key_type),
-    }
-    .build()
-    .context("Error creating public template")
-}
-
 impl KeyReference {
     async fn get_new_key_filesystem(keytype: PublicKeyType) -> Result<Self> {
         let mut hmac_key_buf = [0; 32];
@@ -633,7 +658,7 @@ impl KeyReference {
                         .context("Error creating EC key")?;
                 Ok(KeyReference::FileSystem {
                     sign_key,
-                    hmac_key: hmac_key_buf.to_vec(),
+                    hmac_key: hmac_key_buf[..].into(),
                 })
             }
             _ => bail!("Key type not supported"),
@@ -657,11 +682,13 @@ impl KeyReference {
             fdo_data_formats::devicecredential::file::semi_tpm_primary_key_template()
                 .context("Error creating TPM Primary Key template")?;
         log::trace!("Primary key template: {:?}", primary_template);
-        let signing_template = semi_tpm_signing_key_template(keytype)
-            .context("Error creating TPM Signing key template")?;
+        let signing_template =
+            fdo_data_formats::devicecredential::file::semi_tpm_signing_key_template(keytype)
+                .context("Error creating TPM Signing key template")?;
         log::trace!("Signing key template: {:?}", signing_template);
         let hmac_template =
-            semi_tpm_hmac_key_template(keytype).context("Error creating TPM hmac key template")?;
+            fdo_data_formats::devicecredential::file::semi_tpm_hmac_key_template(keytype)
+                .context("Error creating TPM hmac key template")?;
         log::trace!("HMAC key template: {:?}", hmac_template);
 
         let primary_handle = tss_context
@@ -755,7 +782,10 @@ impl KeyReference {
 
         let sign_key = PKey::private_key_from_der(&sign_key).context("Error loading sign key")?;
 
-        Ok(KeyReference::FileSystem { sign_key, hmac_key })
+        Ok(KeyReference::FileSystem {
+            sign_key,
+            hmac_key: hmac_key.into(),
+        })
     }
 
     async fn env_key() -> Result<Self> {
@@ -786,48 +816,10 @@ impl KeyReference {
             KeyReference::SemiTpm { signing_public, .. } => {
                 let signing_public = tss_esapi::structures::Public::unmarshall(signing_public)
                     .context("Error unmarshalling Public")?;
-                match signing_public {
-                    tss_esapi::structures::Public::Rsa {
-                        parameters, unique, ..
-                    } => {
-                        let exponent = BigNum::from_u32(parameters.exponent().value())
-                            .context("Error converting exponent to BigNum")?;
-                        let modulus = BigNum::from_slice(unique.value())
-                            .context("Error converting modulus to BigNum")?;
-                        Rsa::from_public_components(modulus, exponent)
-                            .context("Error creating RSA key")?
-                            .public_key_to_der()
-                            .context("Error serializing public key")
-                    }
-                    tss_esapi::structures::Public::Ecc {
-                        parameters, unique, ..
-                    } => {
-                        let curve = match parameters.ecc_curve() {
-                            tss_esapi::interface_types::ecc::EccCurve::NistP192 => {
-                                Nid::X9_62_PRIME192V1
-                            }
-                            tss_esapi::interface_types::ecc::EccCurve::NistP224 => Nid::SECP224R1,
-                            tss_esapi::interface_types::ecc::EccCurve::NistP256 => {
-                                Nid::X9_62_PRIME256V1
-                            }
-                            tss_esapi::interface_types::ecc::EccCurve::NistP384 => Nid::SECP384R1,
-                            tss_esapi::interface_types::ecc::EccCurve::NistP521 => Nid::SECP521R1,
-                            _ => bail!("Unsupported ECC curve"),
-                        };
-                        let curve =
-                            EcGroup::from_curve_name(curve).context("Error creating EC group")?;
-                        let x = BigNum::from_slice(unique.x())
-                            .context("Error converting X coordinate to BigNum")?;
-                        let y = BigNum::from_slice(unique.y())
-                            .context("Error converting Y coordinate to BigNum")?;
-
-                        EcKey::from_public_key_affine_coordinates(&curve, &x, &y)
-                            .context("Error creating EC key")?
-                            .public_key_to_der()
-                            .context("Error serializing public key")
-                    }
-                    _ => bail!("Unsupported signing key type"),
-                }
+                tpm_public_key(&signing_public)
+                    .context("Error converting signing key")?
+                    .public_key_to_der()
+                    .context("Error serializing public key")
             }
         }
     }
@@ -862,12 +854,12 @@ impl KeyReference {
 
                     key_storage: KeyStorage::Plain {
                         hmac_secret: hmac_key,
-                        private_key,
+                        private_key: private_key.into(),
                     },
                 };
 
                 let cred = cred
-                    .serialize_data()
+                    .to_file_data()
                     .context("Error serializing device credential")?;
 
                 let filename = match env::var_os("DEVICE_CREDENTIAL_FILENAME") {
@@ -901,7 +893,7 @@ impl KeyReference {
                 };
 
                 let cred = cred
-                    .serialize_data()
+                    .to_file_data()
                     .context("Error serializing device credential")?;
 
                 let filename = match env::var_os("DEVICE_CREDENTIAL_FILENAME") {
@@ -916,10 +908,14 @@ impl KeyReference {
 
     fn perform_hmac(&mut self, data: &[u8]) -> Result<HMac> {
         match self {
-            KeyReference::FileSystem { hmac_key, .. } => {
+            KeyReference::FileSystem { sign_key, hmac_key } => {
+                // The HMAC strength follows the strength of the device key
+                let hmac_type = HashType::for_pkey(sign_key)
+                    .context("Unsupported device key type")?
+                    .hmac();
                 let hmac_key =
                     PKey::hmac(hmac_key.as_slice()).context("Error creating HMAC key")?;
-                let mut hmac_signer = Signer::new(MessageDigest::sha384(), &hmac_key)
+                let mut hmac_signer = Signer::new(hmac_type.get_md(), &hmac_key)
                     .context("Error creating hmac signer")?;
                 hmac_signer
                     .update(data)
@@ -927,8 +923,7 @@ impl KeyReference {
                 let hmac = hmac_signer
                     .sign_to_vec()
                     .context("Error finalizing hmac computation")?;
-                HMac::from_digest(HashType::HmacSha384, hmac)
-                    .context("Error converting result to hmac")
+                HMac::from_digest(hmac_type, hmac).context("Error converting result to hmac")
             }
             KeyReference::SemiTpm {
                 ref mut tss_context,
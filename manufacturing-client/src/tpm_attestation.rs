@@ -0,0 +1,201 @@
+//! Attestation of the TPM-resident device key to the manufacturing server during DIUN.
+//!
+//! The device proves that its device key lives in a genuine TPM by sending the
+//! endorsement key (EK) certificate and an attestation key (AK), activating the
+//! credential the server binds to both, and certifying the device key with the AK.
+
+use std::convert::{TryFrom, TryInto};
+
+use anyhow::{bail, Context as _, Result};
+use tss_esapi::{
+    abstraction::{ak, ek, DefaultKey},
+    attributes::SessionAttributesBuilder,
+    constants::SessionType,
+    handles::{AuthHandle, KeyHandle, ObjectHandle, SessionHandle},
+    interface_types::{
+        algorithm::{AsymmetricAlgorithm, HashingAlgorithm, SignatureSchemeAlgorithm},
+        session_handles::{AuthSession, PolicySession},
+    },
+    structures::{
+        Data, EncryptedSecret, IdObject, Private, Public, Signature, SignatureScheme,
+        SymmetricDefinition,
+    },
+    traits::{Marshall, UnMarshall},
+    Context,
+};
+
+pub(crate) struct AttestationKey {
+    endorsement_key: KeyHandle,
+    attestation_key: KeyHandle,
+    endorsement_key_certificate: Vec<u8>,
+    attestation_key_public: Vec<u8>,
+}
+
+impl AttestationKey {
+    /// Creates and loads an attestation key under the default RSA endorsement key
+    pub(crate) fn create(ctx: &mut Context) -> Result<Self> {
+        let endorsement_key_certificate = ek::retrieve_ek_pubcert(ctx, AsymmetricAlgorithm::Rsa)
+            .context("Error retrieving endorsement key certificate")?;
+        let endorsement_key = ek::create_ek_object(ctx, AsymmetricAlgorithm::Rsa, DefaultKey)
+            .context("Error creating endorsement key")?;
+
+        let ak_result = ak::create_ak(
+            ctx,
+            endorsement_key,
+            HashingAlgorithm::Sha256,
+            SignatureSchemeAlgorithm::RsaSsa,
+            None,
+            DefaultKey,
+        )
+        .context("Error creating attestation key")?;
+        let attestation_key_public = ak_result
+            .out_public
+            .marshall()
+            .context("Error marshalling attestation key public")?;
+        let attestation_key = ak::load_ak(
+            ctx,
+            endorsement_key,
+            None,
+            ak_result.out_private,
+            ak_result.out_public,
+        )
+        .context("Error loading attestation key")?;
+
+        Ok(AttestationKey {
+            endorsement_key,
+            attestation_key,
+            endorsement_key_certificate,
+            attestation_key_public,
+        })
+    }
+
+    pub(crate) fn endorsement_key_certificate(&self) -> &[u8] {
+        &self.endorsement_key_certificate
+    }
+
+    /// The attestation key public area, as marshalled TPMT_PUBLIC
+    pub(crate) fn public(&self) -> &[u8] {
+        &self.attestation_key_public
+    }
+
+    /// Activates the credential from the server, returning its secret
+    pub(crate) fn activate_credential(
+        &self,
+        ctx: &mut Context,
+        credential_blob: &[u8],
+        encrypted_secret: &[u8],
+    ) -> Result<Vec<u8>> {
+        let credential_blob =
+            IdObject::try_from(credential_blob.to_vec()).context("Invalid credential blob")?;
+        let encrypted_secret = EncryptedSecret::try_from(encrypted_secret.to_vec())
+            .context("Invalid encrypted secret")?;
+
+        // Using the endorsement key requires the endorsement hierarchy authorization
+        let session = ctx
+            .start_auth_session(
+                None,
+                None,
+                None,
+                SessionType::Policy,
+                SymmetricDefinition::AES_128_CFB,
+                HashingAlgorithm::Sha256,
+            )
+            .context("Error starting policy session")?
+            .context("No policy session returned")?;
+        let (session_attributes, session_attributes_mask) = SessionAttributesBuilder::new()
+            .with_decrypt(true)
+            .with_encrypt(true)
+            .build();
+        ctx.tr_sess_set_attributes(session, session_attributes, session_attributes_mask)
+            .context("Error setting policy session attributes")?;
+        let policy_session =
+            PolicySession::try_from(session).context("Error getting policy session")?;
+
+        let result = ctx
+            .execute_with_nullauth_session(|ctx| {
+                ctx.policy_secret(
+                    policy_session,
+                    AuthHandle::Endorsement,
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    None,
+                )
+            })
+            .context("Error satisfying endorsement key policy")
+            .and_then(|_| {
+                ctx.execute_with_sessions(
+                    (Some(AuthSession::Password), Some(session), None),
+                    |ctx| {
+                        ctx.activate_credential(
+                            self.attestation_key,
+                            self.endorsement_key,
+                            credential_blob,
+                            encrypted_secret,
+                        )
+                    },
+                )
+                .context("Error activating credential")
+            });
+        ctx.flush_context(SessionHandle::from(session).into())
+            .context("Error flushing policy session")?;
+
+        Ok(result?.to_vec())
+    }
+
+    /// Certifies a key that is loaded in the TPM, returning the marshalled TPMS_ATTEST
+    /// and its signature
+    pub(crate) fn certify(
+        &self,
+        ctx: &mut Context,
+        key: KeyHandle,
+        qualifying_data: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let qualifying_data =
+            Data::try_from(qualifying_data.to_vec()).context("Invalid qualifying data")?;
+        let (attest, signature) = ctx
+            .execute_with_sessions(
+                (
+                    Some(AuthSession::Password),
+                    Some(AuthSession::Password),
+                    None,
+                ),
+                |ctx| {
+                    ctx.certify(
+                        ObjectHandle::from(key),
+                        self.attestation_key,
+                        qualifying_data,
+                        SignatureScheme::Null,
+                    )
+                },
+            )
+            .context("Error certifying key")?;
+
+        let attest = attest.marshall().context("Error marshalling attestation")?;
+        let signature = match signature {
+            Signature::RsaSsa(signature) => signature.signature().value().to_vec(),
+            signature => bail!("Unexpected certification signature: {:?}", signature),
+        };
+        Ok((attest, signature))
+    }
+
+    pub(crate) fn flush(self, ctx: &mut Context) -> Result<()> {
+        ctx.flush_context(self.attestation_key.into())
+            .context("Error flushing attestation key")?;
+        ctx.flush_context(self.endorsement_key.into())
+            .context("Error flushing endorsement key")
+    }
+}
+
+/// Loads a key from its marshalled public area and private blob
+pub(crate) fn load_key(
+    ctx: &mut Context,
+    parent: KeyHandle,
+    public: &[u8],
+    private: &[u8],
+) -> Result<KeyHandle> {
+    let public = Public::unmarshall(public).context("Error unmarshalling public key")?;
+    let private: Private = private.try_into().context("Error converting private key")?;
+    ctx.execute_with_nullauth_session(|ctx| ctx.load(parent, private, public))
+        .context("Error loading key")
+}
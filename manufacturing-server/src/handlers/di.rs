@@ -1,5 +1,7 @@
 use std::convert::TryInto;
 
+use anyhow::{bail, Context};
+
 use crate::{
     ManufacturingServiceUD, ManufacturingServiceUDT, DEVICE_KEY_FROM_DIUN_SES_KEY,
     PERFORMED_DIUN_SES_KEY,
@@ -20,7 +22,7 @@ use openssl::{
     bn::BigNum,
     hash::MessageDigest,
     pkey::{PKey, PKeyRef, Private, Public},
-    x509::{X509Builder, X509NameBuilder, X509},
+    x509::{X509Builder, X509NameBuilder, X509Req, X509},
 };
 
 fn fail_if_no_di_and_not_from_diun<M>(
@@ -91,9 +93,26 @@ pub(crate) async fn app_start(
             )
             .into());
         }
-        Some(v) => PKey::public_key_from_der(&v)
-            .map_err(Error::from_error::<messages::v11::di::AppStart, _>)?,
+        Some(v) => device_public_key(&v).map_err(|e| {
+            log::warn!("Device {}: invalid public key or CSR: {:?}", mfg_info, e);
+            Error::new(
+                ErrorCode::InternalServerError,
+                messages::v11::di::AppStart::message_type(),
+                "Invalid device public key",
+            )
+        })?,
     };
+    if let Some(crypto_policy) = &user_data.crypto_policy {
+        if let Err(violation) = crypto_policy.check_key(&public_key) {
+            log::warn!("Device {}: {}", mfg_info, violation);
+            return Err(Error::new(
+                ErrorCode::InternalServerError,
+                messages::v11::di::AppStart::message_type(),
+                "Device key not allowed",
+            )
+            .into());
+        }
+    }
 
     // Create new device certificate chain
     let device_certificate = create_device_certificate(
@@ -119,9 +138,10 @@ pub(crate) async fn app_start(
             })
         })
         .map_err(Error::from_error::<messages::v11::di::AppStart, _>)?;
-    let device_certificate_chain_hash =
-        Hash::from_data(HashType::Sha384, &device_certificate_chain_serialized)
-            .map_err(Error::from_error::<messages::v11::di::AppStart, _>)?;
+    // The device certificate chain hash follows the strength of the device key
+    let device_certificate_chain_hash = HashType::for_pkey(&public_key)
+        .and_then(|hash_type| Hash::from_data(hash_type, &device_certificate_chain_serialized))
+        .map_err(Error::from_error::<messages::v11::di::AppStart, _>)?;
 
     // Create new ownership voucher header
     let new_voucher_header = OwnershipVoucherHeader::new(
@@ -138,6 +158,11 @@ pub(crate) async fn app_start(
     )
     .map_err(Error::from_error::<messages::v11::di::AppStart, _>)?;
 
+    // Tags the log lines and spans of the next messages with the device
+    session
+        .insert("device_guid", new_voucher_header.guid().to_string())
+        .map_err(Error::from_error::<messages::v11::di::AppStart, _>)?;
+
     // Store the OV Header and device cert chain
     let new_voucher_header_serialized = new_voucher_header
         .serialize_data()
@@ -157,6 +182,24 @@ pub(crate) async fn app_start(
     ))
 }
 
+/// Parses a device public key, or the public key of a PKCS#10 CSR after checking its signature.
+fn device_public_key(data: &[u8]) -> Result<PKey<Public>, anyhow::Error> {
+    if let Ok(key) = PKey::public_key_from_der(data) {
+        return Ok(key);
+    }
+    let csr = X509Req::from_der(data)
+        .or_else(|_| X509Req::from_pem(data))
+        .context("Neither a DER public key nor a PKCS#10 CSR")?;
+    let public_key = csr.public_key().context("Error getting CSR public key")?;
+    if !csr
+        .verify(&public_key)
+        .context("Error verifying CSR signature")?
+    {
+        bail!("CSR signature is invalid");
+    }
+    Ok(public_key)
+}
+
 fn create_device_cert_chain(chain: &X5Chain, device_certificate: X509) -> X5Chain {
     let mut chain: Vec<X509> = chain.chain().to_vec();
     chain.insert(0, device_certificate);
@@ -234,6 +277,18 @@ pub(crate) async fn set_hmac(
         }
     };
 
+    if let Some(crypto_policy) = &user_data.crypto_policy {
+        if let Err(violation) = crypto_policy.check_hash_type(msg.hmac().get_type()) {
+            log::warn!("Device {}: {}", device_guid.to_string(), violation);
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::di::SetHMAC::message_type(),
+                "HMAC type not allowed",
+            )
+            .into());
+        }
+    }
+
     // Create new ownership voucher
     let mut ov = OwnershipVoucher::new(
         ov_header,
@@ -244,11 +299,16 @@ pub(crate) async fn set_hmac(
 
     // If intended, extend with the owner key
     if let Some(manufacturer_key) = user_data.manufacturer_key.as_ref() {
-        ov.extend(
-            manufacturer_key,
-            None,
-            user_data.owner_cert.as_ref().unwrap(),
-        )
+        let owner_cert = user_data.owner_cert.as_ref().unwrap();
+        match manufacturer_key.as_pkey() {
+            Some(key) => ov.extend(key, None, owner_cert),
+            None => {
+                let manufacturer_pubkey = manufacturer_key
+                    .public_key()
+                    .map_err(Error::from_error::<messages::v11::di::SetHMAC, _>)?;
+                ov.extend_with_signer(manufacturer_key, &manufacturer_pubkey, None, owner_cert)
+            }
+        }
         .map_err(Error::from_error::<messages::v11::di::SetHMAC, _>)?;
     }
 
@@ -1,4 +1,5 @@
 use crate::{
+    tpm_attestation::{self, TpmKey},
     ManufacturingServiceUD, ManufacturingServiceUDT, DEVICE_KEY_FROM_DIUN_SES_KEY,
     PERFORMED_DIUN_SES_KEY,
 };
@@ -15,6 +16,8 @@ use fdo_http_wrapper::{
 };
 
 const DIUN_KEYS_SES_KEY: &str = "mfg_diun_keys";
+const ATTESTATION_KEY_SES_KEY: &str = "mfg_diun_attestation_key";
+const ATTESTATION_SECRET_SES_KEY: &str = "mfg_diun_attestation_secret";
 
 fn fail_if_no_diun<M>(user_data: &ManufacturingServiceUD) -> Result<(), warp::Rejection>
 where
@@ -48,6 +51,21 @@ pub(crate) async fn connect(
             false
         };
 
+    if let Some(crypto_policy) = &user_data.crypto_policy {
+        if let Err(violation) = crypto_policy
+            .check_kex_suite(*msg.kex_suite())
+            .and_then(|_| crypto_policy.check_cipher_suite(*msg.cipher_suite()))
+        {
+            log::warn!("DIUN connection rejected: {}", violation);
+            return Err(Error::new(
+                ErrorCode::MessageBodyError,
+                messages::v11::diun::Connect::message_type(),
+                "Key exchange or cipher suite not allowed",
+            )
+            .into());
+        }
+    }
+
     let b_key_exchange = KeyExchange::new(*msg.kex_suite())
         .map_err(Error::from_error::<messages::v11::diun::Connect, _>)?;
 
@@ -163,6 +181,21 @@ pub(crate) async fn provide_key(
 ) -> Result<(messages::v11::diun::Done, RequestInformation), warp::Rejection> {
     fail_if_no_diun::<messages::v11::diun::ProvideKey>(&user_data)?;
 
+    if user_data
+        .diun_configuration
+        .as_ref()
+        .unwrap()
+        .tpm_endorsement_cas
+        .is_some()
+    {
+        return Err(Error::new(
+            ErrorCode::InvalidMessageError,
+            messages::v11::diun::ProvideKey::message_type(),
+            "TPM attestation of the device key is required",
+        )
+        .into());
+    }
+
     let mut session = ses_with_store.session;
 
     // Let's store the key in the session for DI
@@ -187,3 +220,157 @@ pub(crate) async fn provide_key(
         ses_with_store,
     ))
 }
+
+pub(crate) async fn request_attestation_challenge(
+    user_data: ManufacturingServiceUDT,
+    mut ses_with_store: RequestInformation,
+    msg: messages::v11::diun::RequestAttestationChallenge,
+) -> Result<
+    (
+        messages::v11::diun::AttestationChallenge,
+        RequestInformation,
+    ),
+    warp::Rejection,
+> {
+    fail_if_no_diun::<messages::v11::diun::RequestAttestationChallenge>(&user_data)?;
+
+    let endorsement_cas = match &user_data
+        .diun_configuration
+        .as_ref()
+        .unwrap()
+        .tpm_endorsement_cas
+    {
+        Some(endorsement_cas) => endorsement_cas,
+        None => {
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::diun::RequestAttestationChallenge::message_type(),
+                "TPM attestation is not enabled",
+            )
+            .into())
+        }
+    };
+
+    let endorsement_key = tpm_attestation::verify_endorsement_key_certificate(
+        endorsement_cas,
+        msg.endorsement_key_certificate(),
+    )
+    .map_err(|e| {
+        log::warn!("Invalid TPM endorsement key certificate: {:?}", e);
+        Error::new(
+            ErrorCode::InvalidMessageError,
+            messages::v11::diun::RequestAttestationChallenge::message_type(),
+            "Invalid endorsement key certificate",
+        )
+    })?;
+    let attestation_key = TpmKey::parse(msg.attestation_key_public())
+        .and_then(|key| key.check_attestation_key().map(|_| key))
+        .map_err(|e| {
+            log::warn!("Invalid TPM attestation key: {:?}", e);
+            Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::diun::RequestAttestationChallenge::message_type(),
+                "Invalid attestation key",
+            )
+        })?;
+
+    let credential = tpm_attestation::make_credential(&endorsement_key, attestation_key.name())
+        .map_err(|e| {
+            log::error!("Error creating TPM credential: {:?}", e);
+            Error::new(
+                ErrorCode::InternalServerError,
+                messages::v11::diun::RequestAttestationChallenge::message_type(),
+                "Error creating credential",
+            )
+        })?;
+
+    let mut session = ses_with_store.session;
+    session
+        .insert(ATTESTATION_KEY_SES_KEY, msg.attestation_key_public())
+        .map_err(Error::from_error::<messages::v11::diun::RequestAttestationChallenge, _>)?;
+    session
+        .insert(ATTESTATION_SECRET_SES_KEY, &credential.secret)
+        .map_err(Error::from_error::<messages::v11::diun::RequestAttestationChallenge, _>)?;
+    ses_with_store.session = session;
+
+    Ok((
+        messages::v11::diun::AttestationChallenge::new(
+            credential.credential_blob,
+            credential.encrypted_secret,
+        ),
+        ses_with_store,
+    ))
+}
+
+pub(crate) async fn provide_attested_key(
+    user_data: ManufacturingServiceUDT,
+    mut ses_with_store: RequestInformation,
+    msg: messages::v11::diun::ProvideAttestedKey,
+) -> Result<(messages::v11::diun::Done, RequestInformation), warp::Rejection> {
+    fail_if_no_diun::<messages::v11::diun::ProvideAttestedKey>(&user_data)?;
+
+    let mut session = ses_with_store.session;
+
+    let attestation_key: Option<Vec<u8>> = session.get(ATTESTATION_KEY_SES_KEY);
+    let secret: Option<Vec<u8>> = session.get(ATTESTATION_SECRET_SES_KEY);
+    let (attestation_key, secret) = match (attestation_key, secret) {
+        (Some(attestation_key), Some(secret)) => (attestation_key, secret),
+        _ => {
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::diun::ProvideAttestedKey::message_type(),
+                "Sequence error: no attestation challenge",
+            )
+            .into())
+        }
+    };
+    session.remove(ATTESTATION_KEY_SES_KEY);
+    session.remove(ATTESTATION_SECRET_SES_KEY);
+
+    let attestation_key = TpmKey::parse(&attestation_key)
+        .map_err(Error::from_error::<messages::v11::diun::ProvideAttestedKey, _>)?;
+    let device_key = TpmKey::parse(msg.public_key())
+        .and_then(|key| key.check_device_key().map(|_| key))
+        .and_then(|key| {
+            tpm_attestation::verify_certification(
+                attestation_key.pkey(),
+                key.name(),
+                &secret,
+                msg.certify_info(),
+                msg.certify_signature(),
+            )
+            .map(|_| key)
+        })
+        .map_err(|e| {
+            log::warn!("Device key attestation failed: {:?}", e);
+            Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::diun::ProvideAttestedKey::message_type(),
+                "Device key attestation failed",
+            )
+        })?;
+    let public_key = device_key
+        .pkey()
+        .public_key_to_der()
+        .map_err(Error::from_error::<messages::v11::diun::ProvideAttestedKey, _>)?;
+
+    // From here on, this is the same as a device key provided through ProvideKey
+    session
+        .insert(DEVICE_KEY_FROM_DIUN_SES_KEY, public_key)
+        .map_err(Error::from_error::<messages::v11::diun::ProvideAttestedKey, _>)?;
+    session
+        .insert(PERFORMED_DIUN_SES_KEY, true)
+        .map_err(Error::from_error::<messages::v11::diun::ProvideAttestedKey, _>)?;
+
+    ses_with_store.session = session;
+    Ok((
+        messages::v11::diun::Done::new(
+            user_data
+                .diun_configuration
+                .as_ref()
+                .unwrap()
+                .mfg_string_type,
+        ),
+        ses_with_store,
+    ))
+}
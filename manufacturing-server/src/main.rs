@@ -1,13 +1,14 @@
 use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
 use std::fs;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Error, Result};
 use openssl::{
     pkey::{PKey, Private},
-    x509::X509,
+    x509::{store::X509Store, X509},
 };
 use serde_yaml::Value;
 use tokio::signal::unix::{signal, SignalKind};
@@ -21,15 +22,21 @@ use fdo_data_formats::{
     ProtocolVersion,
 };
 use fdo_store::Store;
+use fdo_util::crypto_policy::CryptoPolicy;
 use fdo_util::servers::{
-    configuration::manufacturing_server::{DiunSettings, ManufacturingServerSettings},
-    settings_for, yaml_to_cbor, OwnershipVoucherStoreMetadataKey,
+    configuration::manufacturing_server::{
+        DiunSettings, ManufacturingServerSettings, ManufacturingSettings,
+    },
+    configuration::AbsolutePathBuf,
+    load_settings, yaml_to_cbor, OwnershipVoucherStoreMetadataKey,
 };
+use fdo_util::signing::SigningKey;
 
 const PERFORMED_DIUN_SES_KEY: &str = "mfg_global_diun_performed";
 const DEVICE_KEY_FROM_DIUN_SES_KEY: &str = "mfg_global_device_key_from_diun";
 
 mod handlers;
+mod tpm_attestation;
 
 struct DiunConfiguration {
     mfg_string_type: MfgStringType,
@@ -39,6 +46,8 @@ struct DiunConfiguration {
 
     key: PKey<Private>,
     public_keys: PublicKey,
+
+    tpm_endorsement_cas: Option<X509Store>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -67,11 +76,14 @@ struct ManufacturingServiceUD {
 
     // Certificates
     manufacturer_cert: X509,
-    manufacturer_key: Option<PKey<Private>>,
+    manufacturer_key: Option<SigningKey>,
     device_cert_key: PKey<Private>,
     device_cert_chain: X5Chain,
     owner_cert: Option<PublicKey>,
 
+    // Allowed algorithms
+    crypto_policy: Option<CryptoPolicy>,
+
     // Rendezvous Info
     rendezvous_info: RendezvousInfo,
 
@@ -100,17 +112,33 @@ impl TryFrom<DiunSettings> for DiunConfiguration {
         .try_into()
         .context("Error generating PublicKey")?;
 
+        let allowed_key_storage_types: Vec<KeyStorageType> = value
+            .allowed_key_storage_types
+            .iter()
+            .map(|x| KeyStorageType::from(*x))
+            .collect();
+        let tpm_endorsement_cas = match value.tpm_endorsement_ca_certs_path {
+            None => None,
+            Some(path) => {
+                if !allowed_key_storage_types.contains(&KeyStorageType::Tpm) {
+                    bail!("TPM attestation requires the Tpm key storage type to be allowed");
+                }
+                Some(
+                    tpm_attestation::load_endorsement_cas(path.as_ref())
+                        .context("Error loading TPM endorsement CAs")?,
+                )
+            }
+        };
+
         Ok(DiunConfiguration {
             mfg_string_type: value.mfg_string_type.into(),
             key_type: value.key_type.into(),
-            allowed_key_storage_types: value
-                .allowed_key_storage_types
-                .iter()
-                .map(|x| KeyStorageType::from(*x))
-                .collect(),
+            allowed_key_storage_types,
 
             key,
             public_keys,
+
+            tpm_endorsement_cas,
         })
     }
 }
@@ -165,24 +193,58 @@ async fn perform_maintenance(
     }
 }
 
+fn key_path(path: &AbsolutePathBuf) -> Result<&str> {
+    let path: &Path = path.as_ref();
+    path.to_str()
+        .with_context(|| format!("Key path {path:?} is not valid UTF-8"))
+}
+
+// Device certificates are signed with OpenSSL, so the device CA key has to be held in memory
+fn load_device_cert_key(settings: &ManufacturingSettings) -> Result<PKey<Private>> {
+    match SigningKey::load(key_path(&settings.device_cert_ca_private_key)?)? {
+        SigningKey::Pkey(key) => Ok(key),
+        _ => bail!("The device CA private key must be a private key file"),
+    }
+}
+
+fn load_manufacturer_key(settings: &ManufacturingSettings) -> Result<Option<SigningKey>> {
+    match (
+        &settings.manufacturer_private_key,
+        &settings.manufacturer_private_key_uri,
+    ) {
+        (Some(path), None) => Ok(Some(SigningKey::load(key_path(path)?)?)),
+        (None, Some(uri)) => Ok(Some(SigningKey::load(uri)?)),
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => bail!(
+            "Only one of manufacturer_private_key and manufacturer_private_key_uri may be configured"
+        ),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     fdo_util::add_version!();
     fdo_http_wrapper::init_logging();
 
-    let settings: ManufacturingServerSettings = settings_for("manufacturing-server")?
-        .try_deserialize()
-        .context("Error parsing configuration")?;
+    let settings: ManufacturingServerSettings = load_settings("manufacturing-server")?;
 
     // Bind information
     let bind_addr = settings.bind.clone();
+    let drain_timeout = std::time::Duration::from_secs(settings.drain_timeout_seconds);
 
     // Initialize stores
     let session_store = settings
         .session_store_driver
         .initialize()
         .context("Error initializing session store")?;
-    let session_store = fdo_http_wrapper::server::SessionStore::new(session_store);
+    let session_store_encryption_key = settings
+        .session_store_encryption_key_path
+        .as_ref()
+        .map(|path| fdo_http_wrapper::server::SessionEncryptionKey::load(path.as_ref()))
+        .transpose()
+        .context("Error loading session store encryption key")?;
+    let session_store =
+        fdo_http_wrapper::server::SessionStore::new(session_store, session_store_encryption_key);
     let ownership_voucher_store = settings
         .ownership_voucher_store_driver
         .initialize()
@@ -197,11 +259,10 @@ async fn main() -> Result<()> {
     };
 
     // Read keys and certificates
-    let device_cert_key = PKey::private_key_from_der(
-        &fs::read(settings.manufacturing.device_cert_ca_private_key)
-            .context("Error reading device CA private key")?,
-    )
-    .context("Error parsing device CA private key")?;
+    let device_cert_key = load_device_cert_key(&settings.manufacturing)
+        .context("Error loading device CA private key")?;
+    let manufacturer_key = load_manufacturer_key(&settings.manufacturing)
+        .context("Error loading manufacturer private key")?;
     let device_cert_chain = X5Chain::new(
         X509::stack_from_pem(
             &fs::read(settings.manufacturing.device_cert_ca_chain)
@@ -216,15 +277,6 @@ async fn main() -> Result<()> {
     )
     .context("Error parsing manufacturer certificate")?;
 
-    let manufacturer_key = match settings.manufacturing.manufacturer_private_key {
-        None => None,
-        Some(path) => Some(
-            PKey::private_key_from_der(
-                &fs::read(path).context("Error reading manufacturer private key")?,
-            )
-            .context("Error parsing manufacturer private key")?,
-        ),
-    };
     let owner_cert = match settings.manufacturing.owner_cert_path {
         None => None,
         Some(path) => Some(
@@ -244,6 +296,32 @@ async fn main() -> Result<()> {
         Some(v) => Some(v.try_into().context("Error parsing DIUN configuration")?),
     };
 
+    // Our own keys and certificates must comply with the crypto policy
+    let crypto_policy = settings
+        .crypto_policy
+        .as_ref()
+        .map(CryptoPolicy::from_settings)
+        .transpose()
+        .context("Error loading crypto policy")?;
+    if let Some(crypto_policy) = &crypto_policy {
+        crypto_policy
+            .check_certificate(&manufacturer_cert)
+            .context("Manufacturer certificate does not comply with the crypto policy")?;
+        crypto_policy
+            .check_certificates(device_cert_chain.chain())
+            .context("Device CA chain does not comply with the crypto policy")?;
+        if let Some(owner_cert) = &owner_cert {
+            crypto_policy
+                .check_public_key(owner_cert)
+                .context("Owner certificate does not comply with the crypto policy")?;
+        }
+        if let Some(diun_configuration) = &diun_configuration {
+            crypto_policy
+                .check_public_key(&diun_configuration.public_keys)
+                .context("DIUN certificate does not comply with the crypto policy")?;
+        }
+    }
+
     let rendezvous_info = load_rendezvous_info(&settings.rendezvous_info)
         .context("Error processing rendezvous info")?;
 
@@ -260,6 +338,8 @@ async fn main() -> Result<()> {
         manufacturer_key,
         owner_cert,
 
+        crypto_policy,
+
         rendezvous_info,
 
         enable_di: settings.protocols.plain_di.unwrap_or(false),
@@ -303,6 +383,18 @@ async fn main() -> Result<()> {
         session_store.clone(),
         handlers::diun::provide_key,
     );
+    let handler_diun_request_attestation_challenge = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_1,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::diun::request_attestation_challenge,
+    );
+    let handler_diun_provide_attested_key = fdo_http_wrapper::server::fdo_request_filter(
+        ProtocolVersion::Version1_1,
+        user_data.clone(),
+        session_store.clone(),
+        handlers::diun::provide_attested_key,
+    );
 
     let routes = warp::post()
         .and(
@@ -314,23 +406,30 @@ async fn main() -> Result<()> {
                 // DIUN
                 .or(handler_diun_connect)
                 .or(handler_diun_request_key_parameters)
-                .or(handler_diun_provide_key),
+                .or(handler_diun_provide_key)
+                .or(handler_diun_request_attestation_challenge)
+                .or(handler_diun_provide_attested_key),
         )
         .recover(fdo_http_wrapper::server::handle_rejection)
         .with(warp::log("manufacturing-server"));
 
     log::info!("Listening on {}", bind_addr);
-    let server = warp::serve(routes);
+    // On SIGTERM, let the sessions in progress finish before no longer accepting connections
+    let draining_session_store = user_data.session_store.clone();
+    let server = fdo_http_wrapper::server::serve_with_graceful_shutdown(
+        routes,
+        bind_addr.into(),
+        async move {
+            signal(SignalKind::terminate()).unwrap().recv().await;
+            log::info!("Terminating, draining sessions in progress");
+            draining_session_store.drain(drain_timeout).await;
+        },
+    )
+    .context("Error binding server")?;
 
     let maintenance_runner =
         tokio::spawn(async move { perform_maintenance(user_data.clone()).await });
 
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
-            signal(SignalKind::terminate()).unwrap().recv().await;
-            log::info!("Terminating");
-        })
-        .1;
     let server = tokio::spawn(server);
 
     tokio::select!(
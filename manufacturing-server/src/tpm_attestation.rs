@@ -0,0 +1,600 @@
+//! Verification of TPM-resident device keys during DIUN.
+//!
+//! The device sends its endorsement key (EK) certificate and the public area of an
+//! attestation key (AK). We check the EK certificate against the configured TPM
+//! manufacturer CAs, and challenge the device with a credential bound to both keys
+//! (TPM2_MakeCredential). Only the TPM holding the EK and the AK can activate it. The
+//! device then certifies its device key with the AK (TPM2_Certify), with the activated
+//! credential as qualifying data, proving that the device key lives in that same TPM.
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use openssl::{
+    encrypt::Encrypter,
+    hash::{hash, MessageDigest},
+    pkey::{PKey, PKeyRef, Public},
+    rsa::Padding,
+    sign::{Signer, Verifier},
+    stack::Stack,
+    symm::Cipher,
+    x509::{
+        store::{X509Store, X509StoreBuilder, X509StoreRef},
+        verify::X509VerifyFlags,
+        X509StoreContext, X509,
+    },
+};
+use tss_esapi::{structures::Public as TpmPublic, traits::UnMarshall};
+
+use fdo_data_formats::{devicecredential::file::tpm_public_key, secret::constant_time_eq};
+
+const TPM_ALG_SHA256: [u8; 2] = [0x00, 0x0b];
+const TPM_GENERATED_VALUE: u32 = 0xff54_4347;
+const TPM_ST_ATTEST_CERTIFY: u16 = 0x8017;
+
+// The default (low range) RSA 2048 EK template uses SHA256 as name algorithm and
+// AES-128-CFB as symmetric algorithm, which determine the MakeCredential parameters
+const EK_RSA_BITS: u32 = 2048;
+const EK_SYMMETRIC_KEY_BITS: u32 = 128;
+const CREDENTIAL_SECRET_LEN: usize = 32;
+
+/// Loads the CAs that issue the endorsement key certificates of trusted TPMs.
+///
+/// Every certificate in the file is a trust anchor, so intermediate CAs can be listed
+/// without their roots.
+pub(crate) fn load_endorsement_cas(path: &Path) -> Result<X509Store> {
+    let certs = X509::stack_from_pem(&fs::read(path).context("Error reading TPM endorsement CAs")?)
+        .context("Error parsing TPM endorsement CAs")?;
+    if certs.is_empty() {
+        bail!("No TPM endorsement CA certificates found");
+    }
+    let mut builder = X509StoreBuilder::new()?;
+    for cert in certs {
+        builder.add_cert(cert)?;
+    }
+    builder.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+    Ok(builder.build())
+}
+
+/// Verifies an endorsement key certificate, returning the endorsement public key
+pub(crate) fn verify_endorsement_key_certificate(
+    endorsement_cas: &X509StoreRef,
+    certificate: &[u8],
+) -> Result<PKey<Public>> {
+    let certificate =
+        X509::from_der(certificate).context("Error parsing endorsement key certificate")?;
+
+    let mut context = X509StoreContext::new()?;
+    let untrusted = Stack::new()?;
+    let verify_error = context.init(endorsement_cas, &certificate, &untrusted, |context| {
+        Ok(if context.verify_cert()? {
+            None
+        } else {
+            Some(context.error())
+        })
+    })?;
+    if let Some(verify_error) = verify_error {
+        bail!(
+            "Endorsement key certificate is not issued by a trusted CA: {}",
+            verify_error
+        );
+    }
+
+    let key = certificate
+        .public_key()
+        .context("Error getting endorsement public key")?;
+    if key.rsa().is_err() || key.bits() != EK_RSA_BITS {
+        bail!("Only RSA 2048 endorsement keys are supported");
+    }
+    Ok(key)
+}
+
+/// A TPM key, as sent by the device in marshalled TPMT_PUBLIC form
+pub(crate) struct TpmKey {
+    public: TpmPublic,
+    pkey: PKey<Public>,
+    name: Vec<u8>,
+}
+
+impl TpmKey {
+    pub(crate) fn parse(data: &[u8]) -> Result<Self> {
+        let public = TpmPublic::unmarshall(data).context("Error parsing TPM public area")?;
+        let pkey = tpm_public_key(&public).context("Unsupported TPM key")?;
+
+        // The name of a key is its name algorithm followed by the digest of its public area
+        if data.get(2..4) != Some(&TPM_ALG_SHA256[..]) {
+            bail!("Unsupported TPM key name algorithm");
+        }
+        let mut name = TPM_ALG_SHA256.to_vec();
+        name.extend_from_slice(&hash(MessageDigest::sha256(), data)?);
+
+        Ok(TpmKey { public, pkey, name })
+    }
+
+    pub(crate) fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub(crate) fn pkey(&self) -> &PKey<Public> {
+        &self.pkey
+    }
+
+    /// Checks that the key is a restricted signing key that cannot leave the TPM
+    pub(crate) fn check_attestation_key(&self) -> Result<()> {
+        let attributes = self.public.object_attributes();
+        if !attributes.fixed_tpm() || !attributes.fixed_parent() {
+            bail!("Attestation key can be duplicated");
+        }
+        if !attributes.restricted() || !attributes.sign_encrypt() || attributes.decrypt() {
+            bail!("Attestation key is not a restricted signing key");
+        }
+        Ok(())
+    }
+
+    /// Checks that the key is a signing key that was generated by the TPM and cannot leave it
+    pub(crate) fn check_device_key(&self) -> Result<()> {
+        let attributes = self.public.object_attributes();
+        if !attributes.fixed_tpm() || !attributes.fixed_parent() {
+            bail!("Device key can be duplicated");
+        }
+        if !attributes.sensitive_data_origin() {
+            bail!("Device key was not generated by the TPM");
+        }
+        if !attributes.sign_encrypt() {
+            bail!("Device key is not a signing key");
+        }
+        Ok(())
+    }
+}
+
+/// A credential bound to an endorsement key and an attestation key
+pub(crate) struct Credential {
+    pub(crate) secret: Vec<u8>,
+    pub(crate) credential_blob: Vec<u8>,
+    pub(crate) encrypted_secret: Vec<u8>,
+}
+
+/// KDFa from the TPM 2.0 specification (part 1, section 11.4.10.2), with SHA256
+fn kdfa(
+    key: &[u8],
+    label: &[u8],
+    context_u: &[u8],
+    context_v: &[u8],
+    bits: u32,
+) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let len = (bits as usize + 7) / 8;
+    let mut out = Vec::with_capacity(len);
+    let mut counter: u32 = 0;
+    while out.len() < len {
+        counter += 1;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(&counter.to_be_bytes())?;
+        signer.update(label)?;
+        signer.update(&[0])?;
+        signer.update(context_u)?;
+        signer.update(context_v)?;
+        signer.update(&bits.to_be_bytes())?;
+        out.extend(signer.sign_to_vec()?);
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+/// Performs TPM2_MakeCredential in software (TPM 2.0 specification, part 1, section 24)
+pub(crate) fn make_credential(
+    endorsement_key: &PKey<Public>,
+    attestation_key_name: &[u8],
+) -> Result<Credential> {
+    let mut secret = vec![0; CREDENTIAL_SECRET_LEN];
+    openssl::rand::rand_bytes(&mut secret)?;
+    let mut seed = vec![0; MessageDigest::sha256().size()];
+    openssl::rand::rand_bytes(&mut seed)?;
+    make_credential_with_seed(endorsement_key, attestation_key_name, secret, &seed)
+}
+
+fn make_credential_with_seed(
+    endorsement_key: &PKey<Public>,
+    attestation_key_name: &[u8],
+    secret: Vec<u8>,
+    seed: &[u8],
+) -> Result<Credential> {
+    let mut encrypter = Encrypter::new(endorsement_key)?;
+    encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+    encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+    encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+    encrypter.set_rsa_oaep_label(b"IDENTITY\0")?;
+    let mut encrypted_secret = vec![0; encrypter.encrypt_len(seed)?];
+    let encrypted_len = encrypter.encrypt(seed, &mut encrypted_secret)?;
+    encrypted_secret.truncate(encrypted_len);
+
+    // The secret is encrypted as a TPM2B_DIGEST
+    let mut plain_identity = (secret.len() as u16).to_be_bytes().to_vec();
+    plain_identity.extend_from_slice(&secret);
+    let symmetric_key = kdfa(
+        seed,
+        b"STORAGE",
+        attestation_key_name,
+        &[],
+        EK_SYMMETRIC_KEY_BITS,
+    )?;
+    let encrypted_identity = openssl::symm::encrypt(
+        Cipher::aes_128_cfb128(),
+        &symmetric_key,
+        Some(&[0; 16]),
+        &plain_identity,
+    )?;
+
+    let hmac_key = kdfa(
+        seed,
+        b"INTEGRITY",
+        &[],
+        &[],
+        MessageDigest::sha256().size() as u32 * 8,
+    )?;
+    let hmac_key = PKey::hmac(&hmac_key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &hmac_key)?;
+    signer.update(&encrypted_identity)?;
+    signer.update(attestation_key_name)?;
+    let integrity = signer.sign_to_vec()?;
+
+    let mut credential_blob = (integrity.len() as u16).to_be_bytes().to_vec();
+    credential_blob.extend_from_slice(&integrity);
+    credential_blob.extend_from_slice(&encrypted_identity);
+
+    Ok(Credential {
+        secret,
+        credential_blob,
+        encrypted_secret,
+    })
+}
+
+struct AttestReader<'a>(&'a [u8]);
+
+impl<'a> AttestReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            bail!("Certification information is truncated");
+        }
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn sized_buffer(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+}
+
+/// Verifies that the attestation key certified the device key, identified by its name,
+/// with the credential secret as qualifying data
+pub(crate) fn verify_certification(
+    attestation_key: &PKeyRef<Public>,
+    device_key_name: &[u8],
+    secret: &[u8],
+    certify_info: &[u8],
+    certify_signature: &[u8],
+) -> Result<()> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), attestation_key)?;
+    verifier.update(certify_info)?;
+    if !verifier
+        .verify(certify_signature)
+        .context("Error verifying certification signature")?
+    {
+        bail!("Certification signature is invalid");
+    }
+
+    // TPMS_ATTEST
+    let mut reader = AttestReader(certify_info);
+    if reader.u32()? != TPM_GENERATED_VALUE {
+        bail!("Certification information was not generated by a TPM");
+    }
+    if reader.u16()? != TPM_ST_ATTEST_CERTIFY {
+        bail!("Attestation is not a key certification");
+    }
+    let _qualified_signer = reader.sized_buffer()?;
+    let extra_data = reader.sized_buffer()?;
+    // clockInfo (17 bytes) and firmwareVersion (8 bytes)
+    reader.take(17 + 8)?;
+    // TPMS_CERTIFY_INFO
+    let certified_name = reader.sized_buffer()?;
+
+    if !constant_time_eq(extra_data, secret) {
+        bail!("Certification does not use the activated credential");
+    }
+    if certified_name != device_key_name {
+        bail!("Certification is not for the device key");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        encrypt::Decrypter,
+        nid::Nid,
+        pkey::Private,
+        rsa::Rsa,
+        symm::decrypt,
+    };
+
+    use super::*;
+
+    // The TPM 2.0 specification doesn't come with KDFa test vectors, this is the SHA256 one
+    // from go-tpm (legacy/tpm2/kdf_test.go)
+    #[test]
+    fn test_kdfa_known_answer() {
+        let out = kdfa(b"yolo\0", b"IDENTITY", b"kek\0", b"yoyo\0", 128).unwrap();
+        assert_eq!(hex::encode(out), "d2d72cc7a8a5eb09e8c79012e2da9f22");
+    }
+
+    #[test]
+    fn test_kdfa_multiple_blocks() {
+        // Three HMAC blocks, truncated to 520 bits
+        let mut name = TPM_ALG_SHA256.to_vec();
+        name.extend_from_slice(&[0; 32]);
+        let seed: Vec<u8> = (0..32).collect();
+        let out = kdfa(&seed, b"STORAGE", &name, &[], 520).unwrap();
+        assert_eq!(
+            hex::encode(out),
+            "416f1d19c086568ae8c67e7b75119071775bb6658e71b81b6d74e141a8679ece\
+             155c333a9b935d31cda491b36ded7e81fb5e360b5f76c81785d003f3db9ff9da\
+             cc"
+        );
+    }
+
+    fn attestation_key_name() -> Vec<u8> {
+        let mut name = TPM_ALG_SHA256.to_vec();
+        name.extend_from_slice(&hash(MessageDigest::sha256(), b"attestation key").unwrap());
+        name
+    }
+
+    fn generate_endorsement_key() -> (PKey<Private>, PKey<Public>) {
+        let private = PKey::from_rsa(Rsa::generate(EK_RSA_BITS).unwrap()).unwrap();
+        let public = PKey::public_key_from_der(&private.public_key_to_der().unwrap()).unwrap();
+        (private, public)
+    }
+
+    #[test]
+    fn test_make_credential_known_answer() {
+        let (_, endorsement_key) = generate_endorsement_key();
+        let seed: Vec<u8> = (0..32).collect();
+        let secret: Vec<u8> = (0x20..0x40).collect();
+
+        let credential = make_credential_with_seed(
+            &endorsement_key,
+            &attestation_key_name(),
+            secret.clone(),
+            &seed,
+        )
+        .unwrap();
+        assert_eq!(credential.secret, secret);
+        // TPM2B_DIGEST integrity HMAC, followed by the encrypted TPM2B_DIGEST secret
+        assert_eq!(
+            hex::encode(&credential.credential_blob),
+            "00206006a857fea4daab036101589eda86761ebd45fdfacf53d39647ad75b98b\
+             bac5a91e265838773d6152955f40781485113bfa7c40ba95afebf73a9ff0a31b\
+             f7b48f34"
+        );
+    }
+
+    /// TPM2_ActivateCredential (TPM 2.0 specification, part 1, section 24), as done by the
+    /// TPM holding the endorsement key
+    fn activate_credential(
+        endorsement_key: &PKey<Private>,
+        attestation_key_name: &[u8],
+        credential: &Credential,
+    ) -> Result<Vec<u8>> {
+        let mut decrypter = Decrypter::new(endorsement_key)?;
+        decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+        decrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+        decrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+        decrypter.set_rsa_oaep_label(b"IDENTITY\0")?;
+        let mut seed = vec![0; decrypter.decrypt_len(&credential.encrypted_secret)?];
+        let seed_len = decrypter.decrypt(&credential.encrypted_secret, &mut seed)?;
+        seed.truncate(seed_len);
+
+        let mut blob = AttestReader(&credential.credential_blob);
+        let integrity = blob.sized_buffer()?;
+        let encrypted_identity = blob.0;
+
+        let hmac_key = PKey::hmac(&kdfa(&seed, b"INTEGRITY", &[], &[], 256)?)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &hmac_key)?;
+        signer.update(encrypted_identity)?;
+        signer.update(attestation_key_name)?;
+        if signer.sign_to_vec()? != integrity {
+            bail!("Credential integrity check failed");
+        }
+
+        let symmetric_key = kdfa(&seed, b"STORAGE", attestation_key_name, &[], 128)?;
+        let identity = decrypt(
+            Cipher::aes_128_cfb128(),
+            &symmetric_key,
+            Some(&[0; 16]),
+            encrypted_identity,
+        )?;
+        Ok(AttestReader(&identity).sized_buffer()?.to_vec())
+    }
+
+    #[test]
+    fn test_make_credential_activates() {
+        let (endorsement_private_key, endorsement_key) = generate_endorsement_key();
+        let name = attestation_key_name();
+
+        let credential = make_credential(&endorsement_key, &name).unwrap();
+        assert_eq!(credential.secret.len(), CREDENTIAL_SECRET_LEN);
+        assert_eq!(
+            activate_credential(&endorsement_private_key, &name, &credential).unwrap(),
+            credential.secret
+        );
+
+        // The credential is bound to the attestation key
+        let mut other_name = name.clone();
+        other_name[2] ^= 1;
+        assert!(activate_credential(&endorsement_private_key, &other_name, &credential).is_err());
+
+        // and to the endorsement key
+        let (other_private_key, _) = generate_endorsement_key();
+        assert!(activate_credential(&other_private_key, &name, &credential).is_err());
+    }
+
+    const DEVICE_KEY_NAME: &[u8] = &[0x00, 0x0b, 0x01, 0x02, 0x03];
+
+    /// Builds a TPMS_ATTEST for TPM2_Certify
+    fn certify_info(magic: u32, extra_data: &[u8], certified_name: &[u8]) -> Vec<u8> {
+        let mut info = magic.to_be_bytes().to_vec();
+        info.extend_from_slice(&TPM_ST_ATTEST_CERTIFY.to_be_bytes());
+        // qualifiedSigner
+        info.extend_from_slice(&[0, 2, 0x00, 0x0b]);
+        info.extend_from_slice(&(extra_data.len() as u16).to_be_bytes());
+        info.extend_from_slice(extra_data);
+        // clockInfo and firmwareVersion
+        info.extend_from_slice(&[0; 17 + 8]);
+        info.extend_from_slice(&(certified_name.len() as u16).to_be_bytes());
+        info.extend_from_slice(certified_name);
+        // qualifiedName
+        info.extend_from_slice(&[0, 0]);
+        info
+    }
+
+    fn sign(key: &PKey<Private>, data: &[u8]) -> Vec<u8> {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(data).unwrap();
+        signer.sign_to_vec().unwrap()
+    }
+
+    fn attestation_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn public(key: &PKey<Private>) -> PKey<Public> {
+        PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap()
+    }
+
+    fn assert_error(result: Result<()>, expected: &str) {
+        assert_eq!(result.unwrap_err().to_string(), expected);
+    }
+
+    #[test]
+    fn test_verify_certification() {
+        let key = attestation_key();
+        let secret = [0x42; CREDENTIAL_SECRET_LEN];
+        let info = certify_info(TPM_GENERATED_VALUE, &secret, DEVICE_KEY_NAME);
+
+        verify_certification(
+            &public(&key),
+            DEVICE_KEY_NAME,
+            &secret,
+            &info,
+            &sign(&key, &info),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_verify_certification_bad_signature() {
+        let key = attestation_key();
+        let secret = [0x42; CREDENTIAL_SECRET_LEN];
+        let info = certify_info(TPM_GENERATED_VALUE, &secret, DEVICE_KEY_NAME);
+        let signature = sign(&key, &info);
+
+        // Signed by another key
+        let other_signature = sign(&attestation_key(), &info);
+        assert_error(
+            verify_certification(
+                &public(&key),
+                DEVICE_KEY_NAME,
+                &secret,
+                &info,
+                &other_signature,
+            ),
+            "Certification signature is invalid",
+        );
+
+        // Signature over other certification information
+        let mut tampered = info.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_error(
+            verify_certification(
+                &public(&key),
+                DEVICE_KEY_NAME,
+                &secret,
+                &tampered,
+                &signature,
+            ),
+            "Certification signature is invalid",
+        );
+
+        // Garbage signature
+        assert!(verify_certification(
+            &public(&key),
+            DEVICE_KEY_NAME,
+            &secret,
+            &info,
+            &[0x30, 0x00],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_certification_bad_qualifying_data() {
+        let key = attestation_key();
+        let secret = [0x42; CREDENTIAL_SECRET_LEN];
+
+        for extra_data in [&[0x43; CREDENTIAL_SECRET_LEN][..], &secret[1..], &[]] {
+            let info = certify_info(TPM_GENERATED_VALUE, extra_data, DEVICE_KEY_NAME);
+            assert_error(
+                verify_certification(
+                    &public(&key),
+                    DEVICE_KEY_NAME,
+                    &secret,
+                    &info,
+                    &sign(&key, &info),
+                ),
+                "Certification does not use the activated credential",
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_certification_bad_attestation() {
+        let key = attestation_key();
+        let secret = [0x42; CREDENTIAL_SECRET_LEN];
+        let verify = |info: &[u8]| {
+            verify_certification(
+                &public(&key),
+                DEVICE_KEY_NAME,
+                &secret,
+                info,
+                &sign(&key, info),
+            )
+        };
+
+        assert_error(
+            verify(&certify_info(0, &secret, DEVICE_KEY_NAME)),
+            "Certification information was not generated by a TPM",
+        );
+        assert_error(
+            verify(&certify_info(TPM_GENERATED_VALUE, &secret, &[0x00, 0x0b])),
+            "Certification is not for the device key",
+        );
+        let info = certify_info(TPM_GENERATED_VALUE, &secret, DEVICE_KEY_NAME);
+        assert_error(
+            verify(&info[..info.len() - DEVICE_KEY_NAME.len() - 2]),
+            "Certification information is truncated",
+        );
+    }
+}
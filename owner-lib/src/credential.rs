@@ -0,0 +1,83 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Error};
+
+use fdo_data_formats::devicecredential::{file::FILE_FORMAT_VERSION, FileDeviceCredential};
+
+/// Loads a device credential file, in any supported file format version
+pub fn load_device_credential<P: AsRef<Path>>(path: P) -> Result<FileDeviceCredential, Error> {
+    let contents = fs::read(path).context("Error reading device credential")?;
+    FileDeviceCredential::from_file_data(&contents).context("Error deserializing device credential")
+}
+
+/// Writes a device credential in the current file format version.
+///
+/// It's written to a temporary file first, so a failure can't leave a truncated credential
+/// behind.
+pub fn write_device_credential<P: AsRef<Path>>(
+    dc: &FileDeviceCredential,
+    path: P,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let dc = dc
+        .to_file_data()
+        .context("Error serializing device credential")?;
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, dc).with_context(|| format!("Error writing to {tmp_path:?}"))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Error renaming to {}", path.display()))
+}
+
+/// Outcome of [`migrate_device_credential`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Migration {
+    /// The credential was already at the current file format version
+    UpToDate,
+    /// The credential was migrated from this version, `None` being the legacy format
+    Migrated { from_version: Option<u16> },
+}
+
+/// Upgrades a device credential file to the current file format version.
+///
+/// The migrated credential replaces the original, unless `output` is given, in which case
+/// it must not exist yet.
+pub fn migrate_device_credential<P: AsRef<Path>>(
+    path: P,
+    output: Option<P>,
+) -> Result<Migration, Error> {
+    let path = path.as_ref();
+    let contents = fs::read(path).context("Error reading device credential")?;
+    let version = FileDeviceCredential::file_format_version(&contents)
+        .context("Error determining device credential file format version")?;
+    let dc = FileDeviceCredential::from_file_data(&contents)
+        .context("Error deserializing device credential")?;
+
+    let output = match &output {
+        Some(output) => {
+            let output = output.as_ref();
+            if output.exists() {
+                bail!("Output file {} already exists", output.display());
+            }
+            output
+        }
+        None if version == Some(FILE_FORMAT_VERSION) => return Ok(Migration::UpToDate),
+        None => path,
+    };
+
+    write_device_credential(&dc, output)?;
+    Ok(Migration::Migrated {
+        from_version: version,
+    })
+}
+
+/// Marks a device credential as active or inactive, returning whether it changed
+pub fn set_device_credential_active<P: AsRef<Path>>(path: P, active: bool) -> Result<bool, Error> {
+    let path = path.as_ref();
+    let mut dc = load_device_credential(path)?;
+    if dc.active == active {
+        return Ok(false);
+    }
+    dc.active = active;
+    write_device_credential(&dc, path)?;
+    Ok(true)
+}
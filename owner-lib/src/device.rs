@@ -0,0 +1,616 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{bail, Context, Error, Result};
+use openssl::{
+    asn1::{Asn1Integer, Asn1Time},
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, PKeyRef, Private, Public},
+    rand::rand_bytes,
+    sign::Signer,
+    x509::{
+        extension::{ExtendedKeyUsage, KeyUsage, SubjectAlternativeName},
+        X509Builder, X509Name, X509NameBuilder, X509NameRef, X509Req, X509VerifyResult, X509,
+    },
+};
+use serde::Deserialize;
+
+use fdo_data_formats::{
+    constants::{HashType, PublicKeyType},
+    devicecredential::{file::KeyStorage, FileDeviceCredential},
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    publickey::{PublicKey, X5Chain},
+    secret::Secret,
+    types::{Guid, HMac, RendezvousInfo},
+    ProtocolVersion, Serializable,
+};
+
+use crate::{keys::describe_name, voucher::hash_device_cert_chain};
+
+/// Digest used to sign device certificates
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum CertDigest {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl From<CertDigest> for MessageDigest {
+    fn from(digest: CertDigest) -> Self {
+        match digest {
+            CertDigest::Sha256 => MessageDigest::sha256(),
+            CertDigest::Sha384 => MessageDigest::sha384(),
+            CertDigest::Sha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// Settings for the device certificates created by [`create_device`]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceCertProfile {
+    #[serde(default = "default_device_cert_validity_days")]
+    pub validity_days: u32,
+    #[serde(default = "default_device_cert_digest")]
+    pub digest: CertDigest,
+    /// Subject fields in addition to the CN, which is the device identifier
+    #[serde(default)]
+    pub subject: BTreeMap<String, String>,
+    /// Key usages, by their OpenSSL name (e.g. digitalSignature)
+    #[serde(default)]
+    pub key_usage: Vec<String>,
+    /// Extended key usages, by their OpenSSL short name or OID
+    #[serde(default)]
+    pub extended_key_usage: Vec<String>,
+    /// Add the device GUID as urn:uuid: URI subject alternative name
+    #[serde(default)]
+    pub san_guid: bool,
+}
+
+fn default_device_cert_validity_days() -> u32 {
+    3650
+}
+
+fn default_device_cert_digest() -> CertDigest {
+    CertDigest::Sha384
+}
+
+impl Default for DeviceCertProfile {
+    fn default() -> Self {
+        DeviceCertProfile {
+            validity_days: default_device_cert_validity_days(),
+            digest: default_device_cert_digest(),
+            subject: BTreeMap::new(),
+            key_usage: Vec::new(),
+            extended_key_usage: Vec::new(),
+            san_guid: false,
+        }
+    }
+}
+
+impl DeviceCertProfile {
+    /// Loads a YAML profile, or a TOML one for .toml files
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Error reading device certificate profile {path:?}"))?;
+        if path.extension().map_or(false, |ext| ext == "toml") {
+            toml::from_str(&contents)
+                .with_context(|| format!("Error parsing device certificate profile {path:?}"))
+        } else {
+            serde_yaml::from_str(&contents)
+                .with_context(|| format!("Error parsing device certificate profile {path:?}"))
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.validity_days == 0 {
+            bail!("Device certificate validity must be at least one day");
+        }
+        if self.subject.keys().any(|key| key == "CN") {
+            bail!("The device certificate CN is always the device identifier");
+        }
+        // Check the usages are known, rather than failing for the first device
+        apply_key_usage(&mut KeyUsage::new(), &self.key_usage)?;
+        Ok(())
+    }
+
+    fn subject_name(&self, device_id: &str) -> Result<X509Name, Error> {
+        let mut subject = X509NameBuilder::new().context("Error building device subject")?;
+        for (key, value) in &self.subject {
+            subject
+                .append_entry_by_text(key, value)
+                .with_context(|| format!("Error adding subject field {key}"))?;
+        }
+        subject
+            .append_entry_by_text("CN", device_id)
+            .context("Error building device subject")?;
+        Ok(subject.build())
+    }
+}
+
+fn apply_key_usage<'a>(builder: &'a mut KeyUsage, usages: &[String]) -> Result<&'a mut KeyUsage> {
+    for usage in usages {
+        match usage.as_str() {
+            "critical" => builder.critical(),
+            "digitalSignature" => builder.digital_signature(),
+            "nonRepudiation" => builder.non_repudiation(),
+            "keyEncipherment" => builder.key_encipherment(),
+            "dataEncipherment" => builder.data_encipherment(),
+            "keyAgreement" => builder.key_agreement(),
+            "keyCertSign" => builder.key_cert_sign(),
+            "cRLSign" => builder.crl_sign(),
+            "encipherOnly" => builder.encipher_only(),
+            "decipherOnly" => builder.decipher_only(),
+            other => bail!("Unknown key usage '{}'", other),
+        };
+    }
+    Ok(builder)
+}
+
+fn apply_extended_key_usage<'a>(
+    builder: &'a mut ExtendedKeyUsage,
+    usages: &[String],
+) -> &'a mut ExtendedKeyUsage {
+    for usage in usages {
+        match usage.as_str() {
+            "critical" => builder.critical(),
+            "serverAuth" => builder.server_auth(),
+            "clientAuth" => builder.client_auth(),
+            "codeSigning" => builder.code_signing(),
+            "emailProtection" => builder.email_protection(),
+            "timeStamping" => builder.time_stamping(),
+            // Any other short name or OID is passed to OpenSSL as-is
+            other => builder.other(other),
+        };
+    }
+    builder
+}
+
+/// Where to generate and store the device keys
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum DeviceKeyStorage {
+    /// Keys are stored in the device credential file
+    Filesystem,
+    /// Keys are generated inside, and wrapped by, the TPM of the device running this tool
+    Tpm,
+}
+
+/// Device key generated outside of this crate, e.g. in a TPM
+pub struct ExternalDeviceKey {
+    public_key: PKey<Public>,
+    hmac_secret: Secret,
+}
+
+pub const MIN_DEVICE_HMAC_SECRET_LEN: usize = 32;
+
+enum DeviceKeys<'a> {
+    /// Keys generated by this crate, stored in the device credential
+    Stored(KeyStorage),
+    External(&'a ExternalDeviceKey),
+}
+
+impl DeviceKeys<'_> {
+    fn public_key(&self) -> Result<PKey<Public>, Error> {
+        match self {
+            DeviceKeys::Stored(key_storage) => Ok(key_storage.public_key()?),
+            DeviceKeys::External(external_key) => Ok(external_key.public_key.clone()),
+        }
+    }
+
+    fn perform_hmac(&self, data: &[u8], hmac_type: HashType) -> Result<HMac, Error> {
+        match self {
+            DeviceKeys::Stored(key_storage) => Ok(key_storage.perform_hmac(data, hmac_type)?),
+            DeviceKeys::External(external_key) => external_key.perform_hmac(data, hmac_type),
+        }
+    }
+}
+
+impl ExternalDeviceKey {
+    /// Takes the device key from a PEM or DER PKCS#10 CSR, whose signature is verified
+    pub fn from_csr(csr: &[u8], hmac_secret: Vec<u8>) -> Result<Self, Error> {
+        let csr = X509Req::from_pem(csr)
+            .or_else(|_| X509Req::from_der(csr))
+            .context("Error parsing device CSR")?;
+        let public_key = csr.public_key().context("Error getting CSR public key")?;
+        if !csr
+            .verify(&public_key)
+            .context("Error verifying CSR signature")?
+        {
+            bail!("Signature of device CSR is invalid");
+        }
+
+        if hmac_secret.len() < MIN_DEVICE_HMAC_SECRET_LEN {
+            bail!(
+                "Device HMAC secret must be at least {} bytes",
+                MIN_DEVICE_HMAC_SECRET_LEN
+            );
+        }
+
+        Ok(ExternalDeviceKey {
+            public_key,
+            hmac_secret: hmac_secret.into(),
+        })
+    }
+
+    pub fn load<P: AsRef<Path>, Q: AsRef<Path>>(
+        csr_path: P,
+        hmac_secret_path: Q,
+    ) -> Result<Self, Error> {
+        let csr_path = csr_path.as_ref();
+        let hmac_secret_path = hmac_secret_path.as_ref();
+        let csr =
+            fs::read(csr_path).with_context(|| format!("Error reading device CSR {csr_path:?}"))?;
+        let hmac_secret = fs::read(hmac_secret_path)
+            .with_context(|| format!("Error reading device HMAC secret {hmac_secret_path:?}"))?;
+        Self::from_csr(&csr, hmac_secret)
+            .with_context(|| format!("Invalid device CSR {csr_path:?}"))
+    }
+
+    fn perform_hmac(&self, data: &[u8], hmac_type: HashType) -> Result<HMac, Error> {
+        let hmac_key = PKey::hmac(&self.hmac_secret).context("Error loading HMAC secret")?;
+        let mut signer =
+            Signer::new(hmac_type.get_md(), &hmac_key).context("Error creating HMAC signer")?;
+        signer.update(data).context("Error computing HMAC")?;
+        let hmac = signer.sign_to_vec().context("Error computing HMAC")?;
+        Ok(HMac::from_digest(hmac_type, hmac)?)
+    }
+}
+
+fn is_issuer_of(issuer: &X509, cert: &X509) -> bool {
+    issuer.issued(cert) == X509VerifyResult::OK
+        && issuer
+            .public_key()
+            .and_then(|key| cert.verify(&key))
+            .unwrap_or(false)
+}
+
+fn describe_cert(cert: &X509) -> String {
+    describe_name(cert.subject_name())
+}
+
+/// Checks that the device CA chain starts with the certificate of `signer`, and that every
+/// certificate is issued by the next one. With `reorder`, an unordered chain is put in order.
+pub fn check_device_ca_chain(
+    chain: Vec<X509>,
+    signer: &PKeyRef<Private>,
+    reorder: bool,
+) -> Result<Vec<X509>> {
+    if chain.is_empty() {
+        bail!("The chain does not contain any certificates");
+    }
+    let matches_signer = |cert: &X509| {
+        cert.public_key()
+            .map(|key| key.public_eq(signer))
+            .unwrap_or(false)
+    };
+
+    let mut chain = chain;
+    if reorder {
+        let start = match chain.iter().position(matches_signer) {
+            Some(pos) => pos,
+            None => bail!(
+                "None of the certificates in the chain matches the device CA private key, check that the key and chain belong together"
+            ),
+        };
+        let mut remaining = chain;
+        let mut ordered = vec![remaining.remove(start)];
+        while let Some(pos) = remaining
+            .iter()
+            .position(|cert| is_issuer_of(cert, ordered.last().unwrap()))
+        {
+            ordered.push(remaining.remove(pos));
+        }
+        if !remaining.is_empty() {
+            bail!(
+                "The chain contains certificates that are not part of the issuing path of the device CA: {}",
+                remaining
+                    .iter()
+                    .map(|cert| format!("'{}'", describe_cert(cert)))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            );
+        }
+        chain = ordered;
+    }
+
+    if !matches_signer(&chain[0]) {
+        if chain.iter().any(matches_signer) {
+            bail!(
+                "The certificate of the device CA private key is not first in the chain, order the chain from the issuing CA to the root or pass --reorder-device-cert-ca-chain"
+            );
+        }
+        bail!(
+            "The first certificate in the chain ('{}') does not match the device CA private key",
+            describe_cert(&chain[0])
+        );
+    }
+    for pair in chain.windows(2) {
+        if !is_issuer_of(&pair[1], &pair[0]) {
+            bail!(
+                "Certificate '{}' is not issued by the next certificate in the chain ('{}'), order the chain from the issuing CA to the root or pass --reorder-device-cert-ca-chain",
+                describe_cert(&pair[0]),
+                describe_cert(&pair[1])
+            );
+        }
+    }
+
+    Ok(chain)
+}
+
+fn build_device_cert<T: openssl::pkey::HasPublic>(
+    subject_name: &X509NameRef,
+    device_pubkey: &PKeyRef<T>,
+    device_guid: &Guid,
+    signer: &PKeyRef<Private>,
+    chain: &[X509],
+    profile: &DeviceCertProfile,
+) -> Result<X509> {
+    if chain.is_empty() {
+        bail!("Insufficient device CA certs in the chain");
+    }
+    if !chain[0]
+        .public_key()
+        .context("Error getting device CA public key")?
+        .public_eq(signer)
+    {
+        bail!("Device CA issuer not first in the chain");
+    }
+
+    // Build
+    let mut builder = X509Builder::new().context("Error creating X509Builder")?;
+
+    builder
+        .set_not_after(
+            Asn1Time::days_from_now(profile.validity_days)
+                .context("Error building not-after-time")?
+                .as_ref(),
+        )
+        .context("Error setting not-after")?;
+
+    builder
+        .set_not_before(
+            Asn1Time::days_from_now(0)
+                .context("Error building not-before-time")?
+                .as_ref(),
+        )
+        .context("Error setting not-before")?;
+
+    builder.set_version(2).context("Error setting version")?;
+
+    builder
+        .set_issuer_name(chain[0].subject_name())
+        .context("Error setting issuer name")?;
+
+    builder
+        .set_subject_name(subject_name)
+        .context("Error setting subject name")?;
+
+    builder
+        .set_pubkey(device_pubkey)
+        .context("Error setting device public key")?;
+
+    // Build a new serial number
+    // We are generating a random number for serial number using 64 bits of output
+    //  from a CSPRNG (openssl's rand), according to section 7.1 of
+    //  CA/Browser Forum Baseline Requirements, version 1.7.3
+    let mut serial_buf = [0; 8];
+    rand_bytes(&mut serial_buf).context("Error generating serial number")?;
+    let serial = BigNum::from_slice(&serial_buf).context("Error parsing serial number")?;
+    let serial = Asn1Integer::from_bn(&serial).context("Error converting serial number to asn1")?;
+    builder
+        .set_serial_number(serial.as_ref())
+        .context("Error setting serial number")?;
+
+    // Extensions
+    if !profile.key_usage.is_empty() {
+        let key_usage = apply_key_usage(&mut KeyUsage::new(), &profile.key_usage)?
+            .build()
+            .context("Error building key usage")?;
+        builder
+            .append_extension(key_usage)
+            .context("Error adding key usage")?;
+    }
+    if !profile.extended_key_usage.is_empty() {
+        let extended_key_usage =
+            apply_extended_key_usage(&mut ExtendedKeyUsage::new(), &profile.extended_key_usage)
+                .build()
+                .context("Error building extended key usage")?;
+        builder
+            .append_extension(extended_key_usage)
+            .context("Error adding extended key usage")?;
+    }
+    if profile.san_guid {
+        let san = SubjectAlternativeName::new()
+            .uri(&format!("urn:uuid:{}", device_guid.to_string()))
+            .build(&builder.x509v3_context(Some(&chain[0]), None))
+            .context("Error building subject alternative name")?;
+        builder
+            .append_extension(san)
+            .context("Error adding subject alternative name")?;
+    }
+
+    // Sign and return
+    builder
+        .sign(signer, profile.digest.into())
+        .context("Error signing certificate")?;
+
+    Ok(builder.build())
+}
+
+/// Everything needed to initialize devices, shared by all devices of a batch
+pub struct DeviceInitializationMaterials {
+    pub key_storage: DeviceKeyStorage,
+    pub manufacturer_pubkey: PublicKey,
+    pub device_cert_ca_private_key: PKey<Private>,
+    /// Device CA chain, as checked by [`check_device_ca_chain`]
+    pub device_cert_ca_chain: Vec<X509>,
+    pub device_cert_profile: DeviceCertProfile,
+    pub rendezvous_info: RendezvousInfo,
+}
+
+/// What the device needs to onboard
+pub enum InitializedDeviceCredential {
+    /// Device credential holding the keys generated for the device
+    File(FileDeviceCredential),
+    /// Certificate chain of a device that holds its own keys
+    CertificateChain(Vec<X509>),
+}
+
+/// Result of [`create_device`]
+pub struct InitializedDevice {
+    pub guid: Guid,
+    pub ownership_voucher: OwnershipVoucher,
+    pub device_credential: InitializedDeviceCredential,
+}
+
+impl InitializedDevice {
+    /// Writes the PEM ownership voucher, and the device credential or PEM certificate chain
+    pub fn write<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        ownershipvoucher_out: P,
+        device_credential_out: Q,
+    ) -> Result<(), Error> {
+        let ov = self
+            .ownership_voucher
+            .to_pem()
+            .context("Error serializing ownership voucher")?;
+        let device_credential = match &self.device_credential {
+            InitializedDeviceCredential::File(devcred) => devcred
+                .to_file_data()
+                .context("Error serializing device credential")?,
+            InitializedDeviceCredential::CertificateChain(chain) => chain
+                .iter()
+                .map(|cert| cert.to_pem())
+                .collect::<Result<Vec<_>, _>>()
+                .context("Error serializing device certificate chain")?
+                .concat(),
+        };
+
+        fs::write(ownershipvoucher_out, ov).context("Error writing ownership voucher")?;
+        fs::write(device_credential_out, device_credential)
+            .context("Error writing device credential")?;
+        Ok(())
+    }
+}
+
+/// Creates the ownership voucher and device credential of a new device.
+///
+/// The device keys are generated as configured in `materials`, unless `external_key` is given.
+pub fn create_device(
+    materials: &DeviceInitializationMaterials,
+    device_id: &str,
+    external_key: Option<&ExternalDeviceKey>,
+) -> Result<InitializedDevice, Error> {
+    let device_guid = Guid::new().context("Error generating guid")?;
+
+    // Build device cert
+    let device_subject = materials.device_cert_profile.subject_name(device_id)?;
+    let device_subject = device_subject.as_ref();
+    let device_keys = match (external_key, materials.key_storage) {
+        (Some(external_key), _) => DeviceKeys::External(external_key),
+        (None, DeviceKeyStorage::Filesystem) => {
+            let device_key_group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+                .context("Error getting nist 256 group")?;
+            let device_key =
+                EcKey::generate(&device_key_group).context("Error generating device key")?;
+            let device_key =
+                PKey::from_ec_key(device_key).context("Error converting device key to pkey")?;
+
+            // Build device HMAC key
+            let mut hmac_key_buf = [0; 32];
+            rand_bytes(&mut hmac_key_buf).context("Error creating random device HMAC key")?;
+
+            DeviceKeys::Stored(KeyStorage::Plain {
+                hmac_secret: hmac_key_buf[..].into(),
+                private_key: device_key
+                    .private_key_to_der()
+                    .context("Error serializing device private key")?
+                    .into(),
+            })
+        }
+        (None, DeviceKeyStorage::Tpm) => DeviceKeys::Stored(
+            KeyStorage::new_tpm(PublicKeyType::SECP256R1)
+                .context("Error generating device keys in the TPM")?,
+        ),
+    };
+    let device_pubkey = device_keys
+        .public_key()
+        .context("Error getting device public key")?;
+    // The strength of the device hashes and HMAC follows the device key
+    let device_hash_type =
+        HashType::for_pkey(&device_pubkey).context("Unsupported device key type")?;
+    let device_cert = build_device_cert(
+        device_subject,
+        &device_pubkey,
+        &device_guid,
+        &materials.device_cert_ca_private_key,
+        &materials.device_cert_ca_chain,
+        &materials.device_cert_profile,
+    )
+    .context("Error building device certificate")?;
+
+    // Construct device certificate chain
+    let mut device_cert_chain = materials.device_cert_ca_chain.clone();
+    device_cert_chain.insert(0, device_cert);
+    let device_certs = device_cert_chain.clone();
+    let device_cert_chain = X5Chain::new(device_cert_chain).context("Error creating X5Chain")?;
+    let device_cert_chain_hash = hash_device_cert_chain(&device_cert_chain, device_hash_type)?;
+
+    // Construct Ownership Voucher Header
+    let ov_header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        device_guid.clone(),
+        materials.rendezvous_info.clone(),
+        device_id.to_string(),
+        materials.manufacturer_pubkey.clone(),
+        Some(device_cert_chain_hash),
+    )
+    .context("Error creating new OwnershipVoucher Header")?;
+    let ov_header_ser = ov_header
+        .serialize_data()
+        .context("Error serializing Ownership Voucher header")?;
+
+    // Compute device hash over OV Header
+    let ov_hmac = device_keys
+        .perform_hmac(&ov_header_ser, device_hash_type.hmac())
+        .context("Error computing HMAC")?;
+
+    let device_credential = match device_keys {
+        DeviceKeys::Stored(key_storage) => {
+            InitializedDeviceCredential::File(FileDeviceCredential {
+                active: true,
+                protver: ProtocolVersion::Version1_1,
+                device_info: device_id.to_string(),
+                guid: device_guid.clone(),
+                rvinfo: materials.rendezvous_info.clone(),
+                pubkey_hash: ov_header
+                    .manufacturer_public_key_hash(
+                        materials
+                            .manufacturer_pubkey
+                            .hash_type()
+                            .context("Unsupported manufacturer key type")?,
+                    )
+                    .context("Error computing manufacturer public key hash")?,
+                key_storage,
+            })
+        }
+        // The device holds its own keys, so it only needs its certificate chain
+        DeviceKeys::External(_) => InitializedDeviceCredential::CertificateChain(device_certs),
+    };
+
+    // Build the Ownership Voucher
+    let ownership_voucher = OwnershipVoucher::new(ov_header, ov_hmac, Some(device_cert_chain))
+        .context("Error building ownership voucher")?;
+
+    Ok(InitializedDevice {
+        guid: device_guid,
+        ownership_voucher,
+        device_credential,
+    })
+}
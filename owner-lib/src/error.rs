@@ -0,0 +1,124 @@
+use std::fmt::Display;
+
+use anyhow::{Context, Error, Result};
+use serde::Serialize;
+
+/// Class of a failure, so that callers can tell failures apart without parsing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// Reading or writing a file failed
+    Io,
+    /// A private key or certificate could not be loaded or used
+    Key,
+    /// The ownership voucher could not be parsed or is not supported
+    Voucher,
+    /// The ownership voucher failed verification
+    Validation,
+    Other,
+}
+
+impl ErrorClass {
+    /// Determines the class of `err`.
+    ///
+    /// I/O errors take precedence, so that e.g. a missing key file is reported as such.
+    /// Otherwise the outermost class attached with [`ClassContext`] or [`class_error`] is used.
+    pub fn of(err: &Error) -> Self {
+        if err.chain().any(|cause| cause.is::<std::io::Error>()) {
+            return ErrorClass::Io;
+        }
+        match err.downcast_ref::<Classified>() {
+            Some(classified) => classified.class,
+            None => ErrorClass::Other,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Classified {
+    class: ErrorClass,
+    message: String,
+}
+
+impl Display for Classified {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Classified {}
+
+/// Like [`anyhow::Context`], but also attaches the class of the failure
+pub trait ClassContext<T, E> {
+    fn class_context<C>(self, class: ErrorClass, context: C) -> Result<T>
+    where
+        C: Display;
+
+    fn with_class_context<C, F>(self, class: ErrorClass, f: F) -> Result<T>
+    where
+        C: Display,
+        F: FnOnce() -> C;
+}
+
+impl<T, E> ClassContext<T, E> for Result<T, E>
+where
+    Result<T, E>: Context<T, E>,
+{
+    fn class_context<C>(self, class: ErrorClass, context: C) -> Result<T>
+    where
+        C: Display,
+    {
+        self.context(Classified {
+            class,
+            message: context.to_string(),
+        })
+    }
+
+    fn with_class_context<C, F>(self, class: ErrorClass, f: F) -> Result<T>
+    where
+        C: Display,
+        F: FnOnce() -> C,
+    {
+        self.with_context(|| Classified {
+            class,
+            message: f().to_string(),
+        })
+    }
+}
+
+/// Creates an error of the given class, for failed checks
+pub fn class_error<C>(class: ErrorClass, message: C) -> Error
+where
+    C: Display,
+{
+    Error::new(Classified {
+        class,
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_class() {
+        let err: Result<()> =
+            Err(class_error(ErrorClass::Validation, "Entry 0 is invalid")).context("Outer");
+        assert_eq!(ErrorClass::of(&err.unwrap_err()), ErrorClass::Validation);
+
+        let err: Result<()> = Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+            .class_context(ErrorClass::Key, "Error loading key");
+        assert_eq!(ErrorClass::of(&err.unwrap_err()), ErrorClass::Io);
+
+        let err: Result<()> = Err(anyhow::anyhow!("Invalid key"))
+            .class_context(ErrorClass::Key, "Error loading key")
+            .class_context(ErrorClass::Voucher, "Error extending voucher");
+        assert_eq!(ErrorClass::of(&err.unwrap_err()), ErrorClass::Voucher);
+
+        assert_eq!(
+            ErrorClass::of(&anyhow::anyhow!("Unknown")),
+            ErrorClass::Other
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::{convert::TryFrom, fs, path::Path};
+
+use anyhow::{Context, Error};
+use openssl::{
+    pkey::{PKey, Private},
+    x509::{X509NameRef, X509},
+};
+
+use fdo_data_formats::{
+    constants::PublicKeyType,
+    publickey::{PublicKey, X5Chain},
+};
+
+/// Public key type, for keys whose type can't be detected from their certificate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum KeyType {
+    Secp256r1,
+    Secp384r1,
+    Rsa2048restr,
+    Rsa3072,
+    Ed25519,
+}
+
+impl From<KeyType> for PublicKeyType {
+    fn from(key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::Secp256r1 => PublicKeyType::SECP256R1,
+            KeyType::Secp384r1 => PublicKeyType::SECP384R1,
+            KeyType::Rsa2048restr => PublicKeyType::Rsa2048RESTR,
+            KeyType::Rsa3072 => PublicKeyType::RsaPkcs,
+            KeyType::Ed25519 => PublicKeyType::Ed25519,
+        }
+    }
+}
+
+/// Formats a certificate name as comma separated short name and value pairs, e.g.
+/// "CN=owner, O=Example"
+pub fn describe_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            format!(
+                "{}={}",
+                entry.object().nid().short_name().unwrap_or("?"),
+                entry
+                    .data()
+                    .as_utf8()
+                    .map(|data| data.to_string())
+                    .unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Loads a PEM or DER encoded private key
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<PKey<Private>, Error> {
+    let contents = fs::read(path)?;
+    // Accept both PEM and DER encoded keys, detected by the PEM armor
+    let is_pem = contents
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .map(|start| contents[start..].starts_with(b"-----BEGIN"))
+        .unwrap_or(false);
+    if is_pem {
+        Ok(PKey::private_key_from_pem(&contents).context("Error parsing PEM private key")?)
+    } else {
+        Ok(PKey::private_key_from_der(&contents).context("Error parsing DER private key")?)
+    }
+}
+
+/// Loads a PEM encoded certificate
+pub fn load_x509<P: AsRef<Path>>(path: P) -> Result<X509, Error> {
+    let contents = fs::read(path)?;
+    Ok(X509::from_pem(&contents)?)
+}
+
+/// Loads all certificates of a PEM file
+pub fn load_x509s<P: AsRef<Path>>(path: P) -> Result<Vec<X509>, Error> {
+    let contents = fs::read(path)?;
+    Ok(X509::stack_from_pem(&contents)?)
+}
+
+/// Builds the FDO public key of `cert`, detecting the key type unless `key_type` is given
+pub fn public_key_from_x509(cert: X509, key_type: Option<KeyType>) -> Result<PublicKey, Error> {
+    Ok(match key_type {
+        None => PublicKey::try_from(cert)?,
+        Some(key_type) => PublicKey::from_x509_with_key_type(cert, key_type.into())?,
+    })
+}
+
+/// Builds the FDO public key of a delegated signing certificate, conveyed with the chain up
+/// to the owner certificate (leaf first)
+pub fn public_key_from_x509_chain(
+    chain: Vec<X509>,
+    key_type: Option<KeyType>,
+) -> Result<PublicKey, Error> {
+    let chain = X5Chain::new(chain)?;
+    chain
+        .verify_delegation()
+        .context("Certificate chain is not a valid delegation")?;
+    Ok(match key_type {
+        None => PublicKey::try_from(chain)?,
+        Some(key_type) => PublicKey::from_x5chain_with_key_type(chain, key_type.into())?,
+    })
+}
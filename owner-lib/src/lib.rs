@@ -0,0 +1,20 @@
+//! Owner and manufacturer operations of `fdo-owner-tool`, for embedding in other services.
+//!
+//! - [`device`] initializes devices, creating their ownership voucher and device credential
+//! - [`voucher`] loads, converts and extends ownership vouchers, including resold ones
+//! - [`verify`] verifies ownership vouchers, optionally checking for revoked certificates
+//! - [`provenance`] reports the chain of custody of ownership vouchers
+//! - [`credential`] migrates, deactivates and reactivates device credentials
+//! - [`rendezvous`] parses rendezvous information files
+//! - [`keys`] loads the keys and certificates used by the above
+//!
+//! Errors are [`anyhow::Error`]s, classified with [`error::ErrorClass`].
+
+pub mod credential;
+pub mod device;
+pub mod error;
+pub mod keys;
+pub mod provenance;
+pub mod rendezvous;
+pub mod verify;
+pub mod voucher;
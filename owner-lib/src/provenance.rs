@@ -0,0 +1,124 @@
+use anyhow::{Context, Error};
+use serde::Serialize;
+
+use fdo_data_formats::{
+    constants::HashType,
+    enhanced_types::X5Bag,
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherEntryPayload},
+    publickey::{PublicKey, X5Chain},
+    types::Hash,
+};
+
+use crate::{
+    error::{ClassContext, ErrorClass},
+    keys::describe_name,
+};
+
+/// Verification status of a hop in the chain of custody
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CustodyStatus {
+    Valid,
+    Invalid,
+    // Not checked, e.g. because an earlier hop is invalid
+    Unverified,
+}
+
+/// A party that held the ownership voucher
+#[derive(Debug, Serialize)]
+pub struct CustodyHop {
+    pub key_type: String,
+    /// SHA256 of the DER encoded public key
+    pub key_fingerprint: String,
+    /// Only known for keys conveyed as certificate chains
+    pub subject: Option<String>,
+    pub issuer: Option<String>,
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+    pub status: CustodyStatus,
+    pub error: Option<String>,
+}
+
+impl CustodyHop {
+    fn new(key: &PublicKey, status: CustodyStatus, error: Option<String>) -> Result<Self, Error> {
+        let fingerprint = Hash::from_data(HashType::Sha256, &key.pkey().public_key_to_der()?)
+            .context("Error hashing public key")?;
+        let cert = key.chain().and_then(X5Chain::leaf_certificate);
+        Ok(CustodyHop {
+            key_type: format!("{:?}", key.keytype()),
+            key_fingerprint: hex::encode(fingerprint.value()),
+            subject: cert.map(|cert| describe_name(cert.subject_name())),
+            issuer: cert.map(|cert| describe_name(cert.issuer_name())),
+            not_before: cert.map(|cert| cert.not_before().to_string()),
+            not_after: cert.map(|cert| cert.not_after().to_string()),
+            status,
+            error,
+        })
+    }
+}
+
+/// Chain of custody of an ownership voucher, for supply chain audits
+#[derive(Debug, Serialize)]
+pub struct Provenance {
+    pub guid: String,
+    pub device_info: String,
+    pub manufacturer: CustodyHop,
+    /// Every owner the voucher was extended to, in order
+    pub owners: Vec<CustodyHop>,
+    pub valid: bool,
+}
+
+/// Reports every party that held the voucher, verifying the entries on the way.
+///
+/// Unlike [`crate::verify::verify_ownership_voucher`], invalid entries don't stop the
+/// report, they are marked as such. The manufacturer is only checked against
+/// `trusted_manufacturer_certs` if given, and left unverified otherwise.
+pub fn voucher_provenance(
+    ov: &OwnershipVoucher,
+    trusted_manufacturer_certs: Option<&X5Bag>,
+) -> Result<Provenance, Error> {
+    let ov_header = ov.header();
+
+    let manufacturer_key = ov_header.manufacturer_public_key();
+    let manufacturer = match trusted_manufacturer_certs {
+        None => CustodyHop::new(manufacturer_key, CustodyStatus::Unverified, None)?,
+        Some(certs) if certs.contains_publickey(manufacturer_key) => {
+            CustodyHop::new(manufacturer_key, CustodyStatus::Valid, None)?
+        }
+        Some(_) => CustodyHop::new(
+            manufacturer_key,
+            CustodyStatus::Invalid,
+            Some("Manufacturer public key is not trusted".to_string()),
+        )?,
+    };
+
+    // The entries are verified in order, and verification stops at the first invalid one
+    let mut verified = ov.iter_entries().context("Error creating OV iterator")?;
+    let mut owners = Vec::new();
+    for pos in 0..ov.num_entries() as usize {
+        let (status, error) = match verified.next() {
+            Some(Ok(_)) => (CustodyStatus::Valid, None),
+            Some(Err(e)) => (CustodyStatus::Invalid, Some(e.to_string())),
+            None => (CustodyStatus::Unverified, None),
+        };
+        // Parse the entry again without verification, so invalid entries are reported too
+        let entry = ov
+            .entry(pos)
+            .and_then(|entry| entry.get_payload_unverified::<OwnershipVoucherEntryPayload>())
+            .with_class_context(ErrorClass::Voucher, || format!("Error parsing entry {pos}"))?;
+        owners.push(CustodyHop::new(
+            entry.get_unverified_value().public_key(),
+            status,
+            error,
+        )?);
+    }
+
+    Ok(Provenance {
+        guid: ov_header.guid().to_string(),
+        device_info: ov_header.device_info().to_string(),
+        valid: manufacturer.status != CustodyStatus::Invalid
+            && owners.iter().all(|hop| hop.status == CustodyStatus::Valid),
+        manufacturer,
+        owners,
+    })
+}
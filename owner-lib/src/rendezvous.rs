@@ -0,0 +1,172 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Error, Result};
+use serde_yaml::Value;
+
+use fdo_data_formats::{
+    constants::RendezvousVariable,
+    types::{CborSimpleType, RendezvousInfo},
+};
+
+/// Format of a rendezvous information file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum RendezvousInfoFormat {
+    /// List of entries, each a mapping of rendezvous variables
+    Yaml,
+    /// A `rendezvous_info` array of tables, same as the field in the manufacturing server
+    /// configuration
+    Toml,
+}
+
+impl RendezvousInfoFormat {
+    /// TOML for .toml files, YAML otherwise
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        if path.as_ref().extension().map_or(false, |ext| ext == "toml") {
+            RendezvousInfoFormat::Toml
+        } else {
+            RendezvousInfoFormat::Yaml
+        }
+    }
+}
+
+fn yaml_to_cbor(val: &Value) -> Result<CborSimpleType, Error> {
+    Ok(match val {
+        Value::Null => CborSimpleType::Null,
+        Value::Bool(b) => b.to_owned().into(),
+        Value::Number(nr) => {
+            if let Some(nr) = nr.as_u64() {
+                nr.into()
+            } else if let Some(nr) = nr.as_i64() {
+                nr.into()
+            } else if let Some(nr) = nr.as_f64() {
+                nr.into()
+            } else {
+                bail!("Invalid number encountered");
+            }
+        }
+        Value::String(str) => str.clone().into(),
+        Value::Sequence(seq) => CborSimpleType::from(
+            seq.iter()
+                .map(yaml_to_cbor)
+                .collect::<Result<Vec<CborSimpleType>>>()?,
+        ),
+        Value::Mapping(map) => CborSimpleType::from(
+            map.iter()
+                .map(|(key, val)| (yaml_to_cbor(key).unwrap(), yaml_to_cbor(val).unwrap()))
+                .collect::<std::collections::BTreeMap<CborSimpleType, CborSimpleType>>(),
+        ),
+        Value::Tagged(_) => bail!("YAML tags are unsupported"),
+    })
+}
+
+fn toml_to_cbor(val: &toml::Value) -> Result<CborSimpleType, Error> {
+    Ok(match val {
+        toml::Value::String(str) => str.clone().into(),
+        toml::Value::Integer(i) => CborSimpleType::Integer(*i as i128),
+        toml::Value::Float(f) => CborSimpleType::Float(*f),
+        toml::Value::Boolean(b) => CborSimpleType::Bool(*b),
+        toml::Value::Datetime(_) => bail!("TOML datetimes are unsupported"),
+        toml::Value::Array(arr) => CborSimpleType::from(
+            arr.iter()
+                .map(toml_to_cbor)
+                .collect::<Result<Vec<CborSimpleType>>>()?,
+        ),
+        toml::Value::Table(table) => CborSimpleType::from(
+            table
+                .iter()
+                .map(|(key, val)| Ok((key.clone().into(), toml_to_cbor(val)?)))
+                .collect::<Result<std::collections::BTreeMap<CborSimpleType, CborSimpleType>>>()?,
+        ),
+    })
+}
+
+fn load_rendezvous_entries_yaml(contents: &[u8]) -> Result<Vec<Vec<(String, CborSimpleType)>>> {
+    let value: Value = serde_yaml::from_slice(contents).context("Error parsing rendezvous info")?;
+    let value = match value {
+        Value::Sequence(vals) => vals,
+        _ => bail!("Invalid yaml top type"),
+    };
+
+    value
+        .iter()
+        .map(|val| {
+            let val = match val {
+                Value::Mapping(map) => map,
+                _ => bail!("Invalid entry type"),
+            };
+            val.iter()
+                .map(|(key, val)| match key {
+                    Value::String(key) => Ok((key.clone(), yaml_to_cbor(val)?)),
+                    _ => bail!("Invalid key type"),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// The TOML file contains a rendezvous_info array of tables, same as the
+// field in the manufacturing server configuration
+fn load_rendezvous_entries_toml(contents: &[u8]) -> Result<Vec<Vec<(String, CborSimpleType)>>> {
+    let contents = std::str::from_utf8(contents).context("Rendezvous info is not UTF-8")?;
+    let value: toml::Value = toml::from_str(contents).context("Error parsing rendezvous info")?;
+    let value = match value.get("rendezvous_info") {
+        Some(toml::Value::Array(vals)) => vals,
+        _ => bail!("Missing rendezvous_info array of tables"),
+    };
+
+    value
+        .iter()
+        .map(|val| {
+            let val = match val {
+                toml::Value::Table(table) => table,
+                _ => bail!("Invalid entry type"),
+            };
+            val.iter()
+                .map(|(key, val)| Ok((key.clone(), toml_to_cbor(val)?)))
+                .collect()
+        })
+        .collect()
+}
+
+/// Parses rendezvous information with human readable values, e.g. `ip_address: 192.0.2.1`
+pub fn parse_rendezvous_info(
+    contents: &[u8],
+    format: RendezvousInfoFormat,
+) -> Result<RendezvousInfo, Error> {
+    let entries = match format {
+        RendezvousInfoFormat::Yaml => load_rendezvous_entries_yaml(contents)?,
+        RendezvousInfoFormat::Toml => load_rendezvous_entries_toml(contents)?,
+    };
+
+    let mut info = Vec::new();
+    for val in entries {
+        let mut entry = Vec::new();
+
+        for (key, val) in val {
+            let key = RendezvousVariable::from_str(&key)
+                .with_context(|| format!("Error parsing rendezvous key '{key}'"))?;
+
+            let val = key
+                .value_from_human_to_machine(val)
+                .with_context(|| format!("Error parsing value for key '{key:?}'"))?;
+
+            entry.push((key, val));
+        }
+
+        info.push(entry);
+    }
+
+    RendezvousInfo::new(info).context("Error serializing rendezvous info")
+}
+
+/// Loads a rendezvous information file, detecting the format from its extension unless
+/// `format` is given
+pub fn load_rendezvous_info<P: AsRef<Path>>(
+    path: P,
+    format: Option<RendezvousInfoFormat>,
+) -> Result<RendezvousInfo, Error> {
+    let contents = fs::read(&path)?;
+    let format = format.unwrap_or_else(|| RendezvousInfoFormat::from_path(&path));
+    parse_rendezvous_info(&contents, format)
+}
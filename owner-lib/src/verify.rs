@@ -0,0 +1,171 @@
+use std::fmt::Display;
+
+use anyhow::{Context, Error};
+use openssl::pkey::{PKey, Public};
+
+use fdo_data_formats::{
+    constants::HashType, enhanced_types::X5Bag, ownershipvoucher::OwnershipVoucher,
+};
+use fdo_util::revocation::RevocationChecker;
+
+use crate::{
+    error::{class_error, ClassContext, ErrorClass},
+    voucher::{check_protocol_version, hash_device_cert_chain},
+};
+
+/// A check passed by [`verify_ownership_voucher`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VoucherCheck {
+    HeaderHmac,
+    ManufacturerPublicKey,
+    /// The device certificate chain matches the hash in the header, or neither is present
+    DeviceCertificateChain {
+        present: bool,
+    },
+    Entry(usize),
+    /// The voucher has not been extended, so there are no entries to check
+    NoEntries,
+    CurrentOwner,
+    Revocation,
+}
+
+impl Display for VoucherCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoucherCheck::HeaderHmac => write!(f, "Header HMAC: OK"),
+            VoucherCheck::ManufacturerPublicKey => write!(f, "Manufacturer public key: OK"),
+            VoucherCheck::DeviceCertificateChain { present: false } => {
+                write!(f, "Device certificate chain: <none>")
+            }
+            VoucherCheck::DeviceCertificateChain { present: true } => {
+                write!(f, "Device certificate chain hash: OK")
+            }
+            VoucherCheck::Entry(pos) => write!(f, "Entry {pos}: OK"),
+            VoucherCheck::NoEntries => write!(f, "Entries: <none>"),
+            VoucherCheck::CurrentOwner => write!(f, "Current owner: OK"),
+            VoucherCheck::Revocation => write!(f, "Revocation: OK"),
+        }
+    }
+}
+
+/// What an ownership voucher is verified against
+pub struct VoucherVerification {
+    pub trusted_manufacturer_certs: X5Bag,
+    /// Key of the owner the voucher must have been extended to last
+    pub expected_owner: Option<PKey<Public>>,
+    /// Checks the manufacturer, owner and device certificates for revocation if set
+    pub revocation_checker: Option<RevocationChecker>,
+}
+
+/// Verifies the signatures and hashes of an ownership voucher.
+///
+/// The header HMAC can only be verified by the device, so only its type is checked.
+/// `on_check` is called after every check that passed, in order, so that callers can report
+/// progress; verification stops at the first failed check, which is returned as the error.
+pub async fn verify_ownership_voucher<F>(
+    ov: &OwnershipVoucher,
+    verification: &VoucherVerification,
+    mut on_check: F,
+) -> Result<(), Error>
+where
+    F: FnMut(VoucherCheck),
+{
+    check_protocol_version(ov)?;
+    let ov_header = ov.header();
+
+    match ov.header_hmac().get_type() {
+        HashType::HmacSha256 | HashType::HmacSha384 => {}
+        other => {
+            return Err(class_error(
+                ErrorClass::Validation,
+                format!("Header HMAC has invalid type {other:?}"),
+            ))
+        }
+    }
+    on_check(VoucherCheck::HeaderHmac);
+
+    if !verification
+        .trusted_manufacturer_certs
+        .contains_publickey(ov_header.manufacturer_public_key())
+    {
+        return Err(class_error(
+            ErrorClass::Validation,
+            "Manufacturer public key is not trusted",
+        ));
+    }
+    on_check(VoucherCheck::ManufacturerPublicKey);
+
+    match (
+        ov.device_certificate_chain(),
+        ov_header.device_certificate_chain_hash(),
+    ) {
+        (None, None) => on_check(VoucherCheck::DeviceCertificateChain { present: false }),
+        (Some(_), None) => {
+            return Err(class_error(
+                ErrorClass::Validation,
+                "Device certificate chain present without hash in header",
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(class_error(
+                ErrorClass::Validation,
+                "Device certificate chain hash present without chain",
+            ))
+        }
+        (Some(chain), Some(expected_hash)) => {
+            let chain_hash = hash_device_cert_chain(chain, expected_hash.get_type())?;
+            if chain_hash.compare(expected_hash).is_err() {
+                return Err(class_error(
+                    ErrorClass::Validation,
+                    "Device certificate chain does not match the hash in the header",
+                ));
+            }
+            on_check(VoucherCheck::DeviceCertificateChain { present: true });
+        }
+    }
+
+    let mut current_owner = ov_header.manufacturer_public_key().clone();
+    let ov_iter = ov.iter_entries().context("Error creating OV iterator")?;
+    for (pos, entry) in ov_iter.enumerate() {
+        let entry = entry.with_class_context(ErrorClass::Validation, || {
+            format!("Entry {pos} is invalid (signature or hash chain verification failed)")
+        })?;
+        on_check(VoucherCheck::Entry(pos));
+        current_owner = entry.public_key().clone();
+    }
+    if ov.num_entries() == 0 {
+        on_check(VoucherCheck::NoEntries);
+    }
+
+    if let Some(expected_owner) = &verification.expected_owner {
+        if !current_owner
+            .matches_pkey(expected_owner)
+            .context("Error comparing owner public keys")?
+        {
+            return Err(class_error(
+                ErrorClass::Validation,
+                "Current owner does not match the expected owner",
+            ));
+        }
+        on_check(VoucherCheck::CurrentOwner);
+    }
+
+    if let Some(checker) = &verification.revocation_checker {
+        checker
+            .check_voucher(ov, &verification.trusted_manufacturer_certs)
+            .await
+            .class_context(ErrorClass::Validation, "Revocation check failed")?;
+        if let Some(chain) = ov.device_certificate_chain() {
+            checker
+                .check_chain(chain, &X5Bag::new())
+                .await
+                .class_context(
+                    ErrorClass::Validation,
+                    "Device certificate chain revocation check failed",
+                )?;
+        }
+        on_check(VoucherCheck::Revocation);
+    }
+
+    Ok(())
+}
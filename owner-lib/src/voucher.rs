@@ -0,0 +1,255 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Error};
+use openssl::x509::X509;
+
+use fdo_data_formats::{
+    constants::HashType,
+    ownershipvoucher::OwnershipVoucher,
+    publickey::{PublicKey, X5Chain},
+    types::Hash,
+    ProtocolVersion, Serializable,
+};
+use fdo_util::signing::SigningKey;
+
+use crate::{
+    error::{class_error, ClassContext, ErrorClass},
+    keys::{public_key_from_x509, KeyType},
+};
+
+/// Hashes the device certificate chain the way it is stored in the voucher header
+pub fn hash_device_cert_chain(chain: &X5Chain, hash_type: HashType) -> Result<Hash, Error> {
+    let serialized = chain
+        .chain()
+        .iter()
+        .try_fold(vec![], |mut bytes, cert| {
+            cert.to_der().map(|der| {
+                bytes.extend(der);
+                bytes
+            })
+        })
+        .context("Error serializing device cert chain")?;
+    Hash::from_data(hash_type, &serialized).context("Error hashing device cert chain")
+}
+
+/// Encoding of an ownership voucher
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum VoucherFormat {
+    /// PEM-armored, as exchanged between owners
+    Pem,
+    /// Plain CBOR, as stored by the servers
+    Cose,
+    /// CBOR diagnostic notation, for reviewing and editing vouchers
+    Diagnostic,
+}
+
+/// Loads an ownership voucher in any [`VoucherFormat`]
+pub fn load_voucher<P: AsRef<Path>>(path: P) -> Result<OwnershipVoucher, Error> {
+    let contents = fs::read(path).context("Error reading ownership voucher")?;
+    let is_diagnostic = contents
+        .iter()
+        .find(|c| !c.is_ascii_whitespace())
+        .map_or(false, |c| *c == b'[');
+    if is_diagnostic {
+        let contents = std::str::from_utf8(&contents)
+            .class_context(ErrorClass::Voucher, "Ownership voucher is not valid UTF-8")?;
+        OwnershipVoucher::from_diagnostic(contents).class_context(
+            ErrorClass::Voucher,
+            "Error parsing diagnostic ownership voucher",
+        )
+    } else {
+        OwnershipVoucher::from_pem_or_raw(&contents)
+            .class_context(ErrorClass::Voucher, "Error deserializing ownership voucher")
+    }
+}
+
+/// Serializes an ownership voucher to `format`
+pub fn serialize_voucher(ov: &OwnershipVoucher, format: VoucherFormat) -> Result<Vec<u8>, Error> {
+    Ok(match format {
+        VoucherFormat::Pem => ov.to_pem()?.into_bytes(),
+        VoucherFormat::Cose => ov.serialize_data()?,
+        VoucherFormat::Diagnostic => ov.to_diagnostic()?.into_bytes(),
+    })
+}
+
+/// Writes an ownership voucher PEM-armored, replacing `path` only once it's fully written
+pub fn write_voucher<P: AsRef<Path>>(ov: &OwnershipVoucher, path: P) -> Result<(), Error> {
+    let path = path.as_ref();
+    let ov = ov.to_pem().context("Error serializing ownership voucher")?;
+    let mut new_path = path.as_os_str().to_owned();
+    new_path.push(".new");
+    fs::write(&new_path, ov).with_context(|| format!("Error writing to {new_path:?}"))?;
+    fs::rename(&new_path, path)
+        .with_context(|| format!("Error moving new ownership voucher to {}", path.display()))
+}
+
+/// Checks the signatures and hash chain of every entry, so that corrupted vouchers are not
+/// passed on
+pub fn validate_voucher_entries(ov: &OwnershipVoucher) -> Result<(), Error> {
+    for (pos, entry) in ov
+        .iter_entries()
+        .context("Error creating OV iterator")?
+        .enumerate()
+    {
+        entry.with_class_context(ErrorClass::Validation, || {
+            format!("Error validating entry {pos}")
+        })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn check_protocol_version(ov: &OwnershipVoucher) -> Result<(), Error> {
+    let ov_header = ov.header();
+    if ov_header.protocol_version() != ProtocolVersion::Version1_1 {
+        return Err(class_error(
+            ErrorClass::Voucher,
+            format!(
+                "Protocol version in OV ({}) not supported ({})",
+                ov_header.protocol_version(),
+                ProtocolVersion::Version1_1,
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Extends the voucher to `new_owner_pubkey`, signed by the current owner
+pub fn extend_ownership_voucher(
+    ov: &mut OwnershipVoucher,
+    current_owner_private_key: &SigningKey,
+    new_owner_pubkey: &PublicKey,
+) -> Result<(), Error> {
+    check_protocol_version(ov)?;
+
+    match current_owner_private_key.as_pkey() {
+        Some(current_owner_private_key) => {
+            ov.extend(current_owner_private_key, None, new_owner_pubkey)
+        }
+        None => ov.extend_with_signer(
+            current_owner_private_key,
+            &current_owner_private_key
+                .public_key()
+                .class_context(ErrorClass::Key, "Error getting current owner public key")?,
+            None,
+            new_owner_pubkey,
+        ),
+    }
+    .context("Error extending ownership voucher")?;
+
+    Ok(())
+}
+
+/// Extends a resold voucher to the next owner.
+///
+/// Resale onboarding resets the voucher, so it must not have any entries yet.
+pub fn resell_ownership_voucher(
+    ov: &mut OwnershipVoucher,
+    current_owner_private_key: &SigningKey,
+    new_owner_pubkey: &PublicKey,
+) -> Result<(), Error> {
+    if ov.num_entries() != 0 {
+        return Err(class_error(
+            ErrorClass::Validation,
+            "Ownership voucher has already been extended, it is not a resold voucher",
+        ));
+    }
+    extend_ownership_voucher(ov, current_owner_private_key, new_owner_pubkey)
+}
+
+/// Extends the voucher to every owner in `chain` in turn, each hop being signed by the owner
+/// of the previous hop.
+///
+/// `intermediate_owner_private_keys` are the keys of all but the last owner in `chain`. They
+/// are checked against their certificates before the voucher is touched, and the voucher is
+/// validated again after every hop.
+pub fn extend_ownership_voucher_chain(
+    ov: &mut OwnershipVoucher,
+    current_owner_private_key: SigningKey,
+    chain: Vec<X509>,
+    intermediate_owner_private_keys: Vec<SigningKey>,
+    key_type: Option<KeyType>,
+) -> Result<(), Error> {
+    check_protocol_version(ov)?;
+
+    if chain.is_empty() {
+        return Err(class_error(
+            ErrorClass::Key,
+            "No certificates found in owner chain",
+        ));
+    }
+    if intermediate_owner_private_keys.len() != chain.len() - 1 {
+        return Err(class_error(
+            ErrorClass::Key,
+            format!(
+                "The owner chain has {} certificates, so {} intermediate owner private keys are needed, got {}",
+                chain.len(),
+                chain.len() - 1,
+                intermediate_owner_private_keys.len()
+            ),
+        ));
+    }
+
+    // Check that every intermediate key belongs to its owner before extending anything
+    for (hop, (signing_key, cert)) in intermediate_owner_private_keys
+        .iter()
+        .zip(&chain)
+        .enumerate()
+    {
+        let matches = signing_key.public_key()?.public_eq(&cert.public_key()?);
+        if !matches {
+            return Err(class_error(
+                ErrorClass::Key,
+                format!(
+                    "Intermediate owner private key {} does not match certificate {} of the owner chain",
+                    hop + 1,
+                    hop + 1
+                ),
+            ));
+        }
+    }
+
+    let mut signing_keys = vec![current_owner_private_key];
+    signing_keys.extend(intermediate_owner_private_keys);
+    for (hop, (signing_key, cert)) in signing_keys.iter().zip(chain).enumerate() {
+        let new_owner_pubkey =
+            public_key_from_x509(cert, key_type).with_class_context(ErrorClass::Key, || {
+                format!(
+                    "Error serializing public key of owner {} of the chain",
+                    hop + 1
+                )
+            })?;
+        extend_ownership_voucher(ov, signing_key, &new_owner_pubkey).with_context(|| {
+            format!(
+                "Error extending ownership voucher to owner {} of the chain",
+                hop + 1
+            )
+        })?;
+
+        // Validate the whole voucher again, so a broken hop is caught right away
+        let mut last_entry = None;
+        for entry in ov.iter_entries()? {
+            last_entry = Some(entry.with_class_context(ErrorClass::Validation, || {
+                format!(
+                    "Ownership voucher is invalid after extending it to owner {}",
+                    hop + 1
+                )
+            })?);
+        }
+        let ends_with_new_owner = match last_entry {
+            Some(entry) => entry.public_key().matches_pkey(new_owner_pubkey.pkey())?,
+            None => false,
+        };
+        if !ends_with_new_owner {
+            return Err(class_error(
+                ErrorClass::Validation,
+                format!(
+                    "Ownership voucher does not end with owner {} of the chain after extending it",
+                    hop + 1
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
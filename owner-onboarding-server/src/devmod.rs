@@ -0,0 +1,50 @@
+use std::collections::BTreeMap;
+
+use fdo_data_formats::types::CborSimpleType;
+use fdo_store::{MetadataValue, StoreError};
+
+// The module list is sent as a mixed-type array, and kept separately
+const SKIPPED_KEYS: &[&str] = &["active", "modules", "nummodules"];
+
+/// Device properties reported in the devmod ServiceInfo module, kept with the
+/// ownership voucher for inventory.
+#[derive(Debug, Default)]
+pub(crate) struct DevModInfo {
+    properties: BTreeMap<String, String>,
+}
+
+impl DevModInfo {
+    pub(crate) fn add(&mut self, key: &str, value: &CborSimpleType) {
+        if SKIPPED_KEYS.contains(&key) {
+            return;
+        }
+        let value = match value {
+            CborSimpleType::Text(text) => text.clone(),
+            CborSimpleType::Bool(b) => b.to_string(),
+            CborSimpleType::Integer(i) => i.to_string(),
+            _ => {
+                log::debug!("Ignoring devmod {} with unsupported value {:?}", key, value);
+                return;
+            }
+        };
+        self.properties.insert(key.to_string(), value);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.properties.is_empty()
+    }
+}
+
+impl MetadataValue for DevModInfo {
+    fn to_stored(&self) -> Result<Vec<u8>, StoreError> {
+        Ok(self.to_text().into_bytes())
+    }
+
+    /// One `key=value` line per property, with newlines escaped
+    fn to_text(&self) -> String {
+        self.properties
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value.escape_default()))
+            .collect()
+    }
+}
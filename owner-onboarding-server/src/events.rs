@@ -0,0 +1,271 @@
+use std::sync::Arc;
+
+#[cfg(any(feature = "kafka", feature = "nats"))]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+
+use fdo_util::servers::configuration::owner_onboarding_server::EventPublisherSettings;
+
+/// Onboarding event, published as JSON
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum OnboardingEvent {
+    To2Started { guid: String },
+    To2Completed { guid: String },
+    To0Registered { guid: String, wait_seconds: u32 },
+    To0RegistrationFailed { guid: String, error: String },
+}
+
+impl OnboardingEvent {
+    fn guid(&self) -> &str {
+        match self {
+            OnboardingEvent::To2Started { guid }
+            | OnboardingEvent::To2Completed { guid }
+            | OnboardingEvent::To0Registered { guid, .. }
+            | OnboardingEvent::To0RegistrationFailed { guid, .. } => guid,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EventMessage<'a> {
+    // Seconds since the UNIX epoch
+    timestamp: i64,
+    #[serde(flatten)]
+    event: &'a OnboardingEvent,
+}
+
+#[async_trait]
+trait EventPublisher: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Publishes the JSON encoded event, with the device GUID as key where supported
+    async fn publish(&self, key: &str, payload: &[u8]) -> Result<()>;
+}
+
+struct WebhookPublisher {
+    client: reqwest::Client,
+    url: String,
+    auth_token: Option<String>,
+}
+
+#[async_trait]
+impl EventPublisher for WebhookPublisher {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn publish(&self, _key: &str, payload: &[u8]) -> Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(payload.to_vec());
+        if let Some(auth_token) = &self.auth_token {
+            request = request.bearer_auth(auth_token);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+struct KafkaPublisher {
+    producer: rdkafka::producer::FutureProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl EventPublisher for KafkaPublisher {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, key: &str, payload: &[u8]) -> Result<()> {
+        let record = rdkafka::producer::FutureRecord::to(&self.topic)
+            .key(key)
+            .payload(payload);
+        self.producer
+            .send(record, std::time::Duration::from_secs(0))
+            .await
+            .map_err(|(e, _)| e)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "nats")]
+struct NatsPublisher {
+    client: async_nats::Client,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl EventPublisher for NatsPublisher {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    async fn publish(&self, _key: &str, payload: &[u8]) -> Result<()> {
+        self.client
+            .publish(self.subject.clone(), payload.to_vec().into())
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mqtt")]
+struct MqttPublisher {
+    client: rumqttc::AsyncClient,
+    topic: String,
+}
+
+#[cfg(feature = "mqtt")]
+#[async_trait]
+impl EventPublisher for MqttPublisher {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn publish(&self, _key: &str, payload: &[u8]) -> Result<()> {
+        self.client
+            .publish(
+                &self.topic,
+                rumqttc::QoS::AtLeastOnce,
+                false,
+                payload.to_vec(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+async fn publisher_from_settings(
+    settings: &EventPublisherSettings,
+) -> Result<Box<dyn EventPublisher>> {
+    match settings {
+        EventPublisherSettings::Webhook { url, auth_token } => Ok(Box::new(WebhookPublisher {
+            client: reqwest::Client::new(),
+            url: url.clone(),
+            auth_token: auth_token.clone(),
+        })),
+        #[cfg(feature = "kafka")]
+        EventPublisherSettings::Kafka { brokers, topic } => {
+            let producer = rdkafka::ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .create()
+                .context("Error creating Kafka producer")?;
+            Ok(Box::new(KafkaPublisher {
+                producer,
+                topic: topic.clone(),
+            }))
+        }
+        #[cfg(feature = "nats")]
+        EventPublisherSettings::Nats { url, subject } => {
+            let client = async_nats::connect(url.as_str())
+                .await
+                .with_context(|| format!("Error connecting to NATS server {url}"))?;
+            Ok(Box::new(NatsPublisher {
+                client,
+                subject: subject.clone(),
+            }))
+        }
+        #[cfg(feature = "mqtt")]
+        EventPublisherSettings::Mqtt {
+            host,
+            port,
+            topic,
+            client_id,
+            username,
+            password,
+        } => {
+            let client_id = client_id
+                .clone()
+                .unwrap_or_else(|| "fdo-owner-onboarding-server".to_string());
+            let mut options = rumqttc::MqttOptions::new(client_id, host, *port);
+            if let Some(username) = username {
+                options.set_credentials(username, password.clone().unwrap_or_default());
+            }
+            let (client, mut eventloop) = rumqttc::AsyncClient::new(options, MQTT_QUEUE_SIZE);
+            // The event loop does the actual network traffic, and reconnects when polled again
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = eventloop.poll().await {
+                        log::warn!("MQTT connection error: {:?}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    }
+                }
+            });
+            Ok(Box::new(MqttPublisher {
+                client,
+                topic: topic.clone(),
+            }))
+        }
+        #[allow(unreachable_patterns)]
+        _ => bail!(
+            "Event publisher {:?} is not supported by this build",
+            settings
+        ),
+    }
+}
+
+#[cfg(feature = "mqtt")]
+const MQTT_QUEUE_SIZE: usize = 100;
+
+/// Publishes onboarding events to all configured publishers
+#[derive(Clone)]
+pub(crate) struct EventPublishers(Arc<Vec<Box<dyn EventPublisher>>>);
+
+impl EventPublishers {
+    pub(crate) async fn from_settings(settings: &[EventPublisherSettings]) -> Result<Self> {
+        let mut publishers = Vec::new();
+        for publisher_settings in settings {
+            let publisher = publisher_from_settings(publisher_settings).await?;
+            log::info!("Publishing onboarding events with {}", publisher.name());
+            publishers.push(publisher);
+        }
+        Ok(EventPublishers(Arc::new(publishers)))
+    }
+
+    /// Publishes `event` in the background, failures are only logged.
+    pub(crate) fn publish(&self, event: OnboardingEvent) {
+        if self.0.is_empty() {
+            return;
+        }
+        let message = EventMessage {
+            timestamp: time::OffsetDateTime::now_utc().unix_timestamp(),
+            event: &event,
+        };
+        let payload = match serde_json::to_vec(&message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Error serializing event {:?}: {:?}", event, e);
+                return;
+            }
+        };
+        let publishers = self.0.clone();
+        tokio::spawn(async move {
+            for publisher in publishers.iter() {
+                if let Err(e) = publisher.publish(event.guid(), &payload).await {
+                    log::warn!(
+                        "Error publishing {:?} with {}: {:?}",
+                        event,
+                        publisher.name(),
+                        e
+                    );
+                }
+            }
+        });
+    }
+}
+
+impl std::fmt::Debug for EventPublishers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|publisher| publisher.name()))
+            .finish()
+    }
+}
@@ -3,23 +3,34 @@ use std::{collections::HashSet, str::FromStr};
 use fdo_data_formats::{
     constants::{DeviceSigType, ErrorCode, HeaderKeys},
     messages::Message,
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
     types::{
-        COSEHeaderMap, COSESign, CipherSuite, Guid, KeyDeriveSide, KeyExchange, Nonce,
+        COSEHeaderMap, COSESign, CipherSuite, Guid, HMac, KeyDeriveSide, KeyExchange, Nonce,
         RendezvousInfo, SigInfo, TO2ProveDevicePayload, TO2ProveOVHdrPayload,
         TO2SetupDevicePayload,
     },
+    ProtocolVersion,
 };
 use fdo_data_formats::{
     constants::{FedoraIotServiceInfoModule, StandardServiceInfoModule},
     messages::{self, v11::to2::OwnerServiceInfo},
+    types::{CborSimpleTypeExt, ServiceInfo},
+    Serializable,
 };
 
+use fdo_http_wrapper::server::consume_session_nonce;
+use fdo_http_wrapper::server::run_blocking;
 use fdo_http_wrapper::server::Error;
 use fdo_http_wrapper::server::RequestInformation;
+use fdo_http_wrapper::server::Session;
 use fdo_http_wrapper::EncryptionKeys;
-use fdo_store::MetadataKey;
+use fdo_store::{MetadataKey, MetadataValue};
 use fdo_util::servers::{OwnershipVoucherStoreMetadataKey, ServiceInfoApiReply};
 
+use crate::devmod::DevModInfo;
+use crate::events::OnboardingEvent;
+use crate::spool::{ServiceInfoCursor, ServiceInfoSpool};
+
 pub(super) async fn hello_device(
     user_data: super::OwnerServiceUDT,
     mut request_info: RequestInformation,
@@ -44,9 +55,89 @@ pub(super) async fn hello_device(
         }
         Some(dev) => dev,
     };
+    // Vouchers can end up in the store without being uploaded through the management API
+    if let Err(violation) = user_data.voucher_policy.check(&ownership_voucher) {
+        log::warn!(
+            "Device {:?}: ownership voucher rejected by policy: {}",
+            msg.guid(),
+            violation
+        );
+        return Err(Error::new(
+            ErrorCode::InvalidOwnershipVoucher,
+            messages::v11::to2::HelloDevice::message_type(),
+            "Ownership voucher not accepted",
+        )
+        .into());
+    }
+    if let Err(e) = user_data.check_voucher_revocation(&ownership_voucher).await {
+        log::warn!(
+            "Device {:?}: ownership voucher rejected: {:?}",
+            msg.guid(),
+            e
+        );
+        return Err(Error::new(
+            ErrorCode::InvalidOwnershipVoucher,
+            messages::v11::to2::HelloDevice::message_type(),
+            "Ownership voucher not accepted",
+        )
+        .into());
+    }
+    if let Err(e) = user_data.check_voucher_crypto_policy(&ownership_voucher) {
+        log::warn!(
+            "Device {:?}: ownership voucher rejected: {:?}",
+            msg.guid(),
+            e
+        );
+        return Err(Error::new(
+            ErrorCode::InvalidOwnershipVoucher,
+            messages::v11::to2::HelloDevice::message_type(),
+            "Ownership voucher not accepted",
+        )
+        .into());
+    }
     session
         .insert("device_guid", msg.guid().to_string())
         .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+    user_data.events.publish(OnboardingEvent::To2Started {
+        guid: msg.guid().to_string(),
+    });
+
+    // Check the negotiated algorithms against the crypto policy
+    if let Some(crypto_policy) = &user_data.crypto_policy {
+        if let Err(violation) = crypto_policy
+            .check_kex_suite(msg.kex_suite())
+            .and_then(|_| crypto_policy.check_cipher_suite(msg.cipher_suite()))
+        {
+            log::warn!("Device {:?}: {}", msg.guid(), violation);
+            return Err(Error::new(
+                ErrorCode::MessageBodyError,
+                messages::v11::to2::HelloDevice::message_type(),
+                "Key exchange or cipher suite not allowed",
+            )
+            .into());
+        }
+    }
+
+    // ASYMKEX needs to decrypt the Device random with an RSA Owner key of the suite size
+    if msg.kex_suite().is_asymkex() {
+        let owner_key_usable = match user_data.owner_key.as_pkey() {
+            Some(owner_key) => msg.kex_suite().check_asymkex_owner_key(owner_key).is_ok(),
+            None => false,
+        };
+        if !owner_key_usable {
+            log::warn!(
+                "Device {:?}: key exchange suite {} not supported by the owner key",
+                msg.guid(),
+                msg.kex_suite().to_string()
+            );
+            return Err(Error::new(
+                ErrorCode::MessageBodyError,
+                messages::v11::to2::HelloDevice::message_type(),
+                "Key exchange suite not supported by the owner key",
+            )
+            .into());
+        }
+    }
 
     // Check whether we support the specific siginfo
     match msg.a_signature_info().sig_type() {
@@ -69,13 +160,19 @@ pub(super) async fn hello_device(
         .into());
     }
 
-    // Build kex a
-    let a_key_exchange = KeyExchange::new(msg.kex_suite())
-        .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+    // Build kex a, Diffie-Hellman key generation is slow enough to not run it inline
+    let kex_suite = msg.kex_suite();
+    let (a_key_exchange, a_key_exchange_public) =
+        run_blocking::<messages::v11::to2::HelloDevice, _, _>(move || {
+            let a_key_exchange = KeyExchange::new(kex_suite)
+                .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+            let a_key_exchange_public = a_key_exchange
+                .get_public()
+                .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+            Ok((a_key_exchange, a_key_exchange_public))
+        })
+        .await?;
     let nonce6 = Nonce::new().map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
-    let a_key_exchange_public = a_key_exchange
-        .get_public()
-        .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
 
     // Now produce the result
     let res_payload = TO2ProveOVHdrPayload::new(
@@ -109,8 +206,14 @@ pub(super) async fn hello_device(
         .insert(HeaderKeys::CUPHOwnerPubKey, &user_data.owner_pubkey)
         .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
 
-    let res = COSESign::new(&res_payload, Some(res_header), &user_data.owner_key)
-        .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?;
+    let signing_data = user_data.clone();
+    let res = run_blocking::<messages::v11::to2::HelloDevice, _, _>(move || {
+        Ok(signing_data
+            .owner_key
+            .cose_sign(&res_payload, Some(res_header))
+            .map_err(Error::from_error::<messages::v11::to2::HelloDevice, _>)?)
+    })
+    .await?;
     let res = messages::v11::to2::ProveOVHdr::new(res);
 
     request_info.session = session;
@@ -168,6 +271,16 @@ pub(super) async fn prove_device(
 ) -> Result<(messages::v11::to2::SetupDevice, RequestInformation), warp::Rejection> {
     let mut session = request_info.session;
 
+    if session.get::<bool>("proven_device").is_some() {
+        log::warn!("Device attempted to replay ProveDevice");
+        return Err(Error::new(
+            ErrorCode::InvalidMessageError,
+            messages::v11::to2::ProveDevice::message_type(),
+            "Request sequence failure",
+        )
+        .into());
+    }
+
     let device_guid: String = match session.get("device_guid") {
         Some(v) => v,
         None => {
@@ -235,6 +348,21 @@ pub(super) async fn prove_device(
         }
         Some(dev) => dev,
     };
+    if let Some(chain) = ownership_voucher.device_certificate_chain() {
+        if let Err(e) = user_data.verify_device_certificate_chain(chain).await {
+            log::warn!(
+                "Device {:?}: device certificate not trusted: {:?}",
+                device_guid,
+                e
+            );
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::to2::ProveDevice::message_type(),
+                "Device certificate not trusted",
+            )
+            .into());
+        }
+    }
     let device_certificate = match ownership_voucher.device_certificate_chain() {
         Some(chain) => match chain.leaf_certificate() {
             Some(cert) => cert,
@@ -256,7 +384,7 @@ pub(super) async fn prove_device(
             .into())
         }
     };
-    let dev_pubkey = &device_certificate
+    let dev_pubkey = device_certificate
         .public_key()
         .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
 
@@ -277,9 +405,12 @@ pub(super) async fn prove_device(
         }
     };
 
-    let eat = token
-        .get_eat(dev_pubkey.as_ref())
-        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    let eat = run_blocking::<messages::v11::to2::ProveDevice, _, _>(move || {
+        Ok(token
+            .get_eat(dev_pubkey.as_ref())
+            .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?)
+    })
+    .await?;
 
     let eat_payload: TO2ProveDevicePayload = match eat
         .payload()
@@ -305,6 +436,15 @@ pub(super) async fn prove_device(
         )
         .into());
     }
+    // Verify the token was created by the device we are onboarding
+    if eat.device_guid() != device_guid {
+        return Err(Error::new(
+            ErrorCode::InvalidMessageError,
+            messages::v11::to2::ProveDevice::message_type(),
+            "Device GUID mismatch",
+        )
+        .into());
+    }
     session
         .insert("nonce7", nonce7.clone())
         .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
@@ -318,14 +458,19 @@ pub(super) async fn prove_device(
         };
 
     // Derive and set the keys
-    let new_keys = a_key_exchange
-        .derive_key(
-            KeyDeriveSide::OwnerService,
-            ciphersuite,
-            eat_payload.b_key_exchange(),
-            use_noninteroperable_kdf,
-        )
-        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    let kdf_data = user_data.clone();
+    let new_keys = run_blocking::<messages::v11::to2::ProveDevice, _, _>(move || {
+        Ok(a_key_exchange
+            .derive_key_with_owner_key(
+                KeyDeriveSide::OwnerService,
+                ciphersuite,
+                eat_payload.b_key_exchange(),
+                kdf_data.owner_key.as_pkey(),
+                use_noninteroperable_kdf,
+            )
+            .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?)
+    })
+    .await?;
     let new_keys = EncryptionKeys::from_derived(ciphersuite, new_keys);
     log::trace!("Got new keys, setting: {:?}", new_keys);
     fdo_http_wrapper::server::set_encryption_keys::<messages::v11::to2::ProveDevice>(
@@ -333,16 +478,58 @@ pub(super) async fn prove_device(
         new_keys,
     )?;
 
-    // Generate new ephemeral SetupDevicePayload
-    let new_payload = TO2SetupDevicePayload::new(
-        RendezvousInfo::new(Vec::new())
-            .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?,
-        Guid::new().unwrap(),
-        nonce7,
-        user_data.owner2_pub.clone(),
-    );
-    let new_token = COSESign::new(&new_payload, None, &user_data.owner2_key)
-        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    let (new_payload, sign_with_owner2_key) = if user_data.credential_reuse_enabled {
+        // Credential Reuse: keep the GUID, RendezvousInfo and owner key, so the device
+        // keeps its credential and can be onboarded again with the same voucher
+        log::info!("Device {:?}: offering credential reuse", device_guid);
+        let new_payload = TO2SetupDevicePayload::new(
+            ownership_voucher.header().rendezvous_info().clone(),
+            device_guid.clone(),
+            nonce7,
+            user_data.owner_pubkey.clone(),
+        );
+        (new_payload, false)
+    } else if user_data.resale_enabled {
+        // Resale: the device gets a new GUID, with our own key as its manufacturer key,
+        // so that the new voucher can be extended to a new owner
+        let new_guid =
+            Guid::new().map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+        log::info!(
+            "Device {:?}: replacing credential for resale, new GUID {:?}",
+            device_guid,
+            new_guid
+        );
+        session
+            .insert("resale_guid", new_guid.to_string())
+            .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+        let new_payload = TO2SetupDevicePayload::new(
+            ownership_voucher.header().rendezvous_info().clone(),
+            new_guid,
+            nonce7,
+            user_data.owner_pubkey.clone(),
+        );
+        (new_payload, false)
+    } else {
+        // Generate new ephemeral SetupDevicePayload
+        let new_payload = TO2SetupDevicePayload::new(
+            RendezvousInfo::new(Vec::new())
+                .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?,
+            Guid::new().unwrap(),
+            nonce7,
+            user_data.owner2_pub.clone(),
+        );
+        (new_payload, true)
+    };
+    let signing_data = user_data.clone();
+    let new_token = run_blocking::<messages::v11::to2::ProveDevice, _, _>(move || {
+        let new_token = if sign_with_owner2_key {
+            COSESign::new(&new_payload, None, &signing_data.owner2_key)
+        } else {
+            signing_data.owner_key.cose_sign(&new_payload, None)
+        };
+        Ok(new_token.map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?)
+    })
+    .await?;
     let resp = messages::v11::to2::SetupDevice::new(new_token);
 
     session
@@ -355,9 +542,9 @@ pub(super) async fn prove_device(
 }
 
 pub(super) async fn device_service_info_ready(
-    _user_data: super::OwnerServiceUDT,
-    ses_with_store: RequestInformation,
-    _msg: messages::v11::to2::DeviceServiceInfoReady,
+    user_data: super::OwnerServiceUDT,
+    mut ses_with_store: RequestInformation,
+    msg: messages::v11::to2::DeviceServiceInfoReady,
 ) -> Result<
     (
         messages::v11::to2::OwnerServiceInfoReady,
@@ -378,13 +565,103 @@ pub(super) async fn device_service_info_ready(
         }
     };
 
+    let resale_guid: Option<String> = ses_with_store.session.get("resale_guid");
+    match (msg.replacement_hmac(), resale_guid) {
+        (Some(replacement_hmac), Some(resale_guid)) => {
+            let resale_ov = build_resale_voucher(
+                &user_data,
+                &ses_with_store.session,
+                &resale_guid,
+                replacement_hmac,
+            )
+            .await?;
+            ses_with_store
+                .session
+                .insert("resale_ov", resale_ov)
+                .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfoReady, _>)?;
+        }
+        (None, Some(_)) => {
+            log::warn!("Device did not replace its credential, no resale voucher created");
+            ses_with_store.session.remove("resale_guid");
+        }
+        _ => {}
+    }
+
+    let max_owner_service_info_size = msg
+        .max_owner_service_info_size()
+        .unwrap_or(DEFAULT_MAX_OWNER_SERVICE_INFO_SIZE)
+        .max(MIN_MAX_OWNER_SERVICE_INFO_SIZE);
+    ses_with_store
+        .session
+        .insert("max_owner_service_info_size", max_owner_service_info_size)
+        .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfoReady, _>)?;
+
     Ok((
-        messages::v11::to2::OwnerServiceInfoReady::new(None),
+        messages::v11::to2::OwnerServiceInfoReady::new(user_data.max_device_service_info_size),
         ses_with_store,
     ))
 }
 
+/// Builds the PEM encoded voucher for the replaced device credential, with our
+/// own key as manufacturer key and no entries
+async fn build_resale_voucher(
+    user_data: &super::OwnerServiceUDT,
+    session: &Session,
+    resale_guid: &str,
+    replacement_hmac: &HMac,
+) -> Result<String, Error> {
+    let device_guid: String = match session.get("device_guid") {
+        Some(v) => v,
+        None => {
+            return Err(Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::to2::DeviceServiceInfoReady::message_type(),
+                "Request sequence failure",
+            ))
+        }
+    };
+    let device_guid = Guid::from_str(&device_guid).unwrap();
+    let old_ov = match user_data
+        .ownership_voucher_store
+        .load_data(&device_guid)
+        .await
+        .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfoReady, _>)?
+    {
+        Some(ov) => ov,
+        None => {
+            return Err(Error::new(
+                ErrorCode::ResourceNotFound,
+                messages::v11::to2::DeviceServiceInfoReady::message_type(),
+                "Device not found",
+            ))
+        }
+    };
+
+    let header = OwnershipVoucherHeader::new(
+        ProtocolVersion::Version1_1,
+        Guid::from_str(resale_guid).unwrap(),
+        old_ov.header().rendezvous_info().clone(),
+        old_ov.header().device_info().to_string(),
+        user_data.owner_pubkey.clone(),
+        old_ov.header().device_certificate_chain_hash().cloned(),
+    )
+    .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfoReady, _>)?;
+    OwnershipVoucher::new(
+        header,
+        replacement_hmac.clone(),
+        old_ov.device_certificate_chain().cloned(),
+    )
+    .and_then(|ov| ov.to_pem())
+    .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfoReady, _>)
+}
+
 const MAX_SERVICE_INFO_LOOPS: u32 = 1000;
+// The spec default, used when the device does not tell us its maximum
+const DEFAULT_MAX_OWNER_SERVICE_INFO_SIZE: u64 = 1300;
+// Anything smaller than this would not even fit the fdo.download headers
+const MIN_MAX_OWNER_SERVICE_INFO_SIZE: u64 = 256;
+// Room for the key and CBOR headers around a fdo.download data chunk
+const DOWNLOAD_CHUNK_OVERHEAD: usize = 64;
 
 pub(super) async fn device_service_info(
     user_data: super::OwnerServiceUDT,
@@ -443,18 +720,50 @@ pub(super) async fn device_service_info(
         .insert("num_service_info_loops", num_loops + 1)
         .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfo, _>)?;
 
+    // ServiceInfo the device split across messages is only handled once complete
+    let mut in_si: ServiceInfo = ses_with_store
+        .session
+        .get("pending_device_service_info")
+        .unwrap_or_default();
+    in_si.extend(msg.service_info());
+    if msg.is_more_service_info() {
+        log::trace!(
+            "Device {:?} has more ServiceInfo, waiting for the rest",
+            device_guid
+        );
+        ses_with_store
+            .session
+            .insert("pending_device_service_info", in_si)
+            .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfo, _>)?;
+        return Ok((
+            messages::v11::to2::OwnerServiceInfo::new(false, false, ServiceInfo::new()),
+            ses_with_store,
+        ));
+    }
+    ses_with_store.session.remove("pending_device_service_info");
+
+    // Only messages with complete ServiceInfo count as a loop from here on
+    let loop_num = ses_with_store
+        .session
+        .get::<u32>("num_complete_service_info_loops")
+        .unwrap_or(0);
+    ses_with_store
+        .session
+        .insert("num_complete_service_info_loops", loop_num + 1)
+        .map_err(Error::from_error::<messages::v11::to2::DeviceServiceInfo, _>)?;
+
     log::trace!(
         "Device {:?} is now starting ServiceInfo loop {}",
         device_guid,
-        num_loops
+        loop_num
     );
 
     let resp = match perform_service_info(
         user_data,
         &mut ses_with_store.session,
         device_guid,
-        msg,
-        num_loops,
+        &in_si,
+        loop_num,
     )
     .await
     {
@@ -473,28 +782,119 @@ pub(super) async fn device_service_info(
     Ok((resp, ses_with_store))
 }
 
+/// Reads the next message-sized page at `cursor`, storing the advanced cursor in the session.
+///
+/// Values too large for a message are split across messages, which the device joins
+/// again since the page is sent with IsMoreServiceInfo set.
+async fn next_service_info_page(
+    spool: &ServiceInfoSpool,
+    session: &mut Session,
+    mut cursor: ServiceInfoCursor,
+    max_size: usize,
+) -> Result<OwnerServiceInfo, anyhow::Error> {
+    // Give the device a chance to ask for a resume before sending any file data
+    let (page, value_split) = cursor
+        .next_page(spool, max_size, |module, key| {
+            *module == StandardServiceInfoModule::Download.into() && key == "sha-384"
+        })
+        .await?;
+
+    if cursor.is_finished() {
+        session.remove("service_info_cursor");
+        cursor.remove(spool).await;
+    } else {
+        session.insert("service_info_cursor", cursor)?;
+    }
+
+    log::trace!("Sending ServiceInfo page: {:?}", page);
+
+    Ok(messages::v11::to2::OwnerServiceInfo::new(
+        value_split,
+        false,
+        page,
+    ))
+}
+
 async fn perform_service_info(
     user_data: super::OwnerServiceUDT,
-    _session: &mut fdo_http_wrapper::server::Session,
+    session: &mut Session,
     device_guid: Guid,
-    msg: messages::v11::to2::DeviceServiceInfo,
+    in_si: &ServiceInfo,
     loop_num: u32,
 ) -> Result<OwnerServiceInfo, anyhow::Error> {
+    let max_size = session
+        .get::<u64>("max_owner_service_info_size")
+        .unwrap_or(DEFAULT_MAX_OWNER_SERVICE_INFO_SIZE) as usize;
+
     if loop_num != 0 {
-        // Return DONE for now after the first loop.
-        return Ok(messages::v11::to2::OwnerServiceInfo::new(
-            false,
-            true,
-            Default::default(),
-        ));
+        let mut cursor: Option<ServiceInfoCursor> = session.get("service_info_cursor");
+
+        for (module, key, value) in in_si.iter() {
+            if module == StandardServiceInfoModule::Command.into()
+                || module == FedoraIotServiceInfoModule::Command.into()
+            {
+                match value.as_bytes() {
+                    Some(output) => log::debug!(
+                        "Device {:?} command {}: {}",
+                        device_guid,
+                        key,
+                        String::from_utf8_lossy(output)
+                    ),
+                    None => log::debug!("Device {:?} command {}: {:?}", device_guid, key, value),
+                }
+                continue;
+            }
+            if module != StandardServiceInfoModule::Download.into() {
+                continue;
+            }
+            if key == "resume" {
+                let offset = value
+                    .as_u64()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid fdo.download resume offset"))?;
+                log::debug!(
+                    "Device {:?} resumes file download at offset {}",
+                    device_guid,
+                    offset
+                );
+                match &mut cursor {
+                    Some(cursor) => {
+                        cursor
+                            .skip_download_data(&user_data.service_info_spool, offset)
+                            .await?
+                    }
+                    None => anyhow::bail!("Device asked to resume past the end of the file"),
+                }
+            } else if key == "done" {
+                log::debug!(
+                    "Device {:?} finished downloading file: {:?}",
+                    device_guid,
+                    value
+                );
+            }
+        }
+
+        return match cursor {
+            Some(cursor) => {
+                next_service_info_page(&user_data.service_info_spool, session, cursor, max_size)
+                    .await
+            }
+            None => Ok(messages::v11::to2::OwnerServiceInfo::new(
+                false,
+                true,
+                Default::default(),
+            )),
+        };
     }
-    let in_si = msg.service_info();
 
     log::trace!("Received ServiceInfo loop {}: {:?}", loop_num, in_si);
 
     let mut module_list: Option<Vec<String>> = None;
+    let mut devmod = DevModInfo::default();
 
     for (module, var, value) in in_si.iter() {
+        if module == StandardServiceInfoModule::DevMod.into() {
+            devmod.add(&var, &value);
+        }
         if module == StandardServiceInfoModule::DevMod.into() && var == "modules" {
             let mut rawmodlist: Vec<serde_cbor::Value> = serde_cbor::value::from_value(value)?;
             log::trace!("Received module list: {:?}", rawmodlist);
@@ -518,12 +918,43 @@ async fn perform_service_info(
         Some(l) => l,
     };
 
+    if !devmod.is_empty() {
+        log::info!(
+            "Device {:?} reported devmod: {}",
+            device_guid,
+            devmod.to_text().trim_end().replace('\n', ", ")
+        );
+        // Only kept for inventory, so don't fail the onboarding over it
+        if let Err(e) = user_data
+            .ownership_voucher_store
+            .store_metadata(
+                &device_guid,
+                &MetadataKey::Local(OwnershipVoucherStoreMetadataKey::DevMod),
+                &devmod,
+            )
+            .await
+        {
+            log::warn!("Error storing devmod of device {:?}: {:?}", device_guid, e);
+        }
+    }
+
+    // The serviceinfo API server can use the device info to pick per-device settings
+    let device_info = match user_data
+        .ownership_voucher_store
+        .load_data(&device_guid)
+        .await?
+    {
+        Some(ov) => ov.header().device_info().to_string(),
+        None => String::new(),
+    };
+
     let resp: ServiceInfoApiReply = user_data
         .service_info_api_client
         .send_get([
             ("serviceinfo_api_version", "1"),
             ("device_guid", &device_guid.to_string()),
             ("modules", &module_list.join(",")),
+            ("device_info", &device_info),
         ])
         .await?;
 
@@ -562,9 +993,15 @@ async fn perform_service_info(
                         .as_str()
                         .ok_or_else(|| anyhow::anyhow!("Invalid API response: non-hex"))?,
                 )?;
-                let value = serde_bytes::ByteBuf::from(value);
                 let key = key.replace("|hex", "");
-                out_si.add(module, &key, &value)?;
+                if module == StandardServiceInfoModule::Download.into() && key == "data" {
+                    for chunk in value.chunks(max_size - DOWNLOAD_CHUNK_OVERHEAD) {
+                        out_si.add(module.clone(), &key, &serde_bytes::Bytes::new(chunk))?;
+                    }
+                } else {
+                    let value = serde_bytes::ByteBuf::from(value);
+                    out_si.add(module, &key, &value)?;
+                }
             } else {
                 out_si.add(module, &key, &value)?;
             }
@@ -578,9 +1015,8 @@ async fn perform_service_info(
 
     log::trace!("Sending ServiceInfo result: {:?}", out_si);
 
-    Ok(messages::v11::to2::OwnerServiceInfo::new(
-        false, false, out_si,
-    ))
+    let cursor = user_data.service_info_spool.write(&out_si).await?;
+    next_service_info_page(&user_data.service_info_spool, session, cursor, max_size).await
 }
 
 pub(super) async fn done(
@@ -601,25 +1037,11 @@ pub(super) async fn done(
         }
     };
 
-    let nonce6: Nonce = match ses_with_store.session.get("nonce6") {
-        Some(v) => v,
-        None => {
-            return Err(Error::new(
-                ErrorCode::InvalidMessageError,
-                messages::v11::to2::ProveDevice::message_type(),
-                "Request sequence failure",
-            )
-            .into())
-        }
-    };
-    if &nonce6 != msg.nonce6() {
-        return Err(Error::new(
-            ErrorCode::InvalidMessageError,
-            messages::v11::to2::ProveDevice::message_type(),
-            "Nonce6 invalid",
-        )
-        .into());
-    }
+    consume_session_nonce::<messages::v11::to2::Done>(
+        &mut ses_with_store.session,
+        "nonce6",
+        msg.nonce6(),
+    )?;
 
     let device_guid: String = match ses_with_store.session.get("device_guid") {
         Some(v) => v,
@@ -647,18 +1069,46 @@ pub(super) async fn done(
         }
     };
 
-    user_data
-        .ownership_voucher_store
-        .store_metadata(
-            &device_guid,
-            &MetadataKey::Local(OwnershipVoucherStoreMetadataKey::To2Performed),
-            &true,
-        )
-        .await
-        .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    // With credential reuse the voucher stays valid, so keep reporting it to the
+    // rendezvous server for the next onboarding
+    if !user_data.credential_reuse_enabled {
+        user_data
+            .ownership_voucher_store
+            .store_metadata(
+                &device_guid,
+                &MetadataKey::Local(OwnershipVoucherStoreMetadataKey::To2Performed),
+                &true,
+            )
+            .await
+            .map_err(Error::from_error::<messages::v11::to2::ProveDevice, _>)?;
+    }
 
+    if let Some(resale_ov) = ses_with_store.session.get::<String>("resale_ov") {
+        let resale_ov = OwnershipVoucher::from_pem(resale_ov.as_bytes())
+            .map_err(Error::from_error::<messages::v11::to2::Done, _>)?;
+        log::info!(
+            "Device {:?}: storing resale voucher with GUID {:?}",
+            device_guid,
+            resale_ov.header().guid()
+        );
+        user_data
+            .ownership_voucher_store
+            .store_data(resale_ov.header().guid().clone(), resale_ov)
+            .await
+            .map_err(Error::from_error::<messages::v11::to2::Done, _>)?;
+    }
+
+    if let Some(cursor) = ses_with_store
+        .session
+        .get::<ServiceInfoCursor>("service_info_cursor")
+    {
+        cursor.remove(&user_data.service_info_spool).await;
+    }
     ses_with_store.session.remove("nonce7");
     ses_with_store.session.destroy();
+    user_data.events.publish(OnboardingEvent::To2Completed {
+        guid: device_guid.to_string(),
+    });
 
     Ok((messages::v11::to2::Done2::new(nonce7), ses_with_store))
 }
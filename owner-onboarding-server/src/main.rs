@@ -3,9 +3,8 @@ use std::fs;
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
-use fdo_data_formats::constants::HashType;
 use fdo_data_formats::enhanced_types::RendezvousInterpreterSide;
-use fdo_data_formats::types::{COSESign, Hash, TO0Data, TO1DataPayload};
+use fdo_data_formats::types::{Hash, TO0Data, TO1DataPayload};
 use fdo_data_formats::{messages, ProtocolVersion, Serializable};
 use fdo_http_wrapper::client::RequestResult;
 use openssl::{
@@ -24,21 +23,36 @@ use warp::Filter;
 use fdo_data_formats::{
     enhanced_types::X5Bag,
     ownershipvoucher::OwnershipVoucher,
-    publickey::PublicKey,
+    publickey::{PublicKey, X5Chain},
     types::{Guid, TO2AddressEntry},
 };
-use fdo_store::Store;
+use fdo_store::{CacheMetrics, Store};
+use fdo_util::crypto_policy::CryptoPolicy;
+use fdo_util::ondie::OnDieCache;
+use fdo_util::revocation::RevocationChecker;
 use fdo_util::servers::{
-    configuration::{owner_onboarding_server::OwnerOnboardingServerSettings, AbsolutePathBuf},
-    settings_for, OwnershipVoucherStoreMetadataKey,
+    authorization::AccessControl,
+    configuration::{owner_onboarding_server::OwnerOnboardingServerSettings, Permission},
+    load_settings, OwnershipVoucherStoreMetadataKey,
 };
+use fdo_util::signing::SigningKey;
 
+mod devmod;
+mod events;
 mod handlers;
+mod management;
+mod policy;
+mod spool;
+mod to0;
 
 pub(crate) struct OwnerServiceUD {
     // Trusted keys
-    #[allow(dead_code)]
     trusted_device_keys: X5Bag,
+    ondie: Option<OnDieCache>,
+    verify_device_certificate_chains: bool,
+    voucher_policy: policy::VoucherPolicy,
+    revocation: Option<RevocationChecker>,
+    crypto_policy: Option<CryptoPolicy>,
 
     // Stores
     ownership_voucher_store: Box<
@@ -49,10 +63,11 @@ pub(crate) struct OwnerServiceUD {
             OwnershipVoucherStoreMetadataKey,
         >,
     >,
+    ownership_voucher_cache_metrics: Option<Arc<CacheMetrics>>,
     session_store: Arc<fdo_http_wrapper::server::SessionStore>,
 
     // Our keys
-    owner_key: PKey<Private>,
+    owner_key: SigningKey,
     owner_pubkey: PublicKey,
 
     // The new Owner2Key, randomly generated, but not stored
@@ -61,15 +76,100 @@ pub(crate) struct OwnerServiceUD {
 
     // ServiceInfo API server configuration
     service_info_api_client: fdo_http_wrapper::client::JsonClient,
+    max_device_service_info_size: Option<u64>,
+    service_info_spool: spool::ServiceInfoSpool,
 
     owner_addresses: Vec<TO2AddressEntry>,
+    to0_registration: to0::To0Registration,
+
+    events: events::EventPublishers,
+
+    // Management API
+    management_access_control: Option<AccessControl>,
+    // Configuration as shown by the management API, without secrets
+    redacted_configuration: serde_json::Value,
+
+    credential_reuse_enabled: bool,
+    resale_enabled: bool,
+}
+
+impl OwnerServiceUD {
+    /// Verifies the device certificate chain of a voucher, as far as configured.
+    ///
+    /// OnDie chains are always verified when OnDie support is enabled, other chains only
+    /// when `verify_device_certificate_chains` is set. Other chains are checked for
+    /// revocation if configured.
+    pub(crate) async fn verify_device_certificate_chain(&self, chain: &X5Chain) -> Result<()> {
+        match &self.ondie {
+            Some(ondie) if ondie.is_ondie_chain(chain) => {
+                ondie.verify(chain)?;
+                return Ok(());
+            }
+            _ if self.verify_device_certificate_chains => {
+                chain
+                    .verify_full(&self.trusted_device_keys)
+                    .context("Device certificate chain not trusted")?;
+            }
+            _ => {}
+        }
+        if let Some(revocation) = &self.revocation {
+            revocation
+                .check_chain(chain, &self.trusted_device_keys)
+                .await
+                .context("Device certificate chain revocation check failed")?;
+        }
+        Ok(())
+    }
+
+    /// Checks the manufacturer and owner certificates of a voucher for revocation, if
+    /// configured.
+    pub(crate) async fn check_voucher_revocation(&self, ov: &OwnershipVoucher) -> Result<()> {
+        if let Some(revocation) = &self.revocation {
+            let no_trust_anchors = X5Bag::new();
+            let trust_anchors = self
+                .voucher_policy
+                .trusted_manufacturer_keys()
+                .unwrap_or(&no_trust_anchors);
+            revocation
+                .check_voucher(ov, trust_anchors)
+                .await
+                .context("Voucher revocation check failed")?;
+        }
+        Ok(())
+    }
+
+    /// Checks the algorithms used in a voucher against the crypto policy, if configured.
+    pub(crate) fn check_voucher_crypto_policy(&self, ov: &OwnershipVoucher) -> Result<()> {
+        if let Some(crypto_policy) = &self.crypto_policy {
+            crypto_policy
+                .check_voucher(ov)
+                .context("Voucher does not comply with the crypto policy")?;
+        }
+        Ok(())
+    }
 }
 
 pub(crate) type OwnerServiceUDT = Arc<OwnerServiceUD>;
 
-fn load_private_key(path: &AbsolutePathBuf) -> Result<PKey<Private>> {
-    let contents = fs::read(path)?;
-    Ok(PKey::private_key_from_der(&contents)?)
+const MANAGEMENT_MAX_UPLOAD_SIZE: u64 = 1024 * 1024;
+
+fn load_owner_key(settings: &OwnerOnboardingServerSettings) -> Result<SigningKey> {
+    match (
+        &settings.owner_private_key_path,
+        &settings.owner_private_key_uri,
+    ) {
+        (Some(path), None) => {
+            let contents = fs::read(path)?;
+            Ok(SigningKey::Pkey(PKey::private_key_from_der(&contents)?))
+        }
+        (None, Some(uri)) => SigningKey::load(uri),
+        (None, None) => {
+            bail!("Either owner_private_key_path or owner_private_key_uri must be configured")
+        }
+        (Some(_), Some(_)) => {
+            bail!("Only one of owner_private_key_path and owner_private_key_uri may be configured")
+        }
+    }
 }
 
 async fn report_to_rendezvous(udt: OwnerServiceUDT) -> Result<()> {
@@ -85,36 +185,17 @@ async fn report_to_rendezvous(udt: OwnerServiceUDT) -> Result<()> {
 
     let ov_iter = ft.query().await?;
     if let Some(ovs) = ov_iter {
-        for ov in ovs {
-            match report_ov_to_rendezvous(&ov, &udt.owner_addresses, &udt.owner_key).await {
-                Ok(wait_seconds) => {
-                    udt.ownership_voucher_store
-                        .store_metadata(
-                            ov.header().guid(),
-                            &fdo_store::MetadataKey::Local(
-                                OwnershipVoucherStoreMetadataKey::To0AcceptOwnerWaitSeconds,
-                            ),
-                            &time::Duration::new(wait_seconds.into(), 0),
-                        )
-                        .await?;
-                }
-                Err(e) => {
-                    log::warn!(
-                        "OV({}): failed to report to rendezvous: {}",
-                        ov.header().guid().to_string(),
-                        e
-                    );
-                }
-            };
-        }
+        to0::register_vouchers(&udt, ovs.collect()).await?;
     }
     Ok(())
 }
 
-async fn report_ov_to_rendezvous(
+pub(crate) async fn report_ov_to_rendezvous(
     ov: &OwnershipVoucher,
     owner_addresses: &[TO2AddressEntry],
-    owner_key: &PKey<Private>,
+    owner_key: &SigningKey,
+    rate_limiter: &to0::RendezvousRateLimiter,
+    wait_seconds: u32,
 ) -> Result<u32> {
     let ov_header = ov.header();
     if ov_header.protocol_version() != ProtocolVersion::Version1_1 {
@@ -149,8 +230,11 @@ async fn report_ov_to_rendezvous(
                 rv_url
             );
 
+            rate_limiter.wait(&rv_url).await;
+            // Rendezvous servers may only speak protocol version 1.0
             let mut rv_client =
-                fdo_http_wrapper::client::ServiceClient::new(ProtocolVersion::Version1_1, &rv_url);
+                fdo_http_wrapper::client::ServiceClient::new(ProtocolVersion::Version1_1, &rv_url)
+                    .with_version_negotiation();
 
             // Send: Hello, Receive: HelloAck
             let hello_ack: RequestResult<messages::v11::to0::HelloAck> = rv_client
@@ -166,16 +250,19 @@ async fn report_ov_to_rendezvous(
             };
 
             // Build to0d and to1d
-            // TODO(runcom): 600 has to come from configuration
-            let to0d = TO0Data::new(ov.clone(), 600, hello_ack.nonce3().clone())
+            let to0d = TO0Data::new(ov.clone(), wait_seconds, hello_ack.nonce3().clone())
                 .context("Error creating to0d")?;
             let to0d_vec = to0d.serialize_data().context("Error serializing TO0Data")?;
+            let to0d_hash_type = owner_key
+                .hash_type()
+                .context("Unsupported owner key type")?;
             let to0d_hash =
-                Hash::from_data(HashType::Sha384, &to0d_vec).context("Error hashing to0d")?;
+                Hash::from_data(to0d_hash_type, &to0d_vec).context("Error hashing to0d")?;
             let to0d = ByteBuf::from(to0d_vec);
             let to1d_payload = TO1DataPayload::new(Vec::from(owner_addresses), to0d_hash);
-            let to1d =
-                COSESign::new(&to1d_payload, None, owner_key).context("Error signing to1d")?;
+            let to1d = owner_key
+                .cose_sign(&to1d_payload, None)
+                .context("Error signing to1d")?;
             // Send: OwnerSign, Receive: AcceptOwner
             let msg = messages::v11::to0::OwnerSign::new(to0d, to1d)
                 .context("Error creating OwnerSign message")?;
@@ -210,10 +297,12 @@ async fn perform_maintenance(udt: OwnerServiceUDT) -> std::result::Result<(), &'
 
         let ov_maint = udt.ownership_voucher_store.perform_maintenance();
         let ses_maint = udt.session_store.perform_maintenance();
+        let spool_maint = udt.service_info_spool.perform_maintenance();
         let rtr_maint = report_to_rendezvous(udt.clone());
 
         #[allow(unused_must_use)]
-        let (ov_res, ses_res, rtr_res) = tokio::join!(ov_maint, ses_maint, rtr_maint);
+        let (ov_res, ses_res, spool_res, rtr_res) =
+            tokio::join!(ov_maint, ses_maint, spool_maint, rtr_maint);
 
         if let Err(e) = ov_res {
             log::warn!("Error during ownership voucher store maintenance: {:?}", e);
@@ -221,13 +310,25 @@ async fn perform_maintenance(udt: OwnerServiceUDT) -> std::result::Result<(), &'
         if let Err(e) = ses_res {
             log::warn!("Error during session store maintenance: {:?}", e);
         }
+        if let Err(e) = spool_res {
+            log::warn!("Error during ServiceInfo spool maintenance: {:?}", e);
+        }
         if let Err(e) = rtr_res {
             log::warn!("Error during report to rendezvous maintenance: {:?}", e)
         }
+
+        if let Some(metrics) = &udt.ownership_voucher_cache_metrics {
+            log::info!(
+                "Ownership voucher cache: {} hits, {} misses, {} evictions",
+                metrics.hits(),
+                metrics.misses(),
+                metrics.evictions()
+            );
+        }
     }
 }
 
-/// Generate an ephemeral owner2 key: we do not support reuse or resale protocols
+/// Generate an ephemeral owner2 key, used when not offering credential reuse
 fn generate_owner2_keys() -> Result<(PKey<Private>, PublicKey)> {
     let owner2_key_group =
         EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).context("Error getting nist 256 group")?;
@@ -272,12 +373,14 @@ async fn main() -> Result<()> {
         bail!("Provide environment ALLOW_NONINTEROPERABLE_KDF=1 to enable interoperable KDF");
     }
 
-    let settings: OwnerOnboardingServerSettings = settings_for("owner-onboarding-server")?
-        .try_deserialize()
-        .context("Error parsing configuration")?;
+    let settings: OwnerOnboardingServerSettings = load_settings("owner-onboarding-server")?;
+    let redacted_configuration =
+        management::redacted_configuration(&settings).context("Error serializing configuration")?;
 
     // Bind information
     let bind_addr = settings.bind.clone();
+    let drain_timeout = std::time::Duration::from_secs(settings.drain_timeout_seconds);
+    let max_concurrent_requests = settings.max_concurrent_requests;
 
     // Trusted keys
     let trusted_device_keys = {
@@ -289,14 +392,32 @@ async fn main() -> Result<()> {
     };
     let trusted_device_keys = X5Bag::with_certs(trusted_device_keys)
         .context("Error building trusted device keys X5Bag")?;
+    let ondie = match &settings.ondie {
+        Some(ondie) => Some(
+            OnDieCache::from_settings(ondie)
+                .await
+                .context("Error loading OnDie cache")?,
+        ),
+        None => None,
+    };
+
+    let voucher_policy = policy::VoucherPolicy::from_settings(&settings.voucher_policy)
+        .context("Error loading voucher policy")?;
+    let revocation = settings
+        .revocation
+        .as_ref()
+        .map(RevocationChecker::from_settings)
+        .transpose()
+        .context("Error setting up revocation checking")?;
+    let crypto_policy = settings
+        .crypto_policy
+        .as_ref()
+        .map(CryptoPolicy::from_settings)
+        .transpose()
+        .context("Error loading crypto policy")?;
 
     // Our private key
-    let owner_key = load_private_key(&settings.owner_private_key_path).with_context(|| {
-        format!(
-            "Error loading owner key from {}",
-            &settings.owner_private_key_path
-        )
-    })?;
+    let owner_key = load_owner_key(&settings).context("Error loading owner key")?;
     let owner_pubkey = {
         let contents = std::fs::read(&settings.owner_public_key_path).with_context(|| {
             format!(
@@ -307,17 +428,45 @@ async fn main() -> Result<()> {
         PublicKey::try_from(X509::from_pem(&contents).context("Error parsing owner public key")?)
             .context("Error converting owner public key to PK")?
     };
+    if let Some(crypto_policy) = &crypto_policy {
+        crypto_policy
+            .check_public_key(&owner_pubkey)
+            .context("Owner key does not comply with the crypto policy")?;
+    }
 
     // Initialize stores
     let ownership_voucher_store = settings
         .ownership_voucher_store_driver
         .initialize()
         .context("Error initializing ownership voucher datastore")?;
+    let (ownership_voucher_store, ownership_voucher_cache_metrics) =
+        match &settings.ownership_voucher_cache {
+            None => (ownership_voucher_store, None),
+            Some(cache_config) => {
+                log::info!(
+                    "Caching up to {} ownership vouchers for {} seconds",
+                    cache_config.max_entries,
+                    cache_config.ttl_seconds
+                );
+                let (store, metrics) = cache_config.wrap(ownership_voucher_store);
+                (store, Some(metrics))
+            }
+        };
     let session_store = settings
         .session_store_driver
         .initialize()
         .context("Error initializing session store")?;
-    let session_store = fdo_http_wrapper::server::SessionStore::new(session_store);
+    let session_store_encryption_key = settings
+        .session_store_encryption_key_path
+        .as_ref()
+        .map(|path| fdo_http_wrapper::server::SessionEncryptionKey::load(path.as_ref()))
+        .transpose()
+        .context("Error loading session store encryption key")?;
+    let session_store =
+        fdo_http_wrapper::server::SessionStore::new(session_store, session_store_encryption_key);
+
+    let to0_registration = to0::To0Registration::new(settings.to0_registration.clone())
+        .context("Invalid TO0 registration settings")?;
 
     // Generate a new Owner2
     let (owner2_key, owner2_pub) =
@@ -332,20 +481,55 @@ async fn main() -> Result<()> {
     }
 
     // ServiceInfo API client
-    let service_info_api_client = fdo_http_wrapper::client::JsonClient::new(
+    let service_info_api_ca = match &settings.service_info_api_ca_path {
+        None => None,
+        Some(path) => Some(
+            fs::read(path)
+                .with_context(|| format!("Error reading serviceinfo API CA from {path}"))?,
+        ),
+    };
+    let service_info_api_client = fdo_http_wrapper::client::JsonClient::new_with_root_certificate(
         settings.service_info_api_url,
         settings.service_info_api_authentication,
+        service_info_api_ca.as_deref(),
     )
     .context("Error generating serviceinfo API server")?;
+    let service_info_api_client = match settings.service_info_api_unix_socket {
+        None => service_info_api_client,
+        Some(path) => {
+            log::info!("Connecting to the serviceinfo API server over {}", path);
+            service_info_api_client.with_unix_socket(path.as_ref().to_path_buf())
+        }
+    };
+
+    let service_info_spool =
+        spool::ServiceInfoSpool::new(settings.service_info_spool_store_driver.as_ref())
+            .context("Error setting up the ServiceInfo spool")?;
+
+    let events = events::EventPublishers::from_settings(&settings.event_publishers)
+        .await
+        .context("Error setting up event publishers")?;
+
+    let management_access_control = AccessControl::from_token_or_settings(
+        settings.management_api_auth_token,
+        settings.management_api_access_control,
+    )
+    .context("Error setting up management API access control")?;
 
     // Initialize user data
     let user_data = Arc::new(OwnerServiceUD {
         // Stores
         ownership_voucher_store,
+        ownership_voucher_cache_metrics,
         session_store: session_store.clone(),
 
         // Trusted keys
         trusted_device_keys,
+        ondie,
+        verify_device_certificate_chains: settings.verify_device_certificate_chains,
+        voucher_policy,
+        revocation,
+        crypto_policy,
 
         // Private owner key
         owner_key,
@@ -357,9 +541,21 @@ async fn main() -> Result<()> {
 
         // Service Info
         service_info_api_client,
+        max_device_service_info_size: settings.max_device_service_info_size,
+        service_info_spool,
 
         // Owner addresses
         owner_addresses,
+        to0_registration,
+
+        events,
+
+        // Management API
+        management_access_control,
+        redacted_configuration,
+
+        credential_reuse_enabled: settings.credential_reuse_enabled,
+        resale_enabled: settings.resale_enabled,
     });
 
     // Initialize handlers
@@ -412,7 +608,94 @@ async fn main() -> Result<()> {
         .untuple_one()
         .and_then(handlers::report_to_rendezvous_handler);
 
+    // Management API
+    let management_ov_path = warp::path("management")
+        .and(warp::path("v1"))
+        .and(warp::path("ownership_voucher"));
+    let handler_management_upload = warp::post()
+        .and(management_ov_path)
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::UploadVouchers,
+        ))
+        .and(warp::body::content_length_limit(MANAGEMENT_MAX_UPLOAD_SIZE))
+        .and(warp::body::bytes())
+        .and_then(management::upload_ownership_vouchers);
+    let handler_management_list = warp::get()
+        .and(management_ov_path)
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::ReadVouchers,
+        ))
+        .and(warp::query::query::<management::ListQuery>())
+        .and_then(management::list_ownership_vouchers);
+    let handler_management_get = warp::get()
+        .and(management_ov_path)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::ReadVouchers,
+        ))
+        .and_then(management::get_ownership_voucher);
+    let handler_management_delete = warp::delete()
+        .and(management_ov_path)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::DeleteVouchers,
+        ))
+        .and_then(management::delete_ownership_voucher);
+    let handler_management_resell = warp::post()
+        .and(management_ov_path)
+        .and(warp::path::param::<String>())
+        .and(warp::path("resale"))
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::ResellVouchers,
+        ))
+        .and(warp::body::content_length_limit(MANAGEMENT_MAX_UPLOAD_SIZE))
+        .and(warp::body::bytes())
+        .and_then(management::resell_ownership_voucher);
+    let handler_management_status = warp::get()
+        .and(management_ov_path)
+        .and(warp::path::param::<String>())
+        .and(warp::path("status"))
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::ReadVouchers,
+        ))
+        .and_then(management::ownership_voucher_status);
+    let handler_management_register = warp::post()
+        .and(management_ov_path)
+        .and(warp::path::param::<String>())
+        .and(warp::path("registration"))
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::RegisterVouchers,
+        ))
+        .and_then(management::register_ownership_voucher);
+    let handler_management_configuration = warp::get()
+        .and(warp::path("management"))
+        .and(warp::path("v1"))
+        .and(warp::path("configuration"))
+        .and(warp::path::end())
+        .and(management::management_auth(
+            user_data.clone(),
+            Permission::ReadConfiguration,
+        ))
+        .and_then(management::get_configuration);
+
     let routes = warp::post()
+        .and(fdo_http_wrapper::server::request_concurrency_limit(
+            max_concurrent_requests,
+        ))
         .and(
             hello
                 .or(handler_ping)
@@ -425,21 +708,36 @@ async fn main() -> Result<()> {
                 .or(handler_to2_device_service_info)
                 .or(handler_to2_done),
         )
+        .map(|_slot, reply| reply)
+        // Management
+        .or(handler_management_upload)
+        .or(handler_management_list)
+        .or(handler_management_get)
+        .or(handler_management_delete)
+        .or(handler_management_resell)
+        .or(handler_management_status)
+        .or(handler_management_register)
+        .or(handler_management_configuration)
         .recover(fdo_http_wrapper::server::handle_rejection)
         .with(warp::log("owner-onboarding-service"));
 
     log::info!("Listening on {}", bind_addr);
-    let server = warp::serve(routes);
+    // On SIGTERM, let the sessions in progress finish before no longer accepting connections
+    let draining_session_store = user_data.session_store.clone();
+    let server = fdo_http_wrapper::server::serve_with_graceful_shutdown(
+        routes,
+        bind_addr.into(),
+        async move {
+            signal(SignalKind::terminate()).unwrap().recv().await;
+            log::info!("Terminating, draining sessions in progress");
+            draining_session_store.drain(drain_timeout).await;
+        },
+    )
+    .context("Error binding server")?;
 
     let maintenance_runner =
         tokio::spawn(async move { perform_maintenance(user_data.clone()).await });
 
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
-            signal(SignalKind::terminate()).unwrap().recv().await;
-            log::info!("Terminating");
-        })
-        .1;
     let server = tokio::spawn(server);
 
     tokio::select!(
@@ -0,0 +1,445 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use anyhow::Context;
+use fdo_data_formats::{ownershipvoucher::OwnershipVoucher, publickey::PublicKey, types::Guid};
+use fdo_store::MetadataKey;
+use fdo_util::servers::{
+    configuration::{owner_onboarding_server::OwnerOnboardingServerSettings, Permission},
+    OwnershipVoucherStoreMetadataKey,
+};
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::{HeaderMap, Method, StatusCode},
+    path::FullPath,
+    Filter,
+};
+
+// Settings holding secrets, which the configuration endpoint doesn't show
+const REDACTED_SETTINGS: &[&str] = &[
+    "management_api_auth_token",
+    "auth_token",
+    "token",
+    "oidc",
+    "password",
+    "client_certificate",
+];
+
+#[derive(Debug)]
+struct ManagementFailure(anyhow::Error);
+impl warp::reject::Reject for ManagementFailure {}
+
+fn failure<E: Into<anyhow::Error>>(e: E) -> warp::Rejection {
+    warp::reject::custom(ManagementFailure(e.into()))
+}
+
+async fn management_auth_handler(
+    udt: crate::OwnerServiceUDT,
+    permission: Permission,
+    method: Method,
+    path: FullPath,
+    headers: HeaderMap,
+) -> Result<crate::OwnerServiceUDT, warp::Rejection> {
+    let access_control = match &udt.management_access_control {
+        None => {
+            log::warn!("Management API disabled");
+            return Err(warp::reject::not_found());
+        }
+        Some(access_control) => access_control,
+    };
+
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let client_certificate_subject = access_control
+        .client_certificate_subject_header()
+        .and_then(header);
+    match access_control.authorize(
+        header("Authorization"),
+        client_certificate_subject,
+        permission,
+        &format!("{} {}", method, path.as_str()),
+    ) {
+        Some(_) => Ok(udt),
+        None => Err(warp::reject::reject()),
+    }
+}
+
+/// Only lets management requests through from callers with `permission`
+pub(crate) fn management_auth(
+    udt: crate::OwnerServiceUDT,
+    permission: Permission,
+) -> impl Filter<Extract = (crate::OwnerServiceUDT,), Error = warp::Rejection> + Clone {
+    warp::any()
+        .map(move || udt.clone())
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and_then(
+            move |udt: crate::OwnerServiceUDT,
+                  method: Method,
+                  path: FullPath,
+                  headers: HeaderMap| {
+                management_auth_handler(udt, permission, method, path, headers)
+            },
+        )
+}
+
+#[derive(Debug, Serialize)]
+struct OwnershipVoucherInfo {
+    guid: String,
+    device_info: String,
+}
+
+impl From<&OwnershipVoucher> for OwnershipVoucherInfo {
+    fn from(ov: &OwnershipVoucher) -> Self {
+        OwnershipVoucherInfo {
+            guid: ov.header().guid().to_string(),
+            device_info: ov.header().device_info().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RejectedOwnershipVoucher {
+    guid: String,
+    reason: crate::policy::PolicyViolation,
+}
+
+#[derive(Debug, Serialize)]
+struct OwnershipVoucherStatus {
+    guid: String,
+    device_info: String,
+    // Whether the device completed TO2 with this voucher
+    onboarded: bool,
+    // Whether the voucher is due to be registered with the rendezvous servers
+    registration_due: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ListQuery {
+    device_info: Option<String>,
+    // Only list the vouchers of devices that didn't onboard yet
+    #[serde(default)]
+    pending: bool,
+}
+
+fn parse_guid(guid: &str) -> Result<Guid, warp::Rejection> {
+    Guid::from_str(guid).map_err(|_| warp::reject::not_found())
+}
+
+async fn load_ownership_voucher(
+    udt: &crate::OwnerServiceUDT,
+    guid: &Guid,
+) -> Result<OwnershipVoucher, warp::Rejection> {
+    match udt
+        .ownership_voucher_store
+        .load_data(guid)
+        .await
+        .map_err(failure)?
+    {
+        None => Err(warp::reject::not_found()),
+        Some(ov) => Ok(ov),
+    }
+}
+
+/// Vouchers of the devices that didn't complete TO2 yet
+async fn pending_ownership_vouchers(
+    udt: &crate::OwnerServiceUDT,
+) -> Result<Vec<OwnershipVoucher>, warp::Rejection> {
+    let mut ft = udt
+        .ownership_voucher_store
+        .query_data()
+        .await
+        .map_err(failure)?;
+    ft.neq(
+        &MetadataKey::Local(OwnershipVoucherStoreMetadataKey::To2Performed),
+        &true,
+    );
+    Ok(ft
+        .query()
+        .await
+        .map_err(failure)?
+        .map(|ovs| ovs.collect())
+        .unwrap_or_default())
+}
+
+/// Vouchers that the next TO0 round registers with the rendezvous servers
+async fn registration_due_ownership_vouchers(
+    udt: &crate::OwnerServiceUDT,
+) -> Result<Vec<OwnershipVoucher>, warp::Rejection> {
+    let mut ft = udt
+        .ownership_voucher_store
+        .query_data()
+        .await
+        .map_err(failure)?;
+    ft.lt(
+        &MetadataKey::Local(OwnershipVoucherStoreMetadataKey::To0AcceptOwnerWaitSeconds),
+        time::OffsetDateTime::now_utc().unix_timestamp(),
+    );
+    Ok(ft
+        .query()
+        .await
+        .map_err(failure)?
+        .map(|ovs| ovs.collect())
+        .unwrap_or_default())
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if REDACTED_SETTINGS.contains(&key.as_str()) {
+                    if !value.is_null() {
+                        *value = serde_json::Value::String("<redacted>".to_string());
+                    }
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// The configuration of the server, with the secrets replaced.
+pub(crate) fn redacted_configuration(
+    settings: &OwnerOnboardingServerSettings,
+) -> anyhow::Result<serde_json::Value> {
+    let mut configuration = serde_json::to_value(settings)?;
+    redact(&mut configuration);
+    Ok(configuration)
+}
+
+pub(crate) async fn upload_ownership_vouchers(
+    udt: crate::OwnerServiceUDT,
+    body: warp::hyper::body::Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Accept either one or more PEM encoded vouchers, or a single raw COSE voucher
+    let ovs = if body.starts_with(b"-----BEGIN") {
+        OwnershipVoucher::many_from_pem(&body).map_err(failure)?
+    } else {
+        vec![OwnershipVoucher::from_pem_or_raw(&body).map_err(failure)?]
+    };
+
+    // Nothing is stored if any of the vouchers is rejected
+    let rejected: Vec<RejectedOwnershipVoucher> = ovs
+        .iter()
+        .filter_map(|ov| match udt.voucher_policy.check(ov) {
+            Ok(()) => None,
+            Err(reason) => {
+                log::info!(
+                    "OV({}): rejected by voucher policy: {}",
+                    ov.header().guid().to_string(),
+                    reason
+                );
+                Some(RejectedOwnershipVoucher {
+                    guid: ov.header().guid().to_string(),
+                    reason,
+                })
+            }
+        })
+        .collect();
+    if !rejected.is_empty() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&rejected),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ));
+    }
+
+    for ov in &ovs {
+        if let Some(chain) = ov.device_certificate_chain() {
+            udt.verify_device_certificate_chain(chain)
+                .await
+                .with_context(|| format!("OV({}) rejected", ov.header().guid().to_string()))
+                .map_err(failure)?;
+        }
+        udt.check_voucher_revocation(ov)
+            .await
+            .with_context(|| format!("OV({}) rejected", ov.header().guid().to_string()))
+            .map_err(failure)?;
+        udt.check_voucher_crypto_policy(ov)
+            .with_context(|| format!("OV({}) rejected", ov.header().guid().to_string()))
+            .map_err(failure)?;
+    }
+
+    let mut stored = Vec::new();
+    for ov in ovs {
+        let info = OwnershipVoucherInfo::from(&ov);
+        log::info!("OV({}): stored through management API", info.guid);
+        udt.ownership_voucher_store
+            .store_data(ov.header().guid().clone(), ov)
+            .await
+            .map_err(failure)?;
+        stored.push(info);
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&stored),
+        StatusCode::CREATED,
+    ))
+}
+
+pub(crate) async fn list_ownership_vouchers(
+    udt: crate::OwnerServiceUDT,
+    query: ListQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let ovs = if query.pending {
+        pending_ownership_vouchers(&udt).await?
+    } else {
+        udt.ownership_voucher_store
+            .load_all_data()
+            .await
+            .map_err(failure)?
+    };
+
+    let ovs: Vec<OwnershipVoucherInfo> = ovs
+        .iter()
+        .filter(|ov| match &query.device_info {
+            None => true,
+            Some(device_info) => ov.header().device_info() == device_info,
+        })
+        .map(OwnershipVoucherInfo::from)
+        .collect();
+
+    Ok(warp::reply::json(&ovs))
+}
+
+pub(crate) async fn get_ownership_voucher(
+    guid: String,
+    udt: crate::OwnerServiceUDT,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let guid = parse_guid(&guid)?;
+    let ov = match udt
+        .ownership_voucher_store
+        .load_data(&guid)
+        .await
+        .map_err(failure)?
+    {
+        None => return Err(warp::reject::not_found()),
+        Some(ov) => ov,
+    };
+
+    Ok(warp::reply::with_header(
+        ov.to_pem().map_err(failure)?,
+        "Content-Type",
+        "application/x-pem-file",
+    ))
+}
+
+pub(crate) async fn delete_ownership_voucher(
+    guid: String,
+    udt: crate::OwnerServiceUDT,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let guid = parse_guid(&guid)?;
+    if udt
+        .ownership_voucher_store
+        .load_data(&guid)
+        .await
+        .map_err(failure)?
+        .is_none()
+    {
+        return Err(warp::reject::not_found());
+    }
+    udt.ownership_voucher_store
+        .destroy_data(&guid)
+        .await
+        .map_err(failure)?;
+    log::info!("OV({}): deleted through management API", guid.to_string());
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn resell_ownership_voucher(
+    guid: String,
+    udt: crate::OwnerServiceUDT,
+    new_owner_cert: warp::hyper::body::Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let guid = parse_guid(&guid)?;
+    let mut ov = match udt
+        .ownership_voucher_store
+        .load_data(&guid)
+        .await
+        .map_err(failure)?
+    {
+        None => return Err(warp::reject::not_found()),
+        Some(ov) => ov,
+    };
+
+    let new_owner_pubkey = X509::from_pem(&new_owner_cert)
+        .map_err(failure)
+        .and_then(|cert| PublicKey::try_from(cert).map_err(failure))?;
+    match udt.owner_key.as_pkey() {
+        Some(owner_key) => ov.extend(owner_key, None, &new_owner_pubkey),
+        None => ov.extend_with_signer(
+            &udt.owner_key,
+            udt.owner_pubkey.pkey(),
+            None,
+            &new_owner_pubkey,
+        ),
+    }
+    .map_err(failure)?;
+    let ov = ov.to_pem().map_err(failure)?;
+
+    // The device belongs to the new owner now
+    udt.ownership_voucher_store
+        .destroy_data(&guid)
+        .await
+        .map_err(failure)?;
+    log::info!("OV({}): resold through management API", guid.to_string());
+
+    Ok(warp::reply::with_header(
+        ov,
+        "Content-Type",
+        "application/x-pem-file",
+    ))
+}
+
+pub(crate) async fn ownership_voucher_status(
+    guid: String,
+    udt: crate::OwnerServiceUDT,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let guid = parse_guid(&guid)?;
+    let ov = load_ownership_voucher(&udt, &guid).await?;
+
+    let onboarded = !pending_ownership_vouchers(&udt)
+        .await?
+        .iter()
+        .any(|ov| ov.header().guid() == &guid);
+    let registration_due = registration_due_ownership_vouchers(&udt)
+        .await?
+        .iter()
+        .any(|ov| ov.header().guid() == &guid);
+
+    let info = OwnershipVoucherInfo::from(&ov);
+    Ok(warp::reply::json(&OwnershipVoucherStatus {
+        guid: info.guid,
+        device_info: info.device_info,
+        onboarded,
+        registration_due,
+    }))
+}
+
+pub(crate) async fn register_ownership_voucher(
+    guid: String,
+    udt: crate::OwnerServiceUDT,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let guid = parse_guid(&guid)?;
+    let ov = load_ownership_voucher(&udt, &guid).await?;
+
+    log::info!(
+        "OV({}): registration requested through management API",
+        guid.to_string()
+    );
+    crate::to0::register_voucher(&udt, &ov)
+        .await
+        .map_err(failure)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn get_configuration(
+    udt: crate::OwnerServiceUDT,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&udt.redacted_configuration))
+}
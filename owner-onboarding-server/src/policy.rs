@@ -0,0 +1,155 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use openssl::{nid::Nid, pkey::Id, x509::X509};
+use serde::Serialize;
+
+use fdo_data_formats::{
+    enhanced_types::X5Bag, ownershipvoucher::OwnershipVoucher, ProtocolVersion,
+};
+use fdo_util::servers::configuration::owner_onboarding_server::{
+    DeviceKeyType, VoucherPolicySettings,
+};
+
+/// Reason for rejecting a voucher
+#[derive(Debug, Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub(crate) enum PolicyViolation {
+    ProtocolVersionNotAllowed {
+        protocol_version: ProtocolVersion,
+    },
+    TooManyEntries {
+        num_entries: u16,
+        max_entries: u16,
+    },
+    ManufacturerNotTrusted,
+    // The key type is None if there is no device certificate, or its type is unknown
+    DeviceKeyTypeNotAllowed {
+        device_key_type: Option<DeviceKeyType>,
+    },
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::ProtocolVersionNotAllowed { protocol_version } => {
+                write!(f, "protocol version {protocol_version} not allowed")
+            }
+            PolicyViolation::TooManyEntries {
+                num_entries,
+                max_entries,
+            } => write!(
+                f,
+                "voucher has {num_entries} entries, at most {max_entries} allowed"
+            ),
+            PolicyViolation::ManufacturerNotTrusted => write!(f, "manufacturer not trusted"),
+            PolicyViolation::DeviceKeyTypeNotAllowed {
+                device_key_type: Some(key_type),
+            } => write!(f, "device key type {key_type:?} not allowed"),
+            PolicyViolation::DeviceKeyTypeNotAllowed {
+                device_key_type: None,
+            } => write!(f, "device key type unknown"),
+        }
+    }
+}
+
+/// Policy deciding which vouchers the owner accepts
+#[derive(Debug)]
+pub(crate) struct VoucherPolicy {
+    trusted_manufacturer_keys: Option<X5Bag>,
+    max_entries: Option<u16>,
+    allowed_device_key_types: Option<Vec<DeviceKeyType>>,
+    allowed_protocol_versions: Option<Vec<ProtocolVersion>>,
+}
+
+fn device_key_type(cert: &X509) -> Option<DeviceKeyType> {
+    let key = cert.public_key().ok()?;
+    match key.id() {
+        Id::EC => match key.ec_key().ok()?.group().curve_name()? {
+            Nid::X9_62_PRIME256V1 => Some(DeviceKeyType::Secp256r1),
+            Nid::SECP384R1 => Some(DeviceKeyType::Secp384r1),
+            _ => None,
+        },
+        Id::RSA => match key.bits() {
+            2048 => Some(DeviceKeyType::Rsa2048),
+            3072 => Some(DeviceKeyType::Rsa3072),
+            _ => None,
+        },
+        Id::ED25519 => Some(DeviceKeyType::Ed25519),
+        _ => None,
+    }
+}
+
+impl VoucherPolicy {
+    pub(crate) fn from_settings(settings: &VoucherPolicySettings) -> Result<Self> {
+        let trusted_manufacturer_keys = settings
+            .trusted_manufacturer_keys_path
+            .as_ref()
+            .map(|path| -> Result<X5Bag> {
+                let contents = std::fs::read(path).with_context(|| {
+                    format!("Error reading trusted manufacturer keys at {path}")
+                })?;
+                let certs = X509::stack_from_pem(&contents)
+                    .context("Error parsing trusted manufacturer keys")?;
+                X5Bag::with_certs(certs).context("Error building trusted manufacturer keys X5Bag")
+            })
+            .transpose()?;
+
+        Ok(VoucherPolicy {
+            trusted_manufacturer_keys,
+            max_entries: settings.max_entries,
+            allowed_device_key_types: settings.allowed_device_key_types.clone(),
+            allowed_protocol_versions: settings.allowed_protocol_versions.clone(),
+        })
+    }
+
+    pub(crate) fn trusted_manufacturer_keys(&self) -> Option<&X5Bag> {
+        self.trusted_manufacturer_keys.as_ref()
+    }
+
+    /// Checks `ov` against the policy, returning the first violation found.
+    pub(crate) fn check(&self, ov: &OwnershipVoucher) -> Result<(), PolicyViolation> {
+        let header = ov.header();
+
+        if let Some(allowed) = &self.allowed_protocol_versions {
+            if !allowed.contains(&header.protocol_version()) {
+                return Err(PolicyViolation::ProtocolVersionNotAllowed {
+                    protocol_version: header.protocol_version(),
+                });
+            }
+        }
+
+        if let Some(max_entries) = self.max_entries {
+            if ov.num_entries() > max_entries {
+                return Err(PolicyViolation::TooManyEntries {
+                    num_entries: ov.num_entries(),
+                    max_entries,
+                });
+            }
+        }
+
+        if let Some(trusted_keys) = &self.trusted_manufacturer_keys {
+            let manufacturer_key = header.manufacturer_public_key();
+            let trusted = trusted_keys.contains_publickey(manufacturer_key)
+                || manufacturer_key
+                    .chain()
+                    .map(|chain| chain.verify_full(trusted_keys).is_ok())
+                    .unwrap_or(false);
+            if !trusted {
+                return Err(PolicyViolation::ManufacturerNotTrusted);
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_device_key_types {
+            let device_key_type = ov
+                .device_certificate_chain()
+                .and_then(|chain| chain.leaf_certificate())
+                .and_then(device_key_type);
+            if !device_key_type.map_or(false, |key_type| allowed.contains(&key_type)) {
+                return Err(PolicyViolation::DeviceKeyTypeNotAllowed { device_key_type });
+            }
+        }
+
+        Ok(())
+    }
+}
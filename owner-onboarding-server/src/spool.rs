@@ -0,0 +1,265 @@
+use std::{collections::VecDeque, os::unix::fs::DirBuilderExt};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use fdo_data_formats::{
+    constants::{ServiceInfoModule, StandardServiceInfoModule},
+    types::{CborSimpleType, CborSimpleTypeExt, ServiceInfo},
+    Serializable,
+};
+use fdo_store::{MetadataKey, ReadWriteOpen, Store, StoreConfig, StoreError};
+
+// Spooled entries of sessions that never finished are removed by the maintenance after this long
+const MAX_SPOOL_ENTRY_AGE: time::Duration = time::Duration::days(1);
+
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub(crate) enum SpoolStoreMetadataKey {}
+
+impl fdo_store::MetadataLocalKey for SpoolStoreMetadataKey {
+    fn to_key(&self) -> &'static str {
+        match *self {}
+    }
+}
+
+/// Store with the ServiceInfo still to be sent to devices.
+///
+/// The ServiceInfo of a device, including the contents of the files it downloads, is
+/// written to the store once, every entry under a key of its own. The session only holds
+/// a [`ServiceInfoCursor`] into those entries, so every OwnerServiceInfo message only
+/// loads its own entries, and any server instance sharing the store can send the next one.
+pub(crate) struct ServiceInfoSpool {
+    store: Box<dyn Store<ReadWriteOpen, String, ServiceInfo, SpoolStoreMetadataKey>>,
+}
+
+impl ServiceInfoSpool {
+    pub(crate) fn new(config: Option<&StoreConfig>) -> Result<Self> {
+        let store = match config {
+            Some(config) => config.initialize(),
+            None => {
+                let path = std::env::temp_dir().join("fdo-owner-onboarding-server");
+                std::fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(&path)
+                    .with_context(|| {
+                        format!("Error creating ServiceInfo spool directory {path:?}")
+                    })?;
+                StoreConfig::Directory { path }.initialize()
+            }
+        }
+        .context("Error initializing ServiceInfo spool store")?;
+        Ok(ServiceInfoSpool { store })
+    }
+
+    pub(crate) async fn perform_maintenance(&self) -> Result<usize, StoreError> {
+        self.store.perform_maintenance().await
+    }
+
+    /// Writes `service_info` to the store, returning a cursor at its start
+    pub(crate) async fn write(&self, service_info: &ServiceInfo) -> Result<ServiceInfoCursor> {
+        let mut id = [0; 16];
+        openssl::rand::rand_bytes(&mut id)?;
+        let mut cursor = ServiceInfoCursor {
+            spool: hex::encode(id),
+            entries: 0,
+            position: 0,
+            head: ServiceInfo::new(),
+        };
+
+        // Every entry is stored as a ServiceInfo of its own, so that they can be loaded
+        // one at a time
+        for (module, key, value) in service_info.iter() {
+            let mut entry = ServiceInfo::new();
+            entry.add(module, &key, &value)?;
+            let key = cursor.entry_key(cursor.entries);
+            self.store
+                .store_data(key.clone(), entry)
+                .await
+                .context("Error spooling ServiceInfo")?;
+            self.store
+                .store_metadata(&key, &MetadataKey::Ttl, &MAX_SPOOL_ENTRY_AGE)
+                .await
+                .context("Error spooling ServiceInfo")?;
+            cursor.entries += 1;
+        }
+
+        Ok(cursor)
+    }
+}
+
+/// Position of a device in its spooled ServiceInfo, kept in the session
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ServiceInfoCursor {
+    spool: String,
+    // Number of spooled entries
+    entries: u64,
+    // Index of the next entry to load
+    position: u64,
+    // Entries to send before the one at position, like the rest of a split value
+    head: ServiceInfo,
+}
+
+/// Length of the CBOR array header of `len` items
+fn array_header_len(len: usize) -> usize {
+    match len {
+        0..=23 => 1,
+        24..=0xff => 2,
+        0x100..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+impl ServiceInfoCursor {
+    fn entry_key(&self, index: u64) -> String {
+        format!("{}-{:08}", self.spool, index)
+    }
+
+    async fn next_entry(&mut self, spool: &ServiceInfoSpool) -> Result<Option<ServiceInfo>> {
+        if self.position >= self.entries {
+            return Ok(None);
+        }
+        let entry = spool
+            .store
+            .load_data(&self.entry_key(self.position))
+            .await
+            .context("Error loading spooled ServiceInfo")?
+            .context(
+                "Spooled ServiceInfo entry not found, is the spool store shared by all instances?",
+            )?;
+        self.position += 1;
+        Ok(Some(entry))
+    }
+
+    /// Whether all spooled ServiceInfo has been sent
+    pub(crate) fn is_finished(&self) -> bool {
+        self.head.is_empty() && self.position >= self.entries
+    }
+
+    /// Loads the next page of at most `max_size` serialized bytes, see
+    /// [`ServiceInfo::split_page`] for `ends_page` and the returned bool.
+    pub(crate) async fn next_page<F>(
+        &mut self,
+        spool: &ServiceInfoSpool,
+        max_size: usize,
+        ends_page: F,
+    ) -> Result<(ServiceInfo, bool)>
+    where
+        F: Fn(&ServiceInfoModule, &str) -> bool,
+    {
+        let mut pending = std::mem::take(&mut self.head);
+        // Serialized size of the entries in pending, without the array header
+        let mut entries_size = pending.serialize_data()?.len() - array_header_len(pending.len());
+        while entries_size + array_header_len(pending.len()) <= max_size {
+            match self.next_entry(spool).await? {
+                Some(entry) => {
+                    entries_size += entry.serialize_data()?.len() - array_header_len(entry.len());
+                    pending.extend(&entry);
+                }
+                None => break,
+            }
+        }
+
+        let (page, rest, value_split) = pending.split_page(max_size, true, ends_page)?;
+        self.head = rest;
+        Ok((page, value_split))
+    }
+
+    /// Drops the first `offset` bytes of the fdo.download data at the cursor
+    pub(crate) async fn skip_download_data(
+        &mut self,
+        spool: &ServiceInfoSpool,
+        mut offset: u64,
+    ) -> Result<()> {
+        let mut queue: VecDeque<(ServiceInfoModule, String, CborSimpleType)> =
+            self.head.iter().collect();
+        let mut head = ServiceInfo::new();
+
+        loop {
+            let (module, key, value) = match queue.pop_front() {
+                Some(entry) => entry,
+                None => match self.next_entry(spool).await? {
+                    Some(entry) => {
+                        queue.extend(entry.iter());
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+            if module == StandardServiceInfoModule::Download.into() && key == "data" {
+                let data = value
+                    .as_bytes()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid fdo.download data"))?;
+                if data.len() as u64 <= offset {
+                    offset -= data.len() as u64;
+                    continue;
+                }
+                head.add(
+                    module,
+                    &key,
+                    &serde_bytes::Bytes::new(&data[offset as usize..]),
+                )?;
+                offset = 0;
+            } else {
+                head.add(module, &key, &value)?;
+            }
+            break;
+        }
+        if offset != 0 {
+            bail!("Device asked to resume past the end of the file");
+        }
+
+        for (module, key, value) in queue {
+            head.add(module, &key, &value)?;
+        }
+        self.head = head;
+        Ok(())
+    }
+
+    /// Removes the spooled entries, once the device is done with them
+    pub(crate) async fn remove(self, spool: &ServiceInfoSpool) {
+        for index in 0..self.entries {
+            let key = self.entry_key(index);
+            if let Err(e) = spool.store.destroy_data(&key).await {
+                log::warn!("Error removing spooled ServiceInfo {}: {:?}", key, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::array_header_len;
+
+    use fdo_data_formats::{
+        constants::StandardServiceInfoModule, types::ServiceInfo, Serializable,
+    };
+
+    #[test]
+    fn test_array_header_len() {
+        for len in [0, 1, 23, 24, 255, 256, 300] {
+            let mut service_info = ServiceInfo::new();
+            for _ in 0..len {
+                service_info
+                    .add(StandardServiceInfoModule::DevMod, "active", &true)
+                    .unwrap();
+            }
+            // Every entry is the same size
+            let entry_size = if len == 0 {
+                0
+            } else {
+                let mut entry = ServiceInfo::new();
+                entry
+                    .add(StandardServiceInfoModule::DevMod, "active", &true)
+                    .unwrap();
+                entry.serialize_data().unwrap().len() - 1
+            };
+            assert_eq!(
+                service_info.serialize_data().unwrap().len(),
+                array_header_len(len) + len * entry_size,
+            );
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use fdo_data_formats::ownershipvoucher::OwnershipVoucher;
+use fdo_util::servers::{
+    configuration::owner_onboarding_server::To0RegistrationSettings,
+    OwnershipVoucherStoreMetadataKey,
+};
+
+use crate::events::OnboardingEvent;
+use crate::OwnerServiceUDT;
+
+const PROGRESS_INTERVAL: usize = 1000;
+
+/// Spaces out the registrations sent to each rendezvous server.
+#[derive(Debug)]
+pub(crate) struct RendezvousRateLimiter {
+    interval: Option<Duration>,
+    next_slot: Mutex<HashMap<String, Instant>>,
+}
+
+impl RendezvousRateLimiter {
+    fn new(max_per_second: Option<u32>) -> Self {
+        RendezvousRateLimiter {
+            interval: max_per_second.map(|max| Duration::from_secs(1) / max),
+            next_slot: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until another registration may be sent to `server`.
+    pub(crate) async fn wait(&self, server: &str) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let slot = {
+            let mut next_slot = self
+                .next_slot
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            let slot = match next_slot.get(server) {
+                Some(next) if *next > now => *next,
+                _ => now,
+            };
+            next_slot.insert(server.to_string(), slot + interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct To0Registration {
+    settings: To0RegistrationSettings,
+    pub(crate) rate_limiter: RendezvousRateLimiter,
+    // Only one registration round runs at a time
+    running: tokio::sync::Mutex<()>,
+}
+
+impl To0Registration {
+    pub(crate) fn new(settings: To0RegistrationSettings) -> Result<Self> {
+        if settings.max_concurrent == 0 {
+            bail!("to0_registration.max_concurrent must be at least 1");
+        }
+        if settings.max_per_second_per_server == Some(0) {
+            bail!("to0_registration.max_per_second_per_server must be at least 1");
+        }
+        Ok(To0Registration {
+            rate_limiter: RendezvousRateLimiter::new(settings.max_per_second_per_server),
+            settings,
+            running: tokio::sync::Mutex::new(()),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    registered: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl Progress {
+    fn report(&self, total: usize) {
+        log::info!(
+            "TO0 registration: {} of {} vouchers registered, {} failed",
+            self.registered.load(Ordering::Relaxed),
+            total,
+            self.failed.load(Ordering::Relaxed)
+        );
+    }
+
+    fn record(&self, registered: bool, total: usize) {
+        let counter = if registered {
+            &self.registered
+        } else {
+            &self.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let done = self.registered.load(Ordering::Relaxed) + self.failed.load(Ordering::Relaxed);
+        if done % PROGRESS_INTERVAL == 0 {
+            self.report(total);
+        }
+    }
+}
+
+pub(crate) async fn register_voucher(udt: &OwnerServiceUDT, ov: &OwnershipVoucher) -> Result<()> {
+    let settings = &udt.to0_registration.settings;
+    let mut attempt = 1;
+    let wait_seconds = loop {
+        match crate::report_ov_to_rendezvous(
+            ov,
+            &udt.owner_addresses,
+            &udt.owner_key,
+            &udt.to0_registration.rate_limiter,
+            settings.wait_seconds,
+        )
+        .await
+        {
+            Ok(wait_seconds) => break wait_seconds,
+            Err(e) if attempt >= settings.max_attempts => {
+                udt.events.publish(OnboardingEvent::To0RegistrationFailed {
+                    guid: ov.header().guid().to_string(),
+                    error: format!("{:?}", e),
+                });
+                return Err(e);
+            }
+            Err(e) => {
+                let delay = settings.retry_delay_seconds << (attempt - 1).min(16);
+                log::debug!(
+                    "OV({}): attempt {} to report to rendezvous failed, retrying in {} seconds: {:?}",
+                    ov.header().guid(),
+                    attempt,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+                attempt += 1;
+            }
+        }
+    };
+
+    udt.events.publish(OnboardingEvent::To0Registered {
+        guid: ov.header().guid().to_string(),
+        wait_seconds,
+    });
+
+    // The voucher is picked up for registration again once this time has passed
+    let refresh_seconds = refresh_delay(settings, wait_seconds);
+    log::debug!(
+        "OV({}): registered for {} seconds, refreshing in {} seconds",
+        ov.header().guid(),
+        wait_seconds,
+        refresh_seconds
+    );
+    udt.ownership_voucher_store
+        .store_metadata(
+            ov.header().guid(),
+            &fdo_store::MetadataKey::Local(
+                OwnershipVoucherStoreMetadataKey::To0AcceptOwnerWaitSeconds,
+            ),
+            &time::Duration::new(refresh_seconds, 0),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Number of seconds after which a registration accepted for `wait_seconds` is refreshed.
+///
+/// The refresh happens before the registration expires, with some random jitter so that
+/// vouchers registered at the same time don't all get refreshed at the same time.
+fn refresh_delay(settings: &To0RegistrationSettings, wait_seconds: u32) -> i64 {
+    let wait_seconds = i64::from(wait_seconds);
+    let margin = wait_seconds * i64::from(settings.refresh_margin_percent.min(100)) / 100;
+    let max_jitter = wait_seconds * i64::from(settings.refresh_jitter_percent.min(100)) / 100;
+
+    let mut random = [0u8; 8];
+    let jitter = match openssl::rand::rand_bytes(&mut random) {
+        Ok(()) if max_jitter > 0 => (u64::from_le_bytes(random) % (max_jitter as u64 + 1)) as i64,
+        _ => 0,
+    };
+
+    (wait_seconds - margin - jitter).max(0)
+}
+
+/// Registers `ovs` with their rendezvous servers, with a bounded number of concurrent
+/// registrations. Vouchers that failed to register are retried in the next round.
+pub(crate) async fn register_vouchers(
+    udt: &OwnerServiceUDT,
+    ovs: Vec<OwnershipVoucher>,
+) -> Result<()> {
+    let _running = match udt.to0_registration.running.try_lock() {
+        Ok(running) => running,
+        Err(_) => {
+            log::info!("TO0 registration already in progress, skipping");
+            return Ok(());
+        }
+    };
+
+    let total = ovs.len();
+    if total == 0 {
+        return Ok(());
+    }
+    log::info!("Registering {} vouchers with rendezvous servers", total);
+
+    let progress = Arc::new(Progress::default());
+    let semaphore = Arc::new(Semaphore::new(udt.to0_registration.settings.max_concurrent));
+    let mut tasks = JoinSet::new();
+    for ov in ovs {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let udt = udt.clone();
+        let progress = progress.clone();
+        tasks.spawn(async move {
+            let res = register_voucher(&udt, &ov).await;
+            if let Err(e) = &res {
+                log::warn!(
+                    "OV({}): failed to report to rendezvous: {:?}",
+                    ov.header().guid(),
+                    e
+                );
+            }
+            progress.record(res.is_ok(), total);
+            drop(permit);
+        });
+    }
+    while let Some(res) = tasks.join_next().await {
+        if let Err(e) = res {
+            log::error!("TO0 registration task failed: {:?}", e);
+        }
+    }
+
+    progress.report(total);
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use anyhow::Error;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use fdo_owner_lib::error::ErrorClass;
+
+// Exit status 2 is used by clap for usage errors
+fn exit_status(class: ErrorClass) -> i32 {
+    match class {
+        ErrorClass::Other => 1,
+        ErrorClass::Io => 3,
+        ErrorClass::Key => 4,
+        ErrorClass::Voucher => 5,
+        ErrorClass::Validation => 6,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ErrorFormat {
+    /// Human readable error with its causes
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorOutput {
+    error: ErrorClass,
+    exit_status: i32,
+    message: String,
+    causes: Vec<String>,
+}
+
+/// Prints `err` to stderr and returns the exit status for it
+pub(crate) fn report(err: &Error, format: ErrorFormat) -> i32 {
+    let class = ErrorClass::of(err);
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {err:?}"),
+        ErrorFormat::Json => {
+            let output = ErrorOutput {
+                error: class,
+                exit_status: exit_status(class),
+                message: err.to_string(),
+                causes: err.chain().skip(1).map(|cause| cause.to_string()).collect(),
+            };
+            match serde_json::to_string(&output) {
+                Ok(output) => eprintln!("{output}"),
+                Err(_) => eprintln!("Error: {err:?}"),
+            }
+        }
+    }
+    exit_status(class)
+}
@@ -1,33 +1,70 @@
-use std::{convert::TryFrom, fs, io::Write, path::Path, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+    fs,
+    io::Write,
+    path::Path,
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Error, Result};
-use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
-use openssl::{
-    asn1::{Asn1Integer, Asn1Time},
-    bn::BigNum,
-    ec::{EcGroup, EcKey},
-    hash::MessageDigest,
-    nid::Nid,
-    pkey::{PKey, PKeyRef, Private},
-    rand::rand_bytes,
-    sign::Signer,
-    x509::{X509Builder, X509NameBuilder, X509NameRef, X509},
-};
-use serde_yaml::Value;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
 use tss_esapi::{structures::Public as TssPublic, traits::UnMarshall};
 
 use fdo_data_formats::{
-    constants::{HashType, RendezvousVariable},
-    devicecredential::FileDeviceCredential,
-    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherHeader},
+    devicecredential::file::{KeyStorage, FILE_FORMAT_VERSION},
+    enhanced_types::X5Bag,
+    ownershipvoucher::OwnershipVoucher,
     publickey::{PublicKey, X5Chain},
-    types::{CborSimpleType, Guid, HMac, Hash, RendezvousInfo},
-    ProtocolVersion, Serializable,
+    types::{Hash, RendezvousInfo},
+    ProtocolVersion,
+};
+use fdo_owner_lib::{
+    credential::{
+        load_device_credential, migrate_device_credential, set_device_credential_active, Migration,
+    },
+    device::{
+        check_device_ca_chain, create_device, CertDigest, DeviceCertProfile,
+        DeviceInitializationMaterials, DeviceKeyStorage, ExternalDeviceKey,
+    },
+    error::{class_error, ClassContext, ErrorClass},
+    keys::{
+        load_private_key, load_x509, load_x509s, public_key_from_x509, public_key_from_x509_chain,
+        KeyType,
+    },
+    provenance::{voucher_provenance, CustodyHop, CustodyStatus},
+    rendezvous::{load_rendezvous_info, RendezvousInfoFormat},
+    verify::{verify_ownership_voucher, VoucherVerification},
+    voucher::{
+        extend_ownership_voucher, extend_ownership_voucher_chain, load_voucher,
+        resell_ownership_voucher, serialize_voucher, validate_voucher_entries, write_voucher,
+        VoucherFormat,
+    },
 };
+use fdo_util::{
+    crypto_policy::CryptoPolicy,
+    revocation::{load_crl, RevocationChecker},
+    servers::configuration::RevocationMode,
+    signing::SigningKey,
+};
+
+mod errors;
+mod simulate;
+
+use errors::ErrorFormat;
 
 #[derive(Parser)]
 #[clap(version = "0.1")]
 struct Cli {
+    /// Format of the error printed on failure
+    #[clap(long, value_enum, global = true, default_value = "text")]
+    error_format: ErrorFormat,
+
+    /// Accept signed structures that are not canonically encoded, with a warning
+    #[clap(long, global = true, action = ArgAction::SetTrue)]
+    accept_non_canonical: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -36,12 +73,38 @@ struct Cli {
 enum Commands {
     /// Initializes device token
     InitializeDevice(InitializeDeviceArguments),
+    /// Initializes a batch of device tokens
+    InitializeDevices(InitializeDevicesArguments),
     /// Prints ownership voucher contents
     DumpOwnershipVoucher(DumpOwnershipVoucherArguments),
+    /// Verifies the ownership voucher signatures and hashes
+    VerifyOwnershipVoucher(VerifyOwnershipVoucherArguments),
+    /// Reports the chain of custody of an ownership voucher, for supply chain audits
+    Provenance(ProvenanceArguments),
     /// Prints device credential contents
     DumpDeviceCredential(DumpDeviceCredentialArguments),
     /// Extends an ownership voucher for a new owner
     ExtendOwnershipVoucher(ExtendOwnershipVoucherArguments),
+    /// Extends a resold ownership voucher for a new owner, leaving the original in place
+    Resale(ResaleArguments),
+    /// Exports an ownership voucher in PEM-armored format
+    ExportOwnershipVoucher(ExportOwnershipVoucherArguments),
+    /// Imports a PEM-armored ownership voucher into its binary format
+    ImportOwnershipVoucher(ImportOwnershipVoucherArguments),
+    /// Converts an ownership voucher between the binary, PEM and CBOR diagnostic formats
+    ConvertOwnershipVoucher(ConvertOwnershipVoucherArguments),
+    /// Upgrades a device credential file to the current file format version
+    MigrateDeviceCredential(MigrateDeviceCredentialArguments),
+    /// Marks a device credential as inactive, so the device does not attempt onboarding
+    DeactivateDeviceCredential(SetDeviceCredentialActiveArguments),
+    /// Marks a device credential as active, so the device onboards again
+    ReactivateDeviceCredential(SetDeviceCredentialActiveArguments),
+    /// Onboards a batch of devices concurrently against real servers, to measure their capacity
+    Simulate(SimulateArguments),
+    /// Prints a shell completion script
+    Completions(CompletionsArguments),
+    /// Prints the man page
+    Man,
 }
 
 #[derive(Args)]
@@ -50,8 +113,43 @@ struct InitializeDeviceArguments {
     device_id: String,
     /// Output path for ownership voucher
     ownershipvoucher_out: String,
-    /// Output path for device credential
+    /// Output path for device credential (device certificate chain with --device-csr)
     device_credential_out: String,
+    /// PKCS#10 CSR (PEM or DER) of an externally generated device key, to certify instead of
+    /// generating a device key
+    #[clap(long, action = ArgAction::Set, requires = "device_hmac_secret")]
+    device_csr: Option<String>,
+    /// File with the HMAC secret of the device, used to sign the voucher with --device-csr
+    #[clap(long, action = ArgAction::Set, requires = "device_csr")]
+    device_hmac_secret: Option<String>,
+    #[clap(flatten)]
+    initialization: DeviceInitializationArguments,
+}
+
+#[derive(Args)]
+struct InitializeDevicesArguments {
+    /// Output directory for the ownership vouchers and device credentials
+    output_directory: String,
+    /// Number of devices to initialize
+    #[clap(
+        long,
+        action = ArgAction::Set,
+        required_unless_present = "manifest",
+        conflicts_with = "manifest"
+    )]
+    count: Option<u32>,
+    /// Prefix for the identifiers of the devices initialized with --count
+    #[clap(long, action = ArgAction::Set, default_value = "device")]
+    device_id_prefix: String,
+    /// Path to a CSV file with a device identifier in the first column of each line
+    #[clap(long, action = ArgAction::Set)]
+    manifest: Option<String>,
+    #[clap(flatten)]
+    initialization: DeviceInitializationArguments,
+}
+
+#[derive(Args)]
+struct DeviceInitializationArguments {
     /// Path to the certificate for the manufacturer
     #[clap(long, action = ArgAction::Set)]
     manufacturer_cert: String,
@@ -61,15 +159,55 @@ struct InitializeDeviceArguments {
     /// Chain with CA certificates for device certificate
     #[clap(long, action = ArgAction::Set)]
     device_cert_ca_chain: String,
-    /// Path to a TOML file containing the rendezvous information
+    /// Reorder the device CA chain if it is not ordered from the issuing CA to the root
+    #[clap(long, action = ArgAction::SetTrue)]
+    reorder_device_cert_ca_chain: bool,
+    /// Path to a YAML or TOML file containing the rendezvous information
     #[clap(long, action = ArgAction::Set)]
     rendezvous_info: String,
+    /// Format of the rendezvous information file (default: TOML for .toml files, YAML otherwise)
+    #[clap(value_enum, long, required = false, action = ArgAction::Set)]
+    rendezvous_info_format: Option<RendezvousInfoFormat>,
+    /// Public key type of the manufacturer key (default: detected from the certificate)
+    #[clap(value_enum, long, required = false, action = ArgAction::Set)]
+    key_type: Option<KeyType>,
+    /// Where to generate and store the device keys
+    #[clap(value_enum, long, default_value = "filesystem", action = ArgAction::Set)]
+    key_storage: DeviceKeyStorage,
+    #[clap(flatten)]
+    device_cert: DeviceCertArguments,
+}
+
+#[derive(Args)]
+struct DeviceCertArguments {
+    /// Path to a YAML or TOML device certificate profile, overridden by the other flags
+    #[clap(long, action = ArgAction::Set)]
+    device_cert_profile: Option<String>,
+    /// Validity of the device certificate in days (default: 3650)
+    #[clap(long, action = ArgAction::Set)]
+    device_cert_validity_days: Option<u32>,
+    /// Digest used to sign the device certificate (default: sha384)
+    #[clap(value_enum, long, action = ArgAction::Set)]
+    device_cert_digest: Option<CertDigest>,
+    /// Additional device certificate subject field, as FIELD=VALUE (e.g. O=Example)
+    #[clap(long = "device-cert-subject", action = ArgAction::Append)]
+    device_cert_subject: Vec<String>,
+    /// Key usage of the device certificate (e.g. digitalSignature)
+    #[clap(long = "device-cert-key-usage", action = ArgAction::Append)]
+    device_cert_key_usage: Vec<String>,
+    /// Extended key usage of the device certificate (e.g. clientAuth, or an OID)
+    #[clap(long = "device-cert-extended-key-usage", action = ArgAction::Append)]
+    device_cert_extended_key_usage: Vec<String>,
+    /// Add the device GUID as urn:uuid: URI subject alternative name
+    #[clap(long, action = ArgAction::SetTrue)]
+    device_cert_san_guid: bool,
 }
 
-#[derive(Copy, Clone, ValueEnum)]
-enum OutputFormat {
-    Pem,
-    Cose,
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum DumpFormat {
+    Text,
+    Json,
+    Yaml,
 }
 
 #[derive(Args)]
@@ -78,219 +216,327 @@ struct DumpOwnershipVoucherArguments {
     path: String,
     /// Output format
     #[clap(value_enum, long, required = false, action = ArgAction::Set)]
-    outform: Option<OutputFormat>,
+    outform: Option<VoucherFormat>,
+    /// Format of the printed contents
+    #[clap(
+        value_enum,
+        long,
+        default_value = "text",
+        conflicts_with = "outform",
+        action = ArgAction::Set
+    )]
+    output: DumpFormat,
+}
+
+#[derive(Args)]
+struct VerifyOwnershipVoucherArguments {
+    /// Path to the ownership voucher
+    path: String,
+    /// Path to a PEM file with the trusted manufacturer certificates
+    #[clap(long, action = ArgAction::Set)]
+    trusted_manufacturer_certs: String,
+    /// Path to the certificate of the expected current owner
+    #[clap(long, action = ArgAction::Set)]
+    expected_owner_cert: Option<String>,
+    /// Check the manufacturer, owner and device certificates for revocation, with the CRL
+    /// distribution points and OCSP responders listed in the certificates
+    #[clap(long, action = ArgAction::SetTrue)]
+    check_revocation: bool,
+    /// Path to a CRL (DER or PEM) to check the certificates against, can be repeated
+    #[clap(long, action = ArgAction::Append)]
+    crl: Vec<String>,
+    /// Accept certificates whose revocation status can't be determined
+    #[clap(long, action = ArgAction::SetTrue)]
+    revocation_soft_fail: bool,
+}
+
+#[derive(Args)]
+struct ProvenanceArguments {
+    /// Path to the ownership voucher
+    path: String,
+    /// Path to a PEM file with the trusted manufacturer certificates, to validate the
+    /// manufacturer as well
+    #[clap(long, action = ArgAction::Set)]
+    trusted_manufacturer_certs: Option<String>,
+    /// Format of the report
+    #[clap(value_enum, long, default_value = "text", action = ArgAction::Set)]
+    output: DumpFormat,
 }
 
 #[derive(Args)]
 struct DumpDeviceCredentialArguments {
     /// Path to the device credential
     path: String,
+    /// Format of the printed contents
+    #[clap(value_enum, long, default_value = "text", action = ArgAction::Set)]
+    output: DumpFormat,
 }
 
 #[derive(Args)]
 struct ExtendOwnershipVoucherArguments {
     /// Path to the ownership voucher
     path: String,
-    /// Path to the current owner private key
+    /// Path to the current owner private key, or a PKCS#11, AWS KMS or Azure Key Vault URI
     #[clap(long, action = ArgAction::Set)]
     current_owner_private_key: String,
     /// Path to the new owner certificate
     #[clap(long, action = ArgAction::Set)]
-    new_owner_cert: String,
+    #[clap(required_unless_present_any = ["chain", "new_owner_cert_chain"])]
+    #[clap(conflicts_with_all = ["chain", "new_owner_cert_chain"])]
+    new_owner_cert: Option<String>,
+    /// Path to a PEM file with a signing certificate delegated by the new owner, followed by
+    /// the certificates up to the new owner certificate. The voucher is extended to the
+    /// delegated key, with the chain in the entry
+    #[clap(long, conflicts_with = "chain", action = ArgAction::Set)]
+    new_owner_cert_chain: Option<String>,
+    /// Path to a PEM file with the certificates of successive owners (e.g. distributor,
+    /// reseller and final owner), to extend the voucher to each of them in turn
+    #[clap(long, action = ArgAction::Set)]
+    chain: Option<String>,
+    /// Private key (path or URI) of an owner in the chain, used to extend the voucher to the
+    /// next owner. Given once for every certificate in the chain but the last one, in order
+    #[clap(long, requires = "chain", action = ArgAction::Append)]
+    intermediate_owner_private_key: Vec<String>,
+    /// Public key type of the new owner keys (default: detected from the certificates)
+    #[clap(value_enum, long, required = false, action = ArgAction::Set)]
+    key_type: Option<KeyType>,
+    /// Print the entries that would be added, without writing the ownership voucher
+    #[clap(long, conflicts_with = "output", action = ArgAction::SetTrue)]
+    dry_run: bool,
+    /// Write the extended ownership voucher to this path, instead of replacing the original
+    #[clap(long, action = ArgAction::Set)]
+    output: Option<String>,
+    /// Path to a YAML crypto policy, with the settings of a server crypto_policy section, that
+    /// the extended ownership voucher must comply with
+    #[clap(long, action = ArgAction::Set)]
+    crypto_policy: Option<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    fdo_util::add_version!();
-    fdo_http_wrapper::init_logging();
-
-    match Cli::parse().command {
-        Commands::InitializeDevice(args) => initialize_device(&args),
-        Commands::DumpOwnershipVoucher(args) => dump_voucher(&args),
-        Commands::DumpDeviceCredential(args) => dump_devcred(&args),
-        Commands::ExtendOwnershipVoucher(args) => extend_voucher(&args),
-    }
+#[derive(Args)]
+struct ResaleArguments {
+    /// Path to the ownership voucher produced by the resale onboarding
+    path: String,
+    /// Path to the current owner private key, or a PKCS#11, AWS KMS or Azure Key Vault URI
+    #[clap(long, action = ArgAction::Set)]
+    current_owner_private_key: String,
+    /// Path to the new owner certificate
+    #[clap(long, action = ArgAction::Set)]
+    new_owner_cert: String,
+    /// Public key type of the new owner key (default: detected from the certificate)
+    #[clap(value_enum, long, required = false, action = ArgAction::Set)]
+    key_type: Option<KeyType>,
+    /// Output path for the ownership voucher to hand over to the new owner
+    #[clap(long, action = ArgAction::Set)]
+    output: String,
+    /// Path to a YAML crypto policy, with the settings of a server crypto_policy section, that
+    /// the extended ownership voucher must comply with
+    #[clap(long, action = ArgAction::Set)]
+    crypto_policy: Option<String>,
 }
 
-fn load_private_key(path: &str) -> Result<PKey<Private>, Error> {
-    let contents = fs::read(path)?;
-    Ok(PKey::private_key_from_der(&contents)?)
+#[derive(Args)]
+struct ExportOwnershipVoucherArguments {
+    /// Path to the ownership voucher
+    path: String,
+    /// Output path for the PEM-armored ownership voucher (default: stdout)
+    #[clap(long, action = ArgAction::Set)]
+    output: Option<String>,
 }
 
-fn load_x509(path: &str) -> Result<X509, Error> {
-    let contents = fs::read(path)?;
-    Ok(X509::from_pem(&contents)?)
+#[derive(Args)]
+struct ImportOwnershipVoucherArguments {
+    /// Path to the PEM-armored ownership voucher
+    path: String,
+    /// Output path for the binary ownership voucher
+    output: String,
 }
 
-fn load_x509s(path: &str) -> Result<Vec<X509>, Error> {
-    let contents = fs::read(path)?;
-    Ok(X509::stack_from_pem(&contents)?)
+#[derive(Args)]
+struct ConvertOwnershipVoucherArguments {
+    /// Path to the ownership voucher, in binary, PEM or CBOR diagnostic format
+    path: String,
+    /// Format to convert the ownership voucher to
+    #[clap(value_enum, long, action = ArgAction::Set)]
+    outform: VoucherFormat,
+    /// Output path for the converted ownership voucher (default: stdout)
+    #[clap(long, action = ArgAction::Set)]
+    output: Option<String>,
 }
 
-fn yaml_to_cbor(val: &Value) -> Result<CborSimpleType, Error> {
-    Ok(match val {
-        Value::Null => CborSimpleType::Null,
-        Value::Bool(b) => b.to_owned().into(),
-        Value::Number(nr) => {
-            if let Some(nr) = nr.as_u64() {
-                nr.into()
-            } else if let Some(nr) = nr.as_i64() {
-                nr.into()
-            } else if let Some(nr) = nr.as_f64() {
-                nr.into()
-            } else {
-                bail!("Invalid number encountered");
-            }
-        }
-        Value::String(str) => str.clone().into(),
-        Value::Sequence(seq) => CborSimpleType::from(
-            seq.iter()
-                .map(yaml_to_cbor)
-                .collect::<Result<Vec<CborSimpleType>>>()?,
-        ),
-        Value::Mapping(map) => CborSimpleType::from(
-            map.iter()
-                .map(|(key, val)| (yaml_to_cbor(key).unwrap(), yaml_to_cbor(val).unwrap()))
-                .collect::<std::collections::BTreeMap<CborSimpleType, CborSimpleType>>(),
-        ),
-        Value::Tagged(_) => bail!("YAML tags are unsupported"),
-    })
+#[derive(Args)]
+struct MigrateDeviceCredentialArguments {
+    /// Path to the device credential
+    path: String,
+    /// Output path for the migrated device credential (default: overwrite the input file)
+    #[clap(long, action = ArgAction::Set)]
+    output: Option<String>,
 }
 
-fn load_rendezvous_info(path: &str) -> Result<RendezvousInfo, Error> {
-    let contents = fs::read(path)?;
-    let mut info = Vec::new();
-
-    let value: Value =
-        serde_yaml::from_slice(&contents).context("Error parsing rendezvous info")?;
-    let value = match value {
-        Value::Sequence(vals) => vals,
-        _ => bail!("Invalid yaml top type"),
-    };
-
-    for val in value {
-        let mut entry = Vec::new();
-
-        let val = match val {
-            Value::Mapping(map) => map,
-            _ => bail!("Invalid entry type"),
-        };
+#[derive(Args)]
+struct SetDeviceCredentialActiveArguments {
+    /// Path to the device credential
+    path: String,
+}
 
-        for (key, val) in val.iter() {
-            let key = match key {
-                Value::String(val) => val,
-                _ => bail!("Invalid key type"),
-            };
-            let key = RendezvousVariable::from_str(key)
-                .with_context(|| format!("Error parsing rendezvous key '{key}'"))?;
+#[derive(Args)]
+struct CompletionsArguments {
+    /// Shell to generate the completions for
+    #[clap(value_enum)]
+    shell: clap_complete::Shell,
+}
 
-            let val = yaml_to_cbor(val)?;
-            let val = key
-                .value_from_human_to_machine(val)
-                .with_context(|| format!("Error parsing value for key '{key:?}'"))?;
+#[derive(Args)]
+struct SimulateArguments {
+    /// Directory with the device credentials (*.dc) written by initialize-devices
+    device_credentials_directory: String,
+    /// Number of devices onboarding at the same time
+    #[clap(long, action = ArgAction::Set, default_value = "10")]
+    concurrency: usize,
+    /// Only simulate this many devices
+    #[clap(long, action = ArgAction::Set)]
+    count: Option<usize>,
+}
 
-            entry.push((key, val));
-        }
+#[tokio::main]
+async fn main() {
+    fdo_util::add_version!();
+    fdo_http_wrapper::init_logging();
 
-        info.push(entry);
+    let cli = Cli::parse();
+    fdo_data_formats::canonical::set_accept_non_canonical(cli.accept_non_canonical);
+    if let Err(e) = run(cli.command).await {
+        std::process::exit(errors::report(&e, cli.error_format));
     }
-
-    RendezvousInfo::new(info).context("Error serializing rendezvous info")
 }
 
-fn build_device_cert<T: openssl::pkey::HasPublic>(
-    subject_name: &X509NameRef,
-    device_pubkey: &PKeyRef<T>,
-    signer: &PKeyRef<Private>,
-    chain: &[X509],
-) -> Result<X509> {
-    if chain.is_empty() {
-        bail!("Insufficient device CA certs in the chain");
+async fn run(command: Commands) -> Result<()> {
+    match command {
+        Commands::InitializeDevice(args) => initialize_device(&args),
+        Commands::InitializeDevices(args) => initialize_devices(&args),
+        Commands::DumpOwnershipVoucher(args) => dump_voucher(&args),
+        Commands::VerifyOwnershipVoucher(args) => verify_voucher(&args).await,
+        Commands::Provenance(args) => report_provenance(&args),
+        Commands::DumpDeviceCredential(args) => dump_devcred(&args),
+        Commands::ExtendOwnershipVoucher(args) => extend_voucher(&args),
+        Commands::Resale(args) => resale_voucher(&args),
+        Commands::ExportOwnershipVoucher(args) => export_voucher(&args),
+        Commands::ImportOwnershipVoucher(args) => import_voucher(&args),
+        Commands::ConvertOwnershipVoucher(args) => convert_voucher(&args),
+        Commands::MigrateDeviceCredential(args) => migrate_devcred(&args),
+        Commands::DeactivateDeviceCredential(args) => set_devcred_active(&args, false),
+        Commands::ReactivateDeviceCredential(args) => set_devcred_active(&args, true),
+        Commands::Simulate(args) => simulate::simulate(&args).await,
+        Commands::Completions(args) => print_completions(&args),
+        Commands::Man => print_man_page(),
     }
-    //if chain[0].public_key()?.public_eq(signer) {
-    //    bail!("Device CA issuer not first in the chain");
-    //}
-
-    // Build
-    let mut builder = X509Builder::new().context("Error creating X509Builder")?;
-
-    builder
-        .set_not_after(
-            Asn1Time::days_from_now(3650)
-                .context("Error building not-after-time")?
-                .as_ref(),
-        )
-        .context("Error setting not-after")?;
-
-    builder
-        .set_not_before(
-            Asn1Time::days_from_now(0)
-                .context("Error building not-before-time")?
-                .as_ref(),
-        )
-        .context("Error setting not-before")?;
-
-    builder.set_version(2).context("Error setting version")?;
-
-    builder
-        .set_issuer_name(chain[0].subject_name())
-        .context("Error setting issuer name")?;
-
-    builder
-        .set_subject_name(subject_name)
-        .context("Error setting subject name")?;
+}
 
-    builder
-        .set_pubkey(device_pubkey)
-        .context("Error setting device public key")?;
+fn print_completions(args: &CompletionsArguments) -> Result<(), Error> {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(args.shell, &mut command, name, &mut std::io::stdout());
+    Ok(())
+}
 
-    // Build a new serial number
-    // We are generating a random number for serial number using 64 bits of output
-    //  from a CSPRNG (openssl's rand), according to section 7.1 of
-    //  CA/Browser Forum Baseline Requirements, version 1.7.3
-    let mut serial_buf = [0; 8];
-    rand_bytes(&mut serial_buf).context("Error generating serial number")?;
-    let serial = BigNum::from_slice(&serial_buf).context("Error parsing serial number")?;
-    let serial = Asn1Integer::from_bn(&serial).context("Error converting serial number to asn1")?;
-    builder
-        .set_serial_number(serial.as_ref())
-        .context("Error setting serial number")?;
+fn print_man_page() -> Result<(), Error> {
+    clap_mangen::Man::new(Cli::command())
+        .render(&mut std::io::stdout())
+        .context("Error writing man page")
+}
 
-    // Sign and return
-    builder
-        .sign(signer, MessageDigest::sha384())
-        .context("Error signing certificate")?;
+fn load_device_cert_profile(args: &DeviceCertArguments) -> Result<DeviceCertProfile, Error> {
+    let mut profile = match &args.device_cert_profile {
+        None => DeviceCertProfile::default(),
+        Some(path) => DeviceCertProfile::from_file(path)?,
+    };
 
-    Ok(builder.build())
+    if let Some(validity_days) = args.device_cert_validity_days {
+        profile.validity_days = validity_days;
+    }
+    if let Some(digest) = args.device_cert_digest {
+        profile.digest = digest;
+    }
+    for field in &args.device_cert_subject {
+        let (key, value) = field.split_once('=').with_context(|| {
+            format!("Invalid device certificate subject field '{field}', expected FIELD=VALUE")
+        })?;
+        profile
+            .subject
+            .insert(key.trim().to_string(), value.trim().to_string());
+    }
+    profile
+        .key_usage
+        .extend(args.device_cert_key_usage.iter().cloned());
+    profile
+        .extended_key_usage
+        .extend(args.device_cert_extended_key_usage.iter().cloned());
+    profile.san_guid |= args.device_cert_san_guid;
+
+    profile.validate()?;
+    Ok(profile)
 }
 
-fn initialize_device(args: &InitializeDeviceArguments) -> Result<(), Error> {
-    let manufacturer_cert = load_x509(&args.manufacturer_cert).with_context(|| {
-        format!(
-            "Error loading manufacturer cert at {}",
-            args.manufacturer_cert
-        )
-    })?;
-    let manufacturer_pubkey = PublicKey::try_from(manufacturer_cert)
-        .context("Error creating manufacturer public key representation")?;
+fn load_device_initialization_materials(
+    args: &DeviceInitializationArguments,
+) -> Result<DeviceInitializationMaterials, Error> {
+    let manufacturer_cert =
+        load_x509(&args.manufacturer_cert).with_class_context(ErrorClass::Key, || {
+            format!(
+                "Error loading manufacturer cert at {}",
+                args.manufacturer_cert
+            )
+        })?;
+    let manufacturer_pubkey = public_key_from_x509(manufacturer_cert, args.key_type)
+        .class_context(
+            ErrorClass::Key,
+            "Error creating manufacturer public key representation",
+        )?;
 
     let device_cert_ca_private_key = load_private_key(&args.device_cert_ca_private_key)
-        .with_context(|| {
+        .with_class_context(ErrorClass::Key, || {
             format!(
                 "Error loading device CA private key at {}",
                 args.device_cert_ca_private_key
             )
         })?;
-    let device_cert_ca_chain = load_x509s(&args.device_cert_ca_chain).with_context(|| {
+    let device_cert_ca_chain =
+        load_x509s(&args.device_cert_ca_chain).with_class_context(ErrorClass::Key, || {
+            format!(
+                "Error loading device cert ca chain at {}",
+                args.device_cert_ca_chain
+            )
+        })?;
+    let device_cert_ca_chain = check_device_ca_chain(
+        device_cert_ca_chain,
+        &device_cert_ca_private_key,
+        args.reorder_device_cert_ca_chain,
+    )
+    .with_class_context(ErrorClass::Key, || {
         format!(
-            "Error loading device cert ca chain at {}",
+            "Invalid device cert ca chain at {}",
             args.device_cert_ca_chain
         )
     })?;
 
-    let rendezvous_info = load_rendezvous_info(&args.rendezvous_info)
+    let device_cert_profile = load_device_cert_profile(&args.device_cert)
+        .context("Error loading device certificate profile")?;
+
+    let rendezvous_info = load_rendezvous_info(&args.rendezvous_info, args.rendezvous_info_format)
         .with_context(|| format!("Error loading rendezvous info at {}", args.rendezvous_info))?;
 
+    Ok(DeviceInitializationMaterials {
+        key_storage: args.key_storage,
+        manufacturer_pubkey,
+        device_cert_ca_private_key,
+        device_cert_ca_chain,
+        device_cert_profile,
+        rendezvous_info,
+    })
+}
+
+fn initialize_device(args: &InitializeDeviceArguments) -> Result<(), Error> {
+    let materials = load_device_initialization_materials(&args.initialization)?;
+
     if Path::new(&args.device_credential_out).exists() {
         bail!(
             "Device credential file {} already exists",
@@ -304,133 +550,262 @@ fn initialize_device(args: &InitializeDeviceArguments) -> Result<(), Error> {
         );
     }
 
-    // Build device cert
-    let mut device_subject = X509NameBuilder::new().context("Error building device subject")?;
-    device_subject
-        .append_entry_by_text("CN", &args.device_id)
-        .context("Error building device subject")?;
-    let device_subject = device_subject.build();
-    let device_subject = device_subject.as_ref();
-    let device_key_group =
-        EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).context("Error getting nist 256 group")?;
-    let device_key = EcKey::generate(&device_key_group).context("Error generating device key")?;
-    let device_key =
-        PKey::from_ec_key(device_key).context("Error converting device key to pkey")?;
-    let device_cert = build_device_cert(
-        device_subject,
-        &device_key,
-        &device_cert_ca_private_key,
-        &device_cert_ca_chain,
-    )
-    .context("Error building device certificate")?;
+    let external_key = match (&args.device_csr, &args.device_hmac_secret) {
+        (Some(csr), Some(hmac_secret)) => Some(ExternalDeviceKey::load(csr, hmac_secret)?),
+        _ => None,
+    };
 
-    // Construct device certificate chain
-    let mut device_cert_chain = device_cert_ca_chain;
-    device_cert_chain.insert(0, device_cert);
-    let device_cert_chain = X5Chain::new(device_cert_chain).context("Error creating X5Chain")?;
-    let device_cert_chain_serialized = device_cert_chain
-        .chain()
-        .iter()
-        .try_fold(vec![], |mut bytes, cert| {
-            cert.to_der().map(|der| {
-                bytes.extend(der);
-                bytes
+    let device = create_device(&materials, &args.device_id, external_key.as_ref())?;
+    device.write(&args.ownershipvoucher_out, &args.device_credential_out)?;
+
+    println!(
+        "Created ownership voucher for device {}",
+        device.guid.to_string()
+    );
+
+    Ok(())
+}
+
+fn initialize_devices(args: &InitializeDevicesArguments) -> Result<(), Error> {
+    let materials = load_device_initialization_materials(&args.initialization)?;
+
+    let device_ids: Vec<String> = match (&args.manifest, args.count) {
+        (Some(manifest), _) => fs::read_to_string(manifest)
+            .with_context(|| format!("Error reading device manifest at {manifest}"))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.split(',').next().unwrap().trim().to_string())
+            .collect(),
+        (None, Some(count)) => (0..count)
+            .map(|num| format!("{}-{}", args.device_id_prefix, num))
+            .collect(),
+        (None, None) => bail!("Either --count or --manifest is required"),
+    };
+
+    let output_directory = Path::new(&args.output_directory);
+    fs::create_dir_all(output_directory)
+        .with_context(|| format!("Error creating output directory {}", args.output_directory))?;
+
+    for device_id in &device_ids {
+        // The files are named after the device GUID, which is only known once it's generated
+        let tmp_ov_path = output_directory.join(".ownershipvoucher.tmp");
+        let tmp_dc_path = output_directory.join(".device_credential.tmp");
+        let device = create_device(&materials, device_id, None)
+            .and_then(|device| {
+                device.write(&tmp_ov_path, &tmp_dc_path)?;
+                Ok(device)
             })
+            .with_context(|| format!("Error initializing device {device_id}"))?;
+        let device_guid = device.guid.to_string();
+
+        fs::rename(
+            &tmp_ov_path,
+            output_directory.join(format!("{device_guid}.ov")),
+        )
+        .context("Error moving ownership voucher in place")?;
+        fs::rename(
+            &tmp_dc_path,
+            output_directory.join(format!("{device_guid}.dc")),
+        )
+        .context("Error moving device credential in place")?;
+
+        println!("Created ownership voucher for device {device_id}: {device_guid}");
+    }
+
+    println!("Initialized {} devices", device_ids.len());
+
+    Ok(())
+}
+
+fn load_signing_key(path: &str, what: &str) -> Result<SigningKey, Error> {
+    SigningKey::load(path).with_class_context(ErrorClass::Key, || {
+        format!("Error loading {what} at {path}")
+    })
+}
+
+fn load_owner_public_key(cert: &str, key_type: Option<KeyType>) -> Result<PublicKey, Error> {
+    let cert = load_x509(cert).with_class_context(ErrorClass::Key, || {
+        format!("Error loading new owner certificate at {cert}")
+    })?;
+    public_key_from_x509(cert, key_type)
+        .class_context(ErrorClass::Key, "Error serializing owner public key")
+}
+
+#[derive(Serialize)]
+struct HashOutput {
+    hash_type: String,
+    value: String,
+}
+
+impl From<&Hash> for HashOutput {
+    fn from(hash: &Hash) -> Self {
+        HashOutput {
+            hash_type: format!("{:?}", hash.get_type()),
+            value: hex::encode(hash.value()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PublicKeyOutput {
+    key_type: String,
+    public_key: String,
+    certificate_chain: Option<Vec<String>>,
+}
+
+impl TryFrom<&PublicKey> for PublicKeyOutput {
+    type Error = Error;
+
+    fn try_from(key: &PublicKey) -> Result<Self, Error> {
+        Ok(PublicKeyOutput {
+            key_type: format!("{:?}", key.keytype()),
+            public_key: hex::encode(key.pkey().public_key_to_der()?),
+            certificate_chain: key.chain().map(certificate_chain_output).transpose()?,
         })
-        .context("Error serializing device cert chain")?;
-    let device_cert_chain_hash = Hash::from_data(HashType::Sha384, &device_cert_chain_serialized)
-        .context("Error hashing device cert chain")?;
-
-    // Build device HMAC key
-    let mut hmac_key_buf = [0; 32];
-    rand_bytes(&mut hmac_key_buf).context("Error creating random device HMAC key")?;
-    let hmac_key_buf = hmac_key_buf;
-    let hmac_key = PKey::hmac(&hmac_key_buf).context("Error building hmac key")?;
-    let mut hmac_signer =
-        Signer::new(MessageDigest::sha384(), &hmac_key).context("Error creating hmac signer")?;
-
-    let device_guid = Guid::new().context("Error generating guid")?;
-
-    // Construct Ownership Voucher Header
-    let ov_header = OwnershipVoucherHeader::new(
-        ProtocolVersion::Version1_1,
-        device_guid.clone(),
-        rendezvous_info.clone(),
-        args.device_id.clone(),
-        manufacturer_pubkey,
-        Some(device_cert_chain_hash),
-    )
-    .context("Error creating new OwnershipVoucher Header")?;
-    let ov_header_ser = ov_header
-        .serialize_data()
-        .context("Error serializing Ownership Voucher header")?;
-
-    // Build device credential
-    let devcred = FileDeviceCredential {
-        active: true,
-        protver: ProtocolVersion::Version1_1,
-        device_info: args.device_id.clone(),
-        guid: device_guid.clone(),
-        rvinfo: rendezvous_info,
-        pubkey_hash: ov_header
-            .manufacturer_public_key_hash(HashType::Sha384)
-            .context("Error computing manufacturer public key hash")?,
-        key_storage: fdo_data_formats::devicecredential::file::KeyStorage::Plain {
-            hmac_secret: hmac_key_buf.to_vec(),
-            private_key: device_key
-                .private_key_to_der()
-                .context("Error serializing device private key")?,
-        },
-    };
+    }
+}
 
-    // Compute device hash over OV Header
-    hmac_signer
-        .update(&ov_header_ser)
-        .context("Error computing HMAC")?;
-    let ov_hmac = hmac_signer
-        .sign_to_vec()
-        .context("Error computing hmac signature")?;
-    let ov_hmac = HMac::from_digest(HashType::HmacSha384, ov_hmac)?;
+#[derive(Serialize)]
+struct RendezvousInstructionOutput {
+    variable: &'static str,
+    value: String,
+}
 
-    // Build the Ownership Voucher
-    let ov = OwnershipVoucher::new(ov_header, ov_hmac, Some(device_cert_chain))
-        .context("Error building ownership voucher")?;
+#[derive(Serialize)]
+struct OwnershipVoucherEntryOutput {
+    hash_previous_entry: HashOutput,
+    hash_header_info: HashOutput,
+    extra: Option<BTreeMap<String, String>>,
+    public_key: PublicKeyOutput,
+}
 
-    // Write out the ownership voucher and device credential
-    let ov = ov.to_pem().context("Error serializing device credential")?;
-    let devcred = devcred
-        .serialize_data()
-        .context("Error serializing device credential")?;
+#[derive(Serialize)]
+struct OwnershipVoucherOutput {
+    protocol_version: u16,
+    guid: String,
+    rendezvous_info: Vec<Vec<RendezvousInstructionOutput>>,
+    device_info: String,
+    manufacturer_public_key: PublicKeyOutput,
+    device_certificate_chain_hash: Option<HashOutput>,
+    header_hmac: HashOutput,
+    device_certificate_chain: Option<Vec<String>>,
+    entries: Vec<OwnershipVoucherEntryOutput>,
+}
 
-    fs::write(&args.ownershipvoucher_out, ov).context("Error writing ownership voucher")?;
-    fs::write(&args.device_credential_out, devcred).context("Error writing device credential")?;
+#[derive(Serialize)]
+struct DeviceCredentialOutput {
+    active: bool,
+    protocol_version: u16,
+    device_info: String,
+    guid: String,
+    rendezvous_info: Vec<Vec<RendezvousInstructionOutput>>,
+    public_key_hash: HashOutput,
+    key_storage: &'static str,
+}
 
-    println!(
-        "Created ownership voucher for device {}",
-        device_guid.to_string()
-    );
+fn print_custody_hop(hop: &CustodyHop, title: &str) {
+    println!("{title}:");
+    println!("\tKey: {} ({})", hop.key_type, hop.key_fingerprint);
+    if let Some(subject) = &hop.subject {
+        println!("\tSubject: {subject}");
+    }
+    if let Some(issuer) = &hop.issuer {
+        println!("\tIssuer: {issuer}");
+    }
+    if let (Some(not_before), Some(not_after)) = (&hop.not_before, &hop.not_after) {
+        println!("\tValidity: {not_before} - {not_after}");
+    }
+    match (hop.status, &hop.error) {
+        (CustodyStatus::Valid, _) => println!("\tStatus: valid"),
+        (CustodyStatus::Invalid, Some(error)) => println!("\tStatus: invalid ({error})"),
+        (CustodyStatus::Invalid, None) => println!("\tStatus: invalid"),
+        (CustodyStatus::Unverified, _) => println!("\tStatus: unverified"),
+    }
+}
+
+fn certificate_chain_output(chain: &X5Chain) -> Result<Vec<String>, Error> {
+    chain
+        .chain()
+        .iter()
+        .map(|cert| -> Result<String, Error> {
+            let pem = cert.to_pem().context("Error encoding certificate")?;
+            String::from_utf8(pem).context("Invalid PEM certificate encoding")
+        })
+        .collect()
+}
 
+fn rendezvous_info_output(info: &RendezvousInfo) -> Vec<Vec<RendezvousInstructionOutput>> {
+    info.values()
+        .iter()
+        .map(|directive| {
+            directive
+                .iter()
+                .map(|(variable, value)| RendezvousInstructionOutput {
+                    variable: variable.name(),
+                    value: hex::encode(value),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn print_structured<T: Serialize>(value: &T, format: DumpFormat) -> Result<(), Error> {
+    let output = match format {
+        DumpFormat::Json => {
+            serde_json::to_string_pretty(value).context("Error serializing to JSON")?
+        }
+        DumpFormat::Yaml => serde_yaml::to_string(value).context("Error serializing to YAML")?,
+        DumpFormat::Text => unreachable!("Text output is not structured"),
+    };
+    println!("{output}");
     Ok(())
 }
 
-fn dump_voucher(args: &DumpOwnershipVoucherArguments) -> Result<(), Error> {
-    let ov = {
-        let cts = fs::read(args.path.clone()).context("Error reading ownership voucher")?;
-        OwnershipVoucher::from_pem_or_raw(&cts).context("Error deserializing ownership voucher")?
+fn dump_voucher_structured(ov: &OwnershipVoucher, format: DumpFormat) -> Result<(), Error> {
+    let ov_header = ov.header();
+
+    let mut entries = Vec::new();
+    let ov_iter = ov.iter_entries().context("Error creating OV iterator")?;
+    for (pos, entry) in ov_iter.enumerate() {
+        let entry = entry.with_context(|| format!("Error parsing entry {pos}"))?;
+        entries.push(OwnershipVoucherEntryOutput {
+            hash_previous_entry: entry.hash_previous_entry().into(),
+            hash_header_info: entry.hash_header_info().into(),
+            extra: entry.extra().map(|extra| {
+                extra
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), hex::encode(value)))
+                    .collect()
+            }),
+            public_key: entry.public_key().try_into()?,
+        });
+    }
+
+    let output = OwnershipVoucherOutput {
+        protocol_version: ov_header.protocol_version() as u16,
+        guid: ov_header.guid().to_string(),
+        rendezvous_info: rendezvous_info_output(ov_header.rendezvous_info()),
+        device_info: ov_header.device_info().to_string(),
+        manufacturer_public_key: ov_header.manufacturer_public_key().try_into()?,
+        device_certificate_chain_hash: ov_header.device_certificate_chain_hash().map(Into::into),
+        header_hmac: ov.header_hmac().into(),
+        device_certificate_chain: ov
+            .device_certificate_chain()
+            .map(certificate_chain_output)
+            .transpose()?,
+        entries,
     };
 
-    let outform = args.outform;
-    if let Some(outform) = outform {
-        let output = match outform {
-            OutputFormat::Cose => ov
-                .serialize_data()
-                .context("Error serializing ownership voucher")?,
-            OutputFormat::Pem => ov
-                .to_pem()
-                .context("Error serializing ownership voucher")?
-                .as_bytes()
-                .to_vec(),
-        };
+    print_structured(&output, format)
+}
+
+fn dump_voucher(args: &DumpOwnershipVoucherArguments) -> Result<(), Error> {
+    let ov = load_voucher(&args.path)?;
+
+    if let Some(outform) = args.outform {
+        let output =
+            serialize_voucher(&ov, outform).context("Error serializing ownership voucher")?;
         std::io::stdout()
             .write_all(&output)
             .context("Error writing output")?;
@@ -439,11 +814,18 @@ fn dump_voucher(args: &DumpOwnershipVoucherArguments) -> Result<(), Error> {
 
     let ov_header = ov.header();
     if ov_header.protocol_version() != ProtocolVersion::Version1_1 {
-        bail!(
-            "Protocol version in OV ({}) not supported ({})",
-            ov_header.protocol_version(),
-            ProtocolVersion::Version1_1,
-        );
+        return Err(class_error(
+            ErrorClass::Voucher,
+            format!(
+                "Protocol version in OV ({}) not supported ({})",
+                ov_header.protocol_version(),
+                ProtocolVersion::Version1_1,
+            ),
+        ));
+    }
+
+    if args.output != DumpFormat::Text {
+        return dump_voucher_structured(&ov, args.output);
     }
 
     println!("Header:");
@@ -493,13 +875,117 @@ fn dump_voucher(args: &DumpOwnershipVoucherArguments) -> Result<(), Error> {
     Ok(())
 }
 
-fn dump_devcred(args: &DumpDeviceCredentialArguments) -> Result<(), Error> {
-    let dc = {
-        let dc = fs::read(args.path.clone()).context("Error reading device credential")?;
-        FileDeviceCredential::deserialize_data(&dc)
-            .context("Error deserializing device credential")?
+async fn verify_voucher(args: &VerifyOwnershipVoucherArguments) -> Result<(), Error> {
+    let ov = load_voucher(&args.path)?;
+    let trusted_manufacturer_certs = load_x509s(&args.trusted_manufacturer_certs)
+        .with_class_context(ErrorClass::Key, || {
+            format!(
+                "Error loading trusted manufacturer certificates at {}",
+                args.trusted_manufacturer_certs
+            )
+        })?;
+    let trusted_manufacturer_certs = X5Bag::with_certs(trusted_manufacturer_certs)
+        .context("Error building trusted manufacturer certificate bag")?;
+
+    let expected_owner = match &args.expected_owner_cert {
+        None => None,
+        Some(expected_owner_cert) => Some(
+            load_x509(expected_owner_cert)
+                .with_class_context(ErrorClass::Key, || {
+                    format!("Error loading expected owner certificate at {expected_owner_cert}")
+                })?
+                .public_key()
+                .context("Error getting expected owner public key")?,
+        ),
+    };
+
+    let revocation_checker = if args.check_revocation || !args.crl.is_empty() {
+        let crls = args
+            .crl
+            .iter()
+            .map(|path| load_crl(Path::new(path)))
+            .collect::<Result<Vec<_>>>()
+            .class_context(ErrorClass::Key, "Error loading CRLs")?;
+        let mode = if args.revocation_soft_fail {
+            RevocationMode::SoftFail
+        } else {
+            RevocationMode::HardFail
+        };
+        Some(
+            RevocationChecker::new(
+                mode,
+                crls,
+                args.check_revocation,
+                args.check_revocation,
+                Duration::ZERO,
+            )
+            .class_context(
+                ErrorClass::Validation,
+                "Error setting up revocation checking",
+            )?,
+        )
+    } else {
+        None
+    };
+
+    let verification = VoucherVerification {
+        trusted_manufacturer_certs,
+        expected_owner,
+        revocation_checker,
+    };
+    verify_ownership_voucher(&ov, &verification, |check| println!("{check}")).await?;
+
+    println!("Ownership voucher is valid");
+
+    Ok(())
+}
+
+fn report_provenance(args: &ProvenanceArguments) -> Result<(), Error> {
+    let ov = load_voucher(&args.path)?;
+    let trusted_manufacturer_certs = match &args.trusted_manufacturer_certs {
+        None => None,
+        Some(path) => {
+            let certs = load_x509s(path).with_class_context(ErrorClass::Key, || {
+                format!("Error loading trusted manufacturer certificates at {path}")
+            })?;
+            Some(
+                X5Bag::with_certs(certs)
+                    .context("Error building trusted manufacturer certificate bag")?,
+            )
+        }
     };
 
+    let output = voucher_provenance(&ov, trusted_manufacturer_certs.as_ref())?;
+
+    if args.output != DumpFormat::Text {
+        print_structured(&output, args.output)?;
+    } else {
+        println!("Device GUID: {}", output.guid);
+        println!("Device Info: {}", output.device_info);
+        print_custody_hop(&output.manufacturer, "Manufacturer");
+        for (pos, owner) in output.owners.iter().enumerate() {
+            print_custody_hop(owner, &format!("Owner {}", pos + 1));
+        }
+        if output.owners.is_empty() {
+            println!("Owners: <none>");
+        }
+    }
+
+    if !output.valid {
+        return Err(class_error(
+            ErrorClass::Validation,
+            "Chain of custody is not valid",
+        ));
+    }
+    if args.output == DumpFormat::Text {
+        println!("Chain of custody is valid");
+    }
+    Ok(())
+}
+
+fn dump_devcred(args: &DumpDeviceCredentialArguments) -> Result<(), Error> {
+    let dc = load_device_credential(&args.path)?;
+
     if dc.protver != ProtocolVersion::Version1_1 {
         bail!(
             "Protocol version in OV ({}) not supported ({})",
@@ -508,6 +994,22 @@ fn dump_devcred(args: &DumpDeviceCredentialArguments) -> Result<(), Error> {
         );
     }
 
+    if args.output != DumpFormat::Text {
+        let output = DeviceCredentialOutput {
+            active: dc.active,
+            protocol_version: dc.protver as u16,
+            device_info: dc.device_info.clone(),
+            guid: dc.guid.to_string(),
+            rendezvous_info: rendezvous_info_output(&dc.rvinfo),
+            public_key_hash: (&dc.pubkey_hash).into(),
+            key_storage: match dc.key_storage {
+                KeyStorage::Plain { .. } => "plain",
+                KeyStorage::Tpm { .. } => "tpm",
+            },
+        };
+        return print_structured(&output, args.output);
+    }
+
     println!("Active: {}", dc.active);
     println!("Protocol Version: {}", dc.protver);
     println!("Device Info: {}", dc.device_info);
@@ -519,11 +1021,11 @@ fn dump_devcred(args: &DumpDeviceCredentialArguments) -> Result<(), Error> {
     println!("Public key hash: {}", dc.pubkey_hash);
     println!("HMAC and signing key:");
     match dc.key_storage {
-        fdo_data_formats::devicecredential::file::KeyStorage::Plain { .. } => {
+        KeyStorage::Plain { .. } => {
             println!("\tHMAC key: <secret>");
             println!("\tSigning key: <secret>");
         }
-        fdo_data_formats::devicecredential::file::KeyStorage::Tpm {
+        KeyStorage::Tpm {
             signing_public,
             hmac_public,
             ..
@@ -541,50 +1043,192 @@ fn dump_devcred(args: &DumpDeviceCredentialArguments) -> Result<(), Error> {
     Ok(())
 }
 
+fn load_crypto_policy(path: Option<&str>) -> Result<Option<CryptoPolicy>, Error> {
+    path.map(|path| CryptoPolicy::load(Path::new(path)))
+        .transpose()
+        .class_context(ErrorClass::Other, "Error loading crypto policy")
+}
+
+/// Checks every key, certificate and hash of an extended voucher against the crypto policy
+fn check_crypto_policy(
+    crypto_policy: Option<&CryptoPolicy>,
+    ov: &OwnershipVoucher,
+) -> Result<(), Error> {
+    match crypto_policy {
+        None => Ok(()),
+        Some(crypto_policy) => crypto_policy.check_voucher(ov).class_context(
+            ErrorClass::Validation,
+            "Extended ownership voucher does not comply with the crypto policy",
+        ),
+    }
+}
+
 fn extend_voucher(args: &ExtendOwnershipVoucherArguments) -> Result<(), Error> {
-    let mut ov = {
-        let ov = fs::read(args.path.clone()).context("Error reading ownership voucher")?;
-        OwnershipVoucher::from_pem_or_raw(&ov).context("Error deserializing ownership voucher")?
+    let crypto_policy = load_crypto_policy(args.crypto_policy.as_deref())?;
+    let mut ov = load_voucher(&args.path)?;
+    let original_entries = ov.num_entries() as usize;
+
+    let current_owner_private_key =
+        load_signing_key(&args.current_owner_private_key, "current owner private key")?;
+    match (
+        &args.new_owner_cert,
+        &args.new_owner_cert_chain,
+        &args.chain,
+    ) {
+        (Some(new_owner_cert), _, _) => {
+            let new_owner_pubkey = load_owner_public_key(new_owner_cert, args.key_type)?;
+            extend_ownership_voucher(&mut ov, &current_owner_private_key, &new_owner_pubkey)?;
+        }
+        (None, Some(new_owner_cert_chain), _) => {
+            let certs = load_x509s(new_owner_cert_chain)
+                .with_class_context(ErrorClass::Key, || {
+                    format!("Error loading new owner certificate chain at {new_owner_cert_chain}")
+                })?;
+            let new_owner_pubkey = public_key_from_x509_chain(certs, args.key_type).class_context(
+                ErrorClass::Key,
+                "Error serializing delegated owner public key",
+            )?;
+            extend_ownership_voucher(&mut ov, &current_owner_private_key, &new_owner_pubkey)?;
+        }
+        (None, None, Some(chain)) => {
+            let certs = load_x509s(chain).with_class_context(ErrorClass::Key, || {
+                format!("Error loading owner chain at {chain}")
+            })?;
+            let total = certs.len();
+            let intermediate_owner_private_keys = args
+                .intermediate_owner_private_key
+                .iter()
+                .map(|key| load_signing_key(key, "intermediate owner private key"))
+                .collect::<Result<Vec<_>, Error>>()?;
+            extend_ownership_voucher_chain(
+                &mut ov,
+                current_owner_private_key,
+                certs,
+                intermediate_owner_private_keys,
+                args.key_type,
+            )
+            .with_context(|| format!("Error extending ownership voucher through {chain}"))?;
+            println!("Extended ownership voucher through {total} owners");
+        }
+        (None, None, None) => {
+            bail!("Either --new-owner-cert, --new-owner-cert-chain or --chain is required")
+        }
+    }
+    check_crypto_policy(crypto_policy.as_ref(), &ov)?;
+
+    if args.dry_run {
+        println!("Entries that would be added to {}:", args.path);
+        let ov_iter = ov.iter_entries().context("Error creating OV iterator")?;
+        for (pos, entry) in ov_iter.enumerate().skip(original_entries) {
+            let entry = entry.with_context(|| format!("Error parsing entry {pos}"))?;
+
+            println!("\tEntry {pos}");
+            println!("\t\tPrevious entry hash: {}", entry.hash_previous_entry());
+            println!("\t\tHeader info hash: {}", entry.hash_header_info());
+            println!("\t\tExtra: {:?}", entry.extra());
+            println!("\t\tPublic key: {}", entry.public_key());
+        }
+        return Ok(());
+    }
+
+    write_voucher(&ov, args.output.as_ref().unwrap_or(&args.path))
+}
+
+fn resale_voucher(args: &ResaleArguments) -> Result<(), Error> {
+    let crypto_policy = load_crypto_policy(args.crypto_policy.as_deref())?;
+    let mut ov = load_voucher(&args.path)?;
+    let current_owner_private_key =
+        load_signing_key(&args.current_owner_private_key, "current owner private key")?;
+    let new_owner_pubkey = load_owner_public_key(&args.new_owner_cert, args.key_type)?;
+    resell_ownership_voucher(&mut ov, &current_owner_private_key, &new_owner_pubkey)?;
+    check_crypto_policy(crypto_policy.as_ref(), &ov)?;
+
+    write_voucher(&ov, &args.output)
+}
+
+fn write_output(output: Option<&str>, contents: &[u8]) -> Result<(), Error> {
+    match output {
+        None => std::io::stdout()
+            .write_all(contents)
+            .context("Error writing output")?,
+        Some(path) => {
+            if Path::new(path).exists() {
+                bail!("Output file {} already exists", path);
+            }
+            fs::write(path, contents).with_context(|| format!("Error writing to {path}"))?;
+        }
+    }
+    Ok(())
+}
+
+fn export_voucher(args: &ExportOwnershipVoucherArguments) -> Result<(), Error> {
+    let ov = load_voucher(&args.path)?;
+    let ov = serialize_voucher(&ov, VoucherFormat::Pem)
+        .context("Error serializing ownership voucher")?;
+    write_output(args.output.as_deref(), &ov)
+}
+
+fn import_voucher(args: &ImportOwnershipVoucherArguments) -> Result<(), Error> {
+    let ov = {
+        let ov = fs::read(&args.path).context("Error reading ownership voucher")?;
+        OwnershipVoucher::from_pem(&ov)
+            .class_context(ErrorClass::Voucher, "Error parsing PEM ownership voucher")?
     };
+    // Make sure we don't import a corrupted voucher
+    validate_voucher_entries(&ov)?;
 
-    let ov_header = ov.header();
-    if ov_header.protocol_version() != ProtocolVersion::Version1_1 {
-        bail!(
-            "Protocol version in OV ({}) not supported ({})",
-            ov_header.protocol_version(),
-            ProtocolVersion::Version1_1,
-        );
+    let ov = serialize_voucher(&ov, VoucherFormat::Cose)
+        .context("Error serializing ownership voucher")?;
+    write_output(Some(&args.output), &ov)
+}
+
+fn convert_voucher(args: &ConvertOwnershipVoucherArguments) -> Result<(), Error> {
+    let ov = load_voucher(&args.path)?;
+    // Make sure we don't convert a corrupted voucher
+    validate_voucher_entries(&ov)?;
+
+    let mut output =
+        serialize_voucher(&ov, args.outform).context("Error serializing ownership voucher")?;
+    if args.outform == VoucherFormat::Diagnostic {
+        output.push(b'\n');
     }
+    write_output(args.output.as_deref(), &output)
+}
 
-    let current_owner_private_key = load_private_key(&args.current_owner_private_key)
-        .with_context(|| {
-            format!(
-                "Error loading current owner private key at {}",
-                args.current_owner_private_key
-            )
-        })?;
-    let new_owner_cert = load_x509(&args.new_owner_cert).with_context(|| {
-        format!(
-            "Error loading new owner certificate at {}",
-            args.new_owner_cert
-        )
-    })?;
-    let new_owner_pubkey =
-        PublicKey::try_from(new_owner_cert).context("Error serializing owner public key")?;
+fn migrate_devcred(args: &MigrateDeviceCredentialArguments) -> Result<(), Error> {
+    match migrate_device_credential(&args.path, args.output.as_ref())? {
+        Migration::UpToDate => {
+            println!("Device credential is already at file format version {FILE_FORMAT_VERSION}")
+        }
+        Migration::Migrated { from_version: None } => println!(
+            "Migrated device credential from the legacy format to version {FILE_FORMAT_VERSION}"
+        ),
+        Migration::Migrated {
+            from_version: Some(version),
+        } => println!(
+            "Migrated device credential from version {version} to version {FILE_FORMAT_VERSION}"
+        ),
+    }
 
-    ov.extend(&current_owner_private_key, None, &new_owner_pubkey)
-        .context("Error extending ownership voucher")?;
+    Ok(())
+}
 
-    // Write out
-    let newname = format!("{}.new", args.path);
-    {
-        // A new scope, to ensure the file gets closed before we move it
-        let ov = ov.to_pem().context("Error serializing ownership voucher")?;
-        fs::write(&newname, ov).with_context(|| format!("Error writing to {newname}"))?;
+fn set_devcred_active(
+    args: &SetDeviceCredentialActiveArguments,
+    active: bool,
+) -> Result<(), Error> {
+    if !set_device_credential_active(&args.path, active)? {
+        println!(
+            "Device credential is already {}",
+            if active { "active" } else { "inactive" }
+        );
+        return Ok(());
     }
 
-    fs::rename(newname, args.path.clone())
-        .context("Error moving new ownership voucher in place")?;
+    println!(
+        "Device credential {}",
+        if active { "reactivated" } else { "deactivated" }
+    );
 
     Ok(())
 }
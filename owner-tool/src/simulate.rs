@@ -0,0 +1,530 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use fdo_data_formats::{
+    constants::{DeviceSigType, HeaderKeys, StandardServiceInfoModule, TransportProtocol},
+    devicecredential::FileDeviceCredential,
+    enhanced_types::RendezvousInterpreterSide,
+    messages,
+    types::{
+        new_eat, COSEHeaderMap, COSESign, CipherSuite, EATokenPayload, KexSuite, KeyDeriveSide,
+        KeyExchange, Nonce, PayloadCreating, ServiceInfo, SigInfo, TO1DataPayload, TO2AddressEntry,
+        TO2ProveDevicePayload, TO2ProveOVHdrPayload,
+    },
+    DeviceCredential, ProtocolVersion,
+};
+use fdo_http_wrapper::client::{RequestResult, ServiceClient};
+
+use crate::SimulateArguments;
+
+const MAX_SERVICE_INFO_LOOPS: u32 = 1000;
+
+/// The phases of an onboarding, timed separately
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    To1,
+    To2Hello,
+    To2ProveDevice,
+    ServiceInfo,
+    Done,
+}
+
+const PHASES: [Phase; 5] = [
+    Phase::To1,
+    Phase::To2Hello,
+    Phase::To2ProveDevice,
+    Phase::ServiceInfo,
+    Phase::Done,
+];
+
+impl Phase {
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::To1 => "TO1",
+            Phase::To2Hello => "TO2 HelloDevice/OV entries",
+            Phase::To2ProveDevice => "TO2 ProveDevice",
+            Phase::ServiceInfo => "TO2 ServiceInfo",
+            Phase::Done => "TO2 Done",
+        }
+    }
+}
+
+/// Time taken by each phase of a single simulated device
+#[derive(Debug, Default)]
+struct DeviceTimings {
+    phases: [Duration; 5],
+}
+
+struct SimulationError {
+    phase: Phase,
+    error: anyhow::Error,
+}
+
+/// A simulated device, onboarding with a credential from `initialize-devices`.
+///
+/// The device does not verify the ownership voucher signatures and ignores all
+/// ServiceInfo sent by the owner: only the load on the servers is of interest.
+struct VirtualDevice {
+    devcred: FileDeviceCredential,
+    timings: DeviceTimings,
+    phase_start: Instant,
+}
+
+impl VirtualDevice {
+    fn new(devcred: FileDeviceCredential) -> Self {
+        VirtualDevice {
+            devcred,
+            timings: DeviceTimings::default(),
+            phase_start: Instant::now(),
+        }
+    }
+
+    fn finish_phase(&mut self, phase: Phase) {
+        self.timings.phases[phase as usize] = self.phase_start.elapsed();
+        self.phase_start = Instant::now();
+    }
+
+    fn sign_eat(
+        &self,
+        eat: EATokenPayload<PayloadCreating>,
+        unprotected: Option<COSEHeaderMap>,
+    ) -> Result<COSESign> {
+        let signer = self
+            .devcred
+            .get_signer()
+            .context("Error getting device signer")?;
+        COSESign::from_eat(eat, unprotected, signer.as_ref()).context("Error signing EAT")
+    }
+
+    async fn onboard(mut self) -> Result<DeviceTimings, SimulationError> {
+        self.phase_start = Instant::now();
+        let to2_urls = self.perform_to1().await.map_err(|error| SimulationError {
+            phase: Phase::To1,
+            error,
+        })?;
+        self.finish_phase(Phase::To1);
+
+        let mut last_error = None;
+        for url in to2_urls {
+            match self.perform_to2(&url).await {
+                Ok(()) => return Ok(self.timings),
+                Err(e) => last_error = Some(e),
+            }
+            self.phase_start = Instant::now();
+        }
+        Err(last_error.unwrap_or_else(|| SimulationError {
+            phase: Phase::To1,
+            error: anyhow::anyhow!("No usable TO2 addresses"),
+        }))
+    }
+
+    /// Performs TO1 with the rendezvous servers, returning the owner URLs.
+    async fn perform_to1(&self) -> Result<Vec<String>> {
+        let directives = self
+            .devcred
+            .rendezvous_info()
+            .to_interpreted(RendezvousInterpreterSide::Device)
+            .context("Error parsing rendezvous directives")?;
+
+        let mut last_error = anyhow::anyhow!("No usable rendezvous directives");
+        for directive in &directives {
+            let mut urls = directive.get_dns_urls();
+            urls.extend(directive.get_ip_urls());
+            for url in urls {
+                let mut client = ServiceClient::new(ProtocolVersion::Version1_1, &url)
+                    .with_version_negotiation();
+                match self.perform_to1_with(&mut client).await {
+                    Ok(to1d) => return to2_urls(&to1d),
+                    Err(e) => last_error = e.context(format!("Error performing TO1 with {url}")),
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn perform_to1_with(&self, client: &mut ServiceClient) -> Result<COSESign> {
+        let sig_type = DeviceSigType::StSECP384R1;
+        let hello_rv_ack: RequestResult<messages::v11::to1::HelloRVAck> = client
+            .send_request(
+                messages::v11::to1::HelloRV::new(
+                    self.devcred.device_guid().clone(),
+                    SigInfo::new(sig_type, vec![]),
+                ),
+                None,
+            )
+            .await;
+        let hello_rv_ack = hello_rv_ack.context("Error sending HelloRV")?;
+
+        let eat = new_eat::<bool>(
+            None,
+            hello_rv_ack.nonce4().clone(),
+            self.devcred.device_guid().clone(),
+        )
+        .context("Error creating EAT")?;
+        let token = self.sign_eat(eat, None)?;
+
+        let rv_redirect: RequestResult<messages::v11::to1::RVRedirect> = client
+            .send_request(messages::v11::to1::ProveToRV::new(token), None)
+            .await;
+        Ok(rv_redirect.context("Error sending ProveToRV")?.into_to1d())
+    }
+
+    async fn perform_to2(&mut self, url: &str) -> Result<(), SimulationError> {
+        let mut client = ServiceClient::new(ProtocolVersion::Version1_1, url);
+        let kexsuite = KexSuite::Ecdh384;
+        let ciphersuite = CipherSuite::A256Gcm;
+
+        let in_phase = |phase: Phase| move |error: anyhow::Error| SimulationError { phase, error };
+
+        // Send: HelloDevice, Receive: ProveOVHdr, and fetch the voucher entries
+        let (nonce6, a_key_exchange) = self
+            .perform_hellodevice(&mut client, kexsuite, ciphersuite)
+            .await
+            .map_err(in_phase(Phase::To2Hello))?;
+        self.finish_phase(Phase::To2Hello);
+
+        // Send: ProveDevice, Receive: SetupDevice
+        let nonce7 = self
+            .perform_provedevice(&mut client, &nonce6, &a_key_exchange, kexsuite, ciphersuite)
+            .await
+            .map_err(in_phase(Phase::To2ProveDevice))?;
+        self.finish_phase(Phase::To2ProveDevice);
+
+        perform_serviceinfo(&mut client)
+            .await
+            .map_err(in_phase(Phase::ServiceInfo))?;
+        self.finish_phase(Phase::ServiceInfo);
+
+        // Send: Done, Receive: Done2
+        let done2: RequestResult<messages::v11::to2::Done2> = client
+            .send_request(messages::v11::to2::Done::new(nonce6), None)
+            .await;
+        let done2 = done2
+            .context("Error sending Done")
+            .map_err(in_phase(Phase::Done))?;
+        if done2.nonce7() != &nonce7 {
+            return Err(SimulationError {
+                phase: Phase::Done,
+                error: anyhow::anyhow!("Nonce7 did not match in Done2"),
+            });
+        }
+        self.finish_phase(Phase::Done);
+        Ok(())
+    }
+
+    /// Returns nonce6 and the owner side of the key exchange
+    async fn perform_hellodevice(
+        &self,
+        client: &mut ServiceClient,
+        kexsuite: KexSuite,
+        ciphersuite: CipherSuite,
+    ) -> Result<(Nonce, Vec<u8>)> {
+        let nonce5 = Nonce::new().context("Error generating nonce5")?;
+        let prove_ov_hdr: RequestResult<messages::v11::to2::ProveOVHdr> = client
+            .send_request(
+                messages::v11::to2::HelloDevice::new(
+                    self.devcred.device_guid().clone(),
+                    nonce5.clone(),
+                    kexsuite,
+                    ciphersuite,
+                    SigInfo::new(DeviceSigType::StSECP384R1, vec![]),
+                ),
+                None,
+            )
+            .await;
+        let prove_ov_hdr = prove_ov_hdr
+            .context("Error sending HelloDevice")?
+            .into_token();
+        let payload = prove_ov_hdr
+            .get_payload_unverified::<TO2ProveOVHdrPayload>()
+            .context("Error parsing ProveOVHdr payload")?;
+        let payload = payload.get_unverified_value();
+        if payload.nonce5() != &nonce5 {
+            bail!("Nonce5 value is mismatched");
+        }
+        let nonce6: Nonce = prove_ov_hdr
+            .get_unprotected_value(HeaderKeys::CUPHNonce)
+            .context("Error getting nonce6")?
+            .context("Missing nonce6")?;
+
+        for entry_num in 0..payload.num_ov_entries() {
+            let entry: RequestResult<messages::v11::to2::OVNextEntry> = client
+                .send_request(
+                    messages::v11::to2::GetOVNextEntry::new(entry_num as u8),
+                    None,
+                )
+                .await;
+            entry.with_context(|| format!("Error getting OV entry num {entry_num}"))?;
+        }
+
+        Ok((nonce6, payload.a_key_exchange().to_vec()))
+    }
+
+    /// Returns nonce7
+    async fn perform_provedevice(
+        &self,
+        client: &mut ServiceClient,
+        nonce6: &Nonce,
+        a_key_exchange: &[u8],
+        kexsuite: KexSuite,
+        ciphersuite: CipherSuite,
+    ) -> Result<Nonce> {
+        let non_interoperable_kdf_required = client
+            .non_interoperable_kdf_required()
+            .context("Unknown non-interoperable KDF requirement")?;
+        let b_key_exchange =
+            KeyExchange::new(kexsuite).context("Error creating device side of key exchange")?;
+        let new_keys = b_key_exchange
+            .derive_key(
+                KeyDeriveSide::Device,
+                ciphersuite,
+                a_key_exchange,
+                non_interoperable_kdf_required,
+            )
+            .context("Error performing key derivation")?;
+        let new_keys = fdo_http_wrapper::EncryptionKeys::from_derived(ciphersuite, new_keys);
+
+        let nonce7 = Nonce::new().context("Error generating nonce7")?;
+        let payload = TO2ProveDevicePayload::new(
+            b_key_exchange
+                .get_public()
+                .context("Error getting key exchange public part")?,
+        );
+        let eat = new_eat(
+            Some(&payload),
+            nonce6.clone(),
+            self.devcred.device_guid().clone(),
+        )
+        .context("Error creating ProveDevice EAT")?;
+        let mut unprotected = COSEHeaderMap::new();
+        unprotected
+            .insert(HeaderKeys::EUPHNonce, &nonce7)
+            .context("Error adding nonce7")?;
+        let token = self.sign_eat(eat, Some(unprotected))?;
+
+        let setup_device: RequestResult<messages::v11::to2::SetupDevice> = client
+            .send_request(messages::v11::to2::ProveDevice::new(token), Some(new_keys))
+            .await;
+        setup_device.context("Error sending ProveDevice")?;
+        Ok(nonce7)
+    }
+}
+
+/// Sends devmod and then empty ServiceInfo until the owner is done, ignoring
+/// everything the owner sends.
+async fn perform_serviceinfo(client: &mut ServiceClient) -> Result<()> {
+    let ready: RequestResult<messages::v11::to2::OwnerServiceInfoReady> = client
+        .send_request(
+            messages::v11::to2::DeviceServiceInfoReady::new(None, None),
+            None,
+        )
+        .await;
+    ready.context("Error sending DeviceServiceInfoReady")?;
+
+    let mut devmod = ServiceInfo::new();
+    devmod.add(StandardServiceInfoModule::DevMod, "active", &true)?;
+    devmod.add(StandardServiceInfoModule::DevMod, "os", &"linux")?;
+    devmod.add(
+        StandardServiceInfoModule::DevMod,
+        "device",
+        &"fdo-simulator",
+    )?;
+    devmod.add_modules(&[StandardServiceInfoModule::DevMod.into()])?;
+
+    let mut out_si = Some(devmod);
+    for loop_num in 0..MAX_SERVICE_INFO_LOOPS {
+        let send_si = messages::v11::to2::DeviceServiceInfo::new(
+            false,
+            out_si.take().unwrap_or_else(ServiceInfo::new),
+        );
+        let owner_si: RequestResult<messages::v11::to2::OwnerServiceInfo> =
+            client.send_request(send_si, None).await;
+        let owner_si = owner_si.with_context(|| format!("Error in ServiceInfo loop {loop_num}"))?;
+        if owner_si.is_done() {
+            return Ok(());
+        }
+    }
+    bail!(
+        "Maximum number of ServiceInfo loops ({}) exceeded",
+        MAX_SERVICE_INFO_LOOPS
+    );
+}
+
+fn to2_urls(to1d: &COSESign) -> Result<Vec<String>> {
+    let payload = to1d
+        .get_payload_unverified::<TO1DataPayload>()
+        .context("Error parsing to1d")?;
+    let urls: Vec<String> = payload
+        .get_unverified_value()
+        .to2_addresses()
+        .iter()
+        .flat_map(to2_address_urls)
+        .collect();
+    if urls.is_empty() {
+        bail!("No usable TO2 addresses in to1d");
+    }
+    Ok(urls)
+}
+
+fn to2_address_urls(entry: &TO2AddressEntry) -> Vec<String> {
+    let scheme = match entry.protocol() {
+        TransportProtocol::Http => "http",
+        TransportProtocol::Https => "https",
+        _ => return Vec::new(),
+    };
+    let mut urls = Vec::new();
+    if let Some(dns_name) = entry.dns() {
+        urls.push(format!("{}://{}:{}", scheme, dns_name, entry.port()));
+    }
+    if let Some(ip_address) = entry.ip() {
+        urls.push(format!(
+            "{}://{}:{}",
+            scheme,
+            ip_address.as_url_host(),
+            entry.port()
+        ));
+    }
+    urls
+}
+
+fn load_device_credentials(args: &SimulateArguments) -> Result<Vec<FileDeviceCredential>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(&args.device_credentials_directory)
+        .with_context(|| {
+            format!(
+                "Error reading device credentials directory {}",
+                args.device_credentials_directory
+            )
+        })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "dc"))
+        .collect();
+    paths.sort();
+    if let Some(count) = args.count {
+        paths.truncate(count);
+    }
+
+    paths
+        .iter()
+        .map(|path| {
+            let contents = fs::read(path)
+                .with_context(|| format!("Error reading device credential {path:?}"))?;
+            FileDeviceCredential::from_file_data(&contents)
+                .with_context(|| format!("Error parsing device credential {path:?}"))
+        })
+        .collect()
+}
+
+fn print_latencies(name: &str, durations: &mut [Duration]) {
+    if durations.is_empty() {
+        return;
+    }
+    durations.sort();
+    let percentile = |p: usize| durations[(durations.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+    let total: Duration = durations.iter().sum();
+    println!(
+        "  {:<28} avg {:>9.1} ms  p50 {:>9.1} ms  p95 {:>9.1} ms  p99 {:>9.1} ms  max {:>9.1} ms",
+        name,
+        total.as_secs_f64() * 1000.0 / durations.len() as f64,
+        percentile(50),
+        percentile(95),
+        percentile(99),
+        percentile(100),
+    );
+}
+
+pub(crate) async fn simulate(args: &SimulateArguments) -> Result<()> {
+    if args.concurrency == 0 {
+        bail!("--concurrency must be at least 1");
+    }
+    let devcreds = load_device_credentials(args)?;
+    let total = devcreds.len();
+    if total == 0 {
+        bail!(
+            "No device credentials (*.dc) found in {}",
+            args.device_credentials_directory
+        );
+    }
+    println!(
+        "Simulating {} devices, {} at a time",
+        total, args.concurrency
+    );
+
+    let start = Instant::now();
+    let semaphore = Arc::new(Semaphore::new(args.concurrency));
+    let mut tasks = JoinSet::new();
+    for devcred in devcreds {
+        let permit = semaphore.clone().acquire_owned().await?;
+        tasks.spawn(async move {
+            let guid = devcred.device_guid().clone();
+            let res = VirtualDevice::new(devcred).onboard().await;
+            drop(permit);
+            (guid, res)
+        });
+    }
+
+    let mut timings = Vec::new();
+    let mut failures = [0usize; 5];
+    while let Some(res) = tasks.join_next().await {
+        match res.context("Simulated device task failed")? {
+            (_, Ok(device_timings)) => timings.push(device_timings),
+            (guid, Err(e)) => {
+                log::warn!(
+                    "Device {} failed in {}: {:?}",
+                    guid.to_string(),
+                    e.phase.name(),
+                    e.error
+                );
+                failures[e.phase as usize] += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "Onboarded {} of {} devices in {:.1} seconds ({:.2} devices/second)",
+        timings.len(),
+        total,
+        elapsed.as_secs_f64(),
+        timings.len() as f64 / elapsed.as_secs_f64()
+    );
+    let failed: usize = failures.iter().sum();
+    if failed > 0 {
+        println!("Failed devices: {failed}");
+        for phase in PHASES {
+            if failures[phase as usize] > 0 {
+                println!("  {:<28} {}", phase.name(), failures[phase as usize]);
+            }
+        }
+    }
+    if !timings.is_empty() {
+        println!("Latency of successful onboardings:");
+        for phase in PHASES {
+            let mut durations: Vec<Duration> = timings
+                .iter()
+                .map(|device| device.phases[phase as usize])
+                .collect();
+            print_latencies(phase.name(), &mut durations);
+        }
+        let mut durations: Vec<Duration> = timings
+            .iter()
+            .map(|device| device.phases.iter().sum())
+            .collect();
+        print_latencies("Total", &mut durations);
+    }
+
+    if failed > 0 {
+        bail!(
+            "{} of {} simulated devices failed to onboard",
+            failed,
+            total
+        );
+    }
+    Ok(())
+}
@@ -3,10 +3,13 @@ use std::convert::TryInto;
 use fdo_data_formats::messages;
 use fdo_data_formats::{
     constants::ErrorCode,
+    enhanced_types::X5Bag,
     messages::Message,
     types::{Nonce, TO1DataPayload},
 };
 
+use fdo_http_wrapper::server::consume_session_nonce;
+use fdo_http_wrapper::server::run_blocking;
 use fdo_http_wrapper::server::Error;
 use fdo_http_wrapper::server::RequestInformation;
 
@@ -20,10 +23,9 @@ pub(super) async fn hello(
     let mut session = ses_with_store.session;
 
     let nonce3 = Nonce::new().map_err(Error::from_error::<messages::v11::to0::Hello, _>)?;
-    let nonce3_encoded = nonce3.to_string();
 
     session
-        .insert("nonce3", nonce3_encoded)
+        .insert("nonce3", nonce3.clone())
         .map_err(Error::from_error::<messages::v11::to0::Hello, _>)?;
 
     let res = messages::v11::to0::HelloAck::new(nonce3);
@@ -38,37 +40,21 @@ pub(super) async fn ownersign(
     mut ses_with_store: RequestInformation,
     msg: messages::v11::to0::OwnerSign,
 ) -> Result<(messages::v11::to0::AcceptOwner, RequestInformation), warp::Rejection> {
-    let session = ses_with_store.session;
+    let mut session = ses_with_store.session;
 
     // First check the easy things: whether the nonce in to0d is correct
-    let correct_nonce: String = match session.get("nonce3") {
-        Some(v) => v,
-        None => {
-            return Err(Error::new(
-                ErrorCode::InvalidMessageError,
-                messages::v11::to0::OwnerSign::message_type(),
-                "Request sequence failure",
-            )
-            .into())
-        }
-    };
     let to0d = msg
         .to0d()
         .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
-    let correct_nonce: Nonce = correct_nonce.parse().unwrap();
-    log::trace!(
-        "Matching correct nonce {:?} to received {:?}",
-        correct_nonce,
-        to0d.nonce()
-    );
-    if &correct_nonce != to0d.nonce() {
-        return Err(Error::new(
-            ErrorCode::InvalidMessageError,
-            messages::v11::to0::OwnerSign::message_type(),
-            "Invalid nonce3",
+    log::trace!("Matching correct nonce to received {:?}", to0d.nonce());
+    consume_session_nonce::<messages::v11::to0::OwnerSign>(&mut session, "nonce3", to0d.nonce())?;
+    // Tags the span of the message with the device
+    session
+        .insert(
+            "device_guid",
+            to0d.ownership_voucher().header().guid().to_string(),
         )
-        .into());
-    }
+        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
 
     // Now check the OV first public key: is it one we trust?
     let manufacturer_pubkey = to0d
@@ -91,59 +77,100 @@ pub(super) async fn ownersign(
         }
     }
 
-    // Now, get the final owner key
-    let ov_iter = to0d
-        .ownership_voucher()
-        .iter_entries()
+    // Now, get the final owner key and verify the signature on to1d. Verifying every OV
+    // entry is CPU heavy, so this runs on the blocking pool
+    let ownership_voucher = to0d.ownership_voucher().clone();
+    let to1d = msg.to1d().clone();
+    let to1d_payload = run_blocking::<messages::v11::to0::OwnerSign, _, _>(move || {
+        let ov_iter = ownership_voucher
+            .iter_entries()
+            .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+        let owner = match ov_iter.last() {
+            None => {
+                log::error!("No OV entries encountered");
+                return Err(Error::new(
+                    ErrorCode::InvalidOwnershipVoucher,
+                    messages::v11::to0::OwnerSign::message_type(),
+                    "Invalid OV",
+                )
+                .into());
+            }
+            Some(Err(e)) => {
+                log::error!("Invalid OV entry encountered: {:?}", e);
+                return Err(Error::new(
+                    ErrorCode::InvalidOwnershipVoucher,
+                    messages::v11::to0::OwnerSign::message_type(),
+                    "Invalid OV",
+                )
+                .into());
+            }
+            Some(Ok(owner)) => owner,
+        };
+
+        // Verify the signature on to1d
+        log::trace!(
+            "Checking whether to1d payload is signed by owner public key {:?}",
+            owner.public_key(),
+        );
+        let to1d_payload: TO1DataPayload =
+            match to1d.get_payload_with_public_key(owner.public_key()) {
+                Err(e) => {
+                    log::error!("Error verifying to1d: {:?}", e);
+                    return Err(Error::new(
+                        ErrorCode::InvalidOwnershipVoucher,
+                        messages::v11::to0::OwnerSign::message_type(),
+                        "Invalid TO1D",
+                    )
+                    .into());
+                }
+                Ok(v) => v,
+            };
+        Ok(to1d_payload)
+    })
+    .await?;
+
+    // Verify the to1d -> to0d hash
+    let to1d_to_to0d_hash = to1d_payload.to1d_to_to0d_hash();
+    let to0d_hash = msg
+        .to0d_hash(to1d_to_to0d_hash.get_type())
         .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
-    let owner = match ov_iter.last() {
-        None => {
-            log::error!("No OV entries encountered");
-            return Err(Error::new(
-                ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
-                "Invalid OV",
-            )
-            .into());
-        }
-        Some(Err(e)) => {
-            log::error!("Invalid OV entry encountered: {:?}", e);
+    to1d_to_to0d_hash
+        .compare(&to0d_hash)
+        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+
+    // None of the manufacturer and owner certificates may be revoked
+    if let Some(revocation) = &user_data.revocation {
+        let no_trust_anchors = X5Bag::new();
+        let trust_anchors = user_data
+            .trusted_manufacturer_keys
+            .as_ref()
+            .unwrap_or(&no_trust_anchors);
+        if let Err(e) = revocation
+            .check_voucher(to0d.ownership_voucher(), trust_anchors)
+            .await
+        {
+            log::warn!("Ownership voucher rejected: {:?}", e);
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
                 messages::v11::to0::OwnerSign::message_type(),
-                "Invalid OV",
+                "Ownership voucher certificate revoked",
             )
             .into());
         }
-        Some(Ok(owner)) => owner,
-    };
+    }
 
-    // Verify the signature on to1d
-    log::trace!(
-        "Checking whether to1d payload is signed by owner public key {:?}",
-        owner.public_key(),
-    );
-    let to1d_payload: TO1DataPayload = match msg.to1d().get_payload(owner.public_key().pkey()) {
-        Err(e) => {
-            log::error!("Error verifying to1d: {:?}", e);
+    // The voucher may only use the algorithms allowed by the crypto policy
+    if let Some(crypto_policy) = &user_data.crypto_policy {
+        if let Err(e) = crypto_policy.check_voucher(to0d.ownership_voucher()) {
+            log::warn!("Ownership voucher rejected: {:?}", e);
             return Err(Error::new(
                 ErrorCode::InvalidOwnershipVoucher,
                 messages::v11::to0::OwnerSign::message_type(),
-                "Invalid TO1D",
+                "Ownership voucher does not comply with the crypto policy",
             )
             .into());
         }
-        Ok(v) => v,
-    };
-
-    // Verify the to1d -> to0d hash
-    let to1d_to_to0d_hash = to1d_payload.to1d_to_to0d_hash();
-    let to0d_hash = msg
-        .to0d_hash(to1d_to_to0d_hash.get_type())
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
-    to1d_to_to0d_hash
-        .compare(&to0d_hash)
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+    }
 
     // Okay, wow! We can now trust the to1d payload, and the other data!
     // First, verify the device certificate chain
@@ -159,21 +186,51 @@ pub(super) async fn ownersign(
         Some(v) => v,
     };
     //let device_pubkey = match device_cert_chain.verify_from_x5bag(&user_data.trusted_device_keys) {
-    let device_pubkey = match device_cert_chain.insecure_verify_without_root_verification() {
-        Err(cert_chain_err) => {
-            log::debug!("Error verifying device certificate: {:?}", cert_chain_err);
-            return Err(Error::new(
-                ErrorCode::InvalidOwnershipVoucher,
-                messages::v11::to0::OwnerSign::message_type(),
-                "Device certificate not trusted",
-            )
-            .into());
+    let device_cert = match &user_data.ondie {
+        // OnDie chains are fully verified, including revocation
+        Some(ondie) if ondie.is_ondie_chain(device_cert_chain) => {
+            ondie.verify(device_cert_chain).map_err(|e| {
+                log::debug!("Error verifying OnDie device certificate: {:?}", e);
+                Error::new(
+                    ErrorCode::InvalidOwnershipVoucher,
+                    messages::v11::to0::OwnerSign::message_type(),
+                    "Device certificate not trusted",
+                )
+            })?
         }
-        Ok(v) => v
-            .clone()
-            .try_into()
-            .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?,
+        _ => device_cert_chain
+            .insecure_verify_without_root_verification()
+            .map_err(|cert_chain_err| {
+                log::debug!("Error verifying device certificate: {:?}", cert_chain_err);
+                Error::new(
+                    ErrorCode::InvalidOwnershipVoucher,
+                    messages::v11::to0::OwnerSign::message_type(),
+                    "Device certificate not trusted",
+                )
+            })?,
     };
+    if let Some(revocation) = &user_data.revocation {
+        let is_ondie =
+            matches!(&user_data.ondie, Some(ondie) if ondie.is_ondie_chain(device_cert_chain));
+        if !is_ondie {
+            if let Err(e) = revocation
+                .check_chain(device_cert_chain, &X5Bag::new())
+                .await
+            {
+                log::warn!("Device certificate chain rejected: {:?}", e);
+                return Err(Error::new(
+                    ErrorCode::InvalidOwnershipVoucher,
+                    messages::v11::to0::OwnerSign::message_type(),
+                    "Device certificate revoked",
+                )
+                .into());
+            }
+        }
+    }
+    let device_pubkey = device_cert
+        .clone()
+        .try_into()
+        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
 
     // Now compute the new wait_seconds and stuff to store
     let mut wait_seconds = to0d.wait_seconds();
@@ -202,11 +259,21 @@ pub(super) async fn ownersign(
         .await
         .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
 
-    user_data
+    if let Err(e) = user_data
         .store
         .store_metadata(&device_guid, &fdo_store::MetadataKey::Ttl, &ttl)
         .await
-        .map_err(Error::from_error::<messages::v11::to0::OwnerSign, _>)?;
+    {
+        // Without a TTL the registration would never expire
+        if let Err(destroy_err) = user_data.store.destroy_data(&device_guid).await {
+            log::warn!(
+                "Error removing TO1D for device with GUID {:?} without TTL: {:?}",
+                device_guid,
+                destroy_err
+            );
+        }
+        return Err(Error::from_error::<messages::v11::to0::OwnerSign, _>(e));
+    }
 
     ses_with_store.session = session;
     Ok((
@@ -4,6 +4,8 @@ use fdo_data_formats::{
     types::{Nonce, SigInfo},
 };
 
+use fdo_http_wrapper::server::consume_session_nonce;
+use fdo_http_wrapper::server::run_blocking;
 use fdo_http_wrapper::server::Error;
 use fdo_http_wrapper::server::RequestInformation;
 
@@ -57,10 +59,9 @@ pub(super) async fn hello_rv(
 
     // Create new nonce
     let nonce4 = Nonce::new().map_err(Error::from_error::<messages::v11::to1::HelloRV, _>)?;
-    let nonce4_encoded = nonce4.to_string();
 
     session
-        .insert("nonce4", nonce4_encoded)
+        .insert("nonce4", nonce4.clone())
         .map_err(Error::from_error::<messages::v11::to1::HelloRV, _>)?;
     session
         .insert("device_guid", msg.guid().to_string())
@@ -81,20 +82,7 @@ pub(super) async fn prove_to_rv(
     mut ses_with_store: RequestInformation,
     msg: messages::v11::to1::ProveToRV,
 ) -> Result<(messages::v11::to1::RVRedirect, RequestInformation), warp::Rejection> {
-    let session = ses_with_store.session;
-
-    let nonce4: String = match session.get("nonce4") {
-        Some(v) => v,
-        None => {
-            return Err(Error::new(
-                ErrorCode::InvalidMessageError,
-                messages::v11::to0::OwnerSign::message_type(),
-                "Request sequence failure",
-            )
-            .into())
-        }
-    };
-    let nonce4: Nonce = nonce4.parse().unwrap();
+    let mut session = ses_with_store.session;
 
     let device_guid: String = match session.get("device_guid") {
         Some(v) => v,
@@ -131,26 +119,36 @@ pub(super) async fn prove_to_rv(
     };
 
     // Check if token is signed
-    let device_eat = msg.token().get_eat(dev_pkey.pkey()).map_err(|e| {
-        log::debug!("Error parsing EAToken: {:?}", e);
-        Error::new(
-            ErrorCode::InvalidMessageError,
-            messages::v11::to1::ProveToRV::message_type(),
-            "Token invalid",
-        )
-    })?;
-
-    let signed_nonce: &Nonce = device_eat.nonce();
-
-    if &nonce4 != signed_nonce {
+    let token = msg.token().clone();
+    let device_eat = run_blocking::<messages::v11::to1::ProveToRV, _, _>(move || {
+        Ok(token.get_eat(dev_pkey.pkey()).map_err(|e| {
+            log::debug!("Error parsing EAToken: {:?}", e);
+            Error::new(
+                ErrorCode::InvalidMessageError,
+                messages::v11::to1::ProveToRV::message_type(),
+                "Token invalid",
+            )
+        })?)
+    })
+    .await?;
+
+    if &device_eat.device_guid() != device_guid {
+        log::debug!(
+            "EAToken UEID {:?} does not match device {:?}",
+            device_eat.device_guid(),
+            device_guid
+        );
         return Err(Error::new(
             ErrorCode::InvalidMessageError,
             messages::v11::to1::ProveToRV::message_type(),
-            "Nonce invalid",
+            "Token invalid",
         )
         .into());
     }
 
+    let signed_nonce: &Nonce = device_eat.nonce();
+    consume_session_nonce::<messages::v11::to1::ProveToRV>(&mut session, "nonce4", signed_nonce)?;
+
     // Okay, device is trusted! Now return their owner information
     let rv_redirect = messages::v11::to1::RVRedirect::new(to1d);
 
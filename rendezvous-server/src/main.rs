@@ -1,3 +1,4 @@
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
@@ -13,7 +14,12 @@ use fdo_data_formats::{
     ProtocolVersion, Serializable,
 };
 use fdo_store::Store;
-use fdo_util::servers::{configuration::rendezvous_server::RendezvousServerSettings, settings_for};
+use fdo_util::crypto_policy::CryptoPolicy;
+use fdo_util::ondie::OnDieCache;
+use fdo_util::revocation::RevocationChecker;
+use fdo_util::servers::{
+    configuration::rendezvous_server::RendezvousServerSettings, load_settings,
+};
 
 mod handlers_to0;
 mod handlers_to1;
@@ -65,7 +71,12 @@ impl fdo_store::MetadataLocalKey for RendezvousStoreMetadataKey {
 struct RendezvousUD {
     max_wait_seconds: u32,
     trusted_manufacturer_keys: Option<X5Bag>,
+    ondie: Option<OnDieCache>,
+    revocation: Option<RevocationChecker>,
+    crypto_policy: Option<CryptoPolicy>,
     store: Box<dyn Store<fdo_store::ReadWriteOpen, Guid, StoredItem, RendezvousStoreMetadataKey>>,
+    // Number of owner registrations dropped by maintenance after their TTL passed
+    expired_registrations: AtomicU64,
 
     session_store: Arc<fdo_http_wrapper::server::SessionStore>,
 }
@@ -87,8 +98,20 @@ async fn perform_maintenance(udt: RendezvousUDT) {
         let ses_maint = udt.session_store.perform_maintenance();
 
         let (store_res, ses_res) = tokio::join!(store_maint, ses_maint);
-        if let Err(e) = store_res {
-            log::warn!("Error during store maintenance: {:?}", e);
+        match store_res {
+            Err(e) => log::warn!("Error during store maintenance: {:?}", e),
+            Ok(0) => {}
+            Ok(removed) => {
+                let total = udt
+                    .expired_registrations
+                    .fetch_add(removed as u64, Ordering::Relaxed)
+                    + removed as u64;
+                log::info!(
+                    "Removed {} expired owner registrations ({} since startup)",
+                    removed,
+                    total
+                );
+            }
         }
         if let Err(e) = ses_res {
             log::warn!("Error during session store maintenance: {:?}", e);
@@ -103,9 +126,7 @@ async fn main() -> Result<()> {
     fdo_util::add_version!();
     fdo_http_wrapper::init_logging();
 
-    let settings: RendezvousServerSettings = settings_for("rendezvous-server")?
-        .try_deserialize()
-        .context("Error parsing configuration")?;
+    let settings: RendezvousServerSettings = load_settings("rendezvous-server")?;
 
     let max_wait_seconds = settings
         .max_wait_seconds
@@ -113,6 +134,8 @@ async fn main() -> Result<()> {
 
     // Bind information
     let bind_addr = settings.bind.clone();
+    let drain_timeout = std::time::Duration::from_secs(settings.drain_timeout_seconds);
+    let max_concurrent_requests = settings.max_concurrent_requests;
 
     // Initialize stores
     let store = settings
@@ -123,7 +146,14 @@ async fn main() -> Result<()> {
         .session_store_driver
         .initialize()
         .context("Error initializing session store")?;
-    let session_store = fdo_http_wrapper::server::SessionStore::new(session_store);
+    let session_store_encryption_key = settings
+        .session_store_encryption_key_path
+        .as_ref()
+        .map(|path| fdo_http_wrapper::server::SessionEncryptionKey::load(path.as_ref()))
+        .transpose()
+        .context("Error loading session store encryption key")?;
+    let session_store =
+        fdo_http_wrapper::server::SessionStore::new(session_store, session_store_encryption_key);
 
     // Load X509 certs
     let trusted_manufacturer_keys = settings
@@ -142,11 +172,37 @@ async fn main() -> Result<()> {
         .transpose()
         .context("Error loading trusted manufacturer keys")?;
 
+    let ondie = match &settings.ondie {
+        Some(ondie) => Some(
+            OnDieCache::from_settings(ondie)
+                .await
+                .context("Error loading OnDie cache")?,
+        ),
+        None => None,
+    };
+
+    let revocation = settings
+        .revocation
+        .as_ref()
+        .map(RevocationChecker::from_settings)
+        .transpose()
+        .context("Error setting up revocation checking")?;
+    let crypto_policy = settings
+        .crypto_policy
+        .as_ref()
+        .map(CryptoPolicy::from_settings)
+        .transpose()
+        .context("Error loading crypto policy")?;
+
     // Initialize handler stores
     let user_data = Arc::new(RendezvousUD {
         max_wait_seconds,
         store,
         trusted_manufacturer_keys,
+        ondie,
+        revocation,
+        crypto_policy,
+        expired_registrations: AtomicU64::new(0),
 
         session_store: session_store.clone(),
     });
@@ -156,34 +212,33 @@ async fn main() -> Result<()> {
     let handler_ping = fdo_http_wrapper::server::ping_handler();
 
     // TO0
-    let handler_to0_hello = fdo_http_wrapper::server::fdo_request_filter(
-        ProtocolVersion::Version1_1,
+    let handler_to0_hello = fdo_http_wrapper::server::fdo_versioned_request_filter(
         user_data.clone(),
         session_store.clone(),
         handlers_to0::hello,
     );
-    let handler_to0_ownersign = fdo_http_wrapper::server::fdo_request_filter(
-        ProtocolVersion::Version1_1,
+    let handler_to0_ownersign = fdo_http_wrapper::server::fdo_versioned_request_filter(
         user_data.clone(),
         session_store.clone(),
         handlers_to0::ownersign,
     );
 
     // TO1
-    let handler_to1_hello_rv = fdo_http_wrapper::server::fdo_request_filter(
-        ProtocolVersion::Version1_1,
+    let handler_to1_hello_rv = fdo_http_wrapper::server::fdo_versioned_request_filter(
         user_data.clone(),
         session_store.clone(),
         handlers_to1::hello_rv,
     );
-    let handler_to1_prove_to_rv = fdo_http_wrapper::server::fdo_request_filter(
-        ProtocolVersion::Version1_1,
+    let handler_to1_prove_to_rv = fdo_http_wrapper::server::fdo_versioned_request_filter(
         user_data.clone(),
         session_store.clone(),
         handlers_to1::prove_to_rv,
     );
 
     let routes = warp::post()
+        .and(fdo_http_wrapper::server::request_concurrency_limit(
+            max_concurrent_requests,
+        ))
         .and(
             hello
                 .or(handler_ping)
@@ -194,21 +249,27 @@ async fn main() -> Result<()> {
                 .or(handler_to1_hello_rv)
                 .or(handler_to1_prove_to_rv),
         )
+        .map(|_slot, reply| reply)
         .recover(fdo_http_wrapper::server::handle_rejection)
         .with(warp::log("rendezvous-server"));
 
     log::info!("Listening on {}", bind_addr);
-    let server = warp::serve(routes);
+    // On SIGTERM, let the sessions in progress finish before no longer accepting connections
+    let draining_session_store = user_data.session_store.clone();
+    let server = fdo_http_wrapper::server::serve_with_graceful_shutdown(
+        routes,
+        bind_addr.into(),
+        async move {
+            signal(SignalKind::terminate()).unwrap().recv().await;
+            log::info!("Terminating, draining sessions in progress");
+            draining_session_store.drain(drain_timeout).await;
+        },
+    )
+    .context("Error binding server")?;
 
     let maintenance_runner =
         tokio::spawn(async move { perform_maintenance(user_data.clone()).await });
 
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
-            signal(SignalKind::terminate()).unwrap().recv().await;
-            log::info!("Terminating");
-        })
-        .1;
     let server = tokio::spawn(server);
 
     tokio::select!(
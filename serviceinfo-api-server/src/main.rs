@@ -1,18 +1,34 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use fdo_data_formats::{
-    constants::{FedoraIotServiceInfoModule, HashType, ServiceInfoModule},
+    constants::{
+        FedoraIotServiceInfoModule, HashType, ServiceInfoModule, StandardServiceInfoModule,
+    },
     types::{Guid, Hash},
 };
+use fdo_http_wrapper::telemetry::MessageSpan;
 use fdo_store::Store;
 use fdo_util::servers::{
-    configuration::serviceinfo_api_server::{ServiceInfoApiServerSettings, ServiceInfoSettings},
-    settings_for, settings_per_device, ServiceInfoApiReply, ServiceInfoApiReplyInitialUser,
+    authorization::AccessControl,
+    configuration::{
+        serviceinfo_api_server::{
+            ServiceInfoApiServerSettings, ServiceInfoApiServerUnixSocket, ServiceInfoFile,
+            ServiceInfoSettings,
+        },
+        Permission,
+    },
+    load_settings, settings_per_device, ServiceInfoApiReply, ServiceInfoApiReplyInitialUser,
     ServiceInfoApiReplyReboot,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, str::FromStr};
+use std::{borrow::Cow, collections::HashSet, str::FromStr};
+use tokio::net::UnixListener;
 use tokio::signal::unix::{signal, SignalKind};
-use warp::Filter;
+use tokio::sync::watch;
+use warp::{
+    http::{HeaderMap, Method},
+    path::FullPath,
+    Filter,
+};
 
 #[derive(Debug)]
 struct ServiceInfoFailure(anyhow::Error);
@@ -51,6 +67,9 @@ impl ServiceInfoConfiguration {
                         .with_context(|| format!("Failed to hash file {}", file.source_path))?
                         .value_bytes(),
                 );
+                if file.template && std::str::from_utf8(&contents).is_err() {
+                    bail!("Template file {} is not valid UTF-8", file.source_path);
+                }
                 file.contents_len = contents.len();
                 file.contents_hex = hex::encode(&contents);
 
@@ -86,7 +105,7 @@ struct ServiceInfoApiServerUD {
 
     // Auth Info
     service_info_auth_token: Option<String>,
-    admin_auth_token: Option<String>,
+    admin_access_control: Option<AccessControl>,
 
     // Basic Service Info configuration
     service_info_configuration: ServiceInfoConfiguration,
@@ -130,22 +149,31 @@ impl ServiceInfoApiReplyBuilder {
 
 async fn admin_auth_handler(
     user_data: ServiceInfoApiServerUDT,
-    auth_header: String,
+    method: Method,
+    path: FullPath,
+    headers: HeaderMap,
 ) -> Result<ServiceInfoApiServerUDT, warp::Rejection> {
-    match &user_data.admin_auth_token {
+    let access_control = match &user_data.admin_access_control {
         None => {
             log::warn!("Admin API server disabled");
             return Err(warp::reject::reject());
         }
-        Some(token) => {
-            if token != &auth_header {
-                log::warn!("Request with invalid auth token");
-                return Err(warp::reject::reject());
-            }
-        }
+        Some(access_control) => access_control,
+    };
+
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let client_certificate_subject = access_control
+        .client_certificate_subject_header()
+        .and_then(header);
+    match access_control.authorize(
+        header("Authorization"),
+        client_certificate_subject,
+        Permission::WriteServiceInfo,
+        &format!("{} {}", method, path.as_str()),
+    ) {
+        Some(_) => Ok(user_data),
+        None => Err(warp::reject::reject()),
     }
-
-    Ok(user_data)
 }
 
 #[derive(Debug, Deserialize)]
@@ -203,9 +231,26 @@ async fn serviceinfo_auth_handler(
     Ok(user_data)
 }
 
-async fn serviceinfo_handler(
+// Continues the trace of the TO2 message the Owner Onboarding Server is handling
+async fn traced_serviceinfo_handler(
     user_data: ServiceInfoApiServerUDT,
     query_info: QueryInfo,
+    headers: HeaderMap,
+) -> Result<warp::reply::Json, warp::Rejection> {
+    let span = MessageSpan::server("ServiceInfo API request".to_string(), &headers);
+    span.set_attribute("fdo.device_guid", query_info.device_guid.to_string());
+    let reply = span
+        .in_span(serviceinfo_handler(user_data, query_info))
+        .await;
+    if let Err(rejection) = &reply {
+        span.set_error(format!("{rejection:?}"));
+    }
+    reply
+}
+
+async fn serviceinfo_handler(
+    user_data: ServiceInfoApiServerUDT,
+    mut query_info: QueryInfo,
 ) -> Result<warp::reply::Json, warp::Rejection> {
     if query_info.api_version != 1 {
         log::warn!(
@@ -221,46 +266,93 @@ async fn serviceinfo_handler(
         query_info.modules
     );
 
+    if let Some(disabled_modules) = &user_data
+        .service_info_configuration
+        .settings
+        .disabled_modules
+    {
+        query_info
+            .modules
+            .retain(|module| !disabled_modules.contains(module));
+    }
+
+    // precedence is given to 'per_device' settings over base serviceinfo_api_server.yml config
+    let device_configuration = device_configuration(&query_info)
+        .map_err(|e| warp::reject::custom(ServiceInfoFailure(e)))?;
+    let settings = &device_configuration
+        .as_ref()
+        .unwrap_or(&user_data.service_info_configuration)
+        .settings;
+    let template_vars = TemplateVars {
+        guid: query_info.device_guid.to_string().replace('\"', ""),
+        device_info: query_info.device_info.clone().unwrap_or_default(),
+    };
+
     let mut reply: ServiceInfoApiReplyBuilder = Default::default();
 
     if query_info
         .modules
         .contains(&FedoraIotServiceInfoModule::SSHKey.into())
     {
-        // precedence is given to 'per_device' settings over base serviceinfo_api_server.yml config
-        match settings_per_device(&query_info.device_guid.to_string().replace('\"', "")) {
-            Ok(config) => {
-                let per_device_settings = config;
-                if let Some(initial_user) = &per_device_settings.initial_user {
-                    reply.reply.initial_user = Some(ServiceInfoApiReplyInitialUser {
-                        username: initial_user.username.clone(),
-                        password: initial_user.password.clone(),
-                        ssh_keys: initial_user.sshkeys.clone(),
-                    });
-                }
-            }
-            Err(_) => {
-                log::info!("per-device settings file not available, so loading base config file");
-                if let Some(initial_user) =
-                    &user_data.service_info_configuration.settings.initial_user
-                {
-                    log::debug!("serviceinfo setting from base file applied");
-                    reply.reply.initial_user = Some(ServiceInfoApiReplyInitialUser {
-                        username: initial_user.username.clone(),
-                        password: initial_user.password.clone(),
-                        ssh_keys: initial_user.sshkeys.clone(),
-                    });
-                }
-            }
-        };
+        if let Some(initial_user) = &settings.initial_user {
+            reply.reply.initial_user = Some(ServiceInfoApiReplyInitialUser {
+                username: initial_user.username.clone(),
+                password: initial_user.password.clone(),
+                ssh_keys: initial_user.sshkeys.clone(),
+            });
+        }
     }
 
     if query_info
+        .modules
+        .contains(&StandardServiceInfoModule::Download.into())
+    {
+        if let Some(files) = &settings.files {
+            for file in files {
+                let file = template_vars
+                    .render_file(file)
+                    .map_err(|e| warp::reject::custom(ServiceInfoFailure(e)))?;
+                reply.add_extra(StandardServiceInfoModule::Download, "name", &file.path);
+                reply.add_extra(
+                    StandardServiceInfoModule::Download,
+                    "length",
+                    &file.contents_len,
+                );
+                if let Some(parsed_permissions) = &file.parsed_permissions {
+                    reply.add_extra(
+                        StandardServiceInfoModule::Download,
+                        "mode",
+                        &parsed_permissions,
+                    );
+                }
+                if let Some(owner) = &file.owner {
+                    reply.add_extra(StandardServiceInfoModule::Download, "owner", owner);
+                }
+                if let Some(group) = &file.group {
+                    reply.add_extra(StandardServiceInfoModule::Download, "group", group);
+                }
+                reply.add_extra(
+                    StandardServiceInfoModule::Download,
+                    "sha-384|hex",
+                    &file.hash_hex,
+                );
+                // The Owner Onboarding Server splits this into chunks that fit the device MTU
+                reply.add_extra(
+                    StandardServiceInfoModule::Download,
+                    "data|hex",
+                    &file.contents_hex,
+                );
+            }
+        }
+    } else if query_info
         .modules
         .contains(&FedoraIotServiceInfoModule::BinaryFile.into())
     {
-        if let Some(files) = &user_data.service_info_configuration.settings.files {
+        if let Some(files) = &settings.files {
             for file in files {
+                let file = template_vars
+                    .render_file(file)
+                    .map_err(|e| warp::reject::custom(ServiceInfoFailure(e)))?;
                 reply.add_extra(FedoraIotServiceInfoModule::BinaryFile, "name", &file.path);
                 reply.add_extra(
                     FedoraIotServiceInfoModule::BinaryFile,
@@ -288,34 +380,60 @@ async fn serviceinfo_handler(
         }
     }
 
-    if query_info
+    // Prefer the standard module, older clients only support ours
+    let command_module: Option<ServiceInfoModule> = if query_info
+        .modules
+        .contains(&StandardServiceInfoModule::Command.into())
+    {
+        Some(StandardServiceInfoModule::Command.into())
+    } else if query_info
         .modules
         .contains(&FedoraIotServiceInfoModule::Command.into())
     {
-        if let Some(commands) = &user_data.service_info_configuration.settings.commands {
+        Some(FedoraIotServiceInfoModule::Command.into())
+    } else {
+        None
+    };
+    if let Some(command_module) = command_module {
+        if let Some(commands) = &settings.commands {
             for command in commands {
                 reply.add_extra(
-                    FedoraIotServiceInfoModule::Command,
+                    command_module.clone(),
                     "command",
-                    &command.command,
-                );
-                reply.add_extra(FedoraIotServiceInfoModule::Command, "args", &command.args);
-                reply.add_extra(
-                    FedoraIotServiceInfoModule::Command,
-                    "may_fail",
-                    &command.may_fail,
+                    &template_vars.render(&command.command),
                 );
+                let args: Vec<String> = command
+                    .args
+                    .iter()
+                    .map(|arg| template_vars.render(arg))
+                    .collect();
+                reply.add_extra(command_module.clone(), "args", &args);
+                if let Some(env) = &command.env {
+                    let env: Vec<String> = env
+                        .iter()
+                        .map(|(key, value)| format!("{key}={}", template_vars.render(value)))
+                        .collect();
+                    reply.add_extra(command_module.clone(), "env", &env);
+                }
+                if let Some(stdin) = &command.stdin {
+                    reply.add_extra(
+                        command_module.clone(),
+                        "stdin|hex",
+                        &hex::encode(template_vars.render(stdin)),
+                    );
+                }
+                reply.add_extra(command_module.clone(), "may_fail", &command.may_fail);
                 reply.add_extra(
-                    FedoraIotServiceInfoModule::Command,
+                    command_module.clone(),
                     "return_stdout",
                     &command.return_stdout,
                 );
                 reply.add_extra(
-                    FedoraIotServiceInfoModule::Command,
+                    command_module.clone(),
                     "return_stderr",
                     &command.return_stderr,
                 );
-                reply.add_extra(FedoraIotServiceInfoModule::Command, "execute", &true);
+                reply.add_extra(command_module.clone(), "execute", &true);
             }
         }
     }
@@ -324,11 +442,7 @@ async fn serviceinfo_handler(
         .modules
         .contains(&FedoraIotServiceInfoModule::DiskEncryptionClevis.into())
     {
-        if let Some(disk_encryptions) = &user_data
-            .service_info_configuration
-            .settings
-            .diskencryption_clevis
-        {
+        if let Some(disk_encryptions) = &settings.diskencryption_clevis {
             for encryption in disk_encryptions {
                 reply.add_extra(
                     FedoraIotServiceInfoModule::DiskEncryptionClevis,
@@ -363,30 +477,39 @@ async fn serviceinfo_handler(
         .modules
         .contains(&FedoraIotServiceInfoModule::Reboot.into())
     {
-        if let Some(reboot) = &user_data
-            .service_info_configuration
-            .settings
-            .after_onboarding_reboot
-        {
+        if let Some(reboot) = &settings.after_onboarding_reboot {
             reply.reply.reboot = Some(ServiceInfoApiReplyReboot {
                 reboot: reboot.to_owned(),
             })
         }
     }
 
-    if let Some(additional_serviceinfo) = &user_data
-        .service_info_configuration
-        .settings
-        .additional_serviceinfo
-    {
+    if let Some(additional_serviceinfo) = &settings.additional_serviceinfo {
         for (module, serviceinfo_lines) in additional_serviceinfo {
             if query_info.modules.contains(module) {
                 for (key, value) in serviceinfo_lines {
-                    reply.add_extra(module.clone(), key, value);
+                    reply.add_extra(module.clone(), key, &template_vars.render(value));
                 }
             }
         }
     }
+
+    // ServiceInfo added for this device through the admin API
+    match user_data
+        .device_specific_store
+        .load_data(&query_info.device_guid)
+        .await
+    {
+        Ok(Some(device_serviceinfo)) => {
+            for (module, key, value) in device_serviceinfo {
+                if query_info.modules.contains(&module) {
+                    reply.add_extra(module, &key, &value);
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Error loading device specific ServiceInfo: {:?}", e),
+    }
     Ok(warp::reply::json(&reply.reply))
 }
 
@@ -418,6 +541,148 @@ struct QueryInfo {
     device_guid: fdo_data_formats::types::Guid,
     #[serde(deserialize_with = "deserialize_from_comma_separated_strings")]
     modules: HashSet<ServiceInfoModule>,
+    #[serde(default)]
+    device_info: Option<String>,
+}
+
+/// Loads the per-device settings, keyed by GUID or else by device info, if there are any
+fn device_configuration(query_info: &QueryInfo) -> Result<Option<ServiceInfoConfiguration>> {
+    let mut keys = vec![query_info.device_guid.to_string().replace('\"', "")];
+    if let Some(device_info) = &query_info.device_info {
+        // The device info is used as a file name, so don't allow escaping the directory
+        if !device_info.is_empty() && !device_info.starts_with('.') && !device_info.contains('/') {
+            keys.push(device_info.clone());
+        }
+    }
+
+    for key in keys {
+        match settings_per_device(&key) {
+            Ok(settings) => {
+                log::debug!("Using per-device settings {}", key);
+                return ServiceInfoConfiguration::from_settings(settings)
+                    .map(Some)
+                    .with_context(|| format!("Error preparing per-device settings {key}"));
+            }
+            Err(e) => log::trace!("No per-device settings for {}: {:?}", key, e),
+        }
+    }
+    log::info!("per-device settings file not available, so loading base config file");
+    Ok(None)
+}
+
+/// Values substituted for `{{guid}}` and `{{device_info}}` in the ServiceInfo
+struct TemplateVars {
+    guid: String,
+    device_info: String,
+}
+
+impl TemplateVars {
+    fn render(&self, template: &str) -> String {
+        template
+            .replace("{{guid}}", &self.guid)
+            .replace("{{device_info}}", &self.device_info)
+    }
+
+    fn render_file<'a>(&self, file: &'a ServiceInfoFile) -> Result<Cow<'a, ServiceInfoFile>> {
+        if !file.template {
+            return Ok(Cow::Borrowed(file));
+        }
+        let contents = String::from_utf8(hex::decode(&file.contents_hex)?)
+            .with_context(|| format!("Template file {} is not valid UTF-8", file.source_path))?;
+        let contents = self.render(&contents);
+
+        let mut file = file.clone();
+        file.hash_hex = hex::encode(
+            Hash::from_data(HashType::Sha384, contents.as_bytes())
+                .with_context(|| format!("Failed to hash file {}", file.source_path))?
+                .value_bytes(),
+        );
+        file.contents_len = contents.len();
+        file.contents_hex = hex::encode(&contents);
+        Ok(Cow::Owned(file))
+    }
+}
+
+fn bind_unix_socket(unix_socket: &ServiceInfoApiServerUnixSocket) -> Result<UnixListener> {
+    if unix_socket.allowed_uids.is_empty() && unix_socket.allowed_gids.is_empty() {
+        bail!("The Unix socket needs at least one allowed UID or GID");
+    }
+    // Remove the socket left behind by a previous run
+    match std::fs::remove_file(&unix_socket.path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e)
+                .with_context(|| format!("Error removing stale socket {}", unix_socket.path))
+        }
+        _ => {}
+    }
+    UnixListener::bind(&unix_socket.path)
+        .with_context(|| format!("Error binding to Unix socket {}", unix_socket.path))
+}
+
+/// Serves the routes on the Unix socket, to the peers whose credentials are allowed.
+async fn serve_unix_socket<F>(
+    listener: UnixListener,
+    unix_socket: ServiceInfoApiServerUnixSocket,
+    routes: F,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let service = warp::service(routes);
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::warn!("Error accepting Unix socket connection: {:?}", e);
+                    continue;
+                }
+            },
+        };
+        let peer = match stream.peer_cred() {
+            Ok(peer) => peer,
+            Err(e) => {
+                log::warn!("Error getting Unix socket peer credentials: {:?}", e);
+                continue;
+            }
+        };
+        if !unix_socket.allowed_uids.contains(&peer.uid())
+            && !unix_socket.allowed_gids.contains(&peer.gid())
+        {
+            log::warn!(
+                "Refusing Unix socket connection from UID {} GID {} (PID {:?})",
+                peer.uid(),
+                peer.gid(),
+                peer.pid()
+            );
+            continue;
+        }
+        log::trace!(
+            "Accepted Unix socket connection from UID {} GID {}",
+            peer.uid(),
+            peer.gid()
+        );
+
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = warp::hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+            {
+                log::debug!("Error serving Unix socket connection: {:?}", e);
+            }
+        });
+    }
+
+    if let Err(e) = std::fs::remove_file(&unix_socket.path) {
+        log::debug!("Error removing Unix socket {}: {:?}", unix_socket.path, e);
+    }
+}
+
+async fn shutdown_signal(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.changed().await;
 }
 
 #[tokio::main]
@@ -425,12 +690,20 @@ async fn main() -> Result<()> {
     fdo_util::add_version!();
     fdo_http_wrapper::init_logging();
 
-    let settings: ServiceInfoApiServerSettings = settings_for("serviceinfo-api-server")?
-        .try_deserialize()
-        .context("Error parsing configuration")?;
+    let settings: ServiceInfoApiServerSettings = load_settings("serviceinfo-api-server")?;
 
     // Bind information
-    let bind_addr = settings.bind.clone();
+    if settings.bind.is_none() && settings.unix_socket.is_none() {
+        bail!("Either bind or unix_socket needs to be configured");
+    }
+    if settings.bind.is_none() && settings.tls.is_some() {
+        bail!("TLS is only supported on the bind address");
+    }
+    let unix_listener = settings
+        .unix_socket
+        .as_ref()
+        .map(bind_unix_socket)
+        .transpose()?;
 
     // ServiceInfo settings
     let service_info_configuration = ServiceInfoConfiguration::from_settings(settings.service_info)
@@ -441,6 +714,12 @@ async fn main() -> Result<()> {
         .initialize()
         .context("Error initializing device-specific store")?;
 
+    let admin_access_control = AccessControl::from_token_or_settings(
+        settings.admin_auth_token,
+        settings.admin_access_control,
+    )
+    .context("Error setting up admin API access control")?;
+
     let user_data = std::sync::Arc::new(ServiceInfoApiServerUD {
         service_info_configuration,
 
@@ -449,7 +728,7 @@ async fn main() -> Result<()> {
         service_info_auth_token: settings
             .service_info_auth_token
             .map(|s| format!("Bearer {s}")),
-        admin_auth_token: settings.admin_auth_token.map(|s| format!("Bearer {s}")),
+        admin_access_control,
     });
     let ud_si = user_data.clone();
     let ud_admin = user_data.clone();
@@ -459,13 +738,16 @@ async fn main() -> Result<()> {
         .and(warp::header::header("Authorization"))
         .and_then(serviceinfo_auth_handler)
         .and(warp::query::query::<QueryInfo>())
-        .and_then(serviceinfo_handler);
+        .and(warp::header::headers_cloned())
+        .and_then(traced_serviceinfo_handler);
 
     let admin_v0 = warp::post()
         .and(warp::path("admin"))
         .and(warp::path("v0"))
         .map(move || ud_admin.clone())
-        .and(warp::header::header("Authorization"))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
         .and_then(admin_auth_handler)
         .and(warp::body::json())
         .and_then(admin_v0_handler);
@@ -478,15 +760,59 @@ async fn main() -> Result<()> {
         .or(handler_ping)
         .with(warp::log("serviceinfo-api-server"));
 
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        signal(SignalKind::terminate()).unwrap().recv().await;
+        log::info!("Terminating");
+        let _ = shutdown_sender.send(true);
+    });
+    let shutdown = shutdown_signal(shutdown_receiver.clone());
+
+    let unix_server = {
+        let routes = routes.clone();
+        let unix_socket = settings.unix_socket.clone();
+        async move {
+            if let (Some(listener), Some(unix_socket)) = (unix_listener, unix_socket) {
+                log::info!("Listening on Unix socket {}", unix_socket.path);
+                serve_unix_socket(listener, unix_socket, routes, shutdown_receiver).await;
+            }
+        }
+    };
+
+    let bind_addr = match settings.bind {
+        None => {
+            unix_server.await;
+            fdo_http_wrapper::telemetry::shutdown().await;
+            return Ok(());
+        }
+        Some(bind_addr) => bind_addr,
+    };
+
     log::info!("Listening on {}", bind_addr);
     let server = warp::serve(routes);
-    let server = server
-        .bind_with_graceful_shutdown(bind_addr, async {
-            signal(SignalKind::terminate()).unwrap().recv().await;
-            log::info!("Terminating");
-        })
-        .1;
-    tokio::join!(server);
+    match settings.tls {
+        None => {
+            let server = server.bind_with_graceful_shutdown(bind_addr, shutdown).1;
+            tokio::join!(server, unix_server);
+        }
+        Some(tls) => {
+            log::info!("Serving with TLS");
+            let server = server
+                .tls()
+                .cert_path(&tls.cert_path)
+                .key_path(&tls.key_path);
+            let server = match &tls.client_ca_path {
+                None => server,
+                Some(client_ca_path) => {
+                    log::info!("Requiring client certificates");
+                    server.client_auth_required_path(client_ca_path)
+                }
+            };
+            let server = server.bind_with_graceful_shutdown(bind_addr, shutdown).1;
+            tokio::join!(server, unix_server);
+        }
+    }
+    fdo_http_wrapper::telemetry::shutdown().await;
 
     Ok(())
 }
@@ -0,0 +1,17 @@
+CREATE TABLE fdo_store_entries (
+    store TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value BYTEA NOT NULL,
+    PRIMARY KEY (store, key)
+);
+
+CREATE TABLE fdo_store_metadata (
+    store TEXT NOT NULL,
+    key TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value BYTEA NOT NULL,
+    PRIMARY KEY (store, key, name),
+    FOREIGN KEY (store, key) REFERENCES fdo_store_entries (store, key) ON DELETE CASCADE
+);
+
+CREATE INDEX fdo_store_metadata_name ON fdo_store_metadata (store, name);
@@ -0,0 +1,11 @@
+CREATE TABLE entries (
+    key TEXT PRIMARY KEY NOT NULL,
+    value BLOB NOT NULL
+);
+
+CREATE TABLE metadata (
+    key TEXT NOT NULL REFERENCES entries(key) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    value BLOB NOT NULL,
+    PRIMARY KEY (key, name)
+);
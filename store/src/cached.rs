@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{MetadataLocalKey, MetadataValue, ReadWriteOpen, Store, StoreError};
+
+type CachedStoreWithMetrics<K, V, MKT> =
+    (Box<dyn Store<ReadWriteOpen, K, V, MKT>>, Arc<CacheMetrics>);
+
+fn default_ttl_seconds() -> u64 {
+    300
+}
+
+/// Configuration of an in-memory LRU cache in front of a store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// Maximum number of values kept in the cache
+    pub max_entries: usize,
+    /// Number of seconds after which a cached value is loaded from the store again
+    #[serde(default = "default_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+impl CacheConfig {
+    /// Wraps `inner` with a cache, returning the cached store and its metrics.
+    pub fn wrap<K, V, MKT>(
+        &self,
+        inner: Box<dyn Store<ReadWriteOpen, K, V, MKT>>,
+    ) -> CachedStoreWithMetrics<K, V, MKT>
+    where
+        K: std::string::ToString + Send + Sync + 'static,
+        V: Send + Sync + Clone + 'static,
+        MKT: MetadataLocalKey + 'static,
+    {
+        let metrics = Arc::new(CacheMetrics::default());
+        let store = CachedStore {
+            inner,
+            max_entries: self.max_entries,
+            ttl: Duration::from_secs(self.ttl_seconds),
+            lru: Mutex::new(Lru::new()),
+            metrics: metrics.clone(),
+        };
+        (Box::new(store), metrics)
+    }
+}
+
+/// Counters of cache lookups
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CacheMetrics {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+struct LruEntry<V> {
+    value: V,
+    loaded: Instant,
+    last_used: u64,
+}
+
+struct Lru<V> {
+    entries: HashMap<String, LruEntry<V>>,
+    // Maps the last use of each entry to its key, oldest first
+    usage: BTreeMap<u64, String>,
+    tick: u64,
+    // Increased on every invalidation, so that values loaded from the store before a
+    // concurrent write don't end up in the cache
+    epoch: u64,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new() -> Self {
+        Lru {
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+            tick: 0,
+            epoch: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str, ttl: Duration) -> Option<V> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.loaded.elapsed() > ttl {
+            self.remove(key);
+            return None;
+        }
+        self.usage.remove(&entry.last_used);
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.usage.insert(self.tick, key.to_string());
+        Some(entry.value.clone())
+    }
+
+    /// Inserts a value, returning the number of evicted values.
+    fn insert(&mut self, key: String, value: V, max_entries: usize) -> u64 {
+        if max_entries == 0 {
+            return 0;
+        }
+        self.remove(&key);
+
+        let mut evicted = 0;
+        while self.entries.len() >= max_entries {
+            let oldest = match self.usage.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(oldest_key) = self.usage.remove(&oldest) {
+                self.entries.remove(&oldest_key);
+                evicted += 1;
+            }
+        }
+
+        self.tick += 1;
+        self.usage.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            LruEntry {
+                value,
+                loaded: Instant::now(),
+                last_used: self.tick,
+            },
+        );
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.usage.remove(&entry.last_used);
+        }
+    }
+
+    fn invalidate(&mut self, key: &str) {
+        self.remove(key);
+        self.epoch += 1;
+    }
+
+    fn remove_expired(&mut self, ttl: Duration) {
+        let usage = &mut self.usage;
+        self.entries.retain(|_, entry| {
+            let keep = entry.loaded.elapsed() <= ttl;
+            if !keep {
+                usage.remove(&entry.last_used);
+            }
+            keep
+        });
+    }
+}
+
+fn lock_error<E: std::fmt::Display>(e: E) -> StoreError {
+    StoreError::Unspecified(format!("Store cache lock poisoned: {e}"))
+}
+
+/// Keeps recently loaded values in memory, writing through to the inner store.
+///
+/// Any write for a key drops it from the cache. Changes made to the inner store by other
+/// processes are only seen once the cached value is older than the TTL.
+struct CachedStore<K, V, MKT>
+where
+    MKT: MetadataLocalKey,
+{
+    inner: Box<dyn Store<ReadWriteOpen, K, V, MKT>>,
+    max_entries: usize,
+    ttl: Duration,
+
+    lru: Mutex<Lru<V>>,
+    metrics: Arc<CacheMetrics>,
+}
+
+impl<K, V, MKT> CachedStore<K, V, MKT>
+where
+    K: std::string::ToString,
+    V: Clone,
+    MKT: MetadataLocalKey,
+{
+    fn invalidate(&self, key: &K) -> Result<(), StoreError> {
+        self.lru
+            .lock()
+            .map_err(lock_error)?
+            .invalidate(&key.to_string());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<K, V, MKT> Store<ReadWriteOpen, K, V, MKT> for CachedStore<K, V, MKT>
+where
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError> {
+        let cache_key = key.to_string();
+        let epoch = {
+            let mut lru = self.lru.lock().map_err(lock_error)?;
+            if let Some(value) = lru.get(&cache_key, self.ttl) {
+                log::trace!("Cache hit for {}", cache_key);
+                self.metrics.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value));
+            }
+            lru.epoch
+        };
+        log::trace!("Cache miss for {}", cache_key);
+        self.metrics.misses.fetch_add(1, Ordering::Relaxed);
+
+        let value = self.inner.load_data(key).await?;
+        if let Some(value) = &value {
+            let mut lru = self.lru.lock().map_err(lock_error)?;
+            if lru.epoch == epoch {
+                let evicted = lru.insert(cache_key, value.clone(), self.max_entries);
+                self.metrics.evictions.fetch_add(evicted, Ordering::Relaxed);
+            }
+        }
+        Ok(value)
+    }
+
+    async fn load_all_data(&self) -> Result<Vec<V>, StoreError> {
+        self.inner.load_all_data().await
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError> {
+        // Metadata like the TTL can change whether the value is visible at all
+        let res = self
+            .inner
+            .store_metadata(key, metadata_key, metadata_value)
+            .await;
+        self.invalidate(key)?;
+        res
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError> {
+        let res = self.inner.destroy_metadata(key, metadata_key).await;
+        self.invalidate(key)?;
+        res
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT> {
+        self.inner.query_data().await
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError> {
+        let cache_key = key.to_string();
+        let res = self.inner.store_data(key, value).await;
+        self.lru.lock().map_err(lock_error)?.invalidate(&cache_key);
+        res
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError> {
+        let res = self.inner.destroy_data(key).await;
+        self.invalidate(key)?;
+        res
+    }
+
+    async fn perform_maintenance(&self) -> Result<usize, StoreError> {
+        self.lru
+            .lock()
+            .map_err(lock_error)?
+            .remove_expired(self.ttl);
+        self.inner.perform_maintenance().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn test_lru_get() {
+        let mut lru = Lru::new();
+        assert_eq!(lru.get("a", TTL), None);
+        assert_eq!(lru.insert("a".to_string(), 1, 2), 0);
+        assert_eq!(lru.get("a", TTL), Some(1));
+
+        // Replacing a value doesn't evict anything
+        assert_eq!(lru.insert("a".to_string(), 2, 1), 0);
+        assert_eq!(lru.get("a", TTL), Some(2));
+        assert_eq!(lru.entries.len(), 1);
+        assert_eq!(lru.usage.len(), 1);
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut lru = Lru::new();
+        assert_eq!(lru.insert("a".to_string(), 1, 2), 0);
+        assert_eq!(lru.insert("b".to_string(), 2, 2), 0);
+        // Using "a" makes "b" the least recently used
+        assert_eq!(lru.get("a", TTL), Some(1));
+        assert_eq!(lru.insert("c".to_string(), 3, 2), 1);
+
+        assert_eq!(lru.get("b", TTL), None);
+        assert_eq!(lru.get("a", TTL), Some(1));
+        assert_eq!(lru.get("c", TTL), Some(3));
+        assert_eq!(lru.usage.len(), 2);
+    }
+
+    #[test]
+    fn test_lru_disabled() {
+        let mut lru = Lru::new();
+        assert_eq!(lru.insert("a".to_string(), 1, 0), 0);
+        assert_eq!(lru.get("a", TTL), None);
+    }
+
+    #[test]
+    fn test_lru_invalidate() {
+        let mut lru = Lru::new();
+        lru.insert("a".to_string(), 1, 2);
+        lru.insert("b".to_string(), 2, 2);
+
+        lru.invalidate("a");
+        assert_eq!(lru.epoch, 1);
+        assert_eq!(lru.get("a", TTL), None);
+        assert_eq!(lru.get("b", TTL), Some(2));
+        assert_eq!(lru.usage.len(), 1);
+
+        // Invalidating a key that isn't cached still moves to a new epoch
+        lru.invalidate("c");
+        assert_eq!(lru.epoch, 2);
+    }
+
+    #[test]
+    fn test_lru_expiry() {
+        let ttl = Duration::from_millis(50);
+        let mut lru = Lru::new();
+        lru.insert("a".to_string(), 1, 3);
+        lru.insert("b".to_string(), 2, 3);
+        std::thread::sleep(ttl * 2);
+        lru.insert("c".to_string(), 3, 3);
+
+        assert_eq!(lru.get("a", ttl), None);
+        assert_eq!(lru.entries.len(), 2);
+        lru.remove_expired(ttl);
+        assert_eq!(lru.get("b", ttl), None);
+        assert_eq!(lru.get("c", ttl), Some(3));
+        assert_eq!(lru.entries.len(), 1);
+        assert_eq!(lru.usage.len(), 1);
+    }
+
+    #[cfg(feature = "memory")]
+    mod memory {
+        use super::*;
+
+        struct TestMetadataKey;
+
+        impl MetadataLocalKey for TestMetadataKey {
+            fn to_key(&self) -> &'static str {
+                "test"
+            }
+        }
+
+        type TestStore = CachedStore<String, String, TestMetadataKey>;
+
+        fn cached_memory_store(
+            max_entries: usize,
+            ttl: Duration,
+        ) -> (TestStore, Arc<CacheMetrics>) {
+            let metrics = Arc::new(CacheMetrics::default());
+            let store = CachedStore {
+                inner: crate::memory::initialize().unwrap(),
+                max_entries,
+                ttl,
+                lru: Mutex::new(Lru::new()),
+                metrics: metrics.clone(),
+            };
+            (store, metrics)
+        }
+
+        fn key(key: &str) -> String {
+            key.to_string()
+        }
+
+        #[tokio::test]
+        async fn test_cached_store_hits() {
+            let (store, metrics) = cached_memory_store(10, TTL);
+            store.store_data(key("a"), key("1")).await.unwrap();
+
+            assert_eq!(store.load_data(&key("a")).await.unwrap(), Some(key("1")));
+            assert_eq!(store.load_data(&key("a")).await.unwrap(), Some(key("1")));
+            assert_eq!(metrics.misses(), 1);
+            assert_eq!(metrics.hits(), 1);
+
+            // Missing values aren't cached
+            assert_eq!(store.load_data(&key("b")).await.unwrap(), None);
+            assert_eq!(store.load_data(&key("b")).await.unwrap(), None);
+            assert_eq!(metrics.misses(), 3);
+        }
+
+        #[tokio::test]
+        async fn test_cached_store_write_invalidates() {
+            let (store, metrics) = cached_memory_store(10, TTL);
+            store.store_data(key("a"), key("1")).await.unwrap();
+            store.load_data(&key("a")).await.unwrap();
+
+            store.store_data(key("a"), key("2")).await.unwrap();
+            assert_eq!(store.load_data(&key("a")).await.unwrap(), Some(key("2")));
+            assert_eq!(metrics.misses(), 2);
+
+            // An expired TTL hides the value in the inner store
+            store
+                .store_metadata(
+                    &key("a"),
+                    &crate::MetadataKey::Ttl,
+                    &time::Duration::seconds(-10),
+                )
+                .await
+                .unwrap();
+            assert_eq!(store.load_data(&key("a")).await.unwrap(), None);
+
+            store.store_data(key("a"), key("3")).await.unwrap();
+            store.load_data(&key("a")).await.unwrap();
+            store.destroy_data(&key("a")).await.unwrap();
+            assert_eq!(store.load_data(&key("a")).await.unwrap(), None);
+            assert_eq!(metrics.hits(), 0);
+        }
+
+        #[tokio::test]
+        async fn test_cached_store_ttl() {
+            let ttl = Duration::from_millis(50);
+            let (store, metrics) = cached_memory_store(10, ttl);
+            store.store_data(key("a"), key("1")).await.unwrap();
+            store.load_data(&key("a")).await.unwrap();
+            store.load_data(&key("a")).await.unwrap();
+            assert_eq!(metrics.hits(), 1);
+
+            tokio::time::sleep(ttl * 2).await;
+            assert_eq!(store.load_data(&key("a")).await.unwrap(), Some(key("1")));
+            assert_eq!(metrics.hits(), 1);
+            assert_eq!(metrics.misses(), 2);
+
+            tokio::time::sleep(ttl * 2).await;
+            store.perform_maintenance().await.unwrap();
+            assert!(store.lru.lock().unwrap().entries.is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_cached_store_evictions() {
+            let (store, metrics) = cached_memory_store(1, TTL);
+            store.store_data(key("a"), key("1")).await.unwrap();
+            store.store_data(key("b"), key("2")).await.unwrap();
+
+            store.load_data(&key("a")).await.unwrap();
+            store.load_data(&key("b")).await.unwrap();
+            assert_eq!(metrics.evictions(), 1);
+            assert_eq!(store.load_data(&key("a")).await.unwrap(), Some(key("1")));
+            assert_eq!(metrics.evictions(), 2);
+            assert_eq!(metrics.hits(), 0);
+        }
+    }
+}
@@ -235,6 +235,46 @@ where
         })?))
     }
 
+    async fn load_all_data(&self) -> Result<Vec<V>, StoreError> {
+        log::trace!(
+            "Attempting to load all data from {}",
+            self.directory.display()
+        );
+
+        let dir_entries = fs::read_dir(&self.directory).map_err(|e| {
+            StoreError::Unspecified(format!(
+                "Error listing directory {}: {:?}",
+                self.directory.display(),
+                e
+            ))
+        })?;
+        let mut values = Vec::new();
+        for entry in dir_entries {
+            let entry = entry
+                .map_err(|e| StoreError::Unspecified(format!("Error reading entry: {e:?}")))?;
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(v) if v.is_file() => {}
+                _ => continue,
+            }
+            let file = match File::open(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(StoreError::Unspecified(format!("Error opening file: {e}"))),
+                Ok(f) => f,
+            };
+            match file.get_xattr(format_xattr(crate::MetadataKey::<MKT>::Ttl.to_key())) {
+                Ok(Some(ttl)) if SystemTime::now() > ttl_from_disk(&ttl)? => continue,
+                Ok(_) => {}
+                Err(e) => return Err(StoreError::Unspecified(format!("Error checking TTL: {e}"))),
+            }
+            match V::deserialize_from_reader(&file) {
+                Ok(v) => values.push(v),
+                Err(e) => log::trace!("Error deserializing data {:?}: {}", path, e),
+            }
+        }
+        Ok(values)
+    }
+
     async fn store_metadata(
         &self,
         key: &K,
@@ -336,7 +376,7 @@ where
         })
     }
 
-    async fn perform_maintenance(&self) -> Result<(), StoreError> {
+    async fn perform_maintenance(&self) -> Result<usize, StoreError> {
         let dir_entries = match fs::read_dir(&self.directory) {
             Err(e) => {
                 log::trace!(
@@ -344,10 +384,11 @@ where
                     &self.directory.display(),
                     e
                 );
-                return Ok(());
+                return Ok(0);
             }
             Ok(v) => v,
         };
+        let mut removed = 0;
         for entry in dir_entries {
             let entry = match entry {
                 Ok(v) => v,
@@ -382,11 +423,12 @@ where
                 continue;
             }
             log::trace!("File at {} has expired, attempting removal", path.display());
-            if let Err(e) = fs::remove_file(&path) {
-                log::info!("Error deleting expired file {}: {}", path.display(), e);
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => log::info!("Error deleting expired file {}: {}", path.display(), e),
             }
         }
 
-        Ok(())
+        Ok(removed)
     }
 }
@@ -0,0 +1,338 @@
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use fdo_data_formats::Serializable;
+
+use crate::{FilterType, MetadataLocalKey, MetadataValue, StoreConfig, ValueIter};
+
+use super::Store;
+use super::StoreError;
+
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Where the material of a key encryption key comes from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KeySource {
+    /// File holding the 32 byte raw key
+    File(PathBuf),
+    /// Environment variable holding the hex encoded key
+    Env(String),
+    /// Command printing the hex encoded key, e.g. to fetch it from a KMS
+    Command(Vec<String>),
+}
+
+/// Key encryption key of an encrypted store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyEncryptionKey {
+    /// Identifier stored with every value, so that the key can be found again after rotation
+    pub id: String,
+    #[serde(flatten)]
+    pub source: KeySource,
+}
+
+impl KeyEncryptionKey {
+    fn load(&self) -> Result<[u8; KEY_LEN], StoreError> {
+        let key_error =
+            |e: String| StoreError::Configuration(format!("Error loading key {}: {e}", self.id));
+        let key = match &self.source {
+            KeySource::File(path) => {
+                std::fs::read(path).map_err(|e| key_error(format!("{path:?}: {e}")))?
+            }
+            KeySource::Env(name) => {
+                let value = std::env::var(name).map_err(|e| key_error(format!("{name}: {e}")))?;
+                hex::decode(value.trim()).map_err(|e| key_error(format!("{name}: {e}")))?
+            }
+            KeySource::Command(command) => {
+                let (program, args) = command
+                    .split_first()
+                    .ok_or_else(|| key_error("empty command".to_string()))?;
+                let output = std::process::Command::new(program)
+                    .args(args)
+                    .output()
+                    .map_err(|e| key_error(format!("{program}: {e}")))?;
+                if !output.status.success() {
+                    return Err(key_error(format!("{program}: {}", output.status)));
+                }
+                let value = String::from_utf8_lossy(&output.stdout);
+                hex::decode(value.trim()).map_err(|e| key_error(format!("{program}: {e}")))?
+            }
+        };
+        key.as_slice()
+            .try_into()
+            .map_err(|_| key_error(format!("key must be {KEY_LEN} bytes, got {}", key.len())))
+    }
+}
+
+struct KeyRing {
+    // The first key encrypts new values, the others are only used to decrypt
+    keys: Vec<(String, [u8; KEY_LEN])>,
+}
+
+impl KeyRing {
+    fn current(&self) -> (&str, &[u8; KEY_LEN]) {
+        let (id, key) = &self.keys[0];
+        (id, key)
+    }
+
+    fn get(&self, id: &str) -> Result<&[u8; KEY_LEN], StoreError> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| {
+                StoreError::Unspecified(format!("Value was encrypted with unknown key {id}"))
+            })
+    }
+}
+
+fn crypto_error(e: openssl::error::ErrorStack) -> StoreError {
+    StoreError::Unspecified(format!("Encryption error: {e}"))
+}
+
+fn seal(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, StoreError> {
+    let mut iv = [0u8; IV_LEN];
+    openssl::rand::rand_bytes(&mut iv).map_err(crypto_error)?;
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&iv),
+        aad,
+        plaintext,
+        &mut tag,
+    )
+    .map_err(crypto_error)?;
+    Ok([&iv[..], &ciphertext[..], &tag[..]].concat())
+}
+
+fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, StoreError> {
+    if sealed.len() < IV_LEN + TAG_LEN {
+        return Err(StoreError::Unspecified(
+            "Encrypted value is truncated".to_string(),
+        ));
+    }
+    let (iv, rest) = sealed.split_at(IV_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt_aead(Cipher::aes_256_gcm(), key, Some(iv), aad, ciphertext, tag).map_err(crypto_error)
+}
+
+/// A value as persisted in the inner store.
+///
+/// Every value is encrypted with its own data encryption key, which is in turn encrypted
+/// with the key encryption key `kek_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedValue {
+    // The store key, used as AAD so that values can't be swapped between keys
+    key: String,
+    kek_id: String,
+    wrapped_dek: ByteBuf,
+    ciphertext: ByteBuf,
+}
+
+impl EncryptedValue {
+    fn encrypt<V: Serializable>(
+        keys: &KeyRing,
+        key: String,
+        value: &V,
+    ) -> Result<Self, StoreError> {
+        let plaintext = value
+            .serialize_data()
+            .map_err(|e| StoreError::Unspecified(format!("Error serializing value: {e:?}")))?;
+        let mut dek = [0u8; KEY_LEN];
+        openssl::rand::rand_bytes(&mut dek).map_err(crypto_error)?;
+        let (kek_id, kek) = keys.current();
+        Ok(EncryptedValue {
+            wrapped_dek: ByteBuf::from(seal(kek, kek_id.as_bytes(), &dek)?),
+            ciphertext: ByteBuf::from(seal(&dek, key.as_bytes(), &plaintext)?),
+            kek_id: kek_id.to_string(),
+            key,
+        })
+    }
+
+    fn decrypt<V: Serializable>(&self, keys: &KeyRing, key: &str) -> Result<V, StoreError> {
+        let kek = keys.get(&self.kek_id)?;
+        let dek = open(kek, self.kek_id.as_bytes(), &self.wrapped_dek)?;
+        let plaintext = open(&dek, key.as_bytes(), &self.ciphertext)?;
+        V::deserialize_data(&plaintext)
+            .map_err(|e| StoreError::Unspecified(format!("Error deserializing value: {e:?}")))
+    }
+}
+
+pub(super) fn initialize<OT, K, V, MKT>(
+    store: &StoreConfig,
+    keys: &[KeyEncryptionKey],
+) -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode + 'static,
+    K: Eq + std::hash::Hash + Send + Sync + std::string::ToString + std::str::FromStr + 'static,
+    V: Send + Sync + Clone + Serializable + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    if keys.is_empty() {
+        return Err(StoreError::Configuration(
+            "Encrypted store needs at least one key".to_string(),
+        ));
+    }
+    let keys = keys
+        .iter()
+        .map(|kek| Ok((kek.id.clone(), kek.load()?)))
+        .collect::<Result<_, StoreError>>()?;
+
+    Ok(Box::new(EncryptedStore {
+        phantom_v: PhantomData,
+
+        inner: store.initialize()?,
+        keys: Arc::new(KeyRing { keys }),
+    }))
+}
+
+struct EncryptedStore<OT, K, V, MKT>
+where
+    OT: crate::StoreOpenMode,
+    MKT: MetadataLocalKey,
+{
+    phantom_v: PhantomData<V>,
+
+    inner: Box<dyn Store<OT, K, EncryptedValue, MKT>>,
+    keys: Arc<KeyRing>,
+}
+
+struct EncryptedStoreFilterType<MKT>
+where
+    MKT: MetadataLocalKey,
+{
+    inner: Box<dyn FilterType<EncryptedValue, MKT>>,
+    keys: Arc<KeyRing>,
+}
+
+#[async_trait]
+impl<V, MKT> FilterType<V, MKT> for EncryptedStoreFilterType<MKT>
+where
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey,
+{
+    // Metadata is not encrypted, so filters are applied by the inner store
+    fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
+        self.inner.neq(key, expected);
+    }
+    fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
+        self.inner.lt(key, max);
+    }
+    async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        let encrypted = match self.inner.query().await? {
+            None => return Ok(None),
+            Some(encrypted) => encrypted,
+        };
+        let mut values = Vec::new();
+        for value in encrypted {
+            match value.decrypt(&self.keys, &value.key) {
+                Ok(v) => values.push(v),
+                Err(e) => log::trace!("Error decrypting data {}: {:?}", value.key, e),
+            }
+        }
+        Ok(Some(ValueIter {
+            index: 0,
+            values,
+            errored: false,
+        }))
+    }
+}
+
+#[async_trait]
+impl<OT, K, V, MKT> Store<OT, K, V, MKT> for EncryptedStore<OT, K, V, MKT>
+where
+    OT: crate::StoreOpenMode + 'static,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError>
+    where
+        OT: crate::Readable,
+    {
+        match self.inner.load_data(key).await? {
+            None => Ok(None),
+            Some(value) => value.decrypt(&self.keys, &key.to_string()).map(Some),
+        }
+    }
+
+    async fn load_all_data(&self) -> Result<Vec<V>, StoreError>
+    where
+        OT: crate::Readable,
+    {
+        let mut values = Vec::new();
+        for value in self.inner.load_all_data().await? {
+            match value.decrypt(&self.keys, &value.key) {
+                Ok(v) => values.push(v),
+                Err(e) => log::trace!("Error decrypting data {}: {:?}", value.key, e),
+            }
+        }
+        Ok(values)
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError>
+    where
+        OT: crate::Writable,
+    {
+        self.inner
+            .store_metadata(key, metadata_key, metadata_value)
+            .await
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError>
+    where
+        OT: crate::Writable,
+    {
+        self.inner.destroy_metadata(key, metadata_key).await
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT>
+    where
+        OT: crate::Writable,
+    {
+        Ok(Box::new(EncryptedStoreFilterType {
+            inner: self.inner.query_data().await?,
+            keys: self.keys.clone(),
+        }))
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError>
+    where
+        OT: crate::Writable,
+    {
+        let value = EncryptedValue::encrypt(&self.keys, key.to_string(), &value)?;
+        self.inner.store_data(key, value).await
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError>
+    where
+        OT: crate::Writable,
+    {
+        self.inner.destroy_data(key).await
+    }
+
+    async fn perform_maintenance(&self) -> Result<usize, StoreError>
+    where
+        OT: crate::Writable,
+    {
+        self.inner.perform_maintenance().await
+    }
+}
@@ -150,6 +150,14 @@ pub trait Store<OT: StoreOpenMode, K, V, MKT: MetadataLocalKey>: Send + Sync {
         Self: 'async_trait,
         OT: Readable;
 
+    fn load_all_data<'life0, 'async_trait>(
+        &'life0 self,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<V>, StoreError>> + 'async_trait + Send>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+        OT: Readable;
+
     fn store_metadata<'life0, 'life1, 'life2, 'life3, 'async_trait>(
         &'life0 self,
         key: &'life1 K,
@@ -204,22 +212,77 @@ pub trait Store<OT: StoreOpenMode, K, V, MKT: MetadataLocalKey>: Send + Sync {
         Self: 'async_trait,
         OT: Writable;
 
+    /// Removes expired entries, returning the number of entries removed.
     fn perform_maintenance<'life0, 'async_trait>(
         &'life0 self,
-    ) -> Pin<Box<dyn Future<Output = Result<(), StoreError>> + 'async_trait + Send>>
+    ) -> Pin<Box<dyn Future<Output = Result<usize, StoreError>> + 'async_trait + Send>>
     where
         'life0: 'async_trait,
         Self: 'async_trait,
         OT: Writable;
 }
 
+mod cached;
+pub use cached::{CacheConfig, CacheMetrics};
+mod encrypted;
+pub use encrypted::{KeyEncryptionKey, KeySource};
 #[cfg(feature = "directory")]
 mod directory;
+#[cfg(feature = "memory")]
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum StoreConfig {
     #[cfg(feature = "directory")]
     Directory { path: std::path::PathBuf },
+    // Not persisted, and not shared between server instances
+    #[cfg(feature = "memory")]
+    Memory,
+    // Single database file, only to be used by one server instance at a time
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        path: std::path::PathBuf,
+        // Upgrade the schema when the store is initialized, instead of with migrate()
+        #[serde(default = "default_auto_migrate")]
+        auto_migrate: bool,
+    },
+    // Shared by server instances. The stores of a database share its tables,
+    // every store needs its own name.
+    #[cfg(feature = "postgres")]
+    Postgres {
+        url: String,
+        name: String,
+        // Upgrade the schema when the store is initialized, instead of with migrate()
+        #[serde(default = "default_auto_migrate")]
+        auto_migrate: bool,
+    },
+    // Shared by server instances, every store of a database needs its own name
+    #[cfg(feature = "redis")]
+    Redis { url: String, name: String },
+    // Encrypts the values written to another store, the first key is used for new values
+    Encrypted {
+        store: Box<StoreConfig>,
+        keys: Vec<KeyEncryptionKey>,
+    },
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+fn default_auto_migrate() -> bool {
+    true
+}
+
+/// Schema versions of a store before and after it was migrated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchemaMigration {
+    pub from: usize,
+    pub to: usize,
 }
 
 impl StoreConfig {
@@ -234,6 +297,34 @@ impl StoreConfig {
         match self {
             #[cfg(feature = "directory")]
             StoreConfig::Directory { path } => directory::initialize(path),
+            #[cfg(feature = "memory")]
+            StoreConfig::Memory => memory::initialize(),
+            #[cfg(feature = "sqlite")]
+            StoreConfig::Sqlite { path, auto_migrate } => sqlite::initialize(path, *auto_migrate),
+            #[cfg(feature = "postgres")]
+            StoreConfig::Postgres {
+                url,
+                name,
+                auto_migrate,
+            } => postgres::initialize(url, name, *auto_migrate),
+            #[cfg(feature = "redis")]
+            StoreConfig::Redis { url, name } => redis::initialize(url, name),
+            StoreConfig::Encrypted { store, keys } => encrypted::initialize(store, keys),
+        }
+    }
+
+    /// Upgrades the schema of the store to the latest version.
+    ///
+    /// Returns None for stores that don't have a schema.
+    pub fn migrate(&self) -> Result<Option<SchemaMigration>, StoreError> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            StoreConfig::Sqlite { path, .. } => sqlite::migrate_database(path).map(Some),
+            #[cfg(feature = "postgres")]
+            StoreConfig::Postgres { url, .. } => postgres::migrate_database(url).map(Some),
+            StoreConfig::Encrypted { store, .. } => store.migrate(),
+            #[allow(unreachable_patterns)]
+            _ => Ok(None),
         }
     }
 }
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+
+use crate::{FilterType, MetadataLocalKey, MetadataValue, ValueIter};
+
+use super::Store;
+use super::StoreError;
+
+pub(super) fn initialize<OT, K, V, MKT>() -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    Ok(Box::new(MemoryStore {
+        phantom_k: PhantomData,
+
+        entries: Arc::new(RwLock::new(HashMap::new())),
+    }))
+}
+
+#[derive(Debug, Clone)]
+struct MemoryEntry<V> {
+    value: V,
+    metadata: HashMap<String, Vec<u8>>,
+}
+
+impl<V> MemoryEntry<V> {
+    fn is_expired<MKT: MetadataLocalKey>(&self) -> bool {
+        match self
+            .metadata
+            .get(crate::MetadataKey::<MKT>::Ttl.to_key())
+            .and_then(|ttl| ttl_from_stored(ttl))
+        {
+            Some(ttl) => time::OffsetDateTime::now_utc().unix_timestamp() > ttl,
+            None => false,
+        }
+    }
+}
+
+type MemoryEntries<V> = Arc<RwLock<HashMap<String, MemoryEntry<V>>>>;
+
+#[derive(Debug)]
+struct MemoryStore<K, V> {
+    phantom_k: PhantomData<K>,
+
+    entries: MemoryEntries<V>,
+}
+
+fn ttl_from_stored(ttl: &[u8]) -> Option<i64> {
+    Some(i64::from_le_bytes(ttl.try_into().ok()?))
+}
+
+fn lock_error<E: std::fmt::Display>(e: E) -> StoreError {
+    StoreError::Unspecified(format!("Memory store lock poisoned: {e}"))
+}
+
+pub struct MemoryStoreFilterType<V> {
+    entries: MemoryEntries<V>,
+    neqs: Vec<(String, Vec<u8>)>,
+    lts: Vec<(String, i64)>,
+}
+
+#[async_trait]
+impl<V, MKT> FilterType<V, MKT> for MemoryStoreFilterType<V>
+where
+    V: Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey,
+{
+    fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
+        self.neqs
+            .push((key.to_key().to_owned(), expected.to_stored().unwrap()));
+    }
+    fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
+        self.lts.push((key.to_key().to_owned(), max));
+    }
+    async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        let entries = self.entries.read().map_err(lock_error)?;
+        // Same semantics as the directory store: an entry matches if any of the
+        // neq filters differs, and then any of the lt filters is below the maximum
+        let values = entries
+            .values()
+            .filter(|entry| {
+                self.neqs
+                    .iter()
+                    .any(|(key, expected)| entry.metadata.get(key) != Some(expected))
+            })
+            .filter(|entry| {
+                self.lts
+                    .iter()
+                    .any(|(key, max)| match entry.metadata.get(key) {
+                        Some(v) => matches!(ttl_from_stored(v), Some(value) if value < *max),
+                        None => true,
+                    })
+            })
+            .map(|entry| entry.value.clone())
+            .collect();
+        Ok(Some(ValueIter {
+            index: 0,
+            values,
+            errored: false,
+        }))
+    }
+}
+
+#[async_trait]
+impl<OT, K, V, MKT> Store<OT, K, V, MKT> for MemoryStore<K, V>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to load data for {}", key);
+
+        let mut entries = self.entries.write().map_err(lock_error)?;
+        match entries.get(&key) {
+            None => Ok(None),
+            Some(entry) if entry.is_expired::<MKT>() => {
+                log::trace!("Item has expired, removing");
+                entries.remove(&key);
+                Ok(None)
+            }
+            Some(entry) => Ok(Some(entry.value.clone())),
+        }
+    }
+
+    async fn load_all_data(&self) -> Result<Vec<V>, StoreError> {
+        log::trace!("Attempting to load all data");
+
+        Ok(self
+            .entries
+            .read()
+            .map_err(lock_error)?
+            .values()
+            .filter(|entry| !entry.is_expired::<MKT>())
+            .map(|entry| entry.value.clone())
+            .collect())
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError> {
+        let value = metadata_value.to_stored()?;
+        let mut entries = self.entries.write().map_err(lock_error)?;
+        if let Some(entry) = entries.get_mut(&key.to_string()) {
+            entry
+                .metadata
+                .insert(metadata_key.to_key().to_owned(), value);
+        }
+        Ok(())
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError> {
+        let mut entries = self.entries.write().map_err(lock_error)?;
+        if let Some(entry) = entries.get_mut(&key.to_string()) {
+            entry.metadata.remove(metadata_key.to_key());
+        }
+        Ok(())
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT> {
+        Ok(Box::new(MemoryStoreFilterType {
+            entries: self.entries.clone(),
+            neqs: Vec::new(),
+            lts: Vec::new(),
+        }))
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to store data for {}", key);
+
+        // Replacing a value drops its metadata, just like replacing a file does
+        self.entries.write().map_err(lock_error)?.insert(
+            key,
+            MemoryEntry {
+                value,
+                metadata: HashMap::new(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to delete data for {}", key);
+
+        match self.entries.write().map_err(lock_error)?.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(StoreError::Unspecified(format!(
+                "Error removing '{key}': not found"
+            ))),
+        }
+    }
+
+    async fn perform_maintenance(&self) -> Result<usize, StoreError> {
+        let mut entries = self.entries.write().map_err(lock_error)?;
+        let before = entries.len();
+        entries.retain(|_, entry| !entry.is_expired::<MKT>());
+        Ok(before - entries.len())
+    }
+}
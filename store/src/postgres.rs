@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use postgres_native_tls::MakeTlsConnector;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio_postgres::GenericClient;
+
+use fdo_data_formats::Serializable;
+
+use crate::{FilterType, MetadataLocalKey, MetadataValue, ValueIter};
+
+use super::Store;
+use super::StoreError;
+
+// Migration N brings the schema from version N-1 to version N. The version
+// of a database is kept in the fdo_store_schema table, so only ever append
+// to this list.
+const MIGRATIONS: &[&str] = &[include_str!("../migrations/postgres/0001_initial.sql")];
+
+// Advisory lock held while migrating, so that server instances starting at
+// the same time don't migrate the same database concurrently
+const MIGRATION_LOCK: i64 = 0x6664_6f2d_7374_6f72;
+
+pub(super) fn initialize<OT, K, V, MKT>(
+    url: &str,
+    name: &str,
+    auto_migrate: bool,
+) -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    let config = parse_url(url)?;
+    let tls = tls_connector()?;
+
+    block_on(async {
+        let mut client = connect(&config, tls.clone()).await?;
+        if auto_migrate {
+            migrate(&mut client).await?;
+        } else {
+            let version = schema_version(&client).await?;
+            if version != MIGRATIONS.len() {
+                return Err(StoreError::Configuration(format!(
+                    "Database has schema version {version}, expected version {}: run 'fdo-admin-tool migrate-store' first",
+                    MIGRATIONS.len()
+                )));
+            }
+        }
+        Ok(())
+    })?;
+
+    // Connections are only opened once they're needed, from the runtime using the store
+    let manager = Manager::from_config(
+        config,
+        tls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    let pool = Pool::builder(manager).build().map_err(|e| {
+        StoreError::Configuration(format!(
+            "Database connection pool could not be created: {e}"
+        ))
+    })?;
+
+    Ok(Box::new(PostgresStore {
+        phantom_k: PhantomData,
+        phantom_v: PhantomData,
+
+        pool,
+        name: name.to_string(),
+    }))
+}
+
+pub(super) fn migrate_database(url: &str) -> Result<crate::SchemaMigration, StoreError> {
+    let config = parse_url(url)?;
+    let tls = tls_connector()?;
+    block_on(async {
+        let mut client = connect(&config, tls).await?;
+        migrate(&mut client).await
+    })
+}
+
+// Initializing a store isn't async, but talking to the database is
+fn block_on<T>(future: impl Future<Output = Result<T, StoreError>>) -> Result<T, StoreError> {
+    match Handle::try_current() {
+        Ok(handle) => match handle.runtime_flavor() {
+            RuntimeFlavor::CurrentThread => Err(StoreError::Configuration(
+                "PostgreSQL stores can't be initialized from a single threaded runtime".to_string(),
+            )),
+            _ => tokio::task::block_in_place(|| handle.block_on(future)),
+        },
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| StoreError::Configuration(format!("Runtime could not be created: {e}")))?
+            .block_on(future),
+    }
+}
+
+// The URL isn't part of the errors, as it may contain a password
+fn parse_url(url: &str) -> Result<tokio_postgres::Config, StoreError> {
+    tokio_postgres::Config::from_str(url)
+        .map_err(|e| StoreError::Configuration(format!("Invalid database URL: {e}")))
+}
+
+// Whether TLS is used is set by the sslmode of the URL
+fn tls_connector() -> Result<MakeTlsConnector, StoreError> {
+    let connector = native_tls::TlsConnector::new().map_err(|e| {
+        StoreError::Configuration(format!("TLS connector could not be created: {e}"))
+    })?;
+    Ok(MakeTlsConnector::new(connector))
+}
+
+async fn connect(
+    config: &tokio_postgres::Config,
+    tls: MakeTlsConnector,
+) -> Result<tokio_postgres::Client, StoreError> {
+    let (client, connection) = config
+        .connect(tls)
+        .await
+        .map_err(|e| StoreError::Configuration(format!("Database could not be opened: {e}")))?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::warn!("Database connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+async fn schema_version<C: GenericClient>(client: &C) -> Result<usize, StoreError> {
+    let schema_error = |e: tokio_postgres::Error| {
+        StoreError::Configuration(format!("Schema version of database could not be read: {e}"))
+    };
+    let exists: bool = client
+        .query_one("SELECT to_regclass('fdo_store_schema') IS NOT NULL", &[])
+        .await
+        .map_err(schema_error)?
+        .get(0);
+    if !exists {
+        return Ok(0);
+    }
+    let version: i32 = client
+        .query_opt("SELECT version FROM fdo_store_schema", &[])
+        .await
+        .map_err(schema_error)?
+        .map(|row| row.get(0))
+        .unwrap_or(0);
+    let version = version as usize;
+    // Running against a schema we don't know could lose data
+    if version > MIGRATIONS.len() {
+        return Err(StoreError::Configuration(format!(
+            "Database has schema version {version}, which is newer than the supported version {}",
+            MIGRATIONS.len()
+        )));
+    }
+    Ok(version)
+}
+
+async fn migrate(
+    client: &mut tokio_postgres::Client,
+) -> Result<crate::SchemaMigration, StoreError> {
+    let migrate_error = |e: tokio_postgres::Error| {
+        StoreError::Configuration(format!("Database could not be migrated: {e}"))
+    };
+    // All migrations are applied completely, or not at all
+    let transaction = client.transaction().await.map_err(migrate_error)?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .await
+        .map_err(migrate_error)?;
+    transaction
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS fdo_store_schema (version INTEGER NOT NULL);
+             INSERT INTO fdo_store_schema (version)
+             SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM fdo_store_schema);",
+        )
+        .await
+        .map_err(migrate_error)?;
+    let from = schema_version(&transaction).await?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(from) {
+        let version = index + 1;
+        log::info!("Migrating database to schema version {}", version);
+        transaction
+            .batch_execute(migration)
+            .await
+            .map_err(migrate_error)?;
+        transaction
+            .execute(
+                "UPDATE fdo_store_schema SET version = $1",
+                &[&(version as i32)],
+            )
+            .await
+            .map_err(migrate_error)?;
+    }
+    transaction.commit().await.map_err(migrate_error)?;
+    Ok(crate::SchemaMigration {
+        from,
+        to: MIGRATIONS.len(),
+    })
+}
+
+struct PostgresStore<K, V> {
+    phantom_k: PhantomData<K>,
+    phantom_v: PhantomData<V>,
+
+    pool: Pool,
+    // Stores share the tables, their entries are told apart by this name
+    name: String,
+}
+
+async fn get_client(pool: &Pool) -> Result<deadpool_postgres::Object, StoreError> {
+    pool.get()
+        .await
+        .map_err(|e| StoreError::Unspecified(format!("Database connection error: {e}")))
+}
+
+fn db_error(e: tokio_postgres::Error) -> StoreError {
+    StoreError::Unspecified(format!("Database error: {e}"))
+}
+
+fn ttl_from_stored(ttl: &[u8]) -> Option<i64> {
+    Some(i64::from_le_bytes(ttl.try_into().ok()?))
+}
+
+fn is_expired(ttl: Option<Vec<u8>>) -> bool {
+    match ttl.as_deref().and_then(ttl_from_stored) {
+        Some(ttl) => time::OffsetDateTime::now_utc().unix_timestamp() > ttl,
+        None => false,
+    }
+}
+
+fn deserialize<V: Serializable>(key: &str, value: &[u8]) -> Option<V> {
+    match V::deserialize_data(value) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log::trace!("Error deserializing data {}: {}", key, e);
+            None
+        }
+    }
+}
+
+pub struct PostgresStoreFilterType {
+    pool: Pool,
+    name: String,
+    neqs: Vec<(String, Vec<u8>)>,
+    lts: Vec<(String, i64)>,
+}
+
+#[async_trait]
+impl<V, MKT> FilterType<V, MKT> for PostgresStoreFilterType
+where
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey,
+{
+    fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
+        self.neqs
+            .push((key.to_key().to_owned(), expected.to_stored().unwrap()));
+    }
+    fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
+        self.lts.push((key.to_key().to_owned(), max));
+    }
+    async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        let client = get_client(&self.pool).await?;
+
+        let mut metadata: HashMap<String, HashMap<String, Vec<u8>>> = HashMap::new();
+        let rows = client
+            .query(
+                "SELECT key, name, value FROM fdo_store_metadata WHERE store = $1",
+                &[&self.name],
+            )
+            .await
+            .map_err(db_error)?;
+        for row in rows {
+            metadata
+                .entry(row.get(0))
+                .or_default()
+                .insert(row.get(1), row.get(2));
+        }
+
+        // Same semantics as the directory store: an entry matches if any of the
+        // neq filters differs, and then any of the lt filters is below the maximum
+        let no_metadata = HashMap::new();
+        let mut values = Vec::new();
+        let rows = client
+            .query(
+                "SELECT key, value FROM fdo_store_entries WHERE store = $1",
+                &[&self.name],
+            )
+            .await
+            .map_err(db_error)?;
+        for row in rows {
+            let key: String = row.get(0);
+            let value: Vec<u8> = row.get(1);
+            let entry_metadata = metadata.get(&key).unwrap_or(&no_metadata);
+            let neq_matches = self
+                .neqs
+                .iter()
+                .any(|(name, expected)| entry_metadata.get(name) != Some(expected));
+            let lt_matches = self
+                .lts
+                .iter()
+                .any(|(name, max)| match entry_metadata.get(name) {
+                    Some(v) => matches!(ttl_from_stored(v), Some(value) if value < *max),
+                    None => true,
+                });
+            if !neq_matches || !lt_matches {
+                continue;
+            }
+            if let Some(value) = deserialize(&key, &value) {
+                values.push(value);
+            }
+        }
+        Ok(Some(ValueIter {
+            index: 0,
+            values,
+            errored: false,
+        }))
+    }
+}
+
+#[async_trait]
+impl<OT, K, V, MKT> Store<OT, K, V, MKT> for PostgresStore<K, V>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to load data for {}", key);
+
+        let client = get_client(&self.pool).await?;
+        let row = client
+            .query_opt(
+                "SELECT entries.value, metadata.value FROM fdo_store_entries entries
+                 LEFT JOIN fdo_store_metadata metadata ON metadata.store = entries.store
+                     AND metadata.key = entries.key AND metadata.name = $3
+                 WHERE entries.store = $1 AND entries.key = $2",
+                &[&self.name, &key, &crate::MetadataKey::<MKT>::Ttl.to_key()],
+            )
+            .await
+            .map_err(db_error)?;
+        let row = match row {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        let value: Vec<u8> = row.get(0);
+        if is_expired(row.get(1)) {
+            log::trace!("Item has expired, attempting removal");
+            if let Err(e) = client
+                .execute(
+                    "DELETE FROM fdo_store_entries WHERE store = $1 AND key = $2",
+                    &[&self.name, &key],
+                )
+                .await
+            {
+                log::info!("Error deleting expired entry {}: {}", key, e);
+            }
+            return Ok(None);
+        }
+
+        Ok(Some(V::deserialize_data(&value).map_err(|e| {
+            StoreError::Unspecified(format!("Error deserializing value: {e:?}"))
+        })?))
+    }
+
+    async fn load_all_data(&self) -> Result<Vec<V>, StoreError> {
+        log::trace!("Attempting to load all data");
+
+        let rows = get_client(&self.pool)
+            .await?
+            .query(
+                "SELECT entries.key, entries.value, metadata.value FROM fdo_store_entries entries
+                 LEFT JOIN fdo_store_metadata metadata ON metadata.store = entries.store
+                     AND metadata.key = entries.key AND metadata.name = $2
+                 WHERE entries.store = $1",
+                &[&self.name, &crate::MetadataKey::<MKT>::Ttl.to_key()],
+            )
+            .await
+            .map_err(db_error)?;
+        let mut values = Vec::new();
+        for row in rows {
+            let key: String = row.get(0);
+            let value: Vec<u8> = row.get(1);
+            if is_expired(row.get(2)) {
+                continue;
+            }
+            if let Some(value) = deserialize(&key, &value) {
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError> {
+        let value = metadata_value.to_stored()?;
+        // Metadata of entries that don't exist is ignored, like the other stores do
+        get_client(&self.pool)
+            .await?
+            .execute(
+                "INSERT INTO fdo_store_metadata (store, key, name, value)
+                 SELECT store, key, $3, $4 FROM fdo_store_entries WHERE store = $1 AND key = $2
+                 ON CONFLICT (store, key, name) DO UPDATE SET value = EXCLUDED.value",
+                &[&self.name, &key.to_string(), &metadata_key.to_key(), &value],
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError> {
+        get_client(&self.pool)
+            .await?
+            .execute(
+                "DELETE FROM fdo_store_metadata WHERE store = $1 AND key = $2 AND name = $3",
+                &[&self.name, &key.to_string(), &metadata_key.to_key()],
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT> {
+        Ok(Box::new(PostgresStoreFilterType {
+            pool: self.pool.clone(),
+            name: self.name.clone(),
+            neqs: Vec::new(),
+            lts: Vec::new(),
+        }))
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to store data for {}", key);
+
+        let value = value.serialize_data().map_err(|e| {
+            StoreError::Unspecified(format!("Error serializing value for {key}: {e:?}"))
+        })?;
+        let mut client = get_client(&self.pool).await?;
+        // Replacing a value drops its metadata, just like replacing a file does
+        let transaction = client.transaction().await.map_err(db_error)?;
+        transaction
+            .execute(
+                "DELETE FROM fdo_store_entries WHERE store = $1 AND key = $2",
+                &[&self.name, &key],
+            )
+            .await
+            .map_err(db_error)?;
+        transaction
+            .execute(
+                "INSERT INTO fdo_store_entries (store, key, value) VALUES ($1, $2, $3)",
+                &[&self.name, &key, &value],
+            )
+            .await
+            .map_err(db_error)?;
+        transaction.commit().await.map_err(db_error)
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to delete data for {}", key);
+
+        let removed = get_client(&self.pool)
+            .await?
+            .execute(
+                "DELETE FROM fdo_store_entries WHERE store = $1 AND key = $2",
+                &[&self.name, &key],
+            )
+            .await
+            .map_err(db_error)?;
+        if removed == 0 {
+            return Err(StoreError::Unspecified(format!(
+                "Error removing '{key}': not found"
+            )));
+        }
+        Ok(())
+    }
+
+    async fn perform_maintenance(&self) -> Result<usize, StoreError> {
+        let client = get_client(&self.pool).await?;
+        let rows = client
+            .query(
+                "SELECT key, value FROM fdo_store_metadata WHERE store = $1 AND name = $2",
+                &[&self.name, &crate::MetadataKey::<MKT>::Ttl.to_key()],
+            )
+            .await
+            .map_err(db_error)?;
+        let mut expired = Vec::new();
+        for row in rows {
+            let key: String = row.get(0);
+            if is_expired(row.get(1)) {
+                log::trace!("Entry {} has expired, removing", key);
+                expired.push(key);
+            }
+        }
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        // Other instances sharing the store may have removed some of them already
+        let removed = client
+            .execute(
+                "DELETE FROM fdo_store_entries WHERE store = $1 AND key = ANY($2)",
+                &[&self.name, &expired],
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(removed as usize)
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use tokio::sync::OnceCell;
+
+use fdo_data_formats::Serializable;
+
+use crate::{FilterType, MetadataLocalKey, MetadataValue, ValueIter};
+
+use super::Store;
+use super::StoreError;
+
+// Every entry is a hash, with its value and its metadata as fields. Expiry
+// times are also set on the hashes, so that Redis removes expired entries.
+const VALUE_FIELD: &str = "value";
+const METADATA_FIELD_PREFIX: &str = "metadata:";
+
+// Metadata of entries that don't exist is ignored, like the other stores do
+const STORE_METADATA_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return 0
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+if ARGV[3] ~= '' then
+    redis.call('EXPIREAT', KEYS[1], ARGV[3])
+end
+return 1
+";
+
+// Done in a script, so that entries stored again meanwhile keep their keys
+const REMOVE_EXPIRED_SCRIPT: &str = r"
+local removed = 0
+for _, key in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    if redis.call('EXISTS', ARGV[1] .. key) == 0 then
+        redis.call('SREM', KEYS[1], key)
+        removed = removed + 1
+    end
+end
+return removed
+";
+
+pub(super) fn initialize<OT, K, V, MKT>(
+    url: &str,
+    name: &str,
+) -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    // The URL isn't part of the error, as it may contain a password
+    let client = redis::Client::open(url)
+        .map_err(|e| StoreError::Configuration(format!("Invalid Redis URL: {e}")))?;
+
+    Ok(Box::new(RedisStore {
+        phantom_k: PhantomData,
+        phantom_v: PhantomData,
+
+        client,
+        connection: OnceCell::new(),
+        name: name.to_string(),
+    }))
+}
+
+struct RedisStore<K, V> {
+    phantom_k: PhantomData<K>,
+    phantom_v: PhantomData<V>,
+
+    client: redis::Client,
+    // Opened once it's needed, from the runtime using the store. It reconnects by itself.
+    connection: OnceCell<ConnectionManager>,
+    // Prefix of the Redis keys of the store, so that stores can share a database
+    name: String,
+}
+
+fn entry_key(name: &str, key: &str) -> String {
+    format!("{name}:entry:{key}")
+}
+
+// Set with the keys of all entries, as Redis can't list the keys of a prefix efficiently
+fn index_key(name: &str) -> String {
+    format!("{name}:entries")
+}
+
+fn metadata_field(name: &str) -> String {
+    format!("{METADATA_FIELD_PREFIX}{name}")
+}
+
+impl<K, V> RedisStore<K, V> {
+    async fn connection(&self) -> Result<ConnectionManager, StoreError> {
+        self.connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await
+            .cloned()
+            .map_err(|e| StoreError::Unspecified(format!("Redis connection error: {e}")))
+    }
+}
+
+fn redis_error(e: redis::RedisError) -> StoreError {
+    StoreError::Unspecified(format!("Redis error: {e}"))
+}
+
+fn ttl_from_stored(ttl: &[u8]) -> Option<i64> {
+    Some(i64::from_le_bytes(ttl.try_into().ok()?))
+}
+
+fn deserialize<V: Serializable>(key: &str, value: &[u8]) -> Option<V> {
+    match V::deserialize_data(value) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log::trace!("Error deserializing data {}: {}", key, e);
+            None
+        }
+    }
+}
+
+async fn load_entries(
+    connection: &mut ConnectionManager,
+    name: &str,
+) -> Result<Vec<(String, HashMap<String, Vec<u8>>)>, StoreError> {
+    let keys: Vec<String> = connection
+        .smembers(index_key(name))
+        .await
+        .map_err(redis_error)?;
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.hgetall(entry_key(name, key));
+    }
+    let entries: Vec<HashMap<String, Vec<u8>>> =
+        pipe.query_async(connection).await.map_err(redis_error)?;
+    // Entries removed by Redis once they expired are left out
+    Ok(keys
+        .into_iter()
+        .zip(entries)
+        .filter(|(_, entry)| entry.contains_key(VALUE_FIELD))
+        .collect())
+}
+
+pub struct RedisStoreFilterType {
+    connection: ConnectionManager,
+    name: String,
+    neqs: Vec<(String, Vec<u8>)>,
+    lts: Vec<(String, i64)>,
+}
+
+#[async_trait]
+impl<V, MKT> FilterType<V, MKT> for RedisStoreFilterType
+where
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey,
+{
+    fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
+        self.neqs
+            .push((metadata_field(key.to_key()), expected.to_stored().unwrap()));
+    }
+    fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
+        self.lts.push((metadata_field(key.to_key()), max));
+    }
+    async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        let mut connection = self.connection.clone();
+
+        // Same semantics as the directory store: an entry matches if any of the
+        // neq filters differs, and then any of the lt filters is below the maximum
+        let mut values = Vec::new();
+        for (key, entry) in load_entries(&mut connection, &self.name).await? {
+            let neq_matches = self
+                .neqs
+                .iter()
+                .any(|(field, expected)| entry.get(field) != Some(expected));
+            let lt_matches = self.lts.iter().any(|(field, max)| match entry.get(field) {
+                Some(v) => matches!(ttl_from_stored(v), Some(value) if value < *max),
+                None => true,
+            });
+            if !neq_matches || !lt_matches {
+                continue;
+            }
+            if let Some(value) = deserialize(&key, &entry[VALUE_FIELD]) {
+                values.push(value);
+            }
+        }
+        Ok(Some(ValueIter {
+            index: 0,
+            values,
+            errored: false,
+        }))
+    }
+}
+
+#[async_trait]
+impl<OT, K, V, MKT> Store<OT, K, V, MKT> for RedisStore<K, V>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to load data for {}", key);
+
+        let value: Option<Vec<u8>> = self
+            .connection()
+            .await?
+            .hget(entry_key(&self.name, &key), VALUE_FIELD)
+            .await
+            .map_err(redis_error)?;
+        let value = match value {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+
+        Ok(Some(V::deserialize_data(&value).map_err(|e| {
+            StoreError::Unspecified(format!("Error deserializing value: {e:?}"))
+        })?))
+    }
+
+    async fn load_all_data(&self) -> Result<Vec<V>, StoreError> {
+        log::trace!("Attempting to load all data");
+
+        let mut connection = self.connection().await?;
+        Ok(load_entries(&mut connection, &self.name)
+            .await?
+            .into_iter()
+            .filter_map(|(key, entry)| deserialize(&key, &entry[VALUE_FIELD]))
+            .collect())
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError> {
+        let value = metadata_value.to_stored()?;
+        let expire_at = match metadata_key {
+            crate::MetadataKey::Ttl => match ttl_from_stored(&value) {
+                Some(ttl) => ttl.to_string(),
+                None => {
+                    return Err(StoreError::Unspecified(
+                        "Invalid TTL metadata value".to_string(),
+                    ))
+                }
+            },
+            _ => String::new(),
+        };
+        let _: i64 = redis::Script::new(STORE_METADATA_SCRIPT)
+            .key(entry_key(&self.name, &key.to_string()))
+            .arg(metadata_field(metadata_key.to_key()))
+            .arg(value)
+            .arg(expire_at)
+            .invoke_async(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)?;
+        Ok(())
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError> {
+        let entry = entry_key(&self.name, &key.to_string());
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hdel(&entry, metadata_field(metadata_key.to_key()))
+            .ignore();
+        if let crate::MetadataKey::Ttl = metadata_key {
+            pipe.persist(&entry).ignore();
+        }
+        pipe.query_async(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT> {
+        Ok(Box::new(RedisStoreFilterType {
+            connection: self.connection().await?,
+            name: self.name.clone(),
+            neqs: Vec::new(),
+            lts: Vec::new(),
+        }))
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to store data for {}", key);
+
+        let value = value.serialize_data().map_err(|e| {
+            StoreError::Unspecified(format!("Error serializing value for {key}: {e:?}"))
+        })?;
+        let entry = entry_key(&self.name, &key);
+        // Replacing a value drops its metadata, just like replacing a file does
+        redis::pipe()
+            .atomic()
+            .del(&entry)
+            .ignore()
+            .hset(&entry, VALUE_FIELD, value)
+            .ignore()
+            .sadd(index_key(&self.name), &key)
+            .ignore()
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to delete data for {}", key);
+
+        let (removed,): (usize,) = redis::pipe()
+            .atomic()
+            .del(entry_key(&self.name, &key))
+            .srem(index_key(&self.name), &key)
+            .ignore()
+            .query_async(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)?;
+        if removed == 0 {
+            return Err(StoreError::Unspecified(format!(
+                "Error removing '{key}': not found"
+            )));
+        }
+        Ok(())
+    }
+
+    async fn perform_maintenance(&self) -> Result<usize, StoreError> {
+        // Redis removes the expired entries itself, only their keys are left in the index
+        let removed: usize = redis::Script::new(REMOVE_EXPIRED_SCRIPT)
+            .key(index_key(&self.name))
+            .arg(entry_key(&self.name, ""))
+            .invoke_async(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        // Entries can't clash with the index, whatever their key
+        assert_eq!(entry_key("sessions", "entries"), "sessions:entry:entries");
+        assert_ne!(entry_key("sessions", "entries"), index_key("sessions"));
+        assert_eq!(metadata_field("store_ttl"), "metadata:store_ttl");
+        assert_ne!(metadata_field("value"), VALUE_FIELD);
+    }
+}
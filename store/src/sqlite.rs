@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use fdo_data_formats::Serializable;
+
+use crate::{FilterType, MetadataLocalKey, MetadataValue, ValueIter};
+
+use super::Store;
+use super::StoreError;
+
+// Migration N brings the schema from version N-1 to version N. The version
+// of a database is kept in its user_version, so only ever append to this list.
+const MIGRATIONS: &[&str] = &[include_str!("../migrations/sqlite/0001_initial.sql")];
+
+pub(super) fn initialize<OT, K, V, MKT>(
+    path: &Path,
+    auto_migrate: bool,
+) -> Result<Box<dyn Store<OT, K, V, MKT>>, StoreError>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    let mut connection = open(path)?;
+    if auto_migrate {
+        migrate(&mut connection, path)?;
+    } else {
+        let version = schema_version(&connection, path)?;
+        if version != MIGRATIONS.len() {
+            return Err(StoreError::Configuration(format!(
+                "Database '{path:?}' has schema version {version}, expected version {}: run 'fdo-admin-tool migrate-store' first",
+                MIGRATIONS.len()
+            )));
+        }
+    }
+
+    Ok(Box::new(SqliteStore {
+        phantom_k: PhantomData,
+        phantom_v: PhantomData,
+
+        connection: Arc::new(Mutex::new(connection)),
+    }))
+}
+
+pub(super) fn migrate_database(path: &Path) -> Result<crate::SchemaMigration, StoreError> {
+    let mut connection = open(path)?;
+    migrate(&mut connection, path)
+}
+
+fn open(path: &Path) -> Result<Connection, StoreError> {
+    if !path.is_absolute() {
+        return Err(StoreError::Configuration(
+            "Database path is not absolute".to_string(),
+        ));
+    }
+    let connection = Connection::open(path).map_err(|e| {
+        StoreError::Configuration(format!("Database '{path:?}' could not be opened: {e}"))
+    })?;
+    connection
+        .execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA foreign_keys = ON;
+             PRAGMA busy_timeout = 5000;",
+        )
+        .map_err(|e| {
+            StoreError::Configuration(format!("Database '{path:?}' could not be set up: {e}"))
+        })?;
+    Ok(connection)
+}
+
+fn schema_version(connection: &Connection, path: &Path) -> Result<usize, StoreError> {
+    let version: usize = connection
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| {
+            StoreError::Configuration(format!(
+                "Schema version of database '{path:?}' could not be read: {e}"
+            ))
+        })?;
+    // Running against a schema we don't know could lose data
+    if version > MIGRATIONS.len() {
+        return Err(StoreError::Configuration(format!(
+            "Database '{path:?}' has schema version {version}, which is newer than the supported version {}",
+            MIGRATIONS.len()
+        )));
+    }
+    Ok(version)
+}
+
+fn migrate(connection: &mut Connection, path: &Path) -> Result<crate::SchemaMigration, StoreError> {
+    let from = schema_version(connection, path)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(from) {
+        let version = index + 1;
+        log::info!(
+            "Migrating database {:?} to schema version {}",
+            path,
+            version
+        );
+        let migrate_error = |e: rusqlite::Error| {
+            StoreError::Configuration(format!(
+                "Database '{path:?}' could not be migrated to schema version {version}: {e}"
+            ))
+        };
+        // Each migration is applied completely, or not at all
+        let transaction = connection.transaction().map_err(migrate_error)?;
+        transaction
+            .execute_batch(migration)
+            .map_err(migrate_error)?;
+        transaction
+            .pragma_update(None, "user_version", version)
+            .map_err(migrate_error)?;
+        transaction.commit().map_err(migrate_error)?;
+    }
+    Ok(crate::SchemaMigration {
+        from,
+        to: MIGRATIONS.len(),
+    })
+}
+
+type SqliteConnection = Arc<Mutex<Connection>>;
+
+#[derive(Debug)]
+struct SqliteStore<K, V> {
+    phantom_k: PhantomData<K>,
+    phantom_v: PhantomData<V>,
+
+    connection: SqliteConnection,
+}
+
+fn lock(connection: &SqliteConnection) -> Result<MutexGuard<'_, Connection>, StoreError> {
+    connection
+        .lock()
+        .map_err(|e| StoreError::Unspecified(format!("Database lock poisoned: {e}")))
+}
+
+fn db_error(e: rusqlite::Error) -> StoreError {
+    StoreError::Unspecified(format!("Database error: {e}"))
+}
+
+fn ttl_from_stored(ttl: &[u8]) -> Option<i64> {
+    Some(i64::from_le_bytes(ttl.try_into().ok()?))
+}
+
+fn is_expired(ttl: Option<Vec<u8>>) -> bool {
+    match ttl.as_deref().and_then(ttl_from_stored) {
+        Some(ttl) => time::OffsetDateTime::now_utc().unix_timestamp() > ttl,
+        None => false,
+    }
+}
+
+fn deserialize<V: Serializable>(key: &str, value: &[u8]) -> Option<V> {
+    match V::deserialize_data(value) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log::trace!("Error deserializing data {}: {}", key, e);
+            None
+        }
+    }
+}
+
+pub struct SqliteStoreFilterType {
+    connection: SqliteConnection,
+    neqs: Vec<(String, Vec<u8>)>,
+    lts: Vec<(String, i64)>,
+}
+
+#[async_trait]
+impl<V, MKT> FilterType<V, MKT> for SqliteStoreFilterType
+where
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: MetadataLocalKey,
+{
+    fn neq(&mut self, key: &crate::MetadataKey<MKT>, expected: &dyn MetadataValue) {
+        self.neqs
+            .push((key.to_key().to_owned(), expected.to_stored().unwrap()));
+    }
+    fn lt(&mut self, key: &crate::MetadataKey<MKT>, max: i64) {
+        self.lts.push((key.to_key().to_owned(), max));
+    }
+    async fn query(&self) -> Result<crate::FilterQueryResult<V>, StoreError> {
+        let connection = lock(&self.connection)?;
+
+        let mut metadata: HashMap<String, HashMap<String, Vec<u8>>> = HashMap::new();
+        let mut statement = connection
+            .prepare("SELECT key, name, value FROM metadata")
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(db_error)?;
+        for row in rows {
+            let (key, name, value): (String, String, Vec<u8>) = row.map_err(db_error)?;
+            metadata.entry(key).or_default().insert(name, value);
+        }
+
+        // Same semantics as the directory store: an entry matches if any of the
+        // neq filters differs, and then any of the lt filters is below the maximum
+        let no_metadata = HashMap::new();
+        let mut values = Vec::new();
+        let mut statement = connection
+            .prepare("SELECT key, value FROM entries")
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(db_error)?;
+        for row in rows {
+            let (key, value): (String, Vec<u8>) = row.map_err(db_error)?;
+            let entry_metadata = metadata.get(&key).unwrap_or(&no_metadata);
+            let neq_matches = self
+                .neqs
+                .iter()
+                .any(|(name, expected)| entry_metadata.get(name) != Some(expected));
+            let lt_matches = self
+                .lts
+                .iter()
+                .any(|(name, max)| match entry_metadata.get(name) {
+                    Some(v) => matches!(ttl_from_stored(v), Some(value) if value < *max),
+                    None => true,
+                });
+            if !neq_matches || !lt_matches {
+                continue;
+            }
+            if let Some(value) = deserialize(&key, &value) {
+                values.push(value);
+            }
+        }
+        Ok(Some(ValueIter {
+            index: 0,
+            values,
+            errored: false,
+        }))
+    }
+}
+
+#[async_trait]
+impl<OT, K, V, MKT> Store<OT, K, V, MKT> for SqliteStore<K, V>
+where
+    OT: crate::StoreOpenMode,
+    K: std::string::ToString + Send + Sync + 'static,
+    V: Serializable + Send + Sync + Clone + 'static,
+    MKT: crate::MetadataLocalKey + 'static,
+{
+    async fn load_data(&self, key: &K) -> Result<Option<V>, StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to load data for {}", key);
+
+        let connection = lock(&self.connection)?;
+        let row: Option<(Vec<u8>, Option<Vec<u8>>)> = connection
+            .query_row(
+                "SELECT entries.value, metadata.value FROM entries
+                 LEFT JOIN metadata ON metadata.key = entries.key AND metadata.name = ?2
+                 WHERE entries.key = ?1",
+                params![key, crate::MetadataKey::<MKT>::Ttl.to_key()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(db_error)?;
+        let (value, ttl) = match row {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        if is_expired(ttl) {
+            log::trace!("Item has expired, attempting removal");
+            if let Err(e) = connection.execute("DELETE FROM entries WHERE key = ?1", [&key]) {
+                log::info!("Error deleting expired entry {}: {}", key, e);
+            }
+            return Ok(None);
+        }
+
+        Ok(Some(V::deserialize_data(&value).map_err(|e| {
+            StoreError::Unspecified(format!("Error deserializing value: {e:?}"))
+        })?))
+    }
+
+    async fn load_all_data(&self) -> Result<Vec<V>, StoreError> {
+        log::trace!("Attempting to load all data");
+
+        let connection = lock(&self.connection)?;
+        let mut statement = connection
+            .prepare(
+                "SELECT entries.key, entries.value, metadata.value FROM entries
+                 LEFT JOIN metadata ON metadata.key = entries.key AND metadata.name = ?1",
+            )
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map([crate::MetadataKey::<MKT>::Ttl.to_key()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .map_err(db_error)?;
+        let mut values = Vec::new();
+        for row in rows {
+            let (key, value, ttl): (String, Vec<u8>, Option<Vec<u8>>) = row.map_err(db_error)?;
+            if is_expired(ttl) {
+                continue;
+            }
+            if let Some(value) = deserialize(&key, &value) {
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    async fn store_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+        metadata_value: &dyn MetadataValue,
+    ) -> Result<(), StoreError> {
+        let value = metadata_value.to_stored()?;
+        // Metadata of entries that don't exist is ignored, like the other stores do
+        lock(&self.connection)?
+            .execute(
+                "INSERT OR REPLACE INTO metadata (key, name, value)
+                 SELECT key, ?2, ?3 FROM entries WHERE key = ?1",
+                params![key.to_string(), metadata_key.to_key(), value],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn destroy_metadata(
+        &self,
+        key: &K,
+        metadata_key: &crate::MetadataKey<MKT>,
+    ) -> Result<(), StoreError> {
+        lock(&self.connection)?
+            .execute(
+                "DELETE FROM metadata WHERE key = ?1 AND name = ?2",
+                params![key.to_string(), metadata_key.to_key()],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn query_data(&self) -> crate::QueryResult<V, MKT> {
+        Ok(Box::new(SqliteStoreFilterType {
+            connection: self.connection.clone(),
+            neqs: Vec::new(),
+            lts: Vec::new(),
+        }))
+    }
+
+    async fn store_data(&self, key: K, value: V) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to store data for {}", key);
+
+        let value = value.serialize_data().map_err(|e| {
+            StoreError::Unspecified(format!("Error serializing value for {key}: {e:?}"))
+        })?;
+        let mut connection = lock(&self.connection)?;
+        // Replacing a value drops its metadata, just like replacing a file does
+        let transaction = connection.transaction().map_err(db_error)?;
+        transaction
+            .execute("DELETE FROM entries WHERE key = ?1", [&key])
+            .map_err(db_error)?;
+        transaction
+            .execute(
+                "INSERT INTO entries (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )
+            .map_err(db_error)?;
+        transaction.commit().map_err(db_error)
+    }
+
+    async fn destroy_data(&self, key: &K) -> Result<(), StoreError> {
+        let key = key.to_string();
+        log::trace!("Attempting to delete data for {}", key);
+
+        let removed = lock(&self.connection)?
+            .execute("DELETE FROM entries WHERE key = ?1", [&key])
+            .map_err(db_error)?;
+        if removed == 0 {
+            return Err(StoreError::Unspecified(format!(
+                "Error removing '{key}': not found"
+            )));
+        }
+        Ok(())
+    }
+
+    async fn perform_maintenance(&self) -> Result<usize, StoreError> {
+        let mut connection = lock(&self.connection)?;
+        let transaction = connection.transaction().map_err(db_error)?;
+        let expired: Vec<String> = {
+            let mut statement = transaction
+                .prepare("SELECT key, value FROM metadata WHERE name = ?1")
+                .map_err(db_error)?;
+            let rows = statement
+                .query_map([crate::MetadataKey::<MKT>::Ttl.to_key()], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .map_err(db_error)?;
+            let mut expired = Vec::new();
+            for row in rows {
+                let (key, ttl): (String, Vec<u8>) = row.map_err(db_error)?;
+                if is_expired(Some(ttl)) {
+                    expired.push(key);
+                }
+            }
+            expired
+        };
+        for key in &expired {
+            log::trace!("Entry {} has expired, removing", key);
+            transaction
+                .execute("DELETE FROM entries WHERE key = ?1", [key])
+                .map_err(db_error)?;
+        }
+        transaction.commit().map_err(db_error)?;
+        Ok(expired.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let path = Path::new(":memory:");
+        let mut connection = Connection::open_in_memory().unwrap();
+        let migration = migrate(&mut connection, path).unwrap();
+        assert_eq!(migration.from, 0);
+        assert_eq!(migration.to, MIGRATIONS.len());
+
+        // Migrating again is a no-op
+        let migration = migrate(&mut connection, path).unwrap();
+        assert_eq!(migration.from, MIGRATIONS.len());
+        assert_eq!(schema_version(&connection, path).unwrap(), MIGRATIONS.len());
+
+        // Newer schemas are refused
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(schema_version(&connection, path).is_err());
+        assert!(migrate(&mut connection, path).is_err());
+    }
+}
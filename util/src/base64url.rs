@@ -0,0 +1,40 @@
+//! Unpadded base64url encoding (RFC 4648 section 5), as used by JOSE
+
+use anyhow::{Context, Result};
+use openssl::base64;
+
+pub(crate) fn encode(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+pub(crate) fn decode(data: &str) -> Result<Vec<u8>> {
+    let mut data = data.replace('-', "+").replace('_', "/");
+    while data.len() % 4 != 0 {
+        data.push('=');
+    }
+    base64::decode_block(&data).context("Invalid base64url encoding")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn test_base64url() {
+        let data = b"\xfb\xff\xbf fdo";
+        let encoded = encode(data);
+        assert!(!encoded.contains(['+', '/', '=']));
+        assert_eq!(decode(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn test_base64url_padding() {
+        for len in 0..4 {
+            let data = vec![0xa5; len];
+            assert_eq!(decode(&encode(&data)).unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Deployment wide restrictions on the cryptographic algorithms in use
+//!
+//! A crypto policy lists the key types, hash algorithms, key exchange suites and cipher
+//! suites that are allowed. Servers check their own keys against it when starting, and
+//! refuse keys, certificates, vouchers and sessions using anything else. The client and
+//! the owner tool load the same settings from a file of their own.
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use openssl::{
+    nid::Nid,
+    pkey::{HasPublic, Id, PKeyRef},
+    x509::X509,
+};
+
+use fdo_data_formats::{
+    constants::HashType,
+    ownershipvoucher::{OwnershipVoucher, OwnershipVoucherEntryPayload},
+    publickey::PublicKey,
+    types::{CipherSuite, KexSuite},
+};
+
+use crate::servers::configuration::{
+    CryptoCipherSuite, CryptoHashType, CryptoKexSuite, CryptoKeyType, CryptoPolicySettings,
+};
+
+/// A use of an algorithm the crypto policy does not allow
+#[derive(Debug)]
+pub enum CryptoPolicyViolation {
+    KeyType(Option<CryptoKeyType>),
+    HashType(Option<CryptoHashType>),
+    KeyExchangeSuite(CryptoKexSuite),
+    CipherSuite(CryptoCipherSuite),
+}
+
+impl fmt::Display for CryptoPolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoPolicyViolation::KeyType(Some(key_type)) => write!(
+                f,
+                "key type {} is not allowed by the crypto policy",
+                format!("{key_type:?}").to_lowercase()
+            ),
+            CryptoPolicyViolation::KeyType(None) => {
+                write!(f, "unknown key type is not allowed by the crypto policy")
+            }
+            CryptoPolicyViolation::HashType(Some(hash_type)) => write!(
+                f,
+                "hash algorithm {} is not allowed by the crypto policy",
+                format!("{hash_type:?}").to_lowercase()
+            ),
+            CryptoPolicyViolation::HashType(None) => write!(
+                f,
+                "unknown hash algorithm is not allowed by the crypto policy"
+            ),
+            CryptoPolicyViolation::KeyExchangeSuite(suite) => write!(
+                f,
+                "key exchange suite {} is not allowed by the crypto policy",
+                match suite {
+                    CryptoKexSuite::Ecdh256 => "ECDH256",
+                    CryptoKexSuite::Ecdh384 => "ECDH384",
+                    CryptoKexSuite::DhkexId14 => "DHKEXid14",
+                    CryptoKexSuite::DhkexId15 => "DHKEXid15",
+                    CryptoKexSuite::Asymkex2048 => "ASYMKEX2048",
+                    CryptoKexSuite::Asymkex3072 => "ASYMKEX3072",
+                }
+            ),
+            CryptoPolicyViolation::CipherSuite(suite) => write!(
+                f,
+                "cipher suite {} is not allowed by the crypto policy",
+                match suite {
+                    CryptoCipherSuite::A128Gcm => "A128GCM",
+                    CryptoCipherSuite::A256Gcm => "A256GCM",
+                    CryptoCipherSuite::AesCcm64_128_128 => "AES-CCM-64-128-128",
+                    CryptoCipherSuite::AesCcm64_128_256 => "AES-CCM-64-128-256",
+                }
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CryptoPolicyViolation {}
+
+pub fn key_type<T: HasPublic>(key: &PKeyRef<T>) -> Option<CryptoKeyType> {
+    match key.id() {
+        Id::EC => match key.ec_key().ok()?.group().curve_name()? {
+            Nid::X9_62_PRIME256V1 => Some(CryptoKeyType::Secp256r1),
+            Nid::SECP384R1 => Some(CryptoKeyType::Secp384r1),
+            _ => None,
+        },
+        Id::RSA => match key.bits() {
+            2048 => Some(CryptoKeyType::Rsa2048),
+            3072 => Some(CryptoKeyType::Rsa3072),
+            4096 => Some(CryptoKeyType::Rsa4096),
+            _ => None,
+        },
+        Id::ED25519 => Some(CryptoKeyType::Ed25519),
+        _ => None,
+    }
+}
+
+fn hash_type(hash_type: HashType) -> Option<CryptoHashType> {
+    match hash_type {
+        HashType::Sha256 | HashType::HmacSha256 => Some(CryptoHashType::Sha256),
+        HashType::Sha384 | HashType::HmacSha384 => Some(CryptoHashType::Sha384),
+        _ => None,
+    }
+}
+
+fn kex_suite(suite: KexSuite) -> CryptoKexSuite {
+    match suite {
+        KexSuite::Ecdh256 => CryptoKexSuite::Ecdh256,
+        KexSuite::Ecdh384 => CryptoKexSuite::Ecdh384,
+        KexSuite::DhkexId14 => CryptoKexSuite::DhkexId14,
+        KexSuite::DhkexId15 => CryptoKexSuite::DhkexId15,
+        KexSuite::Asymkex2048 => CryptoKexSuite::Asymkex2048,
+        KexSuite::Asymkex3072 => CryptoKexSuite::Asymkex3072,
+    }
+}
+
+fn cipher_suite(suite: CipherSuite) -> CryptoCipherSuite {
+    match suite {
+        CipherSuite::A128Gcm => CryptoCipherSuite::A128Gcm,
+        CipherSuite::A256Gcm => CryptoCipherSuite::A256Gcm,
+        CipherSuite::AesCcm64_128_128 => CryptoCipherSuite::AesCcm64_128_128,
+        CipherSuite::AesCcm64_128_256 => CryptoCipherSuite::AesCcm64_128_256,
+    }
+}
+
+fn is_allowed<T: PartialEq>(allowed: &Option<Vec<T>>, value: &T) -> bool {
+    allowed
+        .as_ref()
+        .map_or(true, |allowed| allowed.contains(value))
+}
+
+#[derive(Debug)]
+pub struct CryptoPolicy {
+    allowed_key_types: Option<Vec<CryptoKeyType>>,
+    allowed_hash_types: Option<Vec<CryptoHashType>>,
+    allowed_key_exchange_suites: Option<Vec<CryptoKexSuite>>,
+    allowed_cipher_suites: Option<Vec<CryptoCipherSuite>>,
+}
+
+impl CryptoPolicy {
+    /// Validates the policy settings, refusing allow lists that can never be satisfied
+    pub fn from_settings(settings: &CryptoPolicySettings) -> Result<Self> {
+        let empty = [
+            (
+                "allowed_key_types",
+                settings.allowed_key_types.as_ref().map(Vec::len),
+            ),
+            (
+                "allowed_hash_types",
+                settings.allowed_hash_types.as_ref().map(Vec::len),
+            ),
+            (
+                "allowed_key_exchange_suites",
+                settings.allowed_key_exchange_suites.as_ref().map(Vec::len),
+            ),
+            (
+                "allowed_cipher_suites",
+                settings.allowed_cipher_suites.as_ref().map(Vec::len),
+            ),
+        ];
+        for (name, len) in empty {
+            if len == Some(0) {
+                bail!("Crypto policy {} is empty, nothing would be allowed", name);
+            }
+        }
+        // Every certificate and voucher needs SHA256 or SHA384
+        if let Some(allowed) = &settings.allowed_hash_types {
+            if !allowed.contains(&CryptoHashType::Sha256)
+                && !allowed.contains(&CryptoHashType::Sha384)
+            {
+                bail!("Crypto policy allowed_hash_types needs to include sha256 or sha384");
+            }
+        }
+
+        Ok(CryptoPolicy {
+            allowed_key_types: settings.allowed_key_types.clone(),
+            allowed_hash_types: settings.allowed_hash_types.clone(),
+            allowed_key_exchange_suites: settings.allowed_key_exchange_suites.clone(),
+            allowed_cipher_suites: settings.allowed_cipher_suites.clone(),
+        })
+    }
+
+    /// Loads a policy from a YAML file, with the settings of a server `crypto_policy` section
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path)
+            .with_context(|| format!("Error reading crypto policy {}", path.display()))?;
+        let settings: CryptoPolicySettings = serde_yaml::from_slice(&contents)
+            .with_context(|| format!("Error parsing crypto policy {}", path.display()))?;
+        Self::from_settings(&settings)
+    }
+
+    pub fn check_key<T: HasPublic>(&self, key: &PKeyRef<T>) -> Result<(), CryptoPolicyViolation> {
+        if self.allowed_key_types.is_none() {
+            return Ok(());
+        }
+        match key_type(key) {
+            Some(key_type) if is_allowed(&self.allowed_key_types, &key_type) => Ok(()),
+            key_type => Err(CryptoPolicyViolation::KeyType(key_type)),
+        }
+    }
+
+    pub fn check_hash_type(&self, hash: HashType) -> Result<(), CryptoPolicyViolation> {
+        if self.allowed_hash_types.is_none() {
+            return Ok(());
+        }
+        match hash_type(hash) {
+            Some(hash) if is_allowed(&self.allowed_hash_types, &hash) => Ok(()),
+            hash => Err(CryptoPolicyViolation::HashType(hash)),
+        }
+    }
+
+    pub fn check_kex_suite(&self, suite: KexSuite) -> Result<(), CryptoPolicyViolation> {
+        let suite = kex_suite(suite);
+        if is_allowed(&self.allowed_key_exchange_suites, &suite) {
+            Ok(())
+        } else {
+            Err(CryptoPolicyViolation::KeyExchangeSuite(suite))
+        }
+    }
+
+    pub fn check_cipher_suite(&self, suite: CipherSuite) -> Result<(), CryptoPolicyViolation> {
+        let suite = cipher_suite(suite);
+        if is_allowed(&self.allowed_cipher_suites, &suite) {
+            Ok(())
+        } else {
+            Err(CryptoPolicyViolation::CipherSuite(suite))
+        }
+    }
+
+    /// Checks the key of a certificate, and the digest it is signed with
+    pub fn check_certificate(&self, cert: &X509) -> Result<(), CryptoPolicyViolation> {
+        let key = cert
+            .public_key()
+            .map_err(|_| CryptoPolicyViolation::KeyType(None))?;
+        self.check_key(&key)?;
+
+        if self.allowed_hash_types.is_none() {
+            return Ok(());
+        }
+        let digest = cert
+            .signature_algorithm()
+            .object()
+            .nid()
+            .signature_algorithms()
+            .map(|algorithms| algorithms.digest);
+        let digest = match digest {
+            // EdDSA signatures have no separate digest
+            Some(Nid::UNDEF) => return Ok(()),
+            Some(Nid::SHA256) => Some(CryptoHashType::Sha256),
+            Some(Nid::SHA384) => Some(CryptoHashType::Sha384),
+            Some(Nid::SHA512) => Some(CryptoHashType::Sha512),
+            _ => None,
+        };
+        match digest {
+            Some(digest) if is_allowed(&self.allowed_hash_types, &digest) => Ok(()),
+            digest => Err(CryptoPolicyViolation::HashType(digest)),
+        }
+    }
+
+    pub fn check_certificates(&self, certs: &[X509]) -> Result<()> {
+        for (pos, cert) in certs.iter().enumerate() {
+            self.check_certificate(cert)
+                .with_context(|| format!("Certificate {pos}"))?;
+        }
+        Ok(())
+    }
+
+    /// Checks a public key, and its certificate chain if it has one
+    pub fn check_public_key(&self, key: &PublicKey) -> Result<()> {
+        self.check_key(key.pkey())?;
+        if let Some(chain) = key.chain() {
+            self.check_certificates(chain.chain())?;
+        }
+        Ok(())
+    }
+
+    /// Checks the keys, certificates and hashes of every part of a voucher
+    pub fn check_voucher(&self, ov: &OwnershipVoucher) -> Result<()> {
+        let header = ov.header();
+        self.check_public_key(header.manufacturer_public_key())
+            .context("Manufacturer public key")?;
+        self.check_hash_type(ov.header_hmac().get_type())
+            .context("Header HMAC")?;
+        if let Some(hash) = header.device_certificate_chain_hash() {
+            self.check_hash_type(hash.get_type())
+                .context("Device certificate chain hash")?;
+        }
+        if let Some(chain) = ov.device_certificate_chain() {
+            self.check_certificates(chain.chain())
+                .context("Device certificate chain")?;
+        }
+
+        for pos in 0..ov.num_entries() as usize {
+            let entry = ov
+                .entry(pos)?
+                .get_payload_unverified::<OwnershipVoucherEntryPayload>()?;
+            let entry = entry.get_unverified_value();
+            self.check_hash_type(entry.hash_previous_entry().get_type())
+                .with_context(|| format!("Entry {pos} previous entry hash"))?;
+            self.check_hash_type(entry.hash_header_info().get_type())
+                .with_context(|| format!("Entry {pos} header info hash"))?;
+            self.check_public_key(entry.public_key())
+                .with_context(|| format!("Entry {pos} public key"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        nid::Nid,
+        pkey::PKey,
+    };
+
+    use fdo_data_formats::{constants::HashType, types::KexSuite};
+
+    use super::CryptoPolicy;
+    use crate::servers::configuration::{
+        CryptoHashType, CryptoKexSuite, CryptoKeyType, CryptoPolicySettings,
+    };
+
+    fn ec_key(curve: Nid) -> PKey<openssl::pkey::Private> {
+        let group = EcGroup::from_curve_name(curve).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_allow_lists() {
+        let policy = CryptoPolicy::from_settings(&CryptoPolicySettings {
+            allowed_key_types: Some(vec![CryptoKeyType::Secp384r1]),
+            allowed_hash_types: Some(vec![CryptoHashType::Sha384]),
+            allowed_key_exchange_suites: Some(vec![CryptoKexSuite::Ecdh384]),
+            allowed_cipher_suites: None,
+        })
+        .unwrap();
+
+        assert!(policy.check_key(&ec_key(Nid::SECP384R1)).is_ok());
+        let violation = policy
+            .check_key(&ec_key(Nid::X9_62_PRIME256V1))
+            .unwrap_err();
+        assert_eq!(
+            violation.to_string(),
+            "key type secp256r1 is not allowed by the crypto policy"
+        );
+
+        assert!(policy.check_hash_type(HashType::HmacSha384).is_ok());
+        assert!(policy.check_hash_type(HashType::Sha256).is_err());
+        assert!(policy.check_kex_suite(KexSuite::Ecdh384).is_ok());
+        assert!(policy.check_kex_suite(KexSuite::DhkexId14).is_err());
+    }
+
+    #[test]
+    fn test_unset_lists_allow_everything() {
+        let policy = CryptoPolicy::from_settings(&CryptoPolicySettings::default()).unwrap();
+        assert!(policy.check_key(&ec_key(Nid::SECP521R1)).is_ok());
+        assert!(policy.check_hash_type(HashType::Sha256).is_ok());
+    }
+
+    #[test]
+    fn test_unsatisfiable_policy() {
+        assert!(CryptoPolicy::from_settings(&CryptoPolicySettings {
+            allowed_key_types: Some(vec![]),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(CryptoPolicy::from_settings(&CryptoPolicySettings {
+            allowed_hash_types: Some(vec![CryptoHashType::Sha512]),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
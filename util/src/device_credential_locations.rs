@@ -1,10 +1,15 @@
 use std::env;
 use std::fs;
+use std::io::Read;
 use std::path::Path;
 
 use anyhow::{anyhow, Context, Result};
 
-use fdo_data_formats::{devicecredential::FileDeviceCredential, DeviceCredential, Serializable};
+use fdo_data_formats::{
+    devicecredential::FileDeviceCredential,
+    types::{Guid, Hash, RendezvousInfo},
+    DeviceCredential,
+};
 
 pub fn find() -> Option<Result<Box<dyn UsableDeviceCredentialLocation>>> {
     let device_credential_locations: &[Box<dyn DeviceCredentialLocation>] = &[
@@ -13,9 +18,15 @@ pub fn find() -> Option<Result<Box<dyn UsableDeviceCredentialLocation>>> {
                 .to_string(),
             deactivation_method: DeactivationMethod::None,
         }),
+        Box::new(KernelCommandLine {
+            parameter: "fdo.devicecredential".to_string(),
+        }),
         Box::new(FileSystemPathEnv {
             env_var: "DEVICE_CREDENTIAL".to_string(),
         }),
+        Box::new(EfiVariable {
+            env_var: "DEVICE_CREDENTIAL_EFI_VARIABLE".to_string(),
+        }),
         Box::new(FileSystemPath {
             path: "/etc/device-credentials".to_string(),
             deactivation_method: DeactivationMethod::Deactivate,
@@ -47,6 +58,8 @@ pub trait DeviceCredentialLocation: std::fmt::Debug {
 pub trait UsableDeviceCredentialLocation: DeviceCredentialLocation {
     fn read(&self) -> Result<Box<dyn DeviceCredential>>;
     fn deactivate(&self) -> Result<()>;
+    fn reactivate(&self) -> Result<()>;
+    fn replace(&self, guid: Guid, rvinfo: RendezvousInfo, pubkey_hash: Hash) -> Result<()>;
 }
 
 #[derive(Debug, Clone)]
@@ -70,7 +83,7 @@ impl UsableDeviceCredentialLocation for FileSystemPath {
     fn read(&self) -> Result<Box<dyn DeviceCredential>> {
         let contents = fs::read(&self.path)
             .with_context(|| format!("Error reading (device credential) file at {}", &self.path))?;
-        let fdc = FileDeviceCredential::deserialize_data(&contents)
+        let fdc = FileDeviceCredential::from_file_data(&contents)
             .with_context(|| format!("Error parsing device credential from {}", &self.path))?;
         Ok(Box::new(fdc))
     }
@@ -80,24 +93,54 @@ impl UsableDeviceCredentialLocation for FileSystemPath {
             DeactivationMethod::None => Ok(()),
             DeactivationMethod::Delete => fs::remove_file(&self.path)
                 .with_context(|| format!("Error deleting file at {}", &self.path)),
-            DeactivationMethod::Deactivate => self.perform_deactivation(),
+            DeactivationMethod::Deactivate => self.set_active(false),
         }
     }
+
+    fn reactivate(&self) -> Result<()> {
+        self.set_active(true)
+    }
+
+    fn replace(&self, guid: Guid, rvinfo: RendezvousInfo, pubkey_hash: Hash) -> Result<()> {
+        if !Path::new(&self.path).exists() {
+            log::info!(
+                "Device credential at {} was deleted, not storing replacement",
+                &self.path
+            );
+            return Ok(());
+        }
+        let contents = fs::read(&self.path)
+            .with_context(|| format!("Error reading (device credential) file at {}", &self.path))?;
+        let mut fdc = FileDeviceCredential::from_file_data(&contents)
+            .with_context(|| format!("Error parsing device credential from {}", &self.path))?;
+
+        fdc.guid = guid;
+        fdc.rvinfo = rvinfo;
+        fdc.pubkey_hash = pubkey_hash;
+        let new_dc_contents = fdc
+            .to_file_data()
+            .context("Error serializing device credential")?;
+        self.write(new_dc_contents)
+            .context("Error writing out replaced device credential")
+    }
 }
 
 impl FileSystemPath {
-    fn perform_deactivation(&self) -> Result<()> {
+    fn set_active(&self, active: bool) -> Result<()> {
         let contents = fs::read(&self.path)
             .with_context(|| format!("Error reading (device credential) file at {}", &self.path))?;
-        let mut fdc = FileDeviceCredential::deserialize_data(&contents)
+        let mut fdc = FileDeviceCredential::from_file_data(&contents)
             .with_context(|| format!("Error parsing device credential from {}", &self.path))?;
 
-        fdc.active = false;
+        if fdc.active == active {
+            return Ok(());
+        }
+        fdc.active = active;
         let new_dc_contents = fdc
-            .serialize_data()
-            .context("Error serializing deactivating device credential")?;
+            .to_file_data()
+            .context("Error serializing device credential")?;
         self.write(new_dc_contents)
-            .context("Error writing out new device credential for deactivation")
+            .with_context(|| format!("Error writing out new device credential (active: {active})"))
     }
 
     fn write(&self, new_contents: Vec<u8>) -> Result<()> {
@@ -120,6 +163,24 @@ impl DeviceCredentialLocation for FileSystemPathEnv {
                 Err(_) => return Some(Err(anyhow!("Invalid environment variable value"))),
             },
         };
+        if env_val == "-" {
+            log::trace!(
+                "Resolved environment variable {} to standard input",
+                &self.env_var
+            );
+            let mut contents = Vec::new();
+            return Some(
+                std::io::stdin()
+                    .read_to_end(&mut contents)
+                    .context("Error reading device credential from standard input")
+                    .map(|_| {
+                        Box::new(ReadOnlyCredential {
+                            source: "standard input".to_string(),
+                            contents,
+                        }) as Box<dyn UsableDeviceCredentialLocation>
+                    }),
+            );
+        }
         let deactivation_method = match env::var_os(format!("{}_DELETE", &self.env_var)) {
             None => match env::var_os(format!("{}_DEACTIVATE", &self.env_var)) {
                 None => DeactivationMethod::None,
@@ -141,3 +202,146 @@ impl DeviceCredentialLocation for FileSystemPathEnv {
         .resolve()
     }
 }
+
+const KERNEL_COMMAND_LINE: &str = "/proc/cmdline";
+
+/// Returns the value of `parameter` on the kernel command line `cmdline`.
+fn kernel_cmdline_value<'a>(cmdline: &'a str, parameter: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|arg| arg.split_once('='))
+        .find(|(key, _)| *key == parameter)
+        .map(|(_, value)| value)
+}
+
+/// A filesystem path passed on the kernel command line, e.g. `fdo.devicecredential=/path`
+#[derive(Debug)]
+struct KernelCommandLine {
+    parameter: String,
+}
+
+impl DeviceCredentialLocation for KernelCommandLine {
+    fn resolve(&self) -> Option<Result<Box<dyn UsableDeviceCredentialLocation>>> {
+        let cmdline = match fs::read_to_string(KERNEL_COMMAND_LINE) {
+            Ok(cmdline) => cmdline,
+            Err(e) => {
+                log::trace!("Unable to read kernel command line: {:?}", e);
+                return None;
+            }
+        };
+        let path = kernel_cmdline_value(&cmdline, &self.parameter)?;
+        log::trace!(
+            "Resolved kernel command line parameter {} to filesystem path {}",
+            &self.parameter,
+            path
+        );
+
+        match (FileSystemPath {
+            path: path.to_string(),
+            deactivation_method: DeactivationMethod::None,
+        })
+        .resolve()
+        {
+            Some(v) => Some(v),
+            None => Some(Err(anyhow!(
+                "No device credential at {} (from kernel command line)",
+                path
+            ))),
+        }
+    }
+}
+
+const EFI_VARIABLES_DIR: &str = "/sys/firmware/efi/efivars";
+
+/// An EFI variable named in an environment variable as `<name>-<vendor GUID>`
+#[derive(Debug)]
+struct EfiVariable {
+    env_var: String,
+}
+
+impl DeviceCredentialLocation for EfiVariable {
+    fn resolve(&self) -> Option<Result<Box<dyn UsableDeviceCredentialLocation>>> {
+        let variable = env::var(&self.env_var).ok()?;
+        let path = Path::new(EFI_VARIABLES_DIR).join(&variable);
+        log::trace!(
+            "Resolved environment variable {} to EFI variable {:?}",
+            &self.env_var,
+            &path
+        );
+
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Some(
+                    Err(e).with_context(|| format!("Error reading EFI variable {}", &variable)),
+                )
+            }
+        };
+        // The contents start with the 32 bits of attributes of the variable
+        if contents.len() < 4 {
+            return Some(Err(anyhow!("EFI variable {} is too short", &variable)));
+        }
+        Some(Ok(Box::new(ReadOnlyCredential {
+            source: format!("EFI variable {variable}"),
+            contents: contents[4..].to_vec(),
+        })))
+    }
+}
+
+/// A device credential read from a source that can't be written to
+#[derive(Debug)]
+struct ReadOnlyCredential {
+    source: String,
+    contents: Vec<u8>,
+}
+
+impl DeviceCredentialLocation for ReadOnlyCredential {
+    fn resolve(&self) -> Option<Result<Box<dyn UsableDeviceCredentialLocation>>> {
+        None
+    }
+}
+
+impl UsableDeviceCredentialLocation for ReadOnlyCredential {
+    fn read(&self) -> Result<Box<dyn DeviceCredential>> {
+        let fdc = FileDeviceCredential::from_file_data(&self.contents)
+            .with_context(|| format!("Error parsing device credential from {}", &self.source))?;
+        Ok(Box::new(fdc))
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        log::debug!(
+            "Device credential from {} is read-only, not deactivating",
+            &self.source
+        );
+        Ok(())
+    }
+
+    fn reactivate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn replace(&self, _guid: Guid, _rvinfo: RendezvousInfo, _pubkey_hash: Hash) -> Result<()> {
+        log::warn!(
+            "Device credential from {} is read-only, not storing replacement",
+            &self.source
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::kernel_cmdline_value;
+
+    #[test]
+    fn test_kernel_cmdline_value() {
+        let cmdline = "BOOT_IMAGE=/vmlinuz root=/dev/vda1 ro fdo.devicecredential=/boot/dc quiet\n";
+        assert_eq!(
+            kernel_cmdline_value(cmdline, "fdo.devicecredential"),
+            Some("/boot/dc")
+        );
+        assert_eq!(kernel_cmdline_value(cmdline, "root"), Some("/dev/vda1"));
+        assert_eq!(kernel_cmdline_value(cmdline, "ro"), None);
+        assert_eq!(kernel_cmdline_value(cmdline, "fdo"), None);
+    }
+}
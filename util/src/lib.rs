@@ -1,7 +1,13 @@
+mod base64url;
+pub mod crypto_policy;
 pub mod device_credential_locations;
 pub mod device_identification;
+pub mod onboarding;
+pub mod ondie;
 pub mod passwd_shadow;
+pub mod revocation;
 pub mod servers;
+pub mod signing;
 
 pub fn maybe_print_version(
     name: &'static str,
@@ -20,6 +26,27 @@ pub fn maybe_print_version(
     }
 }
 
+/// Exit status of a client that failed with `err`.
+///
+/// This is 2 if a server rejected a request with an FDO error message, and 1 otherwise.
+pub fn exit_status(err: &anyhow::Error) -> i32 {
+    let error_message = err
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<fdo_http_wrapper::client::Error>())
+        .find_map(|cause| cause.error_message());
+    match error_message {
+        Some(error_message) => {
+            log::error!(
+                "Server returned error code {:?}: {}",
+                error_message.error_code(),
+                error_message.error_string()
+            );
+            2
+        }
+        None => 1,
+    }
+}
+
 #[macro_export]
 macro_rules! add_version {
     () => {
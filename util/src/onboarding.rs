@@ -0,0 +1,75 @@
+//! Cheap checks to decide whether a device still needs to be onboarded, without
+//! contacting any server.
+
+use std::{env, fmt, path::Path, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::device_credential_locations;
+
+const DEVICE_ONBOARDING_EXECUTED_MARKER_FILE: &str = "/etc/device_onboarding_performed";
+const TPM_DEVICES: &[&str] = &["/dev/tpmrm0", "/dev/tpm0"];
+
+/// Location of the marker file created after a successful onboarding, which can
+/// be overridden with `DEVICE_ONBOARDING_EXECUTED_MARKER_FILE_PATH`.
+pub fn marker_file_location() -> PathBuf {
+    if let Ok(path) = env::var("DEVICE_ONBOARDING_EXECUTED_MARKER_FILE_PATH") {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from(DEVICE_ONBOARDING_EXECUTED_MARKER_FILE)
+    }
+}
+
+fn tpm_available() -> bool {
+    TPM_DEVICES.iter().any(|dev| Path::new(dev).exists())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnboardingStatus {
+    Required,
+    AlreadyOnboarded,
+    NoDeviceCredential,
+    CredentialInactive,
+    TpmUnavailable,
+}
+
+impl fmt::Display for OnboardingStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnboardingStatus::Required => write!(f, "onboarding required"),
+            OnboardingStatus::AlreadyOnboarded => write!(f, "already onboarded"),
+            OnboardingStatus::NoDeviceCredential => write!(f, "no device credential found"),
+            OnboardingStatus::CredentialInactive => write!(f, "device credential inactive"),
+            OnboardingStatus::TpmUnavailable => {
+                write!(
+                    f,
+                    "device credential keys are in a TPM, but no TPM is available"
+                )
+            }
+        }
+    }
+}
+
+/// Checks whether the device should be onboarded: it has no onboarding marker
+/// file, and has an active device credential whose keys are usable.
+pub fn check() -> Result<OnboardingStatus> {
+    if marker_file_location().exists() {
+        return Ok(OnboardingStatus::AlreadyOnboarded);
+    }
+
+    let devcred_location = match device_credential_locations::find() {
+        None => return Ok(OnboardingStatus::NoDeviceCredential),
+        Some(devcred_location) => devcred_location?,
+    };
+    let dc = devcred_location
+        .read()
+        .context("Error reading device credential")?;
+
+    if !dc.is_active() {
+        Ok(OnboardingStatus::CredentialInactive)
+    } else if dc.requires_tpm() && !tpm_available() {
+        Ok(OnboardingStatus::TpmUnavailable)
+    } else {
+        Ok(OnboardingStatus::Required)
+    }
+}
@@ -0,0 +1,199 @@
+//! Intel OnDie ECDSA device attestation
+//!
+//! Devices using OnDie ECDSA carry a device certificate chain that is rooted in the
+//! Intel OnDie CA. The CA certificates and the CRLs for every level of the chain are
+//! kept in a local cache directory, which can be refreshed from a list of URLs.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use openssl::{
+    asn1::Asn1Time,
+    x509::{CrlStatus, X509Crl, X509VerifyResult, X509},
+};
+
+use fdo_data_formats::publickey::X5Chain;
+
+use crate::servers::configuration::OnDieSettings;
+
+const CERT_EXTENSIONS: &[&str] = &["cer", "crt", "pem"];
+const CRL_EXTENSION: &str = "crl";
+
+#[derive(Debug)]
+pub struct OnDieCache {
+    roots: Vec<X509>,
+    crls: Vec<X509Crl>,
+}
+
+fn parse_cert(contents: &[u8]) -> Result<X509> {
+    X509::from_der(contents)
+        .or_else(|_| X509::from_pem(contents))
+        .context("Error parsing certificate")
+}
+
+fn parse_crl(contents: &[u8]) -> Result<X509Crl> {
+    X509Crl::from_der(contents)
+        .or_else(|_| X509Crl::from_pem(contents))
+        .context("Error parsing CRL")
+}
+
+impl OnDieCache {
+    /// Refreshes the cache if any URLs are configured, and loads it.
+    pub async fn from_settings(settings: &OnDieSettings) -> Result<Self> {
+        if !settings.refresh_urls.is_empty() {
+            Self::refresh(settings.cache_dir.as_ref(), &settings.refresh_urls).await?;
+        }
+        Self::load(settings.cache_dir.as_ref())
+    }
+
+    /// Loads all CA certificates (`.cer`, `.crt` or `.pem`) and CRLs (`.crl`) from `cache_dir`.
+    pub fn load(cache_dir: &Path) -> Result<Self> {
+        let mut roots = Vec::new();
+        let mut crls = Vec::new();
+
+        let entries = std::fs::read_dir(cache_dir)
+            .with_context(|| format!("Error reading OnDie cache directory {cache_dir:?}"))?;
+        for entry in entries {
+            let path = entry?.path();
+            let extension = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) => ext.to_lowercase(),
+                None => continue,
+            };
+            if extension != CRL_EXTENSION && !CERT_EXTENSIONS.contains(&extension.as_str()) {
+                log::trace!("Ignoring unknown file {:?} in OnDie cache", path);
+                continue;
+            }
+            let contents =
+                std::fs::read(&path).with_context(|| format!("Error reading {path:?}"))?;
+            if extension == CRL_EXTENSION {
+                crls.push(parse_crl(&contents).with_context(|| format!("Loading {path:?}"))?);
+            } else {
+                roots.push(parse_cert(&contents).with_context(|| format!("Loading {path:?}"))?);
+            }
+        }
+
+        if roots.is_empty() {
+            bail!("No OnDie CA certificates found in {:?}", cache_dir);
+        }
+        log::info!(
+            "Loaded {} OnDie CA certificates and {} CRLs",
+            roots.len(),
+            crls.len()
+        );
+
+        Ok(OnDieCache { roots, crls })
+    }
+
+    /// Downloads each of `urls` into `cache_dir`, named after the last path segment.
+    ///
+    /// Failing downloads are logged and skipped, so that a previously populated cache
+    /// keeps working when the URLs are unreachable.
+    pub async fn refresh(cache_dir: &Path, urls: &[String]) -> Result<()> {
+        std::fs::create_dir_all(cache_dir)
+            .with_context(|| format!("Error creating OnDie cache directory {cache_dir:?}"))?;
+
+        let client = reqwest::Client::new();
+        for url in urls {
+            let file_name = match url.trim_end_matches('/').rsplit('/').next() {
+                Some(name) if !name.is_empty() && !name.contains(':') => name,
+                _ => bail!("Unable to determine a file name for OnDie URL {}", url),
+            };
+            let contents = match client
+                .get(url)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+            {
+                Ok(resp) => resp.bytes().await,
+                Err(e) => Err(e),
+            };
+            match contents {
+                Ok(contents) => {
+                    std::fs::write(cache_dir.join(file_name), &contents)
+                        .with_context(|| format!("Error writing {file_name} to OnDie cache"))?;
+                    log::debug!("Refreshed {} from {}", file_name, url);
+                }
+                Err(e) => log::warn!("Error downloading OnDie file {}: {:?}", url, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn find_root(&self, cert: &X509) -> Option<&X509> {
+        self.roots.iter().find(|root| {
+            root.issued(cert) == X509VerifyResult::OK
+                && root
+                    .public_key()
+                    .and_then(|key| cert.verify(&key))
+                    .unwrap_or(false)
+        })
+    }
+
+    /// Whether the top of `chain` is one of the cached OnDie CA certificates, or issued by one.
+    pub fn is_ondie_chain(&self, chain: &X5Chain) -> bool {
+        match chain.chain().last() {
+            None => false,
+            Some(top) => self.find_root(top).is_some(),
+        }
+    }
+
+    fn check_crl(&self, cert: &X509, issuer: &X509) -> Result<()> {
+        let issuer_name = issuer.subject_name().to_der()?;
+        let issuer_key = issuer.public_key()?;
+        let now = Asn1Time::days_from_now(0)?;
+
+        let mut found = false;
+        for crl in &self.crls {
+            if crl.issuer_name().to_der()? != issuer_name || !crl.verify(&issuer_key)? {
+                continue;
+            }
+            found = true;
+            if let Some(next_update) = crl.next_update() {
+                if now.compare(next_update)? == std::cmp::Ordering::Greater {
+                    log::warn!(
+                        "OnDie CRL for {:?} is past its next update time",
+                        issuer.subject_name()
+                    );
+                }
+            }
+            if let CrlStatus::Revoked(_) = crl.get_by_cert(cert) {
+                bail!("Certificate {:?} is revoked", cert.subject_name());
+            }
+        }
+        if !found {
+            bail!("No CRL available for issuer {:?}", issuer.subject_name());
+        }
+        Ok(())
+    }
+
+    /// Verifies `chain` up to a cached OnDie CA certificate, checking every certificate
+    /// against the CRLs of its issuer, and returns the device (leaf) certificate.
+    pub fn verify<'a>(&self, chain: &'a X5Chain) -> Result<&'a X509> {
+        let certs = chain.chain();
+        let top = match certs.last() {
+            Some(top) => top,
+            None => bail!("Empty device certificate chain"),
+        };
+        let root = match self.find_root(top) {
+            Some(root) => root,
+            None => bail!("Device certificate chain is not rooted in an OnDie CA"),
+        };
+
+        let root_der = root.to_der()?;
+        for (pos, cert) in certs.iter().enumerate() {
+            if cert.to_der()? == root_der {
+                // The chain includes the trusted root itself
+                break;
+            }
+            let issuer = certs.get(pos + 1).unwrap_or(root);
+            if issuer.issued(cert) != X509VerifyResult::OK || !cert.verify(&issuer.public_key()?)? {
+                bail!("Invalid certificate at position {} in the OnDie chain", pos);
+            }
+            self.check_crl(cert, issuer)
+                .with_context(|| format!("Checking revocation at position {pos}"))?;
+        }
+
+        Ok(&certs[0])
+    }
+}
@@ -0,0 +1,560 @@
+//! Certificate revocation checking with CRLs and OCSP
+//!
+//! Every certificate of a chain is checked against the CRLs of its issuer, either
+//! configured locally or downloaded from the CRL distribution points of the certificate.
+//! If no CRL gives an answer, the OCSP responders of the certificate are asked.
+//! Downloaded CRLs and OCSP answers are cached.
+//!
+//! A revoked certificate always fails the check. When the revocation status can't be
+//! determined, the configured [`RevocationMode`] decides.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus},
+    stack::Stack,
+    x509::{
+        store::X509StoreBuilder, verify::X509VerifyFlags, CrlStatus, X509Crl, X509VerifyResult,
+        X509,
+    },
+};
+
+use fdo_data_formats::{
+    enhanced_types::X5Bag, ownershipvoucher::OwnershipVoucher, publickey::X5Chain,
+};
+
+use crate::servers::configuration::{RevocationMode, RevocationSettings};
+
+// Allowed clock skew for the validity of OCSP responses
+const OCSP_MAX_SKEW_SECONDS: u32 = 300;
+
+// Unreachable CRL and OCSP servers must not hold up the protocol messages for long
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RevocationStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+#[derive(Debug)]
+struct Cached<T> {
+    value: T,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+pub struct RevocationChecker {
+    mode: RevocationMode,
+    crls: Vec<X509Crl>,
+    fetch_crls: bool,
+    ocsp: bool,
+    cache_duration: Duration,
+
+    client: reqwest::Client,
+    crl_cache: Mutex<HashMap<String, Cached<Arc<X509Crl>>>>,
+    ocsp_cache: Mutex<HashMap<Vec<u8>, Cached<RevocationStatus>>>,
+}
+
+/// Loads a DER or PEM encoded CRL
+pub fn load_crl(path: &Path) -> Result<X509Crl> {
+    let contents = std::fs::read(path).with_context(|| format!("Error reading CRL {path:?}"))?;
+    X509Crl::from_der(&contents)
+        .or_else(|_| X509Crl::from_pem(&contents))
+        .with_context(|| format!("Error parsing CRL {path:?}"))
+}
+
+fn http_client(connect_timeout: Duration, request_timeout: Duration) -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .build()
+        .context("Error building HTTP client")
+}
+
+fn is_issuer_of(issuer: &X509, cert: &X509) -> bool {
+    issuer.issued(cert) == X509VerifyResult::OK
+        && issuer
+            .public_key()
+            .and_then(|key| cert.verify(&key))
+            .unwrap_or(false)
+}
+
+fn describe_cert(cert: &X509) -> String {
+    format!("{:?}", cert.subject_name())
+}
+
+/// Looks up `cert` in the CRLs of `issuer` that are still current
+fn crl_status<'a, I>(crls: I, cert: &X509, issuer: &X509) -> Result<RevocationStatus>
+where
+    I: IntoIterator<Item = &'a X509Crl>,
+{
+    let issuer_name = issuer.subject_name().to_der()?;
+    let issuer_key = issuer.public_key()?;
+    let now = Asn1Time::days_from_now(0)?;
+
+    let mut status = RevocationStatus::Unknown;
+    for crl in crls {
+        if crl.issuer_name().to_der()? != issuer_name || !crl.verify(&issuer_key)? {
+            continue;
+        }
+        if let Some(next_update) = crl.next_update() {
+            if now.compare(next_update)? == std::cmp::Ordering::Greater {
+                log::warn!(
+                    "Ignoring CRL for {:?}, it is past its next update time",
+                    issuer.subject_name()
+                );
+                continue;
+            }
+        }
+        if let CrlStatus::Revoked(_) = crl.get_by_cert(cert) {
+            return Ok(RevocationStatus::Revoked);
+        }
+        status = RevocationStatus::Good;
+    }
+    Ok(status)
+}
+
+/// Verifies an OCSP response of the issuer of `cert`, and returns the status it gives
+fn ocsp_response_status(response: &[u8], cert: &X509, issuer: &X509) -> Result<RevocationStatus> {
+    let response = OcspResponse::from_der(response).context("Error parsing OCSP response")?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        bail!("OCSP responder returned status {:?}", response.status());
+    }
+    let response = response.basic().context("Error parsing OCSP response")?;
+
+    // The response must be signed by the issuer, or by a responder it delegated to
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(issuer.clone())?;
+    store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+    let store = store.build();
+    response
+        .verify(&Stack::new()?, &store, OcspFlag::empty())
+        .context("Error verifying OCSP response")?;
+
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)?;
+    let status = match response.find_status(&id) {
+        Some(status) => status,
+        None => bail!("OCSP response has no status for the certificate"),
+    };
+    status
+        .check_validity(OCSP_MAX_SKEW_SECONDS, None)
+        .context("OCSP response is outdated")?;
+
+    Ok(if status.status == OcspCertStatus::GOOD {
+        RevocationStatus::Good
+    } else if status.status == OcspCertStatus::REVOKED {
+        RevocationStatus::Revoked
+    } else {
+        RevocationStatus::Unknown
+    })
+}
+
+fn crl_distribution_points(cert: &X509) -> Vec<String> {
+    cert.crl_distribution_points()
+        .map(|points| {
+            points
+                .iter()
+                .filter_map(|point| point.distpoint()?.fullname())
+                .flat_map(|names| names.iter().filter_map(|name| name.uri()))
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn ocsp_responders(cert: &X509) -> Vec<String> {
+    cert.ocsp_responders()
+        .map(|responders| responders.iter().map(|url| url.to_string()).collect())
+        .unwrap_or_default()
+}
+
+impl RevocationChecker {
+    pub fn new(
+        mode: RevocationMode,
+        crls: Vec<X509Crl>,
+        fetch_crls: bool,
+        ocsp: bool,
+        cache_duration: Duration,
+    ) -> Result<Self> {
+        Ok(RevocationChecker {
+            mode,
+            crls,
+            fetch_crls,
+            ocsp,
+            cache_duration,
+
+            client: http_client(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT)?,
+            crl_cache: Mutex::new(HashMap::new()),
+            ocsp_cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sets the timeouts for connecting to, and for whole requests to, CRL and OCSP servers
+    pub fn with_timeouts(
+        mut self,
+        connect_timeout: Duration,
+        request_timeout: Duration,
+    ) -> Result<Self> {
+        self.client = http_client(connect_timeout, request_timeout)?;
+        Ok(self)
+    }
+
+    pub fn from_settings(settings: &RevocationSettings) -> Result<Self> {
+        let crls = settings
+            .crl_paths
+            .iter()
+            .map(|path| load_crl(path.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        log::info!("Loaded {} CRLs for revocation checking", crls.len());
+
+        Self::new(
+            settings.mode,
+            crls,
+            settings.fetch_crls,
+            settings.ocsp,
+            Duration::from_secs(settings.cache_seconds),
+        )?
+        .with_timeouts(
+            Duration::from_secs(settings.connect_timeout_seconds),
+            Duration::from_secs(settings.request_timeout_seconds),
+        )
+    }
+
+    async fn fetch_crl(&self, url: &str) -> Result<Arc<X509Crl>> {
+        let cached = self
+            .crl_cache
+            .lock()
+            .unwrap()
+            .get(url)
+            .filter(|cached| cached.expires > Instant::now())
+            .map(|cached| cached.value.clone());
+        if let Some(crl) = cached {
+            return Ok(crl);
+        }
+
+        let contents = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| format!("Error downloading CRL from {url}"))?
+            .bytes()
+            .await
+            .with_context(|| format!("Error downloading CRL from {url}"))?;
+        let crl = X509Crl::from_der(&contents)
+            .or_else(|_| X509Crl::from_pem(&contents))
+            .with_context(|| format!("Error parsing CRL from {url}"))?;
+        let crl = Arc::new(crl);
+
+        self.crl_cache.lock().unwrap().insert(
+            url.to_string(),
+            Cached {
+                value: crl.clone(),
+                expires: Instant::now() + self.cache_duration,
+            },
+        );
+        Ok(crl)
+    }
+
+    async fn ocsp_status(&self, url: &str, cert: &X509, issuer: &X509) -> Result<RevocationStatus> {
+        let request = {
+            let mut request = OcspRequest::new()?;
+            request.add_id(OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer)?)?;
+            request.to_der()?
+        };
+        let response = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/ocsp-request")
+            .body(request)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .with_context(|| format!("Error querying OCSP responder {url}"))?
+            .bytes()
+            .await
+            .with_context(|| format!("Error querying OCSP responder {url}"))?;
+
+        ocsp_response_status(&response, cert, issuer)
+    }
+
+    async fn cached_ocsp_status(&self, cert: &X509, issuer: &X509) -> Result<RevocationStatus> {
+        let key = cert.digest(MessageDigest::sha256())?.to_vec();
+        let cached = self
+            .ocsp_cache
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|cached| cached.expires > Instant::now())
+            .map(|cached| cached.value);
+        if let Some(status) = cached {
+            return Ok(status);
+        }
+
+        let mut status = RevocationStatus::Unknown;
+        for url in ocsp_responders(cert) {
+            match self.ocsp_status(&url, cert, issuer).await {
+                Ok(RevocationStatus::Unknown) => {}
+                Ok(found) => {
+                    status = found;
+                    break;
+                }
+                Err(e) => log::warn!("Error checking revocation with OCSP: {:?}", e),
+            }
+        }
+
+        if status != RevocationStatus::Unknown {
+            self.ocsp_cache.lock().unwrap().insert(
+                key,
+                Cached {
+                    value: status,
+                    expires: Instant::now() + self.cache_duration,
+                },
+            );
+        }
+        Ok(status)
+    }
+
+    async fn status(&self, cert: &X509, issuer: &X509) -> Result<RevocationStatus> {
+        let status = crl_status(&self.crls, cert, issuer)?;
+        if status != RevocationStatus::Unknown {
+            return Ok(status);
+        }
+
+        if self.fetch_crls {
+            for url in crl_distribution_points(cert) {
+                let crl = match self.fetch_crl(&url).await {
+                    Ok(crl) => crl,
+                    Err(e) => {
+                        log::warn!("Error checking revocation with CRL: {:?}", e);
+                        continue;
+                    }
+                };
+                let status = crl_status(std::iter::once(crl.as_ref()), cert, issuer)?;
+                if status != RevocationStatus::Unknown {
+                    return Ok(status);
+                }
+            }
+        }
+
+        if self.ocsp {
+            return self.cached_ocsp_status(cert, issuer).await;
+        }
+        Ok(RevocationStatus::Unknown)
+    }
+
+    /// Checks whether any certificate of `chain` is revoked.
+    ///
+    /// The issuer of every certificate is the next certificate of the chain. The issuer of
+    /// the last one is looked up in `trust_anchors`; if it is self-issued or its issuer is
+    /// not known, it is not checked.
+    pub async fn check_chain(&self, chain: &X5Chain, trust_anchors: &X5Bag) -> Result<()> {
+        let certs = chain.chain();
+        for (pos, cert) in certs.iter().enumerate() {
+            let issuer = match certs.get(pos + 1) {
+                Some(issuer) => issuer,
+                None if is_issuer_of(cert, cert) => break,
+                None => match trust_anchors
+                    .certs()
+                    .find(|anchor| is_issuer_of(anchor, cert))
+                {
+                    Some(issuer) => issuer,
+                    None => {
+                        log::debug!(
+                            "Issuer of {} unknown, not checking its revocation",
+                            describe_cert(cert)
+                        );
+                        break;
+                    }
+                },
+            };
+
+            match self.status(cert, issuer).await? {
+                RevocationStatus::Good => {}
+                RevocationStatus::Revoked => {
+                    bail!(
+                        "Certificate {} at position {} is revoked",
+                        describe_cert(cert),
+                        pos
+                    )
+                }
+                RevocationStatus::Unknown => match self.mode {
+                    RevocationMode::HardFail => bail!(
+                        "Unable to determine the revocation status of certificate {} at position {}",
+                        describe_cert(cert),
+                        pos
+                    ),
+                    RevocationMode::SoftFail => log::warn!(
+                        "Unable to determine the revocation status of certificate {}, accepting it",
+                        describe_cert(cert)
+                    ),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the certificate chains of the manufacturer key and of every owner key of `ov`
+    ///
+    /// The entries are checked as far as they can be verified, the validity of the voucher
+    /// itself is up to the caller.
+    pub async fn check_voucher(
+        &self,
+        ov: &OwnershipVoucher,
+        manufacturer_trust_anchors: &X5Bag,
+    ) -> Result<()> {
+        if let Some(chain) = ov.header().manufacturer_public_key().chain() {
+            self.check_chain(chain, manufacturer_trust_anchors)
+                .await
+                .context("Checking the manufacturer certificate chain")?;
+        }
+
+        let owner_chains: Vec<(usize, X5Chain)> = ov
+            .iter_entries()?
+            .map_while(|entry| entry.ok())
+            .enumerate()
+            .filter_map(|(pos, entry)| Some((pos, entry.public_key().chain()?.clone())))
+            .collect();
+        for (pos, chain) in &owner_chains {
+            self.check_chain(chain, &X5Bag::new())
+                .await
+                .with_context(|| format!("Checking the certificate chain of entry {pos}"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use openssl::x509::{X509Crl, X509};
+
+    use fdo_data_formats::{enhanced_types::X5Bag, publickey::X5Chain};
+
+    use super::{crl_status, ocsp_response_status, RevocationChecker, RevocationStatus};
+    use crate::servers::configuration::RevocationMode;
+
+    // Generated by testdata/revocation/generate.sh
+    macro_rules! testdata {
+        ($name:literal) => {
+            include_bytes!(concat!("../testdata/revocation/", $name))
+        };
+    }
+
+    fn cert(pem: &[u8]) -> X509 {
+        X509::from_pem(pem).unwrap()
+    }
+
+    fn crl(pem: &[u8]) -> X509Crl {
+        X509Crl::from_pem(pem).unwrap()
+    }
+
+    fn checker(mode: RevocationMode, crls: Vec<X509Crl>) -> RevocationChecker {
+        RevocationChecker::new(mode, crls, false, false, Duration::ZERO).unwrap()
+    }
+
+    #[test]
+    fn test_crl_status() {
+        let ca = cert(testdata!("ca.pem"));
+        let leaf = cert(testdata!("leaf.pem"));
+        let status = |crls: &[X509Crl]| crl_status(crls, &leaf, &ca).unwrap();
+
+        let good = || crl(testdata!("crl_good.crl"));
+        let revoked = || crl(testdata!("crl_revoked.crl"));
+        // All of these list the leaf certificate as revoked
+        let stale = || crl(testdata!("crl_stale.crl"));
+        let other_issuer = || crl(testdata!("crl_other_issuer.crl"));
+        let forged = || crl(testdata!("crl_forged.crl"));
+
+        assert_eq!(status(&[]), RevocationStatus::Unknown);
+        assert_eq!(status(&[good()]), RevocationStatus::Good);
+        assert_eq!(status(&[revoked()]), RevocationStatus::Revoked);
+        assert_eq!(status(&[good(), revoked()]), RevocationStatus::Revoked);
+        assert_eq!(status(&[stale()]), RevocationStatus::Unknown);
+        assert_eq!(status(&[other_issuer()]), RevocationStatus::Unknown);
+        assert_eq!(status(&[forged()]), RevocationStatus::Unknown);
+        assert_eq!(
+            status(&[stale(), other_issuer(), forged(), good()]),
+            RevocationStatus::Good
+        );
+    }
+
+    #[test]
+    fn test_ocsp_response_status() {
+        let ca = cert(testdata!("ca.pem"));
+        let leaf = cert(testdata!("leaf.pem"));
+
+        assert_eq!(
+            ocsp_response_status(testdata!("ocsp_good.der"), &leaf, &ca).unwrap(),
+            RevocationStatus::Good
+        );
+        assert_eq!(
+            ocsp_response_status(testdata!("ocsp_revoked.der"), &leaf, &ca).unwrap(),
+            RevocationStatus::Revoked
+        );
+
+        // Signed by a CA that isn't the issuer of the certificate
+        assert!(ocsp_response_status(testdata!("ocsp_wrong_signer.der"), &leaf, &ca).is_err());
+        // Past its next update time
+        assert!(ocsp_response_status(testdata!("ocsp_outdated.der"), &leaf, &ca).is_err());
+        // Only about the leaf certificate
+        assert!(ocsp_response_status(testdata!("ocsp_good.der"), &ca, &ca).is_err());
+        // Signed with another key than the one of the issuer
+        let fake_ca = cert(testdata!("fake_ca.pem"));
+        assert!(ocsp_response_status(testdata!("ocsp_good.der"), &leaf, &fake_ca).is_err());
+        assert!(ocsp_response_status(b"not an OCSP response", &leaf, &ca).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_chain() {
+        let ca = cert(testdata!("ca.pem"));
+        let leaf = cert(testdata!("leaf.pem"));
+        let chain = X5Chain::new(vec![leaf.clone(), ca.clone()]).unwrap();
+        let anchors = X5Bag::new();
+
+        // Without any CRL or OCSP responder the status is unknown
+        assert!(checker(RevocationMode::HardFail, vec![])
+            .check_chain(&chain, &anchors)
+            .await
+            .is_err());
+        assert!(checker(RevocationMode::SoftFail, vec![])
+            .check_chain(&chain, &anchors)
+            .await
+            .is_ok());
+
+        for mode in [RevocationMode::HardFail, RevocationMode::SoftFail] {
+            assert!(checker(mode, vec![crl(testdata!("crl_good.crl"))])
+                .check_chain(&chain, &anchors)
+                .await
+                .is_ok());
+            assert!(checker(mode, vec![crl(testdata!("crl_revoked.crl"))])
+                .check_chain(&chain, &anchors)
+                .await
+                .is_err());
+        }
+
+        // The issuer of the last certificate is looked up in the trust anchors
+        let leaf_only = X5Chain::new(vec![leaf]).unwrap();
+        assert!(checker(RevocationMode::HardFail, vec![])
+            .check_chain(&leaf_only, &anchors)
+            .await
+            .is_ok());
+        assert!(checker(RevocationMode::HardFail, vec![])
+            .check_chain(&leaf_only, &X5Bag::with_certs(vec![ca]).unwrap())
+            .await
+            .is_err());
+    }
+}
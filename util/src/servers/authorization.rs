@@ -0,0 +1,782 @@
+//! Role based access control of the management APIs
+//!
+//! Callers are identified by a static bearer token, by the subject of their client
+//! certificate (as forwarded by a trusted TLS terminating proxy), or by an OpenID Connect
+//! token.
+//! Every authorization decision is logged to the `fdo_audit` log target.
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use openssl::{
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Public},
+    rsa::Rsa,
+    sign::Verifier,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use fdo_data_formats::secret::constant_time_eq;
+
+use crate::base64url;
+
+use super::configuration::{AccessControlSettings, IdentityCredential, OidcSettings, Permission};
+
+pub const AUDIT_LOG_TARGET: &str = "fdo_audit";
+
+// Allowed clock difference with the OpenID Connect provider
+const OIDC_LEEWAY_SECONDS: i64 = 60;
+
+const ALL_PERMISSIONS: &[Permission] = &[
+    Permission::ReadVouchers,
+    Permission::UploadVouchers,
+    Permission::DeleteVouchers,
+    Permission::ResellVouchers,
+    Permission::RegisterVouchers,
+    Permission::ReadConfiguration,
+    Permission::WriteServiceInfo,
+];
+
+/// An authenticated caller of a management API
+#[derive(Debug, Clone)]
+pub struct Identity {
+    name: String,
+    permissions: HashSet<Permission>,
+}
+
+impl Identity {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_allowed(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+}
+
+#[derive(Debug)]
+struct ConfiguredIdentity {
+    credential: IdentityCredential,
+    identity: Identity,
+}
+
+#[derive(Debug)]
+pub struct AccessControl {
+    roles: BTreeMap<String, HashSet<Permission>>,
+    identities: Vec<ConfiguredIdentity>,
+    client_certificate_subject_header: Option<String>,
+    oidc: Option<OidcVerifier>,
+}
+
+impl AccessControl {
+    pub fn from_settings(settings: AccessControlSettings) -> Result<Self> {
+        let roles: BTreeMap<String, HashSet<Permission>> = settings
+            .roles
+            .into_iter()
+            .map(|(role, permissions)| (role, permissions.into_iter().collect()))
+            .collect();
+
+        let client_certificate_subject_header = settings
+            .trusted_proxy
+            .map(|proxy| proxy.client_certificate_subject_header);
+        match &client_certificate_subject_header {
+            Some(header) => log::warn!(
+                "Trusting the {} header for client certificate subjects: clients must only \
+                 reach this server through a proxy that removes it from their requests",
+                header
+            ),
+            None => {
+                if let Some(identity) = settings.identities.iter().find(|identity| {
+                    matches!(
+                        identity.credential,
+                        IdentityCredential::ClientCertificateSubject(_)
+                    )
+                }) {
+                    bail!(
+                        "Identity {} uses a client certificate subject, which needs a trusted_proxy",
+                        identity.name
+                    );
+                }
+            }
+        }
+
+        let mut identities = Vec::new();
+        for identity in settings.identities {
+            let permissions = role_permissions(&roles, &identity.roles)
+                .with_context(|| format!("Invalid roles for identity {}", identity.name))?;
+            identities.push(ConfiguredIdentity {
+                credential: match identity.credential {
+                    IdentityCredential::Token(token) => {
+                        IdentityCredential::Token(format!("Bearer {token}"))
+                    }
+                    credential => credential,
+                },
+                identity: Identity {
+                    name: identity.name,
+                    permissions,
+                },
+            });
+        }
+
+        let oidc = match settings.oidc {
+            None => None,
+            Some(oidc) => Some(OidcVerifier::new(oidc).context("Error loading OIDC settings")?),
+        };
+
+        Ok(AccessControl {
+            roles,
+            identities,
+            client_certificate_subject_header,
+            oidc,
+        })
+    }
+
+    /// Access control from either an auth token or access control settings, None if
+    /// neither is configured
+    pub fn from_token_or_settings(
+        auth_token: Option<String>,
+        settings: Option<AccessControlSettings>,
+    ) -> Result<Option<Self>> {
+        match (auth_token, settings) {
+            (None, None) => Ok(None),
+            (Some(auth_token), None) => Ok(Some(AccessControl::with_token(&auth_token))),
+            (None, Some(settings)) => Ok(Some(AccessControl::from_settings(settings)?)),
+            (Some(_), Some(_)) => bail!("Both an auth token and access control are configured"),
+        }
+    }
+
+    /// Access control with a single token that may do everything, as configured with the
+    /// auth token settings from before there were roles
+    pub fn with_token(token: &str) -> Self {
+        AccessControl {
+            roles: BTreeMap::new(),
+            identities: vec![ConfiguredIdentity {
+                credential: IdentityCredential::Token(format!("Bearer {token}")),
+                identity: Identity {
+                    name: "auth_token".to_string(),
+                    permissions: ALL_PERMISSIONS.iter().copied().collect(),
+                },
+            }],
+            client_certificate_subject_header: None,
+            oidc: None,
+        }
+    }
+
+    /// The request header to take the client certificate subject from, as set by the
+    /// trusted proxy
+    pub fn client_certificate_subject_header(&self) -> Option<&str> {
+        self.client_certificate_subject_header.as_deref()
+    }
+
+    /// Identifies the caller from the Authorization header or client certificate subject
+    pub fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        client_certificate_subject: Option<&str>,
+    ) -> Option<Identity> {
+        let client_certificate_subject = match self.client_certificate_subject_header {
+            None => None,
+            Some(_) => client_certificate_subject,
+        };
+
+        for configured in &self.identities {
+            let matches = match (
+                &configured.credential,
+                authorization,
+                client_certificate_subject,
+            ) {
+                (IdentityCredential::Token(token), Some(authorization), _) => {
+                    constant_time_eq(token.as_bytes(), authorization.as_bytes())
+                }
+                (IdentityCredential::ClientCertificateSubject(expected), _, Some(subject)) => {
+                    expected == subject
+                }
+                _ => false,
+            };
+            if matches {
+                return Some(configured.identity.clone());
+            }
+        }
+
+        let token = authorization?.strip_prefix("Bearer ")?;
+        let oidc = self.oidc.as_ref()?;
+        match oidc.verify(token) {
+            Ok(claims) => Some(self.oidc_identity(oidc, &claims)),
+            Err(e) => {
+                log::debug!("Invalid OIDC token: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn oidc_identity(&self, oidc: &OidcVerifier, claims: &OidcClaims) -> Identity {
+        let mut permissions = HashSet::new();
+        for configured in &self.identities {
+            if let IdentityCredential::OidcSubject(subject) = &configured.credential {
+                if subject == &claims.sub {
+                    permissions.extend(configured.identity.permissions.iter().copied());
+                }
+            }
+        }
+        for role in oidc.granted_roles(claims) {
+            // Roles that are not configured here don't grant anything
+            if let Some(role_permissions) = self.roles.get(&role) {
+                permissions.extend(role_permissions.iter().copied());
+            }
+        }
+        Identity {
+            name: format!("oidc:{}", claims.sub),
+            permissions,
+        }
+    }
+
+    /// Authenticates the caller and checks that they have `permission`, logging the
+    /// decision about `action` to the audit log
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        client_certificate_subject: Option<&str>,
+        permission: Permission,
+        action: &str,
+    ) -> Option<Identity> {
+        let identity = match self.authenticate(authorization, client_certificate_subject) {
+            Some(identity) => identity,
+            None => {
+                log::warn!(
+                    target: AUDIT_LOG_TARGET,
+                    "{}: denied, unauthenticated request",
+                    action
+                );
+                return None;
+            }
+        };
+        if !identity.is_allowed(permission) {
+            log::warn!(
+                target: AUDIT_LOG_TARGET,
+                "{}: denied for {}, missing permission {:?}",
+                action,
+                identity.name(),
+                permission
+            );
+            return None;
+        }
+        log::info!(
+            target: AUDIT_LOG_TARGET,
+            "{}: allowed for {}",
+            action,
+            identity.name()
+        );
+        Some(identity)
+    }
+}
+
+fn role_permissions(
+    roles: &BTreeMap<String, HashSet<Permission>>,
+    names: &[String],
+) -> Result<HashSet<Permission>> {
+    let mut permissions = HashSet::new();
+    for name in names {
+        match roles.get(name) {
+            Some(role) => permissions.extend(role.iter().copied()),
+            None => bail!("Unknown role {}", name),
+        }
+    }
+    Ok(permissions)
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct OidcClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    #[serde(default)]
+    nbf: Option<i64>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    // RSA
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    // EC
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+fn jwk_component(value: &Option<String>, name: &str) -> Result<BigNum> {
+    let value = value
+        .as_deref()
+        .with_context(|| format!("Missing {name}"))?;
+    Ok(BigNum::from_slice(&base64url::decode(value)?)?)
+}
+
+impl Jwk {
+    fn public_key(&self) -> Result<PKey<Public>> {
+        match self.kty.as_str() {
+            "RSA" => Ok(PKey::from_rsa(Rsa::from_public_components(
+                jwk_component(&self.n, "n")?,
+                jwk_component(&self.e, "e")?,
+            )?)?),
+            "EC" => {
+                let curve = match self.crv.as_deref() {
+                    Some("P-256") => Nid::X9_62_PRIME256V1,
+                    Some("P-384") => Nid::SECP384R1,
+                    crv => bail!("Unsupported curve {:?}", crv),
+                };
+                let group = EcGroup::from_curve_name(curve)?;
+                let x = jwk_component(&self.x, "x")?;
+                let y = jwk_component(&self.y, "y")?;
+                Ok(PKey::from_ec_key(
+                    EcKey::from_public_key_affine_coordinates(&group, &x, &y)?,
+                )?)
+            }
+            kty => bail!("Unsupported key type {}", kty),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OidcVerifier {
+    settings: OidcSettings,
+    keys: Vec<(Option<String>, PKey<Public>)>,
+}
+
+impl OidcVerifier {
+    fn new(settings: OidcSettings) -> Result<Self> {
+        let jwks: Jwks =
+            serde_json::from_slice(&fs::read(&settings.jwks_path).context("Error reading JWKS")?)
+                .context("Error parsing JWKS")?;
+        let keys = jwks
+            .keys
+            .into_iter()
+            .map(|jwk| {
+                let key = jwk
+                    .public_key()
+                    .with_context(|| format!("Error loading JWK {:?}", jwk.kid))?;
+                Ok((jwk.kid, key))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            bail!("No keys in JWKS");
+        }
+        Ok(OidcVerifier { settings, keys })
+    }
+
+    fn verify(&self, token: &str) -> Result<OidcClaims> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            bail!("Not a JWS compact serialization");
+        }
+        let header: JwtHeader = serde_json::from_slice(&base64url::decode(parts[0])?)?;
+        let signature = base64url::decode(parts[2])?;
+        let signed = &token[..parts[0].len() + 1 + parts[1].len()];
+
+        let (digest, ecdsa_len) = match header.alg.as_str() {
+            "RS256" => (MessageDigest::sha256(), None),
+            "ES256" => (MessageDigest::sha256(), Some(32)),
+            "ES384" => (MessageDigest::sha384(), Some(48)),
+            alg => bail!("Unsupported algorithm {}", alg),
+        };
+        // JWS ECDSA signatures are the concatenated r and s values
+        let signature = match ecdsa_len {
+            None => signature,
+            Some(len) => {
+                if signature.len() != len * 2 {
+                    bail!("Invalid ECDSA signature length");
+                }
+                EcdsaSig::from_private_components(
+                    BigNum::from_slice(&signature[..len])?,
+                    BigNum::from_slice(&signature[len..])?,
+                )?
+                .to_der()?
+            }
+        };
+
+        let mut verified = false;
+        for (kid, key) in &self.keys {
+            if header.kid.is_some() && kid.is_some() && &header.kid != kid {
+                continue;
+            }
+            if key.rsa().is_ok() != ecdsa_len.is_none() {
+                continue;
+            }
+            let mut verifier = Verifier::new(digest, key)?;
+            verifier.update(signed.as_bytes())?;
+            if verifier.verify(&signature).unwrap_or(false) {
+                verified = true;
+                break;
+            }
+        }
+        if !verified {
+            bail!("Token signature is invalid");
+        }
+
+        let claims: OidcClaims = serde_json::from_slice(&base64url::decode(parts[1])?)?;
+        if claims.iss != self.settings.issuer {
+            bail!("Token issued by {}", claims.iss);
+        }
+        let audience_matches = match &claims.aud {
+            Audience::One(aud) => aud == &self.settings.audience,
+            Audience::Many(auds) => auds.contains(&self.settings.audience),
+        };
+        if !audience_matches {
+            bail!("Token is not for audience {}", self.settings.audience);
+        }
+        let now: i64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs()
+            .try_into()?;
+        if claims.exp.saturating_add(OIDC_LEEWAY_SECONDS) < now {
+            bail!("Token expired");
+        }
+        if let Some(nbf) = claims.nbf {
+            if nbf.saturating_sub(OIDC_LEEWAY_SECONDS) > now {
+                bail!("Token not yet valid");
+            }
+        }
+        Ok(claims)
+    }
+
+    fn granted_roles(&self, claims: &OidcClaims) -> Vec<String> {
+        let claim = match &self.settings.roles_claim {
+            None => return Vec::new(),
+            Some(claim) => claim,
+        };
+        match claims.other.get(claim) {
+            Some(Value::String(role)) => vec![role.clone()],
+            Some(Value::Array(roles)) => roles
+                .iter()
+                .filter_map(|role| role.as_str().map(String::from))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::{pkey::Private, sign::Signer};
+    use serde_json::json;
+
+    use super::*;
+    use crate::base64url;
+    use crate::servers::configuration::{AbsolutePathBuf, IdentitySettings, TrustedProxySettings};
+
+    fn access_control() -> AccessControl {
+        let mut roles = BTreeMap::new();
+        roles.insert(
+            "reader".to_string(),
+            vec![Permission::ReadVouchers, Permission::ReadConfiguration],
+        );
+        roles.insert("uploader".to_string(), vec![Permission::UploadVouchers]);
+        AccessControl::from_settings(AccessControlSettings {
+            roles,
+            identities: vec![
+                IdentitySettings {
+                    name: "ci".to_string(),
+                    credential: IdentityCredential::Token("secret".to_string()),
+                    roles: vec!["reader".to_string(), "uploader".to_string()],
+                },
+                IdentitySettings {
+                    name: "dashboard".to_string(),
+                    credential: IdentityCredential::ClientCertificateSubject(
+                        "CN=dashboard".to_string(),
+                    ),
+                    roles: vec!["reader".to_string()],
+                },
+            ],
+            trusted_proxy: Some(TrustedProxySettings {
+                client_certificate_subject_header: "X-Client-Subject".to_string(),
+            }),
+            oidc: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_token_identity() {
+        let ac = access_control();
+        let identity = ac.authenticate(Some("Bearer secret"), None).unwrap();
+        assert_eq!(identity.name(), "ci");
+        assert!(identity.is_allowed(Permission::UploadVouchers));
+        assert!(!identity.is_allowed(Permission::DeleteVouchers));
+
+        assert!(ac.authenticate(Some("Bearer wrong"), None).is_none());
+        assert!(ac.authenticate(None, None).is_none());
+    }
+
+    #[test]
+    fn test_client_certificate_identity() {
+        let ac = access_control();
+        assert!(ac
+            .authorize(
+                None,
+                Some("CN=dashboard"),
+                Permission::ReadVouchers,
+                "GET /test"
+            )
+            .is_some());
+        assert!(ac
+            .authorize(
+                None,
+                Some("CN=dashboard"),
+                Permission::UploadVouchers,
+                "POST /test"
+            )
+            .is_none());
+        assert!(ac.authenticate(None, Some("CN=other")).is_none());
+    }
+
+    #[test]
+    fn test_unknown_role() {
+        let err = AccessControl::from_settings(AccessControlSettings {
+            roles: BTreeMap::new(),
+            identities: vec![IdentitySettings {
+                name: "ci".to_string(),
+                credential: IdentityCredential::Token("secret".to_string()),
+                roles: vec!["admin".to_string()],
+            }],
+            trusted_proxy: None,
+            oidc: None,
+        });
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_client_certificate_identity_needs_trusted_proxy() {
+        let err = AccessControl::from_settings(AccessControlSettings {
+            roles: BTreeMap::new(),
+            identities: vec![IdentitySettings {
+                name: "dashboard".to_string(),
+                credential: IdentityCredential::ClientCertificateSubject(
+                    "CN=dashboard".to_string(),
+                ),
+                roles: vec![],
+            }],
+            trusted_proxy: None,
+            oidc: None,
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("trusted_proxy"), "{}", err);
+    }
+
+    #[test]
+    fn test_with_token() {
+        let ac = AccessControl::with_token("secret");
+        let identity = ac.authenticate(Some("Bearer secret"), None).unwrap();
+        for permission in ALL_PERMISSIONS {
+            assert!(identity.is_allowed(*permission));
+        }
+    }
+
+    const OIDC_ISSUER: &str = "https://idp.example.com";
+    const OIDC_AUDIENCE: &str = "fdo-owner";
+
+    fn rsa_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn oidc_verifier(keys: &[(&str, &PKey<Private>)]) -> OidcVerifier {
+        OidcVerifier {
+            settings: OidcSettings {
+                issuer: OIDC_ISSUER.to_string(),
+                audience: OIDC_AUDIENCE.to_string(),
+                jwks_path: AbsolutePathBuf::new("/dev/null".into()).unwrap(),
+                roles_claim: Some("roles".to_string()),
+            },
+            keys: keys
+                .iter()
+                .map(|(kid, key)| {
+                    let public = PKey::public_key_from_der(&key.public_key_to_der().unwrap());
+                    (Some(kid.to_string()), public.unwrap())
+                })
+                .collect(),
+        }
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .try_into()
+            .unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": OIDC_ISSUER,
+            "sub": "user",
+            "aud": OIDC_AUDIENCE,
+            "exp": now() + 300,
+            "roles": ["reader"],
+        })
+    }
+
+    fn sign_token(alg: &str, kid: &str, claims: &Value, key: &PKey<Private>) -> String {
+        let header = json!({ "alg": alg, "kid": kid });
+        let signed = format!(
+            "{}.{}",
+            base64url::encode(header.to_string().as_bytes()),
+            base64url::encode(claims.to_string().as_bytes())
+        );
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+        let signature = match alg {
+            "ES256" => {
+                let signature = EcdsaSig::from_der(&signature).unwrap();
+                let mut raw = signature.r().to_vec_padded(32).unwrap();
+                raw.extend(signature.s().to_vec_padded(32).unwrap());
+                raw
+            }
+            _ => signature,
+        };
+        format!("{}.{}", signed, base64url::encode(&signature))
+    }
+
+    #[test]
+    fn test_oidc_valid_tokens() {
+        let rsa = rsa_key();
+        let ec = ec_key();
+        let verifier = oidc_verifier(&[("rsa", &rsa), ("ec", &ec)]);
+
+        let verified = verifier
+            .verify(&sign_token("RS256", "rsa", &claims(), &rsa))
+            .unwrap();
+        assert_eq!(verified.sub, "user");
+        assert_eq!(
+            verifier.granted_roles(&verified),
+            vec!["reader".to_string()]
+        );
+        verifier
+            .verify(&sign_token("ES256", "ec", &claims(), &ec))
+            .unwrap();
+
+        let mut multiple_audiences = claims();
+        multiple_audiences["aud"] = json!(["other", OIDC_AUDIENCE]);
+        verifier
+            .verify(&sign_token("RS256", "rsa", &multiple_audiences, &rsa))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_oidc_invalid_signatures() {
+        let rsa = rsa_key();
+        let ec = ec_key();
+        let verifier = oidc_verifier(&[("rsa", &rsa), ("ec", &ec)]);
+
+        // Signed by a key the verifier doesn't know
+        let token = sign_token("ES256", "ec", &claims(), &ec_key());
+        assert!(verifier.verify(&token).is_err());
+
+        // Claims changed after signing
+        let token = sign_token("RS256", "rsa", &claims(), &rsa);
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut changed_claims = claims();
+        changed_claims["sub"] = json!("admin");
+        let changed = format!(
+            "{}.{}.{}",
+            parts[0],
+            base64url::encode(changed_claims.to_string().as_bytes()),
+            parts[2]
+        );
+        assert!(verifier.verify(&changed).is_err());
+
+        // The key with the kid of the header didn't sign it
+        let token = sign_token("ES256", "other", &claims(), &ec);
+        assert!(verifier.verify(&token).is_err());
+        let token = sign_token("RS256", "ec", &claims(), &rsa);
+        assert!(verifier.verify(&token).is_err());
+
+        assert!(verifier.verify("not.a-token").is_err());
+    }
+
+    #[test]
+    fn test_oidc_rejected_algorithms() {
+        let rsa = rsa_key();
+        let verifier = oidc_verifier(&[("rsa", &rsa)]);
+        let payload = base64url::encode(claims().to_string().as_bytes());
+
+        let header = base64url::encode(br#"{"alg":"none","kid":"rsa"}"#);
+        assert!(verifier.verify(&format!("{header}.{payload}.")).is_err());
+
+        // HMAC with the public key as the secret
+        let header = base64url::encode(br#"{"alg":"HS256","kid":"rsa"}"#);
+        let signed = format!("{header}.{payload}");
+        let secret = PKey::hmac(&rsa.public_key_to_pem().unwrap()).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &secret).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        let signature = base64url::encode(&signer.sign_to_vec().unwrap());
+        assert!(verifier.verify(&format!("{signed}.{signature}")).is_err());
+    }
+
+    #[test]
+    fn test_oidc_claims() {
+        let rsa = rsa_key();
+        let verifier = oidc_verifier(&[("rsa", &rsa)]);
+        let verify = |name: &str, value: Value| {
+            let mut claims = claims();
+            claims[name] = value;
+            verifier.verify(&sign_token("RS256", "rsa", &claims, &rsa))
+        };
+
+        assert!(verify("exp", json!(now() - 2 * OIDC_LEEWAY_SECONDS)).is_err());
+        assert!(verify("nbf", json!(now() + 2 * OIDC_LEEWAY_SECONDS)).is_err());
+        assert!(verify("iss", json!("https://other.example.com")).is_err());
+        assert!(verify("aud", json!("other")).is_err());
+        assert!(verify("aud", json!(["other"])).is_err());
+
+        // Within the allowed clock difference
+        assert!(verify("exp", json!(now() - OIDC_LEEWAY_SECONDS / 2)).is_ok());
+        assert!(verify("nbf", json!(now() + OIDC_LEEWAY_SECONDS / 2)).is_ok());
+        // Extreme values don't overflow
+        assert!(verify("exp", json!(i64::MAX)).is_ok());
+        assert!(verify("nbf", json!(i64::MIN)).is_ok());
+        assert!(verify("exp", json!(i64::MIN)).is_err());
+    }
+}
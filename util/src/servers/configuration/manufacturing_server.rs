@@ -4,13 +4,15 @@ use fdo_data_formats::constants::{KeyStorageType, MfgStringType, PublicKeyType};
 use fdo_store::StoreConfig;
 use serde::{Deserialize, Serialize};
 
-use super::{AbsolutePathBuf, Bind};
+use super::{AbsolutePathBuf, Bind, CryptoPolicySettings};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManufacturingServerSettings {
     // Session store info
     #[serde(with = "serde_yaml::with::singleton_map")]
     pub session_store_driver: StoreConfig,
+    #[serde(default)]
+    pub session_store_encryption_key_path: Option<AbsolutePathBuf>,
 
     // Ownership Voucher store info
     #[serde(with = "serde_yaml::with::singleton_map")]
@@ -22,11 +24,18 @@ pub struct ManufacturingServerSettings {
 
     // Bind information
     pub bind: Bind,
+    // Seconds to wait for the sessions in progress when stopping
+    #[serde(default = "super::default_drain_timeout_seconds")]
+    pub drain_timeout_seconds: u64,
 
     pub protocols: ProtocolSetting,
 
     pub rendezvous_info: Vec<BTreeMap<String, serde_yaml::Value>>,
 
+    // Algorithms allowed for keys, certificates and DIUN sessions
+    #[serde(default)]
+    pub crypto_policy: Option<CryptoPolicySettings>,
+
     pub manufacturing: ManufacturingSettings,
 }
 
@@ -37,7 +46,10 @@ pub struct ManufacturingSettings {
     pub device_cert_ca_chain: AbsolutePathBuf,
 
     pub owner_cert_path: Option<AbsolutePathBuf>,
+    // Either a private key file, or a key in a PKCS#11 token or cloud KMS
     pub manufacturer_private_key: Option<AbsolutePathBuf>,
+    #[serde(default)]
+    pub manufacturer_private_key_uri: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -55,6 +67,11 @@ pub struct DiunSettings {
 
     pub key_path: AbsolutePathBuf,
     pub cert_path: AbsolutePathBuf,
+
+    // When set, devices must attest that their device key is stored in a TPM
+    // whose endorsement key certificate chains up to one of these CAs
+    #[serde(default)]
+    pub tpm_endorsement_ca_certs_path: Option<AbsolutePathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -6,6 +6,7 @@ pub mod rendezvous_server;
 pub mod serviceinfo_api_server;
 
 use std::{
+    collections::BTreeMap,
     net::SocketAddr,
     path::{Path, PathBuf},
 };
@@ -64,6 +65,200 @@ impl From<Bind> for SocketAddr {
     }
 }
 
+pub(crate) fn default_drain_timeout_seconds() -> u64 {
+    30
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OnDieSettings {
+    // Directory with the OnDie CA certificates and CRLs
+    pub cache_dir: AbsolutePathBuf,
+    // Certificates and CRLs to download into the cache on startup
+    #[serde(default)]
+    pub refresh_urls: Vec<String>,
+}
+
+// Restricts the algorithms that keys, certificates, vouchers and sessions may use. Every
+// list that is set is an allow list, unset lists allow everything
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CryptoPolicySettings {
+    #[serde(default)]
+    pub allowed_key_types: Option<Vec<CryptoKeyType>>,
+    #[serde(default)]
+    pub allowed_hash_types: Option<Vec<CryptoHashType>>,
+    #[serde(default)]
+    pub allowed_key_exchange_suites: Option<Vec<CryptoKexSuite>>,
+    #[serde(default)]
+    pub allowed_cipher_suites: Option<Vec<CryptoCipherSuite>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CryptoKeyType {
+    Secp256r1,
+    Secp384r1,
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    Ed25519,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CryptoHashType {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CryptoKexSuite {
+    #[serde(rename = "ECDH256")]
+    Ecdh256,
+    #[serde(rename = "ECDH384")]
+    Ecdh384,
+    #[serde(rename = "DHKEXid14")]
+    DhkexId14,
+    #[serde(rename = "DHKEXid15")]
+    DhkexId15,
+    #[serde(rename = "ASYMKEX2048")]
+    Asymkex2048,
+    #[serde(rename = "ASYMKEX3072")]
+    Asymkex3072,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CryptoCipherSuite {
+    #[serde(rename = "A128GCM")]
+    A128Gcm,
+    #[serde(rename = "A256GCM")]
+    A256Gcm,
+    #[serde(rename = "AES-CCM-64-128-128")]
+    AesCcm64_128_128,
+    #[serde(rename = "AES-CCM-64-128-256")]
+    AesCcm64_128_256,
+}
+
+// Revocation checking of certificate chains, with CRLs and OCSP
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevocationSettings {
+    // CRLs (DER or PEM) of the CAs, e.g. for CAs without distribution points
+    #[serde(default)]
+    pub crl_paths: Vec<AbsolutePathBuf>,
+    // Download CRLs from the distribution points listed in the certificates
+    #[serde(default)]
+    pub fetch_crls: bool,
+    // Ask the OCSP responders listed in the certificates
+    #[serde(default)]
+    pub ocsp: bool,
+    // What to do with certificates whose revocation status can't be determined
+    #[serde(default)]
+    pub mode: RevocationMode,
+    // How long downloaded CRLs and OCSP responses are reused
+    #[serde(default = "default_revocation_cache_seconds")]
+    pub cache_seconds: u64,
+    // Timeouts for connecting to, and for whole requests to, CRL and OCSP servers
+    #[serde(default = "default_revocation_connect_timeout_seconds")]
+    pub connect_timeout_seconds: u64,
+    #[serde(default = "default_revocation_request_timeout_seconds")]
+    pub request_timeout_seconds: u64,
+}
+
+fn default_revocation_cache_seconds() -> u64 {
+    3600
+}
+
+fn default_revocation_connect_timeout_seconds() -> u64 {
+    crate::revocation::DEFAULT_CONNECT_TIMEOUT.as_secs()
+}
+
+fn default_revocation_request_timeout_seconds() -> u64 {
+    crate::revocation::DEFAULT_REQUEST_TIMEOUT.as_secs()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevocationMode {
+    // Reject the certificate
+    HardFail,
+    // Log a warning and accept the certificate
+    SoftFail,
+}
+
+impl Default for RevocationMode {
+    fn default() -> Self {
+        RevocationMode::HardFail
+    }
+}
+
+// Role based access control of a management API
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessControlSettings {
+    // Permissions granted by every role
+    pub roles: BTreeMap<String, Vec<Permission>>,
+    #[serde(default)]
+    pub identities: Vec<IdentitySettings>,
+    // Needed for client certificate subject identities
+    #[serde(default)]
+    pub trusted_proxy: Option<TrustedProxySettings>,
+    // Accept bearer tokens issued by an OpenID Connect provider
+    #[serde(default)]
+    pub oidc: Option<OidcSettings>,
+}
+
+// A TLS terminating reverse proxy that verifies client certificates. Clients must not
+// be able to reach the server without going through it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrustedProxySettings {
+    // Header with the subject of the verified client certificate. The proxy must remove
+    // this header from client requests, or clients could claim any identity
+    pub client_certificate_subject_header: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    ReadVouchers,
+    UploadVouchers,
+    DeleteVouchers,
+    ResellVouchers,
+    RegisterVouchers,
+    ReadConfiguration,
+    WriteServiceInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentitySettings {
+    // Name of the identity in the audit log
+    pub name: String,
+    #[serde(flatten)]
+    pub credential: IdentityCredential,
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityCredential {
+    // Static bearer token
+    Token(String),
+    // Subject of a client certificate, e.g. "CN=ci,O=Example"
+    ClientCertificateSubject(String),
+    // "sub" claim of an OpenID Connect token
+    OidcSubject(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcSettings {
+    pub issuer: String,
+    pub audience: String,
+    // JSON Web Key Set with the token signing keys of the issuer
+    pub jwks_path: AbsolutePathBuf,
+    // Claim with the names of the roles granted by the provider, in addition to the
+    // roles of the identities
+    #[serde(default)]
+    pub roles_claim: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct AbsolutePathBuf(PathBuf);
 
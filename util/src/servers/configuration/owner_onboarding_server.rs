@@ -1,35 +1,235 @@
-use fdo_data_formats::types::RemoteConnection;
-use fdo_store::StoreConfig;
+use fdo_data_formats::{types::RemoteConnection, ProtocolVersion};
+use fdo_store::{CacheConfig, StoreConfig};
 use serde::{Deserialize, Serialize};
 
-use super::{AbsolutePathBuf, Bind};
+use super::{
+    AbsolutePathBuf, AccessControlSettings, Bind, CryptoPolicySettings, OnDieSettings,
+    RevocationSettings,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OwnerOnboardingServerSettings {
     // Ownership Voucher storage info
     #[serde(with = "serde_yaml::with::singleton_map")]
     pub ownership_voucher_store_driver: StoreConfig,
+    #[serde(default)]
+    pub ownership_voucher_cache: Option<CacheConfig>,
 
     // Session store info
     #[serde(with = "serde_yaml::with::singleton_map")]
     pub session_store_driver: StoreConfig,
+    #[serde(default)]
+    pub session_store_encryption_key_path: Option<AbsolutePathBuf>,
 
     // Trusted keys
     pub trusted_device_keys_path: AbsolutePathBuf,
 
-    // Our private owner key
-    pub owner_private_key_path: AbsolutePathBuf,
+    // Intel OnDie ECDSA device attestation
+    #[serde(default)]
+    pub ondie: Option<OnDieSettings>,
+
+    // Validate device certificate chains against the trusted device keys
+    #[serde(default)]
+    pub verify_device_certificate_chains: bool,
+
+    // Check the certificates of vouchers and device chains for revocation
+    #[serde(default)]
+    pub revocation: Option<RevocationSettings>,
+
+    // Algorithms allowed for keys, vouchers and TO2 sessions
+    #[serde(default)]
+    pub crypto_policy: Option<CryptoPolicySettings>,
+
+    // Which uploaded and stored vouchers are accepted
+    #[serde(default)]
+    pub voucher_policy: VoucherPolicySettings,
+
+    // Our private owner key, either on disk or in a PKCS#11 token
+    #[serde(default)]
+    pub owner_private_key_path: Option<AbsolutePathBuf>,
+    #[serde(default)]
+    pub owner_private_key_uri: Option<String>,
     pub owner_public_key_path: AbsolutePathBuf,
 
     // Bind information
     pub bind: Bind,
+    // Seconds to wait for the sessions in progress when stopping
+    #[serde(default = "super::default_drain_timeout_seconds")]
+    pub drain_timeout_seconds: u64,
 
     // Service Info API Server
     pub service_info_api_url: String,
     #[serde(with = "serde_yaml::with::singleton_map")]
     pub service_info_api_authentication: fdo_http_wrapper::client::JsonAuthentication,
+    #[serde(default)]
+    pub service_info_api_ca_path: Option<AbsolutePathBuf>,
+    // Sends the requests to service_info_api_url over this Unix socket instead of TCP
+    #[serde(default)]
+    pub service_info_api_unix_socket: Option<AbsolutePathBuf>,
+    // Largest DeviceServiceInfo message to accept, the spec default of 1300 bytes if unset
+    #[serde(default)]
+    pub max_device_service_info_size: Option<u64>,
+    // Where the ServiceInfo still to be sent to devices is kept, a directory in the
+    // system temporary directory if unset
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub service_info_spool_store_driver: Option<StoreConfig>,
 
     pub owner_addresses: Vec<RemoteConnection>,
 
     pub report_to_rendezvous_endpoint_enabled: bool,
+
+    // Registration of the vouchers with the rendezvous servers (TO0)
+    #[serde(default)]
+    pub to0_registration: To0RegistrationSettings,
+
+    // Keep the device credential and voucher after onboarding (Credential Reuse)
+    #[serde(default)]
+    pub credential_reuse_enabled: bool,
+
+    // Replace the device credential during onboarding, so the voucher can be resold
+    #[serde(default)]
+    pub resale_enabled: bool,
+
+    // Management API, disabled if neither a token nor access control is configured
+    #[serde(default)]
+    pub management_api_auth_token: Option<String>,
+    #[serde(default)]
+    pub management_api_access_control: Option<AccessControlSettings>,
+
+    // Where onboarding events are published to
+    #[serde(default)]
+    pub event_publishers: Vec<EventPublisherSettings>,
+
+    // Protocol requests handled at the same time, others wait for a slot. Unlimited if unset
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventPublisherSettings {
+    // JSON POST request for every event
+    Webhook {
+        url: String,
+        #[serde(default)]
+        auth_token: Option<String>,
+    },
+    // Only available when built with the kafka feature
+    Kafka {
+        // Comma separated host:port list
+        brokers: String,
+        topic: String,
+    },
+    // Only available when built with the nats feature
+    Nats {
+        url: String,
+        subject: String,
+    },
+    // Only available when built with the mqtt feature
+    Mqtt {
+        host: String,
+        #[serde(default = "default_mqtt_port")]
+        port: u16,
+        topic: String,
+        #[serde(default)]
+        client_id: Option<String>,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+}
+
+fn default_to0_max_concurrent() -> usize {
+    16
+}
+
+fn default_to0_max_attempts() -> u32 {
+    3
+}
+
+fn default_to0_retry_delay_seconds() -> u64 {
+    5
+}
+
+fn default_to0_wait_seconds() -> u32 {
+    600
+}
+
+fn default_to0_refresh_margin_percent() -> u8 {
+    25
+}
+
+fn default_to0_refresh_jitter_percent() -> u8 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct To0RegistrationSettings {
+    // Number of vouchers registered at the same time
+    #[serde(default = "default_to0_max_concurrent")]
+    pub max_concurrent: usize,
+    // Maximum number of registrations per second sent to each rendezvous server
+    #[serde(default)]
+    pub max_per_second_per_server: Option<u32>,
+    // Number of attempts for each voucher in a registration round
+    #[serde(default = "default_to0_max_attempts")]
+    pub max_attempts: u32,
+    // Delay before the first retry, doubled for every following retry
+    #[serde(default = "default_to0_retry_delay_seconds")]
+    pub retry_delay_seconds: u64,
+    // Registration time requested from the rendezvous servers, which may accept less
+    #[serde(default = "default_to0_wait_seconds")]
+    pub wait_seconds: u32,
+    // Registrations are refreshed when this percentage of the accepted time is left
+    #[serde(default = "default_to0_refresh_margin_percent")]
+    pub refresh_margin_percent: u8,
+    // Refreshes happen up to this percentage of the accepted time earlier, at random
+    #[serde(default = "default_to0_refresh_jitter_percent")]
+    pub refresh_jitter_percent: u8,
+}
+
+impl Default for To0RegistrationSettings {
+    fn default() -> Self {
+        To0RegistrationSettings {
+            max_concurrent: default_to0_max_concurrent(),
+            max_per_second_per_server: None,
+            max_attempts: default_to0_max_attempts(),
+            retry_delay_seconds: default_to0_retry_delay_seconds(),
+            wait_seconds: default_to0_wait_seconds(),
+            refresh_margin_percent: default_to0_refresh_margin_percent(),
+            refresh_jitter_percent: default_to0_refresh_jitter_percent(),
+        }
+    }
+}
+
+/// Key type of a device certificate, as named in the voucher policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKeyType {
+    Secp256r1,
+    Secp384r1,
+    Rsa2048,
+    Rsa3072,
+    Ed25519,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VoucherPolicySettings {
+    // Manufacturer keys or CAs that vouchers need to be issued by
+    #[serde(default)]
+    pub trusted_manufacturer_keys_path: Option<AbsolutePathBuf>,
+    // Maximum number of entries (owner transfers) in a voucher
+    #[serde(default)]
+    pub max_entries: Option<u16>,
+    // Key types allowed for the device certificate
+    #[serde(default)]
+    pub allowed_device_key_types: Option<Vec<DeviceKeyType>>,
+    // Protocol versions allowed in the voucher header
+    #[serde(default)]
+    pub allowed_protocol_versions: Option<Vec<ProtocolVersion>>,
 }
@@ -1,7 +1,7 @@
 use fdo_store::StoreConfig;
 use serde::{Deserialize, Serialize};
 
-use super::{AbsolutePathBuf, Bind};
+use super::{AbsolutePathBuf, Bind, CryptoPolicySettings, OnDieSettings, RevocationSettings};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RendezvousServerSettings {
@@ -12,13 +12,33 @@ pub struct RendezvousServerSettings {
     // Session store info
     #[serde(with = "serde_yaml::with::singleton_map")]
     pub session_store_driver: StoreConfig,
+    #[serde(default)]
+    pub session_store_encryption_key_path: Option<AbsolutePathBuf>,
 
     // Trusted keys
     pub trusted_manufacturer_keys_path: Option<AbsolutePathBuf>,
 
+    // Intel OnDie ECDSA device attestation
+    #[serde(default)]
+    pub ondie: Option<OnDieSettings>,
+
+    // Check the certificates of registered vouchers for revocation
+    #[serde(default)]
+    pub revocation: Option<RevocationSettings>,
+
+    // Algorithms allowed for registered vouchers
+    #[serde(default)]
+    pub crypto_policy: Option<CryptoPolicySettings>,
+
     // Other info
     pub max_wait_seconds: Option<u32>,
+    // Requests handled at the same time, others wait for a slot. Unlimited if unset
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
 
     // Bind information
     pub bind: Bind,
+    // Seconds to wait for the sessions in progress when stopping
+    #[serde(default = "super::default_drain_timeout_seconds")]
+    pub drain_timeout_seconds: u64,
 }
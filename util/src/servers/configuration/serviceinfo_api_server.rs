@@ -4,20 +4,49 @@ use fdo_data_formats::constants::ServiceInfoModule;
 use fdo_store::StoreConfig;
 use serde::{Deserialize, Serialize};
 
-use super::Bind;
+use super::{AbsolutePathBuf, AccessControlSettings, Bind};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServiceInfoApiServerSettings {
     pub service_info: ServiceInfoSettings,
-    pub bind: Bind,
+    // TCP listener, at least one of bind and unix_socket needs to be set
+    #[serde(default)]
+    pub bind: Option<Bind>,
+    #[serde(default)]
+    pub unix_socket: Option<ServiceInfoApiServerUnixSocket>,
 
     pub service_info_auth_token: Option<String>,
     pub admin_auth_token: Option<String>,
+    // Replaces admin_auth_token with role based access control of the admin API
+    #[serde(default)]
+    pub admin_access_control: Option<AccessControlSettings>,
+
+    #[serde(default)]
+    pub tls: Option<ServiceInfoApiServerTls>,
 
     #[serde(with = "serde_yaml::with::singleton_map")]
     pub device_specific_store_driver: StoreConfig,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceInfoApiServerTls {
+    pub cert_path: AbsolutePathBuf,
+    pub key_path: AbsolutePathBuf,
+    // If set, clients need a certificate signed by this CA
+    #[serde(default)]
+    pub client_ca_path: Option<AbsolutePathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServiceInfoApiServerUnixSocket {
+    pub path: AbsolutePathBuf,
+    // Only peers running as one of these users or groups may connect
+    #[serde(default)]
+    pub allowed_uids: Vec<u32>,
+    #[serde(default)]
+    pub allowed_gids: Vec<u32>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ServiceInfoSettings {
@@ -32,6 +61,8 @@ pub struct ServiceInfoSettings {
     pub additional_serviceinfo: Option<HashMap<ServiceInfoModule, Vec<(String, String)>>>,
 
     pub after_onboarding_reboot: Option<bool>,
+
+    pub disabled_modules: Option<Vec<ServiceInfoModule>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -51,6 +82,10 @@ pub struct ServiceInfoDiskEncryptionClevis {
 pub struct ServiceInfoFile {
     pub path: String,
     pub permissions: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    #[serde(default)]
+    pub template: bool,
     #[serde(skip)]
     pub parsed_permissions: Option<u32>,
     #[serde(skip)]
@@ -66,6 +101,8 @@ pub struct ServiceInfoFile {
 pub struct ServiceInfoCommand {
     pub command: String,
     pub args: Vec<String>,
+    pub env: Option<HashMap<String, String>>,
+    pub stdin: Option<String>,
     #[serde(default)]
     pub may_fail: bool,
     #[serde(default)]
@@ -10,6 +10,7 @@ use std::env;
 use std::path::Path;
 use std::result::Result::Ok;
 
+pub mod authorization;
 pub mod configuration;
 use crate::servers::configuration::serviceinfo_api_server::{
     ServiceInfoApiServerSettings, ServiceInfoSettings,
@@ -21,6 +22,7 @@ use crate::servers::configuration::serviceinfo_api_server::{
 pub enum OwnershipVoucherStoreMetadataKey {
     To2Performed,
     To0AcceptOwnerWaitSeconds,
+    DevMod,
 }
 
 impl fdo_store::MetadataLocalKey for OwnershipVoucherStoreMetadataKey {
@@ -30,6 +32,7 @@ impl fdo_store::MetadataLocalKey for OwnershipVoucherStoreMetadataKey {
             OwnershipVoucherStoreMetadataKey::To0AcceptOwnerWaitSeconds => {
                 "fdo.to0_accept_owner_wait_seconds"
             }
+            OwnershipVoucherStoreMetadataKey::DevMod => "fdo.devmod",
         }
     }
 }
@@ -56,10 +59,47 @@ pub fn settings_for(component: &str) -> Result<config::Config> {
             config::File::from(Path::new(&format!("/usr/share/fdo/{component}.yml")))
                 .required(false),
         )
+        // e.g. OWNER_ONBOARDING_SERVER__BIND overrides `bind` from the files
+        .add_source(
+            config::Environment::with_prefix(&component_env_prefix(component))
+                .prefix_separator("__")
+                .separator("__")
+                .try_parsing(true),
+        )
         .build()
         .context(format!("Loading configuration for {component}"))
 }
 
+/// Loads and parses the configuration for `component`.
+///
+/// If the binary was started with `--validate-config`, this exits after reporting
+/// whether the configuration is valid.
+pub fn load_settings<T>(component: &str) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut args = env::args();
+    let validate_only = args.len() == 2 && args.nth(1).unwrap() == "--validate-config";
+
+    let settings = settings_for(component)?
+        .try_deserialize()
+        .with_context(|| format!("Error parsing configuration for {component}"));
+
+    if validate_only {
+        match settings {
+            Ok(_) => {
+                println!("Configuration for {component} is valid");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Configuration for {component} is invalid: {e:?}");
+                std::process::exit(1);
+            }
+        }
+    }
+    settings
+}
+
 pub fn settings_per_device(guid: &str) -> Result<ServiceInfoSettings> {
     // here we first check if the requested device has per-device file stored
     // in device_specific_store_driver, if not return error
@@ -74,6 +114,7 @@ pub fn settings_per_device(guid: &str) -> Result<ServiceInfoSettings> {
             path.push(file_name);
             path.to_string_lossy().into_owned()
         }
+        _ => bail!("Device specific settings require a Directory store driver"),
     };
     let config = Config::builder()
         .add_source(config::File::from(Path::new(&path_per_device_store)))
@@ -0,0 +1,424 @@
+//! Owner and manufacturer signing keys, either loaded from disk or held in an HSM or KMS
+//!
+//! Keys are referenced either by a path to a PEM or DER encoded private key, by a
+//! PKCS#11 URI (RFC 7512), for example:
+//! `pkcs11:token=fdo;object=owner-key?module-path=/usr/lib64/pkcs11/libsofthsm2.so&pin-value=1234`,
+//! or by an AWS KMS or Azure Key Vault URI, see [`kms`].
+
+use std::{collections::HashMap, fs, sync::Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_nitro_enclaves_cose::{
+    crypto::{SigningPrivateKey, SigningPublicKey},
+    error::CoseError,
+    sign::SignatureAlgorithm,
+};
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    mechanism::Mechanism,
+    object::{Attribute, AttributeType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use openssl::{
+    bn::BigNumContext,
+    ec::{EcGroup, EcKey, EcPoint},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, PKeyRef, Private, Public},
+};
+
+use fdo_data_formats::{constants::HashType, types::COSEHeaderMap, types::COSESign, Serializable};
+
+pub mod kms;
+pub use kms::{AwsKmsKey, AzureKeyVaultKey};
+
+const PKCS11_URI_SCHEME: &str = "pkcs11:";
+const PKCS11_MODULE_PATH_ENV: &str = "PKCS11_MODULE_PATH";
+const PKCS11_PIN_ENV: &str = "PKCS11_PIN";
+
+// DER encoded OIDs of the supported curves, as found in CKA_EC_PARAMS
+const OID_PRIME256V1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_SECP384R1: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+#[derive(Debug)]
+pub enum SigningKey {
+    Pkey(PKey<Private>),
+    Pkcs11(Pkcs11Key),
+    AwsKms(AwsKmsKey),
+    AzureKeyVault(AzureKeyVaultKey),
+}
+
+impl SigningKey {
+    /// Loads a signing key from either a PKCS#11, AWS KMS or Azure Key Vault URI, or a path to
+    /// a PEM or DER private key.
+    pub fn load(reference: &str) -> Result<Self> {
+        if reference.starts_with(PKCS11_URI_SCHEME) {
+            Ok(SigningKey::Pkcs11(
+                Pkcs11Key::from_uri(reference).context("Error loading PKCS#11 key")?,
+            ))
+        } else if reference.starts_with(kms::AWS_KMS_URI_SCHEME) {
+            Ok(SigningKey::AwsKms(
+                AwsKmsKey::from_uri(reference).context("Error loading AWS KMS key")?,
+            ))
+        } else if reference.starts_with(kms::AZURE_KEY_VAULT_URI_SCHEME) {
+            Ok(SigningKey::AzureKeyVault(
+                AzureKeyVaultKey::from_uri(reference)
+                    .context("Error loading Azure Key Vault key")?,
+            ))
+        } else {
+            let contents = fs::read(reference)
+                .with_context(|| format!("Error reading private key from {reference}"))?;
+            let is_pem = contents
+                .iter()
+                .position(|c| !c.is_ascii_whitespace())
+                .map(|start| contents[start..].starts_with(b"-----BEGIN"))
+                .unwrap_or(false);
+            let key = if is_pem {
+                PKey::private_key_from_pem(&contents).context("Error parsing PEM private key")?
+            } else {
+                PKey::private_key_from_der(&contents).context("Error parsing DER private key")?
+            };
+            Ok(SigningKey::Pkey(key))
+        }
+    }
+
+    /// Returns the private key if it's held in memory.
+    pub fn as_pkey(&self) -> Option<&PKeyRef<Private>> {
+        match self {
+            SigningKey::Pkey(key) => Some(key),
+            SigningKey::Pkcs11(_) | SigningKey::AwsKms(_) | SigningKey::AzureKeyVault(_) => None,
+        }
+    }
+
+    pub fn public_key(&self) -> Result<PKey<Public>> {
+        match self {
+            SigningKey::Pkey(key) => Ok(PKey::public_key_from_der(&key.public_key_to_der()?)?),
+            SigningKey::Pkcs11(key) => Ok(key.public_key.clone()),
+            SigningKey::AwsKms(key) => Ok(key.public_key().clone()),
+            SigningKey::AzureKeyVault(key) => Ok(key.public_key().clone()),
+        }
+    }
+
+    /// The hash type matching the strength of this key.
+    pub fn hash_type(&self) -> Result<HashType> {
+        Ok(HashType::for_pkey(&self.public_key()?)?)
+    }
+
+    pub fn cose_sign<T>(
+        &self,
+        payload: &T,
+        unprotected: Option<COSEHeaderMap>,
+    ) -> Result<COSESign, fdo_data_formats::Error>
+    where
+        T: Serializable,
+    {
+        match self {
+            SigningKey::Pkey(key) => COSESign::new_with_pkey(payload, unprotected, key),
+            SigningKey::Pkcs11(key) => COSESign::new(payload, unprotected, key),
+            SigningKey::AwsKms(key) => COSESign::new(payload, unprotected, key),
+            SigningKey::AzureKeyVault(key) => COSESign::new(payload, unprotected, key),
+        }
+    }
+}
+
+impl SigningPublicKey for SigningKey {
+    fn get_parameters(&self) -> Result<(SignatureAlgorithm, MessageDigest), CoseError> {
+        match self {
+            SigningKey::Pkey(key) => key.as_ref().get_parameters(),
+            SigningKey::Pkcs11(key) => key.get_parameters(),
+            SigningKey::AwsKms(key) => key.get_parameters(),
+            SigningKey::AzureKeyVault(key) => key.get_parameters(),
+        }
+    }
+
+    fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<bool, CoseError> {
+        match self {
+            SigningKey::Pkey(key) => key.as_ref().verify(digest, signature),
+            SigningKey::Pkcs11(key) => key.verify(digest, signature),
+            SigningKey::AwsKms(key) => key.verify(digest, signature),
+            SigningKey::AzureKeyVault(key) => key.verify(digest, signature),
+        }
+    }
+}
+
+impl SigningPrivateKey for SigningKey {
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, CoseError> {
+        match self {
+            SigningKey::Pkey(key) => key.as_ref().sign(digest),
+            SigningKey::Pkcs11(key) => key.sign(digest),
+            SigningKey::AwsKms(key) => key.sign(digest),
+            SigningKey::AzureKeyVault(key) => key.sign(digest),
+        }
+    }
+}
+
+pub struct Pkcs11Key {
+    // Kept so the module stays loaded for as long as the session is in use
+    _context: Pkcs11,
+    session: Mutex<Session>,
+    private_key: ObjectHandle,
+    public_key: PKey<Public>,
+    algorithm: (SignatureAlgorithm, MessageDigest),
+}
+
+impl std::fmt::Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pkcs11Key")
+            .field("private_key", &self.private_key)
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+            if hex.len() != 2 {
+                bail!("Truncated percent-encoding in PKCS#11 URI");
+            }
+            let hex = std::str::from_utf8(&hex)?;
+            out.push(u8::from_str_radix(hex, 16).context("Invalid percent-encoding")?);
+        } else {
+            out.push(b);
+        }
+    }
+    Ok(out)
+}
+
+struct Pkcs11Uri(HashMap<String, Vec<u8>>);
+
+impl Pkcs11Uri {
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.0.get(key).map(Vec::as_slice)
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        self.0
+            .get(key)
+            .map(|value| {
+                String::from_utf8(value.clone())
+                    .with_context(|| format!("Invalid UTF-8 in PKCS#11 URI attribute {key}"))
+            })
+            .transpose()
+    }
+}
+
+fn parse_pkcs11_uri(uri: &str) -> Result<Pkcs11Uri> {
+    let uri = uri
+        .strip_prefix(PKCS11_URI_SCHEME)
+        .ok_or_else(|| anyhow!("Not a PKCS#11 URI"))?;
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (uri, ""),
+    };
+
+    let mut attributes = HashMap::new();
+    for attr in path
+        .split(';')
+        .chain(query.split('&'))
+        .filter(|attr| !attr.is_empty())
+    {
+        let (key, value) = attr
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid PKCS#11 URI attribute {attr}"))?;
+        attributes.insert(key.to_string(), percent_decode(value)?);
+    }
+    Ok(Pkcs11Uri(attributes))
+}
+
+impl Pkcs11Key {
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let attributes = parse_pkcs11_uri(uri)?;
+
+        let module_path = match attributes.get_str("module-path")? {
+            Some(path) => path,
+            None => std::env::var(PKCS11_MODULE_PATH_ENV)
+                .context("No module-path in PKCS#11 URI and PKCS11_MODULE_PATH not set")?,
+        };
+        let pin = match (
+            attributes.get_str("pin-value")?,
+            attributes.get_str("pin-source")?,
+        ) {
+            (Some(pin), _) => Some(pin),
+            (None, Some(source)) => {
+                let source = source.strip_prefix("file:").unwrap_or(&source);
+                Some(
+                    fs::read_to_string(source)
+                        .with_context(|| format!("Error reading PKCS#11 PIN from {source}"))?
+                        .trim_end()
+                        .to_string(),
+                )
+            }
+            (None, None) => std::env::var(PKCS11_PIN_ENV).ok(),
+        };
+
+        let context = Pkcs11::new(&module_path)
+            .with_context(|| format!("Error loading PKCS#11 module {module_path}"))?;
+        context
+            .initialize(CInitializeArgs::OsThreads)
+            .context("Error initializing PKCS#11 module")?;
+
+        let token = attributes.get_str("token")?;
+        let mut slot = None;
+        for candidate in context
+            .get_slots_with_token()
+            .context("Error listing slots")?
+        {
+            let info = context
+                .get_token_info(candidate)
+                .context("Error getting token info")?;
+            let matches = match &token {
+                Some(token) => info.label() == token,
+                None => true,
+            };
+            if matches {
+                slot = Some(candidate);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| anyhow!("No matching PKCS#11 token found"))?;
+
+        let session = context
+            .open_ro_session(slot)
+            .context("Error opening PKCS#11 session")?;
+        if let Some(pin) = pin {
+            session
+                .login(UserType::User, Some(&AuthPin::new(pin)))
+                .context("Error logging in to PKCS#11 token")?;
+        }
+
+        let mut template = Vec::new();
+        if let Some(label) = attributes.get("object") {
+            template.push(Attribute::Label(label.to_vec()));
+        }
+        if let Some(id) = attributes.get("id") {
+            template.push(Attribute::Id(id.to_vec()));
+        }
+
+        let private_key = Self::find_single_object(&session, &template, ObjectClass::PRIVATE_KEY)?;
+        let public_key = Self::find_single_object(&session, &template, ObjectClass::PUBLIC_KEY)?;
+        let (public_key, algorithm) = Self::load_ec_public_key(&session, public_key)?;
+
+        Ok(Pkcs11Key {
+            _context: context,
+            session: Mutex::new(session),
+            private_key,
+            public_key,
+            algorithm,
+        })
+    }
+
+    fn find_single_object(
+        session: &Session,
+        template: &[Attribute],
+        class: ObjectClass,
+    ) -> Result<ObjectHandle> {
+        let mut template = template.to_vec();
+        template.push(Attribute::Class(class));
+        let mut objects = session
+            .find_objects(&template)
+            .context("Error searching PKCS#11 objects")?;
+        match objects.len() {
+            0 => bail!("No PKCS#11 object of class {:?} found", class),
+            1 => Ok(objects.remove(0)),
+            _ => bail!(
+                "Multiple PKCS#11 objects of class {:?} found, use a more specific URI",
+                class
+            ),
+        }
+    }
+
+    fn load_ec_public_key(
+        session: &Session,
+        object: ObjectHandle,
+    ) -> Result<(PKey<Public>, (SignatureAlgorithm, MessageDigest))> {
+        let mut params = None;
+        let mut point = None;
+        for attribute in session
+            .get_attributes(object, &[AttributeType::EcParams, AttributeType::EcPoint])
+            .context("Error getting public key attributes")?
+        {
+            match attribute {
+                Attribute::EcParams(value) => params = Some(value),
+                Attribute::EcPoint(value) => point = Some(value),
+                _ => {}
+            }
+        }
+        let params = params.ok_or_else(|| anyhow!("Only EC keys are supported"))?;
+        let point = point.ok_or_else(|| anyhow!("Public key is missing EC point"))?;
+
+        let (curve, algorithm) = match params.as_slice() {
+            OID_PRIME256V1 => (
+                Nid::X9_62_PRIME256V1,
+                (SignatureAlgorithm::ES256, MessageDigest::sha256()),
+            ),
+            OID_SECP384R1 => (
+                Nid::SECP384R1,
+                (SignatureAlgorithm::ES384, MessageDigest::sha384()),
+            ),
+            _ => bail!("Unsupported EC curve"),
+        };
+
+        // CKA_EC_POINT is a DER encoded OCTET STRING wrapping the point
+        let point = match point.as_slice() {
+            [0x04, len, rest @ ..] if *len as usize == rest.len() => rest,
+            [0x04, 0x81, len, rest @ ..] if *len as usize == rest.len() => rest,
+            raw => raw,
+        };
+
+        let group = EcGroup::from_curve_name(curve)?;
+        let mut ctx = BigNumContext::new()?;
+        let point = EcPoint::from_bytes(&group, point, &mut ctx).context("Invalid EC point")?;
+        let key = PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)?;
+
+        Ok((key, algorithm))
+    }
+}
+
+impl SigningPublicKey for Pkcs11Key {
+    fn get_parameters(&self) -> Result<(SignatureAlgorithm, MessageDigest), CoseError> {
+        Ok(self.algorithm)
+    }
+
+    fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<bool, CoseError> {
+        self.public_key.as_ref().verify(digest, signature)
+    }
+}
+
+impl SigningPrivateKey for Pkcs11Key {
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, CoseError> {
+        // CKM_ECDSA signs a precomputed digest, and returns r || s as COSE expects
+        self.session
+            .lock()
+            .map_err(|_| CoseError::UnsupportedError("PKCS#11 session lock poisoned".to_string()))?
+            .sign(&Mechanism::Ecdsa, self.private_key, digest)
+            .map_err(|e| CoseError::UnsupportedError(format!("Error signing with PKCS#11: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_pkcs11_uri;
+
+    #[test]
+    fn test_parse_pkcs11_uri() {
+        let attributes = parse_pkcs11_uri(
+            "pkcs11:token=my%20token;object=owner?module-path=/usr/lib/libsofthsm2.so&pin-value=1234",
+        )
+        .unwrap();
+        assert_eq!(attributes.get("token"), Some(&b"my token"[..]));
+        assert_eq!(attributes.get("object"), Some(&b"owner"[..]));
+        assert_eq!(
+            attributes.get_str("module-path").unwrap().as_deref(),
+            Some("/usr/lib/libsofthsm2.so")
+        );
+        assert_eq!(
+            attributes.get_str("pin-value").unwrap().as_deref(),
+            Some("1234")
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! Signing keys held in a cloud key management service
+//!
+//! The private keys never leave the service: only the digests are sent to be signed.
+//! Keys are referenced by URI:
+//! - AWS KMS: `awskms:<key id or ARN>`, with an optional `?region=<region>` (taken from the
+//!   ARN, or the `AWS_REGION` environment variable otherwise) and `&endpoint=<url>`.
+//!   Credentials are read from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optionally
+//!   `AWS_SESSION_TOKEN`.
+//! - Azure Key Vault: `azurekv://<vault>.vault.azure.net/keys/<name>[/<version>]`. The access
+//!   token is read from `AZURE_KEYVAULT_ACCESS_TOKEN`, or requested from the managed identity
+//!   endpoint of the VM otherwise.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use aws_nitro_enclaves_cose::{
+    crypto::{SigningPrivateKey, SigningPublicKey},
+    error::CoseError,
+    sign::SignatureAlgorithm,
+};
+use openssl::{
+    base64,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Public},
+    sign::Signer,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use crate::base64url;
+
+pub(super) const AWS_KMS_URI_SCHEME: &str = "awskms:";
+pub(super) const AZURE_KEY_VAULT_URI_SCHEME: &str = "azurekv://";
+
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+const AZURE_KEY_VAULT_API_VERSION: &str = "7.4";
+const AZURE_IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token?api-version=2018-02-01&resource=https%3A%2F%2Fvault.azure.net";
+// Tokens are requested again this long before they expire
+const AZURE_TOKEN_EXPIRY_MARGIN_SECS: u64 = 60;
+
+fn http_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+        .build()?)
+}
+
+/// Waits for `request` to complete.
+///
+/// The signing traits are synchronous, but are called from async handlers. On a
+/// multi-threaded runtime, the worker thread is handed over with `block_in_place` while the
+/// request runs on the runtime, so other tasks are not held up.
+fn block_on<T>(request: impl Future<Output = Result<T>>) -> Result<T> {
+    match Handle::try_current() {
+        Ok(handle) => match handle.runtime_flavor() {
+            RuntimeFlavor::CurrentThread => {
+                bail!("KMS keys can't be used from a single threaded runtime")
+            }
+            _ => tokio::task::block_in_place(|| handle.block_on(request)),
+        },
+        Err(_) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(request),
+    }
+}
+
+fn ec_parameters(curve: Nid) -> Result<(SignatureAlgorithm, MessageDigest, usize)> {
+    match curve {
+        Nid::X9_62_PRIME256V1 => Ok((SignatureAlgorithm::ES256, MessageDigest::sha256(), 32)),
+        Nid::SECP384R1 => Ok((SignatureAlgorithm::ES384, MessageDigest::sha384(), 48)),
+        _ => bail!("Unsupported EC curve {}", curve.as_raw()),
+    }
+}
+
+fn sign_error(e: anyhow::Error) -> CoseError {
+    CoseError::UnsupportedError(format!("Error signing with KMS: {e:?}"))
+}
+
+pub struct AwsKmsKey {
+    client: reqwest::Client,
+    key_id: String,
+    region: String,
+    endpoint: String,
+    public_key: PKey<Public>,
+    algorithm: (SignatureAlgorithm, MessageDigest),
+    // Length of each of r and s in the signature
+    component_len: usize,
+}
+
+impl std::fmt::Debug for AwsKmsKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsKmsKey")
+            .field("key_id", &self.key_id)
+            .field("region", &self.region)
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    fn from_env() -> Result<Self> {
+        Ok(AwsCredentials {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID")
+                .context("AWS_ACCESS_KEY_ID not set")?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY")
+                .context("AWS_SECRET_ACCESS_KEY not set")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(openssl::sha::sha256(data))
+}
+
+impl AwsKmsKey {
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let uri = uri
+            .strip_prefix(AWS_KMS_URI_SCHEME)
+            .ok_or_else(|| anyhow!("Not an AWS KMS URI"))?;
+        let (key_id, query) = match uri.split_once('?') {
+            Some((key_id, query)) => (key_id, query),
+            None => (uri, ""),
+        };
+        if key_id.is_empty() {
+            bail!("No key ID in AWS KMS URI");
+        }
+        let query: HashMap<&str, &str> = query
+            .split('&')
+            .filter_map(|attr| attr.split_once('='))
+            .collect();
+
+        // ARNs look like arn:aws:kms:<region>:<account>:key/<id>
+        let arn_region = key_id
+            .strip_prefix("arn:")
+            .and_then(|arn| arn.split(':').nth(2));
+        let region = match (query.get("region").copied(), arn_region) {
+            (Some(region), _) | (None, Some(region)) => region.to_string(),
+            (None, None) => std::env::var("AWS_REGION")
+                .context("No region in AWS KMS URI and AWS_REGION not set")?,
+        };
+        let endpoint = match query.get("endpoint") {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://kms.{region}.amazonaws.com"),
+        };
+
+        let client = http_client()?;
+        let key_id = key_id.to_string();
+        let response = block_on(Self::call(
+            &client,
+            &endpoint,
+            &region,
+            "GetPublicKey",
+            json!({ "KeyId": key_id }),
+        ))
+        .context("Error getting public key from AWS KMS")?;
+        let public_key = response["PublicKey"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No public key in AWS KMS response"))?;
+        let public_key = PKey::public_key_from_der(&base64::decode_block(public_key)?)
+            .context("Invalid public key in AWS KMS response")?;
+        let curve = public_key
+            .ec_key()
+            .context("Only EC keys are supported")?
+            .group()
+            .curve_name()
+            .ok_or_else(|| anyhow!("Unnamed EC curve"))?;
+        let (algorithm, digest, component_len) = ec_parameters(curve)?;
+
+        Ok(AwsKmsKey {
+            client,
+            key_id,
+            region,
+            endpoint,
+            public_key,
+            algorithm: (algorithm, digest),
+            component_len,
+        })
+    }
+
+    pub fn public_key(&self) -> &PKey<Public> {
+        &self.public_key
+    }
+
+    /// Calls a KMS action, with a request signed with AWS Signature Version 4.
+    async fn call(
+        client: &reqwest::Client,
+        endpoint: &str,
+        region: &str,
+        action: &str,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let credentials = AwsCredentials::from_env()?;
+        let body = serde_json::to_vec(&body)?;
+        let host = endpoint
+            .split_once("://")
+            .map(|(_, host)| host)
+            .unwrap_or(endpoint)
+            .to_string();
+        let target = format!("TrentService.{action}");
+        let content_type = "application/x-amz-json-1.1";
+
+        let now = time::OffsetDateTime::now_utc();
+        let date = format!(
+            "{:04}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day()
+        );
+        let amz_date = format!(
+            "{date}T{:02}{:02}{:02}Z",
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let scope = format!("{date}/{region}/kms/aws4_request");
+
+        // Headers to sign, sorted by name
+        let mut headers = vec![
+            ("content-type", content_type.to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(session_token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", session_token.clone()));
+        }
+        headers.push(("x-amz-target", target));
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{value}\n"))
+            .collect();
+        let canonical_request = format!(
+            "POST\n/\n\n{canonical_headers}\n{signed_headers}\n{}",
+            sha256_hex(&body)
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let mut signing_key = format!("AWS4{}", credentials.secret_access_key).into_bytes();
+        for part in [date.as_str(), region, "kms", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes())?;
+        }
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes())?);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            credentials.access_key_id
+        );
+
+        let mut request = client
+            .post(format!("{endpoint}/"))
+            .header("Authorization", authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        let response: serde_json::Value = response.json().await?;
+        if !status.is_success() {
+            bail!("AWS KMS returned {}: {}", status, response);
+        }
+        Ok(response)
+    }
+
+    async fn sign_digest(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        let algorithm = match self.algorithm.0 {
+            SignatureAlgorithm::ES256 => "ECDSA_SHA_256",
+            SignatureAlgorithm::ES384 => "ECDSA_SHA_384",
+            _ => bail!("Unsupported signature algorithm"),
+        };
+        let response = Self::call(
+            &self.client,
+            &self.endpoint,
+            &self.region,
+            "Sign",
+            json!({
+                "KeyId": self.key_id,
+                "Message": base64::encode_block(digest),
+                "MessageType": "DIGEST",
+                "SigningAlgorithm": algorithm,
+            }),
+        )
+        .await?;
+        let signature = response["Signature"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No signature in AWS KMS response"))?;
+        // KMS returns a DER encoded signature, while COSE expects r || s
+        let signature = EcdsaSig::from_der(&base64::decode_block(signature)?)?;
+        let len = self.component_len as i32;
+        Ok([
+            signature.r().to_vec_padded(len)?,
+            signature.s().to_vec_padded(len)?,
+        ]
+        .concat())
+    }
+}
+
+impl SigningPublicKey for AwsKmsKey {
+    fn get_parameters(&self) -> Result<(SignatureAlgorithm, MessageDigest), CoseError> {
+        Ok(self.algorithm)
+    }
+
+    fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<bool, CoseError> {
+        self.public_key.as_ref().verify(digest, signature)
+    }
+}
+
+impl SigningPrivateKey for AwsKmsKey {
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, CoseError> {
+        block_on(self.sign_digest(digest)).map_err(sign_error)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AzureToken {
+    access_token: String,
+    // Seconds, as a string
+    expires_in: String,
+}
+
+/// Access token for Azure Key Vault
+#[derive(Default)]
+struct AzureAccessToken {
+    // Managed identity token and when it expires, unless AZURE_KEYVAULT_ACCESS_TOKEN is set
+    cached: Mutex<Option<(String, Instant)>>,
+}
+
+impl AzureAccessToken {
+    async fn get(&self, client: &reqwest::Client) -> Result<String> {
+        if let Ok(token) = std::env::var("AZURE_KEYVAULT_ACCESS_TOKEN") {
+            return Ok(token);
+        }
+        if let Some((token, expires)) = self.cached.lock().unwrap().as_ref() {
+            if Instant::now() < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let token: AzureToken = async {
+            let response = client
+                .get(AZURE_IMDS_TOKEN_URL)
+                .header("Metadata", "true")
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<_, anyhow::Error>(response.json().await?)
+        }
+        .await
+        .context("Error getting Azure managed identity token")?;
+        let expires_in: u64 = token.expires_in.parse().unwrap_or_default();
+        let expires = Instant::now()
+            + Duration::from_secs(expires_in.saturating_sub(AZURE_TOKEN_EXPIRY_MARGIN_SECS));
+        *self.cached.lock().unwrap() = Some((token.access_token.clone(), expires));
+        Ok(token.access_token)
+    }
+}
+
+pub struct AzureKeyVaultKey {
+    client: reqwest::Client,
+    key_url: String,
+    token: AzureAccessToken,
+    public_key: PKey<Public>,
+    algorithm: (SignatureAlgorithm, MessageDigest),
+}
+
+impl std::fmt::Debug for AzureKeyVaultKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureKeyVaultKey")
+            .field("key_url", &self.key_url)
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+/// Calls `operation` on the key at `key_url`.
+async fn azure_key_vault_call(
+    client: &reqwest::Client,
+    token: &AzureAccessToken,
+    key_url: &str,
+    method: reqwest::Method,
+    operation: &str,
+    body: Option<serde_json::Value>,
+) -> Result<serde_json::Value> {
+    let token = token.get(client).await?;
+    let url = format!("{key_url}{operation}?api-version={AZURE_KEY_VAULT_API_VERSION}");
+    let mut request = client.request(method, url).bearer_auth(token);
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    let response: serde_json::Value = response.json().await?;
+    if !status.is_success() {
+        bail!("Azure Key Vault returned {}: {}", status, response);
+    }
+    Ok(response)
+}
+
+impl AzureKeyVaultKey {
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let path = uri
+            .strip_prefix(AZURE_KEY_VAULT_URI_SCHEME)
+            .ok_or_else(|| anyhow!("Not an Azure Key Vault URI"))?;
+        if !path.contains("/keys/") {
+            bail!("Azure Key Vault URI must point to a key, like azurekv://<vault>.vault.azure.net/keys/<name>");
+        }
+        let key_url = format!("https://{}", path.trim_end_matches('/'));
+        let client = http_client()?;
+        let token = AzureAccessToken::default();
+
+        let response = block_on(azure_key_vault_call(
+            &client,
+            &token,
+            &key_url,
+            reqwest::Method::GET,
+            "",
+            None,
+        ))
+        .context("Error getting public key from Azure Key Vault")?;
+        let jwk = &response["key"];
+        let curve = match jwk["crv"].as_str() {
+            Some("P-256") => Nid::X9_62_PRIME256V1,
+            Some("P-384") => Nid::SECP384R1,
+            _ => bail!("Only P-256 and P-384 EC keys are supported"),
+        };
+        let coordinate = |name: &str| -> Result<BigNum> {
+            let value = jwk[name]
+                .as_str()
+                .ok_or_else(|| anyhow!("No {} in Azure Key Vault key", name))?;
+            Ok(BigNum::from_slice(&base64url::decode(value)?)?)
+        };
+        let group = EcGroup::from_curve_name(curve)?;
+        let (x, y) = (coordinate("x")?, coordinate("y")?);
+        let public_key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+            .context("Invalid public key in Azure Key Vault response")?;
+        let (algorithm, digest, _) = ec_parameters(curve)?;
+
+        Ok(AzureKeyVaultKey {
+            client,
+            key_url,
+            token,
+            public_key: PKey::from_ec_key(public_key)?,
+            algorithm: (algorithm, digest),
+        })
+    }
+
+    pub fn public_key(&self) -> &PKey<Public> {
+        &self.public_key
+    }
+
+    async fn sign_digest(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        let algorithm = match self.algorithm.0 {
+            SignatureAlgorithm::ES256 => "ES256",
+            SignatureAlgorithm::ES384 => "ES384",
+            _ => bail!("Unsupported signature algorithm"),
+        };
+        let response = azure_key_vault_call(
+            &self.client,
+            &self.token,
+            &self.key_url,
+            reqwest::Method::POST,
+            "/sign",
+            Some(json!({
+                "alg": algorithm,
+                "value": base64url::encode(digest),
+            })),
+        )
+        .await?;
+        // Key Vault returns r || s, just like COSE expects
+        let signature = response["value"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No signature in Azure Key Vault response"))?;
+        base64url::decode(signature)
+    }
+}
+
+impl SigningPublicKey for AzureKeyVaultKey {
+    fn get_parameters(&self) -> Result<(SignatureAlgorithm, MessageDigest), CoseError> {
+        Ok(self.algorithm)
+    }
+
+    fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<bool, CoseError> {
+        self.public_key.as_ref().verify(digest, signature)
+    }
+}
+
+impl SigningPrivateKey for AzureKeyVaultKey {
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, CoseError> {
+        block_on(self.sign_digest(digest)).map_err(sign_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::block_on;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_block_on_multi_thread() {
+        // The request needs another task to run on the only worker thread
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move { sender.send(42) });
+        assert_eq!(block_on(async { Ok(receiver.await?) }).unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_block_on_current_thread() {
+        assert!(block_on(async { Ok(()) }).is_err());
+    }
+
+    #[test]
+    fn test_block_on_without_runtime() {
+        assert_eq!(block_on(async { Ok(42) }).unwrap(), 42);
+    }
+}
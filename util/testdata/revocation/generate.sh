@@ -0,0 +1,88 @@
+#!/bin/bash
+# Generates the certificates, CRLs and OCSP responses for the revocation tests.
+#
+# ocsp_outdated.der has a next update time a minute after it was generated, so it's
+# only outdated once that minute and the allowed clock skew have passed.
+set -euo pipefail
+
+workdir="$(mktemp -d)"
+trap 'rm -rf "$workdir"' EXIT
+outdir="$(cd "$(dirname "$0")" && pwd)"
+cd "$workdir"
+
+cat >ca.cnf <<'CNF'
+[ ca ]
+default_ca = CA_default
+[ CA_default ]
+database = index.txt
+new_certs_dir = .
+serial = serial
+crlnumber = crlnumber
+default_md = sha256
+default_crl_days = 36500
+policy = policy_any
+[ policy_any ]
+commonName = supplied
+[ v3_ca ]
+basicConstraints = critical, CA:TRUE
+keyUsage = critical, keyCertSign, cRLSign, digitalSignature
+subjectKeyIdentifier = hash
+[ v3_leaf ]
+basicConstraints = CA:FALSE
+authorityKeyIdentifier = keyid
+CNF
+
+make_ca() {
+    openssl ecparam -name prime256v1 -genkey -noout -out "$1.key"
+    openssl req -x509 -new -key "$1.key" -subj "/CN=$2" -days 36500 -sha256 \
+        -config ca.cnf -extensions v3_ca -out "$1.pem"
+}
+
+# fake_ca has the subject of ca, but a different key
+make_ca ca "Revocation Test CA"
+make_ca other_ca "Other Test CA"
+make_ca fake_ca "Revocation Test CA"
+
+openssl ecparam -name prime256v1 -genkey -noout -out leaf.key
+openssl req -new -key leaf.key -subj "/CN=Revocation Test Leaf" -out leaf.csr
+openssl x509 -req -in leaf.csr -CA ca.pem -CAkey ca.key -set_serial 4097 -days 36500 \
+    -sha256 -extfile ca.cnf -extensions v3_leaf -out leaf.pem
+
+# make_crl <output> <ca> <revoke|keep> [extra openssl ca arguments]
+make_crl() {
+    local output="$1" ca="$2" revoke="$3"
+    shift 3
+    rm -f index.txt* crlnumber*
+    touch index.txt
+    echo 01 >crlnumber
+    if [ "$revoke" = revoke ]; then
+        openssl ca -config ca.cnf -keyfile "$ca.key" -cert "$ca.pem" -revoke leaf.pem -batch
+    fi
+    openssl ca -config ca.cnf -keyfile "$ca.key" -cert "$ca.pem" -gencrl -batch "$@" \
+        -out "$output"
+}
+
+make_crl crl_good.crl ca keep
+make_crl crl_revoked.crl ca revoke
+make_crl crl_stale.crl ca revoke -crl_lastupdate 20200101000000Z -crl_nextupdate 20200201000000Z
+make_crl crl_other_issuer.crl other_ca revoke
+make_crl crl_forged.crl fake_ca revoke
+
+openssl ocsp -issuer ca.pem -cert leaf.pem -no_nonce -reqout req.der
+printf 'V\t21260922000000Z\t\t1001\tunknown\t/CN=Revocation Test Leaf\n' >good.txt
+printf 'R\t21260922000000Z\t251016000000Z\t1001\tunknown\t/CN=Revocation Test Leaf\n' >revoked.txt
+
+# make_ocsp <output> <index> <signer> [extra openssl ocsp arguments]
+make_ocsp() {
+    local output="$1" index="$2" signer="$3"
+    shift 3
+    openssl ocsp -index "$index" -CA ca.pem -rsigner "$signer.pem" -rkey "$signer.key" \
+        -reqin req.der "$@" -respout "$output"
+}
+
+make_ocsp ocsp_good.der good.txt ca
+make_ocsp ocsp_revoked.der revoked.txt ca
+make_ocsp ocsp_wrong_signer.der good.txt other_ca
+make_ocsp ocsp_outdated.der good.txt ca -nmin 1
+
+cp ca.pem other_ca.pem fake_ca.pem leaf.pem crl_*.crl ocsp_*.der "$outdir"